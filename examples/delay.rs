@@ -0,0 +1,125 @@
+use std::env;
+use std::ffi::OsStr;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use libc::{EINTR, ENOENT};
+use fuse::{FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyDirectory};
+
+const TTL: Duration = Duration::from_secs(1);           // 1 second
+
+const DELAY: Duration = Duration::from_secs(3);         // 3 seconds
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const DELAY_DIR_ATTR: FileAttr = FileAttr {
+    ino: 1,
+    size: 0,
+    blocks: 0,
+    atime: UNIX_EPOCH,                                  // 1970-01-01 00:00:00
+    mtime: UNIX_EPOCH,
+    ctime: UNIX_EPOCH,
+    crtime: UNIX_EPOCH,
+    kind: FileType::Directory,
+    perm: 0o755,
+    nlink: 2,
+    uid: 501,
+    gid: 20,
+    rdev: 0,
+    flags: 0,
+};
+
+const DELAY_TXT_CONTENT: &str = "Sorry for the delay!\n";
+
+const DELAY_TXT_ATTR: FileAttr = FileAttr {
+    ino: 2,
+    size: 21,
+    blocks: 1,
+    atime: UNIX_EPOCH,                                  // 1970-01-01 00:00:00
+    mtime: UNIX_EPOCH,
+    ctime: UNIX_EPOCH,
+    crtime: UNIX_EPOCH,
+    kind: FileType::RegularFile,
+    perm: 0o644,
+    nlink: 1,
+    uid: 501,
+    gid: 20,
+    rdev: 0,
+    flags: 0,
+};
+
+/// A filesystem with a single file that takes a while to read. Reads are replied
+/// asynchronously by a task, so the filesystem stays responsive in the meantime.
+struct DelayFS;
+
+impl Filesystem for DelayFS {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if parent == 1 && name.to_str() == Some("delay.txt") {
+            reply.entry(&TTL, &DELAY_TXT_ATTR, 0);
+        } else {
+            reply.error(ENOENT);
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        match ino {
+            1 => reply.attr(&TTL, &DELAY_DIR_ATTR),
+            2 => reply.attr(&TTL, &DELAY_TXT_ATTR),
+            _ => reply.error(ENOENT),
+        }
+    }
+
+    fn read(&mut self, req: &Request, ino: u64, _fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        if ino != 2 {
+            reply.error(ENOENT);
+            return;
+        }
+
+        // The task is tied to the session. If the filesystem is unmounted while
+        // waiting, the shutdown token trips and the task replies right away. The
+        // same goes for the read being interrupted (e.g. by Ctrl-C).
+        let interrupted = req.interrupt_flag();
+        let res = req.spawner().spawn(move |token| {
+            let deadline = Instant::now() + DELAY;
+            while Instant::now() < deadline {
+                if token.wait_timeout(POLL_INTERVAL) || interrupted.is_interrupted() {
+                    return reply.error(EINTR);
+                }
+            }
+            let data = DELAY_TXT_CONTENT.as_bytes();
+            let start = (offset as usize).min(data.len());
+            let end = (start + size as usize).min(data.len());
+            reply.data(&data[start..end]);
+        });
+        if let Err(err) = res {
+            eprintln!("Failed to spawn task: {}", err);
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        if ino != 1 {
+            reply.error(ENOENT);
+            return;
+        }
+
+        let entries = vec![
+            (1, FileType::Directory, "."),
+            (1, FileType::Directory, ".."),
+            (2, FileType::RegularFile, "delay.txt"),
+        ];
+
+        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
+            // i + 1 means the index of the next entry
+            reply.add(entry.0, (i + 1) as i64, entry.1, entry.2);
+        }
+        reply.ok();
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let mountpoint = env::args_os().nth(1).unwrap();
+    let options = ["-o", "ro", "-o", "fsname=delay"]
+        .iter()
+        .map(|o| o.as_ref())
+        .collect::<Vec<&OsStr>>();
+    fuse::mount(DelayFS, mountpoint, &options).unwrap();
+}
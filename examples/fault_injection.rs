@@ -0,0 +1,135 @@
+//! Small in-memory filesystem with injected faults: reads fail with EIO after the first three
+//! (e.g. `cat` a file a few times). Other rules can be given in a file as second argument
+//! (see `fuse::faults::parse_rules` for the format).
+
+use std::collections::HashMap;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::time::SystemTime;
+use libc::{ENOENT, ENOTDIR};
+use fuse::faults::FaultInjectingFilesystem;
+use fuse::{CreateMode, FileType, FileAttr, Filesystem, Request, SetattrChanges, FUSE_ROOT_ID};
+use fuse::{ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEntry, ReplyWrite};
+
+const RULES: &str = "read after=3 error=EIO";
+
+/// Flat in-memory filesystem: regular files in the root directory
+struct MemFS {
+    names: HashMap<OsString, u64>,
+    files: HashMap<u64, (FileAttr, Vec<u8>)>,
+    next_ino: u64,
+}
+
+impl MemFS {
+    fn new() -> MemFS {
+        MemFS { names: HashMap::new(), files: HashMap::new(), next_ino: FUSE_ROOT_ID + 1 }
+    }
+
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        if ino == FUSE_ROOT_ID {
+            return Some(FileAttr::builder(ino, FileType::Directory).perm(0o755).nlink(2).times(SystemTime::now()).build());
+        }
+        self.files.get(&ino).map(|(attr, _)| *attr)
+    }
+}
+
+impl Filesystem for MemFS {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.names.get(name).filter(|_| parent == FUSE_ROOT_ID).and_then(|&ino| self.attr(ino)) {
+            Some(attr) => reply.entry_default(&attr, 0),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Some(attr) => reply.attr_default(&attr),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn setattr_changes(&mut self, _req: &Request, ino: u64, changes: SetattrChanges, reply: ReplyAttr) {
+        let (attr, data) = match self.files.get_mut(&ino) {
+            Some(file) => file,
+            None => return reply.error(ENOENT),
+        };
+        if let Some(size) = changes.size() {
+            data.resize(size as usize, 0);
+            attr.size = size;
+        }
+        if let Some(mode) = changes.mode() {
+            attr.perm = mode as u16 & 0o7777;
+        }
+        reply.attr_default(attr);
+    }
+
+    fn create_with_umask(&mut self, req: &Request, parent: u64, name: &OsStr, mode: CreateMode, _flags: u32, reply: ReplyCreate) {
+        if parent != FUSE_ROOT_ID {
+            return reply.error(ENOTDIR);
+        }
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        let attr = FileAttr::builder(ino, FileType::RegularFile)
+            .perm(mode.masked() as u16 & 0o7777)
+            .uid(req.uid())
+            .gid(req.gid())
+            .times(SystemTime::now())
+            .build();
+        self.names.insert(name.into(), ino);
+        self.files.insert(ino, (attr, Vec::new()));
+        reply.created_default(&attr, 0, 0, 0);
+    }
+
+    fn read(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        match self.files.get(&ino) {
+            Some((_, data)) => {
+                let start = (offset as usize).min(data.len());
+                let end = (start + size as usize).min(data.len());
+                reply.data(&data[start..end]);
+            }
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn write(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+        let (attr, contents) = match self.files.get_mut(&ino) {
+            Some(file) => file,
+            None => return reply.error(ENOENT),
+        };
+        // Writing beyond the end of the file leaves a hole that reads as zeros
+        let end = offset as usize + data.len();
+        if contents.len() < end {
+            contents.resize(end, 0);
+        }
+        contents[offset as usize..end].copy_from_slice(data);
+        attr.size = contents.len() as u64;
+        reply.written(data.len() as u32);
+    }
+
+    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        if ino != FUSE_ROOT_ID {
+            return reply.error(ENOTDIR);
+        }
+        let mut entries = vec![(FUSE_ROOT_ID, FileType::Directory, OsStr::new(".")), (FUSE_ROOT_ID, FileType::Directory, OsStr::new(".."))];
+        entries.extend(self.names.iter().map(|(name, &ino)| (ino, FileType::RegularFile, name.as_os_str())));
+        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let mountpoint = env::args_os().nth(1).unwrap();
+    let rules = match env::args_os().nth(2) {
+        Some(path) => fs::read_to_string(path).unwrap(),
+        None => RULES.to_string(),
+    };
+    let fs = FaultInjectingFilesystem::new(MemFS::new());
+    fs.handle().load(&rules).unwrap();
+    fuse::mount(fs, mountpoint, &[]).unwrap();
+}
@@ -1,11 +1,9 @@
 use std::env;
 use std::ffi::OsStr;
-use std::time::{Duration, UNIX_EPOCH};
+use std::time::UNIX_EPOCH;
 use libc::ENOENT;
 use fuse::{FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyDirectory};
 
-const TTL: Duration = Duration::from_secs(1);           // 1 second
-
 const HELLO_DIR_ATTR: FileAttr = FileAttr {
     ino: 1,
     size: 0,
@@ -47,7 +45,7 @@ struct HelloFS;
 impl Filesystem for HelloFS {
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         if parent == 1 && name.to_str() == Some("hello.txt") {
-            reply.entry(&TTL, &HELLO_TXT_ATTR, 0);
+            reply.entry_default(&HELLO_TXT_ATTR, 0);
         } else {
             reply.error(ENOENT);
         }
@@ -55,15 +53,19 @@ impl Filesystem for HelloFS {
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
         match ino {
-            1 => reply.attr(&TTL, &HELLO_DIR_ATTR),
-            2 => reply.attr(&TTL, &HELLO_TXT_ATTR),
+            1 => reply.attr_default(&HELLO_DIR_ATTR),
+            2 => reply.attr_default(&HELLO_TXT_ATTR),
             _ => reply.error(ENOENT),
         }
     }
 
-    fn read(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, _size: u32, reply: ReplyData) {
+    fn read(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32, reply: ReplyData) {
         if ino == 2 {
-            reply.data(&HELLO_TXT_CONTENT.as_bytes()[offset as usize..]);
+            // Reads may start at or beyond the end of the file
+            let data = HELLO_TXT_CONTENT.as_bytes();
+            let start = (offset as usize).min(data.len());
+            let end = (start + size as usize).min(data.len());
+            reply.data(&data[start..end]);
         } else {
             reply.error(ENOENT);
         }
@@ -0,0 +1,231 @@
+//! A read-only filesystem with a single file whose content changes on a timer. The kernel
+//! driver caches the file's attributes and data for a long time, so without notifications,
+//! readers would see the first content until the cache expires. Instead, a background thread
+//! pushes every new content to the kernel driver's page cache with `Notifier::store` and
+//! invalidates the cached attributes with `Notifier::inval_inode`, so that `cat` sees fresh
+//! data right away without the filesystem being polled.
+//!
+//! Usage: notify <mountpoint> [update interval in ms]
+
+#[cfg(feature = "abi-7-15")]
+mod notify {
+    use std::ffi::OsStr;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+    use libc::{ENOENT, ENOTCONN};
+    use fuse::{FileType, FileAttr, Filesystem, Notifier, OpenFlags, Request};
+    use fuse::{ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, ReplyOpen};
+
+    /// Time that the kernel driver caches entries and attributes. Changes are notified, so
+    /// there's no need to expire them early.
+    const TTL: Duration = Duration::from_secs(3600);    // 1 hour
+
+    pub const FILE_NAME: &str = "status.txt";
+
+    const FILE_INO: u64 = 2;
+
+    /// Returns the content of the file in the given version. Every version has the same
+    /// length, so storing it replaces the previous one completely.
+    pub fn render(version: u64) -> Vec<u8> {
+        format!("version {:08}\n", version).into_bytes()
+    }
+
+    /// Current content of the file
+    #[derive(Debug)]
+    struct Content {
+        version: u64,
+        data: Vec<u8>,
+        mtime: SystemTime,
+    }
+
+    impl Content {
+        fn attr(&self) -> FileAttr {
+            FileAttr::builder(FILE_INO, FileType::RegularFile)
+                .size(self.data.len() as u64)
+                .times(self.mtime)
+                .perm(0o444)
+                .build()
+        }
+    }
+
+    /// Content of the file, shared by the filesystem and the thread that updates it
+    #[derive(Clone, Debug)]
+    pub struct SharedContent(Arc<Mutex<Content>>);
+
+    impl SharedContent {
+        pub fn new() -> SharedContent {
+            SharedContent(Arc::new(Mutex::new(Content { version: 1, data: render(1), mtime: SystemTime::now() })))
+        }
+
+        /// Change the content to the next version and push it to the kernel driver with the
+        /// given notifier. Returns the new version.
+        pub fn update(&self, notifier: &Notifier) -> io::Result<u64> {
+            let (version, data) = {
+                let mut content = self.0.lock().unwrap();
+                content.version += 1;
+                content.data = render(content.version);
+                content.mtime = SystemTime::now();
+                (content.version, content.data.clone())
+            };
+            // The kernel driver only caches the file after it was looked up. Until then,
+            // there's nothing to update, and the next read gets the new content anyway.
+            match notifier.store(FILE_INO, 0, &data) {
+                Err(ref err) if err.raw_os_error() == Some(ENOENT) => return Ok(version),
+                res => res?,
+            }
+            // Refresh the cached attributes, which contain the modification time
+            notifier.inval_inode(FILE_INO, -1, 0)?;
+            Ok(version)
+        }
+
+        /// Update the content in a background thread at the given interval, until the
+        /// filesystem is unmounted
+        pub fn update_every(&self, notifier: Notifier, interval: Duration) -> thread::JoinHandle<()> {
+            let content = self.clone();
+            thread::spawn(move || loop {
+                thread::sleep(interval);
+                match content.update(&notifier) {
+                    Ok(_) => (),
+                    Err(ref err) if err.raw_os_error() == Some(ENOTCONN) => break,
+                    Err(err) => eprintln!("Failed to notify update: {}", err),
+                }
+            })
+        }
+    }
+
+    pub struct NotifyFS {
+        content: SharedContent,
+    }
+
+    impl NotifyFS {
+        pub fn new(content: SharedContent) -> NotifyFS {
+            NotifyFS { content }
+        }
+    }
+
+    impl Filesystem for NotifyFS {
+        fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+            if parent == 1 && name == FILE_NAME {
+                reply.entry(&TTL, &self.content.0.lock().unwrap().attr(), 0);
+            } else {
+                reply.error(ENOENT);
+            }
+        }
+
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+            match ino {
+                1 => reply.attr(&TTL, &FileAttr::builder(1, FileType::Directory).build()),
+                FILE_INO => reply.attr(&TTL, &self.content.0.lock().unwrap().attr()),
+                _ => reply.error(ENOENT),
+            }
+        }
+
+        fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: u32, reply: ReplyOpen) {
+            if ino != FILE_INO {
+                reply.error(ENOENT);
+                return;
+            }
+            // Keep the page cache when the file is opened again, it's kept up to date
+            reply.opened(0, OpenFlags::new().keep_cache().bits());
+        }
+
+        fn read(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, size: u32, reply: ReplyData) {
+            if ino != FILE_INO {
+                reply.error(ENOENT);
+                return;
+            }
+            let content = self.content.0.lock().unwrap();
+            let start = (offset as usize).min(content.data.len());
+            let end = start.saturating_add(size as usize).min(content.data.len());
+            reply.data(&content.data[start..end]);
+        }
+
+        fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+            if ino != 1 {
+                reply.error(ENOENT);
+                return;
+            }
+
+            let entries = vec![
+                (1, FileType::Directory, "."),
+                (1, FileType::Directory, ".."),
+                (FILE_INO, FileType::RegularFile, FILE_NAME),
+            ];
+
+            for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
+                // i + 1 means the index of the next entry
+                reply.add(entry.0, (i + 1) as i64, entry.1, entry.2);
+            }
+            reply.ok();
+        }
+    }
+
+
+    #[cfg(test)]
+    #[cfg(all(target_os = "linux", feature = "mount-tests"))]
+    mod test {
+        use std::fs;
+        use std::process;
+        use std::thread;
+        use super::{render, NotifyFS, SharedContent, FILE_NAME};
+
+        #[test]
+        fn reads_see_updates() {
+            let env = match fuse::testing::unprivileged_mount_env() {
+                Ok(env) => env,
+                Err(err) => {
+                    eprintln!("Skipping test: {}", err);
+                    return;
+                }
+            };
+            env.run(|env| {
+                let mountpoint = std::env::temp_dir().join(format!("fuse-rs-notify-{}", process::id()));
+                fs::create_dir_all(&mountpoint).unwrap();
+                let content = SharedContent::new();
+                let session = env.mount(NotifyFS::new(content.clone()), &mountpoint).unwrap();
+                let path = mountpoint.join(FILE_NAME);
+
+                // The first read caches the file
+                assert_eq!(fs::read(&path).unwrap(), render(1));
+                for expected in 2..5 {
+                    let (content, notifier) = (content.clone(), session.notifier());
+                    let version = thread::spawn(move || content.update(&notifier)).join().unwrap().unwrap();
+                    assert_eq!(version, expected);
+                    // The cache would serve the previous version for an hour without the update
+                    assert_eq!(fs::read(&path).unwrap(), render(version));
+                }
+                drop(session);
+                fs::remove_dir(&mountpoint).unwrap();
+            }).unwrap();
+        }
+    }
+}
+
+#[cfg(feature = "abi-7-15")]
+fn main() {
+    use std::env;
+    use std::ffi::OsStr;
+    use std::path::Path;
+    use std::time::Duration;
+    use fuse::Session;
+    use notify::{NotifyFS, SharedContent};
+
+    env_logger::init();
+    let mountpoint = env::args_os().nth(1).unwrap();
+    let interval = env::args().nth(2).map_or(1000, |ms| ms.parse().expect("Invalid interval"));
+    let content = SharedContent::new();
+    let options = ["-o", "ro", "-o", "fsname=notify"]
+        .iter()
+        .map(|o| o.as_ref())
+        .collect::<Vec<&OsStr>>();
+    let mut session = Session::new(NotifyFS::new(content.clone()), Path::new(&mountpoint), &options).unwrap();
+    content.update_every(session.notifier(), Duration::from_millis(interval));
+    session.run().unwrap();
+}
+
+#[cfg(not(feature = "abi-7-15"))]
+fn main() {
+    eprintln!("Storing data in the page cache needs ABI 7.15 (build with --features abi-7-15)");
+}
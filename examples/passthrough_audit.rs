@@ -0,0 +1,422 @@
+//! A passthrough filesystem that logs every access. It's mounted over the directory it serves
+//! and fails open: if the filesystem process dies or hangs, it's unmounted right away so that
+//! applications see the original directory again.
+//!
+//! Usage: passthrough_audit <directory> [heartbeat file]
+//! (run with RUST_LOG=info to see the audit log)
+
+#[cfg(target_os = "linux")]
+mod audit {
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+    use std::ffi::{OsStr, OsString};
+    use std::fs::{self, File, OpenOptions};
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::os::unix::fs::{FileExt, OpenOptionsExt, PermissionsExt};
+    use std::os::unix::io::AsRawFd;
+    use std::path::{Path, PathBuf};
+    use std::ffi::CString;
+    use std::io;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use libc::{c_int, EBADF, ENOENT};
+    use log::info;
+    use fuse::{FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyDirectory};
+    use fuse::{ReplyOpen, ReplyWrite, ReplyEmpty, ReplyCreate, ToErrno, FdBudget, FdGuard};
+    use fuse::{InitConfig, SetattrChanges, SetattrStep, TruncateEffect};
+    use fuse::resolve::{FollowPolicy, ResolutionGuard, ResolvedFd};
+    #[cfg(feature = "abi-7-19")]
+    use fuse::FallocateRequest;
+
+    const TTL: Duration = Duration::from_secs(1);           // 1 second
+
+    /// Returns the timespec to set a file time to with utimensat(2)
+    fn timespec(time: Option<SystemTime>) -> libc::timespec {
+        match time.map(|time| time.duration_since(UNIX_EPOCH).unwrap_or_default()) {
+            Some(d) => libc::timespec { tv_sec: d.as_secs() as libc::time_t, tv_nsec: d.subsec_nanos() as libc::c_long },
+            None => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+        }
+    }
+
+    /// Set the permissions of a backing file. A symlink at the end of the path isn't followed
+    /// (symlinks have no permissions of their own on Linux).
+    fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+        let file = OpenOptions::new().read(true).custom_flags(libc::O_PATH | libc::O_NOFOLLOW).open(path)?;
+        if file.metadata()?.file_type().is_symlink() {
+            return Ok(());
+        }
+        fs::set_permissions(format!("/proc/self/fd/{}", file.as_raw_fd()), fs::Permissions::from_mode(mode & 0o7777))
+    }
+
+    /// Apply a step of attribute changes to a backing file
+    fn apply(path: &Path, file: Option<&File>, changes: &SetattrChanges, step: SetattrStep) -> io::Result<()> {
+        let cpath = CString::new(path.as_os_str().as_bytes()).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+        let res = match step {
+            SetattrStep::Size => {
+                let size = changes.size().unwrap_or_default();
+                return match file {
+                    Some(file) => file.set_len(size),
+                    None => OpenOptions::new().write(true).custom_flags(libc::O_NOFOLLOW).open(path)?.set_len(size),
+                };
+            }
+            SetattrStep::Owner => {
+                let uid = changes.uid().unwrap_or(!0);
+                let gid = changes.gid().unwrap_or(!0);
+                unsafe { libc::lchown(cpath.as_ptr(), uid, gid) }
+            }
+            SetattrStep::Mode => return set_mode(path, changes.mode().unwrap_or_default()),
+            SetattrStep::Times => {
+                let times = [timespec(changes.atime()), timespec(changes.mtime())];
+                unsafe { libc::utimensat(libc::AT_FDCWD, cpath.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) }
+            }
+            // File flags only exist on macOS
+            SetattrStep::Flags => 0,
+        };
+        match res {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// Returns file attributes from the metadata of a backing file (with the inode number of
+    /// the backing file)
+    fn attr(meta: &fs::Metadata) -> io::Result<FileAttr> {
+        FileAttr::try_from(meta).map_err(|err| io::Error::from_raw_os_error(err.to_errno()))
+    }
+
+    pub struct AuditFS {
+        /// Handle to the backing directory (which is hidden by the mount)
+        backing: File,
+        /// Resolves paths below the backing directory without escaping it
+        guard: ResolutionGuard,
+        /// Paths of known inodes, relative to the backing directory
+        paths: HashMap<u64, PathBuf>,
+        /// Inodes of known paths
+        inodes: HashMap<PathBuf, u64>,
+        /// Open files, each holding a file descriptor of the budget
+        files: HashMap<u64, (File, FdGuard)>,
+        /// Budget of file descriptors for open files
+        budget: FdBudget,
+        next_ino: u64,
+        next_fh: u64,
+    }
+
+    impl AuditFS {
+        pub fn new(backing: File) -> AuditFS {
+            let mut fs = AuditFS {
+                backing,
+                guard: ResolutionGuard::new(),
+                paths: HashMap::new(),
+                inodes: HashMap::new(),
+                files: HashMap::new(),
+                budget: FdBudget::with_limit(1024),
+                next_ino: 1,
+                next_fh: 1,
+            };
+            fs.inode(PathBuf::new());
+            fs
+        }
+
+        /// Resolves a path to a backing file (a symlink at the end isn't followed). Symlinks
+        /// on the way are followed, but never out of the backing directory (which is also
+        /// resolved directly, not through the filesystem that is mounted over it).
+        fn resolve(&self, path: &Path) -> io::Result<ResolvedFd> {
+            self.guard.resolve(self.backing.as_raw_fd(), path, FollowPolicy::NoFollow)
+        }
+
+        /// Returns the path of a backing file for calls that don't follow a symlink at the end
+        /// of the path. The path goes through the resolved parent directory, which is returned
+        /// as well and must be kept open while using the path.
+        fn real(&self, path: &Path) -> io::Result<(ResolvedFd, PathBuf)> {
+            if path.file_name().is_none() {
+                let dir = self.resolve(path)?;
+                let real = dir.proc_path().join(".");
+                return Ok((dir, real));
+            }
+            let (dir, name) = self.guard.resolve_parent(self.backing.as_raw_fd(), path)?;
+            let real = dir.proc_path().join(name);
+            Ok((dir, real))
+        }
+
+        /// Returns the inode of the given path, allocating a new one if needed
+        fn inode(&mut self, path: PathBuf) -> u64 {
+            if let Some(&ino) = self.inodes.get(&path) {
+                return ino;
+            }
+            let ino = self.next_ino;
+            self.next_ino += 1;
+            self.paths.insert(ino, path.clone());
+            self.inodes.insert(path, ino);
+            ino
+        }
+
+        fn path(&self, ino: u64) -> Option<PathBuf> {
+            self.paths.get(&ino).cloned()
+        }
+
+        fn child(&self, parent: u64, name: &OsStr) -> Option<PathBuf> {
+            self.paths.get(&parent).map(|p| p.join(name))
+        }
+
+        fn audit(&self, req: &Request<'_>, op: &str, path: &Path) {
+            info!("uid {} pid {} {} /{}", req.uid(), req.pid(), op, path.display());
+        }
+
+        fn entry(&mut self, path: PathBuf, reply: ReplyEntry) {
+            match self.real(&path).and_then(|(_dir, real)| fs::symlink_metadata(real)).and_then(|meta| attr(&meta)) {
+                Ok(attr) => {
+                    let ino = self.inode(path);
+                    reply.entry(&TTL, &FileAttr { ino, ..attr }, 0);
+                }
+                Err(err) => reply.error(err.to_errno()),
+            }
+        }
+
+        fn add_file(&mut self, file: File, guard: FdGuard) -> u64 {
+            let fh = self.next_fh;
+            self.next_fh += 1;
+            self.files.insert(fh, (file, guard));
+            fh
+        }
+    }
+
+    impl Filesystem for AuditFS {
+        fn init(&mut self, _req: &Request<'_>) -> Result<InitConfig, c_int> {
+            // The session raised the limit of open files when mounting
+            if let Ok(budget) = FdBudget::new() {
+                self.budget = budget;
+            }
+            Ok(InitConfig::default())
+        }
+
+        fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+            match self.child(parent, name) {
+                Some(path) => self.entry(path, reply),
+                None => reply.error(ENOENT),
+            }
+        }
+
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+            let path = match self.path(ino) { Some(path) => path, None => return reply.error(ENOENT) };
+            match self.real(&path).and_then(|(_dir, real)| fs::symlink_metadata(real)).and_then(|meta| attr(&meta)) {
+                Ok(attr) => reply.attr(&TTL, &FileAttr { ino, ..attr }),
+                Err(err) => reply.error(err.to_errno()),
+            }
+        }
+
+        fn setattr_changes(&mut self, req: &Request<'_>, ino: u64, changes: SetattrChanges, reply: ReplyAttr) {
+            let path = match self.path(ino) { Some(path) => path, None => return reply.error(ENOENT) };
+            let (_dir, real) = match self.real(&path) { Ok(real) => real, Err(err) => return reply.error(err.to_errno()) };
+            let meta = match fs::symlink_metadata(&real) { Ok(meta) => meta, Err(err) => return reply.error(err.to_errno()) };
+            match changes.truncate(meta.len()) {
+                TruncateEffect::DiscardFrom(offset) => self.audit(req, &format!("truncate (discarding from {})", offset), &path),
+                TruncateEffect::ZeroExtendTo(size) => self.audit(req, &format!("truncate (extending to {})", size), &path),
+                TruncateEffect::NoChange => (),
+            }
+            if changes.uid().is_some() || changes.gid().is_some() || changes.mode().is_some() {
+                self.audit(req, "setattr", &path);
+            }
+            let file = changes.fh().and_then(|fh| self.files.get(&fh)).map(|(file, _)| file);
+            for step in changes.apply_order() {
+                if let Err(err) = apply(&real, file, &changes, step) {
+                    return reply.error(err.to_errno());
+                }
+            }
+            match fs::symlink_metadata(&real).and_then(|meta| attr(&meta)) {
+                Ok(attr) => reply.attr(&TTL, &FileAttr { ino, ..attr }),
+                Err(err) => reply.error(err.to_errno()),
+            }
+        }
+
+        fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData) {
+            let path = match self.path(ino) { Some(path) => path, None => return reply.error(ENOENT) };
+            self.audit(req, "readlink", &path);
+            match self.real(&path).and_then(|(_dir, real)| fs::read_link(real)) {
+                Ok(target) => reply.data(target.as_os_str().as_bytes()),
+                Err(err) => reply.error(err.to_errno()),
+            }
+        }
+
+        fn mkdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+            let path = match self.child(parent, name) { Some(path) => path, None => return reply.error(ENOENT) };
+            self.audit(req, "mkdir", &path);
+            let res = self.real(&path).and_then(|(_dir, real)| fs::create_dir(&real).and_then(|()| set_mode(&real, mode)));
+            match res {
+                Ok(()) => self.entry(path, reply),
+                Err(err) => reply.error(err.to_errno()),
+            }
+        }
+
+        fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+            let path = match self.child(parent, name) { Some(path) => path, None => return reply.error(ENOENT) };
+            self.audit(req, "unlink", &path);
+            match self.real(&path).and_then(|(_dir, real)| fs::remove_file(real)) {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.to_errno()),
+            }
+        }
+
+        fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+            let path = match self.child(parent, name) { Some(path) => path, None => return reply.error(ENOENT) };
+            self.audit(req, "rmdir", &path);
+            match self.real(&path).and_then(|(_dir, real)| fs::remove_dir(real)) {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.to_errno()),
+            }
+        }
+
+        fn open(&mut self, req: &Request<'_>, ino: u64, flags: u32, reply: ReplyOpen) {
+            let path = match self.path(ino) { Some(path) => path, None => return reply.error(ENOENT) };
+            self.audit(req, "open", &path);
+            let guard = match self.budget.acquire() { Ok(guard) => guard, Err(err) => return reply.error(err.to_errno()) };
+            let accmode = flags as i32 & libc::O_ACCMODE;
+            let res = self.real(&path).and_then(|(_dir, real)| {
+                OpenOptions::new()
+                    .read(accmode != libc::O_WRONLY)
+                    .write(accmode != libc::O_RDONLY)
+                    .custom_flags(flags as i32 & libc::O_APPEND | libc::O_NOFOLLOW)
+                    .open(real)
+            });
+            match res {
+                Ok(file) => reply.opened(self.add_file(file, guard), 0),
+                Err(err) => reply.error(err.to_errno()),
+            }
+        }
+
+        fn read(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyData) {
+            let file = match self.files.get(&fh) { Some((file, _)) => file, None => return reply.error(EBADF) };
+            let mut buf = vec![0; size as usize];
+            match file.read_at(&mut buf, offset as u64) {
+                Ok(n) => reply.data(&buf[..n]),
+                Err(err) => reply.error(err.to_errno()),
+            }
+        }
+
+        fn write(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, offset: i64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+            let file = match self.files.get(&fh) { Some((file, _)) => file, None => return reply.error(EBADF) };
+            match file.write_at(data, offset as u64) {
+                Ok(n) => reply.written(n as u32),
+                Err(err) => reply.error(err.to_errno()),
+            }
+        }
+
+        #[cfg(feature = "abi-7-19")]
+        fn fallocate(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, fallocate: FallocateRequest, reply: ReplyEmpty) {
+            let file = match self.files.get(&fh) { Some((file, _)) => file, None => return reply.error(EBADF) };
+            match unsafe { libc::fallocate(file.as_raw_fd(), fallocate.mode(), fallocate.offset(), fallocate.length()) } {
+                0 => reply.ok(),
+                _ => reply.error(io::Error::last_os_error().to_errno()),
+            }
+        }
+
+        #[cfg(feature = "abi-7-24")]
+        fn lseek(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, offset: i64, whence: i32, reply: fuse::ReplyLseek) {
+            let file = match self.files.get(&fh) { Some((file, _)) => file, None => return reply.error(EBADF) };
+            match unsafe { libc::lseek(file.as_raw_fd(), offset, whence) } {
+                -1 => reply.error(io::Error::last_os_error().to_errno()),
+                offset => reply.offset(offset),
+            }
+        }
+
+        fn release(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
+            self.files.remove(&fh);
+            reply.ok();
+        }
+
+        fn readdir(&mut self, req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+            let path = match self.path(ino) { Some(path) => path, None => return reply.error(ENOENT) };
+            if offset == 0 {
+                self.audit(req, "readdir", &path);
+            }
+            let dir = match self.resolve(&path).and_then(|dir| fs::read_dir(dir.proc_path())) { Ok(dir) => dir, Err(err) => return reply.error(err.to_errno()) };
+            let mut entries: Vec<(OsString, FileType)> = vec![
+                (".".into(), FileType::Directory),
+                ("..".into(), FileType::Directory),
+            ];
+            for entry in dir.filter_map(Result::ok) {
+                let kind = match entry.file_type() {
+                    Ok(ft) if ft.is_dir() => FileType::Directory,
+                    Ok(ft) if ft.is_symlink() => FileType::Symlink,
+                    _ => FileType::RegularFile,
+                };
+                entries.push((entry.file_name(), kind));
+            }
+            for (i, (name, kind)) in entries.into_iter().enumerate().skip(offset as usize) {
+                let ino = match name.as_bytes() {
+                    b"." | b".." => ino,
+                    _ => self.inode(path.join(&name)),
+                };
+                // i + 1 means the index of the next entry
+                if reply.add(ino, (i + 1) as i64, kind, &name) {
+                    break;
+                }
+            }
+            reply.ok();
+        }
+
+        fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+            let path = match self.path(ino) { Some(path) => path, None => return reply.error(ENOENT) };
+            self.audit(req, &format!("removexattr {:?}", name), &path);
+            let (_dir, real) = match self.real(&path) { Ok(real) => real, Err(err) => return reply.error(err.to_errno()) };
+            let cpath = match CString::new(real.into_os_string().into_vec()) { Ok(cpath) => cpath, Err(_) => return reply.error(libc::EINVAL) };
+            let cname = match CString::new(name.as_bytes()) { Ok(cname) => cname, Err(_) => return reply.error(libc::EINVAL) };
+            match unsafe { libc::lremovexattr(cpath.as_ptr(), cname.as_ptr()) } {
+                0 => reply.ok(),
+                _ => reply.error(io::Error::last_os_error().to_errno()),
+            }
+        }
+
+        fn create(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, flags: u32, reply: ReplyCreate) {
+            let path = match self.child(parent, name) { Some(path) => path, None => return reply.error(ENOENT) };
+            self.audit(req, "create", &path);
+            let guard = match self.budget.acquire() { Ok(guard) => guard, Err(err) => return reply.error(err.to_errno()) };
+            let res = self.real(&path).and_then(|(_dir, real)| {
+                OpenOptions::new()
+                    .read(flags as i32 & libc::O_ACCMODE != libc::O_WRONLY)
+                    .write(true)
+                    .create(true)
+                    .truncate(flags as i32 & libc::O_TRUNC != 0)
+                    .mode(mode & 0o7777)
+                    .custom_flags(libc::O_NOFOLLOW)
+                    .open(real)
+            }).and_then(|file| file.metadata().and_then(|meta| attr(&meta)).map(|attr| (file, attr)));
+            match res {
+                Ok((file, attr)) => {
+                    let ino = self.inode(path);
+                    let fh = self.add_file(file, guard);
+                    reply.created(&TTL, &FileAttr { ino, ..attr }, 0, fh, 0);
+                }
+                Err(err) => reply.error(err.to_errno()),
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn main() {
+    use std::env;
+    use std::time::Duration;
+    use fuse::SessionBuilder;
+    use fuse::supervisor::FailOpen;
+
+    env_logger::init();
+    let dir = env::args_os().nth(1).unwrap();
+    // Backing files are written with the privileges of this process, so file capabilities
+    // need to be removed when others write to them (see SessionBuilder::safe_defaults)
+    let mut supervisor = FailOpen::new();
+    if let Some(heartbeat) = env::args_os().nth(2) {
+        supervisor = supervisor.heartbeat(heartbeat, Duration::from_secs(1)).watchdog(Duration::from_secs(5));
+    }
+    let options = ["-o", "fsname=audit", "-o", "nonempty"]
+        .iter()
+        .map(|o| o.as_ref())
+        .collect::<Vec<&std::ffi::OsStr>>();
+    if let Err(err) = supervisor.run(SessionBuilder::new().raise_fd_limit().safe_defaults(), dir, &options, audit::AuditFS::new) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("Fail-open supervision is only supported on Linux");
+}
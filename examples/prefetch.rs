@@ -0,0 +1,364 @@
+//! A read-only filesystem with a single large file on a slow backend. Once the file is read
+//! sequentially, the following chunks are fetched from the backend in background tasks and
+//! pushed to the kernel driver's page cache with `Notifier::store`, so that further reads
+//! are served from the page cache without reaching the filesystem. Hits and misses of the
+//! prefetching are printed when the filesystem is unmounted.
+//!
+//! Usage: prefetch <mountpoint> [backend latency in ms]
+
+#[cfg(feature = "abi-7-15")]
+mod prefetch {
+    use std::collections::{HashMap, HashSet};
+    use std::ffi::OsStr;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::thread;
+    use std::time::{Duration, UNIX_EPOCH};
+    use libc::ENOENT;
+    use fuse::{FileType, FileAttr, Filesystem, Notifier, OpenFlags, Request, SequentialReadDetector, Advice};
+    use fuse::{ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen};
+
+    const TTL: Duration = Duration::from_secs(1);           // 1 second
+
+    /// Size of chunks that are fetched from the backend
+    pub const CHUNK_SIZE: u64 = 128 * 1024;
+
+    /// Number of chunks of the file (16 MiB)
+    pub const FILE_CHUNKS: u64 = 128;
+
+    pub const FILE_SIZE: u64 = CHUNK_SIZE * FILE_CHUNKS;
+
+    pub const FILE_NAME: &str = "data.bin";
+
+    /// Number of chunks that are prefetched ahead of sequential reads
+    const PREFETCH_CHUNKS: u64 = 16;
+
+    const DIR_ATTR: FileAttr = FileAttr {
+        ino: 1,
+        size: 0,
+        blocks: 0,
+        atime: UNIX_EPOCH,                                  // 1970-01-01 00:00:00
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind: FileType::Directory,
+        perm: 0o755,
+        nlink: 2,
+        uid: 501,
+        gid: 20,
+        rdev: 0,
+        flags: 0,
+    };
+
+    const FILE_ATTR: FileAttr = FileAttr {
+        ino: 2,
+        size: FILE_SIZE,
+        blocks: FILE_SIZE / 512,
+        kind: FileType::RegularFile,
+        perm: 0o444,
+        nlink: 1,
+        ..DIR_ATTR
+    };
+
+    /// Returns the number of chunks that start before the given position
+    fn chunks_before(pos: u64) -> u64 {
+        match pos % CHUNK_SIZE {
+            0 => pos / CHUNK_SIZE,
+            _ => pos / CHUNK_SIZE + 1,
+        }
+    }
+
+    /// Returns the byte of the file at the given position
+    pub fn content(pos: u64) -> u8 {
+        (pos % 251) as u8
+    }
+
+    /// Simulated backend (e.g. network storage) that takes a while to answer every read
+    #[derive(Clone, Copy, Debug)]
+    pub struct Backend {
+        latency: Duration,
+    }
+
+    impl Backend {
+        pub fn new(latency: Duration) -> Backend {
+            Backend { latency }
+        }
+
+        /// Read up to the given number of bytes of the file at the given offset
+        fn read(&self, offset: u64, size: u64) -> Vec<u8> {
+            thread::sleep(self.latency);
+            let end = offset.saturating_add(size).min(FILE_SIZE);
+            (offset.min(end)..end).map(content).collect()
+        }
+    }
+
+    /// Metrics of the prefetching
+    #[derive(Debug, Default)]
+    pub struct Stats {
+        /// Reads that reached the filesystem (i.e. weren't served from the page cache)
+        pub reads: AtomicU64,
+        /// Chunks that were prefetched and stored in the page cache
+        pub stored: AtomicU64,
+        /// Prefetched chunks that were read by the filesystem anyway (e.g. because the kernel
+        /// driver evicted them, or read them before they were stored)
+        pub wasted: AtomicU64,
+    }
+
+    /// Sequential read stream of an open file
+    #[derive(Debug, Default)]
+    struct Stream {
+        detector: SequentialReadDetector,
+        /// Range of the file that was prefetched for this stream, if it's read sequentially
+        window: Option<(u64, u64)>,
+    }
+
+    pub struct PrefetchFS {
+        backend: Backend,
+        /// Notifier of the session, which is only known after mounting. Nothing is
+        /// prefetched until it's set.
+        notifier: Arc<Mutex<Option<Notifier>>>,
+        streams: HashMap<u64, Stream>,
+        next_fh: u64,
+        /// Indexes of chunks that were stored in the page cache
+        prefetched: Arc<Mutex<HashSet<u64>>>,
+        stats: Arc<Stats>,
+    }
+
+    impl PrefetchFS {
+        pub fn new(backend: Backend, stats: Arc<Stats>) -> PrefetchFS {
+            PrefetchFS {
+                backend,
+                notifier: Arc::default(),
+                streams: HashMap::new(),
+                next_fh: 1,
+                prefetched: Arc::default(),
+                stats,
+            }
+        }
+
+        /// Returns the slot to set the notifier of the session in once mounted
+        pub fn notifier_slot(&self) -> Arc<Mutex<Option<Notifier>>> {
+            self.notifier.clone()
+        }
+
+        /// Fetch the given chunks in background tasks and store them in the page cache
+        fn prefetch(&self, req: &Request<'_>, chunks: &[u64]) {
+            let notifier = match *self.notifier.lock().unwrap() {
+                Some(ref notifier) => notifier.clone(),
+                None => return,
+            };
+            for &chunk in chunks {
+                let (backend, notifier) = (self.backend, notifier.clone());
+                let (prefetched, stats) = (self.prefetched.clone(), self.stats.clone());
+                let res = req.spawner().spawn(move |token| {
+                    if token.is_shutdown() {
+                        return;
+                    }
+                    let data = backend.read(chunk * CHUNK_SIZE, CHUNK_SIZE);
+                    match notifier.store(FILE_ATTR.ino, chunk * CHUNK_SIZE, &data) {
+                        Ok(()) => {
+                            prefetched.lock().unwrap().insert(chunk);
+                            stats.stored.fetch_add(1, Ordering::Relaxed);
+                        }
+                        // Storing fails once unmounted, which doesn't need to be reported
+                        Err(err) => if !token.is_shutdown() {
+                            eprintln!("Failed to store chunk {}: {}", chunk, err);
+                        },
+                    }
+                });
+                if let Err(err) = res {
+                    eprintln!("Failed to spawn task: {}", err);
+                }
+            }
+        }
+    }
+
+    impl Drop for PrefetchFS {
+        /// Print the metrics once unmounted and all tasks finished
+        fn drop(&mut self) {
+            let reads = self.stats.reads.load(Ordering::Relaxed);
+            let stored = self.stats.stored.load(Ordering::Relaxed);
+            let wasted = self.stats.wasted.load(Ordering::Relaxed);
+            println!("Reads (misses): {}", reads);
+            println!("Prefetched chunks: {} ({} hits, {} wasted)", stored, stored - wasted, wasted);
+        }
+    }
+
+    impl Filesystem for PrefetchFS {
+        fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+            if parent == 1 && name == FILE_NAME {
+                reply.entry(&TTL, &FILE_ATTR, 0);
+            } else {
+                reply.error(ENOENT);
+            }
+        }
+
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+            match ino {
+                1 => reply.attr(&TTL, &DIR_ATTR),
+                2 => reply.attr(&TTL, &FILE_ATTR),
+                _ => reply.error(ENOENT),
+            }
+        }
+
+        fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: u32, reply: ReplyOpen) {
+            if ino != 2 {
+                reply.error(ENOENT);
+                return;
+            }
+            let fh = self.next_fh;
+            self.next_fh += 1;
+            self.streams.insert(fh, Stream::default());
+            // Opening the file must not drop prefetched data from the page cache
+            reply.opened(fh, OpenFlags::new().keep_cache().bits());
+        }
+
+        fn read(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyData) {
+            if ino != 2 {
+                reply.error(ENOENT);
+                return;
+            }
+            self.stats.reads.fetch_add(1, Ordering::Relaxed);
+            let offset = offset as u64;
+            let end = offset.saturating_add(u64::from(size)).min(FILE_SIZE);
+            {
+                let mut prefetched = self.prefetched.lock().unwrap();
+                for chunk in offset / CHUNK_SIZE..chunks_before(end) {
+                    if prefetched.remove(&chunk) {
+                        self.stats.wasted.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+
+            let mut chunks = Vec::new();
+            if let Some(stream) = self.streams.get_mut(&fh) {
+                stream.detector.read(offset as i64, size);
+                // Reads within or right after the prefetched range continue the stream, since
+                // the reads of prefetched chunks don't reach the filesystem
+                stream.window = match stream.window {
+                    Some((start, ahead)) if offset >= start && offset <= ahead => Some((start, ahead)),
+                    _ if stream.detector.advice() == Advice::Sequential => Some((end, end)),
+                    _ => None,
+                };
+                if let Some((start, ahead)) = stream.window {
+                    let first = chunks_before(ahead.max(end));
+                    let last = (end / CHUNK_SIZE + PREFETCH_CHUNKS).min(FILE_CHUNKS);
+                    chunks.extend(first..last);
+                    stream.window = Some((start, ahead.max(last * CHUNK_SIZE)));
+                }
+            }
+            self.prefetch(req, &chunks);
+
+            reply.data(&self.backend.read(offset, end.saturating_sub(offset)));
+        }
+
+        fn release(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
+            self.streams.remove(&fh);
+            reply.ok();
+        }
+
+        fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+            if ino != 1 {
+                reply.error(ENOENT);
+                return;
+            }
+
+            let entries = vec![
+                (1, FileType::Directory, "."),
+                (1, FileType::Directory, ".."),
+                (2, FileType::RegularFile, FILE_NAME),
+            ];
+
+            for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
+                // i + 1 means the index of the next entry
+                reply.add(entry.0, (i + 1) as i64, entry.1, entry.2);
+            }
+            reply.ok();
+        }
+    }
+
+
+    #[cfg(test)]
+    #[cfg(all(target_os = "linux", feature = "mount-tests"))]
+    mod test {
+        use std::fs::{self, File};
+        use std::io::Read;
+        use std::process;
+        use std::sync::Arc;
+        use std::sync::atomic::Ordering;
+        use std::time::Duration;
+        use super::{content, Backend, PrefetchFS, Stats, CHUNK_SIZE, FILE_CHUNKS, FILE_NAME, FILE_SIZE};
+
+        #[test]
+        fn sequential_scan() {
+            let env = match fuse::testing::unprivileged_mount_env() {
+                Ok(env) => env,
+                Err(err) => {
+                    eprintln!("Skipping test: {}", err);
+                    return;
+                }
+            };
+            env.run(|env| {
+                let mountpoint = std::env::temp_dir().join(format!("fuse-rs-prefetch-{}", process::id()));
+                fs::create_dir_all(&mountpoint).unwrap();
+                let stats = Arc::new(Stats::default());
+                let filesystem = PrefetchFS::new(Backend::new(Duration::from_millis(5)), stats.clone());
+                let notifier = filesystem.notifier_slot();
+                let session = env.mount(filesystem, &mountpoint).unwrap();
+                *notifier.lock().unwrap() = Some(session.notifier());
+
+                let mut file = File::open(mountpoint.join(FILE_NAME)).unwrap();
+                let mut buf = vec![0; CHUNK_SIZE as usize];
+                let mut pos = 0;
+                loop {
+                    let len = file.read(&mut buf).unwrap();
+                    if len == 0 {
+                        break;
+                    }
+                    for (i, &byte) in buf[..len].iter().enumerate() {
+                        assert_eq!(byte, content(pos + i as u64), "Wrong content at {}", pos + i as u64);
+                    }
+                    pos += len as u64;
+                }
+                assert_eq!(pos, FILE_SIZE);
+                drop(file);
+                drop(session);
+                fs::remove_dir(&mountpoint).unwrap();
+
+                // Most chunks were served from the page cache
+                let reads = stats.reads.load(Ordering::Relaxed);
+                assert!(stats.stored.load(Ordering::Relaxed) > 0);
+                assert!(reads < FILE_CHUNKS / 4, "{} reads reached the filesystem for {} chunks", reads, FILE_CHUNKS);
+            }).unwrap();
+        }
+    }
+}
+
+#[cfg(feature = "abi-7-15")]
+fn main() {
+    use std::env;
+    use std::ffi::OsStr;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use fuse::Session;
+    use prefetch::{Backend, PrefetchFS, Stats};
+
+    env_logger::init();
+    let mountpoint = env::args_os().nth(1).unwrap();
+    let latency = env::args().nth(2).map_or(20, |ms| ms.parse().expect("Invalid latency"));
+    let filesystem = PrefetchFS::new(Backend::new(Duration::from_millis(latency)), Arc::new(Stats::default()));
+    let notifier = filesystem.notifier_slot();
+    let options = ["-o", "ro", "-o", "fsname=prefetch"]
+        .iter()
+        .map(|o| o.as_ref())
+        .collect::<Vec<&OsStr>>();
+    let mut session = Session::new(filesystem, Path::new(&mountpoint), &options).unwrap();
+    *notifier.lock().unwrap() = Some(session.notifier());
+    session.run().unwrap();
+}
+
+#[cfg(not(feature = "abi-7-15"))]
+fn main() {
+    eprintln!("Storing data in the page cache needs ABI 7.15 (build with --features abi-7-15)");
+}
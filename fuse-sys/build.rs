@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 #[cfg(not(target_os = "macos"))]
 const LIBFUSE_NAME: &str = "fuse";
 
@@ -5,9 +7,67 @@ const LIBFUSE_NAME: &str = "fuse";
 const LIBFUSE_NAME: &str = "osxfuse";
 
 fn main() {
-    pkg_config::Config::new()
+    let statik = cfg!(feature = "static");
+    // When linking statically, the search path and library name are emitted by `link_static`
+    // since pkg-config falls back to dynamic linking if the archive is in a system directory
+    let library = pkg_config::Config::new()
         .atleast_version("2.6.0")
+        .statik(statik)
+        .cargo_metadata(!statik)
         .probe(LIBFUSE_NAME)
         .map_err(|e| eprintln!("{}", e))
         .unwrap();
+    let linkage = if statik { link_static(&library) } else { "dynamic" };
+    println!("cargo:rustc-env=FUSE_SYS_LINKAGE={}", linkage);
+    println!("cargo:linkage={}", linkage);
+
+    #[cfg(feature = "bindgen")]
+    generate_bindings(&library);
+}
+
+/// Link the static archive of libfuse and its dependencies. Fails if there's no archive.
+fn link_static(library: &pkg_config::Library) -> &'static str {
+    let archive = format!("lib{}.a", LIBFUSE_NAME);
+    let mut dirs = library.link_paths.clone();
+    match pkg_config::get_variable(LIBFUSE_NAME, "libdir") {
+        Ok(ref libdir) if !libdir.is_empty() => dirs.push(PathBuf::from(libdir)),
+        _ => (),
+    }
+    let dir = dirs.iter().find(|dir| dir.join(&archive).is_file()).unwrap_or_else(|| {
+        panic!("Static linking of libfuse requested (feature `static`), but {} wasn't found in {:?}. \
+                Install the static library of libfuse or build without the `static` feature.", archive, dirs)
+    });
+    for dir in &dirs {
+        println!("cargo:rustc-link-search=native={}", dir.display());
+    }
+    println!("cargo:rustc-link-lib=static={}", LIBFUSE_NAME);
+    // Libraries that libfuse depends on (e.g. pthread, dl) are linked as usual
+    for lib in library.libs.iter().filter(|lib| *lib != LIBFUSE_NAME) {
+        println!("cargo:rustc-link-lib={}", lib);
+    }
+    println!("cargo:warning=Linking libfuse statically ({})", dir.join(&archive).display());
+    "static"
+}
+
+/// Generate bindings from the installed libfuse headers into OUT_DIR
+#[cfg(feature = "bindgen")]
+fn generate_bindings(library: &pkg_config::Library) {
+    println!("cargo:rerun-if-changed=wrapper.h");
+    let includes = library.include_paths.iter().map(|path| format!("-I{}", path.display()));
+    let defines = library.defines.iter().map(|(name, value)| match value {
+        Some(value) => format!("-D{}={}", name, value),
+        None => format!("-D{}", name),
+    });
+    let out = PathBuf::from(std::env::var_os("OUT_DIR").unwrap()).join("bindings.rs");
+    bindgen::Builder::default()
+        .header("wrapper.h")
+        .clang_args(includes.chain(defines))
+        .whitelist_function("fuse_mount_compat25")
+        .whitelist_function("fuse_unmount_compat22")
+        .layout_tests(false)
+        .generate_comments(false)
+        .generate()
+        .unwrap_or_else(|()| panic!("Failed to generate bindings from the libfuse headers"))
+        .write_to_file(&out)
+        .unwrap_or_else(|err| panic!("Failed to write bindings to {}: {}", out.display(), err));
 }
@@ -0,0 +1,19 @@
+// Bindings to libfuse as generated by bindgen from wrapper.h. To update them, build with the
+// `bindgen` feature and copy the generated bindings.rs from the build's OUT_DIR here.
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct fuse_args {
+    pub argc: ::std::os::raw::c_int,
+    pub argv: *mut *mut ::std::os::raw::c_char,
+    pub allocated: ::std::os::raw::c_int,
+}
+extern "C" {
+    pub fn fuse_mount_compat25(
+        mountpoint: *const ::std::os::raw::c_char,
+        args: *mut fuse_args,
+    ) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    pub fn fuse_unmount_compat22(mountpoint: *const ::std::os::raw::c_char);
+}
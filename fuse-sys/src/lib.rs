@@ -2,24 +2,40 @@
 //!
 //! This is a small set of bindings that are required to mount/unmount FUSE filesystems and
 //! open/close a fd to the FUSE kernel driver.
+//!
+//! The bindings are checked in, so that builds don't depend on the installed libfuse headers.
+//! With the `bindgen` feature, they're generated from the installed headers at build time
+//! instead. With the `static` feature, libfuse is linked statically (see `LINKAGE`).
 
 #![warn(missing_debug_implementations, rust_2018_idioms)]
-#![allow(missing_docs)]
+#![allow(missing_docs, non_camel_case_types)]
 
-use std::os::raw::{c_char, c_int};
+#[cfg(not(feature = "bindgen"))]
+include!("bindings.rs");
+
+#[cfg(feature = "bindgen")]
+include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+
+/// How libfuse is linked, either "static" or "dynamic"
+pub const LINKAGE: &str = env!("FUSE_SYS_LINKAGE");
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct fuse_args {
-    pub argc: c_int,
-    pub argv: *const *const c_char,
-    pub allocated: c_int,
-}
 
-extern "C" {
-    // *_compat25 functions were introduced in FUSE 2.6 when function signatures changed.
-    // Therefore, the minimum version requirement for *_compat25 functions is libfuse-2.6.0.
+#[cfg(all(test, feature = "bindgen"))]
+mod test {
+    /// Strip comments and formatting from the given Rust source
+    fn normalize(source: &str) -> String {
+        let tokens: String = source.lines()
+            .filter(|line| !line.trim_start().starts_with("//"))
+            .flat_map(str::split_whitespace)
+            .collect();
+        tokens.replace(",)", ")").replace(",}", "}")
+    }
 
-    pub fn fuse_mount_compat25(mountpoint: *const c_char, args: *const fuse_args) -> c_int;
-    pub fn fuse_unmount_compat22(mountpoint: *const c_char);
+    #[test]
+    fn checked_in_bindings() {
+        let generated = include_str!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+        let checked_in = include_str!("bindings.rs");
+        assert_eq!(normalize(generated), normalize(checked_in),
+                   "checked-in bindings differ from bindgen output, update src/bindings.rs");
+    }
 }
@@ -0,0 +1,9 @@
+/* Headers of the libfuse functions that fuse-sys has bindings for */
+
+#define FUSE_USE_VERSION 26
+
+#include <fuse_common.h>
+
+/* *_compat25 functions were introduced in FUSE 2.6 when function signatures changed.
+ * Therefore, the minimum version requirement for *_compat25 functions is libfuse-2.6.0. */
+#include <fuse_common_compat.h>
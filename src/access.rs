@@ -0,0 +1,141 @@
+//! Cache of access checks
+//!
+//! Some applications call `access` over and over for the same files (e.g. shells resolving
+//! commands in PATH). For filesystems with expensive permission checks, the session can
+//! remember the results for a short time and reply to repeated checks without calling the
+//! filesystem.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use libc::c_int;
+
+use crate::ll;
+
+/// Max number of cached results. Expired results are dropped when the cache is full, and if
+/// that doesn't help, all of them.
+const MAX_ENTRIES: usize = 4096;
+
+/// Identifies an access check
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct AccessKey {
+    pub uid: u32,
+    pub gid: u32,
+    pub ino: u64,
+    pub mask: u32,
+}
+
+/// Results of access checks (0 or an error code) and when they expire
+#[derive(Debug)]
+pub struct AccessCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<AccessKey, (c_int, Instant)>>,
+}
+
+impl AccessCache {
+    /// Create a new cache that keeps results for the given time
+    pub fn new(ttl: Duration) -> AccessCache {
+        AccessCache { ttl, entries: Mutex::default() }
+    }
+
+    /// Returns the cached result of the given access check, if it didn't expire yet
+    pub fn get(&self, key: &AccessKey) -> Option<c_int> {
+        self.get_at(key, Instant::now())
+    }
+
+    fn get_at(&self, key: &AccessKey, now: Instant) -> Option<c_int> {
+        match self.entries.lock().unwrap().get(key) {
+            Some(&(result, expires)) if now < expires => Some(result),
+            _ => None,
+        }
+    }
+
+    /// Remember the result of the given access check. Only grants and permission errors are
+    /// cached, other errors may be temporary.
+    pub fn insert(&self, key: AccessKey, result: c_int) {
+        self.insert_at(key, result, Instant::now())
+    }
+
+    fn insert_at(&self, key: AccessKey, result: c_int, now: Instant) {
+        if result != 0 && result != libc::EACCES && result != libc::EPERM { return; }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, &mut (_, expires)| now < expires);
+            if entries.len() >= MAX_ENTRIES {
+                entries.clear();
+            }
+        }
+        entries.insert(key, (result, now + self.ttl));
+    }
+
+    /// Forget results of inodes whose permissions the given request may change. Changing the
+    /// mode, owner or ACLs (extended attributes) of an inode changes the outcome of access
+    /// checks, and a forgotten inode number may be reused for another file.
+    pub fn observe(&self, request: &ll::Request<'_>) {
+        let forget = |ino: u64| self.entries.lock().unwrap().retain(|key, _| key.ino != ino);
+        match request.operation() {
+            ll::Operation::SetAttr { .. } | ll::Operation::SetXAttr { .. } | ll::Operation::RemoveXAttr { .. } |
+            ll::Operation::Forget { .. } => forget(request.nodeid()),
+            #[cfg(feature = "abi-7-16")]
+            ll::Operation::BatchForget { nodes, .. } => {
+                for node in nodes.iter() {
+                    forget(node.nodeid);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    use super::{AccessCache, AccessKey, MAX_ENTRIES};
+
+    fn key(ino: u64) -> AccessKey {
+        AccessKey { uid: 1000, gid: 1000, ino, mask: libc::X_OK as u32 }
+    }
+
+    #[test]
+    fn expires() {
+        let cache = AccessCache::new(Duration::from_secs(1));
+        let now = Instant::now();
+        cache.insert_at(key(2), 0, now);
+        cache.insert_at(key(3), libc::EACCES, now);
+        assert_eq!(cache.get_at(&key(2), now + Duration::from_millis(500)), Some(0));
+        assert_eq!(cache.get_at(&key(3), now), Some(libc::EACCES));
+        assert_eq!(cache.get_at(&key(2), now + Duration::from_secs(1)), None);
+        // Other users, groups or masks are checked separately
+        assert_eq!(cache.get_at(&AccessKey { uid: 0, ..key(2) }, now), None);
+        assert_eq!(cache.get_at(&AccessKey { gid: 0, ..key(2) }, now), None);
+        assert_eq!(cache.get_at(&AccessKey { mask: libc::W_OK as u32, ..key(2) }, now), None);
+    }
+
+    #[test]
+    fn temporary_errors() {
+        let cache = AccessCache::new(Duration::from_secs(60));
+        cache.insert(key(2), libc::EIO);
+        cache.insert(key(3), libc::EPERM);
+        assert_eq!(cache.get(&key(2)), None);
+        assert_eq!(cache.get(&key(3)), Some(libc::EPERM));
+    }
+
+    #[test]
+    fn bounded() {
+        let cache = AccessCache::new(Duration::from_secs(1));
+        let now = Instant::now();
+        for ino in 0..MAX_ENTRIES as u64 {
+            cache.insert_at(key(ino), 0, now);
+        }
+        // Expired results make room
+        let later = now + Duration::from_secs(2);
+        cache.insert_at(key(1 << 32), 0, later);
+        assert_eq!(cache.entries.lock().unwrap().len(), 1);
+        for ino in 0..MAX_ENTRIES as u64 {
+            cache.insert_at(key(ino), 0, later);
+        }
+        assert_eq!(cache.entries.lock().unwrap().len(), 1);
+        assert_eq!(cache.get_at(&key(MAX_ENTRIES as u64 - 1), later), Some(0));
+    }
+}
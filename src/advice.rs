@@ -0,0 +1,222 @@
+//! File access pattern advice
+//!
+//! Applications announce how they're going to access a file with `posix_fadvise` (e.g. backup
+//! tools reading large files sequentially). The FUSE protocol has no operation to forward
+//! this advice to the filesystem, and the kernel driver only uses it for its own page cache.
+//! A `SequentialReadDetector` infers the same advice from the offsets of read requests, so
+//! filesystems can size backend reads or drop caches accordingly.
+
+use std::fmt;
+
+/// Access pattern advice, like the advice values of `posix_fadvise`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Advice {
+    /// No particular access pattern
+    Normal,
+    /// Data is read sequentially (from lower to higher offsets)
+    Sequential,
+    /// Data is read in random order
+    Random,
+    /// Data will be read in the near future
+    WillNeed,
+    /// Data won't be read in the near future
+    DontNeed,
+    /// Data will be read only once
+    NoReuse,
+}
+
+impl Advice {
+    /// Returns the advice for the given `POSIX_FADV_*` value
+    #[cfg(target_os = "linux")]
+    pub fn from_raw(advice: libc::c_int) -> Option<Advice> {
+        match advice {
+            libc::POSIX_FADV_NORMAL => Some(Advice::Normal),
+            libc::POSIX_FADV_SEQUENTIAL => Some(Advice::Sequential),
+            libc::POSIX_FADV_RANDOM => Some(Advice::Random),
+            libc::POSIX_FADV_WILLNEED => Some(Advice::WillNeed),
+            libc::POSIX_FADV_DONTNEED => Some(Advice::DontNeed),
+            libc::POSIX_FADV_NOREUSE => Some(Advice::NoReuse),
+            _ => None,
+        }
+    }
+
+    /// Returns the `POSIX_FADV_*` value of this advice, e.g. to pass it on to a backing file
+    #[cfg(target_os = "linux")]
+    pub fn as_raw(self) -> libc::c_int {
+        match self {
+            Advice::Normal => libc::POSIX_FADV_NORMAL,
+            Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            Advice::Random => libc::POSIX_FADV_RANDOM,
+            Advice::WillNeed => libc::POSIX_FADV_WILLNEED,
+            Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+            Advice::NoReuse => libc::POSIX_FADV_NOREUSE,
+        }
+    }
+}
+
+impl fmt::Display for Advice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Advice::Normal => "normal",
+            Advice::Sequential => "sequential",
+            Advice::Random => "random",
+            Advice::WillNeed => "willneed",
+            Advice::DontNeed => "dontneed",
+            Advice::NoReuse => "noreuse",
+        })
+    }
+}
+
+/// Default number of consecutive reads that establish an access pattern
+const DEFAULT_THRESHOLD: u32 = 4;
+
+/// Infers the access pattern of an open file from the offsets of read requests. Keep one
+/// detector per file handle and feed it every read.
+///
+/// A read is sequential if it starts where the previous one ended. After a number of
+/// consecutive sequential reads (4 by default), the detector advises `Sequential`, and after
+/// the same number of consecutive non-sequential reads, it advises `Random`. A single
+/// non-sequential read ends a sequential stream and falls back to `Normal`.
+#[derive(Clone, Debug)]
+pub struct SequentialReadDetector {
+    /// Number of consecutive reads that establish an access pattern
+    threshold: u32,
+    /// Offset where the previous read ended
+    next_offset: Option<i64>,
+    /// Number of consecutive sequential reads
+    sequential: u32,
+    /// Number of consecutive non-sequential reads
+    random: u32,
+    /// Currently advised access pattern
+    advice: Advice,
+}
+
+impl Default for SequentialReadDetector {
+    fn default() -> SequentialReadDetector {
+        SequentialReadDetector::new()
+    }
+}
+
+impl SequentialReadDetector {
+    /// Create a new detector
+    pub fn new() -> SequentialReadDetector {
+        SequentialReadDetector { threshold: DEFAULT_THRESHOLD, next_offset: None, sequential: 0, random: 0, advice: Advice::Normal }
+    }
+
+    /// Set the number of consecutive reads that establish an access pattern (defaults to 4,
+    /// at least 1)
+    pub fn threshold(mut self, reads: u32) -> SequentialReadDetector {
+        self.threshold = if reads == 0 { 1 } else { reads };
+        self
+    }
+
+    /// Returns the currently advised access pattern
+    pub fn advice(&self) -> Advice {
+        self.advice
+    }
+
+    /// Feed a read of the given size at the given offset. Returns the new advice if the
+    /// access pattern changed.
+    pub fn read(&mut self, offset: i64, size: u32) -> Option<Advice> {
+        let sequential = self.next_offset == Some(offset);
+        self.next_offset = Some(offset.saturating_add(i64::from(size)));
+        let advice = if sequential {
+            self.sequential = self.sequential.saturating_add(1);
+            self.random = 0;
+            if self.sequential >= self.threshold { Advice::Sequential } else { self.advice }
+        } else {
+            self.sequential = 0;
+            self.random = self.random.saturating_add(1);
+            match self.advice {
+                Advice::Sequential => Advice::Normal,
+                _ if self.random >= self.threshold => Advice::Random,
+                advice => advice,
+            }
+        };
+        if advice == self.advice { return None; }
+        self.advice = advice;
+        Some(advice)
+    }
+
+    /// Forget previous reads, e.g. after the file was truncated
+    pub fn reset(&mut self) {
+        *self = SequentialReadDetector::new().threshold(self.threshold);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{Advice, SequentialReadDetector};
+
+    #[test]
+    fn sequential_stream() {
+        let mut detector = SequentialReadDetector::new();
+        assert_eq!(detector.advice(), Advice::Normal);
+        // The first read has nothing to continue from, 4 more establish the stream
+        for i in 0..4 {
+            assert_eq!(detector.read(i * 4096, 4096), None);
+        }
+        assert_eq!(detector.read(4 * 4096, 4096), Some(Advice::Sequential));
+        assert_eq!(detector.read(5 * 4096, 8192), None);
+        assert_eq!(detector.read(7 * 4096, 4096), None);
+        assert_eq!(detector.advice(), Advice::Sequential);
+    }
+
+    #[test]
+    fn seek_ends_stream() {
+        let mut detector = SequentialReadDetector::new().threshold(2);
+        detector.read(0, 100);
+        detector.read(100, 100);
+        assert_eq!(detector.read(200, 100), Some(Advice::Sequential));
+        // A seek falls back to normal, and the stream starts over from the new offset
+        assert_eq!(detector.read(0, 100), Some(Advice::Normal));
+        assert_eq!(detector.read(100, 100), None);
+        assert_eq!(detector.read(200, 100), Some(Advice::Sequential));
+        // Reading the same data again isn't sequential
+        assert_eq!(detector.read(200, 100), Some(Advice::Normal));
+    }
+
+    #[test]
+    fn random_reads() {
+        let mut detector = SequentialReadDetector::new().threshold(3);
+        assert_eq!(detector.read(8192, 512), None);
+        assert_eq!(detector.read(0, 512), None);
+        assert_eq!(detector.read(65536, 512), Some(Advice::Random));
+        assert_eq!(detector.read(4096, 512), None);
+        // Random until enough sequential reads follow
+        assert_eq!(detector.read(4608, 512), None);
+        assert_eq!(detector.read(5120, 512), None);
+        assert_eq!(detector.read(5632, 512), Some(Advice::Sequential));
+        // A single seek doesn't make it random again
+        assert_eq!(detector.read(0, 512), Some(Advice::Normal));
+        assert_eq!(detector.read(4096, 512), None);
+        assert_eq!(detector.read(8192, 512), Some(Advice::Random));
+    }
+
+    #[test]
+    fn threshold_and_reset() {
+        let mut detector = SequentialReadDetector::new().threshold(0);
+        assert_eq!(detector.read(0, 10), Some(Advice::Random));
+        assert_eq!(detector.read(10, 10), Some(Advice::Sequential));
+        detector.reset();
+        assert_eq!(detector.advice(), Advice::Normal);
+        // The threshold is kept, but there's no previous read to continue from
+        assert_eq!(detector.read(20, 10), Some(Advice::Random));
+        // Offsets don't overflow
+        let max = (!0u64 >> 1) as i64;
+        assert_eq!(detector.read(max, 10), None);
+        assert_eq!(detector.read(max, 10), Some(Advice::Sequential));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn raw_values() {
+        for &advice in &[Advice::Normal, Advice::Sequential, Advice::Random, Advice::WillNeed, Advice::DontNeed, Advice::NoReuse] {
+            assert_eq!(Advice::from_raw(advice.as_raw()), Some(advice));
+        }
+        assert_eq!(Advice::from_raw(libc::POSIX_FADV_SEQUENTIAL), Some(Advice::Sequential));
+        assert_eq!(Advice::from_raw(-1), None);
+        assert_eq!(Advice::Sequential.to_string(), "sequential");
+    }
+}
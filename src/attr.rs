@@ -0,0 +1,144 @@
+//! File attributes from metadata
+//!
+//! Passthrough filesystems reply with the attributes of backing files, which are converted
+//! from their metadata with `FileAttr::try_from`. The inode number is the one of the backing
+//! file, which filesystems that assign their own inode numbers need to replace.
+
+use std::convert::{TryFrom, TryInto};
+use std::fs::Metadata;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{error, fmt};
+use crate::{FileAttr, FileType};
+
+/// Mask of the file type bits of a mode (S_IFMT)
+const FILE_TYPE_MASK: u32 = 0o170000;
+
+impl TryFrom<&Metadata> for FileAttr {
+    type Error = FileAttrTryFromError;
+
+    /// Convert the metadata of a file (e.g. from `std::fs::symlink_metadata`). The creation
+    /// time falls back to the time of last change if it isn't available. Fails if the file
+    /// type is unknown or a value doesn't fit.
+    fn try_from(metadata: &Metadata) -> Result<FileAttr, FileAttrTryFromError> {
+        let file_type = metadata.file_type();
+        let kind = if file_type.is_file() {
+            FileType::RegularFile
+        } else if file_type.is_dir() {
+            FileType::Directory
+        } else if file_type.is_symlink() {
+            FileType::Symlink
+        } else if file_type.is_fifo() {
+            FileType::NamedPipe
+        } else if file_type.is_char_device() {
+            FileType::CharDevice
+        } else if file_type.is_block_device() {
+            FileType::BlockDevice
+        } else if file_type.is_socket() {
+            FileType::Socket
+        } else {
+            return Err(FileAttrTryFromError::UnknownFileType(metadata.mode() & FILE_TYPE_MASK));
+        };
+        let ctime = time(metadata.ctime(), metadata.ctime_nsec(), "ctime")?;
+        #[cfg(target_os = "macos")]
+        let flags = std::os::macos::fs::MetadataExt::st_flags(metadata);
+        #[cfg(not(target_os = "macos"))]
+        let flags = 0;
+        Ok(FileAttr {
+            ino: metadata.ino(),
+            size: metadata.size(),
+            blocks: metadata.blocks(),
+            atime: time(metadata.atime(), metadata.atime_nsec(), "atime")?,
+            mtime: time(metadata.mtime(), metadata.mtime_nsec(), "mtime")?,
+            ctime,
+            crtime: metadata.created().unwrap_or(ctime),
+            kind,
+            perm: convert(metadata.mode() & 0o7777, "mode")?,
+            nlink: convert(metadata.nlink(), "nlink")?,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            rdev: convert(metadata.rdev(), "rdev")?,
+            flags,
+        })
+    }
+}
+
+/// Error of converting metadata to file attributes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileAttrTryFromError {
+    /// The file type (the given file type bits of the mode) isn't known
+    UnknownFileType(u32),
+    /// The named value doesn't fit in file attributes
+    Overflow(&'static str),
+}
+
+impl fmt::Display for FileAttrTryFromError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileAttrTryFromError::UnknownFileType(mode) => write!(f, "Unknown file type {:#o}", mode),
+            FileAttrTryFromError::Overflow(value) => write!(f, "File attributes have {} out of range", value),
+        }
+    }
+}
+
+impl error::Error for FileAttrTryFromError {}
+
+/// Convert the given value of metadata, whose type depends on the platform
+fn convert<T: TryInto<U>, U>(value: T, field: &'static str) -> Result<U, FileAttrTryFromError> {
+    value.try_into().map_err(|_| FileAttrTryFromError::Overflow(field))
+}
+
+/// Returns the time of the given seconds and nanoseconds since the epoch, which are before the
+/// epoch if negative
+fn time(secs: i64, nsecs: i64, field: &'static str) -> Result<SystemTime, FileAttrTryFromError> {
+    let nsecs = Duration::from_nanos(convert(nsecs, field)?);
+    let time = if secs >= 0 {
+        UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64))
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::from_secs(secs.wrapping_neg() as u64))
+    };
+    time.and_then(|time| time.checked_add(nsecs)).ok_or(FileAttrTryFromError::Overflow(field))
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    use std::process;
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::{FileAttr, FileType};
+    use super::{time, FileAttrTryFromError};
+
+    #[test]
+    fn from_metadata() {
+        let dir = std::env::temp_dir().join(format!("fuse-rs-attr-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("file");
+        fs::write(&file, b"hello").unwrap();
+        std::os::unix::fs::symlink("file", dir.join("link")).unwrap();
+        let metadata = fs::metadata(&file).unwrap();
+        let attr = FileAttr::try_from(&metadata).unwrap();
+        assert_eq!((attr.ino, attr.size, attr.kind), (metadata.ino(), 5, FileType::RegularFile));
+        assert_eq!(u32::from(attr.perm), metadata.mode() & 0o7777);
+        assert_eq!((attr.nlink, attr.uid, attr.gid), (1, metadata.uid(), metadata.gid()));
+        assert_eq!(attr.mtime, metadata.modified().unwrap());
+        let link = FileAttr::try_from(&fs::symlink_metadata(dir.join("link")).unwrap()).unwrap();
+        assert_eq!((link.kind, link.size), (FileType::Symlink, 4));
+        assert_eq!(FileAttr::try_from(&fs::metadata(&dir).unwrap()).unwrap().kind, FileType::Directory);
+        fs::remove_dir_all(&dir).unwrap();
+        // Device numbers of devices are kept
+        let null = fs::metadata("/dev/null").unwrap();
+        let attr = FileAttr::try_from(&null).unwrap();
+        assert_eq!((attr.kind, u64::from(attr.rdev)), (FileType::CharDevice, null.rdev()));
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(time(1, 500, "atime"), Ok(UNIX_EPOCH + Duration::new(1, 500)));
+        // Before the epoch, nanoseconds still count forward
+        assert_eq!(time(-2, 500, "atime"), Ok(UNIX_EPOCH - Duration::from_secs(2) + Duration::from_nanos(500)));
+        assert_eq!(time(0, -1, "mtime"), Err(FileAttrTryFromError::Overflow("mtime")));
+    }
+}
@@ -0,0 +1,378 @@
+//! File attribute differences
+//!
+//! The kernel driver caches attributes for the TTL given in lookup and getattr replies. If the
+//! filesystem replies with different attributes for the same inode while a previous reply is
+//! still cached, applications may see either of them (e.g. a stale file size). Comparing
+//! consecutive replies helps to find such cache coherence bugs.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+#[cfg(test)]
+use std::sync::atomic::AtomicUsize;
+use std::time::{Duration, Instant, SystemTime};
+use log::{debug, warn};
+
+use crate::ll;
+use crate::{FileAttr, FileType};
+
+/// A changed file attribute with its old and new value
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AttrChange {
+    /// Inode number
+    Ino(u64, u64),
+    /// Size in bytes
+    Size(u64, u64),
+    /// Size in blocks
+    Blocks(u64, u64),
+    /// Time of last access
+    Atime(SystemTime, SystemTime),
+    /// Time of last modification
+    Mtime(SystemTime, SystemTime),
+    /// Time of last change
+    Ctime(SystemTime, SystemTime),
+    /// Time of creation
+    Crtime(SystemTime, SystemTime),
+    /// Kind of file
+    Kind(FileType, FileType),
+    /// Permissions
+    Perm(u16, u16),
+    /// Number of hard links
+    Nlink(u32, u32),
+    /// User id
+    Uid(u32, u32),
+    /// Group id
+    Gid(u32, u32),
+    /// Rdev
+    Rdev(u32, u32),
+    /// Flags
+    Flags(u32, u32),
+}
+
+/// Formats the difference of two times as a signed duration, e.g. `+3.2s`
+fn fmt_delta(f: &mut fmt::Formatter<'_>, old: SystemTime, new: SystemTime) -> fmt::Result {
+    match new.duration_since(old) {
+        Ok(delta) => write!(f, "+{:?}", delta),
+        Err(err) => write!(f, "-{:?}", err.duration()),
+    }
+}
+
+impl fmt::Display for AttrChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            AttrChange::Ino(old, new) => write!(f, "ino: {}→{}", old, new),
+            AttrChange::Size(old, new) => write!(f, "size: {}→{}", old, new),
+            AttrChange::Blocks(old, new) => write!(f, "blocks: {}→{}", old, new),
+            AttrChange::Atime(old, new) => { f.write_str("atime: ")?; fmt_delta(f, old, new) }
+            AttrChange::Mtime(old, new) => { f.write_str("mtime: ")?; fmt_delta(f, old, new) }
+            AttrChange::Ctime(old, new) => { f.write_str("ctime: ")?; fmt_delta(f, old, new) }
+            AttrChange::Crtime(old, new) => { f.write_str("crtime: ")?; fmt_delta(f, old, new) }
+            AttrChange::Kind(old, new) => write!(f, "kind: {:?}→{:?}", old, new),
+            AttrChange::Perm(old, new) => write!(f, "perm: {:o}→{:o}", old, new),
+            AttrChange::Nlink(old, new) => write!(f, "nlink: {}→{}", old, new),
+            AttrChange::Uid(old, new) => write!(f, "uid: {}→{}", old, new),
+            AttrChange::Gid(old, new) => write!(f, "gid: {}→{}", old, new),
+            AttrChange::Rdev(old, new) => write!(f, "rdev: {}→{}", old, new),
+            AttrChange::Flags(old, new) => write!(f, "flags: {:#x}→{:#x}", old, new),
+        }
+    }
+}
+
+/// Differences between two file attributes (see `FileAttr::diff`). Displays as a compact
+/// list of changed fields, e.g. `size: 4096→8192, mtime: +3.2s, perm: 644→640`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AttrDiff {
+    changes: Vec<AttrChange>,
+}
+
+impl AttrDiff {
+    /// Returns the changed attributes
+    pub fn changes(&self) -> &[AttrChange] {
+        &self.changes
+    }
+
+    /// Returns true if no attributes changed
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+impl fmt::Display for AttrDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.changes.is_empty() {
+            return f.write_str("no changes");
+        }
+        for (i, change) in self.changes.iter().enumerate() {
+            if i > 0 { f.write_str(", ")?; }
+            write!(f, "{}", change)?;
+        }
+        Ok(())
+    }
+}
+
+impl FileAttr {
+    /// Returns the attributes that differ in the given (newer) attributes
+    pub fn diff(&self, other: &FileAttr) -> AttrDiff {
+        let mut changes = Vec::new();
+        macro_rules! compare {
+            ($($field:ident => $change:ident),*) => {
+                $(if self.$field != other.$field {
+                    changes.push(AttrChange::$change(self.$field, other.$field));
+                })*
+            };
+        }
+        compare!(ino => Ino, size => Size, blocks => Blocks, atime => Atime, mtime => Mtime,
+                 ctime => Ctime, crtime => Crtime, kind => Kind, perm => Perm, nlink => Nlink,
+                 uid => Uid, gid => Gid, rdev => Rdev, flags => Flags);
+        AttrDiff { changes }
+    }
+}
+
+/// Last replied attributes of an inode
+#[derive(Debug)]
+struct Replied {
+    /// Generation of the inode, the same inode number may be reused with a new generation
+    generation: u64,
+    /// Replied attributes
+    attr: FileAttr,
+    /// When the kernel driver stops caching the attributes
+    expires: Instant,
+}
+
+/// Attributes of entry and getattr replies that may still be cached by the kernel driver
+#[derive(Debug)]
+pub struct AttrTracker {
+    /// Last replied attributes, by inode number
+    replied: Mutex<HashMap<u64, Replied>>,
+    /// Max number of inodes to keep attributes of. Expired attributes are dropped when the
+    /// limit is reached, and if that doesn't help, all of them.
+    max: usize,
+    /// Number of times all attributes were dropped
+    overflows: AtomicU64,
+    /// Set once a warning about dropping all attributes was logged
+    warned: AtomicBool,
+    /// Number of changed attributes that were replied while the previous ones were cached
+    #[cfg(test)]
+    incoherent: AtomicUsize,
+}
+
+impl AttrTracker {
+    /// Create a new tracker that keeps the attributes of up to the given number of inodes
+    pub fn new(max: usize) -> AttrTracker {
+        AttrTracker {
+            replied: Mutex::default(),
+            max,
+            overflows: AtomicU64::new(0),
+            warned: AtomicBool::new(false),
+            #[cfg(test)]
+            incoherent: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the number of inodes with replied attributes and how often all of them were
+    /// dropped because the limit was reached
+    pub fn stats(&self) -> (usize, u64) {
+        (self.replied.lock().unwrap().len(), self.overflows.load(Ordering::Relaxed))
+    }
+
+    /// Forget replied attributes that the given request may have changed. The kernel driver
+    /// expects attributes to change after modifying an inode (e.g. write or setattr), and
+    /// after unlinking or renaming, which may change the attributes of inodes that aren't
+    /// known here.
+    pub fn observe(&self, request: &ll::Request<'_>) {
+        let mut replied = self.replied.lock().unwrap();
+        match request.operation() {
+            ll::Operation::Lookup { .. } | ll::Operation::GetAttr => (),
+            ll::Operation::Unlink { .. } | ll::Operation::RmDir { .. } | ll::Operation::Rename { .. } => replied.clear(),
+            #[cfg(feature = "abi-7-23")]
+            ll::Operation::Rename2 { .. } => replied.clear(),
+            ll::Operation::Link { arg, .. } => {
+                replied.remove(&arg.oldnodeid);
+                replied.remove(&request.nodeid());
+            }
+            #[cfg(feature = "abi-7-16")]
+            ll::Operation::BatchForget { nodes, .. } => {
+                for node in nodes.iter() {
+                    replied.remove(&node.nodeid);
+                }
+            }
+            _ => { replied.remove(&request.nodeid()); }
+        }
+    }
+
+    /// Remember the given attributes replied with the given TTL. Logs a warning and returns
+    /// the difference if they differ from previously replied attributes that are still valid.
+    /// Attribute replies don't carry a generation, so the attributes are assumed to be of the
+    /// generation of the last entry reply for the inode (or 0 if there was none, e.g. for the
+    /// root inode).
+    pub fn check(&self, attr: &FileAttr, ttl: &Duration) -> Option<AttrDiff> {
+        self.check_at(attr, ttl, None, Instant::now())
+    }
+
+    /// Like `check`, but for attributes of an entry reply with the given generation. If the
+    /// inode number was reused with a different generation, the previous attributes belong to
+    /// another file and aren't compared.
+    pub fn check_entry(&self, attr: &FileAttr, ttl: &Duration, generation: u64) -> Option<AttrDiff> {
+        self.check_at(attr, ttl, Some(generation), Instant::now())
+    }
+
+    fn check_at(&self, attr: &FileAttr, ttl: &Duration, generation: Option<u64>, now: Instant) -> Option<AttrDiff> {
+        let mut replied = self.replied.lock().unwrap();
+        let generation = generation.unwrap_or_else(|| replied.get(&attr.ino).map_or(0, |previous| previous.generation));
+        if replied.len() >= self.max && !replied.contains_key(&attr.ino) {
+            replied.retain(|_, replied| now < replied.expires);
+            if replied.len() >= self.max {
+                replied.clear();
+                self.overflows.fetch_add(1, Ordering::Relaxed);
+                if !self.warned.swap(true, Ordering::Relaxed) {
+                    warn!("Tracking attributes of more than {} inodes, forgetting all of them (see SessionBuilder::max_tracked_inodes)", self.max);
+                }
+            }
+        }
+        let previous = replied.insert(attr.ino, Replied { generation, attr: *attr, expires: now + *ttl });
+        drop(replied);
+        match previous {
+            Some(previous) if previous.generation != generation => {
+                debug!("Inode {} was reused with generation {} (previously {})", attr.ino, generation, previous.generation);
+                None
+            }
+            Some(Replied { attr: previous, expires, .. }) if now < expires => {
+                let diff = previous.diff(attr);
+                if diff.is_empty() { return None; }
+                warn!("Attributes of inode {} changed while cached by the kernel: {}", attr.ino, diff);
+                #[cfg(test)]
+                self.incoherent.fetch_add(1, Ordering::SeqCst);
+                Some(diff)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the generation and last replied attributes of the given inode number
+    #[cfg(test)]
+    pub fn replied(&self, ino: u64) -> Option<(u64, FileAttr)> {
+        self.replied.lock().unwrap().get(&ino).map(|replied| (replied.generation, replied.attr))
+    }
+
+    /// Returns the number of changed attributes that were replied while the previous ones
+    /// were cached
+    #[cfg(test)]
+    pub fn incoherent(&self) -> usize {
+        self.incoherent.load(Ordering::SeqCst)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+    use crate::{FileAttr, FileType};
+    use super::{AttrChange, AttrTracker};
+
+    fn attr() -> FileAttr {
+        FileAttr::builder(2, FileType::RegularFile).size(4096).times(UNIX_EPOCH).build()
+    }
+
+    #[test]
+    fn no_changes() {
+        let diff = attr().diff(&attr());
+        assert!(diff.is_empty());
+        assert_eq!(diff.to_string(), "no changes");
+    }
+
+    #[test]
+    fn changed_fields() {
+        let old = attr();
+        let new = FileAttr { size: 8192, perm: 0o640, nlink: 2, uid: 1000, flags: 0x20, ..old };
+        let diff = old.diff(&new);
+        assert_eq!(diff.changes(), [
+            AttrChange::Size(4096, 8192),
+            AttrChange::Perm(0o644, 0o640),
+            AttrChange::Nlink(1, 2),
+            AttrChange::Uid(0, 1000),
+            AttrChange::Flags(0, 0x20),
+        ]);
+        assert_eq!(diff.to_string(), "size: 4096→8192, perm: 644→640, nlink: 1→2, uid: 0→1000, flags: 0x0→0x20");
+        let new = FileAttr { ino: 3, blocks: 8, kind: FileType::Symlink, gid: 100, rdev: 7, ..old };
+        assert_eq!(old.diff(&new).to_string(), "ino: 2→3, blocks: 0→8, kind: RegularFile→Symlink, gid: 0→100, rdev: 0→7");
+    }
+
+    #[test]
+    fn time_deltas() {
+        let old = attr();
+        let new = FileAttr {
+            atime: UNIX_EPOCH + Duration::from_millis(1),
+            mtime: UNIX_EPOCH + Duration::from_millis(3200),
+            ..old
+        };
+        assert_eq!(old.diff(&new).to_string(), "atime: +1ms, mtime: +3.2s");
+        assert_eq!(new.diff(&old).to_string(), "atime: -1ms, mtime: -3.2s");
+        let new = FileAttr { ctime: UNIX_EPOCH + Duration::from_secs(60), crtime: UNIX_EPOCH + Duration::new(1, 500), ..old };
+        assert_eq!(old.diff(&new).to_string(), "ctime: +60s, crtime: +1.0000005s");
+    }
+
+    #[test]
+    fn changes_within_ttl() {
+        let tracker = AttrTracker::new(16);
+        let now = Instant::now();
+        let ttl = Duration::from_secs(1);
+        assert_eq!(tracker.check_at(&attr(), &ttl, None, now), None);
+        // Same attributes within the TTL, changed attributes after it expired
+        assert_eq!(tracker.check_at(&attr(), &ttl, None, now + Duration::from_millis(500)), None);
+        let changed = FileAttr { size: 8192, ..attr() };
+        assert_eq!(tracker.check_at(&changed, &ttl, None, now + Duration::from_millis(1500)), None);
+        // Changed attributes while the previous ones are cached
+        let diff = tracker.check_at(&attr(), &ttl, None, now + Duration::from_millis(2000)).unwrap();
+        assert_eq!(diff.changes(), [AttrChange::Size(8192, 4096)]);
+        assert_eq!(tracker.incoherent(), 1);
+    }
+
+    #[test]
+    fn reused_inode_number() {
+        let tracker = AttrTracker::new(16);
+        let now = Instant::now();
+        let ttl = Duration::from_secs(60);
+        assert_eq!(tracker.check_at(&attr(), &ttl, Some(1), now), None);
+        // Attribute replies keep the generation of the last entry reply
+        assert_eq!(tracker.check_at(&attr(), &ttl, None, now), None);
+        assert_eq!(tracker.replied(2), Some((1, attr())));
+        // The inode is forgotten and its number reused for a new file with a new generation
+        let new = FileAttr { size: 0, kind: FileType::Directory, ..attr() };
+        assert_eq!(tracker.check_at(&new, &ttl, Some(2), now), None);
+        assert_eq!(tracker.replied(2), Some((2, new)));
+        assert_eq!(tracker.check_at(&new, &ttl, None, now), None);
+        // Changes of the new file are still found
+        let changed = FileAttr { size: 4096, ..new };
+        let diff = tracker.check_at(&changed, &ttl, Some(2), now).unwrap();
+        assert_eq!(diff.changes(), [AttrChange::Size(0, 4096)]);
+        assert_eq!(tracker.incoherent(), 1);
+        assert_eq!(tracker.replied(3), None);
+    }
+
+    #[test]
+    fn bounded() {
+        let tracker = AttrTracker::new(4);
+        let now = Instant::now();
+        let ttl = Duration::from_secs(1);
+        for ino in 2..6 {
+            tracker.check_at(&FileAttr { ino, ..attr() }, &ttl, Some(1), now);
+        }
+        assert_eq!(tracker.stats(), (4, 0));
+        // Expired attributes make room
+        let later = now + Duration::from_secs(2);
+        tracker.check_at(&FileAttr { ino: 6, ..attr() }, &ttl, None, later);
+        assert_eq!(tracker.stats(), (1, 0));
+        for ino in 7..10 {
+            tracker.check_at(&FileAttr { ino, ..attr() }, &ttl, None, later);
+        }
+        // Replies for known inodes are still compared at the limit
+        let changed = FileAttr { ino: 6, size: 8192, ..attr() };
+        assert!(tracker.check_at(&changed, &ttl, None, later).is_some());
+        // Otherwise, all attributes are dropped, and changes of dropped inodes go unnoticed
+        tracker.check_at(&FileAttr { ino: 10, ..attr() }, &ttl, None, later);
+        assert_eq!(tracker.stats(), (1, 1));
+        assert!(tracker.check_at(&changed, &ttl, None, later).is_none());
+        assert_eq!(tracker.replied(2), None);
+    }
+}
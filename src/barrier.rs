@@ -0,0 +1,505 @@
+//! Write barriers for fsync
+//!
+//! Filesystems that reply to writes asynchronously (e.g. once a backend acknowledged them on
+//! another thread) must not reply to fsync before all writes that were acknowledged before are
+//! durable. A `WriteBarrier` keeps track of writes in flight per inode, and lets fsync wait
+//! for exactly those that were registered before it, without waiting for later writes.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use libc::c_int;
+
+use crate::errno::ToErrno;
+
+/// Error of waiting for a barrier
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BarrierError {
+    /// Waiting was cancelled (e.g. the fsync was interrupted)
+    Interrupted,
+    /// The barrier was shut down before all writes completed
+    Shutdown,
+    /// A write registered before the barrier failed
+    WriteFailed,
+}
+
+impl fmt::Display for BarrierError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BarrierError::Interrupted => write!(f, "Waiting for writes was interrupted"),
+            BarrierError::Shutdown => write!(f, "Write barrier was shut down"),
+            BarrierError::WriteFailed => write!(f, "A preceding write failed"),
+        }
+    }
+}
+
+impl error::Error for BarrierError {}
+
+impl ToErrno for BarrierError {
+    fn to_errno(&self) -> c_int {
+        match self {
+            BarrierError::Interrupted => libc::EINTR,
+            BarrierError::Shutdown | BarrierError::WriteFailed => libc::EIO,
+        }
+    }
+}
+
+/// Callback of a barrier that is waited for asynchronously
+type Callback = Box<dyn FnOnce(Result<(), BarrierError>) + Send>;
+
+/// A barrier that waits for writes
+struct Waiter {
+    ino: u64,
+    /// Writes with a lower sequence number were registered before the barrier
+    limit: u64,
+    /// Don't wait for metadata-only writes
+    datasync: bool,
+    /// A write that the barrier waits for failed
+    failed: bool,
+    result: Option<Result<(), BarrierError>>,
+    callback: Option<Callback>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Sequence number of the next write or barrier
+    next: u64,
+    /// Writes in flight (sequence number and whether they write data), by inode
+    pending: HashMap<u64, BTreeMap<u64, bool>>,
+    /// Barriers by sequence number
+    waiters: HashMap<u64, Waiter>,
+    shutdown: bool,
+}
+
+impl State {
+    /// Resolve barriers of the given inode that don't wait for any write anymore. Returns the
+    /// callbacks to call (without holding the lock).
+    fn settle(&mut self, ino: u64) -> Vec<(Callback, Result<(), BarrierError>)> {
+        let pending = self.pending.get(&ino);
+        let mut resolved = Vec::new();
+        for (&id, waiter) in self.waiters.iter_mut() {
+            if waiter.ino != ino || waiter.result.is_some() { continue; }
+            let waiting = match pending {
+                Some(writes) => writes.range(..waiter.limit).any(|(_, &data)| data || !waiter.datasync),
+                None => false,
+            };
+            if !waiting {
+                waiter.result = Some(if waiter.failed { Err(BarrierError::WriteFailed) } else { Ok(()) });
+            } else if self.shutdown {
+                waiter.result = Some(Err(BarrierError::Shutdown));
+            }
+            if waiter.result.is_some() && waiter.callback.is_some() {
+                resolved.push(id);
+            }
+        }
+        self.take_callbacks(resolved)
+    }
+
+    /// Remove the given resolved barriers and return their callbacks
+    fn take_callbacks(&mut self, ids: Vec<u64>) -> Vec<(Callback, Result<(), BarrierError>)> {
+        ids.into_iter().filter_map(|id| {
+            let waiter = self.waiters.remove(&id)?;
+            Some((waiter.callback?, waiter.result?))
+        }).collect()
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    /// Notified whenever barriers are resolved
+    changed: Condvar,
+}
+
+impl Shared {
+    /// Call the given callbacks and wake up blocked waiters
+    fn resolved(&self, callbacks: Vec<(Callback, Result<(), BarrierError>)>) {
+        self.changed.notify_all();
+        for (callback, result) in callbacks {
+            callback(result);
+        }
+    }
+}
+
+/// Keeps track of writes in flight per inode, so fsync can wait for writes that were
+/// acknowledged before it. Cloning a barrier gives another handle to the same writes.
+///
+/// Register every write with `register` before replying to it, and complete the returned
+/// token once the backend made the write durable. In fsync, wait with `wait_for` (or
+/// asynchronously with `barrier` and `Barrier::on_complete`) before replying. Call
+/// `shutdown` in `Filesystem::destroy`, so fsyncs that still wait fail instead of hanging.
+#[derive(Clone, Default)]
+pub struct WriteBarrier {
+    shared: Arc<Shared>,
+}
+
+impl fmt::Debug for WriteBarrier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.shared.state.lock().unwrap();
+        let pending: usize = state.pending.values().map(|writes| writes.len()).sum();
+        f.debug_struct("WriteBarrier").field("pending", &pending).field("waiters", &state.waiters.len()).finish()
+    }
+}
+
+impl WriteBarrier {
+    /// Create a new write barrier
+    pub fn new() -> WriteBarrier {
+        WriteBarrier::default()
+    }
+
+    /// Register a write of data to the given inode. Complete the returned token once the
+    /// write is durable.
+    pub fn register(&self, ino: u64) -> WriteToken {
+        self.register_write(ino, true)
+    }
+
+    /// Register a change of metadata only (e.g. timestamps) of the given inode. Barriers for
+    /// fdatasync don't wait for it.
+    pub fn register_metadata(&self, ino: u64) -> WriteToken {
+        self.register_write(ino, false)
+    }
+
+    fn register_write(&self, ino: u64, data: bool) -> WriteToken {
+        let mut state = self.shared.state.lock().unwrap();
+        let seq = state.next;
+        state.next += 1;
+        state.pending.entry(ino).or_default().insert(seq, data);
+        WriteToken { shared: self.shared.clone(), ino, seq, completed: false }
+    }
+
+    /// Returns the number of writes in flight for the given inode
+    pub fn pending(&self, ino: u64) -> usize {
+        self.shared.state.lock().unwrap().pending.get(&ino).map_or(0, |writes| writes.len())
+    }
+
+    /// Create a barrier for all writes to the given inode that are registered now. Writes
+    /// registered later aren't waited for. If `datasync` is true, metadata-only writes aren't
+    /// waited for either.
+    pub fn barrier(&self, ino: u64, datasync: bool) -> Barrier {
+        let mut state = self.shared.state.lock().unwrap();
+        let id = state.next;
+        state.next += 1;
+        state.waiters.insert(id, Waiter { ino, limit: id, datasync, failed: false, result: None, callback: None });
+        // Resolves the new barrier at once if there's nothing to wait for
+        let callbacks = state.settle(ino);
+        drop(state);
+        self.shared.resolved(callbacks);
+        Barrier { shared: self.shared.clone(), id, waited: false }
+    }
+
+    /// Wait until all writes to the given inode that are registered now completed (see
+    /// `barrier`). Returns an error if one of them failed or the barrier was shut down.
+    pub fn wait_for(&self, ino: u64, datasync: bool) -> Result<(), BarrierError> {
+        self.barrier(ino, datasync).wait()
+    }
+
+    /// Shut down the barrier. Barriers that still wait for writes fail with `Shutdown`, and
+    /// so do new barriers that would have to wait.
+    pub fn shutdown(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.shutdown = true;
+        let inos: Vec<u64> = state.waiters.values().map(|waiter| waiter.ino).collect();
+        let mut callbacks = Vec::new();
+        for ino in inos {
+            callbacks.extend(state.settle(ino));
+        }
+        drop(state);
+        self.shared.resolved(callbacks);
+    }
+}
+
+/// A registered write. Complete it once the write is durable. Dropping it without completing
+/// counts as a failed write, which fails the barriers waiting for it.
+pub struct WriteToken {
+    shared: Arc<Shared>,
+    ino: u64,
+    seq: u64,
+    completed: bool,
+}
+
+impl fmt::Debug for WriteToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteToken").field("ino", &self.ino).field("seq", &self.seq).finish()
+    }
+}
+
+impl WriteToken {
+    /// Mark the write as durable
+    pub fn complete(mut self) {
+        self.completed = true;
+        self.finish(false);
+    }
+
+    /// Mark the write as failed
+    pub fn fail(mut self) {
+        self.completed = true;
+        self.finish(true);
+    }
+
+    fn finish(&self, failed: bool) {
+        let mut state = self.shared.state.lock().unwrap();
+        let data = match state.pending.get_mut(&self.ino) {
+            Some(writes) => {
+                let data = writes.remove(&self.seq).unwrap_or(true);
+                if writes.is_empty() {
+                    state.pending.remove(&self.ino);
+                }
+                data
+            }
+            None => true,
+        };
+        if failed {
+            for waiter in state.waiters.values_mut() {
+                if waiter.ino == self.ino && waiter.limit > self.seq && (data || !waiter.datasync) {
+                    waiter.failed = true;
+                }
+            }
+        }
+        let callbacks = state.settle(self.ino);
+        drop(state);
+        self.shared.resolved(callbacks);
+    }
+}
+
+impl Drop for WriteToken {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.finish(true);
+        }
+    }
+}
+
+/// A barrier for writes registered before it was created (see `WriteBarrier::barrier`)
+pub struct Barrier {
+    shared: Arc<Shared>,
+    id: u64,
+    /// True once waited for
+    waited: bool,
+}
+
+impl fmt::Debug for Barrier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Barrier").field("id", &self.id).finish()
+    }
+}
+
+impl Barrier {
+    /// Returns a handle to cancel waiting for this barrier, e.g. if the fsync was interrupted
+    pub fn cancel_handle(&self) -> BarrierCancel {
+        BarrierCancel { shared: self.shared.clone(), id: self.id }
+    }
+
+    /// Block until all writes of the barrier completed, it was cancelled or shut down
+    pub fn wait(mut self) -> Result<(), BarrierError> {
+        self.waited = true;
+        let id = self.id;
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(result) = state.waiters.get(&id).and_then(|waiter| waiter.result) {
+                state.waiters.remove(&id);
+                return result;
+            }
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Call the given function once all writes of the barrier completed, it was cancelled or
+    /// shut down. The function is called by the thread that resolves the barrier (e.g. the
+    /// one completing the last write), or immediately if it's resolved already.
+    pub fn on_complete<F: FnOnce(Result<(), BarrierError>) + Send + 'static>(mut self, f: F) {
+        self.waited = true;
+        let id = self.id;
+        let mut state = self.shared.state.lock().unwrap();
+        let waiter = state.waiters.get_mut(&id).unwrap();
+        match waiter.result {
+            Some(result) => {
+                state.waiters.remove(&id);
+                drop(state);
+                f(result);
+            }
+            None => waiter.callback = Some(Box::new(f)),
+        }
+    }
+}
+
+impl Drop for Barrier {
+    fn drop(&mut self) {
+        if !self.waited {
+            self.shared.state.lock().unwrap().waiters.remove(&self.id);
+        }
+    }
+}
+
+/// Handle to cancel waiting for a barrier. Cancelling a barrier that's resolved already has
+/// no effect.
+#[derive(Clone)]
+pub struct BarrierCancel {
+    shared: Arc<Shared>,
+    id: u64,
+}
+
+impl fmt::Debug for BarrierCancel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BarrierCancel").field("id", &self.id).finish()
+    }
+}
+
+impl BarrierCancel {
+    /// Stop waiting, the barrier fails with `Interrupted`
+    pub fn cancel(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        let callbacks = match state.waiters.get_mut(&self.id) {
+            Some(waiter) if waiter.result.is_none() => {
+                waiter.result = Some(Err(BarrierError::Interrupted));
+                let resolved = if waiter.callback.is_some() { vec![self.id] } else { Vec::new() };
+                state.take_callbacks(resolved)
+            }
+            _ => return,
+        };
+        drop(state);
+        self.shared.resolved(callbacks);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+    use super::{BarrierError, WriteBarrier};
+
+    #[test]
+    fn nothing_to_wait_for() {
+        let barrier = WriteBarrier::new();
+        assert_eq!(barrier.wait_for(2, false), Ok(()));
+        barrier.register(3).complete();
+        assert_eq!(barrier.wait_for(3, false), Ok(()));
+        assert_eq!(barrier.pending(3), 0);
+    }
+
+    #[test]
+    fn later_writes_dont_delay() {
+        let barrier = WriteBarrier::new();
+        let first = barrier.register(2);
+        let fsync = barrier.barrier(2, false);
+        // Writes after the barrier don't delay it, even if they never complete
+        let late = barrier.register(2);
+        let (tx, rx) = channel();
+        fsync.on_complete(move |result| tx.send(result).unwrap());
+        assert!(rx.try_recv().is_err());
+        first.complete();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), Ok(()));
+        assert_eq!(barrier.pending(2), 1);
+        // A later barrier waits for the late write
+        let fsync = barrier.barrier(2, false);
+        let waiter = thread::spawn(move || fsync.wait());
+        thread::sleep(Duration::from_millis(20));
+        late.complete();
+        assert_eq!(waiter.join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn blocking_wait() {
+        let barrier = WriteBarrier::new();
+        let writes: Vec<_> = (0..4).map(|_| barrier.register(2)).collect();
+        let backend = thread::spawn(move || {
+            for write in writes {
+                thread::sleep(Duration::from_millis(5));
+                write.complete();
+            }
+        });
+        assert_eq!(barrier.wait_for(2, false), Ok(()));
+        assert_eq!(barrier.pending(2), 0);
+        backend.join().unwrap();
+    }
+
+    #[test]
+    fn failed_writes() {
+        let barrier = WriteBarrier::new();
+        let write = barrier.register(2);
+        let fsync = barrier.barrier(2, false);
+        drop(write);
+        assert_eq!(fsync.wait(), Err(BarrierError::WriteFailed));
+        // Only barriers that waited for the failed write fail
+        let write = barrier.register(2);
+        write.fail();
+        assert_eq!(barrier.wait_for(2, false), Ok(()));
+    }
+
+    #[test]
+    fn datasync_skips_metadata() {
+        let barrier = WriteBarrier::new();
+        let times = barrier.register_metadata(2);
+        assert_eq!(barrier.wait_for(2, true), Ok(()));
+        let fsync = barrier.barrier(2, false);
+        let (tx, rx) = channel();
+        fsync.on_complete(move |result| tx.send(result).unwrap());
+        assert!(rx.try_recv().is_err());
+        times.complete();
+        assert_eq!(rx.recv().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn interrupted() {
+        let barrier = WriteBarrier::new();
+        let _write = barrier.register(2);
+        let fsync = barrier.barrier(2, false);
+        let cancel = fsync.cancel_handle();
+        let waiter = thread::spawn(move || fsync.wait());
+        thread::sleep(Duration::from_millis(20));
+        cancel.cancel();
+        assert_eq!(waiter.join().unwrap(), Err(BarrierError::Interrupted));
+        // Asynchronous waiters too, and cancelling twice has no effect
+        let fsync = barrier.barrier(2, false);
+        let cancel = fsync.cancel_handle();
+        let (tx, rx) = channel();
+        fsync.on_complete(move |result| tx.send(result).unwrap());
+        cancel.cancel();
+        cancel.cancel();
+        assert_eq!(rx.recv().unwrap(), Err(BarrierError::Interrupted));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn shutdown() {
+        let barrier = WriteBarrier::new();
+        let _write = barrier.register(2);
+        let fsync = barrier.barrier(2, false);
+        let (tx, rx) = channel();
+        barrier.barrier(2, true).on_complete(move |result| tx.send(result).unwrap());
+        let waiter = thread::spawn(move || fsync.wait());
+        thread::sleep(Duration::from_millis(20));
+        barrier.shutdown();
+        assert_eq!(waiter.join().unwrap(), Err(BarrierError::Shutdown));
+        assert_eq!(rx.recv().unwrap(), Err(BarrierError::Shutdown));
+        // New barriers fail if they'd have to wait, and succeed otherwise
+        assert_eq!(barrier.wait_for(2, false), Err(BarrierError::Shutdown));
+        assert_eq!(barrier.wait_for(3, false), Ok(()));
+    }
+
+    #[test]
+    fn inodes_are_independent() {
+        let barrier = WriteBarrier::new();
+        let slow = barrier.register(2);
+        let writers: Vec<_> = (3..7).map(|ino| {
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    let write = barrier.register(ino);
+                    let fsync = barrier.barrier(ino, false);
+                    write.complete();
+                    assert_eq!(fsync.wait(), Ok(()));
+                }
+            })
+        }).collect();
+        // Other inodes finish their fsyncs while inode 2 still has a write in flight
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(barrier.pending(2), 1);
+        slow.complete();
+        assert_eq!(barrier.wait_for(2, false), Ok(()));
+    }
+}
@@ -0,0 +1,252 @@
+//! Request buffers
+//!
+//! The session loop reads every request into the same large buffer, and filesystem methods
+//! get slices that borrow from it. Write handlers that want to keep the written data beyond
+//! the method call (e.g. to upload it asynchronously) can detach the payload from the buffer
+//! instead of copying it. The session then continues with a replacement buffer from a pool,
+//! and the detached buffer goes back to the pool once the handler drops it.
+
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+/// Pool of request buffers. Bounds the number of buffers that are detached at the same time
+/// (and the number of free buffers kept for reuse).
+#[derive(Debug)]
+pub struct BufferPool {
+    /// Capacity of buffers
+    size: usize,
+    /// Max number of detached buffers
+    max: usize,
+    state: Mutex<PoolState>,
+}
+
+#[derive(Debug)]
+struct PoolState {
+    /// Buffers ready for reuse
+    free: Vec<Vec<u8>>,
+    /// Number of currently detached buffers
+    detached: usize,
+}
+
+impl BufferPool {
+    /// Create a pool of buffers with the given capacity that allows the given number of
+    /// buffers to be detached at the same time
+    pub fn new(size: usize, max: usize) -> BufferPool {
+        BufferPool { size, max, state: Mutex::new(PoolState { free: Vec::new(), detached: 0 }) }
+    }
+
+    /// Get a free buffer, or allocate a new one if there is none
+    fn get(&self) -> Vec<u8> {
+        match self.state.lock().unwrap().free.pop() {
+            Some(buffer) => buffer,
+            None => Vec::with_capacity(self.size),
+        }
+    }
+
+    /// Count a new detached buffer. Returns false if the limit is reached.
+    fn try_detach(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.detached >= self.max { return false; }
+        state.detached += 1;
+        true
+    }
+
+    /// Release a detached buffer, keeping its storage for reuse if given
+    fn release(&self, buffer: Option<Vec<u8>>) {
+        let mut state = self.state.lock().unwrap();
+        state.detached -= 1;
+        if let Some(mut buffer) = buffer {
+            // Copied requests (see `RequestBuffer::copy`) are too small to receive into
+            if state.free.len() < self.max && buffer.capacity() >= self.size {
+                buffer.clear();
+                state.free.push(buffer);
+            }
+        }
+    }
+}
+
+/// The buffer the session loop receives requests into
+#[derive(Debug)]
+pub struct RequestBuffer {
+    data: Arc<Vec<u8>>,
+    pool: Arc<BufferPool>,
+}
+
+impl RequestBuffer {
+    /// Create a request buffer that takes replacement buffers from the given pool
+    pub fn new(pool: Arc<BufferPool>) -> RequestBuffer {
+        RequestBuffer { data: Arc::new(pool.get()), pool }
+    }
+
+    /// Returns the buffer to receive the next request into. If part of the previous request
+    /// is still detached, the buffer is replaced by one from the pool.
+    pub fn receive_buffer(&mut self) -> &mut Vec<u8> {
+        if Arc::get_mut(&mut self.data).is_none() {
+            self.data = Arc::new(self.pool.get());
+        }
+        Arc::get_mut(&mut self.data).unwrap()
+    }
+
+    /// Returns the data of the current request
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Copy the current request into a new buffer of just its size, e.g. to keep it while
+    /// receiving further requests
+    pub fn copy(&self) -> RequestBuffer {
+        RequestBuffer { data: Arc::new(self.data.to_vec()), pool: self.pool.clone() }
+    }
+
+    /// Detach the given part of the current request. Doesn't copy the data unless too many
+    /// buffers are detached already.
+    pub fn detach(&self, part: &[u8]) -> BytesBuffer {
+        let start = (part.as_ptr() as usize).wrapping_sub(self.data.as_ptr() as usize);
+        assert!(start <= self.data.len() && part.len() <= self.data.len() - start, "detached part must be inside the request buffer");
+        if self.pool.try_detach() {
+            BytesBuffer { storage: Storage::Shared(self.data.clone(), self.pool.clone()), start, end: start + part.len() }
+        } else {
+            BytesBuffer { storage: Storage::Owned(part.to_vec()), start: 0, end: part.len() }
+        }
+    }
+}
+
+enum Storage {
+    /// Part of a request buffer, returned to the pool when dropped
+    Shared(Arc<Vec<u8>>, Arc<BufferPool>),
+    /// Copied data
+    Owned(Vec<u8>),
+}
+
+/// Bytes detached from a request (see `Request::take_write_payload`). Derefs to the data
+/// and can be sent to other threads. The underlying storage is returned to the session's
+/// buffer pool when dropped.
+pub struct BytesBuffer {
+    storage: Storage,
+    start: usize,
+    end: usize,
+}
+
+impl BytesBuffer {
+    /// Returns true if the data was copied instead of detached from the request buffer
+    pub fn is_copied(&self) -> bool {
+        match self.storage {
+            Storage::Shared(..) => false,
+            Storage::Owned(..) => true,
+        }
+    }
+}
+
+impl Deref for BytesBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        let data = match self.storage {
+            Storage::Shared(ref data, _) => data,
+            Storage::Owned(ref data) => data,
+        };
+        &data[self.start..self.end]
+    }
+}
+
+impl AsRef<[u8]> for BytesBuffer {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for BytesBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BytesBuffer").field("len", &self.len()).field("copied", &self.is_copied()).finish()
+    }
+}
+
+impl Drop for BytesBuffer {
+    fn drop(&mut self) {
+        let storage = std::mem::replace(&mut self.storage, Storage::Owned(Vec::new()));
+        if let Storage::Shared(data, pool) = storage {
+            // The session may still use the buffer, in which case it keeps it
+            pool.release(Arc::try_unwrap(data).ok());
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+    use super::{BufferPool, RequestBuffer};
+
+    fn receive(buffer: &mut RequestBuffer, data: &[u8]) {
+        let buf = buffer.receive_buffer();
+        buf.clear();
+        buf.extend_from_slice(data);
+    }
+
+    #[test]
+    fn detach_without_copy() {
+        let mut buffer = RequestBuffer::new(Arc::new(BufferPool::new(64, 2)));
+        receive(&mut buffer, b"headerpayload");
+        let payload = buffer.detach(&buffer.data()[6..]);
+        assert!(!payload.is_copied());
+        assert_eq!(payload.as_ptr(), buffer.data()[6..].as_ptr());
+        // The next request goes to a different buffer
+        receive(&mut buffer, b"next");
+        assert_eq!(&*payload, b"payload");
+        assert_eq!(buffer.data(), b"next");
+        let payload = thread::spawn(move || payload.to_vec()).join().unwrap();
+        assert_eq!(payload, b"payload");
+    }
+
+    #[test]
+    fn detached_buffer_is_reused() {
+        let pool = Arc::new(BufferPool::new(64, 2));
+        let mut buffer = RequestBuffer::new(pool.clone());
+        receive(&mut buffer, b"first");
+        let first = buffer.data().as_ptr();
+        let payload = buffer.detach(buffer.data());
+        receive(&mut buffer, b"second");
+        drop(payload);
+        assert_eq!(pool.state.lock().unwrap().free.len(), 1);
+        // Detaching again makes the session continue with the returned buffer
+        let payload = buffer.detach(buffer.data());
+        receive(&mut buffer, b"third");
+        assert_eq!(buffer.data().as_ptr(), first);
+        assert_eq!(&*payload, b"second");
+    }
+
+    #[test]
+    fn copied_request() {
+        let pool = Arc::new(BufferPool::new(64, 2));
+        let mut buffer = RequestBuffer::new(pool.clone());
+        receive(&mut buffer, b"queued");
+        let queued = buffer.copy();
+        receive(&mut buffer, b"next");
+        assert_eq!(queued.data(), b"queued");
+        // Payloads detach from the copy, but it's too small to be reused afterwards
+        let payload = queued.detach(queued.data());
+        drop(queued);
+        assert!(!payload.is_copied());
+        drop(payload);
+        assert_eq!(pool.state.lock().unwrap().free.len(), 0);
+    }
+
+    #[test]
+    fn copy_if_exhausted() {
+        let mut buffer = RequestBuffer::new(Arc::new(BufferPool::new(64, 1)));
+        receive(&mut buffer, b"first");
+        let first = buffer.detach(buffer.data());
+        receive(&mut buffer, b"second");
+        let second = buffer.detach(buffer.data());
+        assert!(!first.is_copied());
+        assert!(second.is_copied());
+        // A copy doesn't keep the request buffer from being reused
+        receive(&mut buffer, b"third");
+        assert_eq!(&*first, b"first");
+        assert_eq!(&*second, b"second");
+        drop(first);
+        assert!(!buffer.detach(buffer.data()).is_copied());
+    }
+}
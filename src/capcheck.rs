@@ -0,0 +1,182 @@
+//! Consistency checks of requests against the negotiated capabilities
+//!
+//! Some requests are only sent by the kernel driver if the negotiated protocol version or init
+//! flags allow them. If such a request arrives anyway, the kernel driver and the session
+//! disagree about what was negotiated, and a filesystem that relies on it may break in subtle
+//! ways. With `SessionBuilder::check_capabilities`, the session warns about such requests and
+//! counts them (see `RunSummary::capability_anomalies`), but still dispatches them, since the
+//! kernel driver is authoritative.
+
+use std::fmt;
+use fuse_abi::consts::*;
+
+use crate::ll;
+use crate::session::ProtocolCapabilities;
+
+/// What needs to be negotiated for the kernel driver to send a request
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Requirement {
+    /// Minimum minor version of the protocol
+    minor: u32,
+    /// Init flags of which at least one must be negotiated (none if 0)
+    flags: u32,
+    /// Names of the init flags
+    names: &'static str,
+}
+
+impl Requirement {
+    #[cfg(feature = "abi-7-11")]
+    fn version(minor: u32) -> Requirement {
+        Requirement { minor, flags: 0, names: "" }
+    }
+
+    fn flags(minor: u32, flags: u32, names: &'static str) -> Requirement {
+        Requirement { minor, flags, names }
+    }
+}
+
+impl fmt::Display for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "needs ABI 7.{}", self.minor)?;
+        if self.flags != 0 {
+            write!(f, " and {}", self.names)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns what needs to be negotiated for the kernel driver to send the given request, or
+/// `None` if it can always be sent.
+///
+/// | Requests                 | Version | Init flags                               |
+/// |--------------------------|---------|------------------------------------------|
+/// | getlk, setlk, setlkw     | 7.7     | `FUSE_POSIX_LOCKS` or `FUSE_FLOCK_LOCKS` |
+/// | ioctl, poll              | 7.11    |                                          |
+/// | ioctl on a directory     | 7.18    | `FUSE_HAS_IOCTL_DIR`                     |
+/// | notify reply             | 7.15    |                                          |
+/// | batch forget             | 7.16    |                                          |
+/// | fallocate                | 7.19    |                                          |
+/// | readdirplus              | 7.21    | `FUSE_DO_READDIRPLUS`                    |
+/// | rename2                  | 7.23    |                                          |
+/// | lseek                    | 7.24    |                                          |
+/// | copy_file_range          | 7.28    |                                          |
+/// | tmpfile                  | 7.37    |                                          |
+pub(crate) fn requirement(request: &ll::Request<'_>) -> Option<Requirement> {
+    match request.operation() {
+        #[cfg(feature = "abi-7-17")]
+        ll::Operation::GetLk { .. } | ll::Operation::SetLk { .. } | ll::Operation::SetLkW { .. } => {
+            Some(Requirement::flags(7, FUSE_POSIX_LOCKS | FUSE_FLOCK_LOCKS, "FUSE_POSIX_LOCKS or FUSE_FLOCK_LOCKS"))
+        }
+        #[cfg(not(feature = "abi-7-17"))]
+        ll::Operation::GetLk { .. } | ll::Operation::SetLk { .. } | ll::Operation::SetLkW { .. } => {
+            Some(Requirement::flags(7, FUSE_POSIX_LOCKS, "FUSE_POSIX_LOCKS"))
+        }
+        #[cfg(feature = "abi-7-18")]
+        ll::Operation::IoCtl { arg, .. } if arg.flags & FUSE_IOCTL_DIR != 0 => {
+            Some(Requirement::flags(18, FUSE_HAS_IOCTL_DIR, "FUSE_HAS_IOCTL_DIR"))
+        }
+        #[cfg(feature = "abi-7-11")]
+        ll::Operation::IoCtl { .. } | ll::Operation::Poll { .. } => Some(Requirement::version(11)),
+        #[cfg(feature = "abi-7-15")]
+        ll::Operation::NotifyReply { .. } => Some(Requirement::version(15)),
+        #[cfg(feature = "abi-7-16")]
+        ll::Operation::BatchForget { .. } => Some(Requirement::version(16)),
+        #[cfg(feature = "abi-7-19")]
+        ll::Operation::FAllocate { .. } => Some(Requirement::version(19)),
+        #[cfg(feature = "abi-7-21")]
+        ll::Operation::ReadDirPlus { .. } => Some(Requirement::flags(21, FUSE_DO_READDIRPLUS, "FUSE_DO_READDIRPLUS")),
+        #[cfg(feature = "abi-7-23")]
+        ll::Operation::Rename2 { .. } => Some(Requirement::version(23)),
+        #[cfg(feature = "abi-7-24")]
+        ll::Operation::Lseek { .. } => Some(Requirement::version(24)),
+        #[cfg(feature = "abi-7-28")]
+        ll::Operation::CopyFileRange { .. } => Some(Requirement::version(28)),
+        #[cfg(feature = "abi-7-37")]
+        ll::Operation::TmpFile { .. } => Some(Requirement::version(37)),
+        _ => None,
+    }
+}
+
+/// Check whether the kernel driver should have sent the given request with the given
+/// negotiated capabilities. Returns what's missing if not.
+pub(crate) fn check(request: &ll::Request<'_>, capabilities: &ProtocolCapabilities) -> Option<Requirement> {
+    let requirement = requirement(request)?;
+    let (_, minor) = capabilities.negotiated_version();
+    let flags = capabilities.negotiated_flags();
+    if minor < requirement.minor || (requirement.flags != 0 && flags & requirement.flags == 0) {
+        Some(requirement)
+    } else {
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+    use std::mem;
+    use fuse_abi::*;
+    use crate::ll;
+    use crate::request::test::request;
+    use crate::session::ProtocolCapabilities;
+    use super::check;
+
+    /// Capabilities negotiated with a kernel driver of the given minor version that reported
+    /// the given flags, if the session asked for the given flags
+    fn negotiated(minor: u32, flags: u32, wanted: u32) -> ProtocolCapabilities {
+        ProtocolCapabilities::wanting(wanted).init(7, minor, 0, flags)
+    }
+
+    #[test]
+    fn always_possible() {
+        let raw = request(fuse_opcode::FUSE_GETATTR, 2, 1, &[]);
+        let req = ll::Request::try_from(&raw[..]).unwrap();
+        assert_eq!(check(&req, &negotiated(8, 0, 0)), None);
+    }
+
+    #[test]
+    fn locks_need_flags() {
+        let raw = request(fuse_opcode::FUSE_GETLK, 2, 1, &[0; mem::size_of::<fuse_lk_in>()]);
+        let req = ll::Request::try_from(&raw[..]).unwrap();
+        let missing = check(&req, &negotiated(31, consts::FUSE_POSIX_LOCKS, 0)).unwrap();
+        assert!(missing.to_string().starts_with("needs ABI 7.7 and FUSE_POSIX_LOCKS"));
+        assert_eq!(check(&req, &negotiated(31, consts::FUSE_POSIX_LOCKS, consts::FUSE_POSIX_LOCKS)), None);
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-21")]
+    fn readdirplus_needs_flag() {
+        let raw = request(fuse_opcode::FUSE_READDIRPLUS, 2, 1, &[0; mem::size_of::<fuse_read_in>()]);
+        let req = ll::Request::try_from(&raw[..]).unwrap();
+        let flags = consts::FUSE_DO_READDIRPLUS | consts::FUSE_READDIRPLUS_AUTO;
+        let missing = check(&req, &negotiated(31, flags, 0)).unwrap();
+        assert_eq!(missing.to_string(), "needs ABI 7.21 and FUSE_DO_READDIRPLUS");
+        assert_eq!(check(&req, &negotiated(31, flags, flags)), None);
+        // Too old kernel driver
+        assert_eq!(check(&req, &negotiated(20, flags, flags)).unwrap().to_string(), "needs ABI 7.21 and FUSE_DO_READDIRPLUS");
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-18")]
+    fn ioctl_dir_needs_flag() {
+        let mut ioctl = vec![0; mem::size_of::<fuse_ioctl_in>()];
+        let raw = request(fuse_opcode::FUSE_IOCTL, 2, 2, &ioctl);
+        let req = ll::Request::try_from(&raw[..]).unwrap();
+        assert_eq!(check(&req, &negotiated(31, 0, 0)), None);
+        ioctl[8..12].copy_from_slice(&consts::FUSE_IOCTL_DIR.to_ne_bytes());
+        let raw = request(fuse_opcode::FUSE_IOCTL, 3, 2, &ioctl);
+        let req = ll::Request::try_from(&raw[..]).unwrap();
+        assert_eq!(check(&req, &negotiated(31, 0, 0)).unwrap().to_string(), "needs ABI 7.18 and FUSE_HAS_IOCTL_DIR");
+        let flags = consts::FUSE_HAS_IOCTL_DIR;
+        assert_eq!(check(&req, &negotiated(31, flags, flags)), None);
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-24")]
+    fn lseek_needs_version() {
+        let raw = request(fuse_opcode::FUSE_LSEEK, 2, 2, &[0; mem::size_of::<fuse_lseek_in>()]);
+        let req = ll::Request::try_from(&raw[..]).unwrap();
+        assert_eq!(check(&req, &negotiated(23, 0, 0)).unwrap().to_string(), "needs ABI 7.24");
+        assert_eq!(check(&req, &negotiated(24, 0, 0)), None);
+    }
+}
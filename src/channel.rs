@@ -2,23 +2,56 @@
 //!
 //! Raw communication channel to the FUSE kernel driver.
 
-use std::io;
+use std::io::{self, IoSlice};
 use std::ffi::{CString, CStr, OsStr};
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
 use std::path::{PathBuf, Path};
 use fuse_sys::{fuse_args, fuse_mount_compat25};
-use libc::{self, c_int, c_void, size_t};
+use libc::{self, c_char, c_int, c_void, size_t};
 use log::error;
 
 use crate::reply::ReplySender;
 
 /// Helper function to provide options as a fuse_args struct
 /// (which contains an argc count and an argv pointer)
-fn with_fuse_args<T, F: FnOnce(&fuse_args) -> T>(options: &[&OsStr], f: F) -> T {
+fn with_fuse_args<T, F: FnOnce(&mut fuse_args) -> T>(options: &[&OsStr], f: F) -> T {
     let mut args = vec![CString::new("fuse-rs").unwrap()];
     args.extend(options.iter().map(|s| CString::new(s.as_bytes()).unwrap()));
-    let argptrs: Vec<_> = args.iter().map(|s| s.as_ptr()).collect();
-    f(&fuse_args { argc: argptrs.len() as i32, argv: argptrs.as_ptr(), allocated: 0 })
+    let mut argptrs: Vec<_> = args.iter().map(|s| s.as_ptr() as *mut c_char).collect();
+    f(&mut fuse_args { argc: argptrs.len() as i32, argv: argptrs.as_mut_ptr(), allocated: 0 })
+}
+
+/// Device of the FUSE kernel driver and the access needed to it
+#[cfg(not(target_os = "macos"))]
+const FUSE_DEVICES: [&str; 1] = ["/dev/fuse"];
+#[cfg(not(target_os = "macos"))]
+const FUSE_DEVICE_ACCESS: c_int = libc::R_OK | libc::W_OK;
+
+/// Bundles of macFUSE and osxfuse, whose mount helpers load the kernel extension on demand
+#[cfg(target_os = "macos")]
+const FUSE_DEVICES: [&str; 2] = ["/Library/Filesystems/macfuse.fs", "/Library/Filesystems/osxfuse.fs"];
+#[cfg(target_os = "macos")]
+const FUSE_DEVICE_ACCESS: c_int = libc::R_OK;
+
+/// Check that one of the given FUSE devices exists and is accessible. A binary that was built
+/// on another machine can run where FUSE isn't installed or loaded, and mounting would then
+/// fail with an obscure error from libfuse.
+fn check_available(devices: &[&str]) -> io::Result<()> {
+    let device = match devices.iter().find(|device| Path::new(device).exists()) {
+        Some(device) => device,
+        None => {
+            let msg = format!("FUSE kernel module not available ({} not found); is it installed and loaded?", devices.join(", "));
+            return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+        }
+    };
+    let path = CString::new(*device)?;
+    if unsafe { libc::access(path.as_ptr(), FUSE_DEVICE_ACCESS) } < 0 {
+        let err = io::Error::last_os_error();
+        let msg = format!("FUSE kernel module not accessible ({}: {}); is the user permitted to use FUSE?", device, err);
+        return Err(io::Error::new(err.kind(), msg));
+    }
+    Ok(())
 }
 
 /// A raw communication channel to the FUSE kernel driver
@@ -26,14 +59,17 @@ fn with_fuse_args<T, F: FnOnce(&fuse_args) -> T>(options: &[&OsStr], f: F) -> T
 pub struct Channel {
     mountpoint: PathBuf,
     fd: c_int,
+    /// True if the channel mounted the mountpoint and needs to unmount it
+    mounted: bool,
 }
 
 impl Channel {
     /// Create a new communication channel to the kernel driver by mounting the
     /// given path. The kernel driver will delegate filesystem operations of
     /// the given path to the channel. If the channel is dropped, the path is
-    /// unmounted.
+    /// unmounted. Fails with a descriptive error if FUSE isn't available.
     pub fn new(mountpoint: &Path, options: &[&OsStr]) -> io::Result<Channel> {
+        check_available(&FUSE_DEVICES)?;
         let mountpoint = mountpoint.canonicalize()?;
         with_fuse_args(options, |args| {
             let mnt = CString::new(mountpoint.as_os_str().as_bytes())?;
@@ -41,11 +77,41 @@ impl Channel {
             if fd < 0 {
                 Err(io::Error::last_os_error())
             } else {
-                Ok(Channel { mountpoint: mountpoint, fd: fd })
+                Ok(Channel { mountpoint: mountpoint, fd: fd, mounted: true })
             }
         })
     }
 
+    /// Create a new communication channel to the kernel driver by mounting the given path
+    /// directly with mount(2), without libfuse. Only root (e.g. of a user namespace, see
+    /// `testing`) is permitted to do so.
+    #[cfg(all(target_os = "linux", feature = "mount-tests"))]
+    pub fn mount_direct(mountpoint: &Path) -> io::Result<Channel> {
+        let mountpoint = mountpoint.canonicalize()?;
+        let mnt = CString::new(mountpoint.as_os_str().as_bytes())?;
+        let dev = CString::new("/dev/fuse").unwrap();
+        let fd = unsafe { libc::open(dev.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fstype = CString::new("fuse").unwrap();
+        let options = crate::testing::mount_options(fd);
+        let rc = unsafe { libc::mount(dev.as_ptr(), mnt.as_ptr(), fstype.as_ptr(), libc::MS_NOSUID | libc::MS_NODEV, options.as_ptr() as *const c_void) };
+        if rc < 0 {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(fd); }
+            return Err(err);
+        }
+        Ok(Channel { mountpoint, fd, mounted: true })
+    }
+
+    /// Create a channel that talks through the given fd (e.g. a socket in tests) instead of
+    /// a mounted kernel driver. The channel takes ownership of the fd.
+    #[cfg(test)]
+    pub fn from_fd(fd: c_int, mountpoint: &Path) -> Channel {
+        Channel { mountpoint: mountpoint.to_path_buf(), fd, mounted: false }
+    }
+
     /// Return path of the mounted filesystem
     pub fn mountpoint(&self) -> &Path {
         &self.mountpoint
@@ -62,6 +128,30 @@ impl Channel {
         }
     }
 
+    /// Wait until a request can be received or the given fd becomes readable. Returns true
+    /// if the given fd is readable (even if there's a request as well).
+    pub fn wait_unless(&self, fd: RawFd) -> io::Result<bool> {
+        let mut fds = [
+            libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd, events: libc::POLLIN, revents: 0 },
+        ];
+        loop {
+            let rc = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+            if rc >= 0 {
+                return Ok(fds[1].revents != 0);
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    /// Returns true if a request can be received without blocking
+    pub fn is_ready(&self) -> io::Result<bool> {
+        is_readable(self.fd)
+    }
+
     /// Returns a sender object for this channel. The sender object can be
     /// used to send to the channel. Multiple sender objects can be used
     /// and they can safely be sent to other threads.
@@ -81,7 +171,9 @@ impl Drop for Channel {
         // (closing it before unnmount prevents sync unmount deadlock)
         unsafe { libc::close(self.fd); }
         // Unmount this channel's mount point
-        let _ = unmount(&self.mountpoint);
+        if self.mounted {
+            let _ = unmount(&self.mountpoint);
+        }
     }
 }
 
@@ -91,18 +183,52 @@ pub struct ChannelSender {
 }
 
 impl ChannelSender {
-    /// Send all data in the slice of slice of bytes in a single write (can block).
+    /// Send all data in the slice of slice of bytes (can block). The data is sent in a
+    /// single write unless the write comes up short, in which case the rest is written
+    /// with further writes.
     pub fn send(&self, buffer: &[&[u8]]) -> io::Result<()> {
-        let iovecs: Vec<_> = buffer.iter().map(|d| {
-            libc::iovec { iov_base: d.as_ptr() as *mut c_void, iov_len: d.len() as size_t }
+        write_all_vectored(buffer, |bufs| {
+            // IoSlice is guaranteed to be ABI compatible with iovec
+            let rc = unsafe { libc::writev(self.fd, bufs.as_ptr() as *const libc::iovec, bufs.len() as c_int) };
+            if rc < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(rc as usize)
+            }
+        })
+    }
+}
+
+/// Write all data in the slice of slice of bytes using the given vectored write function,
+/// which returns the number of bytes it wrote. Writes are repeated after short writes
+/// (continuing in the middle of a slice if needed) and after being interrupted.
+pub(crate) fn write_all_vectored<F>(buffer: &[&[u8]], mut write: F) -> io::Result<()>
+where
+    F: FnMut(&[IoSlice<'_>]) -> io::Result<usize>,
+{
+    // Index of the first slice with unwritten data and the number of bytes written of it
+    let (mut index, mut offset) = (0, 0);
+    while index < buffer.len() && buffer[index].len() == offset {
+        index += 1;
+    }
+    while index < buffer.len() {
+        let bufs: Vec<_> = buffer[index..].iter().enumerate().map(|(i, data)| {
+            IoSlice::new(if i == 0 { &data[offset..] } else { data })
         }).collect();
-        let rc = unsafe { libc::writev(self.fd, iovecs.as_ptr(), iovecs.len() as c_int) };
-        if rc < 0 {
-            Err(io::Error::last_os_error())
-        } else {
-            Ok(())
+        let mut written = match write(&bufs) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write whole reply")),
+            Ok(written) => written,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        while index < buffer.len() && written >= buffer[index].len() - offset {
+            written -= buffer[index].len() - offset;
+            index += 1;
+            offset = 0;
         }
+        offset += written;
     }
+    Ok(())
 }
 
 impl ReplySender for ChannelSender {
@@ -111,6 +237,28 @@ impl ReplySender for ChannelSender {
             error!("Failed to send FUSE reply: {}", err);
         }
     }
+
+    fn send_confirmed(&self, data: &[&[u8]], sent: Box<dyn FnOnce() + Send>) {
+        match ChannelSender::send(self, data) {
+            Ok(()) => sent(),
+            Err(err) => error!("Failed to send FUSE reply: {}", err),
+        }
+    }
+}
+
+/// Returns true if the given fd can be read from without blocking
+pub fn is_readable(fd: RawFd) -> io::Result<bool> {
+    let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    loop {
+        let rc = unsafe { libc::poll(&mut pollfd, 1, 0) };
+        if rc >= 0 {
+            return Ok(pollfd.revents != 0);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
 }
 
 /// Unmount an arbitrary mount point
@@ -159,8 +307,9 @@ pub fn unmount(mountpoint: &Path) -> io::Result<()> {
 
 #[cfg(test)]
 mod test {
-    use super::with_fuse_args;
+    use super::{check_available, with_fuse_args, write_all_vectored};
     use std::ffi::{CStr, OsStr};
+    use std::io::{self, IoSlice};
 
     #[test]
     fn fuse_args() {
@@ -171,4 +320,57 @@ mod test {
             assert_eq!(unsafe { CStr::from_ptr(*args.argv.offset(2)).to_bytes() }, b"bar");
         });
     }
+
+    #[test]
+    fn fuse_unavailable() {
+        let err = check_available(&["/dev/fuse-rs-missing"]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "FUSE kernel module not available (/dev/fuse-rs-missing not found); is it installed and loaded?");
+        // Any of several devices will do
+        check_available(&["/dev/fuse-rs-missing", "/dev/null"]).unwrap();
+    }
+
+    /// Vectored write that writes at most the given number of bytes per call
+    fn short_writes<'a>(out: &'a mut Vec<u8>, calls: &'a mut usize, max: usize) -> impl FnMut(&[IoSlice<'_>]) -> io::Result<usize> + 'a {
+        move |bufs| {
+            // Every other call is interrupted
+            *calls += 1;
+            if *calls & 1 == 0 {
+                return Err(io::Error::from_raw_os_error(libc::EINTR));
+            }
+            let mut written = 0;
+            for buf in bufs {
+                let len = buf.len().min(max - written);
+                out.extend_from_slice(&buf[..len]);
+                written += len;
+            }
+            Ok(written)
+        }
+    }
+
+    #[test]
+    fn write_all_vectored_short_writes() {
+        let buffer: [&[u8]; 4] = [b"header--", b"", b"abcdefghijklmnopqrstuvwxyz", b"0123456789"];
+        let expected: Vec<u8> = buffer.concat();
+        for max in 1..=expected.len() {
+            let (mut out, mut calls) = (Vec::new(), 0);
+            write_all_vectored(&buffer, short_writes(&mut out, &mut calls, max)).unwrap();
+            assert_eq!(out, expected, "{} bytes per write", max);
+        }
+        // Write without data doesn't call the write function
+        write_all_vectored(&[b"", b""], |_| panic!("unexpected write")).unwrap();
+    }
+
+    #[test]
+    fn write_all_vectored_errors() {
+        let err = write_all_vectored(&[b"abc"], |_| Ok(0)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+        let mut calls = 0;
+        let err = write_all_vectored(&[b"abc", b"def"], |bufs| {
+            calls += 1;
+            if calls == 1 { Ok(bufs[0].len() + 1) } else { Err(io::Error::from_raw_os_error(libc::ENOENT)) }
+        }).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        assert_eq!(calls, 2);
+    }
 }
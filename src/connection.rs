@@ -0,0 +1,447 @@
+//! FUSE connection info
+//!
+//! Every mounted FUSE filesystem has a connection in the kernel driver, which can be tuned
+//! and aborted at runtime through a directory in /sys/fs/fuse/connections. The directory is
+//! named after the device number of the mounted filesystem, which is found in
+//! /proc/self/mountinfo. (Calling stat on the mountpoint would tell the device number as
+//! well, but it'd call into the filesystem, which deadlocks if done by the session itself.)
+//!
+//! The same device number is reported as `st_dev` for every file of the filesystem.
+//!
+//! Whether the filesystem is mounted read-only is found in /proc/self/mountinfo as well, which
+//! can be watched for changes by polling it for `POLLPRI`.
+
+use std::ffi::{CString, OsString};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::{Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use libc::{c_int, c_ulong};
+
+/// Directory of FUSE connections in sysfs
+const CONNECTIONS_DIR: &str = "/sys/fs/fuse/connections";
+
+/// Time it may take for the sysfs directory of a connection to be removed after unmounting
+const UNMOUNT_SETTLE_TIME: Duration = Duration::from_millis(100);
+
+/// Interval in which a mount flags watcher checks whether it's still needed
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Unescape a path in mountinfo (spaces, tabs, newlines and backslashes are escaped as
+/// octal numbers, e.g. `\040`)
+fn unescape(field: &str) -> PathBuf {
+    let bytes = field.as_bytes();
+    let mut path = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).filter(|digits| digits.iter().all(|d| (b'0'..=b'7').contains(d)));
+        match (bytes[i], octal) {
+            (b'\\', Some(digits)) => {
+                path.push(digits.iter().fold(0u8, |n, d| n.wrapping_mul(8) + (d - b'0')));
+                i += 4;
+            }
+            (byte, _) => {
+                path.push(byte);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(OsString::from_vec(path))
+}
+
+/// Fields of a FUSE filesystem's line in mountinfo
+struct MountEntry<'a> {
+    /// Device number as major:minor
+    dev: &'a str,
+    /// Options of the mount (e.g. `ro` or `nosuid`)
+    options: &'a str,
+    /// Options of the superblock, i.e. of the filesystem shared by all its mounts
+    super_options: &'a str,
+}
+
+/// Returns the filesystem type and entry of the filesystem mounted by the given line of
+/// mountinfo if it's mounted at the given mountpoint
+fn parse_line<'a>(line: &'a str, mountpoint: &Path) -> Option<(&'a str, MountEntry<'a>)> {
+    // Fields: id, parent id, major:minor, root, mountpoint, options, optional fields...,
+    // separator (-), filesystem type, source, super options
+    let mut fields = line.split(' ');
+    let dev = fields.nth(2)?;
+    let mnt = fields.nth(1)?;
+    let options = fields.next()?;
+    let fstype = fields.find(|&field| field == "-").and_then(|_| fields.next())?;
+    let super_options = fields.nth(1)?;
+    if unescape(mnt) != mountpoint {
+        return None;
+    }
+    Some((fstype, MountEntry { dev, options, super_options }))
+}
+
+/// Tells whether the given filesystem type is FUSE
+fn is_fuse(fstype: &str) -> bool {
+    fstype == "fuse" || fstype == "fuseblk" || fstype.starts_with("fuse.")
+}
+
+/// Returns the entry of the FUSE filesystem mounted at the given mountpoint according to the
+/// given mountinfo. If filesystems are stacked on the mountpoint, the topmost one is used.
+fn fuse_mount<'a>(mountinfo: &'a str, mountpoint: &Path) -> Option<MountEntry<'a>> {
+    mountinfo.lines().rev()
+        .filter_map(|line| parse_line(line, mountpoint))
+        .find(|&(fstype, _)| is_fuse(fstype))
+        .map(|(_, entry)| entry)
+}
+
+/// Parses the major and minor device number of a mountinfo entry
+fn parse_dev(dev: &str) -> Option<(u32, u32)> {
+    let mut dev = dev.splitn(2, ':');
+    let major = dev.next()?.parse().ok()?;
+    let minor = dev.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Returns the major and minor device number of the FUSE filesystem mounted at the given
+/// mountpoint according to the given mountinfo. If filesystems are stacked on the
+/// mountpoint, the topmost one is used.
+fn device_numbers(mountinfo: &str, mountpoint: &Path) -> Option<(u32, u32)> {
+    parse_dev(fuse_mount(mountinfo, mountpoint)?.dev)
+}
+
+/// Tells whether the topmost filesystem mounted at the given mountpoint according to the
+/// given mountinfo is the FUSE filesystem with the given connection id. Unlike
+/// `fuse_mount`, filesystems of other types stacked on top of it aren't skipped.
+fn topmost(mountinfo: &str, mountpoint: &Path, id: u64) -> bool {
+    match mountinfo.lines().rev().find_map(|line| parse_line(line, mountpoint)) {
+        Some((fstype, entry)) => is_fuse(fstype) && parse_dev(entry.dev).map(kernel_dev) == Some(id),
+        None => false,
+    }
+}
+
+/// Tells whether the FUSE filesystem mounted at the given mountpoint is read-only according
+/// to the given mountinfo, either because the mount or because the filesystem is
+fn read_only(mountinfo: &str, mountpoint: &Path) -> Option<bool> {
+    let entry = fuse_mount(mountinfo, mountpoint)?;
+    Some(entry.options.split(',').chain(entry.super_options.split(',')).any(|option| option == "ro"))
+}
+
+/// Kernel internal encoding of device numbers, which names the connection directory
+fn kernel_dev((major, minor): (u32, u32)) -> u64 {
+    u64::from(major) << 20 | u64::from(minor)
+}
+
+/// Returns the major and minor device number of the FUSE filesystem mounted at the given
+/// mountpoint
+fn mounted_device_numbers(mountpoint: &Path) -> io::Result<(u32, u32)> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    device_numbers(&mountinfo, mountpoint).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("no FUSE filesystem mounted at {}", mountpoint.display()))
+    })
+}
+
+/// Returns the connection id of the FUSE filesystem mounted at the given mountpoint
+pub fn connection_id(mountpoint: &Path) -> io::Result<u64> {
+    mounted_device_numbers(mountpoint).map(kernel_dev)
+}
+
+/// Returns the device number of the FUSE filesystem mounted at the given mountpoint, in the
+/// encoding of `st_dev`
+pub fn device_id(mountpoint: &Path) -> io::Result<u64> {
+    let (major, minor) = mounted_device_numbers(mountpoint)?;
+    Ok(libc::makedev(major, minor) as u64)
+}
+
+/// Returns the sysfs directory of the connection of the FUSE filesystem mounted at the
+/// given mountpoint, if it exists (sysfs and the fusectl filesystem must be mounted)
+pub fn sysfs_dir(mountpoint: &Path) -> Option<PathBuf> {
+    let dir = Path::new(CONNECTIONS_DIR).join(connection_id(mountpoint).ok()?.to_string());
+    if dir.is_dir() { Some(dir) } else { None }
+}
+
+/// Abort the connection of the FUSE filesystem mounted at the given mountpoint
+pub fn abort_connection(mountpoint: &Path) -> io::Result<()> {
+    let dir = sysfs_dir(mountpoint).ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+    fs::write(dir.join("abort"), "1")
+}
+
+/// Tells whether the FUSE filesystem with the given connection id is the topmost filesystem
+/// mounted at the given mountpoint, i.e. whether unmounting the mountpoint would unmount it
+pub fn is_topmost(mountpoint: &Path, id: u64) -> io::Result<bool> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    Ok(topmost(&mountinfo, mountpoint, id))
+}
+
+/// Tells whether the connection with the given id, which was mounted at the given mountpoint,
+/// ended by being aborted rather than by unmounting, according to the given mountinfo and
+/// the given check whether the connection still exists. Returns whether the filesystem is
+/// still mounted if it was aborted.
+fn aborted<F: FnOnce() -> bool>(mountinfo: &str, mountpoint: &Path, id: u64, connected: F) -> Option<bool> {
+    if device_numbers(mountinfo, mountpoint).map(kernel_dev) == Some(id) {
+        Some(true)
+    } else if connected() {
+        // Not mounted at the mountpoint anymore, but still alive (e.g. lazily unmounted)
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// Tells whether the ended connection with the given id, which was mounted at the given
+/// mountpoint, was aborted (e.g. through its abort file in sysfs) rather than unmounted, and
+/// whether the filesystem is still mounted if so. A filesystem that was detached from the
+/// mountpoint is only recognized as aborted if the fusectl filesystem is mounted.
+pub fn aborted_connection(mountpoint: &Path, id: u64) -> Option<bool> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+    let dir = Path::new(CONNECTIONS_DIR).join(id.to_string());
+    aborted(&mountinfo, mountpoint, id, || {
+        // After unmounting, the directory is removed right after the connection ended
+        let start = Instant::now();
+        while dir.is_dir() {
+            if start.elapsed() >= UNMOUNT_SETTLE_TIME {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    })
+}
+
+/// Keeps track of whether the FUSE filesystem mounted at a mountpoint is read-only while the
+/// mount table changes (e.g. by `mount -o remount,ro`)
+#[derive(Debug)]
+pub struct MountFlagsWatch {
+    mountpoint: PathBuf,
+    read_only: Option<bool>,
+}
+
+impl MountFlagsWatch {
+    /// Create a new watch for the FUSE filesystem mounted at the given mountpoint
+    pub fn new(mountpoint: &Path) -> MountFlagsWatch {
+        MountFlagsWatch { mountpoint: mountpoint.to_path_buf(), read_only: None }
+    }
+
+    /// Update the state from the given mountinfo. Returns whether the filesystem is read-only
+    /// if that changed since the last update. The first update only sets the initial state,
+    /// and mountinfo without the filesystem (e.g. while it's being unmounted) is ignored.
+    pub fn update(&mut self, mountinfo: &str) -> Option<bool> {
+        let read_only = read_only(mountinfo, &self.mountpoint)?;
+        match self.read_only.replace(read_only) {
+            Some(previous) if previous != read_only => Some(read_only),
+            _ => None,
+        }
+    }
+}
+
+/// Watch the FUSE filesystem mounted at the given mountpoint in a background thread, and
+/// store whether it's read-only in `changed` whenever that changes. The thread ends once
+/// `changed` was dropped.
+pub fn watch_mount_flags(mountpoint: &Path, changed: Weak<Mutex<Option<bool>>>) -> io::Result<()> {
+    let mut file = File::open("/proc/self/mountinfo")?;
+    let mut mountinfo = String::new();
+    file.read_to_string(&mut mountinfo)?;
+    let mut watch = MountFlagsWatch::new(mountpoint);
+    watch.update(&mountinfo);
+    thread::Builder::new().name("fuse-mountinfo".into()).spawn(move || loop {
+        // The kernel signals changes of the mount table with POLLPRI
+        let mut pfd = libc::pollfd { fd: file.as_raw_fd(), events: libc::POLLPRI, revents: 0 };
+        let res = unsafe { libc::poll(&mut pfd, 1, WATCH_INTERVAL.as_millis() as c_int) };
+        let changed = match changed.upgrade() {
+            Some(changed) => changed,
+            None => break,
+        };
+        if res <= 0 {
+            continue;
+        }
+        mountinfo.clear();
+        if file.seek(SeekFrom::Start(0)).and_then(|_| file.read_to_string(&mut mountinfo)).is_err() {
+            break;
+        }
+        if let Some(read_only) = watch.update(&mountinfo) {
+            *changed.lock().unwrap() = Some(read_only);
+        }
+    })?;
+    Ok(())
+}
+
+/// Flags for remounting a mount with the given options read-only or read-write. All other
+/// options are kept, since remounting fails if it would clear locked ones (e.g. `nosuid` of
+/// mounts made by fusermount or in a user namespace).
+fn remount_flags(options: &str, read_only: bool) -> c_ulong {
+    let mut flags = libc::MS_REMOUNT | libc::MS_BIND;
+    for option in options.split(',') {
+        flags |= match option {
+            "nosuid" => libc::MS_NOSUID,
+            "nodev" => libc::MS_NODEV,
+            "noexec" => libc::MS_NOEXEC,
+            "noatime" => libc::MS_NOATIME,
+            "nodiratime" => libc::MS_NODIRATIME,
+            "relatime" => libc::MS_RELATIME,
+            _ => 0,
+        };
+    }
+    if read_only {
+        flags |= libc::MS_RDONLY;
+    }
+    flags
+}
+
+/// Remount the FUSE filesystem mounted at the given mountpoint read-only or read-write. Only
+/// the mount is changed (like `mount -o remount,bind,ro`), not the filesystem's other mounts.
+/// Needs `CAP_SYS_ADMIN`, fails with `PermissionDenied` otherwise.
+pub fn remount(mountpoint: &Path, read_only: bool) -> io::Result<()> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    let entry = fuse_mount(&mountinfo, mountpoint).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("no FUSE filesystem mounted at {}", mountpoint.display()))
+    })?;
+    let mnt = CString::new(mountpoint.as_os_str().as_bytes())?;
+    let flags = remount_flags(entry.options, read_only);
+    if unsafe { libc::mount(ptr::null(), mnt.as_ptr(), ptr::null(), flags, ptr::null()) } == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EPERM) => Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("not permitted to remount {}", mountpoint.display()))),
+        _ => Err(err),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use super::{aborted, device_numbers, kernel_dev, read_only, remount_flags, topmost, unescape, MountFlagsWatch};
+
+    fn parse_mountinfo(mountinfo: &str, mountpoint: &Path) -> Option<u64> {
+        device_numbers(mountinfo, mountpoint).map(kernel_dev)
+    }
+
+    /// Linux 3.x, without optional fields
+    const MOUNTINFO_3: &str = "\
+15 20 0:3 / /proc rw,relatime - proc proc rw
+20 1 8:1 / / rw,relatime - ext4 /dev/sda1 rw,data=ordered
+35 20 0:31 / /home/user/mnt rw,nosuid,nodev,relatime - fuse.hello hello rw,user_id=1000,group_id=1000
+";
+
+    /// Linux 4.x, with shared subtrees and a fuseblk filesystem
+    const MOUNTINFO_4: &str = "\
+22 1 8:2 / / rw,relatime shared:1 - ext4 /dev/sda2 rw,errors=remount-ro
+50 22 8:17 / /media/usb rw,nosuid,nodev,relatime shared:30 - fuseblk /dev/sdb1 rw,user_id=0,group_id=0,allow_other,blksize=4096
+51 22 0:45 / /tmp/my\\040mount rw,nosuid,nodev,relatime shared:31 master:2 - fuse fsname rw,user_id=1000,group_id=1000
+";
+
+    /// Linux 5.x, with a filesystem mounted over another one at the same mountpoint
+    const MOUNTINFO_5: &str = "\
+25 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw
+40 25 0:38 / /srv/data rw,nosuid,nodev,relatime shared:20 - fuse.audit audit rw,user_id=0,group_id=0
+41 40 0:52 / /srv/data rw,nosuid,nodev,relatime shared:21 - fuse.audit audit rw,user_id=0,group_id=0
+42 25 0:53 / /srv/other rw,relatime shared:22 - tmpfs tmpfs rw
+";
+
+    #[test]
+    fn mountinfo_without_optional_fields() {
+        assert_eq!(parse_mountinfo(MOUNTINFO_3, Path::new("/home/user/mnt")), Some(31));
+        assert_eq!(parse_mountinfo(MOUNTINFO_3, Path::new("/proc")), None);
+        assert_eq!(parse_mountinfo(MOUNTINFO_3, Path::new("/home/user")), None);
+    }
+
+    #[test]
+    fn mountinfo_with_optional_fields() {
+        assert_eq!(parse_mountinfo(MOUNTINFO_4, Path::new("/media/usb")), Some(8 << 20 | 17));
+        assert_eq!(parse_mountinfo(MOUNTINFO_4, Path::new("/tmp/my mount")), Some(45));
+        assert_eq!(parse_mountinfo(MOUNTINFO_4, Path::new("/")), None);
+    }
+
+    #[test]
+    fn mountinfo_stacked_mounts() {
+        assert_eq!(parse_mountinfo(MOUNTINFO_5, Path::new("/srv/data")), Some(52));
+        assert_eq!(parse_mountinfo(MOUNTINFO_5, Path::new("/srv/other")), None);
+        assert_eq!(parse_mountinfo("garbage\n\n1 2 x:y / /srv/data rw - fuse x rw\n", Path::new("/srv/data")), None);
+    }
+
+    #[test]
+    fn mountinfo_device_numbers() {
+        assert_eq!(device_numbers(MOUNTINFO_4, Path::new("/media/usb")), Some((8, 17)));
+        assert_eq!(device_numbers(MOUNTINFO_5, Path::new("/srv/data")), Some((0, 52)));
+        // Minor numbers above 255 are encoded differently in st_dev and connection ids
+        let mountinfo = "60 25 0:300 / /mnt rw - fuse.big big rw\n";
+        assert_eq!(device_numbers(mountinfo, Path::new("/mnt")), Some((0, 300)));
+        assert_eq!(parse_mountinfo(mountinfo, Path::new("/mnt")), Some(300));
+        assert_eq!(libc::makedev(0, 300) as u64, 0x10_002c);
+    }
+
+    #[test]
+    fn mountinfo_topmost() {
+        assert!(topmost(MOUNTINFO_5, Path::new("/srv/data"), 52));
+        // Covered by another FUSE filesystem
+        assert!(!topmost(MOUNTINFO_5, Path::new("/srv/data"), 38));
+        assert!(!topmost(MOUNTINFO_5, Path::new("/srv/other"), 53));
+        assert!(!topmost(MOUNTINFO_5, Path::new("/srv/gone"), 52));
+        // Covered by a filesystem of another type
+        let stacked = format!("{}43 41 0:54 / /srv/data rw,relatime shared:23 - tmpfs tmpfs rw\n", MOUNTINFO_5);
+        assert!(!topmost(&stacked, Path::new("/srv/data"), 52));
+    }
+
+    #[test]
+    fn unescape_paths() {
+        assert_eq!(unescape("/a\\040b\\011c\\134d"), Path::new("/a b\tc\\d"));
+        assert_eq!(unescape("/a\\x\\04"), Path::new("/a\\x\\04"));
+    }
+
+    #[test]
+    fn aborted_connection() {
+        let mountpoint = Path::new("/home/user/mnt");
+        // Still mounted after the connection ended
+        assert_eq!(aborted(MOUNTINFO_3, mountpoint, 31, || unreachable!()), Some(true));
+        // Unmounted, and the connection is gone
+        assert_eq!(aborted(MOUNTINFO_4, mountpoint, 31, || false), None);
+        // Detached, but the connection is still alive
+        assert_eq!(aborted(MOUNTINFO_4, mountpoint, 31, || true), Some(false));
+        // Another filesystem is mounted at the same mountpoint now
+        assert_eq!(aborted(MOUNTINFO_3, mountpoint, 32, || false), None);
+    }
+
+    #[test]
+    fn mountinfo_read_only() {
+        assert_eq!(read_only(MOUNTINFO_3, Path::new("/home/user/mnt")), Some(false));
+        assert_eq!(read_only(MOUNTINFO_3, Path::new("/proc")), None);
+        // Read-only mount of a read-write filesystem and the other way round
+        let mountinfo = "35 20 0:31 / /mnt ro,nosuid - fuse.hello hello rw,user_id=0\n36 20 0:32 / /ro rw - fuse.ro ro ro,user_id=0\n";
+        assert_eq!(read_only(mountinfo, Path::new("/mnt")), Some(true));
+        assert_eq!(read_only(mountinfo, Path::new("/ro")), Some(true));
+        // Options that merely start with ro
+        let mountinfo = "35 20 0:31 / /mnt rw,rootcontext=x - fuse.hello hello rw,romode\n";
+        assert_eq!(read_only(mountinfo, Path::new("/mnt")), Some(false));
+    }
+
+    #[test]
+    fn mount_flags_watch() {
+        let line = |options: &str| format!("22 1 8:2 / / rw - ext4 /dev/sda2 rw\n35 22 0:31 / /mnt {} - fuse.hello hello rw\n", options);
+        let mut watch = MountFlagsWatch::new(Path::new("/mnt"));
+        // The initial state isn't reported
+        assert_eq!(watch.update(&line("rw,nosuid")), None);
+        assert_eq!(watch.update(&line("rw,nosuid,noexec")), None);
+        assert_eq!(watch.update(&line("ro,nosuid")), Some(true));
+        assert_eq!(watch.update(&line("ro,nosuid")), None);
+        // Unmounted in between, e.g. while being unmounted
+        assert_eq!(watch.update("22 1 8:2 / / rw - ext4 /dev/sda2 rw\n"), None);
+        assert_eq!(watch.update(&line("ro,nosuid")), None);
+        assert_eq!(watch.update(&line("rw,nosuid")), Some(false));
+        // A read-only mount stacked on top
+        let stacked = format!("{}36 35 0:32 / /mnt ro - fuse.other other rw\n", line("rw"));
+        assert_eq!(watch.update(&stacked), Some(true));
+        // Mounted read-only from the start
+        let mut watch = MountFlagsWatch::new(Path::new("/mnt"));
+        assert_eq!(watch.update(&line("ro")), None);
+        assert_eq!(watch.update(&line("rw")), Some(false));
+    }
+
+    #[test]
+    fn remount_flags_keep_options() {
+        let flags = remount_flags("rw,nosuid,nodev,relatime", true);
+        assert_eq!(flags, libc::MS_REMOUNT | libc::MS_BIND | libc::MS_NOSUID | libc::MS_NODEV | libc::MS_RELATIME | libc::MS_RDONLY);
+        let flags = remount_flags("ro,noexec,noatime", false);
+        assert_eq!(flags, libc::MS_REMOUNT | libc::MS_BIND | libc::MS_NOEXEC | libc::MS_NOATIME);
+    }
+}
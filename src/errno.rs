@@ -0,0 +1,134 @@
+//! Error code mapping
+//!
+//! Filesystem methods reply with plain error codes (errno values). Backends usually fail with
+//! richer error types, which can be converted by implementing `ToErrno`.
+
+use std::ffi::NulError;
+use std::io;
+use libc::c_int;
+use crate::FileAttrTryFromError;
+
+/// Conversion of an error into an error code to reply with, e.g. `reply.error(err.to_errno())`.
+/// Implement it for custom error types to map them to meaningful error codes. Errors that
+/// don't correspond to any error code should map to `EIO`.
+pub trait ToErrno {
+    /// Returns the error code for this error
+    fn to_errno(&self) -> c_int;
+}
+
+impl ToErrno for c_int {
+    fn to_errno(&self) -> c_int {
+        *self
+    }
+}
+
+/// Errors of system calls map to the error code of the system call. Other errors map to an
+/// error code that fits their kind, or `EIO` if there's none.
+impl ToErrno for io::Error {
+    fn to_errno(&self) -> c_int {
+        match self.raw_os_error() {
+            Some(errno) => errno,
+            None => self.kind().to_errno(),
+        }
+    }
+}
+
+impl ToErrno for io::ErrorKind {
+    fn to_errno(&self) -> c_int {
+        match *self {
+            io::ErrorKind::NotFound => libc::ENOENT,
+            io::ErrorKind::PermissionDenied => libc::EACCES,
+            io::ErrorKind::ConnectionRefused => libc::ECONNREFUSED,
+            io::ErrorKind::ConnectionReset => libc::ECONNRESET,
+            io::ErrorKind::ConnectionAborted => libc::ECONNABORTED,
+            io::ErrorKind::NotConnected => libc::ENOTCONN,
+            io::ErrorKind::AddrInUse => libc::EADDRINUSE,
+            io::ErrorKind::AddrNotAvailable => libc::EADDRNOTAVAIL,
+            io::ErrorKind::BrokenPipe => libc::EPIPE,
+            io::ErrorKind::AlreadyExists => libc::EEXIST,
+            io::ErrorKind::WouldBlock => libc::EAGAIN,
+            io::ErrorKind::InvalidInput => libc::EINVAL,
+            io::ErrorKind::TimedOut => libc::ETIMEDOUT,
+            io::ErrorKind::Interrupted => libc::EINTR,
+            _ => libc::EIO,
+        }
+    }
+}
+
+/// Paths or names with interior nul bytes are invalid arguments
+impl ToErrno for NulError {
+    fn to_errno(&self) -> c_int {
+        libc::EINVAL
+    }
+}
+
+/// Files that can't be represented in file attributes, like stat(2) fails for files whose
+/// attributes don't fit
+impl ToErrno for FileAttrTryFromError {
+    fn to_errno(&self) -> c_int {
+        match self {
+            FileAttrTryFromError::UnknownFileType(_) => libc::EIO,
+            FileAttrTryFromError::Overflow(_) => libc::EOVERFLOW,
+        }
+    }
+}
+
+impl<T: ToErrno + ?Sized> ToErrno for &T {
+    fn to_errno(&self) -> c_int {
+        (**self).to_errno()
+    }
+}
+
+impl<T: ToErrno + ?Sized> ToErrno for Box<T> {
+    fn to_errno(&self) -> c_int {
+        (**self).to_errno()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::ffi::CString;
+    use std::io;
+    use libc::{c_int, EACCES, EIO, ENOENT, ENOSPC, EINVAL, ESTALE};
+    use super::ToErrno;
+
+    #[test]
+    fn io_error() {
+        assert_eq!(io::Error::from_raw_os_error(ENOSPC).to_errno(), ENOSPC);
+        assert_eq!(io::Error::from(io::ErrorKind::NotFound).to_errno(), ENOENT);
+        assert_eq!(io::Error::new(io::ErrorKind::PermissionDenied, "denied").to_errno(), EACCES);
+        assert_eq!(io::Error::new(io::ErrorKind::InvalidData, "corrupt").to_errno(), EIO);
+        assert_eq!(CString::new("a\0b").unwrap_err().to_errno(), EINVAL);
+    }
+
+    #[derive(Debug)]
+    enum BackendError {
+        Missing,
+        Stale,
+        Io(io::Error),
+    }
+
+    impl ToErrno for BackendError {
+        fn to_errno(&self) -> c_int {
+            match self {
+                BackendError::Missing => ENOENT,
+                BackendError::Stale => ESTALE,
+                BackendError::Io(err) => err.to_errno(),
+            }
+        }
+    }
+
+    fn reply_error<E: ToErrno>(err: E) -> c_int {
+        err.to_errno()
+    }
+
+    #[test]
+    fn custom_error() {
+        assert_eq!(reply_error(BackendError::Missing), ENOENT);
+        assert_eq!(reply_error(&BackendError::Stale), ESTALE);
+        assert_eq!(reply_error(BackendError::Io(io::Error::from_raw_os_error(ENOSPC))), ENOSPC);
+        let boxed: Box<dyn ToErrno> = Box::new(BackendError::Io(io::ErrorKind::UnexpectedEof.into()));
+        assert_eq!(reply_error(boxed), EIO);
+    }
+}
@@ -0,0 +1,350 @@
+//! Fair dispatch of requests
+//!
+//! The session loop dispatches requests in the order the kernel driver queued them. If one
+//! application issues large reads or writes while another one does small metadata operations
+//! on the same mount, the metadata operations wait behind megabytes of data. With fair
+//! dispatch, the session loop reads requests ahead into per-class queues (bounded by the
+//! queued bytes) and dispatches from them by deficit round-robin: in every round, each queue
+//! may dispatch requests up to a quantum of bytes, so metadata operations interleave with
+//! data operations instead of queueing behind them.
+//!
+//! Requests of the same class are dispatched in the order they were received. Reordering
+//! requests of different classes is fine since the kernel driver only sends requests
+//! concurrently if they don't depend on each other.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::ll;
+
+/// Default max number of bytes of queued requests
+const DEFAULT_MAX_QUEUED_BYTES: usize = 16 * 1024 * 1024;
+
+/// Default number of bytes a queue may dispatch per round
+const DEFAULT_QUANTUM: usize = 128 * 1024;
+
+/// Class of a request for fair dispatch
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum RequestClass {
+    /// Operations on inodes and directory entries (e.g. lookup, getattr, create)
+    Metadata,
+    /// Operations that transfer file or directory contents (read, write, readdir, fallocate
+    /// and copy_file_range)
+    Data,
+}
+
+impl RequestClass {
+    /// Returns the class of the given request
+    pub(crate) fn of(request: &ll::Request<'_>) -> RequestClass {
+        match request.operation() {
+            ll::Operation::Read { .. } | ll::Operation::Write { .. } | ll::Operation::ReadDir { .. } => RequestClass::Data,
+            #[cfg(feature = "abi-7-19")]
+            ll::Operation::FAllocate { .. } => RequestClass::Data,
+            #[cfg(feature = "abi-7-21")]
+            ll::Operation::ReadDirPlus { .. } => RequestClass::Data,
+            #[cfg(feature = "abi-7-28")]
+            ll::Operation::CopyFileRange { .. } => RequestClass::Data,
+            _ => RequestClass::Metadata,
+        }
+    }
+
+    pub(crate) fn index(self) -> usize {
+        match self {
+            RequestClass::Metadata => 0,
+            RequestClass::Data => 1,
+        }
+    }
+}
+
+/// Settings for fair dispatch (see `SessionBuilder::fair_dispatch`)
+#[derive(Clone, Debug)]
+pub struct FairDispatch {
+    max_queued_bytes: usize,
+    quantum: usize,
+    by_pid: bool,
+}
+
+impl Default for FairDispatch {
+    fn default() -> FairDispatch {
+        FairDispatch::new()
+    }
+}
+
+impl FairDispatch {
+    /// Create default settings for fair dispatch
+    pub fn new() -> FairDispatch {
+        FairDispatch { max_queued_bytes: DEFAULT_MAX_QUEUED_BYTES, quantum: DEFAULT_QUANTUM, by_pid: false }
+    }
+
+    /// Set the max number of bytes of requests that are read ahead and queued (defaults to
+    /// 16 MiB). At least one request is always queued.
+    pub fn max_queued_bytes(mut self, bytes: usize) -> FairDispatch {
+        self.max_queued_bytes = bytes;
+        self
+    }
+
+    /// Returns the max number of bytes of queued requests
+    pub(crate) fn queued_bytes_limit(&self) -> usize {
+        self.max_queued_bytes
+    }
+
+    /// Set the number of bytes each queue may dispatch per round (defaults to 128 KiB). The
+    /// smaller the quantum, the fewer data operations are dispatched between two metadata
+    /// operations.
+    pub fn quantum(mut self, bytes: usize) -> FairDispatch {
+        self.quantum = if bytes == 0 { 1 } else { bytes };
+        self
+    }
+
+    /// Queue requests of each requesting process separately, so that processes get their
+    /// fair share as well. Requests of the same class are then only dispatched in order per
+    /// process.
+    pub fn by_pid(mut self) -> FairDispatch {
+        self.by_pid = true;
+        self
+    }
+}
+
+/// Dispatch statistics of a request class
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ClassStats {
+    /// Number of currently queued requests
+    pub queued: usize,
+    /// Number of dispatched requests
+    pub dispatched: u64,
+    /// Total time dispatched requests waited in the queue
+    pub total_wait: Duration,
+    /// Longest time a dispatched request waited in the queue
+    pub max_wait: Duration,
+}
+
+/// Dispatch statistics of a session with fair dispatch. Clones share the same statistics,
+/// so they can be inspected while the session is running.
+#[derive(Clone, Debug, Default)]
+pub struct DispatchStats {
+    classes: Arc<Mutex<[ClassStats; 2]>>,
+}
+
+impl DispatchStats {
+    /// Returns the statistics of the given request class
+    pub fn class(&self, class: RequestClass) -> ClassStats {
+        self.classes.lock().unwrap()[class.index()]
+    }
+}
+
+/// A queued entry
+#[derive(Debug)]
+struct Entry<T> {
+    item: T,
+    cost: usize,
+    received: Instant,
+}
+
+/// Requests of one class (and process)
+#[derive(Debug)]
+struct Queue<T> {
+    entries: VecDeque<Entry<T>>,
+    /// Number of bytes the queue may still dispatch in the current round
+    deficit: usize,
+}
+
+/// Queues of requests that are dispatched by deficit round-robin
+#[derive(Debug)]
+pub(crate) struct FairQueue<T> {
+    quantum: usize,
+    by_pid: bool,
+    queues: HashMap<(RequestClass, Option<u32>), Queue<T>>,
+    /// Keys of non-empty queues in round-robin order, the current one first
+    active: VecDeque<(RequestClass, Option<u32>)>,
+    /// True if the current queue got its quantum for this round already
+    visiting: bool,
+    queued_bytes: usize,
+    stats: DispatchStats,
+}
+
+impl<T> FairQueue<T> {
+    /// Create empty queues with the given settings that record statistics to the given stats
+    pub fn new(config: &FairDispatch, stats: DispatchStats) -> FairQueue<T> {
+        FairQueue {
+            quantum: config.quantum,
+            by_pid: config.by_pid,
+            queues: HashMap::new(),
+            active: VecDeque::new(),
+            visiting: false,
+            queued_bytes: 0,
+            stats,
+        }
+    }
+
+    /// Returns true if nothing is queued
+    pub fn is_empty(&self) -> bool {
+        self.active.is_empty()
+    }
+
+    /// Returns the number of bytes of queued requests
+    pub fn queued_bytes(&self) -> usize {
+        self.queued_bytes
+    }
+
+    /// Queue a request of the given class and process that costs the given number of bytes
+    pub fn push(&mut self, item: T, class: RequestClass, pid: u32, cost: usize, received: Instant) {
+        let key = (class, if self.by_pid { Some(pid) } else { None });
+        let active = &mut self.active;
+        let queue = self.queues.entry(key).or_insert_with(|| Queue { entries: VecDeque::new(), deficit: 0 });
+        if queue.entries.is_empty() {
+            active.push_back(key);
+        }
+        queue.entries.push_back(Entry { item, cost, received });
+        self.queued_bytes += cost;
+        self.stats.classes.lock().unwrap()[class.index()].queued += 1;
+    }
+
+    /// Take the next request to dispatch
+    pub fn pop(&mut self, now: Instant) -> Option<T> {
+        loop {
+            let key = *self.active.front()?;
+            let queue = self.queues.get_mut(&key).expect("active queue must exist");
+            if !self.visiting {
+                queue.deficit = queue.deficit.saturating_add(self.quantum);
+                self.visiting = true;
+            }
+            let cost = queue.entries.front().expect("active queue must not be empty").cost;
+            if cost > queue.deficit {
+                // Not enough left for this round, the next queue's turn
+                self.active.rotate_left(1);
+                self.visiting = false;
+                continue;
+            }
+            queue.deficit -= cost;
+            let entry = queue.entries.pop_front().unwrap();
+            if queue.entries.is_empty() {
+                // Idle queues don't save up for later rounds
+                self.queues.remove(&key);
+                self.active.pop_front();
+                self.visiting = false;
+            }
+            self.queued_bytes -= entry.cost;
+            let wait = now.saturating_duration_since(entry.received);
+            let mut classes = self.stats.classes.lock().unwrap();
+            let stats = &mut classes[key.0.index()];
+            stats.queued -= 1;
+            stats.dispatched += 1;
+            stats.total_wait += wait;
+            stats.max_wait = stats.max_wait.max(wait);
+            return Some(entry.item);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    use super::{DispatchStats, FairDispatch, FairQueue, RequestClass};
+
+    const WRITE_SIZE: usize = 128 * 1024;
+
+    /// Dispatch a 16 MB write burst while a getattr arrives after every 8th dispatched
+    /// request. Returns the number of requests dispatched between arrival and dispatch of
+    /// each getattr.
+    fn getattr_latencies(config: &FairDispatch) -> Vec<usize> {
+        let mut queue = FairQueue::new(config, DispatchStats::default());
+        let now = Instant::now();
+        for i in 0..128usize {
+            queue.push(("write", i), RequestClass::Data, 100, WRITE_SIZE + 80, now);
+        }
+        let mut arrivals = Vec::new();
+        let mut latencies = Vec::new();
+        let mut writes = 0;
+        let mut dispatched = 0usize;
+        while let Some(item) = queue.pop(now) {
+            match item {
+                ("getattr", i) => {
+                    // Same class in order
+                    assert_eq!(i, latencies.len());
+                    latencies.push(dispatched - arrivals[i]);
+                }
+                (_, i) => {
+                    assert_eq!(i, writes);
+                    writes += 1;
+                }
+            }
+            dispatched += 1;
+            if arrivals.len() < 16 && dispatched == 8 * (arrivals.len() + 1) {
+                queue.push(("getattr", arrivals.len()), RequestClass::Metadata, 200, 56, now);
+                arrivals.push(dispatched);
+            }
+        }
+        assert_eq!((writes, latencies.len()), (128, 16));
+        latencies
+    }
+
+    #[test]
+    fn metadata_interleaves_with_data() {
+        // Without fair dispatch, every getattr would wait for the rest of the burst. With it,
+        // a getattr waits for at most the writes of the data queue's current round.
+        let latencies = getattr_latencies(&FairDispatch::new());
+        assert!(latencies.iter().all(|&latency| latency <= 1), "{:?}", latencies);
+        // A larger quantum dispatches more writes per round
+        let latencies = getattr_latencies(&FairDispatch::new().quantum(4 * WRITE_SIZE));
+        assert!(latencies.iter().all(|&latency| latency <= 4), "{:?}", latencies);
+        assert!(latencies.iter().any(|&latency| latency > 1), "{:?}", latencies);
+    }
+
+    #[test]
+    fn deficit_carries_over() {
+        // Requests larger than the quantum are dispatched once enough deficit accumulated
+        let mut queue = FairQueue::new(&FairDispatch::new().quantum(100), DispatchStats::default());
+        let now = Instant::now();
+        queue.push("big", RequestClass::Data, 1, 250, now);
+        queue.push("small1", RequestClass::Metadata, 1, 60, now);
+        queue.push("small2", RequestClass::Metadata, 1, 60, now);
+        queue.push("small3", RequestClass::Metadata, 1, 60, now);
+        let order: Vec<_> = (0..4).map(|_| queue.pop(now).unwrap()).collect();
+        assert_eq!(order, ["small1", "small2", "small3", "big"]);
+        assert!(queue.is_empty());
+        assert_eq!(queue.queued_bytes(), 0);
+    }
+
+    #[test]
+    fn queues_by_pid() {
+        // Two processes reading, one of them much more
+        let config = FairDispatch::new().quantum(100).by_pid();
+        let mut queue = FairQueue::new(&config, DispatchStats::default());
+        let now = Instant::now();
+        for i in 0..4 {
+            queue.push((1, i), RequestClass::Data, 1, 100, now);
+        }
+        queue.push((2, 0), RequestClass::Data, 2, 100, now);
+        queue.push((2, 1), RequestClass::Data, 2, 100, now);
+        let order: Vec<_> = (0..6).map(|_| queue.pop(now).unwrap()).collect();
+        assert_eq!(order, [(1, 0), (2, 0), (1, 1), (2, 1), (1, 2), (1, 3)]);
+        // Without, the same class is dispatched in order
+        let mut queue = FairQueue::new(&FairDispatch::new().quantum(100), DispatchStats::default());
+        queue.push((1, 0), RequestClass::Data, 1, 100, now);
+        queue.push((1, 1), RequestClass::Data, 1, 100, now);
+        queue.push((2, 0), RequestClass::Data, 2, 100, now);
+        let order: Vec<_> = (0..3).map(|_| queue.pop(now).unwrap()).collect();
+        assert_eq!(order, [(1, 0), (1, 1), (2, 0)]);
+    }
+
+    #[test]
+    fn stats() {
+        let stats = DispatchStats::default();
+        let mut queue = FairQueue::new(&FairDispatch::new(), stats.clone());
+        let now = Instant::now();
+        queue.push(1, RequestClass::Data, 1, 1000, now);
+        queue.push(2, RequestClass::Data, 1, 1000, now + Duration::from_millis(10));
+        queue.push(3, RequestClass::Metadata, 1, 50, now);
+        assert_eq!(stats.class(RequestClass::Data).queued, 2);
+        assert_eq!(queue.queued_bytes(), 2050);
+        while queue.pop(now + Duration::from_millis(30)).is_some() {}
+        let data = stats.class(RequestClass::Data);
+        assert_eq!(data.queued, 0);
+        assert_eq!(data.dispatched, 2);
+        assert_eq!(data.total_wait, Duration::from_millis(50));
+        assert_eq!(data.max_wait, Duration::from_millis(30));
+        assert_eq!(stats.class(RequestClass::Metadata).dispatched, 1);
+    }
+}
@@ -0,0 +1,1136 @@
+//! Fault injection
+//!
+//! Applications should handle failing filesystem operations gracefully, but real failures are
+//! rare and hard to provoke. `FaultInjectingFilesystem` wraps a filesystem and makes
+//! operations fail, stall or return bad data according to a set of rules, so that an
+//! application can be tested against these failures reproducibly.
+//!
+//! A `FaultRule` selects operations by their kind (`FaultOp`) or class (`RequestClass`), the
+//! inode they're on and the name of the directory entry they're on (with a glob pattern), and
+//! triggers depending on how often it matched before and with a given probability. Random
+//! numbers come from a seeded generator, so a run with the same requests and rules injects
+//! the same faults. When triggered, the rule's `FaultAction` is applied:
+//!
+//! * Before calling the wrapped filesystem (the default): an error replies without calling
+//!   it, a delay stalls the operation before it's executed.
+//! * After calling the wrapped filesystem: an error replaces its reply (the operation was
+//!   executed, but the application sees it fail), a delay stalls its reply.
+//!
+//! Truncating and corrupting read data always apply to the reply of the wrapped filesystem.
+//! Since silently corrupted data can do real damage if it's written back by the application,
+//! corruption must be allowed explicitly (see `FaultInjectingFilesystem::allow_read_corruption`).
+//!
+//! Rules are evaluated in the order they were added. Per phase (before and after calling the
+//! wrapped filesystem), the first rule that triggers applies; the rules after it aren't
+//! evaluated and don't count the operation. Rules can be added and removed at any time with
+//! a `FaultHandle`, and can be loaded from text (see `parse_rules`).
+
+use std::error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use fuse_abi::fuse_out_header;
+use libc::c_int;
+
+use crate::reply::{Intercept, ReplySender};
+use crate::{CreateMode, Filesystem, InitConfig, Request, RequestClass, SetattrChanges, ShutdownReason};
+use crate::{ReplyEntry, ReplyAttr, ReplyData, ReplyOpen, ReplyWrite, ReplyEmpty, ReplyStatfs};
+use crate::{ReplyCreate, ReplyLock, ReplyBmap, ReplyDirectory, ReplyXattr};
+#[cfg(feature = "abi-7-19")]
+use crate::FallocateRequest;
+#[cfg(feature = "abi-7-23")]
+use crate::Rename2Request;
+#[cfg(target_os = "macos")]
+use crate::ReplyXTimes;
+#[cfg(feature = "abi-7-16")]
+use crate::ForgetOne;
+#[cfg(feature = "abi-7-21")]
+use crate::ReplyDirectoryPlus;
+#[cfg(feature = "abi-7-24")]
+use crate::ReplyLseek;
+#[cfg(feature = "abi-7-11")]
+use crate::{IoctlRequest, ReplyIoctl};
+
+/// Operations that faults can be injected into
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[allow(missing_docs)]
+pub enum FaultOp {
+    Lookup, Getattr, Setattr, Readlink, Mknod, Mkdir, Unlink, Rmdir, Symlink, Rename, Rename2,
+    Link, Open, Read, Write, Flush, Release, Fsync, Opendir, Readdir, Readdirplus, Releasedir,
+    Fsyncdir, Statfs, Setxattr, Getxattr, Listxattr, Removexattr, Access, Create, Tmpfile, Getlk,
+    Setlk, Bmap, Ioctl, Fallocate, Lseek, Setvolname, Exchange, Getxtimes,
+}
+
+/// Operations and their names in rule text (the names of the `Filesystem` methods)
+const OP_NAMES: [(FaultOp, &str); 40] = [
+    (FaultOp::Lookup, "lookup"), (FaultOp::Getattr, "getattr"), (FaultOp::Setattr, "setattr"),
+    (FaultOp::Readlink, "readlink"), (FaultOp::Mknod, "mknod"), (FaultOp::Mkdir, "mkdir"),
+    (FaultOp::Unlink, "unlink"), (FaultOp::Rmdir, "rmdir"), (FaultOp::Symlink, "symlink"),
+    (FaultOp::Rename, "rename"), (FaultOp::Rename2, "rename2"), (FaultOp::Link, "link"),
+    (FaultOp::Open, "open"), (FaultOp::Read, "read"), (FaultOp::Write, "write"),
+    (FaultOp::Flush, "flush"), (FaultOp::Release, "release"), (FaultOp::Fsync, "fsync"),
+    (FaultOp::Opendir, "opendir"), (FaultOp::Readdir, "readdir"), (FaultOp::Readdirplus, "readdirplus"),
+    (FaultOp::Releasedir, "releasedir"), (FaultOp::Fsyncdir, "fsyncdir"), (FaultOp::Statfs, "statfs"),
+    (FaultOp::Setxattr, "setxattr"), (FaultOp::Getxattr, "getxattr"), (FaultOp::Listxattr, "listxattr"),
+    (FaultOp::Removexattr, "removexattr"), (FaultOp::Access, "access"), (FaultOp::Create, "create"),
+    (FaultOp::Tmpfile, "tmpfile"), (FaultOp::Getlk, "getlk"), (FaultOp::Setlk, "setlk"),
+    (FaultOp::Bmap, "bmap"), (FaultOp::Ioctl, "ioctl"), (FaultOp::Fallocate, "fallocate"),
+    (FaultOp::Lseek, "lseek"), (FaultOp::Setvolname, "setvolname"), (FaultOp::Exchange, "exchange"),
+    (FaultOp::Getxtimes, "getxtimes"),
+];
+
+impl FaultOp {
+    /// Returns the class of requests of this operation
+    pub fn class(self) -> RequestClass {
+        match self {
+            FaultOp::Read | FaultOp::Write | FaultOp::Readdir | FaultOp::Readdirplus => RequestClass::Data,
+            _ => RequestClass::Metadata,
+        }
+    }
+}
+
+/// What to do with an operation a rule triggered for
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FaultAction {
+    /// Fail with the given error code
+    Error(c_int),
+    /// Stall for the given duration. Delays block the thread that executes the operation (or
+    /// sends the reply), which is usually the session loop.
+    Delay(Duration),
+    /// Return at most the given number of bytes from reads (short reads)
+    TruncateRead(usize),
+    /// Invert the given number of bytes at the start of the data returned from reads. Must be
+    /// allowed explicitly (see `FaultInjectingFilesystem::allow_read_corruption`).
+    CorruptRead(usize),
+}
+
+impl FaultAction {
+    /// Returns true if the action changes the data of read replies
+    fn is_read_data(self) -> bool {
+        match self {
+            FaultAction::TruncateRead(_) | FaultAction::CorruptRead(_) => true,
+            FaultAction::Error(_) | FaultAction::Delay(_) => false,
+        }
+    }
+
+    /// Apply the action to the given reply (header and data) and send it with the given sender
+    fn apply(self, clock: &dyn Clock, reply: &[&[u8]], sender: &dyn ReplySender) {
+        let header = reply[0];
+        let mut error = [0; 4];
+        error.copy_from_slice(&header[4..8]);
+        match self {
+            FaultAction::Error(err) => send_reply(sender, header, err, &[]),
+            FaultAction::Delay(duration) => {
+                clock.sleep(duration);
+                sender.send(reply);
+            }
+            // Failed reads stay failed
+            _ if i32::from_ne_bytes(error) != 0 => sender.send(reply),
+            FaultAction::TruncateRead(len) => {
+                let mut data = reply[1..].concat();
+                data.truncate(len);
+                send_reply(sender, header, 0, &data);
+            }
+            FaultAction::CorruptRead(len) => {
+                let mut data = reply[1..].concat();
+                for byte in data.iter_mut().take(len) {
+                    *byte = !*byte;
+                }
+                send_reply(sender, header, 0, &data);
+            }
+        }
+    }
+}
+
+/// Send a reply with the given error code and data, using the unique id of the given header
+fn send_reply(sender: &dyn ReplySender, header: &[u8], err: c_int, data: &[u8]) {
+    let mut header = header[..mem::size_of::<fuse_out_header>()].to_vec();
+    let len = (header.len() + data.len()) as u32;
+    header[0..4].copy_from_slice(&len.to_ne_bytes());
+    header[4..8].copy_from_slice(&(-err).to_ne_bytes());
+    sender.send(&[&header, data]);
+}
+
+/// When to apply a rule's action
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FaultPhase {
+    /// Before calling the wrapped filesystem
+    Before,
+    /// After calling the wrapped filesystem, when it replies
+    After,
+}
+
+/// Rule that selects operations and the fault to inject into them
+#[derive(Clone, Debug, PartialEq)]
+pub struct FaultRule {
+    action: FaultAction,
+    phase: FaultPhase,
+    ops: Vec<FaultOp>,
+    class: Option<RequestClass>,
+    ino: Option<u64>,
+    name: Option<Vec<u8>>,
+    after: u64,
+    times: Option<u64>,
+    probability: f64,
+    matched: u64,
+    triggered: u64,
+}
+
+impl FaultRule {
+    /// Rule that applies the given action to every operation before calling the wrapped
+    /// filesystem
+    pub fn new(action: FaultAction) -> FaultRule {
+        FaultRule {
+            action,
+            phase: FaultPhase::Before,
+            ops: Vec::new(),
+            class: None,
+            ino: None,
+            name: None,
+            after: 0,
+            times: None,
+            probability: 1.0,
+            matched: 0,
+            triggered: 0,
+        }
+    }
+
+    /// Apply the action in the given phase. Ignored for truncating and corrupting reads,
+    /// which always apply after calling the wrapped filesystem.
+    pub fn phase(mut self, phase: FaultPhase) -> FaultRule {
+        self.phase = phase;
+        self
+    }
+
+    /// Only match the given operation. Can be used multiple times to match several
+    /// operations. Truncating and corrupting reads only match `FaultOp::Read` anyway.
+    pub fn op(mut self, op: FaultOp) -> FaultRule {
+        self.ops.push(op);
+        self
+    }
+
+    /// Only match operations of the given class
+    pub fn class(mut self, class: RequestClass) -> FaultRule {
+        self.class = Some(class);
+        self
+    }
+
+    /// Only match operations on the given inode. Operations on directory entries (e.g. lookup
+    /// or unlink) are on the parent directory, link is on the new parent directory.
+    pub fn ino(mut self, ino: u64) -> FaultRule {
+        self.ino = Some(ino);
+        self
+    }
+
+    /// Only match operations on directory entries with a name matching the given pattern, in
+    /// which `*` matches any sequence of characters and `?` any single character. Link
+    /// matches the new name.
+    pub fn name(mut self, pattern: &str) -> FaultRule {
+        self.name = Some(pattern.as_bytes().to_vec());
+        self
+    }
+
+    /// Don't trigger for the first `count` matching operations
+    pub fn after(mut self, count: u64) -> FaultRule {
+        self.after = count;
+        self
+    }
+
+    /// Trigger at most `count` times
+    pub fn times(mut self, count: u64) -> FaultRule {
+        self.times = Some(count);
+        self
+    }
+
+    /// Trigger with the given probability (between 0 and 1) for matching operations
+    pub fn probability(mut self, probability: f64) -> FaultRule {
+        self.probability = probability;
+        self
+    }
+
+    /// Returns the action of this rule
+    pub fn action(&self) -> FaultAction {
+        self.action
+    }
+
+    /// Returns the number of operations this rule matched
+    pub fn matched(&self) -> u64 {
+        self.matched
+    }
+
+    /// Returns the number of times this rule triggered
+    pub fn triggered(&self) -> u64 {
+        self.triggered
+    }
+
+    /// Returns true if the rule corrupts read data
+    fn corrupts(&self) -> bool {
+        match self.action {
+            FaultAction::CorruptRead(_) => true,
+            FaultAction::Error(_) | FaultAction::Delay(_) | FaultAction::TruncateRead(_) => false,
+        }
+    }
+
+    /// Returns true if the action applies after calling the wrapped filesystem
+    fn applies_after(&self) -> bool {
+        self.phase == FaultPhase::After || self.action.is_read_data()
+    }
+
+    /// Returns true if the rule selects the given operation
+    fn matches(&self, call: &Call<'_>) -> bool {
+        let class = match self.class {
+            Some(class) => class == call.op.class(),
+            None => true,
+        };
+        let name = match (&self.name, call.name) {
+            (Some(pattern), Some(name)) => glob(pattern, name.as_bytes()),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        (self.ops.is_empty() || self.ops.contains(&call.op))
+            && class
+            && self.ino.unwrap_or(call.ino) == call.ino
+            && name
+            && (!self.action.is_read_data() || call.op == FaultOp::Read)
+    }
+
+    /// Count a matching operation and return true if the rule triggers for it
+    fn trigger(&mut self, rng: &mut u64) -> bool {
+        self.matched += 1;
+        if self.matched <= self.after {
+            return false;
+        }
+        if let Some(times) = self.times {
+            if self.triggered >= times {
+                return false;
+            }
+        }
+        if self.probability < 1.0 && random(rng) >= self.probability {
+            return false;
+        }
+        self.triggered += 1;
+        true
+    }
+}
+
+/// Returns true if the name matches the pattern, in which `*` matches any sequence of bytes
+/// and `?` any single byte
+fn glob(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|i| glob(rest, &name[i..])),
+        Some((b'?', rest)) => !name.is_empty() && glob(rest, &name[1..]),
+        Some((byte, rest)) => name.first() == Some(byte) && glob(rest, &name[1..]),
+    }
+}
+
+/// Returns the next random number in [0, 1) of the generator with the given state (SplitMix64)
+fn random(state: &mut u64) -> f64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Operation that faults may be injected into
+#[derive(Clone, Copy, Debug)]
+struct Call<'a> {
+    op: FaultOp,
+    ino: u64,
+    name: Option<&'a OsStr>,
+}
+
+/// Error of adding or parsing fault rules
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FaultError {
+    /// Invalid rule text in the given line (starting at 1)
+    Syntax {
+        /// Line number
+        line: usize,
+        /// What's wrong
+        message: String,
+    },
+    /// A rule corrupts read data, but corruption isn't allowed
+    CorruptionNotAllowed,
+}
+
+impl fmt::Display for FaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FaultError::Syntax { line, message } => write!(f, "Invalid fault rule in line {}: {}", line, message),
+            FaultError::CorruptionNotAllowed => write!(f, "Corrupting read data isn't allowed"),
+        }
+    }
+}
+
+impl error::Error for FaultError {}
+
+/// Error codes by name for rule text
+const ERRNO_NAMES: [(&str, c_int); 25] = [
+    ("EPERM", libc::EPERM), ("ENOENT", libc::ENOENT), ("EINTR", libc::EINTR), ("EIO", libc::EIO),
+    ("ENXIO", libc::ENXIO), ("EBADF", libc::EBADF), ("EAGAIN", libc::EAGAIN), ("ENOMEM", libc::ENOMEM),
+    ("EACCES", libc::EACCES), ("EBUSY", libc::EBUSY), ("EEXIST", libc::EEXIST), ("EXDEV", libc::EXDEV),
+    ("ENOTDIR", libc::ENOTDIR), ("EISDIR", libc::EISDIR), ("EINVAL", libc::EINVAL), ("EFBIG", libc::EFBIG),
+    ("ENOSPC", libc::ENOSPC), ("EROFS", libc::EROFS), ("EMLINK", libc::EMLINK), ("ENAMETOOLONG", libc::ENAMETOOLONG),
+    ("ENOSYS", libc::ENOSYS), ("ENOTEMPTY", libc::ENOTEMPTY), ("ETIMEDOUT", libc::ETIMEDOUT),
+    ("ESTALE", libc::ESTALE), ("EDQUOT", libc::EDQUOT),
+];
+
+/// Parse a duration with a unit (`us`, `ms` or `s`)
+fn parse_duration(value: &str) -> Option<Duration> {
+    let pos = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(pos);
+    let number = number.parse().ok()?;
+    match unit {
+        "us" => Some(Duration::from_micros(number)),
+        "ms" => Some(Duration::from_millis(number)),
+        "s" => Some(Duration::from_secs(number)),
+        _ => None,
+    }
+}
+
+/// Parse a rule from a line of rule text. Returns `None` for empty lines and comments.
+fn parse_rule(line: &str) -> Result<Option<FaultRule>, String> {
+    let line = match line.find('#') {
+        Some(pos) => &line[..pos],
+        None => line,
+    };
+    let mut tokens = line.split_whitespace();
+    let selector = match tokens.next() {
+        Some(selector) => selector,
+        None => return Ok(None),
+    };
+    let mut rule = FaultRule::new(FaultAction::Error(libc::EIO));
+    match selector {
+        "*" => (),
+        "data" => rule.class = Some(RequestClass::Data),
+        "metadata" => rule.class = Some(RequestClass::Metadata),
+        ops => for name in ops.split(',') {
+            let op = OP_NAMES.iter().find(|(_, op_name)| *op_name == name).map(|(op, _)| *op);
+            rule.ops.push(op.ok_or_else(|| format!("unknown operation `{}`", name))?);
+        }
+    }
+    let mut action = None;
+    for token in tokens {
+        let mut parts = token.splitn(2, '=');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key, value),
+            _ => return Err(format!("expected key=value, got `{}`", token)),
+        };
+        let invalid = || format!("invalid value for {}: `{}`", key, value);
+        let new_action = match key {
+            "ino" => { rule.ino = Some(value.parse().map_err(|_| invalid())?); None }
+            "name" => { rule.name = Some(value.as_bytes().to_vec()); None }
+            "after" => { rule.after = value.parse().map_err(|_| invalid())?; None }
+            "times" => { rule.times = Some(value.parse().map_err(|_| invalid())?); None }
+            "probability" => {
+                let probability: f64 = value.parse().map_err(|_| invalid())?;
+                if !(0.0..=1.0).contains(&probability) { return Err(invalid()); }
+                rule.probability = probability;
+                None
+            }
+            "phase" => {
+                rule.phase = match value {
+                    "before" => FaultPhase::Before,
+                    "after" => FaultPhase::After,
+                    _ => return Err(invalid()),
+                };
+                None
+            }
+            "error" => {
+                let named = ERRNO_NAMES.iter().find(|(name, _)| *name == value).map(|(_, err)| *err);
+                let err = named.or_else(|| value.parse().ok()).filter(|&err| err > 0).ok_or_else(invalid)?;
+                Some(FaultAction::Error(err))
+            }
+            "delay" => Some(FaultAction::Delay(parse_duration(value).ok_or_else(invalid)?)),
+            "truncate" => Some(FaultAction::TruncateRead(value.parse().map_err(|_| invalid())?)),
+            "corrupt" => Some(FaultAction::CorruptRead(value.parse().map_err(|_| invalid())?)),
+            _ => return Err(format!("unknown key `{}`", key)),
+        };
+        if new_action.is_some() {
+            if action.is_some() {
+                return Err("more than one action".to_string());
+            }
+            action = new_action;
+        }
+    }
+    rule.action = action.ok_or_else(|| "no action".to_string())?;
+    Ok(Some(rule))
+}
+
+/// Parse rules from text with one rule per line. A rule starts with the operations it
+/// matches: `*` for all of them, `data` or `metadata` for a class, or a comma-separated list
+/// of `Filesystem` method names (e.g. `read,write`). It's followed by `key=value` settings:
+///
+/// * `ino=<number>`, `name=<pattern>`, `after=<count>`, `times=<count>` and
+///   `probability=<0..1>` set the corresponding `FaultRule` settings
+/// * `phase=before` or `phase=after` sets the phase
+/// * Exactly one action: `error=<name or number>` (e.g. `error=EIO`), `delay=<duration>`
+///   (with unit `us`, `ms` or `s`, e.g. `delay=100ms`), `truncate=<bytes>` or
+///   `corrupt=<bytes>`
+///
+/// Everything after a `#` is a comment. E.g. `read ino=2 after=10 error=EIO` fails all reads
+/// of inode 2 after the first ten.
+pub fn parse_rules(text: &str) -> Result<Vec<FaultRule>, FaultError> {
+    let mut rules = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        match parse_rule(line) {
+            Ok(Some(rule)) => rules.push(rule),
+            Ok(None) => (),
+            Err(message) => return Err(FaultError::Syntax { line: i + 1, message }),
+        }
+    }
+    Ok(rules)
+}
+
+/// Clock used for delays
+pub trait Clock: fmt::Debug + Send + Sync + 'static {
+    /// Block the calling thread for the given duration
+    fn sleep(&self, duration: Duration);
+}
+
+/// Clock that sleeps for real
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Rules and state shared with handles
+#[derive(Debug)]
+struct Faults {
+    rules: Vec<FaultRule>,
+    rng: u64,
+    allow_corruption: bool,
+}
+
+impl Faults {
+    /// Returns the action of the first rule of the given phase that triggers for the operation
+    fn trigger(&mut self, call: &Call<'_>, after: bool) -> Option<FaultAction> {
+        let rng = &mut self.rng;
+        self.rules.iter_mut()
+            .filter(|rule| rule.applies_after() == after && rule.matches(call))
+            .find_map(|rule| if rule.trigger(rng) { Some(rule.action) } else { None })
+    }
+}
+
+/// Handle to change the rules of a `FaultInjectingFilesystem` while it's mounted
+#[derive(Clone, Debug)]
+pub struct FaultHandle {
+    faults: Arc<Mutex<Faults>>,
+}
+
+impl FaultHandle {
+    /// Add a rule after the existing ones
+    pub fn add(&self, rule: FaultRule) -> Result<(), FaultError> {
+        let mut faults = self.faults.lock().unwrap();
+        if !faults.allow_corruption && rule.corrupts() {
+            return Err(FaultError::CorruptionNotAllowed);
+        }
+        faults.rules.push(rule);
+        Ok(())
+    }
+
+    /// Parse rules from text (see `parse_rules`) and add them after the existing ones. Either
+    /// all or none of the rules are added. Returns the number of added rules.
+    pub fn load(&self, text: &str) -> Result<usize, FaultError> {
+        let rules = parse_rules(text)?;
+        let mut faults = self.faults.lock().unwrap();
+        if !faults.allow_corruption && rules.iter().any(FaultRule::corrupts) {
+            return Err(FaultError::CorruptionNotAllowed);
+        }
+        let count = rules.len();
+        faults.rules.extend(rules);
+        Ok(count)
+    }
+
+    /// Remove the rule at the given index. Returns the removed rule, or `None` if there's no
+    /// rule at the given index.
+    pub fn remove(&self, index: usize) -> Option<FaultRule> {
+        let mut faults = self.faults.lock().unwrap();
+        if index < faults.rules.len() { Some(faults.rules.remove(index)) } else { None }
+    }
+
+    /// Remove all rules
+    pub fn clear(&self) {
+        self.faults.lock().unwrap().rules.clear();
+    }
+
+    /// Returns the current rules (with their counts of matched and triggered operations)
+    pub fn rules(&self) -> Vec<FaultRule> {
+        self.faults.lock().unwrap().rules.clone()
+    }
+
+    /// Returns the actions to apply before and after calling the wrapped filesystem for the
+    /// given operation. If it fails before, rules for after aren't evaluated.
+    fn trigger(&self, call: &Call<'_>) -> (Option<FaultAction>, Option<FaultAction>) {
+        let mut faults = self.faults.lock().unwrap();
+        let before = faults.trigger(call, false);
+        let after = match before {
+            Some(FaultAction::Error(_)) => None,
+            _ => faults.trigger(call, true),
+        };
+        (before, after)
+    }
+}
+
+/// Filesystem that injects faults into operations of the wrapped filesystem. See the module
+/// documentation for details.
+#[derive(Debug)]
+pub struct FaultInjectingFilesystem<FS> {
+    inner: FS,
+    handle: FaultHandle,
+    clock: Arc<dyn Clock>,
+}
+
+impl<FS: Filesystem> FaultInjectingFilesystem<FS> {
+    /// Wrap the given filesystem, without any rules yet (add them with `handle`)
+    pub fn new(inner: FS) -> FaultInjectingFilesystem<FS> {
+        let faults = Faults { rules: Vec::new(), rng: 0, allow_corruption: false };
+        let handle = FaultHandle { faults: Arc::new(Mutex::new(faults)) };
+        FaultInjectingFilesystem { inner, handle, clock: Arc::new(SystemClock) }
+    }
+
+    /// Seed the random number generator for rules with probabilities (0 by default). The same
+    /// seed with the same operations triggers the same faults.
+    pub fn seed(self, seed: u64) -> FaultInjectingFilesystem<FS> {
+        self.handle.faults.lock().unwrap().rng = seed;
+        self
+    }
+
+    /// Use the given clock for delays instead of the system clock
+    pub fn clock<C: Clock>(mut self, clock: C) -> FaultInjectingFilesystem<FS> {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Allow rules that corrupt read data. DANGEROUS: an application that reads corrupted
+    /// data may write it back or act on it, and cause damage outside of the test. Only allow
+    /// this for applications that verify the data they read (e.g. with checksums) and never
+    /// for filesystems that hold data that matters.
+    pub fn allow_read_corruption(self, allow: bool) -> FaultInjectingFilesystem<FS> {
+        self.handle.faults.lock().unwrap().allow_corruption = allow;
+        self
+    }
+
+    /// Returns a handle to change the rules
+    pub fn handle(&self) -> FaultHandle {
+        self.handle.clone()
+    }
+
+    /// Returns the wrapped filesystem
+    pub fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    /// Returns the wrapped filesystem
+    pub fn inner_mut(&mut self) -> &mut FS {
+        &mut self.inner
+    }
+
+    /// Inject faults into an operation. Returns the reply to pass to the wrapped filesystem,
+    /// or `None` if the operation failed already.
+    fn inject<R: Intercept>(&self, op: FaultOp, ino: u64, name: Option<&OsStr>, reply: R) -> Option<R> {
+        let (before, after) = self.handle.trigger(&Call { op, ino, name });
+        match before {
+            Some(FaultAction::Error(err)) => {
+                reply.error(err);
+                return None;
+            }
+            Some(FaultAction::Delay(duration)) => self.clock.sleep(duration),
+            _ => (),
+        }
+        match after {
+            Some(action) => {
+                let clock = self.clock.clone();
+                Some(reply.intercept(move |data, sender| action.apply(&*clock, data, sender)))
+            }
+            None => Some(reply),
+        }
+    }
+}
+
+impl<FS: Filesystem> Filesystem for FaultInjectingFilesystem<FS> {
+    fn init(&mut self, req: &Request<'_>) -> Result<InitConfig, c_int> {
+        self.inner.init(req)
+    }
+
+    fn pre_unmount(&mut self) -> Result<(), c_int> {
+        self.inner.pre_unmount()
+    }
+
+    fn destroy(&mut self, reason: ShutdownReason) {
+        self.inner.destroy(reason);
+    }
+
+    fn mount_flags_changed(&mut self, readonly: bool) {
+        self.inner.mount_flags_changed(readonly);
+    }
+
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if let Some(reply) = self.inject(FaultOp::Lookup, parent, Some(name), reply) {
+            self.inner.lookup(req, parent, name, reply);
+        }
+    }
+
+    fn forget(&mut self, req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.inner.forget(req, ino, nlookup);
+    }
+
+    fn interrupt(&mut self, req: &Request<'_>, unique: u64) {
+        self.inner.interrupt(req, unique);
+    }
+
+    #[cfg(feature = "abi-7-16")]
+    fn batch_forget(&mut self, req: &Request<'_>, nodes: &[ForgetOne]) {
+        self.inner.batch_forget(req, nodes);
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        if let Some(reply) = self.inject(FaultOp::Getattr, ino, None, reply) {
+            self.inner.getattr(req, ino, reply);
+        }
+    }
+
+    fn setattr_changes(&mut self, req: &Request<'_>, ino: u64, changes: SetattrChanges, reply: ReplyAttr) {
+        if let Some(reply) = self.inject(FaultOp::Setattr, ino, None, reply) {
+            self.inner.setattr_changes(req, ino, changes, reply);
+        }
+    }
+
+    fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData) {
+        if let Some(reply) = self.inject(FaultOp::Readlink, ino, None, reply) {
+            self.inner.readlink(req, ino, reply);
+        }
+    }
+
+    fn mknod_with_umask(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: CreateMode, rdev: u32, reply: ReplyEntry) {
+        if let Some(reply) = self.inject(FaultOp::Mknod, parent, Some(name), reply) {
+            self.inner.mknod_with_umask(req, parent, name, mode, rdev, reply);
+        }
+    }
+
+    fn mkdir_with_umask(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: CreateMode, reply: ReplyEntry) {
+        if let Some(reply) = self.inject(FaultOp::Mkdir, parent, Some(name), reply) {
+            self.inner.mkdir_with_umask(req, parent, name, mode, reply);
+        }
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        if let Some(reply) = self.inject(FaultOp::Unlink, parent, Some(name), reply) {
+            self.inner.unlink(req, parent, name, reply);
+        }
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        if let Some(reply) = self.inject(FaultOp::Rmdir, parent, Some(name), reply) {
+            self.inner.rmdir(req, parent, name, reply);
+        }
+    }
+
+    fn symlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
+        if let Some(reply) = self.inject(FaultOp::Symlink, parent, Some(name), reply) {
+            self.inner.symlink(req, parent, name, link, reply);
+        }
+    }
+
+    fn rename(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, reply: ReplyEmpty) {
+        if let Some(reply) = self.inject(FaultOp::Rename, parent, Some(name), reply) {
+            self.inner.rename(req, parent, name, newparent, newname, reply);
+        }
+    }
+
+    #[cfg(feature = "abi-7-23")]
+    fn rename2(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, rename: Rename2Request<'_>, reply: ReplyEmpty) {
+        if let Some(reply) = self.inject(FaultOp::Rename2, parent, Some(name), reply) {
+            self.inner.rename2(req, parent, name, rename, reply);
+        }
+    }
+
+    fn link(&mut self, req: &Request<'_>, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        if let Some(reply) = self.inject(FaultOp::Link, newparent, Some(newname), reply) {
+            self.inner.link(req, ino, newparent, newname, reply);
+        }
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: u32, reply: ReplyOpen) {
+        if let Some(reply) = self.inject(FaultOp::Open, ino, None, reply) {
+            self.inner.open(req, ino, flags, reply);
+        }
+    }
+
+    fn read(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        if let Some(reply) = self.inject(FaultOp::Read, ino, None, reply) {
+            self.inner.read(req, ino, fh, offset, size, reply);
+        }
+    }
+
+    fn write(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, data: &[u8], flags: u32, reply: ReplyWrite) {
+        if let Some(reply) = self.inject(FaultOp::Write, ino, None, reply) {
+            self.inner.write(req, ino, fh, offset, data, flags, reply);
+        }
+    }
+
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        if let Some(reply) = self.inject(FaultOp::Flush, ino, None, reply) {
+            self.inner.flush(req, ino, fh, lock_owner, reply);
+        }
+    }
+
+    fn release(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool, reply: ReplyEmpty) {
+        if let Some(reply) = self.inject(FaultOp::Release, ino, None, reply) {
+            self.inner.release(req, ino, fh, flags, lock_owner, flush, reply);
+        }
+    }
+
+    fn fsync_with_flags(&mut self, req: &Request<'_>, ino: u64, fh: u64, fsync_flags: u32, reply: ReplyEmpty) {
+        if let Some(reply) = self.inject(FaultOp::Fsync, ino, None, reply) {
+            self.inner.fsync_with_flags(req, ino, fh, fsync_flags, reply);
+        }
+    }
+
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, flags: u32, reply: ReplyOpen) {
+        if let Some(reply) = self.inject(FaultOp::Opendir, ino, None, reply) {
+            self.inner.opendir(req, ino, flags, reply);
+        }
+    }
+
+    fn readdir(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
+        if let Some(reply) = self.inject(FaultOp::Readdir, ino, None, reply) {
+            self.inner.readdir(req, ino, fh, offset, reply);
+        }
+    }
+
+    #[cfg(feature = "abi-7-21")]
+    fn readdirplus(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, reply: ReplyDirectoryPlus) {
+        if let Some(reply) = self.inject(FaultOp::Readdirplus, ino, None, reply) {
+            self.inner.readdirplus(req, ino, fh, offset, reply);
+        }
+    }
+
+    fn releasedir(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: u32, reply: ReplyEmpty) {
+        if let Some(reply) = self.inject(FaultOp::Releasedir, ino, None, reply) {
+            self.inner.releasedir(req, ino, fh, flags, reply);
+        }
+    }
+
+    fn fsyncdir_with_flags(&mut self, req: &Request<'_>, ino: u64, fh: u64, fsync_flags: u32, reply: ReplyEmpty) {
+        if let Some(reply) = self.inject(FaultOp::Fsyncdir, ino, None, reply) {
+            self.inner.fsyncdir_with_flags(req, ino, fh, fsync_flags, reply);
+        }
+    }
+
+    fn statfs(&mut self, req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        if let Some(reply) = self.inject(FaultOp::Statfs, ino, None, reply) {
+            self.inner.statfs(req, ino, reply);
+        }
+    }
+
+    fn setxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, value: &[u8], flags: u32, position: u32, reply: ReplyEmpty) {
+        if let Some(reply) = self.inject(FaultOp::Setxattr, ino, None, reply) {
+            self.inner.setxattr(req, ino, name, value, flags, position, reply);
+        }
+    }
+
+    fn getxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        if let Some(reply) = self.inject(FaultOp::Getxattr, ino, None, reply) {
+            self.inner.getxattr(req, ino, name, size, reply);
+        }
+    }
+
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        if let Some(reply) = self.inject(FaultOp::Listxattr, ino, None, reply) {
+            self.inner.listxattr(req, ino, size, reply);
+        }
+    }
+
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        if let Some(reply) = self.inject(FaultOp::Removexattr, ino, None, reply) {
+            self.inner.removexattr(req, ino, name, reply);
+        }
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: u32, reply: ReplyEmpty) {
+        if let Some(reply) = self.inject(FaultOp::Access, ino, None, reply) {
+            self.inner.access(req, ino, mask, reply);
+        }
+    }
+
+    fn create_with_umask(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: CreateMode, flags: u32, reply: ReplyCreate) {
+        if let Some(reply) = self.inject(FaultOp::Create, parent, Some(name), reply) {
+            self.inner.create_with_umask(req, parent, name, mode, flags, reply);
+        }
+    }
+
+    #[cfg(feature = "abi-7-37")]
+    fn tmpfile(&mut self, req: &Request<'_>, parent: u64, mode: CreateMode, flags: u32, reply: ReplyCreate) {
+        if let Some(reply) = self.inject(FaultOp::Tmpfile, parent, None, reply) {
+            self.inner.tmpfile(req, parent, mode, flags, reply);
+        }
+    }
+
+    fn getlk(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, reply: ReplyLock) {
+        if let Some(reply) = self.inject(FaultOp::Getlk, ino, None, reply) {
+            self.inner.getlk(req, ino, fh, lock_owner, start, end, typ, pid, reply);
+        }
+    }
+
+    fn setlk(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, sleep: bool, reply: ReplyEmpty) {
+        if let Some(reply) = self.inject(FaultOp::Setlk, ino, None, reply) {
+            self.inner.setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep, reply);
+        }
+    }
+
+    fn bmap(&mut self, req: &Request<'_>, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        if let Some(reply) = self.inject(FaultOp::Bmap, ino, None, reply) {
+            self.inner.bmap(req, ino, blocksize, idx, reply);
+        }
+    }
+
+    #[cfg(feature = "abi-7-11")]
+    fn ioctl(&mut self, req: &Request<'_>, ino: u64, fh: u64, ioctl: IoctlRequest<'_>, reply: ReplyIoctl) {
+        if let Some(reply) = self.inject(FaultOp::Ioctl, ino, None, reply) {
+            self.inner.ioctl(req, ino, fh, ioctl, reply);
+        }
+    }
+
+    #[cfg(feature = "abi-7-15")]
+    fn retrieve_reply(&mut self, req: &Request<'_>, notify_unique: u64, ino: u64, offset: u64, data: &[u8]) {
+        self.inner.retrieve_reply(req, notify_unique, ino, offset, data);
+    }
+
+    #[cfg(feature = "abi-7-19")]
+    fn fallocate(&mut self, req: &Request<'_>, ino: u64, fh: u64, fallocate: FallocateRequest, reply: ReplyEmpty) {
+        if let Some(reply) = self.inject(FaultOp::Fallocate, ino, None, reply) {
+            self.inner.fallocate(req, ino, fh, fallocate, reply);
+        }
+    }
+
+    #[cfg(feature = "abi-7-24")]
+    fn lseek(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, whence: i32, reply: ReplyLseek) {
+        if let Some(reply) = self.inject(FaultOp::Lseek, ino, None, reply) {
+            self.inner.lseek(req, ino, fh, offset, whence, reply);
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn setvolname(&mut self, req: &Request<'_>, name: &OsStr, reply: ReplyEmpty) {
+        if let Some(reply) = self.inject(FaultOp::Setvolname, fuse_abi::FUSE_ROOT_ID, None, reply) {
+            self.inner.setvolname(req, name, reply);
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn exchange(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, options: u64, reply: ReplyEmpty) {
+        if let Some(reply) = self.inject(FaultOp::Exchange, parent, Some(name), reply) {
+            self.inner.exchange(req, parent, name, newparent, newname, options, reply);
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn getxtimes(&mut self, req: &Request<'_>, ino: u64, reply: ReplyXTimes) {
+        if let Some(reply) = self.inject(FaultOp::Getxtimes, ino, None, reply) {
+            self.inner.getxtimes(req, ino, reply);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use libc::{EIO, ENOENT, ENOSPC};
+    use crate::request::test::{read_request, reply_header, run, write_request};
+    use crate::{Filesystem, ReplyData, ReplyWrite, Request, RequestClass, SessionBuilder};
+    use super::{glob, parse_rules, Call, Clock, FaultAction, FaultError, FaultHandle};
+    use super::{FaultInjectingFilesystem, FaultOp, FaultPhase, FaultRule};
+
+    fn call(op: FaultOp, ino: u64, name: Option<&str>) -> Call<'_> {
+        Call { op, ino, name: name.map(OsStr::new) }
+    }
+
+    fn handle() -> FaultHandle {
+        FaultInjectingFilesystem::new(EventFS::default()).handle()
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob(b"*.tmp", b"a.tmp"));
+        assert!(glob(b"*.tmp", b".tmp"));
+        assert!(!glob(b"*.tmp", b"a.tmp~"));
+        assert!(glob(b"f?le*", b"file.txt"));
+        assert!(!glob(b"f?le", b"fle"));
+        assert!(glob(b"*", b""));
+        assert!(!glob(b"", b"a"));
+    }
+
+    #[test]
+    fn rule_precedence() {
+        let faults = handle();
+        faults.add(FaultRule::new(FaultAction::Error(EIO)).op(FaultOp::Read).ino(2).times(1)).unwrap();
+        faults.add(FaultRule::new(FaultAction::Error(ENOSPC)).class(RequestClass::Data)).unwrap();
+        faults.add(FaultRule::new(FaultAction::Delay(Duration::from_millis(1))).phase(FaultPhase::After)).unwrap();
+        faults.add(FaultRule::new(FaultAction::TruncateRead(1)).name("*.tmp")).unwrap();
+        let delay = Some(FaultAction::Delay(Duration::from_millis(1)));
+        // The first rule that triggers applies, and an error before skips rules for after
+        assert_eq!(faults.trigger(&call(FaultOp::Read, 2, None)), (Some(FaultAction::Error(EIO)), None));
+        assert_eq!(faults.trigger(&call(FaultOp::Read, 2, None)), (Some(FaultAction::Error(ENOSPC)), None));
+        assert_eq!(faults.trigger(&call(FaultOp::Write, 3, None)), (Some(FaultAction::Error(ENOSPC)), None));
+        assert_eq!(faults.trigger(&call(FaultOp::Lookup, 1, Some("a.tmp"))), (None, delay));
+        // Truncating only matches reads
+        assert_eq!(faults.remove(2).unwrap().triggered(), 1);
+        assert_eq!(faults.remove(3), None);
+        assert_eq!(faults.trigger(&call(FaultOp::Lookup, 1, Some("a.tmp"))), (None, None));
+        let rules = faults.rules();
+        assert_eq!(rules.iter().map(|rule| rule.matched()).collect::<Vec<_>>(), [2, 2, 0]);
+        assert_eq!(rules.iter().map(|rule| rule.triggered()).collect::<Vec<_>>(), [1, 2, 0]);
+        faults.clear();
+        assert_eq!(faults.trigger(&call(FaultOp::Read, 2, None)), (None, None));
+    }
+
+    #[test]
+    fn count_triggers() {
+        let faults = handle();
+        faults.add(FaultRule::new(FaultAction::Error(EIO)).after(2).times(2)).unwrap();
+        let triggered: Vec<_> = (0..6).map(|_| faults.trigger(&call(FaultOp::Getattr, 1, None)).0.is_some()).collect();
+        assert_eq!(triggered, [false, false, true, true, false, false]);
+        assert_eq!(faults.rules()[0].matched(), 6);
+        assert_eq!(faults.rules()[0].triggered(), 2);
+    }
+
+    #[test]
+    fn probability_is_deterministic() {
+        let sample = |seed| {
+            let faults = FaultInjectingFilesystem::new(EventFS::default()).seed(seed).handle();
+            faults.add(FaultRule::new(FaultAction::Error(EIO)).probability(0.25)).unwrap();
+            (0..1000).map(|_| faults.trigger(&call(FaultOp::Open, 2, None)).0.is_some()).collect::<Vec<_>>()
+        };
+        let triggered = sample(1);
+        let count = triggered.iter().filter(|&&t| t).count();
+        assert!(count > 200 && count < 300, "triggered {} times", count);
+        assert_eq!(sample(1), triggered);
+        assert_ne!(sample(2), triggered);
+    }
+
+    #[test]
+    fn parse() {
+        let text = "
+            # Fail reads of inode 2 after the first ten
+            read ino=2 after=10 error=EIO
+            lookup,create name=*.tmp times=1 phase=after error=28   # ENOSPC
+            data probability=0.5 delay=20ms
+            * truncate=100
+        ";
+        let rules = parse_rules(text).unwrap();
+        assert_eq!(rules, [
+            FaultRule::new(FaultAction::Error(EIO)).op(FaultOp::Read).ino(2).after(10),
+            FaultRule::new(FaultAction::Error(28)).op(FaultOp::Lookup).op(FaultOp::Create).name("*.tmp").times(1).phase(FaultPhase::After),
+            FaultRule::new(FaultAction::Delay(Duration::from_millis(20))).class(RequestClass::Data).probability(0.5),
+            FaultRule::new(FaultAction::TruncateRead(100)),
+        ]);
+        let error = |line, message: &str| Err(FaultError::Syntax { line, message: message.to_string() });
+        assert_eq!(parse_rules("read\nfoo error=EIO"), error(1, "no action"));
+        assert_eq!(parse_rules("\nfoo error=EIO"), error(2, "unknown operation `foo`"));
+        assert_eq!(parse_rules("read error=EFOO"), error(1, "invalid value for error: `EFOO`"));
+        assert_eq!(parse_rules("read delay=5"), error(1, "invalid value for delay: `5`"));
+        assert_eq!(parse_rules("read probability=2 error=EIO"), error(1, "invalid value for probability: `2`"));
+        assert_eq!(parse_rules("read error=EIO delay=1s"), error(1, "more than one action"));
+        assert_eq!(parse_rules("read after error=EIO"), error(1, "expected key=value, got `after`"));
+        // Loading is all or nothing
+        let faults = handle();
+        assert_eq!(faults.load("read error=EIO\nread corrupt=1"), Err(FaultError::CorruptionNotAllowed));
+        assert!(faults.rules().is_empty());
+        assert_eq!(faults.load(text), Ok(4));
+    }
+
+    /// Clock that logs delays instead of sleeping
+    #[derive(Debug)]
+    struct FakeClock {
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Clock for FakeClock {
+        fn sleep(&self, duration: Duration) {
+            self.events.lock().unwrap().push(format!("sleep {:?}", duration));
+        }
+    }
+
+    /// Filesystem that logs calls and replies to reads with fixed data
+    #[derive(Default)]
+    struct EventFS {
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Filesystem for EventFS {
+        fn read(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, _size: u32, reply: ReplyData) {
+            self.events.lock().unwrap().push("read".to_string());
+            reply.data(b"hello world");
+        }
+
+        fn write(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+            self.events.lock().unwrap().push("write".to_string());
+            reply.written(data.len() as u32);
+        }
+    }
+
+    /// Run requests through a filesystem with the given rules. Returns the logged events and
+    /// the error and data of the replies.
+    fn run_faulty(rules: &str, allow_corruption: bool, requests: &[Vec<u8>]) -> (Vec<String>, Vec<(i32, Vec<u8>)>) {
+        let fs = EventFS::default();
+        let events = fs.events.clone();
+        let fs = FaultInjectingFilesystem::new(fs)
+            .clock(FakeClock { events: events.clone() })
+            .allow_read_corruption(allow_corruption);
+        fs.handle().load(rules).unwrap();
+        let replies = run(fs, SessionBuilder::new(), requests);
+        let events = events.lock().unwrap().clone();
+        (events, replies.iter().map(|reply| (reply_header(reply).1, reply[16..].to_vec())).collect())
+    }
+
+    fn reads(count: u64) -> Vec<Vec<u8>> {
+        (0..count).map(|i| read_request(i + 2, 4096)).collect()
+    }
+
+    #[test]
+    fn delay_with_fake_clock() {
+        let rules = "write times=1 delay=10ms\nwrite phase=after delay=2s";
+        let (events, replies) = run_faulty(rules, false, &[write_request(2, b"one"), write_request(3, b"two")]);
+        assert_eq!(events, ["sleep 10ms", "write", "sleep 2s", "write", "sleep 2s"]);
+        assert_eq!(replies.iter().map(|reply| reply.0).collect::<Vec<_>>(), [0, 0]);
+        // Reads aren't delayed
+        let (events, _) = run_faulty(rules, false, &reads(1));
+        assert_eq!(events, ["read"]);
+    }
+
+    #[test]
+    fn read_faults() {
+        let hello = b"hello world".to_vec();
+        // Errors before don't reach the filesystem
+        let (events, replies) = run_faulty("read after=2 error=EIO", false, &reads(4));
+        assert_eq!(events, ["read", "read"]);
+        assert_eq!(replies, [(0, hello.clone()), (0, hello.clone()), (-EIO, vec![]), (-EIO, vec![])]);
+        // Errors after do
+        let (events, replies) = run_faulty("read phase=after error=ENOENT", false, &reads(1));
+        assert_eq!(events, ["read"]);
+        assert_eq!(replies, [(-ENOENT, vec![])]);
+        let (_, replies) = run_faulty("read times=1 truncate=5", false, &reads(2));
+        assert_eq!(replies, [(0, b"hello".to_vec()), (0, hello.clone())]);
+        let (_, replies) = run_faulty("read corrupt=2", true, &reads(1));
+        assert_eq!(replies, [(0, b"\x97\x9allo world".to_vec())]);
+        // Corrupting must be allowed
+        let faults = handle();
+        assert_eq!(faults.add(FaultRule::new(FaultAction::CorruptRead(1))), Err(FaultError::CorruptionNotAllowed));
+    }
+}
@@ -0,0 +1,226 @@
+//! File descriptor budget
+//!
+//! Passthrough filesystems keep a backing file descriptor for every file the kernel opens.
+//! Under the default limit of open files (often 1024), a single busy application can use up
+//! all file descriptors of the filesystem process. Every open then fails with EMFILE, and so
+//! does everything else the process needs descriptors for. An `FdBudget` counts backing file
+//! descriptors against the limit, so that handlers can fail opens early while keeping some
+//! descriptors in reserve.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use libc::EMFILE;
+use log::warn;
+
+/// Number of file descriptors kept in reserve by default for the session itself (the FUSE
+/// device, pipes, notifications), stdio and logging
+const DEFAULT_RESERVED: usize = 32;
+
+/// Default utilization (in percent of the usable descriptors) that triggers a warning
+const DEFAULT_WARN_PERCENT: usize = 80;
+
+/// Min time between two warnings about high utilization
+const WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+struct Usage {
+    /// Number of descriptors in use
+    used: AtomicUsize,
+    /// Time of the last warning about high utilization
+    last_warning: Mutex<Option<Instant>>,
+}
+
+/// Budget of file descriptors for backing files. Clones share the same budget.
+///
+/// Acquire a guard before opening a backing file and keep it together with the file. If the
+/// budget is exhausted, `acquire` fails with EMFILE, which can be used to reply to the open
+/// request right away.
+#[derive(Clone, Debug)]
+pub struct FdBudget {
+    /// Limit of open files of the process
+    limit: usize,
+    /// Number of descriptors that aren't available for backing files
+    reserved: usize,
+    /// Utilization in percent that triggers a warning
+    warn_percent: usize,
+    usage: Arc<Usage>,
+}
+
+impl FdBudget {
+    /// Create a budget for the current limit of open files of the process (RLIMIT_NOFILE)
+    pub fn new() -> io::Result<FdBudget> {
+        let (soft, _) = nofile_limit()?;
+        Ok(FdBudget::with_limit(soft))
+    }
+
+    /// Create a budget for the given limit of open files
+    pub fn with_limit(limit: usize) -> FdBudget {
+        FdBudget { limit, reserved: DEFAULT_RESERVED, warn_percent: DEFAULT_WARN_PERCENT, usage: Arc::default() }
+    }
+
+    /// Set the number of file descriptors to keep in reserve (defaults to 32)
+    pub fn reserve(mut self, reserved: usize) -> FdBudget {
+        self.reserved = reserved;
+        self
+    }
+
+    /// Set the utilization (in percent of the usable descriptors) that triggers a warning
+    /// (defaults to 80). Warnings are logged at most once a minute.
+    pub fn warn_percent(mut self, percent: usize) -> FdBudget {
+        self.warn_percent = percent;
+        self
+    }
+
+    /// Returns the limit of open files the budget is based on
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Returns the number of file descriptors that can be used for backing files
+    pub fn capacity(&self) -> usize {
+        self.limit.saturating_sub(self.reserved)
+    }
+
+    /// Returns the number of file descriptors in use
+    pub fn used(&self) -> usize {
+        self.usage.used.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of file descriptors that are left for backing files
+    pub fn remaining(&self) -> usize {
+        self.capacity().saturating_sub(self.used())
+    }
+
+    /// Take a file descriptor from the budget until the returned guard is dropped. Fails
+    /// with EMFILE if there's none left.
+    pub fn acquire(&self) -> io::Result<FdGuard> {
+        let capacity = self.capacity();
+        let mut used = self.used();
+        while used < capacity {
+            match self.usage.used.compare_exchange(used, used + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => {
+                    self.check_utilization(used + 1, Instant::now());
+                    return Ok(FdGuard { usage: self.usage.clone() });
+                }
+                Err(current) => used = current,
+            }
+        }
+        self.check_utilization(used, Instant::now());
+        Err(io::Error::from_raw_os_error(EMFILE))
+    }
+
+    /// Log a warning if the given number of used file descriptors exceeds the threshold and
+    /// there was no warning recently. Returns true if a warning was logged.
+    fn check_utilization(&self, used: usize, now: Instant) -> bool {
+        if used.saturating_mul(100) < self.capacity().saturating_mul(self.warn_percent) {
+            return false;
+        }
+        let mut last_warning = self.usage.last_warning.lock().unwrap();
+        match *last_warning {
+            Some(last) if now.duration_since(last) < WARNING_INTERVAL => false,
+            _ => {
+                warn!("{} of {} file descriptors for backing files in use (limit of open files is {})",
+                      used, self.capacity(), self.limit);
+                *last_warning = Some(now);
+                true
+            }
+        }
+    }
+}
+
+/// A file descriptor taken from an `FdBudget`, which is returned when the guard is dropped
+#[derive(Debug)]
+pub struct FdGuard {
+    usage: Arc<Usage>,
+}
+
+impl Drop for FdGuard {
+    fn drop(&mut self) {
+        self.usage.used.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Returns the soft and hard limit of open files of the process
+fn nofile_limit() -> io::Result<(usize, usize)> {
+    let mut rlim = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let limit = |value: libc::rlim_t| if value == libc::RLIM_INFINITY { !0 } else { value as usize };
+    Ok((limit(rlim.rlim_cur), limit(rlim.rlim_max)))
+}
+
+/// Raise the soft limit of open files of the process to the hard limit. Returns the
+/// previous and the new soft limit.
+pub(crate) fn raise_nofile_limit() -> io::Result<(usize, usize)> {
+    let previous = nofile_limit()?.0;
+    let mut rlim = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if rlim.rlim_cur < rlim.rlim_max {
+        rlim.rlim_cur = rlim.rlim_max;
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &rlim) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok((previous, nofile_limit()?.0))
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    use libc::EMFILE;
+    use super::{nofile_limit, raise_nofile_limit, FdBudget};
+
+    #[test]
+    fn accounting() {
+        let budget = FdBudget::with_limit(40).reserve(30);
+        assert_eq!(budget.limit(), 40);
+        assert_eq!(budget.capacity(), 10);
+        let guards: Vec<_> = (0..10).map(|_| budget.acquire().unwrap()).collect();
+        assert_eq!(budget.used(), 10);
+        assert_eq!(budget.remaining(), 0);
+        assert_eq!(budget.acquire().unwrap_err().raw_os_error(), Some(EMFILE));
+        // Clones share the budget
+        let clone = budget.clone();
+        drop(guards);
+        assert_eq!(clone.used(), 0);
+        assert_eq!(clone.remaining(), 10);
+        assert!(clone.acquire().is_ok());
+    }
+
+    #[test]
+    fn reserve_exceeds_limit() {
+        let budget = FdBudget::with_limit(16);
+        assert_eq!(budget.capacity(), 0);
+        assert_eq!(budget.acquire().unwrap_err().raw_os_error(), Some(EMFILE));
+    }
+
+    #[test]
+    fn utilization_warning() {
+        let budget = FdBudget::with_limit(110).reserve(10).warn_percent(75);
+        let now = Instant::now();
+        assert!(!budget.check_utilization(74, now));
+        assert!(budget.check_utilization(75, now));
+        // Rate limited
+        assert!(!budget.check_utilization(90, now + Duration::from_secs(30)));
+        assert!(!budget.check_utilization(100, now + Duration::from_secs(59)));
+        assert!(budget.check_utilization(100, now + Duration::from_secs(60)));
+        // Below the threshold doesn't count as a warning
+        assert!(!budget.check_utilization(10, now + Duration::from_secs(200)));
+        assert!(budget.check_utilization(80, now + Duration::from_secs(201)));
+    }
+
+    #[test]
+    fn raise_limit() {
+        // The soft limit may be at the hard limit already, but it never gets lower
+        let (previous, raised) = raise_nofile_limit().unwrap();
+        assert!(raised >= previous);
+        assert_eq!(nofile_limit().unwrap(), (raised, raised));
+        assert_eq!(FdBudget::new().unwrap().limit(), raised);
+    }
+}
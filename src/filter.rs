@@ -0,0 +1,275 @@
+//! Filtering of requests by identity
+//!
+//! A request filter is consulted for every request before it's dispatched to the filesystem
+//! and can refuse it with an error code. This allows to keep certain users away from a mount
+//! regardless of the permissions the filesystem reports (e.g. service accounts that must never
+//! read user files through it), independently of the filesystem implementation.
+//!
+//! Independently of filters, requests that modify the filesystem can be refused while a
+//! read-only switch is enabled (see `ReadOnlySwitch`).
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use libc::{c_int, EACCES, O_ACCMODE, O_RDONLY, O_TRUNC};
+use log::warn;
+
+use crate::fairness::RequestClass;
+use crate::ll;
+
+/// Identity and kind of a request, as given to request filters
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RequestMeta {
+    /// Unique id of the request
+    pub unique: u64,
+    /// User id of the process that caused the request
+    pub uid: u32,
+    /// Group id of the process that caused the request
+    pub gid: u32,
+    /// Process id of the process that caused the request
+    pub pid: u32,
+    /// Inode the request operates on
+    pub nodeid: u64,
+    /// Class of the requested operation
+    pub class: RequestClass,
+}
+
+impl RequestMeta {
+    fn of(request: &ll::Request<'_>) -> RequestMeta {
+        RequestMeta {
+            unique: request.unique(),
+            uid: request.uid(),
+            gid: request.gid(),
+            pid: request.pid(),
+            nodeid: request.nodeid(),
+            class: RequestClass::of(request),
+        }
+    }
+}
+
+/// Decision of a request filter
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FilterDecision {
+    /// Dispatch the request to the filesystem
+    Allow,
+    /// Reply to the request with the given error code and log a warning
+    Deny(c_int),
+    /// Reply to the request with the given error code without logging
+    DenyQuiet(c_int),
+}
+
+/// Filter that decides whether requests are dispatched to the filesystem (see
+/// `SessionBuilder::request_filter`). It's consulted for every request except init, destroy,
+/// forget, interrupt and notify reply requests, so it should decide quickly.
+pub trait RequestFilter: fmt::Debug + Send {
+    /// Decide whether the given request is dispatched
+    fn check(&self, meta: &RequestMeta) -> FilterDecision;
+}
+
+/// Check the given request with the given filter. Returns the error code to reply with if the
+/// request is refused.
+pub(crate) fn refuse(filter: &dyn RequestFilter, request: &ll::Request<'_>) -> Option<c_int> {
+    match request.operation() {
+        ll::Operation::Init { .. } | ll::Operation::Destroy | ll::Operation::Forget { .. } |
+        ll::Operation::Interrupt { .. } => return None,
+        #[cfg(feature = "abi-7-15")]
+        ll::Operation::NotifyReply { .. } => return None,
+        #[cfg(feature = "abi-7-16")]
+        ll::Operation::BatchForget { .. } => return None,
+        _ => (),
+    }
+    match filter.check(&RequestMeta::of(request)) {
+        FilterDecision::Allow => None,
+        FilterDecision::Deny(err) => {
+            warn!("Request filter refused {} of uid {}, gid {}, pid {}", request, request.uid(), request.gid(), request.pid());
+            Some(err)
+        }
+        FilterDecision::DenyQuiet(err) => Some(err),
+    }
+}
+
+/// Request filter with allow and deny lists of user and group ids. Requests of denied users
+/// or groups are refused. If any users or groups are allowed, requests of others are refused
+/// as well. Refused requests are replied with `EACCES` by default.
+#[derive(Clone, Debug)]
+pub struct UidGidFilter {
+    allow_uids: Vec<u32>,
+    allow_gids: Vec<u32>,
+    deny_uids: Vec<u32>,
+    deny_gids: Vec<u32>,
+    classes: Option<Vec<RequestClass>>,
+    err: c_int,
+    quiet: bool,
+}
+
+impl Default for UidGidFilter {
+    fn default() -> UidGidFilter {
+        UidGidFilter {
+            allow_uids: Vec::new(),
+            allow_gids: Vec::new(),
+            deny_uids: Vec::new(),
+            deny_gids: Vec::new(),
+            classes: None,
+            err: EACCES,
+            quiet: false,
+        }
+    }
+}
+
+impl UidGidFilter {
+    /// Create a new filter that allows everything
+    pub fn new() -> UidGidFilter {
+        UidGidFilter::default()
+    }
+
+    /// Allow requests of the given user id
+    pub fn allow_uid(mut self, uid: u32) -> UidGidFilter {
+        self.allow_uids.push(uid);
+        self
+    }
+
+    /// Allow requests of the given group id
+    pub fn allow_gid(mut self, gid: u32) -> UidGidFilter {
+        self.allow_gids.push(gid);
+        self
+    }
+
+    /// Refuse requests of the given user id, even if its group is allowed
+    pub fn deny_uid(mut self, uid: u32) -> UidGidFilter {
+        self.deny_uids.push(uid);
+        self
+    }
+
+    /// Refuse requests of the given group id, even if the user is allowed
+    pub fn deny_gid(mut self, gid: u32) -> UidGidFilter {
+        self.deny_gids.push(gid);
+        self
+    }
+
+    /// Only filter requests of the given class, requests of other classes are always allowed.
+    /// Can be used multiple times to filter more classes. By default, all requests are filtered.
+    pub fn only(mut self, class: RequestClass) -> UidGidFilter {
+        self.classes.get_or_insert_with(Vec::new).push(class);
+        self
+    }
+
+    /// Reply to refused requests with the given error code (defaults to `EACCES`)
+    pub fn error(mut self, err: c_int) -> UidGidFilter {
+        self.err = err;
+        self
+    }
+
+    /// Don't log refused requests
+    pub fn quiet(mut self) -> UidGidFilter {
+        self.quiet = true;
+        self
+    }
+
+    fn allows(&self, meta: &RequestMeta) -> bool {
+        if self.deny_uids.contains(&meta.uid) || self.deny_gids.contains(&meta.gid) {
+            return false;
+        }
+        (self.allow_uids.is_empty() && self.allow_gids.is_empty()) ||
+            self.allow_uids.contains(&meta.uid) || self.allow_gids.contains(&meta.gid)
+    }
+}
+
+impl RequestFilter for UidGidFilter {
+    fn check(&self, meta: &RequestMeta) -> FilterDecision {
+        let filtered = match self.classes {
+            Some(ref classes) => classes.contains(&meta.class),
+            None => true,
+        };
+        match (filtered && !self.allows(meta), self.quiet) {
+            (false, _) => FilterDecision::Allow,
+            (true, false) => FilterDecision::Deny(self.err),
+            (true, true) => FilterDecision::DenyQuiet(self.err),
+        }
+    }
+}
+
+/// Switch that makes the session refuse requests that modify the filesystem with `EROFS`
+/// while it's enabled, like the kernel does for read-only mounts (see
+/// `SessionBuilder::read_only`). Clones share the same state, so it can be toggled at any
+/// time, e.g. from the filesystem when its backend enters maintenance, or from
+/// `Filesystem::mount_flags_changed`.
+#[derive(Clone, Debug, Default)]
+pub struct ReadOnlySwitch(Arc<AtomicBool>);
+
+impl ReadOnlySwitch {
+    /// Create a new switch that is initially enabled or disabled
+    pub fn new(enabled: bool) -> ReadOnlySwitch {
+        ReadOnlySwitch(Arc::new(AtomicBool::new(enabled)))
+    }
+
+    /// Enable or disable refusing modifying requests. Takes effect for the next request
+    /// that is dispatched.
+    pub fn set(&self, enabled: bool) {
+        self.0.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Returns true if modifying requests are refused
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Tells whether the given request modifies the filesystem, i.e. is refused on read-only
+/// mounts. Opening a file counts if it's opened for writing or truncated.
+pub(crate) fn modifies(request: &ll::Request<'_>) -> bool {
+    match request.operation() {
+        ll::Operation::SetAttr { .. } | ll::Operation::MkNod { .. } | ll::Operation::MkDir { .. } |
+        ll::Operation::Unlink { .. } | ll::Operation::RmDir { .. } | ll::Operation::SymLink { .. } |
+        ll::Operation::Rename { .. } | ll::Operation::Link { .. } | ll::Operation::Write { .. } |
+        ll::Operation::SetXAttr { .. } | ll::Operation::RemoveXAttr { .. } | ll::Operation::Create { .. } => true,
+        ll::Operation::Open { arg } => {
+            let flags = arg.flags as c_int;
+            flags & O_ACCMODE != O_RDONLY || flags & O_TRUNC != 0
+        }
+        #[cfg(feature = "abi-7-19")]
+        ll::Operation::FAllocate { .. } => true,
+        #[cfg(feature = "abi-7-23")]
+        ll::Operation::Rename2 { .. } => true,
+        #[cfg(feature = "abi-7-28")]
+        ll::Operation::CopyFileRange { .. } => true,
+        #[cfg(feature = "abi-7-37")]
+        ll::Operation::TmpFile { .. } => true,
+        #[cfg(target_os = "macos")]
+        ll::Operation::SetVolName { .. } | ll::Operation::Exchange { .. } => true,
+        _ => false,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use libc::{EACCES, EPERM};
+    use crate::fairness::RequestClass;
+    use super::{FilterDecision, RequestFilter, RequestMeta, UidGidFilter};
+
+    fn meta(uid: u32, gid: u32, class: RequestClass) -> RequestMeta {
+        RequestMeta { unique: 1, uid, gid, pid: 42, nodeid: 2, class }
+    }
+
+    #[test]
+    fn allow_and_deny_lists() {
+        let filter = UidGidFilter::new();
+        assert_eq!(filter.check(&meta(1000, 1000, RequestClass::Data)), FilterDecision::Allow);
+        let filter = UidGidFilter::new().allow_uid(1000).allow_gid(100).deny_uid(1001);
+        assert_eq!(filter.check(&meta(1000, 1000, RequestClass::Data)), FilterDecision::Allow);
+        assert_eq!(filter.check(&meta(1002, 100, RequestClass::Data)), FilterDecision::Allow);
+        // Denied users are refused even if their group is allowed
+        assert_eq!(filter.check(&meta(1001, 100, RequestClass::Data)), FilterDecision::Deny(EACCES));
+        assert_eq!(filter.check(&meta(1002, 1002, RequestClass::Data)), FilterDecision::Deny(EACCES));
+        let filter = UidGidFilter::new().deny_gid(998).error(EPERM).quiet();
+        assert_eq!(filter.check(&meta(1000, 998, RequestClass::Metadata)), FilterDecision::DenyQuiet(EPERM));
+        assert_eq!(filter.check(&meta(1000, 1000, RequestClass::Metadata)), FilterDecision::Allow);
+    }
+
+    #[test]
+    fn scoped_to_classes() {
+        let filter = UidGidFilter::new().deny_uid(999).only(RequestClass::Data);
+        assert_eq!(filter.check(&meta(999, 999, RequestClass::Data)), FilterDecision::Deny(EACCES));
+        assert_eq!(filter.check(&meta(999, 999, RequestClass::Metadata)), FilterDecision::Allow);
+    }
+}
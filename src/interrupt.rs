@@ -0,0 +1,126 @@
+//! Interrupted requests
+//!
+//! The kernel driver sends an interrupt request if the process that waits for a request gets
+//! a signal (e.g. Ctrl-C). The interrupted request should be replied with `EINTR` as soon as
+//! possible, but may as well be completed normally. Interrupts aren't replied to.
+//!
+//! An interrupt may arrive before the request it refers to is dispatched (e.g. if requests
+//! are queued for fair dispatch). Such interrupts are kept for a while, and the request is
+//! interrupted right away when it's dispatched.
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// Maximum number of interrupts that are kept for requests that weren't dispatched yet. The
+/// oldest ones are dropped if there are more (e.g. interrupts of requests that were replied
+/// already).
+const MAX_EARLY_INTERRUPTS: usize = 64;
+
+#[derive(Debug, Default)]
+struct State {
+    /// Unique ids of outstanding requests that were interrupted
+    interrupted: HashSet<u64>,
+    /// Unique ids of interrupted requests that weren't dispatched yet, oldest first
+    early: VecDeque<u64>,
+}
+
+/// Interrupts of outstanding requests
+#[derive(Debug, Default)]
+pub(crate) struct InterruptTable {
+    state: Mutex<State>,
+}
+
+impl InterruptTable {
+    /// Remember an interrupt of the request with the given unique id, which is outstanding or
+    /// not dispatched yet
+    pub(crate) fn interrupt(&self, unique: u64, outstanding: bool) {
+        let mut state = self.state.lock().unwrap();
+        if outstanding {
+            state.interrupted.insert(unique);
+        } else if !state.early.contains(&unique) {
+            if state.early.len() >= MAX_EARLY_INTERRUPTS {
+                state.early.pop_front();
+            }
+            state.early.push_back(unique);
+        }
+    }
+
+    /// The request with the given unique id was dispatched, which interrupts it if an
+    /// interrupt arrived early
+    pub(crate) fn dispatched(&self, unique: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(pos) = state.early.iter().position(|&early| early == unique) {
+            state.early.remove(pos);
+            state.interrupted.insert(unique);
+        }
+    }
+
+    /// The request with the given unique id was replied
+    pub(crate) fn replied(&self, unique: u64) {
+        self.state.lock().unwrap().interrupted.remove(&unique);
+    }
+
+    /// Returns true if the outstanding request with the given unique id was interrupted
+    fn is_interrupted(&self, unique: u64) -> bool {
+        self.state.lock().unwrap().interrupted.contains(&unique)
+    }
+}
+
+/// Tells whether a request was interrupted, obtained with `Request::interrupt_flag`. Can be
+/// moved to tasks that reply asynchronously, which should check it regularly and reply with
+/// `EINTR` once it's set.
+#[derive(Clone, Debug)]
+pub struct InterruptFlag {
+    table: Arc<InterruptTable>,
+    unique: u64,
+}
+
+impl InterruptFlag {
+    /// Create a flag for the request with the given unique id
+    pub(crate) fn new(table: Arc<InterruptTable>, unique: u64) -> InterruptFlag {
+        InterruptFlag { table, unique }
+    }
+
+    /// Returns true if the request was interrupted (and wasn't replied yet)
+    pub fn is_interrupted(&self) -> bool {
+        self.table.is_interrupted(self.unique)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use super::{InterruptFlag, InterruptTable, MAX_EARLY_INTERRUPTS};
+
+    #[test]
+    fn interrupt_outstanding() {
+        let table = Arc::new(InterruptTable::default());
+        let flag = InterruptFlag::new(table.clone(), 2);
+        table.dispatched(2);
+        assert!(!flag.is_interrupted());
+        table.interrupt(2, true);
+        assert!(flag.is_interrupted());
+        assert!(!InterruptFlag::new(table.clone(), 4).is_interrupted());
+        table.replied(2);
+        assert!(!flag.is_interrupted());
+    }
+
+    #[test]
+    fn interrupt_early() {
+        let table = Arc::new(InterruptTable::default());
+        table.interrupt(2, false);
+        let flag = InterruptFlag::new(table.clone(), 2);
+        assert!(!flag.is_interrupted());
+        table.dispatched(2);
+        assert!(flag.is_interrupted());
+        // Only the oldest early interrupts are dropped
+        for unique in 10..11 + MAX_EARLY_INTERRUPTS as u64 {
+            table.interrupt(unique, false);
+        }
+        table.dispatched(10);
+        table.dispatched(11);
+        assert!(!InterruptFlag::new(table.clone(), 10).is_interrupted());
+        assert!(InterruptFlag::new(table, 11).is_interrupted());
+    }
+}
@@ -0,0 +1,1188 @@
+//! Operation journaling
+//!
+//! Filesystems that must not lose acknowledged mutations when they crash can journal every
+//! mutating operation to a write-ahead log before executing and replying to it. After a
+//! crash, the journal is replayed into the filesystem on startup to recover.
+//!
+//! `JournaledFilesystem` wraps a filesystem and does the journaling: for every enabled
+//! operation, it appends a record to a `Journal` and syncs it, and only then calls the
+//! wrapped filesystem (which replies). If the journal fails, the operation fails with the
+//! journal's error without reaching the wrapped filesystem. So a reply never precedes the
+//! durable record of its operation.
+//!
+//! Records contain the operation's arguments that are needed to repeat it. Write data and
+//! xattr values are only stored as a digest (length and CRC-32) by default, which is enough
+//! to verify data after recovery. Enable full payloads to be able to replay them.
+//!
+//! The journal only grows. Once the filesystem made its state durable by other means, call
+//! `JournaledFilesystem::checkpoint` to clear it.
+
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use libc::c_int;
+use log::{error, warn};
+
+use crate::{CreateMode, Filesystem, InitConfig, Request, SetattrChanges, ShutdownReason, ToErrno};
+use crate::{ReplyEntry, ReplyAttr, ReplyData, ReplyOpen, ReplyWrite, ReplyEmpty, ReplyStatfs};
+use crate::{ReplyCreate, ReplyLock, ReplyBmap, ReplyDirectory, ReplyXattr};
+#[cfg(feature = "abi-7-19")]
+use crate::FallocateRequest;
+#[cfg(feature = "abi-7-23")]
+use crate::Rename2Request;
+#[cfg(target_os = "macos")]
+use crate::ReplyXTimes;
+#[cfg(feature = "abi-7-16")]
+use crate::ForgetOne;
+#[cfg(feature = "abi-7-21")]
+use crate::ReplyDirectoryPlus;
+#[cfg(feature = "abi-7-24")]
+use crate::ReplyLseek;
+#[cfg(feature = "abi-7-11")]
+use crate::{IoctlRequest, ReplyIoctl};
+
+/// Returns the CRC-32 (IEEE) checksum of the given data
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Mutating operations that can be journaled
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum JournalOp {
+    /// `setattr`
+    SetAttr,
+    /// `mknod`
+    Mknod,
+    /// `mkdir`
+    Mkdir,
+    /// `unlink`
+    Unlink,
+    /// `rmdir`
+    Rmdir,
+    /// `symlink`
+    Symlink,
+    /// `rename`
+    Rename,
+    /// `link`
+    Link,
+    /// `write`
+    Write,
+    /// `setxattr`
+    SetXattr,
+    /// `removexattr`
+    RemoveXattr,
+    /// `create`
+    Create,
+    /// `rename2`
+    Rename2,
+    /// `fallocate`
+    Fallocate,
+}
+
+/// All operations that can be journaled
+const ALL_OPS: [JournalOp; 14] = [
+    JournalOp::SetAttr, JournalOp::Mknod, JournalOp::Mkdir, JournalOp::Unlink, JournalOp::Rmdir,
+    JournalOp::Symlink, JournalOp::Rename, JournalOp::Link, JournalOp::Write, JournalOp::SetXattr,
+    JournalOp::RemoveXattr, JournalOp::Create, JournalOp::Rename2, JournalOp::Fallocate,
+];
+
+/// Data of an operation (write data or xattr value)
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Payload {
+    /// Only length and CRC-32 of the data
+    Digest {
+        /// Length of the data
+        len: u64,
+        /// CRC-32 of the data
+        crc: u32,
+    },
+    /// The data itself
+    Data(Vec<u8>),
+}
+
+impl Payload {
+    fn new(data: &[u8], full: bool) -> Payload {
+        if full {
+            Payload::Data(data.to_vec())
+        } else {
+            Payload::Digest { len: data.len() as u64, crc: crc32(data) }
+        }
+    }
+
+    /// Returns the data if it was stored
+    pub fn data(&self) -> Option<&[u8]> {
+        match self {
+            Payload::Digest { .. } => None,
+            Payload::Data(data) => Some(data),
+        }
+    }
+}
+
+/// Journal record of a mutating operation. Timestamps and flags of `setattr` aren't
+/// journaled.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[allow(missing_docs)]
+pub enum JournalRecord {
+    SetAttr { ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64> },
+    Mknod { parent: u64, name: OsString, mode: u32, umask: u32, rdev: u32 },
+    Mkdir { parent: u64, name: OsString, mode: u32, umask: u32 },
+    Unlink { parent: u64, name: OsString },
+    Rmdir { parent: u64, name: OsString },
+    Symlink { parent: u64, name: OsString, link: PathBuf },
+    Rename { parent: u64, name: OsString, newparent: u64, newname: OsString },
+    Link { ino: u64, newparent: u64, newname: OsString },
+    Write { ino: u64, offset: i64, data: Payload },
+    SetXattr { ino: u64, name: OsString, value: Payload, flags: u32, position: u32 },
+    RemoveXattr { ino: u64, name: OsString },
+    Create { parent: u64, name: OsString, mode: u32, umask: u32, flags: u32 },
+    Rename2 { parent: u64, name: OsString, newparent: u64, newname: OsString, flags: u32 },
+    Fallocate { ino: u64, offset: i64, length: i64, mode: i32 },
+}
+
+impl JournalRecord {
+    /// Returns the operation of this record
+    pub fn op(&self) -> JournalOp {
+        match self {
+            JournalRecord::SetAttr { .. } => JournalOp::SetAttr,
+            JournalRecord::Mknod { .. } => JournalOp::Mknod,
+            JournalRecord::Mkdir { .. } => JournalOp::Mkdir,
+            JournalRecord::Unlink { .. } => JournalOp::Unlink,
+            JournalRecord::Rmdir { .. } => JournalOp::Rmdir,
+            JournalRecord::Symlink { .. } => JournalOp::Symlink,
+            JournalRecord::Rename { .. } => JournalOp::Rename,
+            JournalRecord::Link { .. } => JournalOp::Link,
+            JournalRecord::Write { .. } => JournalOp::Write,
+            JournalRecord::SetXattr { .. } => JournalOp::SetXattr,
+            JournalRecord::RemoveXattr { .. } => JournalOp::RemoveXattr,
+            JournalRecord::Create { .. } => JournalOp::Create,
+            JournalRecord::Rename2 { .. } => JournalOp::Rename2,
+            JournalRecord::Fallocate { .. } => JournalOp::Fallocate,
+        }
+    }
+
+    /// Serialize the record
+    pub fn encode(&self) -> Vec<u8> {
+        let mut enc = Encoder(Vec::new());
+        let tag = ALL_OPS.iter().position(|&op| op == self.op()).unwrap();
+        enc.u8(tag as u8);
+        match self {
+            JournalRecord::SetAttr { ino, mode, uid, gid, size } => {
+                enc.u64(*ino);
+                enc.opt_u32(*mode);
+                enc.opt_u32(*uid);
+                enc.opt_u32(*gid);
+                match size {
+                    Some(size) => { enc.u8(1); enc.u64(*size); }
+                    None => enc.u8(0),
+                }
+            }
+            JournalRecord::Mknod { parent, name, mode, umask, rdev } => {
+                enc.u64(*parent);
+                enc.bytes(name.as_bytes());
+                enc.u32(*mode);
+                enc.u32(*umask);
+                enc.u32(*rdev);
+            }
+            JournalRecord::Mkdir { parent, name, mode, umask } => {
+                enc.u64(*parent);
+                enc.bytes(name.as_bytes());
+                enc.u32(*mode);
+                enc.u32(*umask);
+            }
+            JournalRecord::Unlink { parent, name } | JournalRecord::Rmdir { parent, name } => {
+                enc.u64(*parent);
+                enc.bytes(name.as_bytes());
+            }
+            JournalRecord::Symlink { parent, name, link } => {
+                enc.u64(*parent);
+                enc.bytes(name.as_bytes());
+                enc.bytes(link.as_os_str().as_bytes());
+            }
+            JournalRecord::Rename { parent, name, newparent, newname } => {
+                enc.u64(*parent);
+                enc.bytes(name.as_bytes());
+                enc.u64(*newparent);
+                enc.bytes(newname.as_bytes());
+            }
+            JournalRecord::Link { ino, newparent, newname } => {
+                enc.u64(*ino);
+                enc.u64(*newparent);
+                enc.bytes(newname.as_bytes());
+            }
+            JournalRecord::Write { ino, offset, data } => {
+                enc.u64(*ino);
+                enc.u64(*offset as u64);
+                enc.payload(data);
+            }
+            JournalRecord::SetXattr { ino, name, value, flags, position } => {
+                enc.u64(*ino);
+                enc.bytes(name.as_bytes());
+                enc.payload(value);
+                enc.u32(*flags);
+                enc.u32(*position);
+            }
+            JournalRecord::RemoveXattr { ino, name } => {
+                enc.u64(*ino);
+                enc.bytes(name.as_bytes());
+            }
+            JournalRecord::Create { parent, name, mode, umask, flags } => {
+                enc.u64(*parent);
+                enc.bytes(name.as_bytes());
+                enc.u32(*mode);
+                enc.u32(*umask);
+                enc.u32(*flags);
+            }
+            JournalRecord::Rename2 { parent, name, newparent, newname, flags } => {
+                enc.u64(*parent);
+                enc.bytes(name.as_bytes());
+                enc.u64(*newparent);
+                enc.bytes(newname.as_bytes());
+                enc.u32(*flags);
+            }
+            JournalRecord::Fallocate { ino, offset, length, mode } => {
+                enc.u64(*ino);
+                enc.u64(*offset as u64);
+                enc.u64(*length as u64);
+                enc.u32(*mode as u32);
+            }
+        }
+        enc.0
+    }
+
+    /// Deserialize a record. Returns `None` if the data isn't a valid record.
+    pub fn decode(data: &[u8]) -> Option<JournalRecord> {
+        let mut dec = Decoder(data);
+        let op = *ALL_OPS.get(dec.u8()? as usize)?;
+        let record = match op {
+            JournalOp::SetAttr => JournalRecord::SetAttr {
+                ino: dec.u64()?,
+                mode: dec.opt_u32()?,
+                uid: dec.opt_u32()?,
+                gid: dec.opt_u32()?,
+                size: match dec.u8()? { 0 => None, _ => Some(dec.u64()?) },
+            },
+            JournalOp::Mknod => JournalRecord::Mknod { parent: dec.u64()?, name: dec.name()?, mode: dec.u32()?, umask: dec.u32()?, rdev: dec.u32()? },
+            JournalOp::Mkdir => JournalRecord::Mkdir { parent: dec.u64()?, name: dec.name()?, mode: dec.u32()?, umask: dec.u32()? },
+            JournalOp::Unlink => JournalRecord::Unlink { parent: dec.u64()?, name: dec.name()? },
+            JournalOp::Rmdir => JournalRecord::Rmdir { parent: dec.u64()?, name: dec.name()? },
+            JournalOp::Symlink => JournalRecord::Symlink { parent: dec.u64()?, name: dec.name()?, link: dec.name()?.into() },
+            JournalOp::Rename => JournalRecord::Rename { parent: dec.u64()?, name: dec.name()?, newparent: dec.u64()?, newname: dec.name()? },
+            JournalOp::Link => JournalRecord::Link { ino: dec.u64()?, newparent: dec.u64()?, newname: dec.name()? },
+            JournalOp::Write => JournalRecord::Write { ino: dec.u64()?, offset: dec.u64()? as i64, data: dec.payload()? },
+            JournalOp::SetXattr => JournalRecord::SetXattr { ino: dec.u64()?, name: dec.name()?, value: dec.payload()?, flags: dec.u32()?, position: dec.u32()? },
+            JournalOp::RemoveXattr => JournalRecord::RemoveXattr { ino: dec.u64()?, name: dec.name()? },
+            JournalOp::Create => JournalRecord::Create { parent: dec.u64()?, name: dec.name()?, mode: dec.u32()?, umask: dec.u32()?, flags: dec.u32()? },
+            JournalOp::Rename2 => JournalRecord::Rename2 { parent: dec.u64()?, name: dec.name()?, newparent: dec.u64()?, newname: dec.name()?, flags: dec.u32()? },
+            JournalOp::Fallocate => JournalRecord::Fallocate { ino: dec.u64()?, offset: dec.u64()? as i64, length: dec.u64()? as i64, mode: dec.u32()? as i32 },
+        };
+        if !dec.0.is_empty() { return None; }
+        Some(record)
+    }
+}
+
+/// Little endian record serializer
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn opt_u32(&mut self, value: Option<u32>) {
+        match value {
+            Some(value) => { self.u8(1); self.u32(value); }
+            None => self.u8(0),
+        }
+    }
+
+    fn bytes(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.0.extend_from_slice(data);
+    }
+
+    fn payload(&mut self, payload: &Payload) {
+        match payload {
+            Payload::Digest { len, crc } => { self.u8(0); self.u64(*len); self.u32(*crc); }
+            Payload::Data(data) => { self.u8(1); self.bytes(data); }
+        }
+    }
+}
+
+/// Little endian record deserializer
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len { return None; }
+        let (data, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(data)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|data| data[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Some(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Some(u64::from_le_bytes(bytes))
+    }
+
+    fn opt_u32(&mut self) -> Option<Option<u32>> {
+        match self.u8()? {
+            0 => Some(None),
+            _ => Some(Some(self.u32()?)),
+        }
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn name(&mut self) -> Option<OsString> {
+        self.bytes().map(|data| OsString::from_vec(data.to_vec()))
+    }
+
+    fn payload(&mut self) -> Option<Payload> {
+        match self.u8()? {
+            0 => Some(Payload::Digest { len: self.u64()?, crc: self.u32()? }),
+            _ => Some(Payload::Data(self.bytes()?.to_vec())),
+        }
+    }
+}
+
+/// Storage of journal records
+pub trait Journal {
+    /// Append a serialized record. The record doesn't need to be durable before `sync`. If
+    /// appending fails, no part of the record may be kept, since the operation fails and a
+    /// torn record could hide records appended after it.
+    fn append(&mut self, record: &[u8]) -> io::Result<()>;
+
+    /// Make all appended records durable. If syncing fails, the records appended since the
+    /// last sync may not be kept either.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Returns all records, in the order they were appended
+    fn records(&mut self) -> io::Result<Vec<Vec<u8>>>;
+
+    /// Remove all records
+    fn clear(&mut self) -> io::Result<()>;
+}
+
+impl<J: Journal + ?Sized> Journal for Box<J> {
+    fn append(&mut self, record: &[u8]) -> io::Result<()> {
+        (**self).append(record)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        (**self).sync()
+    }
+
+    fn records(&mut self) -> io::Result<Vec<Vec<u8>>> {
+        (**self).records()
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        (**self).clear()
+    }
+}
+
+/// Size of the header of a record in a journal file (length and CRC-32)
+const FRAME_HEADER_SIZE: usize = 8;
+
+/// File that a `FileJournal` is stored in. Implemented for `File`, other implementations are
+/// mainly useful to test how a journal handles failing storage.
+pub trait JournalFile: Read + Write + Seek {
+    /// Truncate the file to the given length
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Make the written data durable
+    fn sync_data(&mut self) -> io::Result<()>;
+}
+
+impl JournalFile for File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+}
+
+/// Journal stored in a file. Every record is prefixed by its length and CRC-32, so that a
+/// record that was only partially written when crashing (a torn write) is detected. Such a
+/// record was never synced, so it's discarded when opening the journal. If appending or
+/// syncing fails, the records that weren't synced are removed right away. If that fails as
+/// well, the journal refuses to append records until it's cleared.
+#[derive(Debug)]
+pub struct FileJournal<F = File> {
+    file: F,
+    /// Length of the appended records
+    len: u64,
+    /// Length of the synced records
+    synced: u64,
+    /// Whether records that weren't synced couldn't be removed after a failure
+    poisoned: bool,
+}
+
+impl FileJournal {
+    /// Open the journal in the given file, creating it if it doesn't exist. A torn record at
+    /// the end of the file is removed.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FileJournal> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        FileJournal::with_file(file)
+    }
+}
+
+impl<F: JournalFile> FileJournal<F> {
+    /// Use the journal in the given file. A torn record at the end of the file is removed.
+    pub fn with_file(file: F) -> io::Result<FileJournal<F>> {
+        let mut journal = FileJournal { file, len: 0, synced: 0, poisoned: false };
+        let (_, valid_len) = journal.scan()?;
+        let len = journal.file.seek(SeekFrom::End(0))?;
+        if valid_len < len {
+            warn!("Discarding {} bytes of torn journal records", len - valid_len);
+            journal.file.set_len(valid_len)?;
+            journal.file.sync_data()?;
+        }
+        journal.file.seek(SeekFrom::Start(valid_len))?;
+        journal.len = valid_len;
+        journal.synced = valid_len;
+        Ok(journal)
+    }
+
+    /// Remove the records that weren't synced after appending or syncing failed. A torn
+    /// record would hide all records appended after it, so if they can't be removed, the
+    /// journal is poisoned.
+    fn discard_unsynced(&mut self) {
+        let synced = self.synced;
+        let res = self.file.set_len(synced).and_then(|()| self.file.seek(SeekFrom::Start(synced)));
+        match res {
+            Ok(_) => self.len = synced,
+            Err(err) => {
+                error!("Failed to remove journal records that weren't synced, refusing to append: {}", err);
+                self.poisoned = true;
+            }
+        }
+    }
+
+    /// Read all valid records. Returns the records and the length of the file they take up.
+    fn scan(&mut self) -> io::Result<(Vec<Vec<u8>>, u64)> {
+        let mut data = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut data)?;
+        let mut records = Vec::new();
+        let mut pos = 0;
+        while data.len() - pos >= FRAME_HEADER_SIZE {
+            let mut header = [0; 4];
+            header.copy_from_slice(&data[pos..pos + 4]);
+            let len = u32::from_le_bytes(header) as usize;
+            header.copy_from_slice(&data[pos + 4..pos + 8]);
+            let crc = u32::from_le_bytes(header);
+            let start = pos + FRAME_HEADER_SIZE;
+            if data.len() - start < len || crc32(&data[start..start + len]) != crc {
+                break;
+            }
+            records.push(data[start..start + len].to_vec());
+            pos = start + len;
+        }
+        Ok((records, pos as u64))
+    }
+}
+
+impl<F: JournalFile> Journal for FileJournal<F> {
+    fn append(&mut self, record: &[u8]) -> io::Result<()> {
+        if self.poisoned {
+            // Records that weren't synced couldn't be removed after a failure
+            return Err(io::Error::from_raw_os_error(libc::EIO));
+        }
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + record.len());
+        frame.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32(record).to_le_bytes());
+        frame.extend_from_slice(record);
+        if let Err(err) = self.file.write_all(&frame) {
+            self.discard_unsynced();
+            return Err(err);
+        }
+        self.len += frame.len() as u64;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if let Err(err) = self.file.sync_data() {
+            self.discard_unsynced();
+            return Err(err);
+        }
+        self.synced = self.len;
+        Ok(())
+    }
+
+    fn records(&mut self) -> io::Result<Vec<Vec<u8>>> {
+        let (records, _) = self.scan()?;
+        self.file.seek(SeekFrom::Start(self.len))?;
+        Ok(records)
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.len = 0;
+        self.synced = 0;
+        self.poisoned = false;
+        self.file.sync_data()
+    }
+}
+
+/// Filesystems that can recover by replaying journal records
+pub trait Replay {
+    /// Repeat the operation of the given record. Records of operations that were completed
+    /// before crashing are replayed as well, so replaying should be idempotent (e.g. treat
+    /// an already existing directory as success when replaying mkdir).
+    fn replay(&mut self, record: &JournalRecord) -> Result<(), c_int>;
+}
+
+/// Filesystem that journals mutating operations before passing them to the wrapped
+/// filesystem. See the module documentation for details.
+#[derive(Debug)]
+pub struct JournaledFilesystem<FS, J> {
+    inner: FS,
+    journal: J,
+    ops: Vec<JournalOp>,
+    full_payloads: bool,
+}
+
+impl<FS: Filesystem, J: Journal> JournaledFilesystem<FS, J> {
+    /// Wrap the given filesystem and journal all mutating operations to the given journal
+    pub fn new(inner: FS, journal: J) -> JournaledFilesystem<FS, J> {
+        JournaledFilesystem { inner, journal, ops: ALL_OPS.to_vec(), full_payloads: false }
+    }
+
+    /// Only journal the given operations
+    pub fn journal_ops(mut self, ops: &[JournalOp]) -> JournaledFilesystem<FS, J> {
+        self.ops = ops.to_vec();
+        self
+    }
+
+    /// Store write data and xattr values in the journal instead of only a digest. Required to
+    /// be able to replay writes and setxattr.
+    pub fn full_payloads(mut self, full: bool) -> JournaledFilesystem<FS, J> {
+        self.full_payloads = full;
+        self
+    }
+
+    /// Returns the wrapped filesystem
+    pub fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    /// Returns the wrapped filesystem
+    pub fn inner_mut(&mut self) -> &mut FS {
+        &mut self.inner
+    }
+
+    /// Replay all journal records into the wrapped filesystem. Call this on startup, before
+    /// mounting. Records that fail to replay are skipped with a warning. Returns the number
+    /// of records that were replayed successfully.
+    pub fn recover(&mut self) -> io::Result<usize> where FS: Replay {
+        let mut replayed = 0;
+        for data in self.journal.records()? {
+            let record = JournalRecord::decode(&data)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid journal record"))?;
+            match self.inner.replay(&record) {
+                Ok(()) => replayed += 1,
+                Err(err) => warn!("Failed to replay {:?}: error {}", record.op(), err),
+            }
+        }
+        Ok(replayed)
+    }
+
+    /// Clear the journal. Call this once the wrapped filesystem made all journaled
+    /// operations durable by itself.
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        self.journal.clear()
+    }
+
+    /// Durably journal an operation if it's enabled. The record is only created if needed.
+    fn journal<F: FnOnce(bool) -> JournalRecord>(&mut self, op: JournalOp, record: F) -> Result<(), c_int> {
+        if !self.ops.contains(&op) { return Ok(()); }
+        let data = record(self.full_payloads).encode();
+        let res = self.journal.append(&data).and_then(|()| self.journal.sync());
+        res.map_err(|err| {
+            warn!("Failed to journal {:?}: {}", op, err);
+            err.to_errno()
+        })
+    }
+}
+
+impl<FS: Filesystem, J: Journal> Filesystem for JournaledFilesystem<FS, J> {
+    fn init(&mut self, req: &Request<'_>) -> Result<InitConfig, c_int> {
+        self.inner.init(req)
+    }
+
+    fn pre_unmount(&mut self) -> Result<(), c_int> {
+        self.inner.pre_unmount()
+    }
+
+    fn destroy(&mut self, reason: ShutdownReason) {
+        self.inner.destroy(reason);
+    }
+
+    fn mount_flags_changed(&mut self, readonly: bool) {
+        self.inner.mount_flags_changed(readonly);
+    }
+
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.inner.lookup(req, parent, name, reply);
+    }
+
+    fn forget(&mut self, req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.inner.forget(req, ino, nlookup);
+    }
+
+    fn interrupt(&mut self, req: &Request<'_>, unique: u64) {
+        self.inner.interrupt(req, unique);
+    }
+
+    #[cfg(feature = "abi-7-16")]
+    fn batch_forget(&mut self, req: &Request<'_>, nodes: &[ForgetOne]) {
+        self.inner.batch_forget(req, nodes);
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        self.inner.getattr(req, ino, reply);
+    }
+
+    fn setattr_changes(&mut self, req: &Request<'_>, ino: u64, changes: SetattrChanges, reply: ReplyAttr) {
+        let record = JournalRecord::SetAttr { ino, mode: changes.mode(), uid: changes.uid(), gid: changes.gid(), size: changes.size() };
+        if let Err(err) = self.journal(JournalOp::SetAttr, |_| record) {
+            return reply.error(err);
+        }
+        self.inner.setattr_changes(req, ino, changes, reply);
+    }
+
+    fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData) {
+        self.inner.readlink(req, ino, reply);
+    }
+
+    fn mknod_with_umask(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: CreateMode, rdev: u32, reply: ReplyEntry) {
+        if let Err(err) = self.journal(JournalOp::Mknod, |_| JournalRecord::Mknod { parent, name: name.into(), mode: mode.mode(), umask: mode.umask(), rdev }) {
+            return reply.error(err);
+        }
+        self.inner.mknod_with_umask(req, parent, name, mode, rdev, reply);
+    }
+
+    fn mkdir_with_umask(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: CreateMode, reply: ReplyEntry) {
+        if let Err(err) = self.journal(JournalOp::Mkdir, |_| JournalRecord::Mkdir { parent, name: name.into(), mode: mode.mode(), umask: mode.umask() }) {
+            return reply.error(err);
+        }
+        self.inner.mkdir_with_umask(req, parent, name, mode, reply);
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        if let Err(err) = self.journal(JournalOp::Unlink, |_| JournalRecord::Unlink { parent, name: name.into() }) {
+            return reply.error(err);
+        }
+        self.inner.unlink(req, parent, name, reply);
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        if let Err(err) = self.journal(JournalOp::Rmdir, |_| JournalRecord::Rmdir { parent, name: name.into() }) {
+            return reply.error(err);
+        }
+        self.inner.rmdir(req, parent, name, reply);
+    }
+
+    fn symlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
+        if let Err(err) = self.journal(JournalOp::Symlink, |_| JournalRecord::Symlink { parent, name: name.into(), link: link.into() }) {
+            return reply.error(err);
+        }
+        self.inner.symlink(req, parent, name, link, reply);
+    }
+
+    fn rename(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, reply: ReplyEmpty) {
+        if let Err(err) = self.journal(JournalOp::Rename, |_| JournalRecord::Rename { parent, name: name.into(), newparent, newname: newname.into() }) {
+            return reply.error(err);
+        }
+        self.inner.rename(req, parent, name, newparent, newname, reply);
+    }
+
+    #[cfg(feature = "abi-7-23")]
+    fn rename2(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, rename: Rename2Request<'_>, reply: ReplyEmpty) {
+        if let Err(err) = self.journal(JournalOp::Rename2, |_| JournalRecord::Rename2 { parent, name: name.into(), newparent: rename.newparent(), newname: rename.newname().into(), flags: rename.flags().bits() }) {
+            return reply.error(err);
+        }
+        self.inner.rename2(req, parent, name, rename, reply);
+    }
+
+    fn link(&mut self, req: &Request<'_>, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        if let Err(err) = self.journal(JournalOp::Link, |_| JournalRecord::Link { ino, newparent, newname: newname.into() }) {
+            return reply.error(err);
+        }
+        self.inner.link(req, ino, newparent, newname, reply);
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: u32, reply: ReplyOpen) {
+        self.inner.open(req, ino, flags, reply);
+    }
+
+    fn read(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        self.inner.read(req, ino, fh, offset, size, reply);
+    }
+
+    fn write(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, data: &[u8], flags: u32, reply: ReplyWrite) {
+        if let Err(err) = self.journal(JournalOp::Write, |full| JournalRecord::Write { ino, offset, data: Payload::new(data, full) }) {
+            return reply.error(err);
+        }
+        self.inner.write(req, ino, fh, offset, data, flags, reply);
+    }
+
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        self.inner.flush(req, ino, fh, lock_owner, reply);
+    }
+
+    fn release(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool, reply: ReplyEmpty) {
+        self.inner.release(req, ino, fh, flags, lock_owner, flush, reply);
+    }
+
+    fn fsync_with_flags(&mut self, req: &Request<'_>, ino: u64, fh: u64, fsync_flags: u32, reply: ReplyEmpty) {
+        self.inner.fsync_with_flags(req, ino, fh, fsync_flags, reply);
+    }
+
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, flags: u32, reply: ReplyOpen) {
+        self.inner.opendir(req, ino, flags, reply);
+    }
+
+    fn readdir(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
+        self.inner.readdir(req, ino, fh, offset, reply);
+    }
+
+    #[cfg(feature = "abi-7-21")]
+    fn readdirplus(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, reply: ReplyDirectoryPlus) {
+        self.inner.readdirplus(req, ino, fh, offset, reply);
+    }
+
+    fn releasedir(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: u32, reply: ReplyEmpty) {
+        self.inner.releasedir(req, ino, fh, flags, reply);
+    }
+
+    fn fsyncdir_with_flags(&mut self, req: &Request<'_>, ino: u64, fh: u64, fsync_flags: u32, reply: ReplyEmpty) {
+        self.inner.fsyncdir_with_flags(req, ino, fh, fsync_flags, reply);
+    }
+
+    fn statfs(&mut self, req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        self.inner.statfs(req, ino, reply);
+    }
+
+    fn setxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, value: &[u8], flags: u32, position: u32, reply: ReplyEmpty) {
+        if let Err(err) = self.journal(JournalOp::SetXattr, |full| JournalRecord::SetXattr { ino, name: name.into(), value: Payload::new(value, full), flags, position }) {
+            return reply.error(err);
+        }
+        self.inner.setxattr(req, ino, name, value, flags, position, reply);
+    }
+
+    fn getxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        self.inner.getxattr(req, ino, name, size, reply);
+    }
+
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        self.inner.listxattr(req, ino, size, reply);
+    }
+
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        if let Err(err) = self.journal(JournalOp::RemoveXattr, |_| JournalRecord::RemoveXattr { ino, name: name.into() }) {
+            return reply.error(err);
+        }
+        self.inner.removexattr(req, ino, name, reply);
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: u32, reply: ReplyEmpty) {
+        self.inner.access(req, ino, mask, reply);
+    }
+
+    fn create_with_umask(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: CreateMode, flags: u32, reply: ReplyCreate) {
+        if let Err(err) = self.journal(JournalOp::Create, |_| JournalRecord::Create { parent, name: name.into(), mode: mode.mode(), umask: mode.umask(), flags }) {
+            return reply.error(err);
+        }
+        self.inner.create_with_umask(req, parent, name, mode, flags, reply);
+    }
+
+    // Unnamed files don't survive a crash, so only linking them is journaled
+    #[cfg(feature = "abi-7-37")]
+    fn tmpfile(&mut self, req: &Request<'_>, parent: u64, mode: CreateMode, flags: u32, reply: ReplyCreate) {
+        self.inner.tmpfile(req, parent, mode, flags, reply);
+    }
+
+    fn getlk(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, reply: ReplyLock) {
+        self.inner.getlk(req, ino, fh, lock_owner, start, end, typ, pid, reply);
+    }
+
+    fn setlk(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, sleep: bool, reply: ReplyEmpty) {
+        self.inner.setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep, reply);
+    }
+
+    fn bmap(&mut self, req: &Request<'_>, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        self.inner.bmap(req, ino, blocksize, idx, reply);
+    }
+
+    #[cfg(feature = "abi-7-11")]
+    fn ioctl(&mut self, req: &Request<'_>, ino: u64, fh: u64, ioctl: IoctlRequest<'_>, reply: ReplyIoctl) {
+        self.inner.ioctl(req, ino, fh, ioctl, reply);
+    }
+
+    #[cfg(feature = "abi-7-15")]
+    fn retrieve_reply(&mut self, req: &Request<'_>, notify_unique: u64, ino: u64, offset: u64, data: &[u8]) {
+        self.inner.retrieve_reply(req, notify_unique, ino, offset, data);
+    }
+
+    #[cfg(feature = "abi-7-19")]
+    fn fallocate(&mut self, req: &Request<'_>, ino: u64, fh: u64, fallocate: FallocateRequest, reply: ReplyEmpty) {
+        if let Err(err) = self.journal(JournalOp::Fallocate, |_| JournalRecord::Fallocate { ino, offset: fallocate.offset(), length: fallocate.length(), mode: fallocate.mode() }) {
+            return reply.error(err);
+        }
+        self.inner.fallocate(req, ino, fh, fallocate, reply);
+    }
+
+    #[cfg(feature = "abi-7-24")]
+    fn lseek(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, whence: i32, reply: ReplyLseek) {
+        self.inner.lseek(req, ino, fh, offset, whence, reply);
+    }
+
+    #[cfg(target_os = "macos")]
+    fn setvolname(&mut self, req: &Request<'_>, name: &OsStr, reply: ReplyEmpty) {
+        self.inner.setvolname(req, name, reply);
+    }
+
+    #[cfg(target_os = "macos")]
+    fn exchange(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, options: u64, reply: ReplyEmpty) {
+        self.inner.exchange(req, parent, name, newparent, newname, options, reply);
+    }
+
+    #[cfg(target_os = "macos")]
+    fn getxtimes(&mut self, req: &Request<'_>, ino: u64, reply: ReplyXTimes) {
+        self.inner.getxtimes(req, ino, reply);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsString;
+    use std::fs::{self, OpenOptions};
+    use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+    use std::process;
+    use std::sync::{Arc, Mutex};
+    use libc::{c_int, ENOSPC};
+    use crate::request::test::{reply_header, run, write_request};
+    use crate::{Filesystem, ReplyWrite, Request, SessionBuilder};
+    use super::{crc32, FileJournal, Journal, JournalFile, JournaledFilesystem, JournalOp, JournalRecord, Payload, Replay};
+
+    fn records() -> Vec<JournalRecord> {
+        let name = OsString::from("file");
+        vec![
+            JournalRecord::SetAttr { ino: 2, mode: Some(0o644), uid: None, gid: Some(100), size: Some(0) },
+            JournalRecord::Mknod { parent: 1, name: name.clone(), mode: 0o666, umask: 0o022, rdev: 0 },
+            JournalRecord::Mkdir { parent: 1, name: name.clone(), mode: 0o777, umask: 0o027 },
+            JournalRecord::Unlink { parent: 1, name: name.clone() },
+            JournalRecord::Rmdir { parent: 1, name: name.clone() },
+            JournalRecord::Symlink { parent: 1, name: name.clone(), link: "target".into() },
+            JournalRecord::Rename { parent: 1, name: name.clone(), newparent: 3, newname: "other".into() },
+            JournalRecord::Link { ino: 2, newparent: 3, newname: name.clone() },
+            JournalRecord::Write { ino: 2, offset: 4096, data: Payload::Data(b"data".to_vec()) },
+            JournalRecord::Write { ino: 2, offset: -1, data: Payload::new(b"data", false) },
+            JournalRecord::SetXattr { ino: 2, name: "user.a".into(), value: Payload::Data(vec![]), flags: 1, position: 0 },
+            JournalRecord::RemoveXattr { ino: 2, name: "user.a".into() },
+            JournalRecord::Create { parent: 1, name: name.clone(), mode: 0o666, umask: 0o077, flags: 0 },
+            JournalRecord::Rename2 { parent: 1, name, newparent: 3, newname: "other".into(), flags: 2 },
+            JournalRecord::Fallocate { ino: 2, offset: 4096, length: 8192, mode: 3 },
+        ]
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn record_round_trip() {
+        for record in records() {
+            let data = record.encode();
+            assert_eq!(JournalRecord::decode(&data), Some(record));
+            assert_eq!(JournalRecord::decode(&data[..data.len() - 1]), None);
+        }
+        assert_eq!(JournalRecord::decode(&[]), None);
+        assert_eq!(JournalRecord::decode(&[99]), None);
+    }
+
+    /// Filesystem that collects replayed records
+    #[derive(Default)]
+    struct ReplayFS {
+        replayed: Vec<JournalRecord>,
+    }
+
+    impl Filesystem for ReplayFS {}
+
+    impl Replay for ReplayFS {
+        fn replay(&mut self, record: &JournalRecord) -> Result<(), c_int> {
+            self.replayed.push(record.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn torn_tail_is_truncated() {
+        let path = std::env::temp_dir().join(format!("fuse-rs-journal-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let records = records();
+        {
+            let mut journal = FileJournal::open(&path).unwrap();
+            for record in &records[..3] {
+                journal.append(&record.encode()).unwrap();
+            }
+            journal.sync().unwrap();
+        }
+        let valid_len = fs::metadata(&path).unwrap().len();
+        // Crash while writing the next record: only part of it made it to the file
+        let mut torn = Vec::new();
+        torn.extend_from_slice(&100u32.to_le_bytes());
+        torn.extend_from_slice(&0u32.to_le_bytes());
+        torn.extend_from_slice(b"partial");
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&torn).unwrap();
+
+        let journal = FileJournal::open(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), valid_len);
+        let mut fs = JournaledFilesystem::new(ReplayFS::default(), journal);
+        assert_eq!(fs.recover().unwrap(), 3);
+        assert_eq!(fs.inner().replayed, &records[..3]);
+
+        // Appending continues after the last valid record
+        fs.journal.append(&records[3].encode()).unwrap();
+        fs.journal.sync().unwrap();
+        drop(fs);
+        let mut journal = FileJournal::open(&path).unwrap();
+        assert_eq!(journal.records().unwrap().len(), 4);
+        // A record with a wrong checksum is torn as well
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(valid_len + 5).unwrap();
+        let mut journal = FileJournal::open(&path).unwrap();
+        assert_eq!(journal.records().unwrap().len(), 3);
+        journal.clear().unwrap();
+        assert_eq!(journal.records().unwrap().len(), 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Journal file in memory whose writes fail once a number of bytes was written, like a
+    /// filling disk
+    struct FillingFile {
+        data: Cursor<Vec<u8>>,
+        space: Option<usize>,
+        truncate_fails: bool,
+    }
+
+    impl Read for FillingFile {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.data.read(buf)
+        }
+    }
+
+    impl Write for FillingFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = match self.space {
+                Some(0) => return Err(io::Error::from_raw_os_error(ENOSPC)),
+                Some(space) => buf.len().min(space),
+                None => buf.len(),
+            };
+            self.space = self.space.map(|space| space - len);
+            self.data.write(&buf[..len])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for FillingFile {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.data.seek(pos)
+        }
+    }
+
+    impl JournalFile for FillingFile {
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            if self.truncate_fails { return Err(io::Error::from_raw_os_error(libc::EIO)); }
+            self.data.get_mut().resize(len as usize, 0);
+            Ok(())
+        }
+
+        fn sync_data(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_append_is_removed() {
+        let records = records();
+        let file = FillingFile { data: Cursor::new(Vec::new()), space: None, truncate_fails: false };
+        let mut journal = FileJournal::with_file(file).unwrap();
+        journal.append(&records[0].encode()).unwrap();
+        journal.sync().unwrap();
+        // The disk fills up in the middle of the next record
+        journal.file.space = Some(5);
+        assert_eq!(journal.append(&records[1].encode()).unwrap_err().raw_os_error(), Some(ENOSPC));
+        // The part that was written doesn't hide records appended after it
+        journal.file.space = None;
+        journal.append(&records[2].encode()).unwrap();
+        journal.sync().unwrap();
+        let replayed: Vec<_> = journal.records().unwrap().iter().map(|data| JournalRecord::decode(data).unwrap()).collect();
+        assert_eq!(replayed, [records[0].clone(), records[2].clone()]);
+        // If the part that was written can't be removed, appending fails until cleared
+        journal.file.space = Some(5);
+        journal.file.truncate_fails = true;
+        assert!(journal.append(&records[3].encode()).is_err());
+        journal.file.space = None;
+        assert!(journal.append(&records[3].encode()).is_err());
+        journal.file.truncate_fails = false;
+        journal.clear().unwrap();
+        journal.append(&records[3].encode()).unwrap();
+        journal.sync().unwrap();
+        assert_eq!(journal.records().unwrap(), [records[3].encode()]);
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-12")]
+    fn umask_is_journaled() {
+        use std::mem;
+        use fuse_abi::{fuse_mkdir_in, fuse_opcode};
+        use crate::request::test::request;
+
+        let path = std::env::temp_dir().join(format!("fuse-rs-journal-umask-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let mut args = vec![0; mem::size_of::<fuse_mkdir_in>()];
+        args[0..4].copy_from_slice(&(libc::S_IFDIR | 0o777).to_ne_bytes());
+        args[4..8].copy_from_slice(&0o022u32.to_ne_bytes());
+        args.extend_from_slice(b"dir\0");
+        let fs = JournaledFilesystem::new(ReplayFS::default(), FileJournal::open(&path).unwrap()).journal_ops(&[JournalOp::Mkdir]);
+        run(fs, SessionBuilder::new().dont_mask(), &[request(fuse_opcode::FUSE_MKDIR, 2, 1, &args)]);
+
+        // The filesystem applies the umask, so replaying must apply the same one
+        let mut fs = JournaledFilesystem::new(ReplayFS::default(), FileJournal::open(&path).unwrap());
+        assert_eq!(fs.recover().unwrap(), 1);
+        assert_eq!(fs.inner().replayed, [JournalRecord::Mkdir { parent: 1, name: "dir".into(), mode: libc::S_IFDIR | 0o777, umask: 0o022 }]);
+        drop(fs);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-19")]
+    fn fallocate_is_replayed() {
+        use std::mem;
+        use fuse_abi::{fuse_fallocate_in, fuse_opcode};
+        use crate::request::test::request;
+
+        let path = std::env::temp_dir().join(format!("fuse-rs-journal-fallocate-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let mut args = vec![0; mem::size_of::<fuse_fallocate_in>()];
+        args[8..16].copy_from_slice(&4096u64.to_ne_bytes());
+        args[16..24].copy_from_slice(&8192u64.to_ne_bytes());
+        args[24..28].copy_from_slice(&(libc::FALLOC_FL_KEEP_SIZE as u32).to_ne_bytes());
+        let fs = JournaledFilesystem::new(ReplayFS::default(), FileJournal::open(&path).unwrap()).journal_ops(&[JournalOp::Fallocate]);
+        run(fs, SessionBuilder::new(), &[request(fuse_opcode::FUSE_FALLOCATE, 2, 2, &args)]);
+
+        let mut fs = JournaledFilesystem::new(ReplayFS::default(), FileJournal::open(&path).unwrap());
+        assert_eq!(fs.recover().unwrap(), 1);
+        assert_eq!(fs.inner().replayed, [JournalRecord::Fallocate { ino: 2, offset: 4096, length: 8192, mode: libc::FALLOC_FL_KEEP_SIZE }]);
+        drop(fs);
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Journal that logs calls, and fails if there's no space left
+    struct EventJournal {
+        events: Arc<Mutex<Vec<&'static str>>>,
+        full: bool,
+    }
+
+    impl Journal for EventJournal {
+        fn append(&mut self, _record: &[u8]) -> io::Result<()> {
+            if self.full { return Err(io::Error::from_raw_os_error(ENOSPC)); }
+            self.events.lock().unwrap().push("append");
+            Ok(())
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            self.events.lock().unwrap().push("sync");
+            Ok(())
+        }
+
+        fn records(&mut self) -> io::Result<Vec<Vec<u8>>> {
+            Ok(Vec::new())
+        }
+
+        fn clear(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Filesystem that logs replies
+    struct EventFS {
+        events: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl Filesystem for EventFS {
+        fn write(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+            self.events.lock().unwrap().push("reply");
+            reply.written(data.len() as u32);
+        }
+    }
+
+    fn run_journaled(journal_full: bool, ops: &[JournalOp]) -> (Vec<&'static str>, Vec<(u64, i32)>) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let journal = EventJournal { events: events.clone(), full: journal_full };
+        let fs = JournaledFilesystem::new(EventFS { events: events.clone() }, journal).journal_ops(ops);
+        let replies = run(fs, SessionBuilder::new(), &[write_request(2, b"one"), write_request(3, b"two")]);
+        let events = events.lock().unwrap().clone();
+        (events, replies.iter().map(|reply| reply_header(reply)).collect())
+    }
+
+    #[test]
+    fn reply_after_durable_append() {
+        let (events, replies) = run_journaled(false, &[JournalOp::Write]);
+        assert_eq!(events, ["append", "sync", "reply", "append", "sync", "reply"]);
+        assert_eq!(replies, [(2, 0), (3, 0)]);
+    }
+
+    #[test]
+    fn journal_failure_fails_operation() {
+        let (events, replies) = run_journaled(true, &[JournalOp::Write]);
+        assert!(events.is_empty());
+        assert_eq!(replies, [(2, -ENOSPC), (3, -ENOSPC)]);
+        // Operations that aren't journaled don't touch the journal
+        let (events, replies) = run_journaled(true, &[JournalOp::Mkdir]);
+        assert_eq!(events, ["reply", "reply"]);
+        assert_eq!(replies, [(2, 0), (3, 0)]);
+    }
+}
@@ -0,0 +1,189 @@
+//! Removal of file capabilities on write
+//!
+//! Writing to or truncating a file must drop its privileges: the setuid and setgid bits, and
+//! file capabilities (the `security.capability` extended attribute). Otherwise a user who may
+//! write to a privileged binary (e.g. one with `cap_setuid`) could replace its code and keep
+//! its privileges. Who drops them depends on the negotiated init flags:
+//!
+//! * Without `FUSE_HANDLE_KILLPRIV` and `FUSE_HANDLE_KILLPRIV_V2`, the kernel driver clears
+//!   setuid and setgid bits by sending a setattr request with the new mode. File capabilities
+//!   are left to the filesystem; a passthrough filesystem that writes to backing files with
+//!   its own privileges keeps them.
+//! * With `FUSE_HANDLE_KILLPRIV` (ABI 7.26), the filesystem is responsible for all of it, on
+//!   every write, chown and truncate.
+//! * With `FUSE_HANDLE_KILLPRIV_V2` (ABI 7.33, see `SessionBuilder::handle_killpriv_v2`), the
+//!   filesystem is responsible as well, but the kernel driver flags the writes
+//!   (`FUSE_WRITE_KILL_SUIDGID`), setattr requests (`FATTR_KILL_SUIDGID`) and truncating opens
+//!   (`FUSE_OPEN_KILL_SUIDGID`) that must clear setuid and setgid bits, taking the caller's
+//!   `CAP_FSETID` and the group execute bit into account like local filesystems do. File
+//!   capabilities must be removed on every write and truncate.
+//!
+//! Unless `FUSE_HANDLE_KILLPRIV_V2` was negotiated, the session can remove file capabilities
+//! on behalf of the filesystem (see `SessionBuilder::safe_defaults`): before dispatching a
+//! write by another user than the file's owner or a setattr request that changes the size, it
+//! calls the filesystem's `removexattr` for `security.capability`.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use libc::{c_int, ENOSYS};
+use fuse_abi::consts::FATTR_SIZE;
+use log::warn;
+
+use crate::ll;
+use crate::reply::ReplySender;
+use crate::session::ProtocolCapabilities;
+use crate::FileAttr;
+
+/// Name of the extended attribute that holds file capabilities
+pub const CAPABILITY_XATTR: &str = "security.capability";
+
+/// Error for a missing extended attribute
+#[cfg(not(target_os = "macos"))]
+const ENOATTR: c_int = libc::ENODATA;
+#[cfg(target_os = "macos")]
+const ENOATTR: c_int = libc::ENOATTR;
+
+/// Owners of inodes and inodes whose capabilities were removed already. Both are forgotten
+/// completely when the given max number of inodes is reached. Writes by owners of forgotten
+/// inodes then remove capabilities as well, and capabilities may be removed again.
+#[derive(Debug)]
+pub struct KillPriv {
+    owners: Mutex<HashMap<u64, u32>>,
+    cleared: Mutex<HashSet<u64>>,
+    max: usize,
+    /// Number of times all owners were forgotten
+    overflows: AtomicU64,
+    /// Set once a warning about forgetting all owners was logged
+    warned: AtomicBool,
+}
+
+impl KillPriv {
+    /// Create a new tracker of owners of up to the given number of inodes
+    pub fn new(max: usize) -> KillPriv {
+        KillPriv { owners: Mutex::default(), cleared: Mutex::default(), max, overflows: AtomicU64::new(0), warned: AtomicBool::new(false) }
+    }
+
+    /// Returns the number of inodes with known owners and how often all of them were forgotten
+    /// because the limit was reached
+    pub fn stats(&self) -> (usize, u64) {
+        (self.owners.lock().unwrap().len(), self.overflows.load(Ordering::Relaxed))
+    }
+
+    /// Remember the owner of replied attributes
+    pub fn replied(&self, attr: &FileAttr) {
+        let mut owners = self.owners.lock().unwrap();
+        if owners.len() >= self.max && !owners.contains_key(&attr.ino) {
+            owners.clear();
+            self.overflows.fetch_add(1, Ordering::Relaxed);
+            if !self.warned.swap(true, Ordering::Relaxed) {
+                warn!("Tracking owners of more than {} inodes, forgetting all of them (see SessionBuilder::max_tracked_inodes)", self.max);
+            }
+        }
+        owners.insert(attr.ino, attr.uid);
+    }
+
+    /// Returns true if the capabilities of the inode of the given request must be removed
+    /// before dispatching it. Writes by the owner (if known from replied attributes) don't
+    /// drop capabilities, and capabilities are removed once until they're set again.
+    pub fn observe(&self, request: &ll::Request<'_>) -> bool {
+        let ino = request.nodeid();
+        let kill = match request.operation() {
+            ll::Operation::Write { .. } => self.owners.lock().unwrap().get(&ino) != Some(&request.uid()),
+            ll::Operation::SetAttr { arg } => arg.valid & FATTR_SIZE != 0,
+            ll::Operation::SetXAttr { name, .. } if *name == OsStr::new(CAPABILITY_XATTR) => {
+                self.cleared.lock().unwrap().remove(&ino);
+                false
+            }
+            ll::Operation::Forget { .. } => {
+                self.forget(ino);
+                false
+            }
+            #[cfg(feature = "abi-7-16")]
+            ll::Operation::BatchForget { nodes, .. } => {
+                for node in nodes.iter() {
+                    self.forget(node.nodeid);
+                }
+                false
+            }
+            _ => false,
+        };
+        if !kill {
+            return false;
+        }
+        let mut cleared = self.cleared.lock().unwrap();
+        if cleared.len() >= self.max && !cleared.contains(&ino) {
+            cleared.clear();
+        }
+        cleared.insert(ino)
+    }
+
+    /// Forget everything about the given inode, its number may be reused for another file
+    fn forget(&self, ino: u64) {
+        self.owners.lock().unwrap().remove(&ino);
+        self.cleared.lock().unwrap().remove(&ino);
+    }
+}
+
+/// Returns true if the kernel driver flags requests that must clear privileges, so that the
+/// filesystem knows when to remove file capabilities (`FUSE_HANDLE_KILLPRIV_V2` was negotiated)
+pub(crate) fn flagged_by_kernel(capabilities: &ProtocolCapabilities) -> bool {
+    #[cfg(all(feature = "abi-7-33", not(target_os = "macos")))]
+    return capabilities.handle_killpriv_v2();
+    #[cfg(not(all(feature = "abi-7-33", not(target_os = "macos"))))]
+    {
+        let _ = capabilities;
+        false
+    }
+}
+
+/// Reply sender for removexattr calls issued by the session, which logs failures instead of
+/// sending replies to the kernel driver
+#[derive(Debug)]
+pub(crate) struct KillPrivSender {
+    pub ino: u64,
+}
+
+impl ReplySender for KillPrivSender {
+    fn send(&self, data: &[&[u8]]) {
+        let mut error = [0; 4];
+        error.copy_from_slice(&data[0][4..8]);
+        match -i32::from_ne_bytes(error) {
+            0 | ENOATTR | ENOSYS => (),
+            err => warn!("Failed to remove file capabilities of inode {}: error {}", self.ino, err),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::UNIX_EPOCH;
+    use crate::{FileAttr, FileType};
+    use super::KillPriv;
+
+    #[test]
+    fn owners() {
+        let privs = KillPriv::new(16);
+        privs.replied(&FileAttr::builder(2, FileType::RegularFile).uid(1000).times(UNIX_EPOCH).build());
+        assert_eq!(privs.owners.lock().unwrap().get(&2), Some(&1000));
+        privs.forget(2);
+        assert!(privs.owners.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn bounded() {
+        let privs = KillPriv::new(2);
+        for ino in 2..4 {
+            privs.replied(&FileAttr::builder(ino, FileType::RegularFile).uid(1000).times(UNIX_EPOCH).build());
+        }
+        assert_eq!(privs.stats(), (2, 0));
+        // Known owners can be updated at the limit, otherwise all are forgotten
+        privs.replied(&FileAttr::builder(3, FileType::RegularFile).uid(1001).times(UNIX_EPOCH).build());
+        assert_eq!(privs.stats(), (2, 0));
+        privs.replied(&FileAttr::builder(4, FileType::RegularFile).uid(1000).times(UNIX_EPOCH).build());
+        assert_eq!(privs.stats(), (1, 1));
+        assert_eq!(privs.owners.lock().unwrap().get(&2), None);
+    }
+}
@@ -12,22 +12,83 @@ use std::ffi::OsStr;
 use std::path::Path;
 use std::time::SystemTime;
 use libc::{c_int, ENOSYS};
+use fuse_abi::consts::FUSE_FSYNC_FDATASYNC;
 
 pub use fuse_abi::FUSE_ROOT_ID;
 pub use fuse_abi::consts;
+pub use advice::{Advice, SequentialReadDetector};
+pub use attr::FileAttrTryFromError;
+pub use attrdiff::{AttrDiff, AttrChange};
+pub use barrier::{Barrier, BarrierCancel, BarrierError, WriteBarrier, WriteToken};
+pub use buffer::BytesBuffer;
+pub use errno::ToErrno;
+pub use fairness::{FairDispatch, RequestClass, ClassStats, DispatchStats};
+pub use fdbudget::{FdBudget, FdGuard};
+pub use filter::{RequestFilter, RequestMeta, FilterDecision, UidGidFilter, ReadOnlySwitch};
+pub use interrupt::InterruptFlag;
+pub use lock::{PosixLock, PosixLockTable};
+pub use metrics::Metrics;
+#[cfg(feature = "abi-7-12")]
+pub use notify::Notifier;
 pub use reply::{Reply, ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr, ReplyOpen};
 pub use reply::{ReplyWrite, ReplyStatfs, ReplyCreate, ReplyLock, ReplyBmap, ReplyDirectory};
-pub use reply::ReplyXattr;
+pub use reply::{ReplyXattr, XAttrValue, XAttrList, OpenFlags};
 #[cfg(target_os = "macos")]
 pub use reply::ReplyXTimes;
+#[cfg(feature = "abi-7-21")]
+pub use reply::ReplyDirectoryPlus;
+#[cfg(feature = "abi-7-24")]
+pub use reply::ReplyLseek;
+#[cfg(feature = "abi-7-11")]
+pub use reply::ReplyIoctl;
+pub use pidns::{PidInfo, PidError};
+#[cfg(target_os = "linux")]
+pub use pidns::{ProcessInfo, Cgroup};
 pub use request::Request;
-pub use session::{Session, BackgroundSession};
+#[cfg(feature = "abi-7-16")]
+pub use request::ForgetOne;
+pub use session::{Session, SessionBuilder, BackgroundSession, ShutdownReason, ProtocolCapabilities, InitConfig, InitFlags, RunSummary, TrackingStats};
+pub use setattr::{SetattrChanges, SetattrStep, TruncateEffect};
+pub use statfs::{StatFs, StatFsBuilder, StatFsError};
+pub use task::{TaskScope, ShutdownToken};
 
+mod access;
+mod advice;
+mod attr;
+mod attrdiff;
+mod barrier;
+mod buffer;
+mod capcheck;
 mod channel;
+#[cfg(target_os = "linux")]
+mod connection;
+mod errno;
+mod fairness;
+mod fdbudget;
+mod interrupt;
+mod filter;
+pub mod faults;
+pub mod journal;
+mod killpriv;
 mod ll;
+mod lock;
+mod lookups;
+mod metrics;
+#[cfg(feature = "abi-7-12")]
+mod notify;
+mod pidns;
 mod reply;
 mod request;
+pub mod resolve;
 mod session;
+mod setattr;
+mod statfs;
+#[cfg(target_os = "linux")]
+pub mod supervisor;
+pub mod syscalls;
+mod task;
+pub mod testing;
+mod xattrcache;
 
 /// File types
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -48,7 +109,246 @@ pub enum FileType {
     Socket,
 }
 
-/// File attributes
+/// Flags of a rename2 request
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RenameFlags(u32);
+
+impl RenameFlags {
+    /// Don't overwrite the target (RENAME_NOREPLACE)
+    pub const NOREPLACE: u32 = 1 << 0;
+    /// Atomically exchange source and target (RENAME_EXCHANGE)
+    pub const EXCHANGE: u32 = 1 << 1;
+    /// Leave a whiteout object at the source (RENAME_WHITEOUT, used by overlay filesystems)
+    pub const WHITEOUT: u32 = 1 << 2;
+
+    /// Create rename flags from the raw flag bits
+    pub fn from_bits(bits: u32) -> RenameFlags {
+        RenameFlags(bits)
+    }
+
+    /// Returns the raw flag bits
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// The target must not exist. If it does, reply with `EEXIST`.
+    pub fn noreplace(self) -> bool {
+        self.0 & RenameFlags::NOREPLACE != 0
+    }
+
+    /// Source and target must be exchanged atomically. Both must exist, if either doesn't,
+    /// reply with `ENOENT`.
+    pub fn exchange(self) -> bool {
+        self.0 & RenameFlags::EXCHANGE != 0
+    }
+
+    /// A whiteout object must be created at the source
+    pub fn whiteout(self) -> bool {
+        self.0 & RenameFlags::WHITEOUT != 0
+    }
+
+    /// Returns true if the flags are a valid combination of known flags
+    #[cfg_attr(not(any(test, feature = "abi-7-23")), allow(dead_code))]
+    fn is_valid(self) -> bool {
+        let known = RenameFlags::NOREPLACE | RenameFlags::EXCHANGE | RenameFlags::WHITEOUT;
+        self.0 & !known == 0 && !(self.exchange() && (self.noreplace() || self.whiteout()))
+    }
+}
+
+/// Target and flags of a rename2 request
+#[derive(Clone, Copy, Debug)]
+pub struct Rename2Request<'a> {
+    newparent: u64,
+    newname: &'a OsStr,
+    flags: RenameFlags,
+}
+
+impl<'a> Rename2Request<'a> {
+    #[cfg_attr(not(feature = "abi-7-23"), allow(dead_code))]
+    pub(crate) fn new(newparent: u64, newname: &'a OsStr, flags: RenameFlags) -> Rename2Request<'a> {
+        Rename2Request { newparent, newname, flags }
+    }
+
+    /// Inode number of the target directory
+    pub fn newparent(&self) -> u64 {
+        self.newparent
+    }
+
+    /// Name of the target entry
+    pub fn newname(&self) -> &'a OsStr {
+        self.newname
+    }
+
+    /// Rename flags
+    pub fn flags(&self) -> RenameFlags {
+        self.flags
+    }
+}
+
+/// Flags of an ioctl request
+///
+/// On 64-bit systems, ioctls of 32-bit processes are marked as compat ioctls. Their argument
+/// and any structures it points to have the layout of the 32-bit ABI: pointers and `long`
+/// are 4 bytes instead of 8, and 64-bit integers may be aligned to 4 bytes only, so
+/// structures may be smaller and have different field offsets. The command number is the
+/// same, but its encoded size may differ if the argument structure differs.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct IoctlFlags(u32);
+
+impl IoctlFlags {
+    /// 32-bit compat ioctl on a 64-bit system (FUSE_IOCTL_COMPAT)
+    pub const COMPAT: u32 = 1 << 0;
+    /// Not restricted to well-formed ioctls, retry allowed (FUSE_IOCTL_UNRESTRICTED, CUSE
+    /// only)
+    pub const UNRESTRICTED: u32 = 1 << 1;
+    /// Retry with new iovecs (FUSE_IOCTL_RETRY, only used in replies)
+    pub const RETRY: u32 = 1 << 2;
+    /// The process is 32-bit (FUSE_IOCTL_32BIT, ABI 7.16)
+    pub const BIT32: u32 = 1 << 3;
+    /// The ioctl is on a directory (FUSE_IOCTL_DIR, ABI 7.18)
+    pub const DIR: u32 = 1 << 4;
+
+    /// Create ioctl flags from the raw flag bits (e.g. `fuse_ioctl_in.flags`)
+    pub fn from_bits(bits: u32) -> IoctlFlags {
+        IoctlFlags(bits)
+    }
+
+    /// Returns the raw flag bits
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// The ioctl was issued by a 32-bit process on a 64-bit system, so its argument has the
+    /// layout of the 32-bit ABI
+    pub fn is_compat(self) -> bool {
+        self.0 & IoctlFlags::COMPAT != 0
+    }
+
+    /// The ioctl was issued by a 32-bit process. Unlike `is_compat`, this is also set on
+    /// 32-bit systems (since ABI 7.16), so it tells the size of pointers and `long` in the
+    /// argument regardless of the system.
+    pub fn is_32bit(self) -> bool {
+        self.0 & IoctlFlags::BIT32 != 0
+    }
+
+    /// The ioctl isn't restricted to well-formed ioctls (CUSE only)
+    pub fn is_unrestricted(self) -> bool {
+        self.0 & IoctlFlags::UNRESTRICTED != 0
+    }
+
+    /// The ioctl is on a directory
+    pub fn is_dir(self) -> bool {
+        self.0 & IoctlFlags::DIR != 0
+    }
+}
+
+/// Command, argument and data of an ioctl request
+#[derive(Clone, Copy, Debug)]
+pub struct IoctlRequest<'a> {
+    flags: IoctlFlags,
+    cmd: u32,
+    arg: u64,
+    in_data: &'a [u8],
+    out_size: u32,
+}
+
+impl<'a> IoctlRequest<'a> {
+    #[cfg_attr(not(feature = "abi-7-11"), allow(dead_code))]
+    pub(crate) fn new(flags: IoctlFlags, cmd: u32, arg: u64, in_data: &'a [u8], out_size: u32) -> IoctlRequest<'a> {
+        IoctlRequest { flags, cmd, arg, in_data, out_size }
+    }
+
+    /// Ioctl flags
+    pub fn flags(&self) -> IoctlFlags {
+        self.flags
+    }
+
+    /// Command number
+    pub fn cmd(&self) -> u32 {
+        self.cmd
+    }
+
+    /// Raw argument in the caller's address space, which is only useful to retry
+    /// unrestricted ioctls (see `ReplyIoctl::retry`)
+    pub fn arg(&self) -> u64 {
+        self.arg
+    }
+
+    /// Input data of the command (as much as its encoded size tells for restricted ioctls)
+    pub fn in_data(&self) -> &'a [u8] {
+        self.in_data
+    }
+
+    /// Maximum size of the output data to reply with
+    pub fn out_size(&self) -> u32 {
+        self.out_size
+    }
+}
+
+/// Mode of a new file and umask of the creating process
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CreateMode {
+    mode: u32,
+    umask: u32,
+}
+
+impl CreateMode {
+    /// Create a mode of a new file with the given umask
+    pub fn new(mode: u32, umask: u32) -> CreateMode {
+        CreateMode { mode, umask }
+    }
+
+    /// Returns the mode of the new file, including the file type bits
+    pub fn mode(self) -> u32 {
+        self.mode
+    }
+
+    /// Returns the umask of the creating process (0 before ABI 7.12)
+    pub fn umask(self) -> u32 {
+        self.umask
+    }
+
+    /// Returns the mode with the umask applied. Only permission bits are masked, the file
+    /// type bits are kept. Filesystems need this if `FUSE_DONT_MASK` was negotiated (see
+    /// `Filesystem::mknod_with_umask`).
+    pub fn masked(self) -> u32 {
+        self.mode & !(self.umask & 0o777)
+    }
+}
+
+/// Range and mode of a fallocate request
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FallocateRequest {
+    offset: i64,
+    length: i64,
+    mode: i32,
+}
+
+impl FallocateRequest {
+    #[cfg_attr(not(feature = "abi-7-19"), allow(dead_code))]
+    pub(crate) fn new(offset: i64, length: i64, mode: i32) -> FallocateRequest {
+        FallocateRequest { offset, length, mode }
+    }
+
+    /// Start of the range
+    pub fn offset(&self) -> i64 {
+        self.offset
+    }
+
+    /// Length of the range
+    pub fn length(&self) -> i64 {
+        self.length
+    }
+
+    /// `FALLOC_FL_*` flags
+    pub fn mode(&self) -> i32 {
+        self.mode
+    }
+}
+
+/// File attributes. There's no device number (`st_dev`): the kernel driver assigns one to
+/// every mount and reports it for all files of the filesystem (see `Session::device_id`).
+/// Passthrough filesystems can convert the metadata of backing files with `FileAttr::try_from`.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct FileAttr {
     /// Inode number
@@ -75,28 +375,196 @@ pub struct FileAttr {
     pub uid: u32,
     /// Group id
     pub gid: u32,
-    /// Rdev
+    /// Device number of character and block device files (not of the filesystem)
     pub rdev: u32,
     /// Flags (macOS only, see chflags(2))
     pub flags: u32,
 }
 
+impl FileAttr {
+    /// Create a builder for file attributes of the given inode and kind of file. See
+    /// `FileAttrBuilder` for defaults.
+    pub fn builder(ino: u64, kind: FileType) -> FileAttrBuilder {
+        FileAttrBuilder::new(ino, kind)
+    }
+}
+
+/// Builder for file attributes with sensible defaults: permissions are 0o755 for
+/// directories, 0o777 for symlinks and 0o644 for everything else. The number of hard
+/// links is 2 for directories and 1 for everything else. All times are set to the
+/// current time, everything else is 0.
+#[derive(Clone, Copy, Debug)]
+pub struct FileAttrBuilder {
+    attr: FileAttr,
+}
+
+impl FileAttrBuilder {
+    /// Create a builder for file attributes of the given inode and kind of file
+    pub fn new(ino: u64, kind: FileType) -> FileAttrBuilder {
+        let now = SystemTime::now();
+        let (perm, nlink) = match kind {
+            FileType::Directory => (0o755, 2),
+            FileType::Symlink => (0o777, 1),
+            _ => (0o644, 1),
+        };
+        FileAttrBuilder {
+            attr: FileAttr {
+                ino,
+                size: 0,
+                blocks: 0,
+                atime: now,
+                mtime: now,
+                ctime: now,
+                crtime: now,
+                kind,
+                perm,
+                nlink,
+                uid: 0,
+                gid: 0,
+                rdev: 0,
+                flags: 0,
+            },
+        }
+    }
+
+    /// Set the size in bytes
+    pub fn size(mut self, size: u64) -> FileAttrBuilder {
+        self.attr.size = size;
+        self
+    }
+
+    /// Set the size in blocks
+    pub fn blocks(mut self, blocks: u64) -> FileAttrBuilder {
+        self.attr.blocks = blocks;
+        self
+    }
+
+    /// Set the time of last access
+    pub fn atime(mut self, atime: SystemTime) -> FileAttrBuilder {
+        self.attr.atime = atime;
+        self
+    }
+
+    /// Set the time of last modification
+    pub fn mtime(mut self, mtime: SystemTime) -> FileAttrBuilder {
+        self.attr.mtime = mtime;
+        self
+    }
+
+    /// Set the time of last change
+    pub fn ctime(mut self, ctime: SystemTime) -> FileAttrBuilder {
+        self.attr.ctime = ctime;
+        self
+    }
+
+    /// Set the time of creation (macOS only)
+    pub fn crtime(mut self, crtime: SystemTime) -> FileAttrBuilder {
+        self.attr.crtime = crtime;
+        self
+    }
+
+    /// Set all times (access, modification, change and creation) to the given time
+    pub fn times(self, time: SystemTime) -> FileAttrBuilder {
+        self.atime(time).mtime(time).ctime(time).crtime(time)
+    }
+
+    /// Set the permissions
+    pub fn perm(mut self, perm: u16) -> FileAttrBuilder {
+        self.attr.perm = perm;
+        self
+    }
+
+    /// Set the number of hard links
+    pub fn nlink(mut self, nlink: u32) -> FileAttrBuilder {
+        self.attr.nlink = nlink;
+        self
+    }
+
+    /// Set the user id
+    pub fn uid(mut self, uid: u32) -> FileAttrBuilder {
+        self.attr.uid = uid;
+        self
+    }
+
+    /// Set the group id
+    pub fn gid(mut self, gid: u32) -> FileAttrBuilder {
+        self.attr.gid = gid;
+        self
+    }
+
+    /// Set the rdev
+    pub fn rdev(mut self, rdev: u32) -> FileAttrBuilder {
+        self.attr.rdev = rdev;
+        self
+    }
+
+    /// Set the flags (macOS only, see chflags(2))
+    pub fn flags(mut self, flags: u32) -> FileAttrBuilder {
+        self.attr.flags = flags;
+        self
+    }
+
+    /// Build the file attributes
+    pub fn build(self) -> FileAttr {
+        self.attr
+    }
+}
+
 /// Filesystem trait.
 ///
 /// This trait must be implemented to provide a userspace filesystem via FUSE.
 /// These methods correspond to fuse_lowlevel_ops in libfuse. Reasonable default
 /// implementations are provided here to get a mountable filesystem that does
 /// nothing.
+///
+/// Operations that a filesystem doesn't implement should be replied with `ENOSYS`
+/// (which is what the default implementations do). For some operations, the kernel
+/// driver remembers this and doesn't send them again for the lifetime of the mount:
+/// `flush`, `fsync`, `fsyncdir`, `setxattr`, `getxattr`, `listxattr`, `removexattr`,
+/// `access`, `bmap` and `create` (the kernel falls back to `mknod` and `open` instead).
+/// Some of them also fail with a different error from then on: `fallocate` and `tmpfile`
+/// with `EOPNOTSUPP` and `rename2` with `EINVAL`, while `lseek` treats the whole file as
+/// data. `ioctl` fails with `ENOTTY` instead, but is sent again every time. This makes it
+/// safe to implement only a subset of operations. For all other operations, `ENOSYS` is
+/// simply returned to the caller every time.
 pub trait Filesystem {
     /// Initialize filesystem.
-    /// Called before any other filesystem method.
-    fn init(&mut self, _req: &Request<'_>) -> Result<(), c_int> {
+    /// Called before any other filesystem method. Returns the init settings the filesystem
+    /// asks for, e.g. additional init flags like `FUSE_WRITEBACK_CACHE` (see `InitConfig`).
+    /// The capabilities of the kernel driver are available with `Request::capabilities`.
+    fn init(&mut self, _req: &Request<'_>) -> Result<InitConfig, c_int> {
+        Ok(InitConfig::default())
+    }
+
+    /// Flush pending state before the session ends.
+    /// Called exactly once right before `destroy`, if `init` was called before, to give
+    /// writeback or batching filesystems a chance to flush buffered data to their backing
+    /// store. Unlike `destroy`, which is about cleaning up, this may fail: errors are logged,
+    /// and `destroy` is called anyway. The filesystem may be unmounted already (e.g. if the
+    /// connection was closed by an external unmount), so this must not rely on the kernel
+    /// driver. The order is: `pre_unmount`, `destroy`, `Session::run` returns, the session
+    /// unmounts the filesystem (if still mounted) when it's dropped, the filesystem is dropped.
+    fn pre_unmount(&mut self) -> Result<(), c_int> {
         Ok(())
     }
 
     /// Clean up filesystem.
-    /// Called on filesystem exit.
-    fn destroy(&mut self, _req: &Request<'_>) {}
+    /// Called exactly once when the filesystem exits, with the reason why, if `init` was
+    /// called before. It's called before `Session::run` returns, no matter whether the
+    /// kernel sent a destroy request, the connection was closed or the session loop failed.
+    /// No other methods are called afterwards, but tasks spawned using `Request::spawner`
+    /// may still be running. Implementing this is optional: since the session owns the
+    /// filesystem, cleanup that doesn't need the reason can be done in `Drop` as well, which
+    /// runs once the session is dropped, after tasks finished and the filesystem was
+    /// unmounted.
+    fn destroy(&mut self, _reason: ShutdownReason) {}
+
+    /// The mount was made read-only or read-write (e.g. by `mount -o remount,ro`).
+    /// Only called if the session watches the mount (see `SessionBuilder::watch_mount_flags`),
+    /// from the session loop before the next request is dispatched. The kernel driver refuses
+    /// modifying operations of read-only mounts itself, so this is meant for adapting caches
+    /// or reported state.
+    fn mount_flags_changed(&mut self, _readonly: bool) {}
 
     /// Look up a directory entry by name and get its attributes.
     fn lookup(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEntry) {
@@ -109,19 +577,49 @@ pub trait Filesystem {
     /// inodes acquire a single reference on each lookup, and lose nlookup references on
     /// each forget. The filesystem may ignore forget calls, if the inodes don't need to
     /// have a limited lifetime. On unmount it is not guaranteed, that all referenced
-    /// inodes will receive a forget message.
+    /// inodes will receive a forget message. Forgets aren't replied to, so filesystems
+    /// that don't keep track of inodes can just return (which is what the default does).
     fn forget(&mut self, _req: &Request<'_>, _ino: u64, _nlookup: u64) {}
 
+    /// Interrupt the outstanding request with the given unique id, e.g. because the process
+    /// that waits for it got a signal. The request should be replied with `EINTR` as soon as
+    /// possible (or completed normally). Only called for requests that weren't replied yet,
+    /// which are typically replied asynchronously; requests can check whether they were
+    /// interrupted with `Request::interrupt_flag` instead, which is also set if the interrupt
+    /// arrives before the request is dispatched. Interrupts aren't replied to.
+    fn interrupt(&mut self, _req: &Request<'_>, _unique: u64) {}
+
+    /// Forget about multiple inodes.
+    /// The kernel driver sends batches of forgets (of up to thousands of inodes) instead
+    /// of single forgets with ABI 7.16 and later. The default calls `forget` for each
+    /// inode without allocating. Filesystems that don't keep track of inodes can override
+    /// this with an empty method to ignore a whole batch at once.
+    #[cfg(feature = "abi-7-16")]
+    fn batch_forget(&mut self, req: &Request<'_>, nodes: &[ForgetOne]) {
+        for node in nodes {
+            self.forget(req, node.ino(), node.nlookup());
+        }
+    }
+
     /// Get file attributes.
     fn getattr(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyAttr) {
         reply.error(ENOSYS);
     }
 
     /// Set file attributes.
+    /// Prefer implementing `setattr_changes`, which calls this by default.
     fn setattr(&mut self, _req: &Request<'_>, _ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<SystemTime>, _mtime: Option<SystemTime>, _fh: Option<u64>, _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>, _flags: Option<u32>, reply: ReplyAttr) {
         reply.error(ENOSYS);
     }
 
+    /// Set file attributes.
+    /// A request can change several attributes at once, which should be applied in the order
+    /// of `changes.apply_order()`. The default implementation calls `setattr` with the changes
+    /// as separate parameters.
+    fn setattr_changes(&mut self, req: &Request<'_>, ino: u64, changes: SetattrChanges, reply: ReplyAttr) {
+        self.setattr(req, ino, changes.mode(), changes.uid(), changes.gid(), changes.size(), changes.atime(), changes.mtime(), changes.fh(), changes.crtime(), changes.chgtime(), changes.bkuptime(), changes.flags(), reply);
+    }
+
     /// Read symbolic link.
     fn readlink(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyData) {
         reply.error(ENOSYS);
@@ -129,15 +627,33 @@ pub trait Filesystem {
 
     /// Create file node.
     /// Create a regular file, character device, block device, fifo or socket node.
+    /// Filesystems that need the umask implement `mknod_with_umask` instead.
     fn mknod(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, _mode: u32, _rdev: u32, reply: ReplyEntry) {
         reply.error(ENOSYS);
     }
 
+    /// Create file node, with the umask of the creating process.
+    /// The kernel driver applied the umask to the mode already, unless `FUSE_DONT_MASK` was
+    /// negotiated (see `SessionBuilder::dont_mask`), in which case the filesystem is
+    /// responsible for applying it (see `CreateMode::masked`). The same goes for
+    /// `mkdir_with_umask`, `create_with_umask` and `tmpfile`. The default implementation calls
+    /// `mknod` with the mode as it is.
+    fn mknod_with_umask(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: CreateMode, rdev: u32, reply: ReplyEntry) {
+        self.mknod(req, parent, name, mode.mode(), rdev, reply);
+    }
+
     /// Create a directory.
     fn mkdir(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, _mode: u32, reply: ReplyEntry) {
         reply.error(ENOSYS);
     }
 
+    /// Create a directory, with the umask of the creating process.
+    /// See `mknod_with_umask` on whether the filesystem needs to apply the umask. The default
+    /// implementation calls `mkdir` with the mode as it is.
+    fn mkdir_with_umask(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: CreateMode, reply: ReplyEntry) {
+        self.mkdir(req, parent, name, mode.mode(), reply);
+    }
+
     /// Remove a file.
     fn unlink(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
         reply.error(ENOSYS);
@@ -158,7 +674,20 @@ pub trait Filesystem {
         reply.error(ENOSYS);
     }
 
+    /// Rename a file with flags.
+    /// Only sent if flags are given (otherwise `rename` is called). With `noreplace`, reply
+    /// with `EEXIST` if the target exists. With `exchange`, atomically swap source and
+    /// target, and reply with `ENOENT` if either of them doesn't exist. Invalid flag
+    /// combinations are rejected with `EINVAL` before calling this method. If this method
+    /// isn't implemented, the kernel driver fails renames with flags with `EINVAL`.
+    #[cfg(feature = "abi-7-23")]
+    fn rename2(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, _rename: Rename2Request<'_>, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
     /// Create a hard link.
+    /// The inode may refer to an unnamed file created by `tmpfile` (linkat of an O_TMPFILE
+    /// file descriptor), which gets its first name this way.
     fn link(&mut self, _req: &Request<'_>, _ino: u64, _newparent: u64, _newname: &OsStr, reply: ReplyEntry) {
         reply.error(ENOSYS);
     }
@@ -180,8 +709,11 @@ pub trait Filesystem {
     /// otherwise the rest of the data will be substituted with zeroes. An exception to
     /// this is when the file has been opened in 'direct_io' mode, in which case the
     /// return value of the read system call will reflect the return value of this
-    /// operation. fh will contain the value set by the open method, or will be undefined
-    /// if the open method didn't set any value.
+    /// operation. Replies are bounded by the requested size, more data is cut off (with a
+    /// warning, and a panic in debug builds). fh will contain the value set by the open
+    /// method, or will be undefined if the open method didn't set any value. Advice given
+    /// with `posix_fadvise` isn't forwarded to the filesystem; use a `SequentialReadDetector`
+    /// per file handle to infer the access pattern.
     fn read(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, _size: u32, reply: ReplyData) {
         reply.error(ENOSYS);
     }
@@ -191,7 +723,11 @@ pub trait Filesystem {
     /// exception to this is when the file has been opened in 'direct_io' mode, in
     /// which case the return value of the write system call will reflect the return
     /// value of this operation. fh will contain the value set by the open method, or
-    /// will be undefined if the open method didn't set any value.
+    /// will be undefined if the open method didn't set any value. data borrows from the
+    /// session's request buffer, use `Request::take_write_payload` to keep it without copying.
+    /// Delayed writes from the page cache are reported by `Request::is_kernel_initiated`.
+    /// The offset may be beyond the end of the file, which leaves a hole that reads as zeros
+    /// (e.g. in-memory filesystems need to zero-fill their buffer up to the offset first).
     fn write(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, _data: &[u8], _flags: u32, reply: ReplyWrite) {
         reply.error(ENOSYS);
     }
@@ -205,7 +741,8 @@ pub trait Filesystem {
     /// NOTE: the name of the method is misleading, since (unlike fsync) the filesystem
     /// is not forced to flush pending writes. One reason to flush data, is if the
     /// filesystem wants to return write errors. If the filesystem supports file locking
-    /// operations (setlk, getlk) it should remove all locks belonging to 'lock_owner'.
+    /// operations (setlk, getlk) it must remove all locks belonging to 'lock_owner', e.g.
+    /// by calling `PosixLockTable::unlock_owner`.
     fn flush(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
@@ -217,18 +754,28 @@ pub trait Filesystem {
     /// error, but error values are not returned to close() or munmap() which triggered
     /// the release. fh will contain the value set by the open method, or will be undefined
     /// if the open method didn't set any value. flags will contain the same flags as for
-    /// open.
+    /// open. If flush is set, locks belonging to 'lock_owner' must be removed as in flush,
+    /// e.g. by calling `PosixLockTable::release` with 'lock_owner' and 'flush'.
     fn release(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
         reply.ok();
     }
 
     /// Synchronize file contents.
     /// If the datasync parameter is non-zero, then only the user data should be flushed,
-    /// not the meta data.
+    /// not the meta data. Filesystems that reply to writes before they're durable can use
+    /// a `WriteBarrier` to wait for preceding writes only.
     fn fsync(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
+    /// Synchronize file contents.
+    /// fsync_flags are the raw flags of the request (see `consts::FUSE_FSYNC_FDATASYNC`), for
+    /// filesystems that want to inspect flags other than datasync. The default implementation
+    /// calls `fsync` with datasync set if `FUSE_FSYNC_FDATASYNC` is.
+    fn fsync_with_flags(&mut self, req: &Request<'_>, ino: u64, fh: u64, fsync_flags: u32, reply: ReplyEmpty) {
+        self.fsync(req, ino, fh, fsync_flags & FUSE_FSYNC_FDATASYNC != 0, reply);
+    }
+
     /// Open a directory.
     /// Filesystem may store an arbitrary file handle (pointer, index, etc) in fh, and
     /// use this in other all other directory stream operations (readdir, releasedir,
@@ -249,6 +796,17 @@ pub trait Filesystem {
         reply.error(ENOSYS);
     }
 
+    /// Read directory entries with their attributes.
+    /// Like readdir, but every entry is added with its attributes, which saves the kernel a
+    /// lookup per entry. Every added entry with an inode number other than 0 increases the
+    /// inode's lookup count like a lookup does. The kernel only sends this if readdirplus was
+    /// enabled with `SessionBuilder::readdirplus`, and doesn't fall back to readdir if it
+    /// fails, so filesystems that enable it need to implement this.
+    #[cfg(feature = "abi-7-21")]
+    fn readdirplus(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, reply: ReplyDirectoryPlus) {
+        reply.error(ENOSYS);
+    }
+
     /// Release an open directory.
     /// For every opendir call there will be exactly one releasedir call. fh will
     /// contain the value set by the opendir method, or will be undefined if the
@@ -265,12 +823,26 @@ pub trait Filesystem {
         reply.error(ENOSYS);
     }
 
+    /// Synchronize directory contents.
+    /// Like `fsync_with_flags`, the default implementation calls `fsyncdir` with datasync set
+    /// if `FUSE_FSYNC_FDATASYNC` is set in fsync_flags.
+    fn fsyncdir_with_flags(&mut self, req: &Request<'_>, ino: u64, fh: u64, fsync_flags: u32, reply: ReplyEmpty) {
+        self.fsyncdir(req, ino, fh, fsync_flags & FUSE_FSYNC_FDATASYNC != 0, reply);
+    }
+
     /// Get file system statistics.
+    /// There's no way to report a filesystem id, statvfs always reports `f_fsid` as 0 for
+    /// FUSE filesystems on Linux (the fsname mount option only changes how the mount is
+    /// displayed). Use the device number of the mount to identify it instead (see
+    /// `Session::device_id`). Flags like `ST_RDONLY` can't be replied either, they're taken
+    /// from the mount options.
     fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
-        reply.statfs(0, 0, 0, 0, 0, 512, 255, 0);
+        reply.stats(&StatFs::default());
     }
 
     /// Set an extended attribute.
+    /// The value is borrowed from the buffer the request was received in, which is reused
+    /// for the next request. It must be copied if it needs to be retained.
     fn setxattr(&mut self, _req: &Request<'_>, _ino: u64, _name: &OsStr, _value: &[u8], _flags: u32, _position: u32, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
@@ -278,7 +850,11 @@ pub trait Filesystem {
     /// Get an extended attribute.
     /// If `size` is 0, the size of the value should be sent with `reply.size()`.
     /// If `size` is not 0, and the value fits, send it with `reply.data()`, or
-    /// `reply.error(ERANGE)` if it doesn't.
+    /// `reply.error(ERANGE)` if it doesn't. `reply.respond()` handles all of these cases,
+    /// `reply.value()` does too and can produce large values lazily when they're needed.
+    /// If the attribute doesn't exist, reply with `ENODATA` (`ENOATTR` on macOS). Don't
+    /// reply with `ENOSYS`, which makes the kernel stop sending any xattr requests (see
+    /// `SessionBuilder::negative_xattr` for attributes that never exist).
     fn getxattr(&mut self, _req: &Request<'_>, _ino: u64, _name: &OsStr, _size: u32, reply: ReplyXattr) {
         reply.error(ENOSYS);
     }
@@ -286,7 +862,9 @@ pub trait Filesystem {
     /// List extended attribute names.
     /// If `size` is 0, the size of the value should be sent with `reply.size()`.
     /// If `size` is not 0, and the value fits, send it with `reply.data()`, or
-    /// `reply.error(ERANGE)` if it doesn't.
+    /// `reply.error(ERANGE)` if it doesn't. Filesystems that get the values along with the
+    /// names can reply with `reply.list()`, which saves the round-trips of following getxattr
+    /// requests if the session caches extended attributes (see `SessionBuilder::cache_xattrs`).
     fn listxattr(&mut self, _req: &Request<'_>, _ino: u64, _size: u32, reply: ReplyXattr) {
         reply.error(ENOSYS);
     }
@@ -299,7 +877,8 @@ pub trait Filesystem {
     /// Check file access permissions.
     /// This will be called for the access() system call. If the 'default_permissions'
     /// mount option is given, this method is not called. This method is not called
-    /// under Linux kernel versions 2.4.x
+    /// under Linux kernel versions 2.4.x. Repeated checks can be answered from a cache, see
+    /// `SessionBuilder::cache_access`.
     fn access(&mut self, _req: &Request<'_>, _ino: u64, _mask: u32, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
@@ -313,12 +892,31 @@ pub trait Filesystem {
     /// filesystem may set, to change the way the file is opened. See fuse_file_info
     /// structure in <fuse_common.h> for more details. If this method is not
     /// implemented or under Linux kernel versions earlier than 2.6.15, the mknod()
-    /// and open() methods will be called instead.
+    /// and open() methods will be called instead. Opens with O_TMPFILE don't create a named
+    /// file and are sent to `tmpfile` instead; the flags are passed on as they are though, so
+    /// a filesystem can tell such flags apart if it receives them from elsewhere.
     fn create(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, _mode: u32, _flags: u32, reply: ReplyCreate) {
         reply.error(ENOSYS);
     }
 
-    /// Test for a POSIX file lock.
+    /// Create and open a file, with the umask of the creating process.
+    /// See `mknod_with_umask` on whether the filesystem needs to apply the umask. The default
+    /// implementation calls `create` with the mode as it is.
+    fn create_with_umask(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: CreateMode, flags: u32, reply: ReplyCreate) {
+        self.create(req, parent, name, mode.mode(), flags, reply);
+    }
+
+    /// Create and open an unnamed file in the given directory (O_TMPFILE).
+    /// Reply like `create`, with the attributes of a new inode that has no name (and an
+    /// nlink of 0 unless it's linked). The file can be given a name later by `link`,
+    /// otherwise it's removed once it's released and forgotten. If this method isn't
+    /// implemented, the kernel driver fails opens with O_TMPFILE with `EOPNOTSUPP`.
+    #[cfg(feature = "abi-7-37")]
+    fn tmpfile(&mut self, _req: &Request<'_>, _parent: u64, _mode: CreateMode, _flags: u32, reply: ReplyCreate) {
+        reply.error(ENOSYS);
+    }
+
+    /// Test for a POSIX file lock. See `PosixLockTable` for keeping track of locks.
     fn getlk(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: u32, _pid: u32, reply: ReplyLock) {
         reply.error(ENOSYS);
     }
@@ -341,6 +939,43 @@ pub trait Filesystem {
         reply.error(ENOSYS);
     }
 
+    /// Control a device (ioctl). The ioctl request has the command with its input data, and
+    /// the maximum size of the output data to reply with. fh will contain the value set by the
+    /// open method, or by the opendir method for ioctls on directories, which the kernel
+    /// driver only sends if `FUSE_HAS_IOCTL_DIR` was negotiated.
+    #[cfg(feature = "abi-7-11")]
+    fn ioctl(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _ioctl: IoctlRequest<'_>, reply: ReplyIoctl) {
+        reply.error(ENOSYS);
+    }
+
+    /// Receive data that was retrieved from the kernel driver's page cache with
+    /// `Notifier::retrieve`. notify_unique is the value returned by `retrieve`. The data
+    /// starts at offset and may be shorter than asked for (or empty) if not all of it was
+    /// cached. Notify replies aren't replied to, so the default just drops the data.
+    #[cfg(feature = "abi-7-15")]
+    fn retrieve_reply(&mut self, _req: &Request<'_>, _notify_unique: u64, _ino: u64, _offset: u64, _data: &[u8]) {}
+
+    /// Preallocate or deallocate space in a file (fallocate, posix_fallocate).
+    /// The mode contains the `FALLOC_FL_*` flags. With a mode of 0, space in the given range
+    /// is allocated and the file is extended if the range ends beyond its size. Reply with
+    /// `EOPNOTSUPP` if the mode isn't supported. fh will contain the value set by the open
+    /// method. If this method isn't implemented, the kernel driver fails fallocate with
+    /// `EOPNOTSUPP` and doesn't call it again.
+    #[cfg(feature = "abi-7-19")]
+    fn fallocate(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _fallocate: FallocateRequest, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Reposition the offset of an open file, mainly to find data and holes in sparse files.
+    /// whence is the raw value passed to lseek(2), which is `SEEK_DATA` or `SEEK_HOLE` since
+    /// the kernel driver handles the other values itself. Reply with the resulting offset.
+    /// fh will contain the value set by the open method. If this method isn't implemented,
+    /// the kernel driver treats the whole file as data and doesn't call it again.
+    #[cfg(feature = "abi-7-24")]
+    fn lseek(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, _whence: i32, reply: ReplyLseek) {
+        reply.error(ENOSYS);
+    }
+
     /// macOS only: Rename the volume. Set fuse_init_out.flags during init to
     /// FUSE_VOL_RENAME to enable
     #[cfg(target_os = "macos")]
@@ -373,9 +1008,94 @@ pub fn mount<FS: Filesystem, P: AsRef<Path>>(filesystem: FS, mountpoint: P, opti
 
 /// Mount the given filesystem to the given mountpoint. This function spawns
 /// a background thread to handle filesystem operations while being mounted
-/// and therefore returns immediately. The returned handle should be stored
+/// and returns as soon as the kernel driver initialized the filesystem, so it
+/// can be used immediately. The returned handle should be stored
 /// to reference the mounted filesystem. If it's dropped, the filesystem will
 /// be unmounted.
 pub unsafe fn spawn_mount<'a, FS: Filesystem+Send+'a, P: AsRef<Path>>(filesystem: FS, mountpoint: P, options: &[&OsStr]) -> io::Result<BackgroundSession<'a>> {
     Session::new(filesystem, mountpoint.as_ref(), options).and_then(|se| se.spawn())
 }
+
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+    use super::{FileAttr, FileType, IoctlFlags, RenameFlags};
+
+    #[test]
+    fn file_attr_builder_file_defaults() {
+        let attr = FileAttr::builder(2, FileType::RegularFile).build();
+        assert_eq!(attr.ino, 2);
+        assert_eq!(attr.kind, FileType::RegularFile);
+        assert_eq!(attr.perm, 0o644);
+        assert_eq!(attr.nlink, 1);
+        assert_eq!(attr.size, 0);
+        assert_eq!((attr.uid, attr.gid), (0, 0));
+        assert_eq!(attr.atime, attr.mtime);
+        assert_eq!(attr.mtime, attr.ctime);
+    }
+
+    #[test]
+    fn file_attr_builder_dir_defaults() {
+        let attr = FileAttr::builder(1, FileType::Directory).build();
+        assert_eq!(attr.ino, 1);
+        assert_eq!(attr.kind, FileType::Directory);
+        assert_eq!(attr.perm, 0o755);
+        assert_eq!(attr.nlink, 2);
+    }
+
+    #[test]
+    fn file_attr_builder_setters() {
+        let time = UNIX_EPOCH + Duration::new(0x1234, 0x5678);
+        let attr = FileAttr::builder(3, FileType::RegularFile).size(13).blocks(1).perm(0o600)
+            .uid(501).gid(20).times(time).build();
+        assert_eq!(attr, FileAttr { ino: 3, size: 13, blocks: 1, atime: time, mtime: time, ctime: time, crtime: time,
+            kind: FileType::RegularFile, perm: 0o600, nlink: 1, uid: 501, gid: 20, rdev: 0, flags: 0 });
+    }
+
+    #[test]
+    fn rename_flags() {
+        let flags = RenameFlags::from_bits(RenameFlags::NOREPLACE);
+        assert!(flags.noreplace() && !flags.exchange() && !flags.whiteout());
+        assert!(flags.is_valid());
+        let flags = RenameFlags::from_bits(RenameFlags::EXCHANGE);
+        assert!(flags.exchange() && !flags.noreplace());
+        assert!(flags.is_valid());
+        assert!(RenameFlags::from_bits(RenameFlags::WHITEOUT).whiteout());
+        assert!(!RenameFlags::from_bits(RenameFlags::EXCHANGE | RenameFlags::NOREPLACE).is_valid());
+        assert!(!RenameFlags::from_bits(0x100).is_valid());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn rename_flags_match_libc() {
+        assert_eq!(RenameFlags::NOREPLACE, libc::RENAME_NOREPLACE);
+        assert_eq!(RenameFlags::EXCHANGE, libc::RENAME_EXCHANGE);
+        assert_eq!(RenameFlags::WHITEOUT, libc::RENAME_WHITEOUT);
+    }
+
+    #[test]
+    fn ioctl_flags() {
+        let flags = IoctlFlags::from_bits(IoctlFlags::COMPAT | IoctlFlags::BIT32);
+        assert!(flags.is_compat() && flags.is_32bit());
+        assert!(!flags.is_unrestricted() && !flags.is_dir());
+        let flags = IoctlFlags::from_bits(IoctlFlags::BIT32);
+        assert!(!flags.is_compat() && flags.is_32bit());
+        let flags = IoctlFlags::default();
+        assert!(!flags.is_compat() && !flags.is_32bit());
+        assert!(IoctlFlags::from_bits(IoctlFlags::DIR | IoctlFlags::UNRESTRICTED).is_dir());
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-18")]
+    fn ioctl_flags_from_request() {
+        use fuse_abi::consts::*;
+        use fuse_abi::fuse_ioctl_in;
+        let arg = fuse_ioctl_in { fh: 1, flags: FUSE_IOCTL_COMPAT | FUSE_IOCTL_32BIT | FUSE_IOCTL_DIR, cmd: 0x8004_6601, arg: 0, in_size: 0, out_size: 4 };
+        let flags = IoctlFlags::from_bits(arg.flags);
+        assert!(flags.is_compat() && flags.is_32bit() && flags.is_dir());
+        assert_eq!(flags.bits(), arg.flags);
+        assert_eq!(IoctlFlags::UNRESTRICTED, FUSE_IOCTL_UNRESTRICTED);
+        assert_eq!(IoctlFlags::RETRY, FUSE_IOCTL_RETRY);
+    }
+}
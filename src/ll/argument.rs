@@ -4,7 +4,7 @@
 //! structures (request arguments).
 
 use std::ffi::OsStr;
-use std::mem;
+use std::{mem, slice};
 use std::os::unix::ffi::OsStrExt;
 
 
@@ -25,6 +25,7 @@ impl<'a> ArgumentIterator<'a> {
     }
 
     /// Fetch a slice of all remaining bytes.
+    #[cfg_attr(not(test), allow(dead_code))]
     pub fn fetch_all(&mut self) -> &'a [u8] {
         let bytes = self.data;
         self.data = &[];
@@ -47,6 +48,16 @@ impl<'a> ArgumentIterator<'a> {
         (bytes.as_ptr() as *const T).as_ref()
     }
 
+    /// Fetch a slice of typed arguments. Returns `None` if there's not enough data left. This
+    /// function is unsafe because there is no guarantee that the data actually contains the
+    /// type T.
+    #[cfg_attr(not(feature = "abi-7-16"), allow(dead_code))]
+    pub unsafe fn fetch_slice<T>(&mut self, count: usize) -> Option<&'a [T]> {
+        let len = mem::size_of::<T>().checked_mul(count)?;
+        let bytes = self.fetch_bytes(len)?;
+        Some(slice::from_raw_parts(bytes.as_ptr() as *const T, count))
+    }
+
     /// Fetch a (zero-terminated) string (can be non-utf8). Returns `None` if there's not enough
     /// data left or no zero-termination could be found. This function is unsafe because there is
     /// no guarantee that the data actually contains a string.
@@ -68,6 +79,9 @@ mod tests {
     #[repr(C)]
     struct TestArgument { p1: u8, p2: u8, p3: u16 }
 
+    #[repr(C, align(8))]
+    struct AlignedData([u8; 10]);
+
     #[test]
     fn all_data() {
         let mut it = ArgumentIterator::new(&TEST_DATA);
@@ -110,6 +124,20 @@ mod tests {
         assert_eq!(it.len(), 2);
     }
 
+    #[test]
+    fn slice_argument() {
+        let data = AlignedData(TEST_DATA);
+        let mut it = ArgumentIterator::new(&data.0);
+        let arg: &[TestArgument] = unsafe { it.fetch_slice(2).unwrap() };
+        assert_eq!(arg.len(), 2);
+        assert_eq!(arg[0].p1, 0x66);
+        assert_eq!(arg[1].p3, 0x0072);
+        assert_eq!(it.len(), 2);
+        let arg: Option<&[TestArgument]> = unsafe { it.fetch_slice(1) };
+        assert!(arg.is_none());
+        assert_eq!(it.len(), 2);
+    }
+
     #[test]
     fn mixed_arguments() {
         let mut it = ArgumentIterator::new(&TEST_DATA);
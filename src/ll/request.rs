@@ -153,28 +153,51 @@ pub enum Operation<'a> {
         arg: &'a fuse_bmap_in,
     },
     Destroy,
-    // TODO: FUSE_IOCTL since ABI 7.11
-    // IoCtl {
-    //     arg: &'a fuse_ioctl_in,
-    //     data: &'a [u8],
-    // },
-    // TODO: FUSE_POLL since ABI 7.11
-    // Poll {
-    //     arg: &'a fuse_poll_in,
-    // },
-    // TODO: FUSE_NOTIFY_REPLY since ABI 7.15
-    // NotifyReply {
-    //     data: &'a [u8],
-    // },
-    // TODO: FUSE_BATCH_FORGET since ABI 7.16
-    // BatchForget {
-    //     arg: &'a fuse_forget_in,
-    //     nodes: &'a [fuse_forget_one],
-    // },
-    // TODO: FUSE_FALLOCATE since ABI 7.19
-    // FAllocate {
-    //     arg: &'a fuse_fallocate_in,
-    // },
+    #[cfg(feature = "abi-7-11")]
+    IoCtl {
+        arg: &'a fuse_ioctl_in,
+        data: &'a [u8],
+    },
+    #[cfg(feature = "abi-7-11")]
+    Poll {
+        arg: &'a fuse_poll_in,
+    },
+    #[cfg(feature = "abi-7-15")]
+    NotifyReply {
+        arg: &'a fuse_notify_retrieve_in,
+        data: &'a [u8],
+    },
+    #[cfg(feature = "abi-7-16")]
+    BatchForget {
+        arg: &'a fuse_batch_forget_in,
+        nodes: &'a [fuse_forget_one],
+    },
+    #[cfg(feature = "abi-7-19")]
+    FAllocate {
+        arg: &'a fuse_fallocate_in,
+    },
+    #[cfg(feature = "abi-7-21")]
+    ReadDirPlus {
+        arg: &'a fuse_read_in,
+    },
+    #[cfg(feature = "abi-7-23")]
+    Rename2 {
+        arg: &'a fuse_rename2_in,
+        name: &'a OsStr,
+        newname: &'a OsStr,
+    },
+    #[cfg(feature = "abi-7-24")]
+    Lseek {
+        arg: &'a fuse_lseek_in,
+    },
+    #[cfg(feature = "abi-7-28")]
+    CopyFileRange {
+        arg: &'a fuse_copy_file_range_in,
+    },
+    #[cfg(feature = "abi-7-37")]
+    TmpFile {
+        arg: &'a fuse_create_in,
+    },
 
     #[cfg(target_os = "macos")]
     SetVolName {
@@ -189,10 +212,10 @@ pub enum Operation<'a> {
         newname: &'a OsStr,
     },
 
-    // TODO: CUSE_INIT since ABI 7.12
-    // CuseInit {
-    //     arg: &'a fuse_init_in,
-    // },
+    #[cfg(feature = "abi-7-12")]
+    CuseInit {
+        arg: &'a cuse_init_in,
+    },
 }
 
 impl<'a> fmt::Display for Operation<'a> {
@@ -234,6 +257,26 @@ impl<'a> fmt::Display for Operation<'a> {
             Operation::Interrupt { arg } => write!(f, "INTERRUPT unique {}", arg.unique),
             Operation::BMap { arg } => write!(f, "BMAP blocksize {}, ids {}", arg.blocksize, arg.block),
             Operation::Destroy => write!(f, "DESTROY"),
+            #[cfg(feature = "abi-7-11")]
+            Operation::IoCtl { arg, data } => write!(f, "IOCTL fh {}, flags {:#x}, cmd {}, arg {}, in_size {}, out_size {}, data size {}", arg.fh, arg.flags, arg.cmd, arg.arg, arg.in_size, arg.out_size, data.len()),
+            #[cfg(feature = "abi-7-11")]
+            Operation::Poll { arg } => write!(f, "POLL fh {}, kh {}, flags {:#x}", arg.fh, arg.kh, arg.flags),
+            #[cfg(feature = "abi-7-15")]
+            Operation::NotifyReply { arg, data } => write!(f, "NOTIFY REPLY offset {}, size {}", arg.offset, data.len()),
+            #[cfg(feature = "abi-7-16")]
+            Operation::BatchForget { arg, nodes } => write!(f, "BATCH FORGET count {}, nodes {:?}", arg.count, nodes),
+            #[cfg(feature = "abi-7-19")]
+            Operation::FAllocate { arg } => write!(f, "FALLOCATE fh {}, offset {}, length {}, mode {:#05o}", arg.fh, arg.offset, arg.length, arg.mode),
+            #[cfg(feature = "abi-7-21")]
+            Operation::ReadDirPlus { arg } => write!(f, "READDIRPLUS fh {}, offset {}, size {}", arg.fh, arg.offset, arg.size),
+            #[cfg(feature = "abi-7-23")]
+            Operation::Rename2 { arg, name, newname } => write!(f, "RENAME2 name {:?}, newdir {:#018x}, newname {:?}, flags {:#x}", name, arg.newdir, newname, arg.flags),
+            #[cfg(feature = "abi-7-24")]
+            Operation::Lseek { arg } => write!(f, "LSEEK fh {}, offset {}, whence {}", arg.fh, arg.offset, arg.whence),
+            #[cfg(feature = "abi-7-28")]
+            Operation::CopyFileRange { arg } => write!(f, "COPY_FILE_RANGE fh_in {}, off_in {}, nodeid_out {:#018x}, fh_out {}, off_out {}, len {}, flags {:#x}", arg.fh_in, arg.off_in, arg.nodeid_out, arg.fh_out, arg.off_out, arg.len, arg.flags),
+            #[cfg(feature = "abi-7-37")]
+            Operation::TmpFile { arg } => write!(f, "TMPFILE mode {:#05o}, flags {:#x}", arg.mode, arg.flags),
 
             #[cfg(target_os = "macos")]
             Operation::SetVolName { name } => write!(f, "SETVOLNAME name {:?}", name),
@@ -241,6 +284,9 @@ impl<'a> fmt::Display for Operation<'a> {
             Operation::GetXTimes => write!(f, "GETXTIMES"),
             #[cfg(target_os = "macos")]
             Operation::Exchange { arg, oldname, newname } => write!(f, "EXCHANGE olddir {:#018x}, oldname {:?}, newdir {:#018x}, newname {:?}, options {:#x}", arg.olddir, oldname, arg.newdir, newname, arg.options),
+
+            #[cfg(feature = "abi-7-12")]
+            Operation::CuseInit { arg } => write!(f, "CUSE_INIT kernel ABI {}.{}, flags {:#x}", arg.major, arg.minor, arg.flags),
         }
     }
 }
@@ -285,18 +331,24 @@ impl<'a> Operation<'a> {
                 },
                 fuse_opcode::FUSE_OPEN => Operation::Open { arg: data.fetch()? },
                 fuse_opcode::FUSE_READ => Operation::Read { arg: data.fetch()? },
-                fuse_opcode::FUSE_WRITE => Operation::Write {
-                    arg: data.fetch()?,
-                    data: data.fetch_all(),
-                },
+                fuse_opcode::FUSE_WRITE => {
+                    let arg: &fuse_write_in = data.fetch()?;
+                    Operation::Write {
+                        arg,
+                        data: data.fetch_bytes(arg.size as usize)?,
+                    }
+                }
                 fuse_opcode::FUSE_STATFS => Operation::StatFs,
                 fuse_opcode::FUSE_RELEASE => Operation::Release { arg: data.fetch()? },
                 fuse_opcode::FUSE_FSYNC => Operation::FSync { arg: data.fetch()? },
-                fuse_opcode::FUSE_SETXATTR => Operation::SetXAttr {
-                    arg: data.fetch()?,
-                    name: data.fetch_str()?,
-                    value: data.fetch_all(),
-                },
+                fuse_opcode::FUSE_SETXATTR => {
+                    let arg: &fuse_setxattr_in = data.fetch()?;
+                    Operation::SetXAttr {
+                        arg,
+                        name: data.fetch_str()?,
+                        value: data.fetch_bytes(arg.size as usize)?,
+                    }
+                }
                 fuse_opcode::FUSE_GETXATTR => Operation::GetXAttr {
                     arg: data.fetch()?,
                     name: data.fetch_str()?,
@@ -322,6 +374,49 @@ impl<'a> Operation<'a> {
                 fuse_opcode::FUSE_INTERRUPT => Operation::Interrupt { arg: data.fetch()? },
                 fuse_opcode::FUSE_BMAP => Operation::BMap { arg: data.fetch()? },
                 fuse_opcode::FUSE_DESTROY => Operation::Destroy,
+                #[cfg(feature = "abi-7-11")]
+                fuse_opcode::FUSE_IOCTL => {
+                    let arg: &fuse_ioctl_in = data.fetch()?;
+                    Operation::IoCtl {
+                        arg,
+                        data: data.fetch_bytes(arg.in_size as usize)?,
+                    }
+                }
+                #[cfg(feature = "abi-7-11")]
+                fuse_opcode::FUSE_POLL => Operation::Poll { arg: data.fetch()? },
+                #[cfg(feature = "abi-7-15")]
+                fuse_opcode::FUSE_NOTIFY_REPLY => {
+                    let arg: &fuse_notify_retrieve_in = data.fetch()?;
+                    Operation::NotifyReply {
+                        arg,
+                        data: data.fetch_bytes(arg.size as usize)?,
+                    }
+                }
+                #[cfg(feature = "abi-7-16")]
+                fuse_opcode::FUSE_BATCH_FORGET => {
+                    let arg: &fuse_batch_forget_in = data.fetch()?;
+                    Operation::BatchForget {
+                        arg,
+                        nodes: data.fetch_slice(arg.count as usize)?,
+                    }
+                }
+                #[cfg(feature = "abi-7-19")]
+                fuse_opcode::FUSE_FALLOCATE => Operation::FAllocate { arg: data.fetch()? },
+                #[cfg(feature = "abi-7-21")]
+                fuse_opcode::FUSE_READDIRPLUS => Operation::ReadDirPlus { arg: data.fetch()? },
+                #[cfg(feature = "abi-7-23")]
+                fuse_opcode::FUSE_RENAME2 => Operation::Rename2 {
+                    arg: data.fetch()?,
+                    name: data.fetch_str()?,
+                    newname: data.fetch_str()?,
+                },
+                #[cfg(feature = "abi-7-24")]
+                fuse_opcode::FUSE_LSEEK => Operation::Lseek { arg: data.fetch()? },
+                #[cfg(feature = "abi-7-28")]
+                fuse_opcode::FUSE_COPY_FILE_RANGE => Operation::CopyFileRange { arg: data.fetch()? },
+                // Followed by the name of the unnamed file's dentry ("/"), which is ignored
+                #[cfg(feature = "abi-7-37")]
+                fuse_opcode::FUSE_TMPFILE => Operation::TmpFile { arg: data.fetch()? },
 
                 #[cfg(target_os = "macos")]
                 fuse_opcode::FUSE_SETVOLNAME => Operation::SetVolName {
@@ -335,6 +430,9 @@ impl<'a> Operation<'a> {
                     oldname: data.fetch_str()?,
                     newname: data.fetch_str()?,
                 },
+
+                #[cfg(feature = "abi-7-12")]
+                fuse_opcode::CUSE_INIT => Operation::CuseInit { arg: data.fetch()? },
             })
         }
     }
@@ -372,6 +470,9 @@ impl<'a> TryFrom<&'a [u8]> for Request<'a> {
         if data_len < header.len as usize {
             return Err(RequestError::ShortRead(data_len, header.len as usize));
         }
+        // Arguments must not extend beyond the request length given in the header
+        let args_len = (header.len as usize).checked_sub(data_len - data.len()).ok_or(RequestError::InsufficientData)?;
+        let mut data = ArgumentIterator::new(data.fetch_bytes(args_len).ok_or(RequestError::InsufficientData)?);
         // Parse/check operation arguments
         let operation =
             Operation::parse(&opcode, &mut data).ok_or_else(|| RequestError::InsufficientData)?;
@@ -425,6 +526,7 @@ impl<'a> Request<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::request::test::request;
 
     #[cfg(target_endian = "big")]
     const INIT_REQUEST: [u8; 56] = [
@@ -448,7 +550,7 @@ mod tests {
         0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // max_readahead, flags
     ];
 
-    #[cfg(target_endian = "big")]
+    #[cfg(all(target_endian = "big", not(feature = "abi-7-12")))]
     const MKNOD_REQUEST: [u8; 56] = [
         0x00, 0x00, 0x00, 0x38, 0x00, 0x00, 0x00, 0x08, // len, opcode
         0xde, 0xad, 0xbe, 0xef, 0xba, 0xad, 0xd0, 0x0d, // unique
@@ -459,7 +561,7 @@ mod tests {
         0x66, 0x6f, 0x6f, 0x2e, 0x74, 0x78, 0x74, 0x00, // name
     ];
 
-    #[cfg(target_endian = "little")]
+    #[cfg(all(target_endian = "little", not(feature = "abi-7-12")))]
     const MKNOD_REQUEST: [u8; 56] = [
         0x38, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // len, opcode
         0x0d, 0xf0, 0xad, 0xba, 0xef, 0xbe, 0xad, 0xde, // unique
@@ -486,6 +588,90 @@ mod tests {
         }
     }
 
+    /// Asserts that the given request fails to parse because of insufficient argument data
+    fn assert_insufficient(data: &[u8]) {
+        match Request::try_from(data) {
+            Err(RequestError::InsufficientData) => (),
+            res => panic!("Unexpected request parsing result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn truncated_arguments() {
+        let init = vec![0; mem::size_of::<fuse_init_in>()];
+        assert_insufficient(&request(fuse_opcode::FUSE_INIT, 1, 0, &init[..init.len() - 1]));
+        // Names without zero-termination
+        assert_insufficient(&request(fuse_opcode::FUSE_LOOKUP, 2, 1, b"foo"));
+        let mut rename = vec![0; mem::size_of::<fuse_rename_in>()];
+        rename.extend_from_slice(b"foo\0bar");
+        assert_insufficient(&request(fuse_opcode::FUSE_RENAME, 3, 1, &rename));
+        let mut mknod = vec![0; mem::size_of::<fuse_mknod_in>() - 1];
+        assert_insufficient(&request(fuse_opcode::FUSE_MKNOD, 4, 1, &mknod));
+        mknod.push(0);
+        mknod.extend_from_slice(b"foo");
+        assert_insufficient(&request(fuse_opcode::FUSE_MKNOD, 4, 1, &mknod));
+        mknod.push(0);
+        assert!(Request::try_from(&request(fuse_opcode::FUSE_MKNOD, 4, 1, &mknod)[..]).is_ok());
+    }
+
+    #[test]
+    fn truncated_payloads() {
+        // Write data shorter than the size in the arguments
+        let mut write = vec![0; mem::size_of::<fuse_write_in>()];
+        write[16..20].copy_from_slice(&8u32.to_ne_bytes());
+        write.extend_from_slice(b"data");
+        assert_insufficient(&request(fuse_opcode::FUSE_WRITE, 2, 2, &write));
+        write.extend_from_slice(b"more");
+        match Request::try_from(&request(fuse_opcode::FUSE_WRITE, 2, 2, &write)[..]).unwrap().operation() {
+            Operation::Write { data, .. } => assert_eq!(*data, b"datamore"),
+            _ => panic!("Unexpected request operation"),
+        }
+        // Xattr value shorter than the size in the arguments
+        let mut setxattr = vec![0; mem::size_of::<fuse_setxattr_in>()];
+        setxattr[0..4].copy_from_slice(&6u32.to_ne_bytes());
+        setxattr.extend_from_slice(b"user.foo\0value");
+        assert_insufficient(&request(fuse_opcode::FUSE_SETXATTR, 3, 2, &setxattr));
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-16")]
+    fn truncated_batch_forget() {
+        // More nodes than there's data for
+        let mut forget = vec![0; mem::size_of::<fuse_batch_forget_in>()];
+        forget[0..4].copy_from_slice(&3u32.to_ne_bytes());
+        forget.extend_from_slice(&[0; 2 * mem::size_of::<fuse_forget_one>()]);
+        assert_insufficient(&request(fuse_opcode::FUSE_BATCH_FORGET, 2, 0, &forget));
+        forget[0..4].copy_from_slice(&(!0u32).to_ne_bytes());
+        assert_insufficient(&request(fuse_opcode::FUSE_BATCH_FORGET, 2, 0, &forget));
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-11")]
+    fn ioctl_input() {
+        // Input data shorter than the size in the arguments
+        let mut ioctl = vec![0; mem::size_of::<fuse_ioctl_in>()];
+        ioctl[24..28].copy_from_slice(&4u32.to_ne_bytes());
+        ioctl.extend_from_slice(b"in");
+        assert_insufficient(&request(fuse_opcode::FUSE_IOCTL, 2, 2, &ioctl));
+        ioctl.extend_from_slice(b"put");
+        match Request::try_from(&request(fuse_opcode::FUSE_IOCTL, 2, 2, &ioctl)[..]).unwrap().operation() {
+            Operation::IoCtl { data, .. } => assert_eq!(*data, b"inpu"),
+            _ => panic!("Unexpected request operation"),
+        }
+    }
+
+    #[test]
+    fn arguments_within_request_length() {
+        // Data beyond the request length in the header isn't part of the arguments
+        let mut data = request(fuse_opcode::FUSE_LOOKUP, 2, 1, b"foo");
+        data.extend_from_slice(b"\0");
+        assert_insufficient(&data);
+        // Request length shorter than the header
+        let mut data = request(fuse_opcode::FUSE_GETATTR, 3, 1, &[0; 16]);
+        data[0..4].copy_from_slice(&8u32.to_ne_bytes());
+        assert_insufficient(&data);
+    }
+
     #[test]
     fn init() {
         let req = Request::try_from(&INIT_REQUEST[..]).unwrap();
@@ -507,6 +693,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(not(feature = "abi-7-12"))]
     fn mknod() {
         let req = Request::try_from(&MKNOD_REQUEST[..]).unwrap();
         assert_eq!(req.header.len, 56);
@@ -524,4 +711,29 @@ mod tests {
             _ => panic!("Unexpected request operation"),
         }
     }
+
+    #[test]
+    #[cfg(feature = "abi-7-16")]
+    fn batch_forget() {
+        // Count and padding, followed by nodeid and nlookup of every node
+        let mut forget = Vec::new();
+        for value in &[2u32, 0] {
+            forget.extend_from_slice(&value.to_ne_bytes());
+        }
+        for value in &[0x1122_3344_5566_7788u64, 3, 0x99aa_bbcc_ddee_ff00, 0x1_0000_0001] {
+            forget.extend_from_slice(&value.to_ne_bytes());
+        }
+        let data = request(fuse_opcode::FUSE_BATCH_FORGET, 2, 0, &forget);
+        let req = Request::try_from(&data[..]).unwrap();
+        assert_eq!(req.header.len, 80);
+        match req.operation() {
+            Operation::BatchForget { arg, nodes } => {
+                assert_eq!(arg.count, 2);
+                assert_eq!(nodes.len(), 2);
+                assert_eq!((nodes[0].nodeid, nodes[0].nlookup), (0x1122_3344_5566_7788, 3));
+                assert_eq!((nodes[1].nodeid, nodes[1].nlookup), (0x99aa_bbcc_ddee_ff00, 0x1_0000_0001));
+            }
+            _ => panic!("Unexpected request operation"),
+        }
+    }
 }
@@ -0,0 +1,188 @@
+//! POSIX lock bookkeeping
+//!
+//! Filesystems that implement `getlk` and `setlk` (e.g. network filesystems) need to keep track
+//! of the byte-range locks held on each file. This module provides a simple lock table that
+//! implements the POSIX semantics: locks are owned by a lock owner, read locks can be shared,
+//! write locks are exclusive, and changing or unlocking part of a locked range splits it.
+
+use libc::{c_int, EAGAIN, EINVAL, F_RDLCK, F_UNLCK, F_WRLCK};
+
+/// A POSIX byte-range lock. Ranges are inclusive, an end of `OFFSET_MAX` (as sent by the
+/// kernel driver) or larger extends to the end of the file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PosixLock {
+    /// First byte of the locked range
+    pub start: u64,
+    /// Last byte of the locked range
+    pub end: u64,
+    /// Lock type (`F_RDLCK` or `F_WRLCK`)
+    pub typ: u32,
+    /// Pid of the process that holds the lock (only to report it to getlk)
+    pub pid: u32,
+    /// Owner of the lock
+    pub lock_owner: u64,
+}
+
+impl PosixLock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    fn conflicts(&self, lock_owner: u64, start: u64, end: u64, typ: u32) -> bool {
+        self.lock_owner != lock_owner && self.overlaps(start, end) &&
+            (self.typ == F_WRLCK as u32 || typ == F_WRLCK as u32)
+    }
+}
+
+/// Table of POSIX locks held on a single file. Keep one table per inode and use it from
+/// `getlk` and `setlk`. Since closing any file descriptor of a file releases all locks the
+/// process holds on that file, `flush` must call `unlock_owner` with the given lock owner,
+/// and `release` must call `release` with the given lock owner and flush flag.
+#[derive(Clone, Debug, Default)]
+pub struct PosixLockTable {
+    locks: Vec<PosixLock>,
+}
+
+impl PosixLockTable {
+    /// Create an empty lock table
+    pub fn new() -> PosixLockTable {
+        PosixLockTable { locks: Vec::new() }
+    }
+
+    /// Returns all locks currently held
+    pub fn locks(&self) -> &[PosixLock] {
+        &self.locks
+    }
+
+    /// Returns the first lock that conflicts with the given lock (held by another owner), or
+    /// `None` if the lock could be placed. Suitable to reply to `getlk`: reply with the
+    /// conflicting lock, or with type `F_UNLCK` if there's none.
+    pub fn getlk(&self, lock_owner: u64, start: u64, end: u64, typ: u32) -> Option<PosixLock> {
+        if typ == F_UNLCK as u32 { return None; }
+        self.locks.iter().find(|l| l.conflicts(lock_owner, start, end, typ)).cloned()
+    }
+
+    /// Acquire, modify or release a lock. Replaces any locks of the given owner in the given
+    /// range. Fails with `EAGAIN` if the lock conflicts with a lock of another owner (the table
+    /// doesn't wait for conflicting locks to be released, filesystems that want to support
+    /// blocking `setlk` need to queue and retry the request). Fails with `EINVAL` for an
+    /// invalid range or lock type.
+    pub fn setlk(&mut self, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32) -> Result<(), c_int> {
+        if start > end { return Err(EINVAL); }
+        let typ_int = typ as c_int;
+        if typ_int != F_RDLCK && typ_int != F_WRLCK && typ_int != F_UNLCK {
+            return Err(EINVAL);
+        }
+        if self.getlk(lock_owner, start, end, typ).is_some() {
+            return Err(EAGAIN);
+        }
+        self.remove_range(lock_owner, start, end);
+        if typ_int != F_UNLCK {
+            self.locks.push(PosixLock { start, end, typ, pid, lock_owner });
+        }
+        Ok(())
+    }
+
+    /// Release all locks held by the given owner. Call this from `flush`.
+    pub fn unlock_owner(&mut self, lock_owner: u64) {
+        self.locks.retain(|l| l.lock_owner != lock_owner);
+    }
+
+    /// Release all locks held by the given owner if the flush flag is set, i.e. if the kernel
+    /// driver didn't send a `flush` for the last close of the file. Call this from `release`.
+    /// Without the flush flag, the lock owner of a release request isn't necessarily an owner
+    /// of POSIX locks, so nothing is released.
+    pub fn release(&mut self, lock_owner: u64, flush: bool) {
+        if flush {
+            self.unlock_owner(lock_owner);
+        }
+    }
+
+    /// Remove the given range from all locks of the given owner, splitting locks as needed
+    fn remove_range(&mut self, lock_owner: u64, start: u64, end: u64) {
+        let mut remaining = Vec::with_capacity(self.locks.len());
+        for lock in self.locks.drain(..) {
+            if lock.lock_owner != lock_owner || !lock.overlaps(start, end) {
+                remaining.push(lock);
+                continue;
+            }
+            if lock.start < start {
+                remaining.push(PosixLock { end: start - 1, ..lock });
+            }
+            if lock.end > end {
+                remaining.push(PosixLock { start: end + 1, ..lock });
+            }
+        }
+        self.locks = remaining;
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use libc::{EAGAIN, F_RDLCK, F_UNLCK, F_WRLCK};
+    use super::PosixLockTable;
+
+    const RD: u32 = F_RDLCK as u32;
+    const WR: u32 = F_WRLCK as u32;
+    const UN: u32 = F_UNLCK as u32;
+
+    #[test]
+    fn shared_read_locks() {
+        let mut table = PosixLockTable::new();
+        assert_eq!(table.setlk(1, 0, 99, RD, 10), Ok(()));
+        assert_eq!(table.setlk(2, 50, 149, RD, 20), Ok(()));
+        assert_eq!(table.setlk(3, 90, 90, WR, 30), Err(EAGAIN));
+        assert_eq!(table.getlk(3, 90, 90, WR).unwrap().lock_owner, 1);
+        assert!(table.getlk(3, 150, 200, WR).is_none());
+    }
+
+    #[test]
+    fn unlock_splits_range() {
+        let mut table = PosixLockTable::new();
+        table.setlk(1, 0, 99, WR, 10).unwrap();
+        table.setlk(1, 40, 59, UN, 10).unwrap();
+        assert_eq!(table.locks().len(), 2);
+        assert!(table.getlk(2, 40, 59, WR).is_none());
+        assert_eq!(table.getlk(2, 39, 39, RD).unwrap().end, 39);
+        assert_eq!(table.getlk(2, 60, 60, RD).unwrap().start, 60);
+    }
+
+    #[test]
+    fn owner_changes_own_lock() {
+        let mut table = PosixLockTable::new();
+        table.setlk(1, 0, !0, RD, 10).unwrap();
+        table.setlk(1, 10, 19, WR, 10).unwrap();
+        assert_eq!(table.locks().len(), 3);
+        assert_eq!(table.getlk(2, 15, 15, RD).unwrap().typ, WR);
+        assert!(table.getlk(2, 100, 100, RD).is_none());
+    }
+
+    #[test]
+    fn flush_unlocks_owner() {
+        let mut table = PosixLockTable::new();
+        table.setlk(1, 0, 9, WR, 10).unwrap();
+        table.setlk(1, 20, 29, RD, 10).unwrap();
+        table.setlk(2, 10, 19, WR, 20).unwrap();
+        // What a filesystem does in flush(lock_owner = 1)
+        table.unlock_owner(1);
+        assert_eq!(table.locks().len(), 1);
+        assert_eq!(table.locks()[0].lock_owner, 2);
+        assert_eq!(table.setlk(3, 0, 9, WR, 30), Ok(()));
+        assert_eq!(table.setlk(3, 10, 19, WR, 30), Err(EAGAIN));
+    }
+
+    #[test]
+    fn release_unlocks_owner_on_flush() {
+        let mut table = PosixLockTable::new();
+        table.setlk(1, 0, 9, WR, 10).unwrap();
+        table.setlk(2, 10, 19, WR, 20).unwrap();
+        // What a filesystem does in release(lock_owner = 1, flush = false)
+        table.release(1, false);
+        assert_eq!(table.locks().len(), 2);
+        // What a filesystem does in release(lock_owner = 1, flush = true)
+        table.release(1, true);
+        assert_eq!(table.locks().len(), 1);
+        assert_eq!(table.setlk(3, 0, 9, WR, 30), Ok(()));
+    }
+}
@@ -0,0 +1,189 @@
+//! Lookup counts of inodes
+//!
+//! The kernel driver counts how often an inode was replied in an entry (by lookup, create,
+//! readdirplus etc) and tells the filesystem to forget as many lookups once it drops the inode.
+//! Afterwards, the filesystem may reuse the inode number for another file with a new
+//! generation. A notification that is meant for the forgotten file, but arrives after the
+//! number was reused, would affect the other file. With `SessionBuilder::track_lookups`, the
+//! session keeps track of lookup counts and generations, so that such notifications can be
+//! suppressed (see `Notifier::if_live`).
+//!
+//! The number of tracked inodes is limited (see `SessionBuilder::max_tracked_inodes`). Once
+//! the limit is reached, inodes that weren't known before aren't tracked anymore, and since
+//! the session can't tell them apart from forgotten inodes then, notifications for unknown
+//! inodes aren't suppressed anymore.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use log::{debug, warn};
+use fuse_abi::FUSE_ROOT_ID;
+
+use crate::ll;
+
+/// Generation and lookup count of an inode that the kernel driver knows
+#[derive(Debug)]
+struct Known {
+    generation: u64,
+    nlookup: u64,
+}
+
+/// Inodes known to the kernel driver, by inode number. Unlike other trackers, inodes can't
+/// be dropped to make room: the kernel driver keeps every inode until it tells the
+/// filesystem to forget it. Instead, new inodes aren't tracked once the limit is reached.
+#[derive(Debug)]
+pub(crate) struct LookupTracker {
+    known: Mutex<HashMap<u64, Known>>,
+    /// Max number of tracked inodes
+    max: usize,
+    /// Whether an inode wasn't tracked because the limit was reached
+    degraded: AtomicBool,
+    /// Number of notifications that were suppressed
+    suppressed: AtomicU64,
+}
+
+impl LookupTracker {
+    /// Create a new tracker of up to the given number of inodes
+    pub(crate) fn new(max: usize) -> LookupTracker {
+        LookupTracker { known: Mutex::default(), max, degraded: AtomicBool::new(false), suppressed: AtomicU64::new(0) }
+    }
+
+    /// The given inode was replied in an entry with the given generation, which increments its
+    /// lookup count. Inode number 0 (a negative entry) isn't counted.
+    pub(crate) fn entry(&self, ino: u64, generation: u64) {
+        if ino == 0 {
+            return;
+        }
+        let mut known = self.known.lock().unwrap();
+        if known.len() >= self.max && !known.contains_key(&ino) {
+            if !self.degraded.swap(true, Ordering::Relaxed) {
+                warn!("Tracking lookups of more than {} inodes, not tracking new inodes anymore (see SessionBuilder::max_tracked_inodes)", self.max);
+            }
+            return;
+        }
+        let known = known.entry(ino).or_insert(Known { generation, nlookup: 0 });
+        if known.generation != generation {
+            // The kernel driver marks the inode as bad if it still knows it
+            if known.nlookup > 0 {
+                warn!("Inode {} was replied with generation {} before its generation {} was forgotten", ino, generation, known.generation);
+            }
+            *known = Known { generation, nlookup: 0 };
+        }
+        known.nlookup += 1;
+    }
+
+    /// Count down the lookups that the given request tells to forget
+    pub(crate) fn observe(&self, request: &ll::Request<'_>) {
+        match request.operation() {
+            ll::Operation::Forget { arg } => self.forget(request.nodeid(), arg.nlookup),
+            #[cfg(feature = "abi-7-16")]
+            ll::Operation::BatchForget { nodes, .. } => {
+                for node in nodes.iter() {
+                    self.forget(node.nodeid, node.nlookup);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn forget(&self, ino: u64, nlookup: u64) {
+        if let Entry::Occupied(mut known) = self.known.lock().unwrap().entry(ino) {
+            known.get_mut().nlookup = known.get().nlookup.saturating_sub(nlookup);
+            if known.get().nlookup == 0 {
+                known.remove();
+            }
+        }
+    }
+
+    /// Returns true if the kernel driver knows the given inode with the given generation. The
+    /// root inode is always known, and so are unknown inodes once new inodes aren't tracked
+    /// anymore (they might not be tracked). Otherwise, a suppressed notification is counted.
+    #[cfg_attr(not(any(test, feature = "abi-7-12")), allow(dead_code))]
+    pub(crate) fn is_live(&self, ino: u64, generation: u64) -> bool {
+        let live = ino == FUSE_ROOT_ID || match self.known.lock().unwrap().get(&ino) {
+            Some(known) => known.generation == generation,
+            None => self.degraded.load(Ordering::Relaxed),
+        };
+        if !live {
+            debug!("Suppressing notification for inode {} with generation {}, which the kernel driver doesn't know", ino, generation);
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+        }
+        live
+    }
+
+    /// Returns true if the kernel driver knows the given inode with any generation, or might
+    /// know it since new inodes aren't tracked anymore
+    #[cfg_attr(not(any(test, feature = "abi-7-12")), allow(dead_code))]
+    pub(crate) fn knows(&self, ino: u64) -> bool {
+        ino == FUSE_ROOT_ID || self.degraded.load(Ordering::Relaxed) || self.known.lock().unwrap().contains_key(&ino)
+    }
+
+    /// Returns the number of inodes known to the kernel driver and the number of suppressed
+    /// notifications
+    pub(crate) fn stats(&self) -> (usize, u64) {
+        (self.known.lock().unwrap().len(), self.suppressed.load(Ordering::Relaxed))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::LookupTracker;
+
+    #[test]
+    fn lookup_counts() {
+        let tracker = LookupTracker::new(16);
+        tracker.entry(2, 1);
+        tracker.entry(2, 1);
+        tracker.entry(0, 1);
+        assert_eq!(tracker.stats(), (1, 0));
+        assert!(tracker.is_live(2, 1));
+        // Still known until all lookups are forgotten
+        tracker.forget(2, 1);
+        assert!(tracker.is_live(2, 1));
+        tracker.forget(2, 1);
+        assert!(!tracker.is_live(2, 1));
+        assert!(!tracker.knows(2));
+        assert_eq!(tracker.stats(), (0, 1));
+        // The root inode is never forgotten
+        assert!(tracker.is_live(1, 0));
+        assert!(tracker.knows(1));
+    }
+
+    #[test]
+    fn reused_inode() {
+        let tracker = LookupTracker::new(16);
+        tracker.entry(2, 1);
+        tracker.forget(2, 1);
+        tracker.entry(2, 2);
+        assert!(!tracker.is_live(2, 1));
+        assert!(tracker.is_live(2, 2));
+        // Forgetting more than known doesn't underflow
+        tracker.forget(2, 5);
+        tracker.forget(3, 1);
+        assert_eq!(tracker.stats(), (0, 1));
+    }
+    #[test]
+    fn limit() {
+        let tracker = LookupTracker::new(2);
+        tracker.entry(2, 1);
+        tracker.entry(3, 1);
+        tracker.forget(3, 1);
+        assert!(!tracker.is_live(3, 1));
+        tracker.entry(3, 1);
+        // Not tracked, so notifications for unknown inodes can't be suppressed anymore
+        tracker.entry(4, 1);
+        assert_eq!(tracker.stats(), (2, 1));
+        assert!(tracker.is_live(4, 1));
+        assert!(tracker.is_live(5, 1));
+        assert!(tracker.knows(5));
+        // Counts of tracked inodes are kept
+        tracker.entry(2, 1);
+        tracker.forget(2, 1);
+        assert!(tracker.is_live(2, 1));
+        assert!(!tracker.is_live(2, 2));
+        tracker.forget(2, 1);
+        assert_eq!(tracker.stats(), (1, 2));
+    }
+}
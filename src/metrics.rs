@@ -0,0 +1,485 @@
+//! Metrics in OpenMetrics text format
+//!
+//! `Session::metrics` returns a handle that renders the counters of the session, the sizes of
+//! its trackers and (with `SessionBuilder::latency_histograms`) histograms of the time from
+//! dispatching a request to sending its reply, as OpenMetrics text exposition that Prometheus
+//! scrapes as well. Every sample is labeled with the fsname mount option and the mountpoint.
+//! Serving the text (e.g. over HTTP, or as a file for the textfile collector of the node
+//! exporter) is up to the application.
+//!
+//! Replies are sent by many threads at once. To keep recording latencies wait-free, every
+//! thread adds to one of several shards of atomic counters, which are only merged when
+//! rendering. Rendering may therefore see a latency counted in its bucket, but not yet in the
+//! sum of latencies.
+
+use std::ffi::OsStr;
+use std::fmt::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::attrdiff::AttrTracker;
+use crate::fairness::RequestClass;
+use crate::killpriv::KillPriv;
+use crate::lookups::LookupTracker;
+use crate::session::{OutstandingReplies, TrackingStats};
+
+/// Number of latency buckets with an upper bound, which are 1µs, 2µs, 4µs and so on up to
+/// 2^19µs (about half a second). Another bucket counts longer latencies.
+const BOUNDS: usize = 20;
+
+/// Number of shards that threads add latencies to
+const SHARDS: usize = 16;
+
+/// Upper bound of the number of rendered lines
+const MAX_LINES: usize = 96;
+
+/// Bytes to reserve per rendered line, besides the labels of the mount
+const LINE_CAPACITY: usize = 96;
+
+/// Shard of the next thread that records a latency
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Shard that the current thread adds latencies to
+    static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % SHARDS;
+}
+
+/// Latencies recorded by some threads, aligned to a cache line so that threads adding to
+/// different shards don't contend
+#[derive(Debug, Default)]
+#[repr(align(64))]
+struct Shard {
+    /// Number of latencies per bucket (not cumulative)
+    buckets: [AtomicU64; BOUNDS + 1],
+    /// Sum of latencies in nanoseconds
+    sum: AtomicU64,
+}
+
+/// Histogram of latencies with fixed buckets, whose upper bounds double
+#[derive(Debug)]
+pub(crate) struct LatencyHistogram {
+    shards: Vec<Shard>,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> LatencyHistogram {
+        LatencyHistogram { shards: (0..SHARDS).map(|_| Shard::default()).collect() }
+    }
+}
+
+impl LatencyHistogram {
+    /// Record the given latency
+    pub(crate) fn record(&self, latency: Duration) {
+        let nanos = latency.as_nanos().min(u128::from(!0u64)) as u64;
+        let shard = SHARD.with(|&shard| &self.shards[shard]);
+        shard.buckets[bucket(nanos)].fetch_add(1, Ordering::Relaxed);
+        shard.sum.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    /// Merge the shards. Returns the number of latencies per bucket (not cumulative) and
+    /// their sum in nanoseconds.
+    fn snapshot(&self) -> ([u64; BOUNDS + 1], u64) {
+        let mut buckets = [0; BOUNDS + 1];
+        let mut sum = 0u64;
+        for shard in &self.shards {
+            for (count, bucket) in buckets.iter_mut().zip(shard.buckets.iter()) {
+                *count += bucket.load(Ordering::Relaxed);
+            }
+            sum = sum.wrapping_add(shard.sum.load(Ordering::Relaxed));
+        }
+        (buckets, sum)
+    }
+}
+
+/// Returns the index of the bucket of the given latency in nanoseconds, i.e. the first one
+/// whose upper bound isn't below the latency
+fn bucket(nanos: u64) -> usize {
+    let micros = match nanos % 1000 {
+        0 => nanos / 1000,
+        _ => nanos / 1000 + 1,
+    };
+    if micros <= 1 {
+        return 0;
+    }
+    let index = 64 - (micros - 1).leading_zeros() as usize;
+    if index < BOUNDS { index } else { BOUNDS }
+}
+
+/// Latency histograms of the request classes (see `SessionBuilder::latency_histograms`)
+#[derive(Debug, Default)]
+pub(crate) struct Latencies {
+    classes: [LatencyHistogram; 2],
+}
+
+impl Latencies {
+    /// Record the latency of a reply to a request of the given class
+    pub(crate) fn record(&self, class: RequestClass, latency: Duration) {
+        self.classes[class.index()].record(latency);
+    }
+}
+
+/// Handle to render the metrics of a session in OpenMetrics text format (see
+/// `Session::metrics`). Clones share the same metrics, and rendering is safe from any thread
+/// while the session is running.
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    /// Labels that identify the mount, escaped already
+    labels: Arc<str>,
+    outstanding: Arc<OutstandingReplies>,
+    attrs: Option<Arc<AttrTracker>>,
+    privs: Option<Arc<KillPriv>>,
+    lookups: Option<Arc<LookupTracker>>,
+}
+
+impl Metrics {
+    /// Create a handle for the metrics of the mount with the given fsname and mountpoint
+    pub(crate) fn new(fsname: &str, mountpoint: &Path, outstanding: Arc<OutstandingReplies>, attrs: Option<Arc<AttrTracker>>, privs: Option<Arc<KillPriv>>, lookups: Option<Arc<LookupTracker>>) -> Metrics {
+        let mut labels = String::new();
+        labels.push_str("fsname=\"");
+        escape(&mut labels, fsname);
+        labels.push_str("\",mountpoint=\"");
+        escape(&mut labels, &mountpoint.to_string_lossy());
+        labels.push('"');
+        Metrics { labels: labels.into(), outstanding, attrs, privs, lookups }
+    }
+
+    /// Render all counters, gauges and histograms as OpenMetrics text exposition (ending with
+    /// `# EOF`). The text is rendered into a single allocation, whose size only depends on the
+    /// length of the labels.
+    pub fn render_openmetrics(&self) -> String {
+        let summary = self.outstanding.summary(Duration::default());
+        let tracking = TrackingStats::collect(self.attrs.as_ref(), self.privs.as_ref(), self.lookups.as_ref());
+        let mut text = String::with_capacity(capacity(&self.labels));
+        let mut exposition = Exposition { text: &mut text, labels: &self.labels };
+        exposition.counter("fuse_requests", "Number of dispatched requests", summary.requests_processed);
+        exposition.counter("fuse_errors", "Number of requests that were replied with an error", summary.errors);
+        exposition.counter("fuse_read_bytes", "Number of bytes replied to read requests", summary.bytes_read);
+        exposition.counter("fuse_written_bytes", "Number of bytes replied as written to write requests", summary.bytes_written);
+        exposition.counter("fuse_opened_existing", "Number of create requests that opened an existing file", summary.opened_existing);
+        exposition.counter("fuse_capability_anomalies", "Number of requests that weren't expected with the negotiated capabilities", summary.capability_anomalies);
+        exposition.gauge("fuse_outstanding_requests", "Number of requests that wait for a reply", self.outstanding.outstanding() as u64);
+        exposition.gauge("fuse_tracked_attrs", "Number of inodes whose replied attributes are tracked", tracking.attrs as u64);
+        exposition.counter("fuse_tracked_attrs_overflows", "Number of times all tracked attributes were forgotten", tracking.attr_overflows);
+        exposition.gauge("fuse_tracked_owners", "Number of inodes with known owners", tracking.owners as u64);
+        exposition.counter("fuse_tracked_owners_overflows", "Number of times all known owners were forgotten", tracking.owner_overflows);
+        exposition.gauge("fuse_known_inodes", "Number of inodes known to the kernel driver", tracking.lookups as u64);
+        exposition.counter("fuse_suppressed_notifications", "Number of notifications for forgotten or reused inodes", tracking.suppressed_notifications);
+        if let Some(latencies) = self.outstanding.latencies() {
+            exposition.histogram("fuse_reply_latency_seconds", "Time from dispatching a request to sending its reply", latencies);
+        }
+        text.push_str("# EOF\n");
+        text
+    }
+}
+
+/// Returns the number of bytes to reserve for rendering with the given labels
+fn capacity(labels: &str) -> usize {
+    MAX_LINES * (LINE_CAPACITY + labels.len())
+}
+
+/// Append the given label value, escaped for OpenMetrics
+fn escape(text: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '\\' => text.push_str("\\\\"),
+            '"' => text.push_str("\\\""),
+            '\n' => text.push_str("\\n"),
+            c => text.push(c),
+        }
+    }
+}
+
+/// Returns the value of the fsname mount option in the given options, if any
+pub(crate) fn fsname(options: &[&OsStr]) -> Option<String> {
+    let values = options.windows(2).filter(|pair| pair[0] == OsStr::new("-o")).flat_map(|pair| pair[1].to_str());
+    let mut fsnames = values.flat_map(|value| value.split(',')).filter_map(|option| {
+        let mut parts = option.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some("fsname"), Some(fsname)) => Some(fsname),
+            _ => None,
+        }
+    });
+    fsnames.next_back().map(String::from)
+}
+
+/// Text exposition that metric families are rendered into
+struct Exposition<'a> {
+    text: &'a mut String,
+    labels: &'a str,
+}
+
+impl Exposition<'_> {
+    /// Render the metadata of a metric family
+    fn family(&mut self, name: &str, kind: &str, help: &str) -> fmt::Result {
+        writeln!(self.text, "# TYPE {} {}", name, kind)?;
+        writeln!(self.text, "# HELP {} {}", name, help)
+    }
+
+    fn counter(&mut self, name: &str, help: &str, value: u64) {
+        // Writing to a string doesn't fail
+        let _ = self.family(name, "counter", help).and_then(|_| writeln!(self.text, "{}_total{{{}}} {}", name, self.labels, value));
+    }
+
+    fn gauge(&mut self, name: &str, help: &str, value: u64) {
+        let _ = self.family(name, "gauge", help).and_then(|_| writeln!(self.text, "{}{{{}}} {}", name, self.labels, value));
+    }
+
+    fn histogram(&mut self, name: &str, help: &str, latencies: &Latencies) {
+        let _ = self.render_histogram(name, help, latencies);
+    }
+
+    fn render_histogram(&mut self, name: &str, help: &str, latencies: &Latencies) -> fmt::Result {
+        writeln!(self.text, "# TYPE {} histogram", name)?;
+        writeln!(self.text, "# UNIT {} seconds", name)?;
+        writeln!(self.text, "# HELP {} {}", name, help)?;
+        for &class in &[RequestClass::Metadata, RequestClass::Data] {
+            let class_name = match class {
+                RequestClass::Metadata => "metadata",
+                RequestClass::Data => "data",
+            };
+            let (buckets, sum) = latencies.classes[class.index()].snapshot();
+            let mut count = 0;
+            for (index, bucket) in buckets.iter().enumerate() {
+                count += bucket;
+                write!(self.text, "{}_bucket{{{},class=\"{}\",le=\"", name, self.labels, class_name)?;
+                match index {
+                    BOUNDS => self.text.push_str("+Inf"),
+                    _ => write!(self.text, "{}", (1u64 << index) as f64 / 1e6)?,
+                }
+                writeln!(self.text, "\"}} {}", count)?;
+            }
+            writeln!(self.text, "{}_count{{{},class=\"{}\"}} {}", name, self.labels, class_name, count)?;
+            writeln!(self.text, "{}_sum{{{},class=\"{}\"}} {}", name, self.labels, class_name, sum as f64 / 1e9)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::ffi::OsStr;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use crate::fairness::RequestClass;
+    use crate::session::OutstandingReplies;
+    use super::{capacity, fsname, Latencies, LatencyHistogram, Metrics, BOUNDS};
+
+    /// Labels of a sample, in order
+    type Labels<'a> = Vec<(&'a str, String)>;
+
+    /// Parse a sample line into its name, labels and value
+    fn parse_sample(line: &str) -> Result<(&str, Labels<'_>, f64), String> {
+        let name_end = line.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == ':')).ok_or("missing value")?;
+        let (name, mut rest) = line.split_at(name_end);
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(format!("invalid metric name in {:?}", line));
+        }
+        let mut labels = Vec::new();
+        if rest.starts_with('{') {
+            rest = &rest[1..];
+            while !rest.starts_with('}') {
+                let eq = rest.find("=\"").ok_or("invalid label")?;
+                let label = &rest[..eq];
+                if label.is_empty() || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                    return Err(format!("invalid label name in {:?}", line));
+                }
+                let mut value = String::new();
+                let mut chars = rest[eq + 2..].char_indices();
+                let end = loop {
+                    match chars.next() {
+                        Some((i, '"')) => break eq + 2 + i + 1,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, '\\')) => value.push('\\'),
+                            Some((_, '"')) => value.push('"'),
+                            Some((_, 'n')) => value.push('\n'),
+                            _ => return Err(format!("invalid escape in {:?}", line)),
+                        },
+                        Some((_, '\n')) | None => return Err(format!("unterminated label value in {:?}", line)),
+                        Some((_, c)) => value.push(c),
+                    }
+                };
+                if labels.iter().any(|&(other, _)| other == label) {
+                    return Err(format!("duplicate label in {:?}", line));
+                }
+                labels.push((label, value));
+                rest = &rest[end..];
+                if rest.starts_with(',') {
+                    rest = &rest[1..];
+                } else if !rest.starts_with('}') {
+                    return Err(format!("invalid label separator in {:?}", line));
+                }
+            }
+            rest = &rest[1..];
+        }
+        if !rest.starts_with(' ') {
+            return Err(format!("missing value in {:?}", line));
+        }
+        let value = rest[1..].parse().map_err(|_| format!("invalid value in {:?}", line))?;
+        Ok((name, labels, value))
+    }
+
+    /// Validate the given OpenMetrics text exposition: families are declared once before
+    /// their samples, which are contiguous and named by the type of the family, and histogram
+    /// buckets are cumulative, ordered and complete. Returns the number of samples.
+    fn validate(text: &str) -> Result<usize, String> {
+        if !text.ends_with("# EOF\n") || text.matches("# EOF").count() != 1 {
+            return Err("missing or misplaced # EOF".into());
+        }
+        let mut families: HashMap<&str, &str> = HashMap::new();
+        let mut current: Option<(&str, &str)> = None;
+        // Buckets of the current histogram by labels (without le): the last bound (none for
+        // +Inf) and count
+        let mut buckets: HashMap<Labels<'_>, (Option<f64>, f64)> = HashMap::new();
+        let mut samples = 0;
+        for line in text.lines() {
+            if line == "# EOF" {
+                break;
+            }
+            let mut parts = line.splitn(4, ' ');
+            if parts.next() == Some("#") {
+                let (keyword, name, arg) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+                match keyword {
+                    "TYPE" => {
+                        if !["counter", "gauge", "histogram"].contains(&arg) {
+                            return Err(format!("unknown type in {:?}", line));
+                        }
+                        if families.insert(name, arg).is_some() {
+                            return Err(format!("family {} declared twice", name));
+                        }
+                        current = Some((name, arg));
+                        buckets.clear();
+                    }
+                    "HELP" | "UNIT" if current.map(|(family, _)| family) != Some(name) => return Err(format!("metadata of undeclared family in {:?}", line)),
+                    "UNIT" if !name.ends_with(&format!("_{}", arg)) => return Err(format!("name doesn't end with unit in {:?}", line)),
+                    "HELP" | "UNIT" => (),
+                    _ => return Err(format!("unknown metadata in {:?}", line)),
+                }
+                continue;
+            }
+            let (name, mut labels, value) = parse_sample(line)?;
+            let (family, kind) = current.ok_or_else(|| format!("sample without family in {:?}", line))?;
+            let suffix = match name.get(..family.len()) {
+                Some(prefix) if prefix == family => &name[family.len()..],
+                _ => return Err(format!("sample {} outside of its family", name)),
+            };
+            match (kind, suffix) {
+                ("counter", "_total") | ("gauge", "") => (),
+                ("histogram", "_bucket") => {
+                    let le = labels.iter().position(|&(label, _)| label == "le").ok_or("bucket without le")?;
+                    let bound = match labels.remove(le).1.as_str() {
+                        "+Inf" => None,
+                        le => Some(le.parse::<f64>().map_err(|_| format!("invalid le in {:?}", line))?),
+                    };
+                    let ordered = match (buckets.get(&labels), bound) {
+                        (None, _) => true,
+                        (Some(&(None, _)), _) => false,
+                        (Some(&(Some(_), count)), None) => value >= count,
+                        (Some(&(Some(previous), count)), Some(bound)) => bound > previous && value >= count,
+                    };
+                    if !ordered {
+                        return Err(format!("buckets not cumulative in {:?}", line));
+                    }
+                    buckets.insert(labels, (bound, value));
+                }
+                ("histogram", "_count") => match buckets.get(&labels) {
+                    Some(&(None, count)) if count == value => (),
+                    _ => return Err(format!("count without matching +Inf bucket in {:?}", line)),
+                },
+                ("histogram", "_sum") => (),
+                _ => return Err(format!("sample {} doesn't fit the {} family {}", name, kind, family)),
+            }
+            samples += 1;
+        }
+        Ok(samples)
+    }
+
+    #[test]
+    fn validator() {
+        let valid = "# TYPE a counter\n# HELP a Some \"help\"\na_total{x=\"1\\\"\\n\"} 3\n# TYPE b_seconds histogram\n# UNIT b_seconds seconds\nb_seconds_bucket{le=\"0.5\"} 1\nb_seconds_bucket{le=\"+Inf\"} 2\nb_seconds_count 2\nb_seconds_sum 1.5\n# EOF\n";
+        assert_eq!(validate(valid), Ok(5));
+        let invalid = [
+            "a_total 1\n# EOF\n",
+            "# TYPE a counter\na 1\n# EOF\n",
+            "# TYPE a counter\na_total 1\n",
+            "# TYPE a counter\n# TYPE a counter\n# EOF\n",
+            "# TYPE a counter\na_total{x=\"1} 1\n# EOF\n",
+            "# TYPE a counter\na_total{x=\"1\",x=\"2\"} 1\n# EOF\n",
+            "# TYPE a counter\na_total one\n# EOF\n",
+            "# TYPE a gauge\n# UNIT a seconds\n# EOF\n",
+            "# TYPE a histogram\na_bucket{le=\"1\"} 2\na_bucket{le=\"+Inf\"} 1\n# EOF\n",
+            "# TYPE a histogram\na_bucket{le=\"1\"} 1\na_count 1\n# EOF\n",
+            "# TYPE a gauge\na 1\n# TYPE b gauge\nb 1\na 2\n# EOF\n",
+        ];
+        for text in &invalid {
+            assert!(validate(text).is_err(), "accepted {:?}", text);
+        }
+    }
+
+    #[test]
+    fn buckets() {
+        let histogram = LatencyHistogram::default();
+        let latencies = [0, 500, 1000, 1001, 2000, 3000, 1_000_000, 524_288_000, 524_288_001, 10_000_000_000];
+        for &nanos in &latencies {
+            histogram.record(Duration::from_nanos(nanos));
+        }
+        let (buckets, sum) = histogram.snapshot();
+        let mut expected = [0; BOUNDS + 1];
+        // Up to 1µs, up to 2µs, up to 4µs, 1ms is up to 1024µs, up to 2^19µs and beyond
+        expected[0] = 3;
+        expected[1] = 2;
+        expected[2] = 1;
+        expected[10] = 1;
+        expected[19] = 1;
+        expected[20] = 2;
+        assert_eq!((buckets, sum), (expected, latencies.iter().sum()));
+        // Latencies recorded by other threads are merged
+        let histogram = Arc::new(histogram);
+        let threads: Vec<_> = (0..4).map(|_| {
+            let histogram = histogram.clone();
+            thread::spawn(move || for _ in 0..100 { histogram.record(Duration::from_micros(3)); })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(histogram.snapshot().0[2], 401);
+    }
+
+    #[test]
+    fn exposition() {
+        let outstanding = OutstandingReplies::unordered(Some(Arc::new(Latencies::default())));
+        outstanding.count_request();
+        let latencies = outstanding.latencies().unwrap();
+        latencies.record(RequestClass::Metadata, Duration::from_micros(3));
+        latencies.record(RequestClass::Metadata, Duration::from_millis(1));
+        latencies.record(RequestClass::Data, Duration::from_secs(2));
+        let metrics = Metrics::new("my \"fs\"\n", Path::new("/mnt/a\\b"), outstanding.clone(), None, None, None);
+        let text = metrics.render_openmetrics();
+        let samples = validate(&text).unwrap();
+        assert_eq!(samples, 13 + 2 * (BOUNDS + 3));
+        assert!(text.len() <= capacity(&metrics.labels));
+        let labels = "fsname=\"my \\\"fs\\\"\\n\",mountpoint=\"/mnt/a\\\\b\"";
+        assert!(text.contains(&format!("fuse_requests_total{{{}}} 1\n", labels)));
+        assert!(text.contains(&format!("fuse_reply_latency_seconds_bucket{{{},class=\"metadata\",le=\"0.000004\"}} 1\n", labels)));
+        assert!(text.contains(&format!("fuse_reply_latency_seconds_bucket{{{},class=\"metadata\",le=\"0.001024\"}} 2\n", labels)));
+        assert!(text.contains(&format!("fuse_reply_latency_seconds_bucket{{{},class=\"data\",le=\"0.524288\"}} 0\n", labels)));
+        assert!(text.contains(&format!("fuse_reply_latency_seconds_count{{{},class=\"data\"}} 1\n", labels)));
+        assert!(text.contains(&format!("fuse_reply_latency_seconds_sum{{{},class=\"metadata\"}} 0.001003\n", labels)));
+        // Without latency histograms, there are only counters and gauges
+        let metrics = Metrics::new("", Path::new("/mnt"), Arc::new(OutstandingReplies::default()), None, None, None);
+        assert_eq!(validate(&metrics.render_openmetrics()), Ok(13));
+    }
+
+    #[test]
+    fn fsname_option() {
+        let options: Vec<&OsStr> = ["-o", "ro", "-o", "allow_other,fsname=hello,nonempty"].iter().map(OsStr::new).collect();
+        assert_eq!(fsname(&options), Some("hello".to_string()));
+        let options: Vec<&OsStr> = ["-o", "ro", "fsname=hello"].iter().map(OsStr::new).collect();
+        assert_eq!(fsname(&options), None);
+    }
+}
@@ -0,0 +1,436 @@
+//! Notifications to the kernel driver
+//!
+//! Besides replying to requests, a filesystem can send notifications to the kernel driver at
+//! any time, e.g. to invalidate cached data or entries after files were changed remotely. A
+//! notification is written to the FUSE device like a reply, but with a unique id of 0 and the
+//! notification code in the error field of the header. Unlike replies, notifications are
+//! answered: the kernel driver fails the write if it can't process a notification (e.g. with
+//! `ENOENT` if the inode or entry to invalidate isn't cached).
+//!
+//! Data can be stored in and retrieved from the kernel driver's page cache as well. Retrieved
+//! data arrives later as a notify reply request, which is dispatched to
+//! `Filesystem::retrieve_reply`.
+
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::{mpsc, Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+#[cfg(feature = "abi-7-15")]
+use std::sync::atomic::{AtomicU64, Ordering};
+use fuse_abi::{fuse_notify_code, fuse_out_header};
+use fuse_abi::{fuse_notify_inval_inode_out, fuse_notify_inval_entry_out};
+#[cfg(feature = "abi-7-15")]
+use fuse_abi::{fuse_notify_retrieve_out, fuse_notify_store_out};
+#[cfg(feature = "abi-7-18")]
+use fuse_abi::fuse_notify_delete_out;
+use libc::{ENAMETOOLONG, ENOENT, ENOTCONN};
+use log::warn;
+
+use crate::channel::ChannelSender;
+use crate::lookups::LookupTracker;
+use crate::reply::as_bytes;
+
+/// Longest name the kernel driver accepts in notifications (`FUSE_NAME_MAX`)
+const NAME_MAX: usize = 1024;
+
+/// Handle to send notifications to the kernel driver, obtained with `Session::notifier`.
+/// Clones can be used from any thread, also while the session loop is running (e.g. by
+/// giving one to the filesystem through `Session::filesystem` before running the session).
+/// Once the session is dropped, sending fails with `ENOTCONN`.
+///
+/// Invalidations must not be sent from within a filesystem method for the same inode or
+/// directory: the kernel driver may wait for the method to finish, which deadlocks.
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    sender: Arc<RwLock<Option<ChannelSender>>>,
+    /// Unique id of the next retrieve notification
+    #[cfg(feature = "abi-7-15")]
+    next_retrieve: Arc<AtomicU64>,
+    /// Inodes known to the kernel driver, if lookups are tracked
+    lookups: Option<Arc<LookupTracker>>,
+}
+
+impl Notifier {
+    /// Create a notifier that sends with the given channel sender
+    pub(crate) fn new(sender: ChannelSender) -> Notifier {
+        Notifier {
+            sender: Arc::new(RwLock::new(Some(sender))),
+            #[cfg(feature = "abi-7-15")]
+            next_retrieve: Arc::new(AtomicU64::new(1)),
+            lookups: None,
+        }
+    }
+
+    /// Check generations of inodes with the given tracker (see `SessionBuilder::track_lookups`)
+    pub(crate) fn track_lookups(&mut self, lookups: Arc<LookupTracker>) {
+        self.lookups = Some(lookups);
+    }
+
+    /// Stop sending notifications, before the channel gets closed. Waits for notifications
+    /// that are being sent.
+    pub(crate) fn close(&self) {
+        *self.sender.write().unwrap() = None;
+    }
+
+    /// Returns this notifier if the kernel driver still knows the given inode with the given
+    /// generation, to send a notification for the inode. If the inode was forgotten, or its
+    /// number was reused for another file with another generation, the notification would
+    /// affect the other file: returns `None` then, and counts a suppressed notification (see
+    /// `TrackingStats::suppressed_notifications`). Needs `SessionBuilder::track_lookups`,
+    /// otherwise it always returns this notifier.
+    ///
+    /// ```no_run
+    /// # fn refresh(notifier: &fuse::Notifier, ino: u64, generation: u64) -> std::io::Result<()> {
+    /// if let Some(notifier) = notifier.if_live(ino, generation) {
+    ///     notifier.inval_inode(ino, 0, 0)?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// The inode is checked right before the notification is sent, but not while it's being
+    /// sent. Filesystems should therefore not reuse an inode number while a notification for
+    /// its previous generation may be on its way.
+    pub fn if_live(&self, ino: u64, generation: u64) -> Option<&Notifier> {
+        match self.lookups {
+            Some(ref lookups) if !lookups.is_live(ino, generation) => None,
+            _ => Some(self),
+        }
+    }
+
+    /// Invalidate the cached attributes and data of the given inode. Cached data is
+    /// invalidated from `offset` on for `len` bytes (to the end of the file if `len` is 0 or
+    /// less). A negative `offset` only invalidates the attributes.
+    pub fn inval_inode(&self, ino: u64, offset: i64, len: i64) -> io::Result<()> {
+        let arg = fuse_notify_inval_inode_out { ino, off: offset, len };
+        as_bytes(&arg, |arg| self.send(fuse_notify_code::FUSE_NOTIFY_INVAL_INODE, &[arg[0]]))
+    }
+
+    /// Invalidate the cached entry with the given name in the given directory, so that the
+    /// kernel driver looks it up again the next time it's accessed (e.g. after the file was
+    /// renamed or removed behind the filesystem's back). The attributes of the directory are
+    /// invalidated as well. Fails with `ENOENT` if the directory isn't cached, i.e. there's
+    /// nothing to invalidate, and with `ENAMETOOLONG` if the name is longer than the kernel
+    /// driver allows.
+    pub fn inval_entry(&self, parent: u64, name: &OsStr) -> io::Result<()> {
+        check_name(name)?;
+        let arg = fuse_notify_inval_entry_out { parent, namelen: name.len() as u32, padding: 0 };
+        as_bytes(&arg, |arg| self.send(fuse_notify_code::FUSE_NOTIFY_INVAL_ENTRY, &[arg[0], name.as_bytes(), b"\0"]))
+    }
+
+    /// Tell the kernel driver that the entry with the given name in the given directory,
+    /// which refers to the inode `child`, was deleted. Like `inval_entry`, but if the entry is
+    /// cached, the dentry is deleted like on unlink (e.g. inotify watchers are notified). Fails
+    /// with `ENOENT` if the entry doesn't refer to `child`.
+    #[cfg(feature = "abi-7-18")]
+    pub fn delete(&self, parent: u64, child: u64, name: &OsStr) -> io::Result<()> {
+        check_name(name)?;
+        let arg = fuse_notify_delete_out { parent, child, namelen: name.len() as u32, padding: 0 };
+        as_bytes(&arg, |arg| self.send(fuse_notify_code::FUSE_NOTIFY_DELETE, &[arg[0], name.as_bytes(), b"\0"]))
+    }
+
+    /// Store the given data in the kernel driver's page cache for the given inode, starting at
+    /// offset. The file size is extended if the data ends beyond it. Fails with `ENOENT` if
+    /// the inode isn't cached.
+    #[cfg(feature = "abi-7-15")]
+    pub fn store(&self, ino: u64, offset: u64, data: &[u8]) -> io::Result<()> {
+        let arg = fuse_notify_store_out { nodeid: ino, offset, size: data.len() as u32, padding: 0 };
+        as_bytes(&arg, |arg| self.send(fuse_notify_code::FUSE_NOTIFY_STORE, &[arg[0], data]))
+    }
+
+    /// Ask the kernel driver for the cached data of the given inode, starting at offset, up to
+    /// size bytes. The data is passed to `Filesystem::retrieve_reply` together with the
+    /// returned unique id, which tells retrievals apart. Fails with `ENOENT` if the inode
+    /// isn't cached.
+    #[cfg(feature = "abi-7-15")]
+    pub fn retrieve(&self, ino: u64, offset: u64, size: u32) -> io::Result<u64> {
+        let notify_unique = self.next_retrieve.fetch_add(1, Ordering::Relaxed);
+        let arg = fuse_notify_retrieve_out { notify_unique, nodeid: ino, offset, size, padding: 0 };
+        as_bytes(&arg, |arg| self.send(fuse_notify_code::FUSE_NOTIFY_RETRIEVE, &[arg[0]]))?;
+        Ok(notify_unique)
+    }
+
+    /// Send a notification with the given code and payload
+    fn send(&self, code: fuse_notify_code, data: &[&[u8]]) -> io::Result<()> {
+        let len = data.iter().map(|data| data.len()).sum::<usize>();
+        let header = fuse_out_header {
+            len: (mem::size_of::<fuse_out_header>() + len) as u32,
+            error: code as i32,
+            unique: 0,
+        };
+        let sender = self.sender.read().unwrap();
+        let sender = sender.as_ref().ok_or_else(|| io::Error::from_raw_os_error(ENOTCONN))?;
+        as_bytes(&header, |header| {
+            let mut bytes = vec![header[0]];
+            bytes.extend_from_slice(data);
+            sender.send(&bytes)
+        })
+    }
+}
+
+/// Fail with `ENAMETOOLONG` if the kernel driver would refuse the given name. The length sent
+/// doesn't count the terminating NUL.
+fn check_name(name: &OsStr) -> io::Result<()> {
+    if name.len() > NAME_MAX {
+        return Err(io::Error::from_raw_os_error(ENAMETOOLONG));
+    }
+    Ok(())
+}
+
+/// Message to the thread that sends entry invalidations
+#[derive(Debug)]
+enum Invalidation {
+    /// Invalidate the given entries
+    Entries(Vec<(u64, OsString)>),
+    /// Notify once all earlier messages are handled
+    Flush(mpsc::Sender<()>),
+}
+
+/// Queue of entry invalidations to a thread that sends them (see
+/// `SessionBuilder::auto_invalidate_entries`). Writing an invalidation blocks until the kernel
+/// driver gets the lock of the directory, which another operation in the directory may hold
+/// while it waits for its reply. Sending invalidations right after replying could therefore
+/// block the session loop (or the reply writer) that has to send this very reply.
+#[derive(Debug)]
+pub(crate) struct EntryInvalidator {
+    queue: mpsc::Sender<Invalidation>,
+}
+
+impl EntryInvalidator {
+    /// Start a thread that sends queued entry invalidations with the given notifier. The
+    /// thread ends once the invalidator and all invalidations created with it are dropped.
+    pub(crate) fn start(notifier: Notifier) -> io::Result<EntryInvalidator> {
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new().name("fuse-invalidate".into()).spawn(move || invalidate_entries(notifier, rx))?;
+        Ok(EntryInvalidator { queue: tx })
+    }
+
+    /// Wait until the invalidations queued so far are sent, at most for the given time
+    pub(crate) fn flush(&self, timeout: Duration) {
+        let (tx, rx) = mpsc::channel();
+        if self.queue.send(Invalidation::Flush(tx)).is_ok() && rx.recv_timeout(timeout).is_err() {
+            warn!("Timed out sending entry invalidations");
+        }
+    }
+}
+
+/// Send the entry invalidations from the given queue. Entries that aren't cached (anymore)
+/// are skipped by the kernel driver with `ENOENT`.
+fn invalidate_entries(notifier: Notifier, queue: mpsc::Receiver<Invalidation>) {
+    for message in queue {
+        match message {
+            Invalidation::Entries(entries) => {
+                for (parent, name) in entries {
+                    match notifier.inval_entry(parent, &name) {
+                        Err(ref err) if err.raw_os_error() != Some(ENOENT) && err.raw_os_error() != Some(ENOTCONN) => {
+                            warn!("Failed to invalidate entry {:?} in directory {}: {}", name, parent, err);
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            Invalidation::Flush(done) => { let _ = done.send(()); }
+        }
+    }
+}
+
+/// Entries to invalidate once the reply to the operation that removed or renamed them was
+/// sent. Only entries in directories that the kernel driver knows can be cached, so others are
+/// left out.
+#[derive(Debug)]
+pub(crate) struct EntryInvalidation {
+    queue: mpsc::Sender<Invalidation>,
+    entries: Vec<(u64, OsString)>,
+}
+
+impl EntryInvalidation {
+    /// Create an invalidation of the given entries, if any of them can be cached
+    pub(crate) fn new(invalidator: &EntryInvalidator, lookups: &LookupTracker, entries: &[(u64, &OsStr)]) -> Option<EntryInvalidation> {
+        let mut cached: Vec<(u64, OsString)> = Vec::new();
+        for &(parent, name) in entries {
+            if lookups.knows(parent) && !cached.iter().any(|entry| entry.0 == parent && entry.1 == name) {
+                cached.push((parent, name.to_os_string()));
+            }
+        }
+        if cached.is_empty() {
+            return None;
+        }
+        Some(EntryInvalidation { queue: invalidator.queue.clone(), entries: cached })
+    }
+
+    /// Queue the invalidations to the thread that sends them
+    pub(crate) fn send(self) {
+        // The thread only ends once all queues are dropped
+        let _ = self.queue.send(Invalidation::Entries(self.entries));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixDatagram;
+    use std::path::Path;
+    use std::io;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+    use crate::channel::Channel;
+    use crate::lookups::LookupTracker;
+    use crate::reply::{Reply, ReplyEmpty};
+    use super::{EntryInvalidation, EntryInvalidator, Notifier};
+
+    /// Returns a notifier that sends over a socket, the channel and the other end of the socket
+    fn socket_notifier() -> (Notifier, Channel, UnixDatagram) {
+        let (notifier, ch, kernel, _) = filled_socket_notifier(false);
+        (notifier, ch, kernel)
+    }
+
+    /// Returns a notifier that sends over a socket like `socket_notifier`, and the number of
+    /// datagrams that were sent to fill the socket so that sending blocks, if requested
+    fn filled_socket_notifier(fill: bool) -> (Notifier, Channel, UnixDatagram, usize) {
+        let (kernel, fuse) = UnixDatagram::pair().unwrap();
+        let mut filled = 0;
+        if fill {
+            fuse.set_nonblocking(true).unwrap();
+            loop {
+                match fuse.send(&[0; 16]) {
+                    Ok(_) => filled += 1,
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(err) => panic!("{}", err),
+                }
+            }
+            fuse.set_nonblocking(false).unwrap();
+        }
+        let ch = Channel::from_fd(fuse.into_raw_fd(), Path::new("/mnt"));
+        (Notifier::new(ch.sender()), ch, kernel, filled)
+    }
+
+    fn receive(kernel: &UnixDatagram) -> Vec<u8> {
+        let mut buf = vec![0; 2048];
+        let len = kernel.recv(&mut buf).unwrap();
+        buf.truncate(len);
+        buf
+    }
+
+    #[test]
+    fn inval_inode() {
+        let (notifier, _ch, kernel) = socket_notifier();
+        notifier.clone().inval_inode(0x1122, 0x1000, -1).unwrap();
+        assert_eq!(receive(&kernel), [
+            0x28, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x22, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        ]);
+    }
+
+    #[test]
+    fn inval_entry() {
+        let (notifier, _ch, kernel) = socket_notifier();
+        notifier.inval_entry(1, OsStr::new("hello")).unwrap();
+        assert_eq!(receive(&kernel), [
+            0x26, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x00,
+        ]);
+        // Names the kernel driver would refuse aren't sent
+        let long = "x".repeat(1025);
+        assert_eq!(notifier.inval_entry(1, OsStr::new(&long)).unwrap_err().raw_os_error(), Some(libc::ENAMETOOLONG));
+        notifier.inval_entry(1, OsStr::new(&long[..1024])).unwrap();
+        assert_eq!(receive(&kernel)[24..28], 1024u32.to_ne_bytes());
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-18")]
+    fn delete() {
+        let (notifier, _ch, kernel) = socket_notifier();
+        notifier.delete(1, 0x33, OsStr::new("hello")).unwrap();
+        assert_eq!(receive(&kernel), [
+            0x2e, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x00,
+        ]);
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-15")]
+    fn store() {
+        let (notifier, _ch, kernel) = socket_notifier();
+        notifier.store(0x22, 0x1000, b"hello").unwrap();
+        assert_eq!(receive(&kernel), [
+            0x2d, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x68, 0x65, 0x6c, 0x6c, 0x6f,
+        ]);
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-15")]
+    fn retrieve() {
+        let (notifier, _ch, kernel) = socket_notifier();
+        assert_eq!(notifier.retrieve(0x22, 0x1000, 0x2000).unwrap(), 1);
+        assert_eq!(receive(&kernel), [
+            0x30, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]);
+        // Clones share the unique ids
+        assert_eq!(notifier.clone().retrieve(0x22, 0, 1).unwrap(), 2);
+    }
+
+    #[test]
+    fn send_to_other_threads() {
+        fn assert_send<T: Send + Sync + Clone>(_: &T) {}
+        let (notifier, _ch, kernel) = socket_notifier();
+        assert_send(&notifier);
+        let notifier = notifier.clone();
+        thread::spawn(move || notifier.inval_inode(2, -1, 0)).join().unwrap().unwrap();
+        assert_eq!(receive(&kernel)[16..24], 2u64.to_ne_bytes());
+    }
+
+    #[test]
+    fn invalidate_entries_after_reply() {
+        let (notifier, _ch, kernel, filled) = filled_socket_notifier(true);
+        let invalidator = EntryInvalidator::start(notifier).unwrap();
+        let lookups = LookupTracker::new(16);
+        // Entries in directories the kernel driver doesn't know aren't invalidated
+        assert!(EntryInvalidation::new(&invalidator, &lookups, &[(2, OsStr::new("a"))]).is_none());
+        let invalidation = EntryInvalidation::new(&invalidator, &lookups, &[(1, OsStr::new("a")), (1, OsStr::new("a"))]);
+        // The reply is sent while writing the notification blocks
+        let (tx, rx) = channel();
+        let reply: ReplyEmpty = Reply::new(2, tx);
+        reply.invalidating(invalidation).ok();
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        for _ in 0..filled {
+            assert_eq!(receive(&kernel).len(), 16);
+        }
+        kernel.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(receive(&kernel), [
+            0x22, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x61, 0x00,
+        ]);
+        // Duplicate entries are invalidated once
+        kernel.set_nonblocking(true).unwrap();
+        drop(invalidator);
+        thread::sleep(Duration::from_millis(50));
+        assert!(kernel.recv(&mut [0; 64]).is_err());
+    }
+
+    #[test]
+    fn closed() {
+        let (notifier, ch, kernel) = socket_notifier();
+        notifier.close();
+        drop(ch);
+        let err = notifier.inval_inode(2, 0, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTCONN));
+        // Errors of the write are returned as well
+        let (notifier, _ch, kernel2) = socket_notifier();
+        drop(kernel2);
+        assert!(notifier.inval_inode(2, 0, 0).is_err());
+        drop(kernel);
+    }
+}
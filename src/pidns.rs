@@ -0,0 +1,297 @@
+//! Pid namespace awareness
+//!
+//! The kernel driver translates the pid of a requesting process into the pid namespace of the
+//! process that mounted the filesystem (and sends 0 if the requester isn't visible there).
+//! Pids are only meaningful for /proc lookups if that namespace is the same as the one of the
+//! /proc this process sees. If they differ (e.g. a containerized filesystem serving host
+//! processes, or a host /proc bind-mounted into a container), a pid may refer to a different,
+//! unrelated process.
+
+use std::error;
+#[cfg(target_os = "linux")]
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::unix::ffi::OsStringExt;
+use std::path::Path;
+#[cfg(target_os = "linux")]
+use std::path::PathBuf;
+
+/// Relation between the pid namespace of requests and the pid namespace of /proc, determined
+/// once when mounting
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PidNamespace {
+    /// Request pids can be looked up in /proc
+    Same,
+    /// Request pids refer to a different pid namespace than /proc
+    Different,
+    /// Namespaces couldn't be inspected (e.g. /proc isn't mounted)
+    Unknown,
+}
+
+impl PidNamespace {
+    /// Inspect the namespaces using the given proc filesystem. `mount_ns` is the id of the pid
+    /// namespace this process was in when mounting (see `pid_namespace_id`).
+    pub fn inspect(proc_root: &Path, mount_ns: Option<u64>) -> PidNamespace {
+        let current_ns = pid_namespace_id(proc_root);
+        // /proc/self points to our pid as seen by the pid namespace /proc belongs to
+        let proc_pid = fs::read_link(proc_root.join("self")).ok()
+            .and_then(|link| link.to_str().and_then(|s| s.parse::<u32>().ok()));
+        match (mount_ns, current_ns, proc_pid) {
+            (Some(mount_ns), Some(current_ns), Some(proc_pid)) => {
+                if mount_ns == current_ns && proc_pid == std::process::id() {
+                    PidNamespace::Same
+                } else {
+                    PidNamespace::Different
+                }
+            }
+            _ => PidNamespace::Unknown,
+        }
+    }
+
+    /// Returns the pid information of a request pid
+    pub fn pid_info(self, pid: u32) -> PidInfo {
+        match (self, pid) {
+            // Requester isn't visible in the namespace of the connection
+            (_, 0) => PidInfo::Unknown,
+            (PidNamespace::Same, pid) => PidInfo::SameNamespace(pid),
+            (PidNamespace::Different, raw_pid) => PidInfo::DifferentNamespace { raw_pid },
+            (PidNamespace::Unknown, _) => PidInfo::Unknown,
+        }
+    }
+}
+
+/// Returns the id (inode number) of the pid namespace of this process, parsed from the
+/// `ns/pid` link (e.g. `pid:[4026531836]`) below the given proc filesystem
+pub fn pid_namespace_id(proc_root: &Path) -> Option<u64> {
+    let link = fs::read_link(proc_root.join("self/ns/pid")).ok()?;
+    let link = link.to_str()?;
+    if !link.starts_with("pid:[") || !link.ends_with(']') { return None; }
+    link[5..link.len() - 1].parse().ok()
+}
+
+/// Pid of the process that caused a request, as far as it can be used for /proc lookups
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PidInfo {
+    /// The pid is in the pid namespace of this process' /proc
+    SameNamespace(u32),
+    /// The pid is in a different pid namespace and must not be used for /proc lookups
+    DifferentNamespace {
+        /// Pid as sent by the kernel driver
+        raw_pid: u32,
+    },
+    /// The pid namespace couldn't be determined, or the requesting process isn't visible in
+    /// the pid namespace of the connection
+    Unknown,
+}
+
+/// Error of helpers that look up the requesting process in /proc
+#[derive(Debug)]
+pub enum PidError {
+    /// The request pid is in a different pid namespace
+    ForeignNamespace {
+        /// Pid as sent by the kernel driver
+        raw_pid: u32,
+    },
+    /// The pid namespace of the request pid is unknown
+    UnknownNamespace,
+    /// Reading from /proc failed (e.g. the process exited already)
+    Io(io::Error),
+}
+
+impl fmt::Display for PidError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PidError::ForeignNamespace { raw_pid } => write!(f, "Pid {} is in a foreign pid namespace", raw_pid),
+            PidError::UnknownNamespace => write!(f, "Pid namespace of request is unknown"),
+            PidError::Io(err) => write!(f, "Failed to inspect requesting process: {}", err),
+        }
+    }
+}
+
+impl error::Error for PidError {}
+
+impl From<io::Error> for PidError {
+    fn from(err: io::Error) -> PidError {
+        PidError::Io(err)
+    }
+}
+
+/// Returns the pid of the given process if it can be looked up in /proc
+fn proc_pid(info: PidInfo) -> Result<u32, PidError> {
+    match info {
+        PidInfo::SameNamespace(pid) => Ok(pid),
+        PidInfo::DifferentNamespace { raw_pid } => Err(PidError::ForeignNamespace { raw_pid }),
+        PidInfo::Unknown => Err(PidError::UnknownNamespace),
+    }
+}
+
+/// Returns the supplementary groups of the given process
+pub fn groups(proc_root: &Path, info: PidInfo) -> Result<Vec<u32>, PidError> {
+    let pid = proc_pid(info)?;
+    let status = fs::read_to_string(proc_root.join(pid.to_string()).join("status"))?;
+    parse_groups(&status).ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData).into())
+}
+
+/// Parse the `Groups:` line of /proc/<pid>/status
+fn parse_groups(status: &str) -> Option<Vec<u32>> {
+    let line = status.lines().find(|line| line.starts_with("Groups:"))?;
+    line["Groups:".len()..].split_whitespace().map(|gid| gid.parse().ok()).collect()
+}
+
+/// Membership of a process in a cgroup hierarchy (a line of /proc/<pid>/cgroup)
+#[cfg(target_os = "linux")]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Cgroup {
+    /// Id of the hierarchy (0 for the unified hierarchy of cgroup v2)
+    pub hierarchy: u32,
+    /// Controllers bound to the hierarchy (empty for the unified hierarchy)
+    pub controllers: Vec<String>,
+    /// Path of the cgroup relative to the root of the hierarchy
+    pub path: PathBuf,
+}
+
+/// Command name and cgroups of the process that caused a request
+#[cfg(target_os = "linux")]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProcessInfo {
+    /// Pid of the process in the pid namespace of /proc
+    pub pid: u32,
+    /// Command name (`comm`), i.e. the first 15 bytes of the executable's name unless the
+    /// process changed it
+    pub comm: OsString,
+    /// Cgroups of the process, one per hierarchy (only one with cgroup v2)
+    pub cgroups: Vec<Cgroup>,
+}
+
+/// Returns the command name and cgroups of the given process
+#[cfg(target_os = "linux")]
+pub fn process_info(proc_root: &Path, info: PidInfo) -> Result<ProcessInfo, PidError> {
+    let pid = proc_pid(info)?;
+    let dir = proc_root.join(pid.to_string());
+    let mut comm = fs::read(dir.join("comm"))?;
+    if comm.last() == Some(&b'\n') {
+        comm.pop();
+    }
+    let cgroup = fs::read_to_string(dir.join("cgroup"))?;
+    let cgroups = parse_cgroups(&cgroup).ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+    Ok(ProcessInfo { pid, comm: OsString::from_vec(comm), cgroups })
+}
+
+/// Parse /proc/<pid>/cgroup (lines of `hierarchy-id:controllers:path`)
+#[cfg(target_os = "linux")]
+fn parse_cgroups(cgroup: &str) -> Option<Vec<Cgroup>> {
+    cgroup.lines().map(|line| {
+        let mut fields = line.splitn(3, ':');
+        let hierarchy = fields.next()?.parse().ok()?;
+        let controllers = fields.next()?.split(',').filter(|name| !name.is_empty()).map(String::from).collect();
+        let path = PathBuf::from(fields.next()?);
+        Some(Cgroup { hierarchy, controllers, path })
+    }).collect()
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+    use std::process;
+    use super::{groups, parse_groups, pid_namespace_id, PidError, PidInfo, PidNamespace};
+
+    /// Create a fake proc filesystem with the given ns/pid and self links
+    fn fixture(name: &str, ns_link: &str, self_link: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("fuse-rs-pidns-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let own = root.join(self_link);
+        fs::create_dir_all(own.join("ns")).unwrap();
+        symlink(ns_link, own.join("ns/pid")).unwrap();
+        fs::write(own.join("status"), "Name:\ttest\nUid:\t1000\t1000\t1000\t1000\nGroups:\t4 24 1000 \n").unwrap();
+        fs::write(own.join("comm"), "test\n").unwrap();
+        fs::write(own.join("cgroup"), "0::/user.slice/app.scope\n").unwrap();
+        symlink(self_link, root.join("self")).unwrap();
+        root
+    }
+
+    #[test]
+    fn same_namespace() {
+        let root = fixture("same", "pid:[4026531836]", &process::id().to_string());
+        assert_eq!(pid_namespace_id(&root), Some(4026531836));
+        let ns = PidNamespace::inspect(&root, Some(4026531836));
+        assert_eq!(ns, PidNamespace::Same);
+        assert_eq!(ns.pid_info(process::id()), PidInfo::SameNamespace(process::id()));
+        assert_eq!(groups(&root, ns.pid_info(process::id())).unwrap(), vec![4, 24, 1000]);
+        #[cfg(target_os = "linux")]
+        {
+            let info = super::process_info(&root, ns.pid_info(process::id())).unwrap();
+            assert_eq!((info.pid, info.comm.to_str()), (process::id(), Some("test")));
+            assert_eq!(info.cgroups, [super::Cgroup { hierarchy: 0, controllers: vec![], path: PathBuf::from("/user.slice/app.scope") }]);
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn different_namespace() {
+        // /proc of another pid namespace, where we have a different pid
+        let root = fixture("different", "pid:[4026531836]", "1");
+        let ns = PidNamespace::inspect(&root, Some(4026531836));
+        assert_eq!(ns, PidNamespace::Different);
+        assert_eq!(ns.pid_info(42), PidInfo::DifferentNamespace { raw_pid: 42 });
+        match groups(&root, ns.pid_info(42)) {
+            Err(PidError::ForeignNamespace { raw_pid: 42 }) => (),
+            res => panic!("Unexpected result {:?}", res),
+        }
+        // Namespace changed since mounting
+        let ns = PidNamespace::inspect(&root, Some(4026532000));
+        assert_eq!(ns, PidNamespace::Different);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn unknown_namespace() {
+        let root = fixture("unknown", "garbage", &process::id().to_string());
+        assert_eq!(pid_namespace_id(&root), None);
+        let ns = PidNamespace::inspect(&root, Some(4026531836));
+        assert_eq!(ns, PidNamespace::Unknown);
+        assert_eq!(ns.pid_info(42), PidInfo::Unknown);
+        assert_eq!(PidNamespace::Same.pid_info(0), PidInfo::Unknown);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn groups_parsing() {
+        assert_eq!(parse_groups("Groups:\t\n"), Some(vec![]));
+        assert_eq!(parse_groups("Name:\tx\nGroups:\t1 2\n"), Some(vec![1, 2]));
+        assert_eq!(parse_groups("Name:\tx\n"), None);
+        assert_eq!(parse_groups("Groups:\tx\n"), None);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn cgroups_parsing() {
+        let cgroups = super::parse_cgroups("12:cpu,cpuacct:/system.slice\n1:name=systemd:/init.scope\n0::/\n").unwrap();
+        assert_eq!(cgroups[0].controllers, ["cpu", "cpuacct"]);
+        assert_eq!((cgroups[1].hierarchy, cgroups[1].path.to_str()), (1, Some("/init.scope")));
+        assert_eq!((cgroups[2].hierarchy, cgroups[2].controllers.len(), cgroups[2].path.to_str()), (0, 0, Some("/")));
+        // Paths may contain colons
+        assert_eq!(super::parse_cgroups("0::/a:b\n").unwrap()[0].path, PathBuf::from("/a:b"));
+        assert_eq!(super::parse_cgroups("x::/\n"), None);
+        assert_eq!(super::parse_cgroups("0:/\n"), None);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn own_process_info() {
+        let proc_root = std::path::Path::new("/proc");
+        let ns = PidNamespace::inspect(proc_root, pid_namespace_id(proc_root));
+        let info = super::process_info(proc_root, ns.pid_info(process::id())).unwrap();
+        // The command name of the main thread is the start of the executable's name
+        let exe = std::env::current_exe().unwrap();
+        let name = exe.file_name().unwrap().to_str().unwrap();
+        assert_eq!(info.pid, process::id());
+        assert_eq!(info.comm.to_str(), Some(&name[..name.len().min(15)]));
+        assert!(!info.cgroups.is_empty());
+    }
+}
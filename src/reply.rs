@@ -7,27 +7,61 @@
 //! error() exactly once).
 
 use std::{mem, ptr, slice};
-use std::convert::AsRef;
-use std::ffi::OsStr;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::convert::{AsRef, TryFrom};
+use std::ffi::{OsStr, OsString};
 use std::fmt;
+use std::io::{self, Write};
 use std::marker::PhantomData;
 use std::os::unix::ffi::OsStrExt;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime, SystemTimeError, UNIX_EPOCH};
 use fuse_abi::{fuse_attr, fuse_kstatfs, fuse_file_lock, fuse_entry_out, fuse_attr_out};
 use fuse_abi::{fuse_open_out, fuse_write_out, fuse_statfs_out, fuse_lk_out, fuse_bmap_out};
 use fuse_abi::fuse_getxattr_out;
 #[cfg(target_os = "macos")]
 use fuse_abi::fuse_getxtimes_out;
+#[cfg(feature = "abi-7-24")]
+use fuse_abi::fuse_lseek_out;
+#[cfg(feature = "abi-7-11")]
+use fuse_abi::fuse_ioctl_out;
+#[cfg(feature = "abi-7-16")]
+use fuse_abi::fuse_ioctl_iovec;
+#[cfg(feature = "abi-7-21")]
+use fuse_abi::fuse_direntplus;
 use fuse_abi::{fuse_out_header, fuse_dirent};
-use libc::{c_int, S_IFIFO, S_IFCHR, S_IFBLK, S_IFDIR, S_IFREG, S_IFLNK, S_IFSOCK, EIO};
-use log::warn;
-
-use crate::{FileType, FileAttr};
+use fuse_abi::consts;
+use fuse_abi::FUSE_ROOT_ID;
+use libc::{c_int, S_IFIFO, S_IFCHR, S_IFBLK, S_IFDIR, S_IFREG, S_IFLNK, S_IFSOCK, E2BIG, EIO, ERANGE};
+use log::{error, warn};
+
+use crate::access::{AccessCache, AccessKey};
+use crate::attrdiff::AttrTracker;
+use crate::errno::ToErrno;
+use crate::killpriv::KillPriv;
+use crate::lookups::LookupTracker;
+#[cfg(feature = "abi-7-12")]
+use crate::notify::EntryInvalidation;
+use crate::session::{OutstandingReplies, DEFAULT_TTL, MAX_WRITE_SIZE};
+use crate::xattrcache::XAttrCache;
+use crate::{FileType, FileAttr, StatFs};
+
+/// Function to call once a reply was sent (see `ReplySender::send_confirmed`)
+pub(crate) type OnSent = Box<dyn FnOnce() + Send>;
 
 /// Generic reply callback to send data
 pub trait ReplySender: Send + 'static {
     /// Send data.
     fn send(&self, data: &[&[u8]]);
+
+    /// Send data, and call the given function once the kernel driver took it. The function
+    /// isn't called if sending fails. The default calls it right after `send`, for senders
+    /// that can't tell.
+    fn send_confirmed(&self, data: &[&[u8]], sent: Box<dyn FnOnce() + Send>) {
+        self.send(data);
+        sent();
+    }
 }
 
 impl fmt::Debug for Box<dyn ReplySender> {
@@ -43,7 +77,7 @@ pub trait Reply {
 }
 
 /// Serialize an arbitrary type to bytes (memory copy, useful for fuse_*_out types)
-fn as_bytes<T, U, F: FnOnce(&[&[u8]]) -> U>(data: &T, f: F) -> U {
+pub(crate) fn as_bytes<T, U, F: FnOnce(&[&[u8]]) -> U>(data: &T, f: F) -> U {
     let len = mem::size_of::<T>();
     match len {
         0 => f(&[]),
@@ -55,6 +89,19 @@ fn as_bytes<T, U, F: FnOnce(&[&[u8]]) -> U>(data: &T, f: F) -> U {
     }
 }
 
+/// Returns the given data of a reply, cut off at the size that was requested (if any).
+/// Replying more data than requested is a bug of the filesystem, so it panics in debug builds.
+fn cut_off<'d>(data: &'d [u8], max_size: Option<usize>, op: &str) -> &'d [u8] {
+    match max_size {
+        Some(size) if data.len() > size => {
+            debug_assert!(false, "{} replied {} bytes, but only {} were requested", op, data.len(), size);
+            warn!("{} replied {} bytes, but only {} were requested, truncating", op, data.len(), size);
+            &data[..size]
+        }
+        _ => data,
+    }
+}
+
 fn time_from_system_time(system_time: &SystemTime) -> Result<(u64, u32), SystemTimeError> {
     let duration = system_time.duration_since(UNIX_EPOCH)?;
     Ok((duration.as_secs(), duration.subsec_nanos()))
@@ -103,6 +150,10 @@ fn fuse_attr_from_attr(attr: &FileAttr) -> fuse_attr {
         gid: attr.gid,
         rdev: attr.rdev,
         flags: attr.flags,
+        #[cfg(feature = "abi-7-9")]
+        blksize: 0,                                 // use the filesystem's block size
+        #[cfg(feature = "abi-7-9")]
+        padding: 0,
     }
 }
 
@@ -129,6 +180,25 @@ fn fuse_attr_from_attr(attr: &FileAttr) -> fuse_attr {
         uid: attr.uid,
         gid: attr.gid,
         rdev: attr.rdev,
+        #[cfg(feature = "abi-7-9")]
+        blksize: 0,                                 // use the filesystem's block size
+        #[cfg(all(feature = "abi-7-9", not(feature = "abi-7-32")))]
+        padding: 0,
+        #[cfg(feature = "abi-7-32")]
+        flags: 0,
+    }
+}
+
+/// Returns the dirent type (`DT_*`) for the file type bits of a mode, or `DT_UNKNOWN` (0) if
+/// they aren't a known file type
+fn dirent_type_from_mode(mode: u32) -> u32 {
+    let kinds = [
+        FileType::NamedPipe, FileType::CharDevice, FileType::BlockDevice, FileType::Directory,
+        FileType::RegularFile, FileType::Symlink, FileType::Socket,
+    ];
+    match kinds.iter().map(|&kind| mode_from_kind_and_perm(kind, 0)).find(|&kind| mode & 0o170_000 == kind) {
+        Some(kind) => kind >> 12,
+        None => 0,
     }
 }
 
@@ -157,6 +227,12 @@ impl<T> ReplyRaw<T> {
     /// Reply to a request with the given error code and data. Must be called
     /// only once (the `ok` and `error` methods ensure this by consuming `self`)
     fn send(&mut self, err: c_int, bytes: &[&[u8]]) {
+        self.send_confirmed(err, bytes, None);
+    }
+
+    /// Reply to a request like `send`, and call the given function once the kernel driver
+    /// took the reply
+    fn send_confirmed(&mut self, err: c_int, bytes: &[&[u8]], sent: Option<OnSent>) {
         assert!(self.sender.is_some());
         let len = bytes.iter().fold(0, |l, b| l + b.len());
         let header = fuse_out_header {
@@ -168,7 +244,10 @@ impl<T> ReplyRaw<T> {
             let sender = self.sender.take().unwrap();
             let mut sendbytes = headerbytes.to_vec();
             sendbytes.extend(bytes);
-            sender.send(&sendbytes);
+            match sent {
+                Some(sent) => sender.send_confirmed(&sendbytes, sent),
+                None => sender.send(&sendbytes),
+            }
         });
     }
 
@@ -179,6 +258,14 @@ impl<T> ReplyRaw<T> {
         })
     }
 
+    /// Reply to a request with the given type, and call the given function once the kernel
+    /// driver took the reply
+    fn ok_confirmed(mut self, data: &T, sent: Option<OnSent>) {
+        as_bytes(data, |bytes| {
+            self.send_confirmed(0, bytes, sent);
+        })
+    }
+
     /// Reply to a request with the given error code
     pub fn error(mut self, err: c_int) {
         self.send(err, &[]);
@@ -194,28 +281,138 @@ impl<T> Drop for ReplyRaw<T> {
     }
 }
 
+/// Reply sender that passes replies to a function, which sends them with the original sender
+struct InterceptSender<F> {
+    sender: ConfirmingSender,
+    f: F,
+}
+
+impl<F: Fn(&[&[u8]], &dyn ReplySender) + Send + 'static> ReplySender for InterceptSender<F> {
+    fn send(&self, data: &[&[u8]]) {
+        (self.f)(data, &self.sender);
+    }
+
+    fn send_confirmed(&self, data: &[&[u8]], sent: Box<dyn FnOnce() + Send>) {
+        self.sender.sent.set(Some(sent));
+        (self.f)(data, &self.sender);
+    }
+}
+
+/// Reply sender that passes on the confirmation of an intercepted reply, unless the function
+/// that intercepted it sends an error instead
+struct ConfirmingSender {
+    sender: Box<dyn ReplySender>,
+    sent: Cell<Option<OnSent>>,
+}
+
+impl ReplySender for ConfirmingSender {
+    fn send(&self, data: &[&[u8]]) {
+        match self.sent.take() {
+            Some(sent) if data[0][4..8] == [0; 4] => self.sender.send_confirmed(data, sent),
+            _ => self.sender.send(data),
+        }
+    }
+}
+
+/// Replies that can be intercepted before they're sent (used for fault injection)
+pub(crate) trait Intercept: Sized {
+    /// Reply to a request with the given error code
+    fn error(self, err: c_int);
+
+    /// Pass the reply (header and data) to the given function instead of sending it. The
+    /// function is responsible for sending it (possibly modified) with the given sender.
+    fn intercept<F: Fn(&[&[u8]], &dyn ReplySender) + Send + 'static>(self, f: F) -> Self;
+}
+
+macro_rules! intercept {
+    ($($reply:ident),*) => {$(
+        impl Intercept for $reply {
+            fn error(self, err: c_int) {
+                $reply::error(self, err);
+            }
+
+            fn intercept<F: Fn(&[&[u8]], &dyn ReplySender) + Send + 'static>(mut self, f: F) -> $reply {
+                let sender = ConfirmingSender { sender: self.reply.sender.take().unwrap(), sent: Cell::new(None) };
+                self.reply.sender = Some(Box::new(InterceptSender { sender, f }));
+                self
+            }
+        }
+    )*};
+}
+
+intercept!(ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr, ReplyOpen, ReplyWrite, ReplyStatfs);
+intercept!(ReplyCreate, ReplyLock, ReplyBmap, ReplyDirectory, ReplyXattr);
+#[cfg(target_os = "macos")]
+intercept!(ReplyXTimes);
+#[cfg(feature = "abi-7-21")]
+intercept!(ReplyDirectoryPlus);
+#[cfg(feature = "abi-7-24")]
+intercept!(ReplyLseek);
+#[cfg(feature = "abi-7-11")]
+intercept!(ReplyIoctl);
+
 ///
 /// Empty reply
 ///
 #[derive(Debug)]
 pub struct ReplyEmpty {
     reply: ReplyRaw<()>,
+    access: Option<(Arc<AccessCache>, AccessKey)>,
+    #[cfg(feature = "abi-7-12")]
+    invalidation: Option<EntryInvalidation>,
 }
 
 impl Reply for ReplyEmpty {
     fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyEmpty {
-        ReplyEmpty { reply: Reply::new(unique, sender) }
+        ReplyEmpty {
+            reply: Reply::new(unique, sender),
+            access: None,
+            #[cfg(feature = "abi-7-12")]
+            invalidation: None,
+        }
     }
 }
 
 impl ReplyEmpty {
+    /// Remember the result of the given access check in the given cache
+    pub(crate) fn cached(mut self, cache: Option<&Arc<AccessCache>>, key: AccessKey) -> ReplyEmpty {
+        self.access = cache.map(|cache| (cache.clone(), key));
+        self
+    }
+
+    /// Invalidate the given entries once the reply was sent successfully
+    #[cfg(feature = "abi-7-12")]
+    pub(crate) fn invalidating(mut self, invalidation: Option<EntryInvalidation>) -> ReplyEmpty {
+        self.invalidation = invalidation;
+        self
+    }
+
+    /// Don't invalidate the entries that the replied operation removed or renamed (see
+    /// `SessionBuilder::auto_invalidate_entries`), e.g. if the filesystem invalidates them
+    /// itself
+    #[cfg(feature = "abi-7-12")]
+    pub fn without_invalidation(mut self) -> ReplyEmpty {
+        self.invalidation = None;
+        self
+    }
+
     /// Reply to a request with nothing
     pub fn ok(mut self) {
-        self.reply.send(0, &[]);
+        if let Some((cache, key)) = self.access.take() {
+            cache.insert(key, 0);
+        }
+        #[cfg(feature = "abi-7-12")]
+        let sent = self.invalidation.take().map(|invalidation| Box::new(move || invalidation.send()) as OnSent);
+        #[cfg(not(feature = "abi-7-12"))]
+        let sent = None;
+        self.reply.send_confirmed(0, &[], sent);
     }
 
     /// Reply to a request with the given error code
     pub fn error(self, err: c_int) {
+        if let Some((cache, key)) = self.access {
+            cache.insert(key, err);
+        }
         self.reply.error(err);
     }
 }
@@ -226,17 +423,26 @@ impl ReplyEmpty {
 #[derive(Debug)]
 pub struct ReplyData {
     reply: ReplyRaw<()>,
+    max_size: Option<usize>,
 }
 
 impl Reply for ReplyData {
     fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyData {
-        ReplyData { reply: Reply::new(unique, sender) }
+        ReplyData { reply: Reply::new(unique, sender), max_size: None }
     }
 }
 
 impl ReplyData {
-    /// Reply to a request with the given data
+    /// Limit the replied data to the given size, the size requested by a read
+    pub(crate) fn limited(mut self, size: u32) -> ReplyData {
+        self.max_size = Some(size as usize);
+        self
+    }
+
+    /// Reply to a request with the given data. Data of a read must not be larger than the
+    /// requested size, excess data is cut off (and panics in debug builds).
     pub fn data(mut self, data: &[u8]) {
+        let data = cut_off(data, self.max_size, "Read");
         self.reply.send(0, &[data]);
     }
 
@@ -246,24 +452,83 @@ impl ReplyData {
     }
 }
 
+/// Checks that attributes of the root inode are those of a directory. The kernel driver
+/// refuses to mount (or fails later on) if the root is anything else.
+fn check_root_kind(attr: &FileAttr) {
+    if attr.ino == FUSE_ROOT_ID && attr.kind != FileType::Directory {
+        debug_assert!(false, "Attributes of the root inode must be of kind Directory, but are {:?}", attr.kind);
+        warn!("Attributes of the root inode must be of kind Directory, but are {:?}", attr.kind);
+    }
+}
+
+/// Returns a function that counts a lookup of the given inode with the given tracker, to be
+/// called once the entry reply was sent. If sending fails (e.g. because the request was
+/// interrupted), the kernel driver didn't take the lookup.
+fn lookup_counter(lookups: Option<Arc<LookupTracker>>, ino: u64, generation: u64) -> Option<OnSent> {
+    lookups.map(|lookups| Box::new(move || lookups.entry(ino, generation)) as OnSent)
+}
+
 ///
 /// Entry reply
 ///
 #[derive(Debug)]
 pub struct ReplyEntry {
     reply: ReplyRaw<fuse_entry_out>,
+    attrs: Option<Arc<AttrTracker>>,
+    privs: Option<Arc<KillPriv>>,
+    lookups: Option<Arc<LookupTracker>>,
+    default_ttl: Duration,
 }
 
 impl Reply for ReplyEntry {
     fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyEntry {
-        ReplyEntry { reply: Reply::new(unique, sender) }
+        ReplyEntry { reply: Reply::new(unique, sender), attrs: None, privs: None, lookups: None, default_ttl: DEFAULT_TTL }
     }
 }
 
 impl ReplyEntry {
+    /// Check replied attributes with the given tracker
+    pub(crate) fn tracked(mut self, attrs: Option<&Arc<AttrTracker>>) -> ReplyEntry {
+        self.attrs = attrs.cloned();
+        self
+    }
+
+    /// Remember owners of replied attributes for removing file capabilities
+    pub(crate) fn owners(mut self, privs: Option<&Arc<KillPriv>>) -> ReplyEntry {
+        self.privs = privs.cloned();
+        self
+    }
+
+    /// Count lookups of replied entries with the given tracker
+    pub(crate) fn lookups(mut self, lookups: Option<&Arc<LookupTracker>>) -> ReplyEntry {
+        self.lookups = lookups.cloned();
+        self
+    }
+
+    /// Use the given TTL for replies without an explicit TTL
+    pub(crate) fn default_ttl(mut self, ttl: Duration) -> ReplyEntry {
+        self.default_ttl = ttl;
+        self
+    }
+
+    /// Reply to a request with the given entry, cached for the session's default TTL (see
+    /// `SessionBuilder::default_ttl`)
+    pub fn entry_default(self, attr: &FileAttr, generation: u64) {
+        let ttl = self.default_ttl;
+        self.entry(&ttl, attr, generation);
+    }
+
     /// Reply to a request with the given entry
     pub fn entry(self, ttl: &Duration, attr: &FileAttr, generation: u64) {
-        self.reply.ok(&fuse_entry_out {
+        check_root_kind(attr);
+        if let Some(attrs) = self.attrs {
+            attrs.check_entry(attr, ttl, generation);
+        }
+        if let Some(privs) = self.privs {
+            privs.replied(attr);
+        }
+        let sent = lookup_counter(self.lookups, attr.ino, generation);
+        self.reply.ok_confirmed(&fuse_entry_out {
             nodeid: attr.ino,
             generation: generation,
             entry_valid: ttl.as_secs(),
@@ -271,7 +536,41 @@ impl ReplyEntry {
             entry_valid_nsec: ttl.subsec_nanos(),
             attr_valid_nsec: ttl.subsec_nanos(),
             attr: fuse_attr_from_attr(attr),
-        });
+        }, sent);
+    }
+
+    /// Reply to a lookup with the given directory as a stub for a separately managed mount
+    /// (e.g. by autofs). The entry and attributes aren't cached by the kernel driver, so every
+    /// access looks the name up again and sees the mount once it's there. With ABI 7.32, the
+    /// directory is also flagged as submount root (`FUSE_ATTR_SUBMOUNT`), which makes kernel
+    /// drivers that support submounts (see `ProtocolCapabilities::submounts`) mount it as a
+    /// separate filesystem (with its own device number); others ignore the flag. FUSE can't
+    /// prevent descending into the directory, so the filesystem should make it look empty.
+    /// Replies with `EIO` if the attributes aren't of a directory.
+    pub fn submount_stub(self, attr: &FileAttr, generation: u64) {
+        if attr.kind != FileType::Directory {
+            error!("Submount stub {} is not a directory ({:?})", attr.ino, attr.kind);
+            self.reply.error(EIO);
+            return;
+        }
+        let ttl = Duration::from_secs(0);
+        if let Some(attrs) = self.attrs {
+            attrs.check_entry(attr, &ttl, generation);
+        }
+        #[allow(unused_mut)]
+        let mut fuse_attr = fuse_attr_from_attr(attr);
+        #[cfg(all(feature = "abi-7-32", not(target_os = "macos")))]
+        { fuse_attr.flags |= consts::FUSE_ATTR_SUBMOUNT; }
+        let sent = lookup_counter(self.lookups, attr.ino, generation);
+        self.reply.ok_confirmed(&fuse_entry_out {
+            nodeid: attr.ino,
+            generation,
+            entry_valid: 0,
+            attr_valid: 0,
+            entry_valid_nsec: 0,
+            attr_valid_nsec: 0,
+            attr: fuse_attr,
+        }, sent);
     }
 
     /// Reply to a request with the given error code
@@ -286,17 +585,52 @@ impl ReplyEntry {
 #[derive(Debug)]
 pub struct ReplyAttr {
     reply: ReplyRaw<fuse_attr_out>,
+    attrs: Option<Arc<AttrTracker>>,
+    privs: Option<Arc<KillPriv>>,
+    default_ttl: Duration,
 }
 
 impl Reply for ReplyAttr {
     fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyAttr {
-        ReplyAttr { reply: Reply::new(unique, sender) }
+        ReplyAttr { reply: Reply::new(unique, sender), attrs: None, privs: None, default_ttl: DEFAULT_TTL }
     }
 }
 
 impl ReplyAttr {
+    /// Check replied attributes with the given tracker
+    pub(crate) fn tracked(mut self, attrs: Option<&Arc<AttrTracker>>) -> ReplyAttr {
+        self.attrs = attrs.cloned();
+        self
+    }
+
+    /// Remember owners of replied attributes for removing file capabilities
+    pub(crate) fn owners(mut self, privs: Option<&Arc<KillPriv>>) -> ReplyAttr {
+        self.privs = privs.cloned();
+        self
+    }
+
+    /// Use the given TTL for replies without an explicit TTL
+    pub(crate) fn default_ttl(mut self, ttl: Duration) -> ReplyAttr {
+        self.default_ttl = ttl;
+        self
+    }
+
+    /// Reply to a request with the given attribute, cached for the session's default TTL (see
+    /// `SessionBuilder::default_ttl`)
+    pub fn attr_default(self, attr: &FileAttr) {
+        let ttl = self.default_ttl;
+        self.attr(&ttl, attr);
+    }
+
     /// Reply to a request with the given attribute
     pub fn attr(self, ttl: &Duration, attr: &FileAttr) {
+        check_root_kind(attr);
+        if let Some(attrs) = self.attrs {
+            attrs.check(attr, ttl);
+        }
+        if let Some(privs) = self.privs {
+            privs.replied(attr);
+        }
         self.reply.ok(&fuse_attr_out {
             attr_valid: ttl.as_secs(),
             attr_valid_nsec: ttl.subsec_nanos(),
@@ -348,6 +682,74 @@ impl ReplyXTimes {
     }
 }
 
+/// Flags to reply to an open, opendir or create request with. Flags that are not supported by
+/// the ABI version the kernel driver negotiated are ignored by the kernel driver.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    /// Create empty open flags
+    pub fn new() -> OpenFlags {
+        OpenFlags(0)
+    }
+
+    /// Bypass the page cache for this open file
+    pub fn direct_io(self) -> OpenFlags {
+        OpenFlags(self.0 | consts::FOPEN_DIRECT_IO)
+    }
+
+    /// Don't invalidate the data cache on open
+    pub fn keep_cache(self) -> OpenFlags {
+        OpenFlags(self.0 | consts::FOPEN_KEEP_CACHE)
+    }
+
+    /// The file is not seekable. Seeking fails with ESPIPE and reads and writes are issued
+    /// without a meaningful offset.
+    #[cfg(feature = "abi-7-10")]
+    pub fn nonseekable(self) -> OpenFlags {
+        OpenFlags(self.0 | consts::FOPEN_NONSEEKABLE)
+    }
+
+    /// Allow the kernel driver to cache this directory's entries (opendir only)
+    #[cfg(feature = "abi-7-28")]
+    pub fn cache_dir(self) -> OpenFlags {
+        OpenFlags(self.0 | consts::FOPEN_CACHE_DIR)
+    }
+
+    /// The file is stream-like and has no file position at all, like a pipe or socket. The
+    /// kernel driver doesn't track an offset for it, so reads and writes always get offset 0,
+    /// seeking fails with ESPIPE and concurrent reads and writes on the same file descriptor
+    /// aren't serialized (so a blocking read doesn't hold up a write). Needs ABI 7.31; kernel
+    /// drivers that negotiated a lower version ignore this flag.
+    #[cfg(feature = "abi-7-31")]
+    pub fn stream(self) -> OpenFlags {
+        OpenFlags(self.0 | consts::FOPEN_STREAM)
+    }
+
+    /// Purge attribute cache on open
+    #[cfg(target_os = "macos")]
+    pub fn purge_attr(self) -> OpenFlags {
+        OpenFlags(self.0 | consts::FOPEN_PURGE_ATTR)
+    }
+
+    /// Purge unified buffer cache on open
+    #[cfg(target_os = "macos")]
+    pub fn purge_ubc(self) -> OpenFlags {
+        OpenFlags(self.0 | consts::FOPEN_PURGE_UBC)
+    }
+
+    /// Returns the raw flag bits
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+}
+
+impl From<OpenFlags> for u32 {
+    fn from(flags: OpenFlags) -> u32 {
+        flags.bits()
+    }
+}
+
 ///
 /// Open Reply
 ///
@@ -363,7 +765,7 @@ impl Reply for ReplyOpen {
 }
 
 impl ReplyOpen {
-    /// Reply to a request with the given open result
+    /// Reply to a request with the given open result. See `OpenFlags` for building flags.
     pub fn opened(self, fh: u64, flags: u32) {
         self.reply.ok(&fuse_open_out {
             fh: fh,
@@ -422,7 +824,27 @@ impl Reply for ReplyStatfs {
 }
 
 impl ReplyStatfs {
-    /// Reply to a request with the given open result
+    /// Reply to a request with the given filesystem statistics (see `Filesystem::statfs` on
+    /// why there's no filesystem id)
+    pub fn stats(self, stats: &StatFs) {
+        self.reply.ok(&fuse_statfs_out {
+            st: fuse_kstatfs {
+                blocks: stats.blocks(),
+                bfree: stats.bfree(),
+                bavail: stats.bavail(),
+                files: stats.files(),
+                ffree: stats.ffree(),
+                bsize: stats.bsize(),
+                namelen: stats.namelen(),
+                frsize: stats.frsize(),
+                padding: 0,
+                spare: [0; 6],
+            },
+        });
+    }
+
+    /// Reply to a request with the given filesystem statistics, unchecked
+    #[deprecated(since = "0.4.0", note = "use `ReplyStatfs::stats` with a `StatFs` instead, which names and checks the values")]
     pub fn statfs(self, blocks: u64, bfree: u64, bavail: u64, files: u64, ffree: u64, bsize: u32, namelen: u32, frsize: u32) {
         self.reply.ok(&fuse_statfs_out {
             st: fuse_kstatfs {
@@ -452,18 +874,80 @@ impl ReplyStatfs {
 #[derive(Debug)]
 pub struct ReplyCreate {
     reply: ReplyRaw<(fuse_entry_out, fuse_open_out)>,
+    attrs: Option<Arc<AttrTracker>>,
+    privs: Option<Arc<KillPriv>>,
+    lookups: Option<Arc<LookupTracker>>,
+    default_ttl: Duration,
+    outstanding: Option<Arc<OutstandingReplies>>,
 }
 
 impl Reply for ReplyCreate {
     fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyCreate {
-        ReplyCreate { reply: Reply::new(unique, sender) }
+        ReplyCreate { reply: Reply::new(unique, sender), attrs: None, privs: None, lookups: None, default_ttl: DEFAULT_TTL, outstanding: None }
     }
 }
 
 impl ReplyCreate {
-    /// Reply to a request with the given entry
+    /// Check replied attributes with the given tracker
+    pub(crate) fn tracked(mut self, attrs: Option<&Arc<AttrTracker>>) -> ReplyCreate {
+        self.attrs = attrs.cloned();
+        self
+    }
+
+    /// Remember owners of replied attributes for removing file capabilities
+    pub(crate) fn owners(mut self, privs: Option<&Arc<KillPriv>>) -> ReplyCreate {
+        self.privs = privs.cloned();
+        self
+    }
+
+    /// Count lookups of replied entries with the given tracker
+    pub(crate) fn lookups(mut self, lookups: Option<&Arc<LookupTracker>>) -> ReplyCreate {
+        self.lookups = lookups.cloned();
+        self
+    }
+
+    /// Use the given TTL for replies without an explicit TTL
+    pub(crate) fn default_ttl(mut self, ttl: Duration) -> ReplyCreate {
+        self.default_ttl = ttl;
+        self
+    }
+
+    /// Count replies that opened an existing file in the run summary of the given requests
+    pub(crate) fn counted(mut self, outstanding: &Arc<OutstandingReplies>) -> ReplyCreate {
+        self.outstanding = Some(outstanding.clone());
+        self
+    }
+
+    /// Reply to a request with an existing file that was opened instead of created, e.g. if a
+    /// concurrent create of the same name came first. Only valid if the request's flags don't
+    /// contain `O_EXCL`, reply with `EEXIST` otherwise. The reply is the same as with `created`
+    /// (the kernel driver replaces a cached negative entry for the name with the replied entry
+    /// either way), but it's counted separately (see `RunSummary::opened_existing`).
+    pub fn opened_existing(mut self, ttl: &Duration, attr: &FileAttr, generation: u64, fh: u64, flags: u32) {
+        if let Some(outstanding) = self.outstanding.take() {
+            outstanding.count_opened_existing();
+        }
+        self.created(ttl, attr, generation, fh, flags);
+    }
+
+    /// Reply to a request with the given entry, cached for the session's default TTL (see
+    /// `SessionBuilder::default_ttl`). See `OpenFlags` for building flags.
+    pub fn created_default(self, attr: &FileAttr, generation: u64, fh: u64, flags: u32) {
+        let ttl = self.default_ttl;
+        self.created(&ttl, attr, generation, fh, flags);
+    }
+
+    /// Reply to a request with the given entry. See `OpenFlags` for building flags.
     pub fn created(self, ttl: &Duration, attr: &FileAttr, generation: u64, fh: u64, flags: u32) {
-        self.reply.ok(&(fuse_entry_out {
+        check_root_kind(attr);
+        if let Some(attrs) = self.attrs {
+            attrs.check_entry(attr, ttl, generation);
+        }
+        if let Some(privs) = self.privs {
+            privs.replied(attr);
+        }
+        let sent = lookup_counter(self.lookups, attr.ino, generation);
+        self.reply.ok_confirmed(&(fuse_entry_out {
             nodeid: attr.ino,
             generation: generation,
             entry_valid: ttl.as_secs(),
@@ -475,7 +959,7 @@ impl ReplyCreate {
             fh: fh,
             open_flags: flags,
             padding: 0,
-        }));
+        }), sent);
     }
 
     /// Reply to a request with the given error code
@@ -545,13 +1029,131 @@ impl ReplyBmap {
     }
 }
 
+///
+/// Lseek Reply
+///
+#[cfg(feature = "abi-7-24")]
+#[derive(Debug)]
+pub struct ReplyLseek {
+    reply: ReplyRaw<fuse_lseek_out>,
+}
+
+#[cfg(feature = "abi-7-24")]
+impl Reply for ReplyLseek {
+    fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyLseek {
+        ReplyLseek { reply: Reply::new(unique, sender) }
+    }
+}
+
+#[cfg(feature = "abi-7-24")]
+impl ReplyLseek {
+    /// Reply to a request with the resulting file offset
+    pub fn offset(self, offset: i64) {
+        self.reply.ok(&fuse_lseek_out {
+            offset: offset as u64,
+        });
+    }
+
+    /// Reply to a request with the given error code
+    pub fn error(self, err: c_int) {
+        self.reply.error(err);
+    }
+}
+
+///
+/// Ioctl reply
+///
+/// An ioctl is replied with its result (the return value of ioctl(2) for the caller) and the
+/// data that the kernel driver copies to the caller's buffer, which must not be larger than
+/// the requested output size. Unrestricted ioctls (CUSE only) can instead be retried with
+/// iovecs that tell the kernel driver which memory of the caller to copy in and out. It then
+/// sends the ioctl again with the input data of the given iovecs.
+///
+#[cfg(feature = "abi-7-11")]
+#[derive(Debug)]
+pub struct ReplyIoctl {
+    reply: ReplyRaw<fuse_ioctl_out>,
+    max_size: Option<usize>,
+}
+
+#[cfg(feature = "abi-7-11")]
+impl Reply for ReplyIoctl {
+    fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyIoctl {
+        ReplyIoctl { reply: Reply::new(unique, sender), max_size: None }
+    }
+}
+
+#[cfg(feature = "abi-7-11")]
+impl ReplyIoctl {
+    /// Limit the replied data to the given size, the output size requested by an ioctl
+    pub(crate) fn limited(mut self, size: u32) -> ReplyIoctl {
+        self.max_size = Some(size as usize);
+        self
+    }
+
+    /// Reply to a request with the given result and output data. Output data must not be
+    /// larger than the requested size, excess data is cut off (and panics in debug builds).
+    pub fn ioctl(mut self, result: i32, data: &[u8]) {
+        let data = cut_off(data, self.max_size, "Ioctl");
+        let out = fuse_ioctl_out { result, flags: 0, in_iovs: 0, out_iovs: 0 };
+        as_bytes(&out, |bytes| {
+            let mut bytes = bytes.to_vec();
+            bytes.push(data);
+            self.reply.send(0, &bytes);
+        });
+    }
+
+    /// Reply to an unrestricted ioctl with the memory of the caller to copy in and out, as
+    /// (address, length) pairs in the caller's address space (usually derived from the ioctl
+    /// argument). The kernel driver sends the ioctl again with the input data, and expects the
+    /// output data in the reply. It fails the ioctl with `EIO` if the ioctl isn't unrestricted
+    /// or if there are more than `FUSE_IOCTL_MAX_IOV` (256) iovecs in total.
+    #[cfg(feature = "abi-7-16")]
+    pub fn retry(mut self, in_iovs: &[(u64, u64)], out_iovs: &[(u64, u64)]) {
+        let out = fuse_ioctl_out {
+            result: 0,
+            flags: consts::FUSE_IOCTL_RETRY,
+            in_iovs: in_iovs.len() as u32,
+            out_iovs: out_iovs.len() as u32,
+        };
+        let mut iovs = Vec::with_capacity((in_iovs.len() + out_iovs.len()) * mem::size_of::<fuse_ioctl_iovec>());
+        for &(base, len) in in_iovs.iter().chain(out_iovs) {
+            as_bytes(&fuse_ioctl_iovec { base, len }, |bytes| {
+                for b in bytes {
+                    iovs.extend_from_slice(b);
+                }
+            });
+        }
+        as_bytes(&out, |bytes| {
+            let mut bytes = bytes.to_vec();
+            bytes.push(&iovs);
+            self.reply.send(0, &bytes);
+        });
+    }
+
+    /// Reply to a request with the given error code
+    pub fn error(self, err: c_int) {
+        self.reply.error(err);
+    }
+}
+
 ///
 /// Directory reply
 ///
+/// The size requested by the kernel only covers the directory entries, the `fuse_out_header`
+/// that precedes them is not part of it. A single entry takes up at most
+/// `size_of::<fuse_dirent>()` plus the length of its name (padded to 64 bit). In practice the
+/// kernel requests at least a page, which is large enough for any entry with a name of up to
+/// NAME_MAX (255) bytes. If the requested size is too small to fit even a single entry, replying
+/// with an empty buffer would signal the end of the directory to the kernel, so `ok()` replies
+/// with `ERANGE` instead.
+///
 #[derive(Debug)]
 pub struct ReplyDirectory {
     reply: ReplyRaw<()>,
     data: Vec<u8>,
+    size: usize,
+    overflow: bool,
 }
 
 impl ReplyDirectory {
@@ -560,25 +1162,50 @@ impl ReplyDirectory {
         ReplyDirectory {
             reply: Reply::new(unique, sender),
             data: Vec::with_capacity(size),
+            size,
+            overflow: false,
         }
     }
 
     /// Add an entry to the directory reply buffer. Returns true if the buffer is full.
     /// A transparent offset value can be provided for each entry. The kernel uses these
-    /// value to request the next entries in further readdir calls
+    /// value to request the next entries in further readdir calls.
+    ///
+    /// The name must be a file name, not a path: names that contain `/` or NUL are invalid.
+    /// Adding one panics in debug builds, and skips the entry with a warning otherwise.
     pub fn add<T: AsRef<OsStr>>(&mut self, ino: u64, offset: i64, kind: FileType, name: T) -> bool {
-        let name = name.as_ref().as_bytes();
+        self.add_entry(ino, offset, mode_from_kind_and_perm(kind, 0) >> 12, name.as_ref())
+    }
+
+    /// Add an entry like `add`, but with the file type taken from the given raw mode (e.g.
+    /// `st_mode` of a backing file). Entries with an unknown file type in the mode are added
+    /// with type `DT_UNKNOWN`, which makes the kernel look them up to get their type.
+    pub fn add_with_mode<T: AsRef<OsStr>>(&mut self, ino: u64, offset: i64, mode: u32, name: T) -> bool {
+        self.add_entry(ino, offset, dirent_type_from_mode(mode), name.as_ref())
+    }
+
+    /// Add an entry with the given dirent type to the buffer. Returns true if the buffer is full.
+    fn add_entry(&mut self, ino: u64, offset: i64, typ: u32, name: &OsStr) -> bool {
+        if !valid_entry_name(name) {
+            return false;
+        }
+        let name = name.as_bytes();
         let entlen = mem::size_of::<fuse_dirent>() + name.len();
         let entsize = (entlen + mem::size_of::<u64>() - 1) & !(mem::size_of::<u64>() - 1); // 64bit align
         let padlen = entsize - entlen;
-        if self.data.len() + entsize > self.data.capacity() { return true; }
+        if self.data.len() + entsize > self.size {
+            // Remember if not even the first entry fits, so that `ok()` doesn't send an
+            // empty buffer which the kernel would take as end of directory
+            if self.data.is_empty() { self.overflow = true; }
+            return true;
+        }
         unsafe {
             let p = self.data.as_mut_ptr().offset(self.data.len() as isize);
             let pdirent: *mut fuse_dirent = mem::transmute(p);
             (*pdirent).ino = ino;
             (*pdirent).off = offset as u64;
             (*pdirent).namelen = name.len() as u32;
-            (*pdirent).typ = mode_from_kind_and_perm(kind, 0) >> 12;
+            (*pdirent).typ = typ;
             let p = p.offset(mem::size_of_val(&*pdirent) as isize);
             ptr::copy_nonoverlapping(name.as_ptr(), p, name.len());
             let p = p.offset(name.len() as isize);
@@ -591,6 +1218,11 @@ impl ReplyDirectory {
 
     /// Reply to a request with the filled directory buffer
     pub fn ok(mut self) {
+        if self.overflow && self.data.is_empty() {
+            warn!("Directory entry doesn't fit into requested size of {} bytes, replying with ERANGE", self.size);
+            self.reply.error(ERANGE);
+            return;
+        }
         self.reply.send(0, &[&self.data]);
     }
 
@@ -600,22 +1232,253 @@ impl ReplyDirectory {
     }
 }
 
+/// Tells whether the given name can be added to a directory reply. Names that contain `/` or
+/// NUL are invalid, which panics in debug builds and is logged otherwise.
+fn valid_entry_name(name: &OsStr) -> bool {
+    if name.as_bytes().iter().any(|&byte| byte == b'/' || byte == 0) {
+        if cfg!(debug_assertions) {
+            panic!("Invalid directory entry name {:?}, names must not contain '/' or NUL", name);
+        }
+        warn!("Skipping directory entry with invalid name {:?}, names must not contain '/' or NUL", name);
+        return false;
+    }
+    true
+}
+
+///
+/// Directory reply with attributes (readdirplus)
+///
+/// Like `ReplyDirectory`, but every entry also carries the entry's attributes like a reply to
+/// a lookup, which saves the kernel a lookup per entry (e.g. for `ls -l`). An entry takes up
+/// `size_of::<fuse_direntplus>()` plus the length of its name (padded to 64 bit).
+///
+#[cfg(feature = "abi-7-21")]
+#[derive(Debug)]
+pub struct ReplyDirectoryPlus {
+    reply: ReplyRaw<()>,
+    data: Vec<u8>,
+    size: usize,
+    overflow: bool,
+    lookups: Option<Arc<LookupTracker>>,
+    /// Inode numbers and generations of added entries that count as lookups
+    entries: Vec<(u64, u64)>,
+}
+
+#[cfg(feature = "abi-7-21")]
+impl ReplyDirectoryPlus {
+    /// Creates a new ReplyDirectoryPlus with a specified buffer size.
+    pub fn new<S: ReplySender>(unique: u64, sender: S, size: usize) -> ReplyDirectoryPlus {
+        ReplyDirectoryPlus {
+            reply: Reply::new(unique, sender),
+            data: Vec::with_capacity(size),
+            size,
+            overflow: false,
+            lookups: None,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Count lookups of added entries with the given tracker
+    pub(crate) fn lookups(mut self, lookups: Option<&Arc<LookupTracker>>) -> ReplyDirectoryPlus {
+        self.lookups = lookups.cloned();
+        self
+    }
+
+    /// Add an entry with the given attributes to the directory reply buffer. Returns true if
+    /// the buffer is full. Offsets work like with `ReplyDirectory::add`, and the name must be
+    /// a file name as well. Like a reply to a lookup, every added entry increases the lookup
+    /// count of the inode `ino`, unless `ino` is 0: such entries are only listed (with the
+    /// inode number of the given attributes), and the kernel doesn't cache them. The TTL,
+    /// attributes and generation are the same as for `ReplyEntry::entry`.
+    pub fn add<T: AsRef<OsStr>>(&mut self, ino: u64, offset: i64, name: T, ttl: &Duration, attr: &FileAttr, generation: u64) -> bool {
+        let name = name.as_ref();
+        if !valid_entry_name(name) {
+            return false;
+        }
+        let name = name.as_bytes();
+        let entlen = mem::size_of::<fuse_direntplus>() + name.len();
+        let entsize = (entlen + mem::size_of::<u64>() - 1) & !(mem::size_of::<u64>() - 1); // 64bit align
+        if self.data.len() + entsize > self.size {
+            if self.data.is_empty() { self.overflow = true; }
+            return true;
+        }
+        let (entry_valid, entry_valid_nsec) = if ino == 0 { (0, 0) } else { (ttl.as_secs(), ttl.subsec_nanos()) };
+        let entry = fuse_direntplus {
+            entry_out: fuse_entry_out {
+                nodeid: ino,
+                generation: if ino == 0 { 0 } else { generation },
+                entry_valid,
+                attr_valid: entry_valid,
+                entry_valid_nsec,
+                attr_valid_nsec: entry_valid_nsec,
+                attr: fuse_attr_from_attr(attr),
+            },
+            dirent: fuse_dirent {
+                ino: attr.ino,
+                off: offset as u64,
+                namelen: name.len() as u32,
+                typ: mode_from_kind_and_perm(attr.kind, 0) >> 12,
+            },
+        };
+        as_bytes(&entry, |bytes| self.data.extend_from_slice(bytes[0]));
+        self.data.extend_from_slice(name);
+        self.data.resize(self.data.len() + entsize - entlen, 0);
+        // The kernel driver doesn't count lookups of "." and ".."
+        if self.lookups.is_some() && ino != 0 && name != b"." && name != b".." {
+            self.entries.push((ino, generation));
+        }
+        false
+    }
+
+    /// Reply to a request with the filled directory buffer
+    pub fn ok(mut self) {
+        if self.overflow && self.data.is_empty() {
+            warn!("Directory entry doesn't fit into requested size of {} bytes, replying with ERANGE", self.size);
+            self.reply.error(ERANGE);
+            return;
+        }
+        let entries = self.entries;
+        let sent = self.lookups.map(|lookups| Box::new(move || {
+            for (ino, generation) in entries {
+                lookups.entry(ino, generation);
+            }
+        }) as OnSent);
+        self.reply.send_confirmed(0, &[&self.data], sent);
+    }
+
+    /// Reply to a request with the given error code
+    pub fn error(self, err: c_int) {
+        self.reply.error(err);
+    }
+}
+
+/// Function that produces an xattr value by writing it to the given writer
+type XAttrValueFn<'a> = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + 'a>;
+
+enum XAttrValueInner<'a> {
+    Slice(&'a [u8]),
+    Lazy(usize, XAttrValueFn<'a>),
+}
+
+/// Value of an extended attribute to reply with. The value can be given as a slice or it can
+/// be provided lazily, in which case it's only produced if it's actually sent to the kernel
+/// (i.e. not for size probes or if it doesn't fit).
+pub struct XAttrValue<'a> {
+    inner: XAttrValueInner<'a>,
+}
+
+impl<'a> XAttrValue<'a> {
+    /// Create a value from the given bytes
+    pub fn from_slice(data: &'a [u8]) -> XAttrValue<'a> {
+        XAttrValue { inner: XAttrValueInner::Slice(data) }
+    }
+
+    /// Create a value that is produced by the given function when needed. The size of the
+    /// value needs to be known up front. The function gets a writer to write the value to.
+    pub fn from_fn<F: FnOnce(&mut dyn Write) -> io::Result<()> + 'a>(size: usize, f: F) -> XAttrValue<'a> {
+        XAttrValue { inner: XAttrValueInner::Lazy(size, Box::new(f)) }
+    }
+
+    /// Returns the size of the value
+    pub fn len(&self) -> usize {
+        match self.inner {
+            XAttrValueInner::Slice(data) => data.len(),
+            XAttrValueInner::Lazy(size, _) => size,
+        }
+    }
+
+    /// Returns true if the value is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'a> From<&'a [u8]> for XAttrValue<'a> {
+    fn from(data: &'a [u8]) -> XAttrValue<'a> {
+        XAttrValue::from_slice(data)
+    }
+}
+
+impl<'a> fmt::Debug for XAttrValue<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.inner {
+            XAttrValueInner::Slice(data) => write!(f, "XAttrValue({:?})", data),
+            XAttrValueInner::Lazy(size, _) => write!(f, "XAttrValue(<{} bytes>)", size),
+        }
+    }
+}
+
+/// Complete list of extended attributes of an inode together with their values, to reply to
+/// listxattr with (see `ReplyXattr::list`). If the session caches extended attributes (see
+/// `SessionBuilder::cache_xattrs`), it replies to following getxattr requests for the inode
+/// from the list, otherwise only the names are used.
+#[derive(Debug, Default)]
+pub struct XAttrList {
+    names: Vec<u8>,
+    values: HashMap<OsString, Vec<u8>>,
+}
+
+impl XAttrList {
+    /// Create a new empty list
+    pub fn new() -> XAttrList {
+        XAttrList::default()
+    }
+
+    /// Add an extended attribute with the given name and value to the list
+    pub fn add<N: AsRef<OsStr>>(&mut self, name: N, value: &[u8]) {
+        let name = name.as_ref();
+        if self.values.insert(name.to_os_string(), value.to_vec()).is_none() {
+            self.names.extend_from_slice(name.as_bytes());
+            self.names.push(0);
+        }
+    }
+
+    /// Returns the number of extended attributes in the list
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Returns true if the list is empty
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
 ///
 /// Xattr reply
 ///
 #[derive(Debug)]
 pub struct ReplyXattr {
     reply: ReplyRaw<fuse_getxattr_out>,
+    xattrs: Option<(Arc<XAttrCache>, u64)>,
 }
 
 impl Reply for ReplyXattr {
     fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyXattr {
-        ReplyXattr { reply: Reply::new(unique, sender) }
+        ReplyXattr { reply: Reply::new(unique, sender), xattrs: None }
     }
 }
 
 impl ReplyXattr {
-    /// Reply to a request with the size of the xattr.
+    /// Remember the values of a listxattr reply for the given inode in the given cache
+    pub(crate) fn cached(mut self, cache: Option<&Arc<XAttrCache>>, ino: u64) -> ReplyXattr {
+        self.xattrs = cache.map(|cache| (cache.clone(), ino));
+        self
+    }
+
+    /// Reply to a listxattr request with the names of the given list, given the size that was
+    /// requested by the kernel (like `respond`). The values are remembered for following
+    /// getxattr requests if the session caches extended attributes, even if the kernel only
+    /// asked for the size.
+    pub fn list(mut self, requested_size: u32, list: XAttrList) {
+        if let Some((cache, ino)) = self.xattrs.take() {
+            cache.insert(ino, list.values);
+        }
+        self.respond(requested_size, &list.names);
+    }
+
+    /// Reply to a request with the size of the xattr. Only valid if the kernel asked for the
+    /// size (requested size of 0), see `respond`.
     pub fn size(self, size: u32) {
         self.reply.ok(&fuse_getxattr_out {
             size: size,
@@ -623,11 +1486,52 @@ impl ReplyXattr {
         });
     }
 
-    /// Reply to a request with the data in the xattr.
+    /// Reply to a request with the data in the xattr. Only valid if the kernel asked for the
+    /// data (requested size larger than 0) and it fits, see `respond`.
     pub fn data(mut self, data: &[u8]) {
         self.reply.send(0, &[data]);
     }
 
+    /// Reply to a request for an xattr value, given the size that was requested by the
+    /// kernel. If the requested size is 0, only the size of the value is sent. If the value
+    /// doesn't fit into the requested size, `ERANGE` is sent. Lazily provided values are
+    /// only produced if they're actually sent.
+    pub fn value(mut self, size: u32, value: XAttrValue<'_>) {
+        let len = value.len();
+        if size == 0 {
+            // Size probe, no need to produce the value
+            match u32::try_from(len) {
+                Ok(len) => self.size(len),
+                Err(_) => self.error(E2BIG),
+            }
+        } else if len > size as usize {
+            self.error(ERANGE);
+        } else if len > MAX_WRITE_SIZE {
+            self.error(E2BIG);
+        } else {
+            match value.inner {
+                XAttrValueInner::Slice(data) => self.reply.send(0, &[data]),
+                XAttrValueInner::Lazy(_, f) => {
+                    let mut data = Vec::with_capacity(len);
+                    match f(&mut data) {
+                        // The provider may produce more than it announced
+                        Ok(()) if data.len() > size as usize => self.error(ERANGE),
+                        Ok(()) => self.reply.send(0, &[&data]),
+                        Err(err) => self.error(err.to_errno()),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reply to a request for the given xattr value, given the size that was requested by
+    /// the kernel: with the size of the value if the kernel only asked for the size (requested
+    /// size of 0), with `ERANGE` if the value doesn't fit into the requested size, and with
+    /// the value otherwise. Use `value` to provide large values lazily.
+    pub fn respond(self, requested_size: u32, value: &[u8]) {
+        self.value(requested_size, XAttrValue::from_slice(value));
+    }
+
     /// Reply to a request with the given error code.
     pub fn error(self, err: c_int) {
         self.reply.error(err);
@@ -642,10 +1546,14 @@ mod test {
     use super::as_bytes;
     use super::{Reply, ReplyRaw, ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr, ReplyOpen};
     use super::{ReplyWrite, ReplyStatfs, ReplyCreate, ReplyLock, ReplyBmap, ReplyDirectory};
-    use super::ReplyXattr;
+    use super::{ReplyXattr, XAttrValue, XAttrList, OpenFlags};
     #[cfg(target_os = "macos")]
     use super::ReplyXTimes;
-    use crate::{FileType, FileAttr};
+    use crate::{FileType, FileAttr, StatFs};
+    #[cfg(feature = "abi-7-21")]
+    use std::mem;
+    #[cfg(feature = "abi-7-21")]
+    use fuse_abi::fuse_direntplus;
 
     #[allow(dead_code)]
     #[repr(C)]
@@ -741,36 +1649,65 @@ mod test {
         reply.data(&[0xde, 0xad, 0xbe, 0xef]);
     }
 
+    /// Sender that passes replies on as a whole
+    struct CollectSender(Sender<Vec<u8>>);
+
+    impl super::ReplySender for CollectSender {
+        fn send(&self, data: &[&[u8]]) {
+            self.0.send(data.concat()).unwrap()
+        }
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "Read replied 5 bytes, but only 4 were requested"))]
+    fn reply_data_limited() {
+        let (tx, rx) = channel();
+        let reply: ReplyData = Reply::new(0xdeadbeef, CollectSender(tx.clone()));
+        reply.limited(4).data(&[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(rx.recv().unwrap()[16..], [0xde, 0xad, 0xbe, 0xef]);
+        // More data than requested is cut off
+        let reply: ReplyData = Reply::new(0xdeadbeef, CollectSender(tx));
+        reply.limited(4).data(&[0xde, 0xad, 0xbe, 0xef, 0x12]);
+        assert_eq!(rx.recv().unwrap(), [
+            0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00,
+            0xde, 0xad, 0xbe, 0xef,
+        ]);
+    }
+
     #[test]
     fn reply_entry() {
-        let sender = AssertSender {
-            expected: if cfg!(target_os = "macos") {
-                vec![
-                    vec![0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
-                    vec![0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x21, 0x43, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,  0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
-                         0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,  0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00,
-                         0x88, 0x00, 0x00, 0x00, 0x99, 0x00, 0x00, 0x00],
-                ]
-            } else {
-                vec![
-                    vec![0x88, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
-                    vec![0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x21, 0x43, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,  0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
-                         0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00,  0x55, 0x00, 0x00, 0x00, 0x66, 0x00, 0x00, 0x00,
-                         0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00],
-                ]
-            }
+        let mut expected = if cfg!(target_os = "macos") {
+            vec![
+                vec![0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x21, 0x43, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,  0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                     0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,  0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00,
+                     0x88, 0x00, 0x00, 0x00, 0x99, 0x00, 0x00, 0x00],
+            ]
+        } else {
+            vec![
+                vec![0x88, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x21, 0x43, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,  0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                     0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00,  0x55, 0x00, 0x00, 0x00, 0x66, 0x00, 0x00, 0x00,
+                     0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00],
+            ]
         };
+        if cfg!(feature = "abi-7-9") {
+            // fuse_attr gained blksize and padding at its end
+            expected[0][0] += 8;
+            expected[1].extend_from_slice(&[0; 8]);
+        }
+        let sender = AssertSender { expected };
         let reply: ReplyEntry = Reply::new(0xdeadbeef, sender);
         let time = UNIX_EPOCH + Duration::new(0x1234, 0x5678);
         let ttl = Duration::new(0x8765, 0x4321);
@@ -780,31 +1717,79 @@ mod test {
     }
 
     #[test]
-    fn reply_attr() {
+    #[cfg(not(target_os = "macos"))]
+    fn reply_entry_submount_stub() {
+        let mut expected = vec![
+            vec![0x88, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+            vec![0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                 0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                 0x78, 0x56, 0x00, 0x00, 0xed, 0x41, 0x00, 0x00,  0x02, 0x00, 0x00, 0x00, 0x66, 0x00, 0x00, 0x00,
+                 0x77, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        ];
+        if cfg!(feature = "abi-7-32") {
+            // blksize and the attribute flags with FUSE_ATTR_SUBMOUNT
+            expected[0][0] += 8;
+            expected[1].extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]);
+        } else if cfg!(feature = "abi-7-9") {
+            expected[0][0] += 8;
+            expected[1].extend_from_slice(&[0; 8]);
+        }
+        let sender = AssertSender { expected };
+        let reply: ReplyEntry = Reply::new(0xdeadbeef, sender);
+        let time = UNIX_EPOCH + Duration::new(0x1234, 0x5678);
+        let attr = FileAttr { ino: 0x11, size: 0x22, blocks: 0x33, atime: time, mtime: time, ctime: time, crtime: time,
+            kind: FileType::Directory, perm: 0o755, nlink: 2, uid: 0x66, gid: 0x77, rdev: 0, flags: 0 };
+        reply.submount_stub(&attr, 0xaa);
+    }
+
+    #[test]
+    fn reply_entry_submount_stub_not_directory() {
         let sender = AssertSender {
-            expected: if cfg!(target_os = "macos") {
-                vec![
-                    vec![0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
-                    vec![0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x21, 0x43, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
-                         0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
-                         0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00,  0x88, 0x00, 0x00, 0x00, 0x99, 0x00, 0x00, 0x00],
-                ]
-            } else {
-                vec![
-                    vec![0x70, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
-                    vec![0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x21, 0x43, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00,
-                         0x55, 0x00, 0x00, 0x00, 0x66, 0x00, 0x00, 0x00,  0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00],
-                ]
-            }
+            expected: vec![
+                vec![0x10, 0x00, 0x00, 0x00, 0xfb, 0xff, 0xff, 0xff,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+            ]
+        };
+        let reply: ReplyEntry = Reply::new(0xdeadbeef, sender);
+        let time = UNIX_EPOCH + Duration::new(0x1234, 0x5678);
+        let attr = FileAttr { ino: 0x11, size: 0x22, blocks: 0x33, atime: time, mtime: time, ctime: time, crtime: time,
+            kind: FileType::Symlink, perm: 0o777, nlink: 1, uid: 0x66, gid: 0x77, rdev: 0, flags: 0 };
+        reply.submount_stub(&attr, 0xaa);
+    }
+
+    #[test]
+    fn reply_attr() {
+        let mut expected = if cfg!(target_os = "macos") {
+            vec![
+                vec![0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x21, 0x43, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                     0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
+                     0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00,  0x88, 0x00, 0x00, 0x00, 0x99, 0x00, 0x00, 0x00],
+            ]
+        } else {
+            vec![
+                vec![0x70, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x21, 0x43, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00,
+                     0x55, 0x00, 0x00, 0x00, 0x66, 0x00, 0x00, 0x00,  0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00],
+            ]
         };
+        if cfg!(feature = "abi-7-9") {
+            // fuse_attr gained blksize and padding at its end
+            expected[0][0] += 8;
+            expected[1].extend_from_slice(&[0; 8]);
+        }
+        let sender = AssertSender { expected };
         let reply: ReplyAttr = Reply::new(0xdeadbeef, sender);
         let time = UNIX_EPOCH + Duration::new(0x1234, 0x5678);
         let ttl = Duration::new(0x8765, 0x4321);
@@ -840,6 +1825,27 @@ mod test {
         reply.opened(0x1122, 0x33);
     }
 
+    #[test]
+    fn open_flags() {
+        assert_eq!(OpenFlags::new().bits(), 0);
+        assert_eq!(OpenFlags::new().direct_io().keep_cache().bits(), 0x03);
+        assert_eq!(u32::from(OpenFlags::new().keep_cache()), 0x02);
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-31")]
+    fn open_flags_stream() {
+        assert_eq!(OpenFlags::new().stream().bits(), 1 << 4);
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0x22, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            ]
+        };
+        let reply: ReplyOpen = Reply::new(0xdeadbeef, sender);
+        reply.opened(0x1122, OpenFlags::new().nonseekable().stream().into());
+    }
+
     #[test]
     fn reply_write() {
         let sender = AssertSender {
@@ -853,6 +1859,7 @@ mod test {
     }
 
     #[test]
+    #[allow(deprecated)]
     fn reply_statfs() {
         let sender = AssertSender {
             expected: vec![
@@ -868,38 +1875,62 @@ mod test {
         reply.statfs(0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88);
     }
 
+    #[test]
+    #[allow(deprecated)]
+    fn reply_statfs_stats() {
+        let expected = vec![
+            vec![0x60, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+            vec![0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x55, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                 0x44, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00,
+                 0x88, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        ];
+        // Named values are replied like the positional ones
+        let stats = StatFs::builder().blocks(0x33).bfree(0x22).bavail(0x11).files(0x55).ffree(0x44).bsize(0x66).namelen(0x77).frsize(0x88);
+        let reply: ReplyStatfs = Reply::new(0xdeadbeef, AssertSender { expected: expected.clone() });
+        reply.stats(&stats.build().unwrap());
+        let reply: ReplyStatfs = Reply::new(0xdeadbeef, AssertSender { expected });
+        reply.statfs(0x33, 0x22, 0x11, 0x55, 0x44, 0x66, 0x77, 0x88);
+    }
+
     #[test]
     fn reply_create() {
-        let sender = AssertSender {
-            expected: if cfg!(target_os = "macos") {
-                vec![
-                    vec![0xa8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
-                    vec![0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x21, 0x43, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,  0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
-                         0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,  0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00,
-                         0x88, 0x00, 0x00, 0x00, 0x99, 0x00, 0x00, 0x00,  0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0xcc, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
-                ]
-            } else {
-                vec![
-                    vec![0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
-                    vec![0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x21, 0x43, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,  0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
-                         0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00,  0x55, 0x00, 0x00, 0x00, 0x66, 0x00, 0x00, 0x00,
-                         0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,  0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                         0xcc, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
-                ]
-            }
+        let mut expected = if cfg!(target_os = "macos") {
+            vec![
+                vec![0xa8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x21, 0x43, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,  0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                     0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,  0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00,
+                     0x88, 0x00, 0x00, 0x00, 0x99, 0x00, 0x00, 0x00,  0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0xcc, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            ]
+        } else {
+            vec![
+                vec![0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x21, 0x43, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,  0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                     0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00,  0x55, 0x00, 0x00, 0x00, 0x66, 0x00, 0x00, 0x00,
+                     0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,  0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0xcc, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            ]
         };
+        if cfg!(feature = "abi-7-9") {
+            // fuse_attr gained blksize and padding at its end, followed by fuse_open_out
+            expected[0][0] += 8;
+            let pos = expected[1].len() - 16;
+            expected[1].splice(pos..pos, vec![0; 8]);
+        }
+        let sender = AssertSender { expected };
         let reply: ReplyCreate = Reply::new(0xdeadbeef, sender);
         let time = UNIX_EPOCH + Duration::new(0x1234, 0x5678);
         let ttl = Duration::new(0x8765, 0x4321);
@@ -933,6 +1964,48 @@ mod test {
         reply.bmap(0x1234);
     }
 
+    #[test]
+    #[cfg(feature = "abi-7-24")]
+    fn reply_lseek() {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0x00, 0x30, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00],
+            ]
+        };
+        let reply: super::ReplyLseek = Reply::new(0xdeadbeef, sender);
+        reply.offset(0x1_0000_3000);
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-11")]
+    fn reply_ioctl() {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x23, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+                vec![0x6f, 0x75, 0x74],
+            ]
+        };
+        let reply: super::ReplyIoctl = Reply::new(0xdeadbeef, sender);
+        reply.limited(4).ioctl(0x11, b"out");
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-16")]
+    fn reply_ioctl_retry() {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0x00, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00,  0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00],
+                vec![0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            ]
+        };
+        let reply: super::ReplyIoctl = Reply::new(0xdeadbeef, sender);
+        reply.retry(&[(0x1000, 8)], &[(0x2000, 4)]);
+    }
+
     #[test]
     fn reply_directory() {
         let sender = AssertSender {
@@ -950,6 +2023,85 @@ mod test {
         reply.ok();
     }
 
+    #[test]
+    fn reply_directory_with_mode() {
+        assert_eq!(super::dirent_type_from_mode(0o100644), libc::DT_REG as u32);
+        assert_eq!(super::dirent_type_from_mode(0o040755), libc::DT_DIR as u32);
+        assert_eq!(super::dirent_type_from_mode(0o120777), libc::DT_LNK as u32);
+        assert_eq!(super::dirent_type_from_mode(0o010600), libc::DT_FIFO as u32);
+        assert_eq!(super::dirent_type_from_mode(0o020620), libc::DT_CHR as u32);
+        assert_eq!(super::dirent_type_from_mode(0o060660), libc::DT_BLK as u32);
+        assert_eq!(super::dirent_type_from_mode(0o140755), libc::DT_SOCK as u32);
+        // Unknown file types and modes without type bits
+        assert_eq!(super::dirent_type_from_mode(0o070644), libc::DT_UNKNOWN as u32);
+        assert_eq!(super::dirent_type_from_mode(0o644), libc::DT_UNKNOWN as u32);
+        // Entries added with the mode are the same as with the file type
+        let (tx, _rx) = channel();
+        let mut with_kind = ReplyDirectory::new(0xdeadbeef, tx.clone(), 4096);
+        let mut with_mode = ReplyDirectory::new(0xdeadbeef, tx, 4096);
+        with_kind.add(0xaabb, 1, FileType::Symlink, "link");
+        with_mode.add_with_mode(0xaabb, 1, 0o120777, "link");
+        assert_eq!(with_kind.data, with_mode.data);
+        with_kind.ok();
+        with_mode.ok();
+    }
+
+    #[test]
+    fn reply_directory_too_small() {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x10, 0x00, 0x00, 0x00, 0xde, 0xff, 0xff, 0xff,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+            ]
+        };
+        let mut reply = ReplyDirectory::new(0xdeadbeef, sender, 100);
+        let name = "x".repeat(255);
+        assert!(reply.add(0xaabb, 1, FileType::RegularFile, &name));
+        reply.ok();
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "Invalid directory entry name"))]
+    fn reply_directory_invalid_name() {
+        let (tx, _rx) = channel();
+        let mut reply = ReplyDirectory::new(0xdeadbeef, tx.clone(), 4096);
+        let mut expected = ReplyDirectory::new(0xdeadbeef, tx, 4096);
+        // A path instead of a file name is skipped
+        assert!(!reply.add(0xccdd, 1, FileType::RegularFile, "dir/world.rs"));
+        assert!(!reply.add(0xccdd, 1, FileType::RegularFile, "world\0.rs"));
+        reply.add(0xaabb, 2, FileType::Directory, "hello");
+        expected.add(0xaabb, 2, FileType::Directory, "hello");
+        assert_eq!(reply.data, expected.data);
+        reply.ok();
+        expected.ok();
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-21")]
+    fn reply_directory_plus() {
+        let (tx, _rx) = channel();
+        let mut reply = super::ReplyDirectoryPlus::new(0xdeadbeef, tx, 4096);
+        let time = UNIX_EPOCH + Duration::new(0x1234, 0x5678);
+        let attr = FileAttr::builder(0x11, FileType::RegularFile).size(0x22).times(time).perm(0o644).build();
+        let ttl = Duration::new(0x8765, 0x4321);
+        assert!(!reply.add(0x11, 1, "hello", &ttl, &attr, 0xaa));
+        assert!(!reply.add(0, 2, "world.rs", &ttl, &attr, 0xaa));
+        // Entries are 64 bit aligned
+        let entry_size = |name: &str| (mem::size_of::<fuse_direntplus>() + name.len() + 7) & !7;
+        assert_eq!(reply.data.len(), entry_size("hello") + entry_size("world.rs"));
+        let entry = |i: usize| {
+            let data = &reply.data[i * entry_size("hello")..];
+            let header = unsafe { &*(data.as_ptr() as *const fuse_direntplus) };
+            let name = &data[mem::size_of::<fuse_direntplus>()..][..header.dirent.namelen as usize];
+            let entry = &header.entry_out;
+            ((entry.nodeid, entry.generation, entry.entry_valid, entry.attr_valid_nsec, entry.attr.size),
+             (header.dirent.ino, header.dirent.off, header.dirent.typ), name.to_vec())
+        };
+        assert_eq!(entry(0), ((0x11, 0xaa, 0x8765, 0x4321, 0x22), (0x11, 1, libc::DT_REG as u32), b"hello".to_vec()));
+        // An entry with ino 0 isn't cached and doesn't increase the lookup count
+        assert_eq!(entry(1), ((0, 0, 0, 0, 0x22), (0x11, 2, libc::DT_REG as u32), b"world.rs".to_vec()));
+        reply.ok();
+    }
+
     impl super::ReplySender for Sender<()> {
         fn send(&self, _: &[&[u8]]) {
             Sender::send(self, ()).unwrap()
@@ -980,6 +2132,121 @@ mod test {
         reply.data(&vec![0x11, 0x22, 0x33, 0x44]);
     }
 
+    #[test]
+    fn reply_xattr_list() {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x1e, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xEF, 0xBE, 0xAD, 0xDE, 0x00, 0x00,  0x00, 0x00],
+                vec![0x75, 0x2e, 0x61, 0x00, 0x75, 0x2e, 0x62, 0x63, 0x64, 0x00, 0x75, 0x2e, 0x65, 0x00],
+            ]
+        };
+        let mut list = XAttrList::new();
+        list.add("u.a", b"1");
+        list.add("u.bcd", b"");
+        list.add("u.e", b"23");
+        // Names are listed once
+        list.add("u.a", b"4");
+        assert_eq!(list.len(), 3);
+        let reply = ReplyXattr::new(0xdeadbeef, sender);
+        reply.list(14, list);
+    }
+
+    #[test]
+    fn reply_xattr_value_probe() {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xEF, 0xBE, 0xAD, 0xDE, 0x00, 0x00,  0x00, 0x00],
+                vec![0x00, 0x00, 0x40, 0x00, 0x00,0x00, 0x00, 0x00],
+            ]
+        };
+        let reply = ReplyXattr::new(0xdeadbeef, sender);
+        reply.value(0, XAttrValue::from_fn(4 * 1024 * 1024, |_| panic!("value produced for size probe")));
+    }
+
+    #[test]
+    fn reply_xattr_value_exact_fit() {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xEF, 0xBE, 0xAD, 0xDE, 0x00, 0x00,  0x00, 0x00],
+                vec![0x11, 0x22, 0x33, 0x44],
+            ]
+        };
+        let reply = ReplyXattr::new(0xdeadbeef, sender);
+        reply.value(4, XAttrValue::from_fn(4, |w| w.write_all(&[0x11, 0x22, 0x33, 0x44])));
+    }
+
+    #[test]
+    fn reply_xattr_value_too_small() {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x10, 0x00, 0x00, 0x00, 0xde, 0xff, 0xff, 0xff,  0xEF, 0xBE, 0xAD, 0xDE, 0x00, 0x00,  0x00, 0x00],
+            ]
+        };
+        let reply = ReplyXattr::new(0xdeadbeef, sender);
+        reply.value(3, XAttrValue::from_fn(4, |_| panic!("value produced although it doesn't fit")));
+    }
+
+    #[test]
+    fn reply_xattr_respond_probe() {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xEF, 0xBE, 0xAD, 0xDE, 0x00, 0x00,  0x00, 0x00],
+                vec![0x04, 0x00, 0x00, 0x00, 0x00,0x00, 0x00, 0x00],
+            ]
+        };
+        let reply = ReplyXattr::new(0xdeadbeef, sender);
+        reply.respond(0, &[0x11, 0x22, 0x33, 0x44]);
+    }
+
+    #[test]
+    fn reply_xattr_respond_fit() {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xEF, 0xBE, 0xAD, 0xDE, 0x00, 0x00,  0x00, 0x00],
+                vec![0x11, 0x22, 0x33, 0x44],
+            ]
+        };
+        let reply = ReplyXattr::new(0xdeadbeef, sender);
+        reply.respond(64, &[0x11, 0x22, 0x33, 0x44]);
+    }
+
+    #[test]
+    fn reply_xattr_respond_erange() {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x10, 0x00, 0x00, 0x00, 0xde, 0xff, 0xff, 0xff,  0xEF, 0xBE, 0xAD, 0xDE, 0x00, 0x00,  0x00, 0x00],
+            ]
+        };
+        let reply = ReplyXattr::new(0xdeadbeef, sender);
+        reply.respond(3, &[0x11, 0x22, 0x33, 0x44]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "root inode must be of kind Directory")]
+    fn root_attr_not_directory() {
+        let (tx, _rx) = channel::<()>();
+        let reply: ReplyAttr = Reply::new(0xdeadbeef, tx);
+        reply.attr(&Duration::from_secs(1), &FileAttr::builder(1, FileType::RegularFile).build());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "root inode must be of kind Directory")]
+    fn root_entry_not_directory() {
+        let (tx, _rx) = channel::<()>();
+        let reply: ReplyEntry = Reply::new(0xdeadbeef, tx);
+        reply.entry(&Duration::from_secs(1), &FileAttr::builder(1, FileType::Symlink).build(), 0);
+    }
+
+    #[test]
+    fn root_attr_directory() {
+        let (tx, rx) = channel::<()>();
+        let reply: ReplyAttr = Reply::new(0xdeadbeef, tx);
+        reply.attr(&Duration::from_secs(1), &FileAttr::builder(1, FileType::Directory).build());
+        rx.recv().unwrap();
+    }
+
     #[test]
     fn async_reply() {
         let (tx, rx) = channel::<()>();
@@ -989,4 +2256,41 @@ mod test {
         });
         rx.recv().unwrap();
     }
+
+    #[test]
+    fn lookups_counted_once_sent() {
+        use std::net::Shutdown;
+        use std::os::unix::io::IntoRawFd;
+        use std::os::unix::net::UnixDatagram;
+        use std::path::Path;
+        use std::sync::Arc;
+        use crate::channel::Channel;
+        use crate::lookups::LookupTracker;
+        use super::{Intercept, ReplySender};
+
+        let (kernel, session) = UnixDatagram::pair().unwrap();
+        let ch = Channel::from_fd(session.into_raw_fd(), Path::new("/test"));
+        let lookups = Arc::new(LookupTracker::new(16));
+        let ttl = Duration::from_secs(1);
+        let entry = |ino| ReplyEntry::new(0xdeadbeef, ch.sender()).lookups(Some(&lookups)).entry(&ttl, &FileAttr::builder(ino, FileType::RegularFile).build(), 1);
+        entry(2);
+        let reply: ReplyCreate = Reply::new(0xdeadbeef, ch.sender());
+        reply.lookups(Some(&lookups)).created(&ttl, &FileAttr::builder(3, FileType::RegularFile).build(), 1, 0, 0);
+        assert_eq!(lookups.stats(), (2, 0));
+        // An entry that is replaced with an error when intercepted isn't counted
+        let reply: ReplyEntry = Reply::new(0xdeadbeef, ch.sender());
+        let reply = reply.lookups(Some(&lookups)).intercept(|data, sender: &dyn ReplySender| {
+            let mut header = data[0][..16].to_vec();
+            header[0..4].copy_from_slice(&16u32.to_ne_bytes());
+            header[4..8].copy_from_slice(&(-libc::EIO).to_ne_bytes());
+            sender.send(&[&header]);
+        });
+        reply.entry(&ttl, &FileAttr::builder(4, FileType::RegularFile).build(), 1);
+        assert_eq!(lookups.stats(), (2, 0));
+        // Neither is an entry whose reply can't be written (e.g. because the kernel driver
+        // fails replies to requests that were interrupted meanwhile)
+        kernel.shutdown(Shutdown::Read).unwrap();
+        entry(5);
+        assert_eq!(lookups.stats(), (2, 0));
+    }
 }
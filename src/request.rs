@@ -6,28 +6,61 @@
 //! TODO: This module is meant to go away soon in favor of `ll::Request`.
 
 use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::sync::Arc;
 use std::path::Path;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use libc::{EIO, ENOSYS, EPROTO};
+#[cfg(feature = "abi-7-16")]
+use std::slice;
+use libc::{c_int, EIO, ENOSYS, EPROTO, EROFS};
+#[cfg(feature = "abi-7-23")]
+use libc::EINVAL;
 use fuse_abi::*;
 use fuse_abi::consts::*;
 use log::{debug, error, warn};
 
+use crate::access::AccessKey;
+use crate::buffer::{BytesBuffer, RequestBuffer};
+use crate::capcheck;
 use crate::channel::ChannelSender;
+use crate::fairness::RequestClass;
+use crate::filter;
+use crate::interrupt::InterruptFlag;
+use crate::killpriv::{self, KillPrivSender, CAPABILITY_XATTR};
 use crate::ll;
-use crate::reply::{Reply, ReplyRaw, ReplyEmpty, ReplyDirectory};
-use crate::session::{MAX_WRITE_SIZE, Session};
-use crate::Filesystem;
+use crate::pidns::{self, PidError, PidInfo, PidNamespace};
+#[cfg(target_os = "linux")]
+use crate::pidns::ProcessInfo;
+use crate::reply::{Reply, ReplyRaw, ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr, ReplyCreate, ReplyDirectory, ReplyXattr};
+#[cfg(feature = "abi-7-21")]
+use crate::reply::ReplyDirectoryPlus;
+#[cfg(feature = "abi-7-11")]
+use crate::reply::ReplyIoctl;
+use crate::session::{InflightLimit, InflightSender, InitFlags, OutstandingReplies, ProtocolCapabilities, Session, ShutdownReason, Transfer};
+use crate::setattr::SetattrChanges;
+use crate::task::TaskScope;
+use crate::{CreateMode, Filesystem};
+#[cfg(feature = "abi-7-11")]
+use crate::{IoctlFlags, IoctlRequest};
+#[cfg(feature = "abi-7-19")]
+use crate::FallocateRequest;
+#[cfg(feature = "abi-7-23")]
+use crate::{RenameFlags, Rename2Request};
+
+/// Error for a missing extended attribute
+#[cfg(not(target_os = "macos"))]
+const ENOATTR: c_int = libc::ENODATA;
+#[cfg(target_os = "macos")]
+const ENOATTR: c_int = libc::ENOATTR;
 
 /// We generally support async reads
 #[cfg(not(target_os = "macos"))]
-const INIT_FLAGS: u32 = FUSE_ASYNC_READ;
+pub(crate) const INIT_FLAGS: u32 = FUSE_ASYNC_READ;
 // TODO: Add FUSE_EXPORT_SUPPORT and FUSE_BIG_WRITES (requires ABI 7.10)
 
-/// On macOS, we additionally support case insensitiveness, volume renames and xtimes
-/// TODO: we should eventually let the filesystem implementation decide which flags to set
+/// On macOS, we additionally support case insensitiveness, volume renames and xtimes.
+/// Filesystems can ask for more flags with `InitConfig::flags`.
 #[cfg(target_os = "macos")]
-const INIT_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_CASE_INSENSITIVE | FUSE_VOL_RENAME | FUSE_XTIMES;
+pub(crate) const INIT_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_CASE_INSENSITIVE | FUSE_VOL_RENAME | FUSE_XTIMES;
 // TODO: Add FUSE_EXPORT_SUPPORT and FUSE_BIG_WRITES (requires ABI 7.10)
 
 /// Request data structure
@@ -35,6 +68,18 @@ const INIT_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_CASE_INSENSITIVE | FUSE_VOL_RENAM
 pub struct Request<'a> {
     /// Channel sender for sending the reply
     ch: ChannelSender,
+    /// Scope of tasks spawned by the filesystem
+    tasks: TaskScope,
+    /// Requests that wait for a reply
+    outstanding: Arc<OutstandingReplies>,
+    /// Limit of outstanding requests
+    inflight: Option<Arc<InflightLimit>>,
+    /// Whether request pids can be used for /proc lookups
+    pid_ns: PidNamespace,
+    /// Capabilities of the kernel driver
+    capabilities: ProtocolCapabilities,
+    /// Buffer that holds the request data
+    buffer: &'a RequestBuffer,
     /// Request raw data
     data: &'a [u8],
     /// Parsed request
@@ -42,18 +87,31 @@ pub struct Request<'a> {
 }
 
 impl<'a> Request<'a> {
-    /// Create a new request from the given data
-    pub fn new(ch: ChannelSender, data: &'a [u8]) -> Option<Request<'a>> {
+    /// Create a new request from the data in the given buffer. Unknown operations (e.g. of
+    /// newer kernels) are replied with ENOSYS right away.
+    pub fn new(ch: ChannelSender, tasks: &TaskScope, outstanding: &Arc<OutstandingReplies>, inflight: Option<&Arc<InflightLimit>>, pid_ns: PidNamespace, capabilities: ProtocolCapabilities, buffer: &'a RequestBuffer) -> Result<Request<'a>, ll::RequestError> {
+        let data = buffer.data();
         let request = match ll::Request::try_from(data) {
             Ok(request) => request,
+            Err(ll::RequestError::UnknownOperation(opcode)) => {
+                // The header is complete, so the unique id is known
+                let mut unique = [0; 8];
+                unique.copy_from_slice(&data[8..16]);
+                let unique = u64::from_ne_bytes(unique);
+                warn!("Unknown FUSE opcode ({}), replying ENOSYS", opcode);
+                // Ordered, counted and timed like replies to known operations
+                outstanding.count_request();
+                let sender = InflightSender::new(ch, unique, outstanding, inflight).timed(RequestClass::Metadata);
+                ReplyEmpty::new(unique, sender).error(ENOSYS);
+                return Err(ll::RequestError::UnknownOperation(opcode));
+            }
             Err(err) => {
-                // FIXME: Reply with ENOSYS?
                 error!("{}", err);
-                return None;
+                return Err(err);
             }
         };
 
-        Some(Self { ch, data, request})
+        Ok(Self { ch, tasks: tasks.clone(), outstanding: outstanding.clone(), inflight: inflight.cloned(), pid_ns, capabilities, buffer, data, request})
     }
 
     /// Dispatch request to the given filesystem.
@@ -61,6 +119,43 @@ impl<'a> Request<'a> {
     /// request and sends back the returned reply to the kernel
     pub fn dispatch<FS: Filesystem>(&self, se: &mut Session<FS>) {
         debug!("{}", self.request);
+        self.outstanding.count_request();
+        se.notify_mount_flags();
+        if se.check_capabilities() && se.initialized {
+            if let Some(missing) = capcheck::check(&self.request, &self.capabilities) {
+                let (major, minor) = self.capabilities.negotiated_version();
+                warn!("Unexpected {}: {}, but negotiated ABI {}.{} with init flags {:#x}", self.request, missing, major, minor, self.capabilities.negotiated_flags());
+                self.outstanding.count_anomaly();
+            }
+        }
+        if let Some(err) = se.request_filter().and_then(|filter| filter::refuse(filter, &self.request)) {
+            self.reply::<ReplyEmpty>().error(err);
+            return;
+        }
+        if let Some(switch) = se.read_only() {
+            if switch.is_enabled() && filter::modifies(&self.request) {
+                debug!("Refusing {} of read-only filesystem", self.request);
+                self.reply::<ReplyEmpty>().error(EROFS);
+                return;
+            }
+        }
+        if let Some(attrs) = se.attr_tracker() {
+            attrs.observe(&self.request);
+        }
+        if let Some(access) = se.access_cache() {
+            access.observe(&self.request);
+        }
+        if let Some(xattrs) = se.xattr_cache() {
+            xattrs.observe(&self.request);
+        }
+        if let Some(lookups) = se.lookup_tracker() {
+            lookups.observe(&self.request);
+        }
+        if let Some(privs) = se.kill_priv() {
+            if privs.observe(&self.request) && !killpriv::flagged_by_kernel(&self.capabilities) {
+                self.kill_capabilities(se);
+            }
+        }
 
         match self.request.operation() {
             // Filesystem initialization
@@ -72,29 +167,48 @@ impl<'a> Request<'a> {
                     reply.error(EPROTO);
                     return;
                 }
-                // Remember ABI version supported by kernel
-                se.proto_major = arg.major;
-                se.proto_minor = arg.minor;
-                // Call filesystem init method and give it a chance to return an error
-                let res = se.filesystem.init(self);
-                if let Err(err) = res {
-                    reply.error(err);
-                    return;
-                }
+                // Remember ABI version and capabilities of the kernel
+                let capabilities = self.capabilities();
+                se.set_capabilities(capabilities);
+                // Call filesystem init method and give it a chance to return an error. Flags
+                // it asks for are masked with the capable flags of the kernel driver.
+                let capabilities = match se.filesystem.init(self) {
+                    Ok(config) => capabilities.configure(&config),
+                    Err(err) => {
+                        reply.error(err);
+                        return;
+                    }
+                };
+                se.set_capabilities(capabilities);
                 // Reply with our desired version and settings. If the kernel supports a
                 // larger major version, it'll re-send a matching init message. If it
                 // supports only lower major versions, we replied with an error above.
                 let init = fuse_init_out {
                     major: FUSE_KERNEL_VERSION,
                     minor: FUSE_KERNEL_MINOR_VERSION,
-                    max_readahead: arg.max_readahead,       // accept any readahead size
-                    flags: arg.flags & INIT_FLAGS,          // use features given in INIT_FLAGS and reported as capable
+                    max_readahead: capabilities.max_readahead(), // the kernel's size, unless the filesystem asked for less
+                    flags: capabilities.negotiated_flags(), // use features the session wants and the kernel reported as capable
+                    #[cfg(not(feature = "abi-7-13"))]
                     unused: 0,
-                    max_write: MAX_WRITE_SIZE as u32,       // use a max write size that fits into the session's buffer
+                    #[cfg(feature = "abi-7-13")]
+                    max_background: capabilities.max_background(),
+                    #[cfg(feature = "abi-7-13")]
+                    congestion_threshold: capabilities.congestion_threshold(),
+                    max_write: se.max_write() as u32,       // use a max write size that fits into the session's buffer
+                    #[cfg(feature = "abi-7-23")]
+                    time_gran: 1,                           // nanosecond timestamp granularity
+                    #[cfg(feature = "abi-7-28")]
+                    max_pages: 0,                           // ignored since FUSE_MAX_PAGES isn't set
+                    #[cfg(all(feature = "abi-7-28", not(feature = "abi-7-31")))]
+                    padding: 0,
+                    #[cfg(feature = "abi-7-31")]
+                    map_alignment: 0,
+                    #[cfg(feature = "abi-7-28")]
+                    unused: [0; 8],
                 };
-                debug!("INIT response: ABI {}.{}, flags {:#x}, max readahead {}, max write {}", init.major, init.minor, init.flags, init.max_readahead, init.max_write);
-                se.initialized = true;
+                debug!("INIT response: ABI {}.{}, flags {}, max readahead {}, max write {}", init.major, init.minor, InitFlags(init.flags), init.max_readahead, init.max_write);
                 reply.ok(&init);
+                se.set_initialized();
             }
             // Any operation is invalid before initialization
             _ if !se.initialized => {
@@ -103,8 +217,7 @@ impl<'a> Request<'a> {
             }
             // Filesystem destroyed
             ll::Operation::Destroy => {
-                se.filesystem.destroy(self);
-                se.destroyed = true;
+                se.destroy(ShutdownReason::KernelDestroy);
                 self.reply::<ReplyEmpty>().ok();
             }
             // Any operation is invalid after destroy
@@ -113,110 +226,81 @@ impl<'a> Request<'a> {
                 self.reply::<ReplyEmpty>().error(EIO);
             }
 
-            ll::Operation::Interrupt { .. } => {
-                // TODO: handle FUSE_INTERRUPT
-                self.reply::<ReplyEmpty>().error(ENOSYS);
+            ll::Operation::Interrupt { arg } => {
+                if self.outstanding.interrupt(arg.unique) {
+                    se.filesystem.interrupt(self, arg.unique); // no reply
+                }
             }
 
             ll::Operation::Lookup { name } => {
-                se.filesystem.lookup(self, self.request.nodeid(), &name, self.reply());
+                let reply = self.reply::<ReplyEntry>().tracked(se.attr_tracker()).owners(se.kill_priv()).lookups(se.lookup_tracker()).default_ttl(se.default_ttl());
+                se.filesystem.lookup(self, self.request.nodeid(), &name, reply);
             }
             ll::Operation::Forget { arg } => {
                 se.filesystem.forget(self, self.request.nodeid(), arg.nlookup); // no reply
             }
             ll::Operation::GetAttr => {
-                se.filesystem.getattr(self, self.request.nodeid(), self.reply());
+                let reply = self.reply::<ReplyAttr>().tracked(se.attr_tracker()).owners(se.kill_priv()).default_ttl(se.default_ttl());
+                se.filesystem.getattr(self, self.request.nodeid(), reply);
             }
             ll::Operation::SetAttr { arg } => {
-                let mode = match arg.valid & FATTR_MODE {
-                    0 => None,
-                    _ => Some(arg.mode),
-                };
-                let uid = match arg.valid & FATTR_UID {
-                    0 => None,
-                    _ => Some(arg.uid),
-                };
-                let gid = match arg.valid & FATTR_GID {
-                    0 => None,
-                    _ => Some(arg.gid),
-                };
-                let size = match arg.valid & FATTR_SIZE {
-                    0 => None,
-                    _ => Some(arg.size),
-                };
-                let atime = match arg.valid & FATTR_ATIME {
-                    0 => None,
-                    _ => Some(UNIX_EPOCH + Duration::new(arg.atime, arg.atimensec)),
-                };
-                let mtime = match arg.valid & FATTR_MTIME {
-                    0 => None,
-                    _ => Some(UNIX_EPOCH + Duration::new(arg.mtime, arg.mtimensec)),
-                };
-                let fh = match arg.valid & FATTR_FH {
-                    0 => None,
-                    _ => Some(arg.fh),
-                };
-                #[cfg(target_os = "macos")]
-                #[inline]
-                fn get_macos_setattr(arg: &fuse_setattr_in) -> (Option<SystemTime>, Option<SystemTime>, Option<SystemTime>, Option<u32>) {
-                    let crtime = match arg.valid & FATTR_CRTIME {
-                        0 => None,
-                        _ => Some(UNIX_EPOCH + Duration::new(arg.crtime, arg.crtimensec)),
-                    };
-                    let chgtime = match arg.valid & FATTR_CHGTIME {
-                        0 => None,
-                        _ => Some(UNIX_EPOCH + Duration::new(arg.chgtime, arg.chgtimensec)),
-                    };
-                    let bkuptime = match arg.valid & FATTR_BKUPTIME {
-                        0 => None,
-                        _ => Some(UNIX_EPOCH + Duration::new(arg.bkuptime, arg.bkuptimensec)),
-                    };
-                    let flags = match arg.valid & FATTR_FLAGS {
-                        0 => None,
-                        _ => Some(arg.flags),
-                    };
-                    (crtime, chgtime, bkuptime, flags)
-                }
-                #[cfg(not(target_os = "macos"))]
-                #[inline]
-                fn get_macos_setattr(_arg: &fuse_setattr_in) -> (Option<SystemTime>, Option<SystemTime>, Option<SystemTime>, Option<u32>) {
-                    (None, None, None, None)
-                }
-                let (crtime, chgtime, bkuptime, flags) = get_macos_setattr(arg);
-                se.filesystem.setattr(self, self.request.nodeid(), mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags, self.reply());
+                se.filesystem.setattr_changes(self, self.request.nodeid(), SetattrChanges::new(arg), self.reply());
             }
             ll::Operation::ReadLink => {
                 se.filesystem.readlink(self, self.request.nodeid(), self.reply());
             }
             ll::Operation::MkNod { arg, name } => {
-                se.filesystem.mknod(self, self.request.nodeid(), &name, arg.mode, arg.rdev, self.reply());
+                #[cfg(feature = "abi-7-12")]
+                let umask = arg.umask;
+                #[cfg(not(feature = "abi-7-12"))]
+                let umask = 0;
+                let reply = self.reply::<ReplyEntry>().tracked(se.attr_tracker()).owners(se.kill_priv()).lookups(se.lookup_tracker()).default_ttl(se.default_ttl());
+                se.filesystem.mknod_with_umask(self, self.request.nodeid(), &name, CreateMode::new(arg.mode, umask), arg.rdev, reply);
             }
             ll::Operation::MkDir { arg, name } => {
-                se.filesystem.mkdir(self, self.request.nodeid(), &name, arg.mode, self.reply());
+                #[cfg(feature = "abi-7-12")]
+                let umask = arg.umask;
+                #[cfg(not(feature = "abi-7-12"))]
+                let umask = 0;
+                let reply = self.reply::<ReplyEntry>().tracked(se.attr_tracker()).owners(se.kill_priv()).lookups(se.lookup_tracker()).default_ttl(se.default_ttl());
+                se.filesystem.mkdir_with_umask(self, self.request.nodeid(), &name, CreateMode::new(arg.mode, umask), reply);
             }
             ll::Operation::Unlink { name } => {
-                se.filesystem.unlink(self, self.request.nodeid(), &name, self.reply());
+                #[cfg(feature = "abi-7-12")]
+                let reply = self.reply::<ReplyEmpty>().invalidating(se.entry_invalidation(&[(self.request.nodeid(), name)]));
+                #[cfg(not(feature = "abi-7-12"))]
+                let reply = self.reply();
+                se.filesystem.unlink(self, self.request.nodeid(), &name, reply);
             }
             ll::Operation::RmDir { name } => {
-                se.filesystem.rmdir(self, self.request.nodeid(), &name, self.reply());
+                #[cfg(feature = "abi-7-12")]
+                let reply = self.reply::<ReplyEmpty>().invalidating(se.entry_invalidation(&[(self.request.nodeid(), name)]));
+                #[cfg(not(feature = "abi-7-12"))]
+                let reply = self.reply();
+                se.filesystem.rmdir(self, self.request.nodeid(), &name, reply);
             }
             ll::Operation::SymLink { name, link } => {
-                se.filesystem.symlink(self, self.request.nodeid(), &name, &Path::new(link), self.reply());
+                let reply = self.reply::<ReplyEntry>().tracked(se.attr_tracker()).owners(se.kill_priv()).lookups(se.lookup_tracker()).default_ttl(se.default_ttl());
+                se.filesystem.symlink(self, self.request.nodeid(), &name, &Path::new(link), reply);
             }
             ll::Operation::Rename { arg, name, newname } => {
-                se.filesystem.rename(self, self.request.nodeid(), &name, arg.newdir, &newname, self.reply());
+                #[cfg(feature = "abi-7-12")]
+                let reply = self.reply::<ReplyEmpty>().invalidating(se.entry_invalidation(&[(self.request.nodeid(), name), (arg.newdir, newname)]));
+                #[cfg(not(feature = "abi-7-12"))]
+                let reply = self.reply();
+                se.filesystem.rename(self, self.request.nodeid(), &name, arg.newdir, &newname, reply);
             }
             ll::Operation::Link { arg, name } => {
-                se.filesystem.link(self, arg.oldnodeid, self.request.nodeid(), &name, self.reply());
+                let reply = self.reply::<ReplyEntry>().tracked(se.attr_tracker()).owners(se.kill_priv()).lookups(se.lookup_tracker()).default_ttl(se.default_ttl());
+                se.filesystem.link(self, arg.oldnodeid, self.request.nodeid(), &name, reply);
             }
             ll::Operation::Open { arg } => {
                 se.filesystem.open(self, self.request.nodeid(), arg.flags, self.reply());
             }
             ll::Operation::Read { arg } => {
-                se.filesystem.read(self, self.request.nodeid(), arg.fh, arg.offset as i64, arg.size, self.reply());
+                se.filesystem.read(self, self.request.nodeid(), arg.fh, arg.offset as i64, arg.size, self.reply::<ReplyData>().limited(arg.size));
             }
             ll::Operation::Write { arg, data } => {
-                assert!(data.len() == arg.size as usize);
                 se.filesystem.write(self, self.request.nodeid(), arg.fh, arg.offset as i64, data, arg.write_flags, self.reply());
             }
             ll::Operation::Flush { arg } => {
@@ -230,33 +314,24 @@ impl<'a> Request<'a> {
                 se.filesystem.release(self, self.request.nodeid(), arg.fh, arg.flags, arg.lock_owner, flush, self.reply());
             }
             ll::Operation::FSync { arg } => {
-                let datasync = match arg.fsync_flags & 1 {
-                    0 => false,
-                    _ => true,
-                };
-                se.filesystem.fsync(self, self.request.nodeid(), arg.fh, datasync, self.reply());
+                se.filesystem.fsync_with_flags(self, self.request.nodeid(), arg.fh, arg.fsync_flags, self.reply());
             }
             ll::Operation::OpenDir { arg } => {
                 se.filesystem.opendir(self, self.request.nodeid(), arg.flags, self.reply());
             }
             ll::Operation::ReadDir { arg } => {
-                se.filesystem.readdir(self, self.request.nodeid(), arg.fh, arg.offset as i64, ReplyDirectory::new(self.request.unique(), self.ch, arg.size as usize));
+                se.filesystem.readdir(self, self.request.nodeid(), arg.fh, arg.offset as i64, ReplyDirectory::new(self.request.unique(), self.sender(), arg.size as usize));
             }
             ll::Operation::ReleaseDir { arg } => {
                 se.filesystem.releasedir(self, self.request.nodeid(), arg.fh, arg.flags, self.reply());
             }
             ll::Operation::FSyncDir { arg } => {
-                let datasync = match arg.fsync_flags & 1 {
-                    0 => false,
-                    _ => true,
-                };
-                se.filesystem.fsyncdir(self, self.request.nodeid(), arg.fh, datasync, self.reply());
+                se.filesystem.fsyncdir_with_flags(self, self.request.nodeid(), arg.fh, arg.fsync_flags, self.reply());
             }
             ll::Operation::StatFs => {
                 se.filesystem.statfs(self, self.request.nodeid(), self.reply());
             }
             ll::Operation::SetXAttr { arg, name, value } => {
-                assert!(value.len() == arg.size as usize);
                 #[cfg(target_os = "macos")]
                 #[inline]
                 fn get_position (arg: &fuse_setxattr_in) -> u32 { arg.position }
@@ -265,20 +340,41 @@ impl<'a> Request<'a> {
                 fn get_position (_arg: &fuse_setxattr_in) -> u32 { 0 }
                 se.filesystem.setxattr(self, self.request.nodeid(), name, value, arg.flags, get_position(arg), self.reply());
             }
+            ll::Operation::GetXAttr { name, .. } if se.is_negative_xattr(name) => {
+                self.reply::<ReplyEmpty>().error(ENOATTR);
+            }
             ll::Operation::GetXAttr { arg, name } => {
-                se.filesystem.getxattr(self, self.request.nodeid(), name, arg.size, self.reply());
+                match se.xattr_cache().and_then(|cache| cache.get(self.request.nodeid(), name)) {
+                    Some(Some(value)) => self.reply::<ReplyXattr>().respond(arg.size, &value),
+                    Some(None) => self.reply::<ReplyXattr>().error(ENOATTR),
+                    None => se.filesystem.getxattr(self, self.request.nodeid(), name, arg.size, self.reply()),
+                }
             }
             ll::Operation::ListXAttr { arg } => {
-                se.filesystem.listxattr(self, self.request.nodeid(), arg.size, self.reply());
+                let reply = self.reply::<ReplyXattr>().cached(se.xattr_cache(), self.request.nodeid());
+                se.filesystem.listxattr(self, self.request.nodeid(), arg.size, reply);
             }
             ll::Operation::RemoveXAttr { name } => {
                 se.filesystem.removexattr(self, self.request.nodeid(), name, self.reply());
             }
             ll::Operation::Access { arg } => {
-                se.filesystem.access(self, self.request.nodeid(), arg.mask, self.reply());
+                let key = AccessKey { uid: self.request.uid(), gid: self.request.gid(), ino: self.request.nodeid(), mask: arg.mask };
+                match se.access_cache().and_then(|cache| cache.get(&key)) {
+                    Some(0) => self.reply::<ReplyEmpty>().ok(),
+                    Some(err) => self.reply::<ReplyEmpty>().error(err),
+                    None => {
+                        let reply = self.reply::<ReplyEmpty>().cached(se.access_cache(), key);
+                        se.filesystem.access(self, self.request.nodeid(), arg.mask, reply);
+                    }
+                }
             }
             ll::Operation::Create { arg, name } => {
-                se.filesystem.create(self, self.request.nodeid(), &name, arg.mode, arg.flags, self.reply());
+                #[cfg(feature = "abi-7-12")]
+                let umask = arg.umask;
+                #[cfg(not(feature = "abi-7-12"))]
+                let umask = 0;
+                let reply = self.reply::<ReplyCreate>().tracked(se.attr_tracker()).owners(se.kill_priv()).lookups(se.lookup_tracker()).default_ttl(se.default_ttl()).counted(&self.outstanding);
+                se.filesystem.create_with_umask(self, self.request.nodeid(), &name, CreateMode::new(arg.mode, umask), arg.flags, reply);
             }
             ll::Operation::GetLk { arg } => {
                 se.filesystem.getlk(self, self.request.nodeid(), arg.fh, arg.owner, arg.lk.start, arg.lk.end, arg.lk.typ, arg.lk.pid, self.reply());
@@ -293,6 +389,60 @@ impl<'a> Request<'a> {
                 se.filesystem.bmap(self, self.request.nodeid(), arg.blocksize, arg.block, self.reply());
             }
 
+            #[cfg(feature = "abi-7-11")]
+            ll::Operation::IoCtl { arg, data } => {
+                let reply = self.reply::<ReplyIoctl>().limited(arg.out_size);
+                let ioctl = IoctlRequest::new(IoctlFlags::from_bits(arg.flags), arg.cmd, arg.arg, data, arg.out_size);
+                se.filesystem.ioctl(self, self.request.nodeid(), arg.fh, ioctl, reply);
+            }
+            #[cfg(feature = "abi-7-11")]
+            ll::Operation::Poll { .. } => {
+                // TODO: handle FUSE_POLL
+                self.reply::<ReplyEmpty>().error(ENOSYS);
+            }
+            #[cfg(feature = "abi-7-15")]
+            ll::Operation::NotifyReply { arg, data } => {
+                se.filesystem.retrieve_reply(self, self.request.unique(), self.request.nodeid(), arg.offset, data); // no reply
+            }
+            #[cfg(feature = "abi-7-16")]
+            ll::Operation::BatchForget { nodes, .. } => {
+                se.filesystem.batch_forget(self, ForgetOne::from_nodes(nodes)); // no reply
+            }
+            #[cfg(feature = "abi-7-19")]
+            ll::Operation::FAllocate { arg } => {
+                let fallocate = FallocateRequest::new(arg.offset as i64, arg.length as i64, arg.mode as i32);
+                se.filesystem.fallocate(self, self.request.nodeid(), arg.fh, fallocate, self.reply());
+            }
+            #[cfg(feature = "abi-7-21")]
+            ll::Operation::ReadDirPlus { arg } => {
+                se.filesystem.readdirplus(self, self.request.nodeid(), arg.fh, arg.offset as i64, ReplyDirectoryPlus::new(self.request.unique(), self.sender(), arg.size as usize).lookups(se.lookup_tracker()));
+            }
+            #[cfg(feature = "abi-7-23")]
+            ll::Operation::Rename2 { arg, name, newname } => {
+                let flags = RenameFlags::from_bits(arg.flags);
+                if flags.is_valid() {
+                    let reply = self.reply::<ReplyEmpty>().invalidating(se.entry_invalidation(&[(self.request.nodeid(), name), (arg.newdir, newname)]));
+                    let rename = Rename2Request::new(arg.newdir, newname, flags);
+                    se.filesystem.rename2(self, self.request.nodeid(), name, rename, reply);
+                } else {
+                    self.reply::<ReplyEmpty>().error(EINVAL);
+                }
+            }
+            #[cfg(feature = "abi-7-24")]
+            ll::Operation::Lseek { arg } => {
+                se.filesystem.lseek(self, self.request.nodeid(), arg.fh, arg.offset as i64, arg.whence as i32, self.reply());
+            }
+            #[cfg(feature = "abi-7-28")]
+            ll::Operation::CopyFileRange { .. } => {
+                // TODO: handle FUSE_COPY_FILE_RANGE
+                self.reply::<ReplyEmpty>().error(ENOSYS);
+            }
+            #[cfg(feature = "abi-7-37")]
+            ll::Operation::TmpFile { arg } => {
+                let reply = self.reply::<ReplyCreate>().tracked(se.attr_tracker()).owners(se.kill_priv()).lookups(se.lookup_tracker()).default_ttl(se.default_ttl());
+                se.filesystem.tmpfile(self, self.request.nodeid(), CreateMode::new(arg.mode, arg.umask), arg.flags, reply);
+            }
+
             #[cfg(target_os = "macos")]
             ll::Operation::SetVolName { name } => {
                 se.filesystem.setvolname(self, name, self.reply());
@@ -305,13 +455,63 @@ impl<'a> Request<'a> {
             ll::Operation::Exchange { arg, oldname, newname } => {
                 se.filesystem.exchange(self, arg.olddir, &oldname, arg.newdir, &newname, arg.options, self.reply());
             }
+
+            #[cfg(feature = "abi-7-12")]
+            ll::Operation::CuseInit { .. } => {
+                // TODO: handle CUSE_INIT
+                self.reply::<ReplyEmpty>().error(ENOSYS);
+            }
+        }
+    }
+
+    /// Remove the file capabilities of the inode of this request by calling the filesystem's
+    /// removexattr (see `SessionBuilder::safe_defaults`). Its reply isn't sent to the kernel
+    /// driver, which didn't request it.
+    fn kill_capabilities<FS: Filesystem>(&self, se: &mut Session<FS>) {
+        let ino = self.request.nodeid();
+        debug!("Removing file capabilities of inode {} before {}", ino, self.request);
+        if let Some(xattrs) = se.xattr_cache() {
+            xattrs.forget(ino);
         }
+        let reply = Reply::new(self.request.unique(), KillPrivSender { ino });
+        se.filesystem.removexattr(self, ino, OsStr::new(CAPABILITY_XATTR), reply);
     }
 
     /// Create a reply object for this request that can be passed to the filesystem
     /// implementation and makes sure that a request is replied exactly once
     fn reply<T: Reply>(&self) -> T {
-        Reply::new(self.request.unique(), self.ch)
+        Reply::new(self.request.unique(), self.sender())
+    }
+
+    /// Create a reply sender for this request that counts as outstanding request
+    /// until the reply is sent
+    fn sender(&self) -> InflightSender {
+        let transfer = match self.request.operation() {
+            ll::Operation::Read { .. } => Some(Transfer::Read),
+            ll::Operation::Write { .. } => Some(Transfer::Write),
+            _ => None,
+        };
+        InflightSender::new(self.ch, self.request.unique(), &self.outstanding, self.inflight.as_ref()).counting(transfer).timed(RequestClass::of(&self.request))
+    }
+
+    /// Returns the task scope of the session. Filesystem methods that want to reply
+    /// asynchronously should spawn their work using this scope rather than spawning
+    /// threads on their own, so that the work is tied to the lifetime of the session.
+    #[inline]
+    pub fn spawner(&self) -> &TaskScope {
+        &self.tasks
+    }
+
+    /// Returns true if this request was interrupted (see `Request::interrupt_flag`)
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupt_flag().is_interrupted()
+    }
+
+    /// Returns a flag that tells whether this request was interrupted, e.g. because the
+    /// waiting process got a signal. Interrupted requests should be replied with `EINTR` as
+    /// soon as possible. Tasks that reply asynchronously should check it regularly.
+    pub fn interrupt_flag(&self) -> InterruptFlag {
+        self.outstanding.interrupt_flag(self.request.unique())
     }
 
     /// Returns the unique identifier of this request
@@ -335,10 +535,1997 @@ impl<'a> Request<'a> {
         self.request.gid()
     }
 
-    /// Returns the pid of this request
+    /// Returns the pid of this request. The pid is in the pid namespace of the process that
+    /// mounted the filesystem, so it's not necessarily usable for /proc lookups, see `pid_info`.
     #[inline]
     #[allow(dead_code)]
     pub fn pid(&self) -> u32 {
         self.request.pid()
     }
+
+    /// Returns the protocol version and capabilities of the kernel driver. Available from the
+    /// init request on.
+    pub fn capabilities(&self) -> ProtocolCapabilities {
+        match self.request.operation() {
+            ll::Operation::Init { arg } => self.capabilities.init(arg.major, arg.minor, arg.max_readahead, arg.flags),
+            _ => self.capabilities,
+        }
+    }
+
+    /// Returns the pid of this request along with whether it can be used for /proc lookups
+    pub fn pid_info(&self) -> PidInfo {
+        self.pid_ns.pid_info(self.request.pid())
+    }
+
+    /// Returns the supplementary groups of the process that caused this request, read from
+    /// /proc. Refuses to look up pids of a different or unknown pid namespace. Note that the
+    /// process may have exited or changed its groups in the meantime. Fails on systems
+    /// without /proc.
+    pub fn groups(&self) -> Result<Vec<u32>, PidError> {
+        pidns::groups(Path::new("/proc"), self.pid_info())
+    }
+
+    /// Returns the command name and cgroups of the process that caused this request, read
+    /// from /proc (e.g. for audit logs or per-application policies). Refuses to look up pids
+    /// of a different or unknown pid namespace. This is best effort: the process may have
+    /// exited in the meantime, and its pid may even have been reused by another process, so
+    /// the result must not be trusted for security decisions.
+    #[cfg(target_os = "linux")]
+    pub fn process_info(&self) -> Result<ProcessInfo, PidError> {
+        pidns::process_info(Path::new("/proc"), self.pid_info())
+    }
+
+    /// Takes the data of a write request without copying it, so that the filesystem can keep
+    /// it beyond the `write` call (e.g. to write it back asynchronously). The session continues
+    /// with a different buffer until the returned one is dropped. If too many buffers are
+    /// taken already, the data is copied (see `SessionBuilder::max_write_payloads`). Returns
+    /// `None` if this isn't a write request.
+    pub fn take_write_payload(&self) -> Option<BytesBuffer> {
+        match self.request.operation() {
+            ll::Operation::Write { data, .. } => Some(self.buffer.detach(data)),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the kernel issued this request on its own rather than for a syscall of
+    /// the requesting process, e.g. to rate limit speculative I/O differently. Only writes can
+    /// be told apart: delayed writes from the page cache (with writeback caching) are flagged
+    /// with `FUSE_WRITE_CACHE` (requires ABI 7.9). The kernel sends readahead as regular
+    /// reads, so reads (like all other operations) are never reported as kernel initiated.
+    pub fn is_kernel_initiated(&self) -> bool {
+        match self.request.operation() {
+            #[cfg(feature = "abi-7-9")]
+            ll::Operation::Write { arg, .. } => arg.write_flags & FUSE_WRITE_CACHE != 0,
+            _ => false,
+        }
+    }
+}
+
+/// An inode to forget in a batch forget (see `Filesystem::batch_forget`)
+#[cfg(feature = "abi-7-16")]
+#[repr(transparent)]
+#[derive(Debug)]
+pub struct ForgetOne(fuse_forget_one);
+
+#[cfg(feature = "abi-7-16")]
+impl ForgetOne {
+    /// Inode number to forget
+    pub fn ino(&self) -> u64 {
+        self.0.nodeid
+    }
+
+    /// Number of lookups to forget
+    pub fn nlookup(&self) -> u64 {
+        self.0.nlookup
+    }
+
+    /// Wrap the nodes of a batch forget request without copying them
+    fn from_nodes(nodes: &[fuse_forget_one]) -> &[ForgetOne] {
+        // Safe because ForgetOne is a transparent wrapper of fuse_forget_one
+        unsafe { slice::from_raw_parts(nodes.as_ptr() as *const ForgetOne, nodes.len()) }
+    }
+}
+
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::ffi::OsStr;
+    use std::mem;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixDatagram;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use fuse_abi::*;
+    use crate::buffer::BytesBuffer;
+    use crate::channel::Channel;
+    use crate::reply::{ReplyWrite, ReplyXattr};
+    use crate::session::{OutstandingReplies, ProtocolCapabilities, RunSummary, Session, SessionBuilder, ShutdownReason};
+    use crate::Filesystem;
+    pub use crate::testing::reply_header;
+    use super::{ReplyEmpty, Request, ENOATTR};
+
+    /// Build a raw request with the given arguments
+    pub fn request(opcode: fuse_opcode, unique: u64, nodeid: u64, args: &[u8]) -> Vec<u8> {
+        let len = mem::size_of::<fuse_in_header>() + args.len();
+        let mut data = Vec::with_capacity(len);
+        data.extend_from_slice(&(len as u32).to_ne_bytes());
+        data.extend_from_slice(&(opcode as u32).to_ne_bytes());
+        data.extend_from_slice(&unique.to_ne_bytes());
+        data.extend_from_slice(&nodeid.to_ne_bytes());
+        data.extend_from_slice(&[0; 16]);                   // uid, gid, pid, padding
+        data.extend_from_slice(args);
+        data
+    }
+
+    /// Build a raw init request
+    pub fn init_request() -> Vec<u8> {
+        let mut args = vec![0; mem::size_of::<fuse_init_in>()];
+        args[0..4].copy_from_slice(&FUSE_KERNEL_VERSION.to_ne_bytes());
+        args[4..8].copy_from_slice(&FUSE_KERNEL_MINOR_VERSION.to_ne_bytes());
+        args[8..12].copy_from_slice(&(128 * 1024u32).to_ne_bytes());
+        // The kernel driver is capable of async reads, big writes and leaving the umask and
+        // clearing privileges to the filesystem
+        #[allow(unused_mut)]
+        let mut flags = consts::FUSE_ASYNC_READ;
+        #[cfg(feature = "abi-7-9")]
+        { flags |= consts::FUSE_BIG_WRITES; }
+        #[cfg(feature = "abi-7-12")]
+        { flags |= consts::FUSE_DONT_MASK; }
+        #[cfg(feature = "abi-7-21")]
+        { flags |= consts::FUSE_DO_READDIRPLUS | consts::FUSE_READDIRPLUS_AUTO; }
+        #[cfg(all(feature = "abi-7-33", not(target_os = "macos")))]
+        { flags |= consts::FUSE_HANDLE_KILLPRIV_V2; }
+        args[12..16].copy_from_slice(&flags.to_ne_bytes());
+        request(fuse_opcode::FUSE_INIT, 1, 0, &args)
+    }
+
+    /// Run a session for the given filesystem that receives the given requests (after an init
+    /// request) over a socket instead of the kernel driver. Returns the raw replies (except the
+    /// init reply) once all requests were dispatched.
+    pub fn run<FS: Filesystem>(filesystem: FS, builder: SessionBuilder, requests: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        run_with(filesystem, builder, requests, |_| ())
+    }
+
+    /// Like `run`, but calls the given function with the session before running it
+    pub fn run_with<FS: Filesystem, F: FnOnce(&mut Session<FS>)>(filesystem: FS, builder: SessionBuilder, requests: &[Vec<u8>], setup: F) -> Vec<Vec<u8>> {
+        run_summarized(filesystem, builder, requests, setup).0
+    }
+
+    /// Like `run_with`, but also returns the run summary of the session
+    pub fn run_summarized<FS: Filesystem, F: FnOnce(&mut Session<FS>)>(filesystem: FS, builder: SessionBuilder, requests: &[Vec<u8>], setup: F) -> (Vec<Vec<u8>>, RunSummary) {
+        let (kernel, session) = UnixDatagram::pair().unwrap();
+        kernel.send(&init_request()).unwrap();
+        for req in requests {
+            kernel.send(req).unwrap();
+        }
+        // Sending an empty datagram makes the session loop quit
+        kernel.send(&[]).unwrap();
+        let fd = session.into_raw_fd();
+        let mut se = Session::with_channel(filesystem, Channel::from_fd(fd, Path::new("/test")));
+        builder.configure(&mut se);
+        setup(&mut se);
+        let summary = se.run_with_summary().unwrap();
+        drop(se);
+        kernel.set_nonblocking(true).unwrap();
+        let mut replies = Vec::new();
+        let mut buf = vec![0; 64 * 1024];
+        while let Ok(n) = kernel.recv(&mut buf) {
+            if n == 0 { break; }
+            replies.push(buf[..n].to_vec());
+        }
+        assert_eq!(reply_header(&replies.remove(0)), (1, 0), "init failed");
+        (replies, summary)
+    }
+
+    /// Build a raw getxattr request
+    fn getxattr_request(unique: u64, name: &str) -> Vec<u8> {
+        let mut args = vec![0; mem::size_of::<fuse_getxattr_in>()];
+        args.extend_from_slice(name.as_bytes());
+        args.push(0);
+        request(fuse_opcode::FUSE_GETXATTR, unique, 1, &args)
+    }
+
+    /// Filesystem without any xattrs that replies properly
+    struct NoXattrFS;
+
+    impl Filesystem for NoXattrFS {
+        fn getxattr(&mut self, _req: &Request<'_>, _ino: u64, name: &OsStr, _size: u32, reply: ReplyXattr) {
+            assert_ne!(name, "security.capability", "negative xattr reached the filesystem");
+            reply.error(ENOATTR);
+        }
+    }
+
+    /// Filesystem that doesn't implement xattrs
+    struct NullFS;
+
+    impl Filesystem for NullFS {}
+
+    /// Filesystem that hands getattr replies to the test instead of replying
+    struct HoldingFS {
+        held: Arc<Mutex<Vec<crate::ReplyAttr>>>,
+        dispatched: std::sync::mpsc::Sender<u64>,
+    }
+
+    impl Filesystem for HoldingFS {
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: crate::ReplyAttr) {
+            self.held.lock().unwrap().push(reply);
+            self.dispatched.send(ino).unwrap();
+        }
+    }
+
+    #[test]
+    fn inflight_limit_pauses_reader() {
+        let held = Arc::new(Mutex::new(Vec::new()));
+        let (tx, rx) = std::sync::mpsc::channel();
+        let fs = HoldingFS { held: held.clone(), dispatched: tx };
+        let requests: Vec<_> = (2..5).map(|ino| request(fuse_opcode::FUSE_GETATTR, ino, ino, &[])).collect();
+        let reply = |held: &Arc<Mutex<Vec<crate::ReplyAttr>>>| {
+            let reply = held.lock().unwrap().remove(0);
+            reply.attr(&Duration::from_secs(1), &crate::FileAttr::builder(2, crate::FileType::RegularFile).times(std::time::UNIX_EPOCH).build());
+        };
+        let control = thread::spawn(move || {
+            let mut dispatched = vec![rx.recv().unwrap(), rx.recv().unwrap()];
+            // The reader pauses while two requests are outstanding...
+            let paused = rx.recv_timeout(Duration::from_millis(100)).is_err();
+            // ...and resumes as soon as one of them is replied
+            reply(&held);
+            dispatched.push(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+            while !held.lock().unwrap().is_empty() {
+                reply(&held);
+            }
+            (paused, dispatched)
+        });
+        let replies = run(fs, SessionBuilder::new().max_inflight(2), &requests);
+        assert_eq!(control.join().unwrap(), (true, vec![2, 3, 4]));
+        assert_eq!(replies.len(), 3);
+        // A limit of 0 would never let the reader continue
+        let replies = run(NullFS, SessionBuilder::new().max_inflight(0), &requests[..1]);
+        assert_eq!(reply_header(&replies[0]), (2, -libc::ENOSYS));
+    }
+
+    #[test]
+    fn missing_xattr_is_enodata() {
+        let replies = run(NoXattrFS, SessionBuilder::new(), &[getxattr_request(2, "user.foo")]);
+        assert_eq!(reply_header(&replies[0]), (2, -ENOATTR));
+    }
+
+    #[test]
+    fn unimplemented_xattr_is_enosys() {
+        let replies = run(NullFS, SessionBuilder::new(), &[getxattr_request(2, "user.foo")]);
+        assert_eq!(reply_header(&replies[0]), (2, -libc::ENOSYS));
+    }
+
+    #[test]
+    fn unknown_opcode_is_enosys() {
+        let mut unknown = request(fuse_opcode::FUSE_GETATTR, 2, 1, &[]);
+        unknown[4..8].copy_from_slice(&4242u32.to_ne_bytes());
+        // The session continues with the next request
+        let replies = run(NoXattrFS, SessionBuilder::new(), &[unknown.clone(), getxattr_request(3, "user.foo")]);
+        let headers: Vec<_> = replies.iter().map(|reply| reply_header(reply)).collect();
+        assert_eq!(headers, [(2, -libc::ENOSYS), (3, -ENOATTR)]);
+        // The reply is ordered and counted like replies to known operations
+        unknown[8..16].copy_from_slice(&3u64.to_ne_bytes());
+        let requests = [request(fuse_opcode::FUSE_GETATTR, 2, 2, &[]), unknown, request(fuse_opcode::FUSE_GETATTR, 4, 5, &[])];
+        let (replies, summary) = run_summarized(SlowFS, SessionBuilder::new().ordered_replies(true), &requests, |_| ());
+        let headers: Vec<_> = replies.iter().map(|reply| reply_header(reply)).collect();
+        assert_eq!(headers, [(2, 0), (3, -libc::ENOSYS), (4, 0)]);
+        assert_eq!((summary.requests_processed, summary.errors), (4, 1));
+    }
+
+    #[test]
+    fn negative_xattr_skips_filesystem() {
+        let builder = SessionBuilder::new().negative_xattr("security.capability");
+        let requests = [getxattr_request(2, "security.capability"), getxattr_request(3, "user.foo")];
+        let replies = run(NoXattrFS, builder, &requests);
+        assert_eq!(reply_header(&replies[0]), (2, -ENOATTR));
+        assert_eq!(reply_header(&replies[1]), (3, -ENOATTR));
+        // Even for filesystems without xattr support
+        let builder = SessionBuilder::new().negative_xattr("security.capability");
+        let replies = run(NullFS, builder, &[getxattr_request(2, "security.capability")]);
+        assert_eq!(reply_header(&replies[0]), (2, -ENOATTR));
+    }
+
+    /// Filesystem that reads a fixed content and accepts any write
+    struct ReadWriteFS;
+
+    impl Filesystem for ReadWriteFS {
+        fn read(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, _size: u32, reply: crate::ReplyData) {
+            reply.data(b"hello");
+        }
+
+        fn write(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+            reply.written(data.len() as u32);
+        }
+    }
+
+    #[test]
+    fn run_summary() {
+        let requests = [
+            read_request(2, 4096),
+            write_request(3, b"content"),
+            write_request(4, b"more"),
+            getxattr_request(5, "user.foo"),
+        ];
+        let (replies, summary) = run_summarized(ReadWriteFS, SessionBuilder::new(), &requests, |_| ());
+        assert_eq!(replies.len(), 4);
+        // The init request is counted as well, unimplemented getxattr fails
+        assert_eq!(summary, RunSummary { requests_processed: 5, errors: 1, bytes_read: 5, bytes_written: 11, opened_existing: 0, capability_anomalies: 0, uptime: summary.uptime });
+    }
+
+    /// Filesystem with a file that appears after a failed lookup, like if another client
+    /// created it concurrently
+    struct RacingCreateFS {
+        create_flags: Arc<Mutex<Vec<u32>>>,
+    }
+
+    impl Filesystem for RacingCreateFS {
+        fn lookup(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: crate::ReplyEntry) {
+            reply.error(libc::ENOENT);
+        }
+
+        fn create(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, _mode: u32, flags: u32, reply: crate::ReplyCreate) {
+            self.create_flags.lock().unwrap().push(flags);
+            if flags as i32 & libc::O_EXCL != 0 {
+                return reply.error(libc::EEXIST);
+            }
+            let attr = crate::FileAttr::builder(2, crate::FileType::RegularFile).times(std::time::UNIX_EPOCH).build();
+            reply.opened_existing(&Duration::from_secs(1), &attr, 0, 1, 0);
+        }
+    }
+
+    #[test]
+    fn create_opened_existing() {
+        let create = |unique, flags: i32| {
+            let mut args = vec![0; mem::size_of::<fuse_create_in>()];
+            args[0..4].copy_from_slice(&(flags as u32).to_ne_bytes());
+            args.extend_from_slice(b"file\0");
+            request(fuse_opcode::FUSE_CREATE, unique, 1, &args)
+        };
+        let requests = [
+            request(fuse_opcode::FUSE_LOOKUP, 2, 1, b"file\0"),
+            create(3, libc::O_CREAT | libc::O_RDWR),
+            create(4, libc::O_CREAT | libc::O_EXCL | libc::O_RDWR),
+        ];
+        let create_flags = Arc::new(Mutex::new(Vec::new()));
+        let (replies, summary) = run_summarized(RacingCreateFS { create_flags: create_flags.clone() }, SessionBuilder::new(), &requests, |_| ());
+        let headers: Vec<_> = replies.iter().map(|reply| reply_header(reply)).collect();
+        assert_eq!(headers, [(2, -libc::ENOENT), (3, 0), (4, -libc::EEXIST)]);
+        // Opening the existing file replies the entry like creating it
+        assert_eq!(replies[1].len(), 16 + mem::size_of::<fuse_entry_out>() + mem::size_of::<fuse_open_out>());
+        assert_eq!(summary.opened_existing, 1);
+        // The flags are passed as requested, including O_EXCL
+        let flags: Vec<_> = create_flags.lock().unwrap().iter().map(|&flags| flags as i32 & (libc::O_CREAT | libc::O_EXCL)).collect();
+        assert_eq!(flags, [libc::O_CREAT, libc::O_CREAT | libc::O_EXCL]);
+    }
+
+    /// Filesystem that records the capabilities seen by init and getxattr
+    struct CapabilitiesFS {
+        seen: Arc<Mutex<Vec<ProtocolCapabilities>>>,
+    }
+
+    impl Filesystem for CapabilitiesFS {
+        fn init(&mut self, req: &Request<'_>) -> Result<crate::InitConfig, libc::c_int> {
+            self.seen.lock().unwrap().push(req.capabilities());
+            Ok(crate::InitConfig::default())
+        }
+
+        fn getxattr(&mut self, req: &Request<'_>, _ino: u64, _name: &OsStr, _size: u32, reply: ReplyXattr) {
+            self.seen.lock().unwrap().push(req.capabilities());
+            reply.error(ENOATTR);
+        }
+    }
+
+    #[test]
+    fn capabilities() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        run(CapabilitiesFS { seen: seen.clone() }, SessionBuilder::new(), &[getxattr_request(2, "user.foo")]);
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 2);
+        assert_eq!(seen[0].version(), (FUSE_KERNEL_VERSION, FUSE_KERNEL_MINOR_VERSION));
+        assert_eq!(seen[0].negotiated_flags(), consts::FUSE_ASYNC_READ);
+        assert_eq!(seen[1], seen[0]);
+    }
+
+    /// Filesystem that asks for the given init settings and records the capabilities seen by
+    /// getxattr
+    #[cfg(feature = "abi-7-23")]
+    struct ConfiguringFS {
+        config: crate::InitConfig,
+        seen: Arc<Mutex<Option<ProtocolCapabilities>>>,
+    }
+
+    #[cfg(feature = "abi-7-23")]
+    impl Filesystem for ConfiguringFS {
+        fn init(&mut self, _req: &Request<'_>) -> Result<crate::InitConfig, libc::c_int> {
+            Ok(self.config)
+        }
+
+        fn getxattr(&mut self, req: &Request<'_>, _ino: u64, _name: &OsStr, _size: u32, reply: ReplyXattr) {
+            *self.seen.lock().unwrap() = Some(req.capabilities());
+            reply.error(ENOATTR);
+        }
+    }
+
+    /// Returns the capabilities negotiated with the given init settings of the filesystem
+    #[cfg(feature = "abi-7-23")]
+    fn configured(config: crate::InitConfig) -> ProtocolCapabilities {
+        let seen = Arc::new(Mutex::new(None));
+        run(ConfiguringFS { config, seen: seen.clone() }, SessionBuilder::new(), &[getxattr_request(2, "user.foo")]);
+        let seen = seen.lock().unwrap();
+        seen.unwrap()
+    }
+
+    #[cfg(feature = "abi-7-23")]
+    #[test]
+    fn init_config() {
+        let capabilities = configured(crate::InitConfig::new());
+        assert_eq!((capabilities.negotiated_flags(), capabilities.max_readahead()), (consts::FUSE_ASYNC_READ, 128 * 1024));
+        // The kernel driver isn't capable of writeback caching, which is dropped
+        let config = crate::InitConfig::new().flags(consts::FUSE_BIG_WRITES | consts::FUSE_WRITEBACK_CACHE).max_readahead(4096);
+        let capabilities = configured(config);
+        assert_eq!((capabilities.negotiated_flags(), capabilities.max_readahead()), (consts::FUSE_ASYNC_READ | consts::FUSE_BIG_WRITES, 4096));
+        // Readahead isn't raised beyond the kernel driver's size
+        assert_eq!(configured(crate::InitConfig::new().max_readahead(1024 * 1024)).max_readahead(), 128 * 1024);
+    }
+
+    #[cfg(feature = "abi-7-13")]
+    #[test]
+    fn max_background() {
+        // The kernel driver's defaults
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        run(CapabilitiesFS { seen: seen.clone() }, SessionBuilder::new(), &[]);
+        let capabilities = seen.lock().unwrap()[0];
+        assert_eq!((capabilities.max_background(), capabilities.congestion_threshold()), (12, 9));
+        // Configured values are known to init already
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        run(CapabilitiesFS { seen: seen.clone() }, SessionBuilder::new().max_background(64).congestion_threshold(48), &[getxattr_request(2, "user.foo")]);
+        let seen = seen.lock().unwrap();
+        assert_eq!((seen[0].max_background(), seen[0].congestion_threshold()), (64, 48));
+        assert_eq!(seen[1], seen[0]);
+    }
+
+    /// Filesystem that lists a file and an entry without lookup with readdirplus, and records
+    /// whether readdirplus was negotiated
+    #[cfg(feature = "abi-7-21")]
+    struct PlusFS {
+        negotiated: Arc<Mutex<Option<bool>>>,
+    }
+
+    #[cfg(feature = "abi-7-21")]
+    impl Filesystem for PlusFS {
+        fn init(&mut self, req: &Request<'_>) -> Result<crate::InitConfig, libc::c_int> {
+            *self.negotiated.lock().unwrap() = Some(req.capabilities().readdirplus());
+            Ok(crate::InitConfig::default())
+        }
+
+        fn readdirplus(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, offset: i64, mut reply: crate::ReplyDirectoryPlus) {
+            assert_eq!(offset, 0);
+            let ttl = Duration::from_secs(1);
+            let file = crate::FileAttr::builder(2, crate::FileType::RegularFile).build();
+            let other = crate::FileAttr::builder(3, crate::FileType::Directory).build();
+            reply.add(2, 1, "file", &ttl, &file, 0);
+            reply.add(0, 2, "other", &ttl, &other, 0);
+            reply.ok();
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-21")]
+    fn readdirplus() {
+        let mut args = vec![0; mem::size_of::<fuse_read_in>()];
+        args[16..20].copy_from_slice(&4096u32.to_ne_bytes());
+        let requests = [request(fuse_opcode::FUSE_READDIRPLUS, 2, 1, &args)];
+        let negotiated = Arc::new(Mutex::new(None));
+        let replies = run(PlusFS { negotiated: negotiated.clone() }, SessionBuilder::new().readdirplus(), &requests);
+        assert_eq!(*negotiated.lock().unwrap(), Some(true));
+        assert_eq!(reply_header(&replies[0]), (2, 0));
+        let u64_at = |pos: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&replies[0][pos..pos + 8]);
+            u64::from_ne_bytes(bytes)
+        };
+        // Entries: entry_out (nodeid first), dirent (ino, off, namelen, type), name, padding
+        let dirent = 16 + mem::size_of::<fuse_entry_out>();
+        let entsize = (mem::size_of::<fuse_direntplus>() + 4 + 7) & !7;
+        assert_eq!(replies[0].len(), 16 + entsize + ((mem::size_of::<fuse_direntplus>() + 5 + 7) & !7));
+        assert_eq!((u64_at(16), u64_at(dirent), u64_at(dirent + 8)), (2, 2, 1));
+        assert_eq!(&replies[0][dirent + 24..dirent + 28], b"file");
+        // The entry without lookup has no node id, but lists the inode number
+        assert_eq!((u64_at(16 + entsize), u64_at(dirent + entsize), u64_at(dirent + entsize + 8)), (0, 3, 2));
+        assert_eq!(&replies[0][dirent + entsize + 24..dirent + entsize + 29], b"other");
+        // Not negotiated unless asked for
+        let negotiated = Arc::new(Mutex::new(None));
+        run(PlusFS { negotiated: negotiated.clone() }, SessionBuilder::new(), &[]);
+        assert_eq!(*negotiated.lock().unwrap(), Some(false));
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-21")]
+    fn unexpected_readdirplus() {
+        let mut args = vec![0; mem::size_of::<fuse_read_in>()];
+        args[16..20].copy_from_slice(&4096u32.to_ne_bytes());
+        let requests = [request(fuse_opcode::FUSE_READDIRPLUS, 2, 1, &args)];
+        // Readdirplus wasn't negotiated, but the request is dispatched anyway
+        let negotiated = Arc::new(Mutex::new(None));
+        let (replies, summary) = run_summarized(PlusFS { negotiated: negotiated.clone() }, SessionBuilder::new().check_capabilities(), &requests, |_| ());
+        assert_eq!(*negotiated.lock().unwrap(), Some(false));
+        assert_eq!(reply_header(&replies[0]), (2, 0));
+        assert!(replies[0].len() > 16);
+        assert_eq!(summary.capability_anomalies, 1);
+        // Expected if negotiated, and not checked unless asked for
+        let (_, summary) = run_summarized(PlusFS { negotiated: Arc::default() }, SessionBuilder::new().readdirplus().check_capabilities(), &requests, |_| ());
+        assert_eq!(summary.capability_anomalies, 0);
+        let (_, summary) = run_summarized(PlusFS { negotiated: Arc::default() }, SessionBuilder::new(), &requests, |_| ());
+        assert_eq!(summary.capability_anomalies, 0);
+    }
+
+    /// Filesystem that records retrievals from the page cache and the retrieved data
+    #[cfg(feature = "abi-7-15")]
+    struct RetrieveFS {
+        retrieved: Arc<Mutex<Vec<(u64, u64, u64)>>>,
+        data: Arc<Mutex<Vec<u8>>>,
+    }
+
+    #[cfg(feature = "abi-7-15")]
+    impl Filesystem for RetrieveFS {
+        fn retrieve_reply(&mut self, _req: &Request<'_>, notify_unique: u64, ino: u64, offset: u64, data: &[u8]) {
+            self.retrieved.lock().unwrap().push((notify_unique, ino, offset));
+            self.data.lock().unwrap().extend_from_slice(data);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-15")]
+    fn retrieve_reply() {
+        let mut args = vec![0; mem::size_of::<fuse_notify_retrieve_in>()];
+        args[8..16].copy_from_slice(&0x1000u64.to_ne_bytes());
+        args[16..20].copy_from_slice(&5u32.to_ne_bytes());
+        args.extend_from_slice(b"hello");
+        let requests = [request(fuse_opcode::FUSE_NOTIFY_REPLY, 7, 0x22, &args)];
+        let (retrieved, data) = (Arc::new(Mutex::new(Vec::new())), Arc::new(Mutex::new(Vec::new())));
+        let replies = run(RetrieveFS { retrieved: retrieved.clone(), data: data.clone() }, SessionBuilder::new(), &requests);
+        assert_eq!(*retrieved.lock().unwrap(), [(7, 0x22, 0x1000)]);
+        assert_eq!(*data.lock().unwrap(), b"hello");
+        // Notify replies aren't replied to
+        assert!(replies.is_empty());
+    }
+
+    /// Filesystem that replies to reads asynchronously with `EINTR` once they're interrupted,
+    /// and records the interrupts it's told about
+    struct InterruptFS {
+        interrupts: Arc<Mutex<Vec<u64>>>,
+    }
+
+    impl Filesystem for InterruptFS {
+        fn interrupt(&mut self, _req: &Request<'_>, unique: u64) {
+            self.interrupts.lock().unwrap().push(unique);
+        }
+
+        fn read(&mut self, req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, _size: u32, reply: crate::ReplyData) {
+            let interrupted = req.interrupt_flag();
+            req.spawner().spawn(move |token| {
+                while !interrupted.is_interrupted() {
+                    if token.wait_timeout(Duration::from_millis(10)) {
+                        return reply.data(b"data");
+                    }
+                }
+                reply.error(libc::EINTR);
+            }).unwrap();
+        }
+    }
+
+    #[test]
+    fn interrupt() {
+        let interrupt = |unique, interrupted: u64| request(fuse_opcode::FUSE_INTERRUPT, unique, 0, &interrupted.to_ne_bytes());
+        let interrupts = Arc::new(Mutex::new(Vec::new()));
+        let requests = [read_request(2, 4096), interrupt(3, 2), interrupt(5, 4)];
+        let replies = run(InterruptFS { interrupts: interrupts.clone() }, SessionBuilder::new(), &requests);
+        assert_eq!(*interrupts.lock().unwrap(), [2]);
+        // Interrupts aren't replied to
+        assert_eq!(replies.len(), 1);
+        assert_eq!(reply_header(&replies[0]), (2, -libc::EINTR));
+        // An interrupt may arrive before the request it refers to
+        let interrupts = Arc::new(Mutex::new(Vec::new()));
+        let requests = [interrupt(3, 2), read_request(2, 4096)];
+        let replies = run(InterruptFS { interrupts: interrupts.clone() }, SessionBuilder::new(), &requests);
+        assert!(interrupts.lock().unwrap().is_empty());
+        assert_eq!(replies.len(), 1);
+        assert_eq!(reply_header(&replies[0]), (2, -libc::EINTR));
+        // Requests that aren't interrupted are replied normally
+        let replies = run(InterruptFS { interrupts: Arc::default() }, SessionBuilder::new(), &[read_request(2, 4096)]);
+        assert_eq!(&replies[0][16..], b"data");
+    }
+
+    /// Filesystem that records the mode of new directories, with the umask applied if the
+    /// kernel driver left it to the filesystem
+    #[cfg(feature = "abi-7-12")]
+    struct UmaskFS {
+        modes: Arc<Mutex<Vec<(u32, bool)>>>,
+    }
+
+    #[cfg(feature = "abi-7-12")]
+    impl Filesystem for UmaskFS {
+        fn mkdir_with_umask(&mut self, req: &Request<'_>, _parent: u64, _name: &OsStr, mode: crate::CreateMode, reply: crate::ReplyEntry) {
+            let dont_mask = req.capabilities().dont_mask();
+            let mode = if dont_mask { mode.masked() } else { mode.mode() };
+            self.modes.lock().unwrap().push((mode, dont_mask));
+            reply.error(libc::EROFS);
+        }
+    }
+
+    /// Run a mkdir request with the given permissions and umask 0o022, returns the mode the
+    /// filesystem would create the directory with and whether it applied the umask
+    #[cfg(feature = "abi-7-12")]
+    fn mkdir_mode(builder: SessionBuilder, mode: u32) -> (u32, bool) {
+        let mut args = vec![0; mem::size_of::<fuse_mkdir_in>()];
+        args[0..4].copy_from_slice(&(libc::S_IFDIR | mode).to_ne_bytes());
+        args[4..8].copy_from_slice(&0o022u32.to_ne_bytes());
+        args.extend_from_slice(b"d\0");
+        let modes = Arc::new(Mutex::new(Vec::new()));
+        run(UmaskFS { modes: modes.clone() }, builder, &[request(fuse_opcode::FUSE_MKDIR, 2, 1, &args)]);
+        let modes = modes.lock().unwrap();
+        modes[0]
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-12")]
+    fn umask() {
+        // The kernel driver applied the umask already (the mode is as it would send it)
+        assert_eq!(mkdir_mode(SessionBuilder::new(), 0o755), (libc::S_IFDIR | 0o755, false));
+        // The filesystem applies it
+        assert_eq!(mkdir_mode(SessionBuilder::new().dont_mask(), 0o777), (libc::S_IFDIR | 0o755, true));
+        assert_eq!(crate::CreateMode::new(libc::S_IFREG | 0o666, 0o027).masked(), libc::S_IFREG | 0o640);
+    }
+
+    /// Filesystem that records the attribute changes of setattr requests
+    struct SetattrFS {
+        changes: Arc<Mutex<Vec<crate::SetattrChanges>>>,
+    }
+
+    impl Filesystem for SetattrFS {
+        fn setattr_changes(&mut self, _req: &Request<'_>, _ino: u64, changes: crate::SetattrChanges, reply: crate::ReplyAttr) {
+            self.changes.lock().unwrap().push(changes);
+            reply.error(libc::EROFS);
+        }
+    }
+
+    #[test]
+    fn setattr_changes() {
+        // open(O_TRUNC) of a file that is made read-only at the same time
+        let mut args = vec![0; mem::size_of::<fuse_setattr_in>()];
+        args[0..4].copy_from_slice(&(consts::FATTR_MODE | consts::FATTR_SIZE | consts::FATTR_FH).to_ne_bytes());
+        args[8..16].copy_from_slice(&7u64.to_ne_bytes());
+        args[16..24].copy_from_slice(&0u64.to_ne_bytes());
+        args[68..72].copy_from_slice(&(libc::S_IFREG | 0o444).to_ne_bytes());
+        args[76..80].copy_from_slice(&1000u32.to_ne_bytes());
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        run(SetattrFS { changes: changes.clone() }, SessionBuilder::new(), &[request(fuse_opcode::FUSE_SETATTR, 2, 2, &args)]);
+        let changes = changes.lock().unwrap();
+        assert_eq!((changes[0].mode(), changes[0].size(), changes[0].fh()), (Some(libc::S_IFREG | 0o444), Some(0), Some(7)));
+        // Values of attributes without valid bits are ignored
+        assert_eq!((changes[0].uid(), changes[0].mtime()), (None, None));
+        assert_eq!(changes[0].apply_order(), [crate::SetattrStep::Size, crate::SetattrStep::Mode]);
+        assert_eq!(changes[0].truncate(4096), crate::TruncateEffect::DiscardFrom(0));
+    }
+
+    /// Filesystem that records the raw flags of fsync requests and the datasync parameter of
+    /// fsyncdir requests
+    struct FsyncFS {
+        syncs: Arc<Mutex<Vec<(&'static str, u32)>>>,
+    }
+
+    impl Filesystem for FsyncFS {
+        fn fsync_with_flags(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, fsync_flags: u32, reply: ReplyEmpty) {
+            self.syncs.lock().unwrap().push(("fsync", fsync_flags));
+            reply.ok();
+        }
+
+        fn fsyncdir(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, datasync: bool, reply: ReplyEmpty) {
+            self.syncs.lock().unwrap().push(("fsyncdir", datasync as u32));
+            reply.ok();
+        }
+    }
+
+    #[test]
+    fn fsync_flags() {
+        let fsync = |opcode, unique, flags: u32| {
+            let mut args = vec![0; mem::size_of::<fuse_fsync_in>()];
+            args[8..12].copy_from_slice(&flags.to_ne_bytes());
+            request(opcode, unique, 2, &args)
+        };
+        let requests = [
+            // Unknown flags are passed through along with datasync
+            fsync(fuse_opcode::FUSE_FSYNC, 2, consts::FUSE_FSYNC_FDATASYNC | 0x100),
+            // By default, only datasync is passed on
+            fsync(fuse_opcode::FUSE_FSYNCDIR, 3, consts::FUSE_FSYNC_FDATASYNC | 0x100),
+            fsync(fuse_opcode::FUSE_FSYNCDIR, 4, 0x100),
+        ];
+        let syncs = Arc::new(Mutex::new(Vec::new()));
+        let replies = run(FsyncFS { syncs: syncs.clone() }, SessionBuilder::new(), &requests);
+        assert_eq!(replies.len(), 3);
+        assert_eq!(*syncs.lock().unwrap(), [("fsync", 0x101), ("fsyncdir", 1), ("fsyncdir", 0)]);
+    }
+
+    /// Filesystem that records fallocate requests
+    #[cfg(feature = "abi-7-19")]
+    struct FallocateFS {
+        calls: Arc<Mutex<Vec<(i64, i64, i32)>>>,
+    }
+
+    #[cfg(feature = "abi-7-19")]
+    impl Filesystem for FallocateFS {
+        fn fallocate(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, fallocate: crate::FallocateRequest, reply: ReplyEmpty) {
+            assert_eq!(fh, 7);
+            self.calls.lock().unwrap().push((fallocate.offset(), fallocate.length(), fallocate.mode()));
+            reply.ok();
+        }
+    }
+
+    #[cfg(feature = "abi-7-19")]
+    #[test]
+    fn fallocate() {
+        let fallocate = |unique, offset: u64, length: u64, mode: i32| {
+            let mut args = vec![0; mem::size_of::<fuse_fallocate_in>()];
+            args[0..8].copy_from_slice(&7u64.to_ne_bytes());
+            args[8..16].copy_from_slice(&offset.to_ne_bytes());
+            args[16..24].copy_from_slice(&length.to_ne_bytes());
+            args[24..28].copy_from_slice(&(mode as u32).to_ne_bytes());
+            request(fuse_opcode::FUSE_FALLOCATE, unique, 2, &args)
+        };
+        // Modes are passed through untouched
+        let punch_hole = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        let requests = [fallocate(2, 4096, 1 << 20, libc::FALLOC_FL_KEEP_SIZE), fallocate(3, 0, 4096, punch_hole), fallocate(4, 0, 1 << 20, 0)];
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let replies = run(FallocateFS { calls: calls.clone() }, SessionBuilder::new(), &requests);
+        assert!(replies.iter().zip(2..).all(|(reply, unique)| reply_header(reply) == (unique, 0)));
+        assert_eq!(*calls.lock().unwrap(), [(4096, 1 << 20, libc::FALLOC_FL_KEEP_SIZE), (0, 4096, punch_hole), (0, 1 << 20, 0)]);
+        // Filesystems that don't implement it fail it like before
+        let replies = run(NullFS, SessionBuilder::new(), &requests[..1]);
+        assert_eq!(reply_header(&replies[0]), (2, -libc::ENOSYS));
+    }
+
+    /// Filesystem whose files have data only in their first 4096 bytes
+    #[cfg(feature = "abi-7-24")]
+    struct SparseFS;
+
+    #[cfg(feature = "abi-7-24")]
+    impl Filesystem for SparseFS {
+        fn lseek(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, offset: i64, whence: i32, reply: crate::ReplyLseek) {
+            match whence {
+                libc::SEEK_DATA if offset < 4096 => reply.offset(offset),
+                libc::SEEK_HOLE => reply.offset(offset.max(4096)),
+                _ => reply.error(libc::ENXIO),
+            }
+        }
+    }
+
+    #[cfg(feature = "abi-7-24")]
+    #[test]
+    fn lseek() {
+        let lseek = |unique, offset: u64, whence: i32| {
+            let mut args = vec![0; mem::size_of::<fuse_lseek_in>()];
+            args[8..16].copy_from_slice(&offset.to_ne_bytes());
+            args[16..20].copy_from_slice(&(whence as u32).to_ne_bytes());
+            request(fuse_opcode::FUSE_LSEEK, unique, 2, &args)
+        };
+        let requests = [lseek(2, 100, libc::SEEK_HOLE), lseek(3, 100, libc::SEEK_DATA), lseek(4, 8192, libc::SEEK_DATA)];
+        let replies = run(SparseFS, SessionBuilder::new(), &requests);
+        assert_eq!(reply_header(&replies[0]), (2, 0));
+        assert_eq!(replies[0][16..], 4096u64.to_ne_bytes());
+        assert_eq!(replies[1][16..], 100u64.to_ne_bytes());
+        assert_eq!(reply_header(&replies[2]), (4, -libc::ENXIO));
+    }
+
+    /// Device-like filesystem whose only ioctl returns its input in reverse
+    #[cfg(feature = "abi-7-11")]
+    struct ReversingFS {
+        calls: Arc<Mutex<Vec<(u64, u32, bool)>>>,
+    }
+
+    #[cfg(feature = "abi-7-11")]
+    impl Filesystem for ReversingFS {
+        fn ioctl(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, ioctl: crate::IoctlRequest<'_>, reply: crate::ReplyIoctl) {
+            self.calls.lock().unwrap().push((ino, ioctl.out_size(), ioctl.flags().is_dir()));
+            let in_data = ioctl.in_data();
+            match ioctl.cmd() {
+                1 => reply.ioctl(in_data.len() as i32, &in_data.iter().rev().cloned().collect::<Vec<_>>()),
+                _ => reply.error(libc::ENOTTY),
+            }
+        }
+    }
+
+    #[cfg(feature = "abi-7-11")]
+    #[test]
+    fn ioctl() {
+        let ioctl = |unique, ino, flags: u32, cmd: u32, data: &[u8]| {
+            let mut args = vec![0; mem::size_of::<fuse_ioctl_in>()];
+            args[8..12].copy_from_slice(&flags.to_ne_bytes());
+            args[12..16].copy_from_slice(&cmd.to_ne_bytes());
+            args[24..28].copy_from_slice(&(data.len() as u32).to_ne_bytes());
+            args[28..32].copy_from_slice(&8u32.to_ne_bytes());
+            args.extend_from_slice(data);
+            request(fuse_opcode::FUSE_IOCTL, unique, ino, &args)
+        };
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let requests = [ioctl(2, 2, 0, 1, b"abc"), ioctl(3, 2, 0, 2, b""), ioctl(4, 1, 1 << 4, 1, b"")];
+        let replies = run(ReversingFS { calls: calls.clone() }, SessionBuilder::new(), &requests);
+        assert_eq!(reply_header(&replies[0]), (2, 0));
+        assert_eq!(replies[0][16..20], 3i32.to_ne_bytes());
+        assert_eq!(replies[0][32..], *b"cba");
+        assert_eq!(reply_header(&replies[1]), (3, -libc::ENOTTY));
+        // Ioctls on directories are delivered the same way
+        assert_eq!(reply_header(&replies[2]), (4, 0));
+        assert_eq!(replies[2].len(), 32);
+        assert_eq!(*calls.lock().unwrap(), [(2, 8, false), (2, 8, false), (1, 8, true)]);
+        // Filesystems that don't implement it fail it like before
+        let replies = run(NullFS, SessionBuilder::new(), &requests[..1]);
+        assert_eq!(reply_header(&replies[0]), (2, -libc::ENOSYS));
+    }
+
+    /// Filesystem with files owned by uid 1000 that records calls which may clear privileges
+    struct PrivilegedFS {
+        calls: Arc<Mutex<Vec<(&'static str, u64)>>>,
+    }
+
+    impl Filesystem for PrivilegedFS {
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: crate::ReplyAttr) {
+            let attr = crate::FileAttr::builder(ino, crate::FileType::RegularFile).uid(1000).times(std::time::UNIX_EPOCH).build();
+            reply.attr(&Duration::from_secs(60), &attr);
+        }
+
+        fn write(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, _offset: i64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+            self.calls.lock().unwrap().push(("write", ino));
+            reply.written(data.len() as u32);
+        }
+
+        fn setattr_changes(&mut self, _req: &Request<'_>, ino: u64, _changes: crate::SetattrChanges, reply: crate::ReplyAttr) {
+            self.calls.lock().unwrap().push(("setattr", ino));
+            reply.error(libc::EROFS);
+        }
+
+        fn setxattr(&mut self, _req: &Request<'_>, ino: u64, _name: &OsStr, _value: &[u8], _flags: u32, _position: u32, reply: crate::ReplyEmpty) {
+            self.calls.lock().unwrap().push(("setxattr", ino));
+            reply.ok();
+        }
+
+        fn removexattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, reply: crate::ReplyEmpty) {
+            assert_eq!(name, "security.capability");
+            self.calls.lock().unwrap().push(("removexattr", ino));
+            reply.error(ENOATTR);
+        }
+    }
+
+    /// Build a raw setattr request that changes the given attributes of the given inode
+    fn setattr_request(unique: u64, nodeid: u64, valid: u32) -> Vec<u8> {
+        let mut args = vec![0; mem::size_of::<fuse_setattr_in>()];
+        args[0..4].copy_from_slice(&valid.to_ne_bytes());
+        request(fuse_opcode::FUSE_SETATTR, unique, nodeid, &args)
+    }
+
+    /// Run the given requests with the given builder, returns the replies and the recorded
+    /// calls of the filesystem
+    fn privileged(builder: SessionBuilder, requests: &[Vec<u8>]) -> (Vec<Vec<u8>>, Vec<(&'static str, u64)>) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let replies = run(PrivilegedFS { calls: calls.clone() }, builder, requests);
+        let calls = calls.lock().unwrap().clone();
+        (replies, calls)
+    }
+
+    #[test]
+    fn kill_capabilities() {
+        let mut setxattr = vec![0; mem::size_of::<fuse_setxattr_in>()];
+        setxattr.extend_from_slice(b"security.capability\0");
+        let requests = [
+            request(fuse_opcode::FUSE_GETATTR, 2, 2, &[]),
+            // Writes by the owner keep capabilities
+            as_user(write_request(3, b"data"), 1000, 1000),
+            // Writes by others remove them before the write, once until they're set again
+            as_user(write_request(4, b"data"), 1001, 1001),
+            as_user(write_request(5, b"data"), 1001, 1001),
+            request(fuse_opcode::FUSE_SETXATTR, 6, 2, &setxattr),
+            as_user(write_request(7, b"data"), 0, 0),
+            // Truncating removes them too, even of files with unknown owners
+            setattr_request(8, 3, consts::FATTR_MODE),
+            setattr_request(9, 3, consts::FATTR_SIZE | consts::FATTR_MODE),
+        ];
+        let (replies, calls) = privileged(SessionBuilder::new().safe_defaults(), &requests);
+        assert_eq!(calls, [
+            ("write", 2), ("removexattr", 2), ("write", 2), ("write", 2), ("setxattr", 2), ("removexattr", 2), ("write", 2),
+            ("setattr", 3), ("removexattr", 3), ("setattr", 3),
+        ]);
+        // Only the requested replies are sent
+        let uniques: Vec<_> = replies.iter().map(|reply| reply_header(reply).0).collect();
+        assert_eq!(uniques, [2, 3, 4, 5, 6, 7, 8, 9]);
+        // Without safe defaults, capabilities are left to the filesystem
+        let (_, calls) = privileged(SessionBuilder::new(), &requests);
+        assert!(!calls.contains(&("removexattr", 2)) && !calls.contains(&("removexattr", 3)));
+    }
+
+    #[test]
+    #[cfg(all(feature = "abi-7-33", not(target_os = "macos")))]
+    fn kill_capabilities_flagged_by_kernel() {
+        let requests = [as_user(write_request(2, b"data"), 1001, 1001), setattr_request(3, 2, consts::FATTR_SIZE)];
+        let (_, calls) = privileged(SessionBuilder::new().safe_defaults().handle_killpriv_v2(), &requests);
+        assert_eq!(calls, [("write", 2), ("setattr", 2)]);
+    }
+
+    /// Filesystem that replies to lookup and getattr with the default TTL
+    struct DefaultTtlFS;
+
+    impl Filesystem for DefaultTtlFS {
+        fn lookup(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: crate::ReplyEntry) {
+            reply.entry_default(&crate::FileAttr::builder(2, crate::FileType::RegularFile).times(std::time::UNIX_EPOCH).build(), 0);
+        }
+
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: crate::ReplyAttr) {
+            reply.attr_default(&crate::FileAttr::builder(ino, crate::FileType::RegularFile).times(std::time::UNIX_EPOCH).build());
+        }
+    }
+
+    /// Returns the entry and attribute TTLs (in seconds) of lookup and getattr replies of a
+    /// `DefaultTtlFS` run with the given builder
+    fn default_ttls(builder: SessionBuilder) -> (u64, u64, u64) {
+        let requests = [request(fuse_opcode::FUSE_LOOKUP, 2, 1, b"file\0"), request(fuse_opcode::FUSE_GETATTR, 3, 2, &[])];
+        let replies = run(DefaultTtlFS, builder, &requests);
+        // The TTLs follow the header, and the node id and generation of entries
+        let secs = |reply: &[u8], offset: usize| {
+            let mut secs = [0; 8];
+            secs.copy_from_slice(&reply[16 + offset..24 + offset]);
+            u64::from_ne_bytes(secs)
+        };
+        (secs(&replies[0], 16), secs(&replies[0], 24), secs(&replies[1], 0))
+    }
+
+    #[test]
+    fn default_ttl() {
+        assert_eq!(default_ttls(SessionBuilder::new()), (1, 1, 1));
+        assert_eq!(default_ttls(SessionBuilder::new().default_ttl(Duration::from_secs(30))), (30, 30, 30));
+    }
+
+    /// Filesystem that lists two xattrs with their values and counts getxattr calls
+    struct ListingFS {
+        gets: Arc<Mutex<usize>>,
+    }
+
+    impl Filesystem for ListingFS {
+        fn getxattr(&mut self, _req: &Request<'_>, _ino: u64, _name: &OsStr, _size: u32, reply: ReplyXattr) {
+            *self.gets.lock().unwrap() += 1;
+            reply.error(ENOATTR);
+        }
+
+        fn listxattr(&mut self, _req: &Request<'_>, _ino: u64, size: u32, reply: ReplyXattr) {
+            let mut list = crate::XAttrList::new();
+            list.add("user.a", b"1");
+            list.add("user.b", b"22");
+            reply.list(size, list);
+        }
+
+        fn removexattr(&mut self, _req: &Request<'_>, _ino: u64, _name: &OsStr, reply: crate::ReplyEmpty) {
+            reply.ok();
+        }
+    }
+
+    /// Run a listxattr size probe followed by getxattr requests, returns the replies and the
+    /// number of getxattr calls that reached the filesystem
+    fn list_and_get(builder: SessionBuilder) -> (Vec<Vec<u8>>, usize) {
+        let sized = |mut req: Vec<u8>, size: u32| {
+            req[40..44].copy_from_slice(&size.to_ne_bytes());
+            req
+        };
+        let requests = [
+            request(fuse_opcode::FUSE_LISTXATTR, 2, 1, &[0; 8]),
+            getxattr_request(3, "user.a"),
+            sized(getxattr_request(4, "user.b"), 64),
+            getxattr_request(5, "user.c"),
+            request(fuse_opcode::FUSE_REMOVEXATTR, 6, 1, b"user.a\0"),
+            getxattr_request(7, "user.b"),
+        ];
+        let gets = Arc::new(Mutex::new(0));
+        let replies = run(ListingFS { gets: gets.clone() }, builder, &requests);
+        let gets = *gets.lock().unwrap();
+        (replies, gets)
+    }
+
+    #[test]
+    fn cached_xattrs() {
+        let (replies, gets) = list_and_get(SessionBuilder::new().cache_xattrs(Duration::from_secs(60)));
+        let headers: Vec<_> = replies.iter().map(|reply| reply_header(reply)).collect();
+        assert_eq!(headers, [(2, 0), (3, 0), (4, 0), (5, -ENOATTR), (6, 0), (7, -ENOATTR)]);
+        // The size of the names, and the size and value of listed xattrs
+        assert_eq!(replies[0][16..20], 14u32.to_ne_bytes());
+        assert_eq!(replies[1][16..20], 1u32.to_ne_bytes());
+        assert_eq!(&replies[2][16..], b"22");
+        // Removing an xattr forgets the listed values of the inode
+        assert_eq!(gets, 1);
+        // Without cache, every getxattr reaches the filesystem
+        let (_, gets) = list_and_get(SessionBuilder::new());
+        assert_eq!(gets, 4);
+    }
+
+    /// Build a raw read request of the given size
+    pub fn read_request(unique: u64, size: u32) -> Vec<u8> {
+        let mut args = vec![0; mem::size_of::<fuse_read_in>()];
+        args[16..20].copy_from_slice(&size.to_ne_bytes());
+        request(fuse_opcode::FUSE_READ, unique, 2, &args)
+    }
+
+    /// Build a raw write request
+    pub fn write_request(unique: u64, data: &[u8]) -> Vec<u8> {
+        let mut args = vec![0; mem::size_of::<fuse_write_in>()];
+        args[16..20].copy_from_slice(&(data.len() as u32).to_ne_bytes());
+        args.extend_from_slice(data);
+        request(fuse_opcode::FUSE_WRITE, unique, 2, &args)
+    }
+
+    /// Filesystem that keeps the written data
+    struct RetainFS {
+        payloads: Arc<Mutex<Vec<BytesBuffer>>>,
+    }
+
+    impl Filesystem for RetainFS {
+        fn write(&mut self, req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+            let payload = req.take_write_payload().unwrap();
+            assert_eq!(&*payload, data);
+            reply.written(payload.len() as u32);
+            self.payloads.lock().unwrap().push(payload);
+        }
+
+        fn getxattr(&mut self, req: &Request<'_>, _ino: u64, _name: &OsStr, _size: u32, reply: ReplyXattr) {
+            assert!(req.take_write_payload().is_none());
+            reply.error(ENOATTR);
+        }
+    }
+
+    #[test]
+    fn take_write_payload() {
+        let payloads = Arc::new(Mutex::new(Vec::new()));
+        let fs = RetainFS { payloads: payloads.clone() };
+        let builder = SessionBuilder::new().max_write_payloads(2);
+        let requests = [
+            write_request(2, b"first"),
+            getxattr_request(3, "user.foo"),
+            write_request(4, b"second"),
+            write_request(5, b"third"),
+            getxattr_request(6, "user.bar"),
+        ];
+        let replies = run(fs, builder, &requests);
+        let headers: Vec<_> = replies.iter().map(|reply| reply_header(reply)).collect();
+        assert_eq!(headers, [(2, 0), (3, -ENOATTR), (4, 0), (5, 0), (6, -ENOATTR)]);
+        let payloads = payloads.lock().unwrap();
+        let data: Vec<&[u8]> = payloads.iter().map(|p| &**p).collect();
+        assert_eq!(data, [&b"first"[..], &b"second"[..], &b"third"[..]]);
+        // Payloads beyond the limit are copied
+        let copied: Vec<_> = payloads.iter().map(BytesBuffer::is_copied).collect();
+        assert_eq!(copied, [false, false, true]);
+    }
+
+    /// Filesystem that records whether writes were initiated by the kernel
+    struct WriteSourceFS {
+        sources: Arc<Mutex<Vec<bool>>>,
+    }
+
+    impl Filesystem for WriteSourceFS {
+        fn write(&mut self, req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+            self.sources.lock().unwrap().push(req.is_kernel_initiated());
+            reply.written(data.len() as u32);
+        }
+    }
+
+    #[test]
+    fn kernel_initiated_write() {
+        let sources = Arc::new(Mutex::new(Vec::new()));
+        let fs = WriteSourceFS { sources: sources.clone() };
+        let mut cached = write_request(3, b"cached");
+        // write_flags of fuse_write_in (after the header of 40 bytes)
+        cached[60..64].copy_from_slice(&1u32.to_ne_bytes());
+        let replies = run(fs, SessionBuilder::new(), &[write_request(2, b"direct"), cached]);
+        assert_eq!(replies.len(), 2);
+        // Without ABI 7.9, there's no FUSE_WRITE_CACHE flag
+        assert_eq!(*sources.lock().unwrap(), [false, cfg!(feature = "abi-7-9")]);
+    }
+
+    /// Filesystem that replies to getattr asynchronously, the later the lower the inode number
+    struct SlowFS;
+
+    impl Filesystem for SlowFS {
+        fn getattr(&mut self, req: &Request<'_>, ino: u64, reply: crate::ReplyAttr) {
+            req.spawner().spawn(move |_| {
+                thread::sleep(Duration::from_millis(20 * (6 - ino)));
+                reply.attr(&Duration::from_secs(1), &crate::FileAttr::builder(ino, crate::FileType::RegularFile).times(std::time::UNIX_EPOCH).build());
+            }).unwrap();
+        }
+
+        fn forget(&mut self, _req: &Request<'_>, _ino: u64, _nlookup: u64) {}
+    }
+
+    #[test]
+    fn ordered_replies() {
+        let mut requests: Vec<_> = (2..6).map(|ino| request(fuse_opcode::FUSE_GETATTR, ino, ino, &[])).collect();
+        // Requests without a reply don't hold back later replies
+        requests.insert(2, request(fuse_opcode::FUSE_FORGET, 10, 2, &1u64.to_ne_bytes()));
+        requests.push(request(fuse_opcode::FUSE_GETXATTR, 6, 1, &[0; 9]));
+        let replies = run(SlowFS, SessionBuilder::new().ordered_replies(true), &requests);
+        let headers: Vec<_> = replies.iter().map(|reply| reply_header(reply)).collect();
+        assert_eq!(headers, [(2, 0), (3, 0), (4, 0), (5, 0), (6, -libc::ENOSYS)]);
+    }
+
+    /// Filesystem that records calls of its destroy method
+    struct DestroyFS {
+        reasons: Arc<Mutex<Vec<ShutdownReason>>>,
+    }
+
+    impl Filesystem for DestroyFS {
+        fn destroy(&mut self, reason: ShutdownReason) {
+            self.reasons.lock().unwrap().push(reason);
+        }
+    }
+
+    /// Run a session for a `DestroyFS` and return the reasons its destroy method was called with
+    fn destroy_reasons<F: FnOnce(&mut Session<DestroyFS>)>(requests: &[Vec<u8>], setup: F) -> (Vec<ShutdownReason>, Vec<Vec<u8>>) {
+        let reasons = Arc::new(Mutex::new(Vec::new()));
+        let fs = DestroyFS { reasons: reasons.clone() };
+        let replies = run_with(fs, SessionBuilder::new(), requests, setup);
+        let reasons = reasons.lock().unwrap().clone();
+        (reasons, replies)
+    }
+
+    #[test]
+    fn destroy_by_kernel() {
+        let requests = [request(fuse_opcode::FUSE_DESTROY, 2, 0, &[]), getxattr_request(3, "user.foo")];
+        let (reasons, replies) = destroy_reasons(&requests, |_| ());
+        // Not called again when the connection is closed afterwards
+        assert_eq!(reasons, [ShutdownReason::KernelDestroy]);
+        let headers: Vec<_> = replies.iter().map(|reply| reply_header(reply)).collect();
+        assert_eq!(headers, [(2, 0), (3, -libc::EIO)]);
+    }
+
+    #[test]
+    fn destroy_on_device_closed() {
+        let (reasons, _) = destroy_reasons(&[getxattr_request(2, "user.foo")], |_| ());
+        assert_eq!(reasons, [ShutdownReason::DeviceClosed]);
+    }
+
+    /// Filesystem whose reply to getattr fails as if the filesystem was unmounted meanwhile
+    struct UnmountedFS {
+        outstanding: Option<Arc<OutstandingReplies>>,
+        reasons: Arc<Mutex<Vec<ShutdownReason>>>,
+    }
+
+    impl Filesystem for UnmountedFS {
+        fn getattr(&mut self, _req: &Request<'_>, _ino: u64, reply: crate::ReplyAttr) {
+            self.outstanding.as_ref().unwrap().sent(Err(std::io::Error::from_raw_os_error(libc::ENODEV)));
+            reply.error(libc::EIO);
+        }
+
+        fn destroy(&mut self, reason: ShutdownReason) {
+            self.reasons.lock().unwrap().push(reason);
+        }
+    }
+
+    #[test]
+    fn destroy_on_unmounted_while_replying() {
+        let reasons = Arc::new(Mutex::new(Vec::new()));
+        let fs = UnmountedFS { outstanding: None, reasons: reasons.clone() };
+        let requests = [request(fuse_opcode::FUSE_GETATTR, 2, 1, &[]), getxattr_request(3, "user.foo")];
+        let (replies, summary) = run_summarized(fs, SessionBuilder::new(), &requests, |se| {
+            se.filesystem.outstanding = Some(se.outstanding().clone());
+        });
+        // The session ends without dispatching the next request
+        assert_eq!(replies.len(), 1);
+        assert_eq!(summary.requests_processed, 2);
+        assert_eq!(*reasons.lock().unwrap(), [ShutdownReason::DeviceClosed]);
+    }
+
+    #[test]
+    fn destroy_on_loop_error() {
+        let (reasons, _) = destroy_reasons(&[vec![1, 2, 3]], |_| ());
+        assert_eq!(reasons, [ShutdownReason::LoopError]);
+    }
+
+    #[test]
+    fn destroy_on_user_unmount() {
+        // What dropping a background session does before unmounting
+        let (reasons, _) = destroy_reasons(&[], |se| se.request_unmount());
+        assert_eq!(reasons, [ShutdownReason::UserRequested]);
+    }
+
+    /// Filesystem that records when it's destroyed and dropped, and whether the channel to the
+    /// kernel driver was closed by the time it's dropped
+    struct DroppedFS {
+        events: Arc<Mutex<Vec<&'static str>>>,
+        kernel: UnixDatagram,
+    }
+
+    impl Filesystem for DroppedFS {
+        fn destroy(&mut self, _reason: ShutdownReason) {
+            self.events.lock().unwrap().push("destroy");
+        }
+    }
+
+    impl Drop for DroppedFS {
+        fn drop(&mut self) {
+            let closed = self.kernel.send(&[0]).is_err();
+            self.events.lock().unwrap().push(if closed { "drop after close" } else { "drop before close" });
+        }
+    }
+
+    #[test]
+    fn drop_after_unmount() {
+        let (kernel, session) = UnixDatagram::pair().unwrap();
+        kernel.send(&init_request()).unwrap();
+        kernel.send(&[]).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let fs = DroppedFS { events: events.clone(), kernel: kernel.try_clone().unwrap() };
+        let mut se = Session::with_channel(fs, Channel::from_fd(session.into_raw_fd(), Path::new("/test")));
+        se.run().unwrap();
+        assert_eq!(*events.lock().unwrap(), ["destroy"]);
+        drop(se);
+        assert_eq!(*events.lock().unwrap(), ["destroy", "drop after close"]);
+    }
+
+    #[test]
+    fn run_until_shutdown_fd() {
+        let mut pipe = [0; 2];
+        assert_eq!(unsafe { libc::pipe(pipe.as_mut_ptr()) }, 0);
+        let (kernel, session) = UnixDatagram::pair().unwrap();
+        kernel.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        kernel.send(&init_request()).unwrap();
+        let reasons = Arc::new(Mutex::new(Vec::new()));
+        let fs = DestroyFS { reasons: reasons.clone() };
+        let se = Session::with_channel(fs, Channel::from_fd(session.into_raw_fd(), Path::new("/test")));
+        let shutdown = pipe[0];
+        let thread = thread::spawn(move || se.run_until(shutdown));
+        // Requests are dispatched until the shutdown fd becomes readable
+        let mut buf = vec![0; 4096];
+        let n = kernel.recv(&mut buf).unwrap();
+        assert_eq!(reply_header(&buf[..n]), (1, 0));
+        kernel.send(&getxattr_request(2, "user.foo")).unwrap();
+        let n = kernel.recv(&mut buf).unwrap();
+        assert_eq!(reply_header(&buf[..n]), (2, -libc::ENOSYS));
+        assert_eq!(unsafe { libc::write(pipe[1], b"x".as_ptr() as *const libc::c_void, 1) }, 1);
+        thread.join().unwrap().unwrap();
+        assert_eq!(*reasons.lock().unwrap(), [ShutdownReason::UserRequested]);
+        // The shutdown fd is still open and wasn't read from
+        let mut byte = 0u8;
+        assert_eq!(unsafe { libc::read(pipe[0], &mut byte as *mut u8 as *mut libc::c_void, 1) }, 1);
+        unsafe {
+            libc::close(pipe[0]);
+            libc::close(pipe[1]);
+        }
+    }
+
+    /// Filesystem that records the order of dispatched writes and getattrs
+    struct OrderFS {
+        order: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl Filesystem for OrderFS {
+        fn getattr(&mut self, _req: &Request<'_>, _ino: u64, reply: crate::ReplyAttr) {
+            self.order.lock().unwrap().push("getattr");
+            reply.error(libc::ENOENT);
+        }
+
+        fn write(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+            self.order.lock().unwrap().push("write");
+            reply.written(data.len() as u32);
+        }
+    }
+
+    /// Run a burst of writes followed by getattrs, returns the dispatch order and statistics
+    fn write_burst(builder: SessionBuilder) -> (Vec<&'static str>, Option<crate::DispatchStats>) {
+        let mut requests: Vec<_> = (0..16).map(|i| write_request(2 + i, &[0; 4096])).collect();
+        requests.extend((0..4).map(|i| request(fuse_opcode::FUSE_GETATTR, 20 + i, 2, &[])));
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut stats = None;
+        let replies = run_with(OrderFS { order: order.clone() }, builder, &requests, |se| stats = se.dispatch_stats());
+        assert_eq!(replies.len(), 20);
+        let order = order.lock().unwrap().clone();
+        (order, stats)
+    }
+
+    #[test]
+    fn fair_dispatch() {
+        // Without fair dispatch, requests are dispatched in order
+        let (order, stats) = write_burst(SessionBuilder::new());
+        assert_eq!(order.iter().position(|&op| op == "getattr"), Some(16));
+        assert!(stats.is_none());
+        // With it, getattrs don't wait for all writes
+        let builder = SessionBuilder::new().fair_dispatch(crate::FairDispatch::new().quantum(4096));
+        let (order, stats) = write_burst(builder);
+        assert!(order.iter().position(|&op| op == "getattr").unwrap() < 4, "{:?}", order);
+        assert_eq!(order.iter().filter(|&&op| op == "write").count(), 16);
+        let stats = stats.unwrap();
+        assert_eq!(stats.class(crate::RequestClass::Data).dispatched, 16);
+        // Including the init request
+        assert_eq!(stats.class(crate::RequestClass::Metadata).dispatched, 5);
+        assert_eq!(stats.class(crate::RequestClass::Data).queued, 0);
+    }
+
+    /// Filesystem that records calls of its pre_unmount and destroy methods
+    struct FlushFS {
+        calls: Arc<Mutex<Vec<&'static str>>>,
+        result: Result<(), libc::c_int>,
+    }
+
+    impl Filesystem for FlushFS {
+        fn pre_unmount(&mut self) -> Result<(), libc::c_int> {
+            self.calls.lock().unwrap().push("pre_unmount");
+            self.result
+        }
+
+        fn destroy(&mut self, _reason: ShutdownReason) {
+            self.calls.lock().unwrap().push("destroy");
+        }
+    }
+
+    #[test]
+    fn pre_unmount_before_destroy() {
+        for &result in &[Ok(()), Err(libc::EIO)] {
+            for requests in &[vec![], vec![request(fuse_opcode::FUSE_DESTROY, 2, 0, &[])]] {
+                let calls = Arc::new(Mutex::new(Vec::new()));
+                run(FlushFS { calls: calls.clone(), result }, SessionBuilder::new(), requests);
+                // Called once on loop exit, even if flushing failed
+                assert_eq!(*calls.lock().unwrap(), ["pre_unmount", "destroy"]);
+            }
+        }
+    }
+
+    /// Filesystem that replies to the first write late, after the kernel resent it
+    struct LateFS {
+        pending: Option<ReplyWrite>,
+    }
+
+    impl Filesystem for LateFS {
+        fn write(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+            match self.pending.take() {
+                None => self.pending = Some(reply),
+                Some(stale) => {
+                    reply.written(data.len() as u32);
+                    stale.written(data.len() as u32);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stale_reply_is_dropped() {
+        let requests = [write_request(2, b"data"), write_request(2, b"data"), getxattr_request(3, "user.foo")];
+        let replies = run(LateFS { pending: None }, SessionBuilder::new(), &requests);
+        let headers: Vec<_> = replies.iter().map(|reply| reply_header(reply)).collect();
+        assert_eq!(headers, [(2, 0), (3, -libc::ENOSYS)]);
+    }
+
+    /// Filesystem that renames only if the flags are as expected
+    #[cfg(feature = "abi-7-23")]
+    struct Rename2FS;
+
+    #[cfg(feature = "abi-7-23")]
+    impl Filesystem for Rename2FS {
+        fn rename2(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, rename: crate::Rename2Request<'_>, reply: crate::ReplyEmpty) {
+            assert_eq!((parent, name, rename.newparent(), rename.newname()), (1, OsStr::new("a"), 3, OsStr::new("b")));
+            match (rename.flags().exchange(), rename.flags().noreplace()) {
+                (true, false) => reply.error(libc::ENOENT),
+                (false, true) => reply.error(libc::EEXIST),
+                _ => reply.ok(),
+            }
+        }
+    }
+
+    /// Build a raw rename2 request
+    #[cfg(feature = "abi-7-23")]
+    fn rename2_request(unique: u64, flags: u32) -> Vec<u8> {
+        let mut args = vec![0; mem::size_of::<fuse_rename2_in>()];
+        args[0..8].copy_from_slice(&3u64.to_ne_bytes());
+        args[8..12].copy_from_slice(&flags.to_ne_bytes());
+        args.extend_from_slice(b"a\0b\0");
+        request(fuse_opcode::FUSE_RENAME2, unique, 1, &args)
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-23")]
+    fn rename2_flags() {
+        use crate::RenameFlags;
+        let requests = [
+            rename2_request(2, RenameFlags::EXCHANGE),
+            rename2_request(3, RenameFlags::NOREPLACE),
+            rename2_request(4, RenameFlags::WHITEOUT),
+            rename2_request(5, RenameFlags::EXCHANGE | RenameFlags::NOREPLACE),
+        ];
+        let replies = run(Rename2FS, SessionBuilder::new(), &requests);
+        let headers: Vec<_> = replies.iter().map(|reply| reply_header(reply)).collect();
+        assert_eq!(headers, [(2, -libc::ENOENT), (3, -libc::EEXIST), (4, 0), (5, -libc::EINVAL)]);
+    }
+
+    /// Filesystem that creates an unnamed file and links it into the root directory
+    #[cfg(feature = "abi-7-37")]
+    struct TmpFileFS {
+        unnamed: Option<u64>,
+    }
+
+    #[cfg(feature = "abi-7-37")]
+    impl Filesystem for TmpFileFS {
+        fn tmpfile(&mut self, _req: &Request<'_>, parent: u64, mode: crate::CreateMode, flags: u32, reply: crate::ReplyCreate) {
+            assert_eq!((parent, mode.mode(), flags & libc::O_TMPFILE as u32), (1, libc::S_IFREG | 0o600, libc::O_TMPFILE as u32));
+            self.unnamed = Some(5);
+            let attr = crate::FileAttr::builder(5, crate::FileType::RegularFile).perm(0o600).nlink(0).build();
+            reply.created(&std::time::Duration::from_secs(1), &attr, 0, 9, 0);
+        }
+
+        fn link(&mut self, _req: &Request<'_>, ino: u64, newparent: u64, newname: &OsStr, reply: crate::ReplyEntry) {
+            assert_eq!((Some(ino), newparent, newname), (self.unnamed.take(), 1, OsStr::new("t")));
+            let attr = crate::FileAttr::builder(ino, crate::FileType::RegularFile).perm(0o600).build();
+            reply.entry(&std::time::Duration::from_secs(1), &attr, 0);
+        }
+    }
+
+    /// Build a raw tmpfile request for an unnamed file in the given directory
+    #[cfg(feature = "abi-7-37")]
+    fn tmpfile_request(unique: u64, parent: u64) -> Vec<u8> {
+        let mut args = vec![0; mem::size_of::<fuse_create_in>()];
+        args[0..4].copy_from_slice(&((libc::O_RDWR | libc::O_TMPFILE) as u32).to_ne_bytes());
+        args[4..8].copy_from_slice(&(libc::S_IFREG | 0o600).to_ne_bytes());
+        // The kernel driver sends the name of the unnamed file's dentry
+        args.extend_from_slice(b"/\0");
+        request(fuse_opcode::FUSE_TMPFILE, unique, parent, &args)
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-37")]
+    fn tmpfile_then_link() {
+        let mut link = 5u64.to_ne_bytes().to_vec();
+        link.extend_from_slice(b"t\0");
+        let requests = [tmpfile_request(2, 1), request(fuse_opcode::FUSE_LINK, 3, 1, &link)];
+        let replies = run(TmpFileFS { unnamed: None }, SessionBuilder::new(), &requests);
+        let headers: Vec<_> = replies.iter().map(|reply| reply_header(reply)).collect();
+        assert_eq!(headers, [(2, 0), (3, 0)]);
+        let created_len = mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_entry_out>() + mem::size_of::<fuse_open_out>();
+        assert_eq!(replies[0].len(), created_len);
+        // Entry out starts with the node id of the unnamed file
+        let header_len = mem::size_of::<fuse_out_header>();
+        assert_eq!(replies[0][header_len..header_len + 8], 5u64.to_ne_bytes());
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-37")]
+    fn tmpfile_unimplemented() {
+        let replies = run(LateFS { pending: None }, SessionBuilder::new(), &[tmpfile_request(2, 1)]);
+        let headers: Vec<_> = replies.iter().map(|reply| reply_header(reply)).collect();
+        assert_eq!(headers, [(2, -libc::ENOSYS)]);
+    }
+
+    /// Filesystem that records the flags of create requests
+    struct CreateFS {
+        flags: Arc<Mutex<Vec<u32>>>,
+    }
+
+    impl Filesystem for CreateFS {
+        fn create(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, _mode: u32, flags: u32, reply: crate::ReplyCreate) {
+            assert_eq!((parent, name), (1, OsStr::new("t")));
+            self.flags.lock().unwrap().push(flags);
+            reply.error(libc::EOPNOTSUPP);
+        }
+    }
+
+    #[test]
+    fn create_flags() {
+        let create = |unique, flags: libc::c_int| {
+            let mut args = vec![0; mem::size_of::<fuse_create_in>()];
+            args[0..4].copy_from_slice(&(flags as u32).to_ne_bytes());
+            args[4..8].copy_from_slice(&(libc::S_IFREG | 0o600).to_ne_bytes());
+            args.extend_from_slice(b"t\0");
+            request(fuse_opcode::FUSE_CREATE, unique, 1, &args)
+        };
+        // Open flags reach the filesystem unchanged, including O_TMPFILE
+        let flags = Arc::new(Mutex::new(Vec::new()));
+        let requests = [create(2, libc::O_RDWR | libc::O_EXCL), create(3, libc::O_RDWR | libc::O_TMPFILE)];
+        let replies = run(CreateFS { flags: flags.clone() }, SessionBuilder::new(), &requests);
+        assert!(replies.iter().all(|reply| reply_header(reply).1 == -libc::EOPNOTSUPP));
+        let flags = flags.lock().unwrap();
+        assert_eq!(*flags, [(libc::O_RDWR | libc::O_EXCL) as u32, (libc::O_RDWR | libc::O_TMPFILE) as u32]);
+        assert_ne!(flags[1] & libc::O_TMPFILE as u32, 0);
+    }
+
+    /// Filesystem that counts reads and getxattr calls
+    struct CountingFS {
+        calls: Arc<Mutex<usize>>,
+    }
+
+    impl Filesystem for CountingFS {
+        fn read(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, _size: u32, reply: crate::ReplyData) {
+            *self.calls.lock().unwrap() += 1;
+            reply.data(b"data");
+        }
+
+        fn getxattr(&mut self, _req: &Request<'_>, _ino: u64, _name: &OsStr, _size: u32, reply: ReplyXattr) {
+            *self.calls.lock().unwrap() += 1;
+            reply.error(ENOATTR);
+        }
+    }
+
+    /// Filter that refuses uid 999 and quietly refuses uid 998
+    #[derive(Debug)]
+    struct TestFilter;
+
+    impl crate::RequestFilter for TestFilter {
+        fn check(&self, meta: &crate::RequestMeta) -> crate::FilterDecision {
+            match meta.uid {
+                999 => crate::FilterDecision::Deny(libc::EACCES),
+                998 => crate::FilterDecision::DenyQuiet(libc::EPERM),
+                _ => crate::FilterDecision::Allow,
+            }
+        }
+    }
+
+    /// Set the uid and gid of the given raw request
+    fn as_user(mut req: Vec<u8>, uid: u32, gid: u32) -> Vec<u8> {
+        req[24..28].copy_from_slice(&uid.to_ne_bytes());
+        req[28..32].copy_from_slice(&gid.to_ne_bytes());
+        req
+    }
+
+    /// Run the given requests with the given builder, returns the replies and the number of
+    /// calls that reached the filesystem
+    fn filtered(builder: SessionBuilder, requests: &[Vec<u8>]) -> (Vec<(u64, i32)>, usize) {
+        let calls = Arc::new(Mutex::new(0));
+        let replies = run(CountingFS { calls: calls.clone() }, builder, requests);
+        let headers = replies.iter().map(|reply| reply_header(reply)).collect();
+        let calls = *calls.lock().unwrap();
+        (headers, calls)
+    }
+
+    #[test]
+    fn request_filter() {
+        let read = |unique| read_request(unique, 4096);
+        let requests = [
+            as_user(read(2), 1000, 1000),
+            as_user(read(3), 999, 999),
+            as_user(read(4), 998, 998),
+            // Forget requests aren't filtered (and not replied)
+            as_user(request(fuse_opcode::FUSE_FORGET, 5, 2, &[0; mem::size_of::<fuse_forget_in>()]), 999, 999),
+            as_user(getxattr_request(6, "user.foo"), 1000, 1000),
+        ];
+        let (headers, calls) = filtered(SessionBuilder::new().request_filter(TestFilter), &requests);
+        assert_eq!(headers, [(2, 0), (3, -libc::EACCES), (4, -libc::EPERM), (6, -ENOATTR)]);
+        assert_eq!(calls, 2);
+        // Without filter, everything is dispatched
+        let (headers, calls) = filtered(SessionBuilder::new(), &requests);
+        assert_eq!(headers, [(2, 0), (3, 0), (4, 0), (6, -ENOATTR)]);
+        assert_eq!(calls, 4);
+    }
+
+    #[test]
+    fn uid_gid_filter() {
+        let read = |unique| read_request(unique, 4096);
+        let requests = [
+            as_user(read(2), 999, 999),
+            as_user(getxattr_request(3, "user.foo"), 999, 999),
+            as_user(read(4), 1000, 999),
+        ];
+        // Only reads and writes of the service account are refused
+        let filter = crate::UidGidFilter::new().deny_uid(999).only(crate::RequestClass::Data);
+        let (headers, calls) = filtered(SessionBuilder::new().request_filter(filter), &requests);
+        assert_eq!(headers, [(2, -libc::EACCES), (3, -ENOATTR), (4, 0)]);
+        assert_eq!(calls, 2);
+    }
+
+    /// Filesystem that counts calls and enters or leaves maintenance on getattr by toggling
+    /// its read-only switch
+    struct MaintenanceFS {
+        switch: crate::ReadOnlySwitch,
+        calls: Arc<Mutex<usize>>,
+    }
+
+    impl Filesystem for MaintenanceFS {
+        fn getattr(&mut self, _req: &Request<'_>, _ino: u64, reply: crate::ReplyAttr) {
+            self.switch.set(!self.switch.is_enabled());
+            reply.error(libc::ENOENT);
+        }
+
+        fn mkdir(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, _mode: u32, reply: crate::ReplyEntry) {
+            *self.calls.lock().unwrap() += 1;
+            reply.error(libc::EEXIST);
+        }
+
+        fn open(&mut self, _req: &Request<'_>, _ino: u64, _flags: u32, reply: crate::ReplyOpen) {
+            *self.calls.lock().unwrap() += 1;
+            reply.opened(0, 0);
+        }
+    }
+
+    #[test]
+    fn read_only_switch() {
+        let mut mkdir_args = vec![0; mem::size_of::<fuse_mkdir_in>()];
+        mkdir_args.extend_from_slice(b"d\0");
+        let mkdir = |unique| request(fuse_opcode::FUSE_MKDIR, unique, 1, &mkdir_args);
+        let open = |unique, flags: libc::c_int| {
+            let mut args = vec![0; mem::size_of::<fuse_open_in>()];
+            args[0..4].copy_from_slice(&(flags as u32).to_ne_bytes());
+            request(fuse_opcode::FUSE_OPEN, unique, 2, &args)
+        };
+        let toggle = |unique| request(fuse_opcode::FUSE_GETATTR, unique, 2, &[]);
+        let requests = [
+            mkdir(2),
+            toggle(3),
+            mkdir(4),
+            open(5, libc::O_RDONLY),
+            open(6, libc::O_WRONLY),
+            open(7, libc::O_RDONLY | libc::O_TRUNC),
+            toggle(8),
+            mkdir(9),
+            open(10, libc::O_RDWR),
+        ];
+        let switch = crate::ReadOnlySwitch::new(false);
+        let calls = Arc::new(Mutex::new(0));
+        let fs = MaintenanceFS { switch: switch.clone(), calls: calls.clone() };
+        let replies = run(fs, SessionBuilder::new().read_only(switch.clone()), &requests);
+        let headers: Vec<_> = replies.iter().map(|reply| reply_header(reply)).collect();
+        assert_eq!(headers, [
+            (2, -libc::EEXIST),
+            (3, -libc::ENOENT),
+            (4, -libc::EROFS),
+            (5, 0),
+            (6, -libc::EROFS),
+            (7, -libc::EROFS),
+            (8, -libc::ENOENT),
+            (9, -libc::EEXIST),
+            (10, 0),
+        ]);
+        assert_eq!(*calls.lock().unwrap(), 4);
+        assert!(!switch.is_enabled());
+    }
+
+    /// Filesystem that counts access checks and grants execute permission only
+    struct AccessFS {
+        checks: Arc<Mutex<usize>>,
+    }
+
+    impl Filesystem for AccessFS {
+        fn access(&mut self, _req: &Request<'_>, _ino: u64, mask: u32, reply: crate::ReplyEmpty) {
+            *self.checks.lock().unwrap() += 1;
+            if mask == libc::X_OK as u32 { reply.ok(); } else { reply.error(libc::EACCES); }
+        }
+
+        fn setattr(&mut self, _req: &Request<'_>, _ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<std::time::SystemTime>, _mtime: Option<std::time::SystemTime>, _fh: Option<u64>, _crtime: Option<std::time::SystemTime>, _chgtime: Option<std::time::SystemTime>, _bkuptime: Option<std::time::SystemTime>, _flags: Option<u32>, reply: crate::ReplyAttr) {
+            reply.error(libc::EPERM);
+        }
+    }
+
+    /// Run access checks of the given masks for inode 2, returns the errors and the number of
+    /// checks the filesystem made
+    fn access_checks(builder: SessionBuilder, requests: &[Vec<u8>]) -> (Vec<i32>, usize) {
+        let checks = Arc::new(Mutex::new(0));
+        let replies = run(AccessFS { checks: checks.clone() }, builder, requests);
+        let errors = replies.iter().map(|reply| reply_header(reply).1).collect();
+        let checks = *checks.lock().unwrap();
+        (errors, checks)
+    }
+
+    #[test]
+    fn cached_access() {
+        let access = |unique, mask: libc::c_int| {
+            let mut args = vec![0; mem::size_of::<fuse_access_in>()];
+            args[0..4].copy_from_slice(&(mask as u32).to_ne_bytes());
+            request(fuse_opcode::FUSE_ACCESS, unique, 2, &args)
+        };
+        let requests = [access(2, libc::X_OK), access(3, libc::X_OK), access(4, libc::W_OK), access(5, libc::W_OK), access(6, libc::X_OK)];
+        let errors = vec![0, 0, -libc::EACCES, -libc::EACCES, 0];
+        // Without cache, every check reaches the filesystem
+        assert_eq!(access_checks(SessionBuilder::new(), &requests), (errors.clone(), 5));
+        // Repeated checks within the TTL are replied from the cache
+        let builder = SessionBuilder::new().cache_access(std::time::Duration::from_secs(60));
+        assert_eq!(access_checks(builder, &requests), (errors, 2));
+        // Expired results are checked again
+        let builder = SessionBuilder::new().cache_access(std::time::Duration::from_secs(0));
+        assert_eq!(access_checks(builder, &requests[..2]).1, 2);
+        // Changing attributes of the inode forgets its results
+        let setattr = request(fuse_opcode::FUSE_SETATTR, 3, 2, &vec![0; mem::size_of::<fuse_setattr_in>()]);
+        let builder = SessionBuilder::new().cache_access(std::time::Duration::from_secs(60));
+        let (errors, checks) = access_checks(builder, &[access(2, libc::X_OK), setattr, access(4, libc::X_OK)]);
+        assert_eq!((errors, checks), (vec![0, -libc::EPERM, 0], 2));
+    }
+
+    /// Filesystem whose only file grows with every getattr
+    struct GrowingFS {
+        ttl: std::time::Duration,
+        size: u64,
+    }
+
+    impl Filesystem for GrowingFS {
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: crate::ReplyAttr) {
+            self.size += 1;
+            reply.attr(&self.ttl, &crate::FileAttr::builder(ino, crate::FileType::RegularFile).size(self.size).build());
+        }
+
+        fn write(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+            reply.written(data.len() as u32);
+        }
+    }
+
+    /// Run the given requests with attribute tracking and return the number of warnings
+    fn attr_warnings(ttl: std::time::Duration, requests: &[Vec<u8>]) -> usize {
+        let mut tracker = None;
+        let replies = run_with(GrowingFS { ttl, size: 0 }, SessionBuilder::new().track_attr_changes(), requests, |se| {
+            tracker = se.attr_tracker().cloned();
+        });
+        assert!(replies.iter().all(|reply| reply_header(reply).1 == 0));
+        tracker.unwrap().incoherent()
+    }
+
+    #[test]
+    fn attr_changes_within_ttl() {
+        let getattr = |unique| request(fuse_opcode::FUSE_GETATTR, unique, 2, &[]);
+        // Changed attributes while the previous ones are cached
+        assert_eq!(attr_warnings(std::time::Duration::from_secs(60), &[getattr(2), getattr(3)]), 1);
+        // Previous attributes expired already
+        assert_eq!(attr_warnings(std::time::Duration::from_secs(0), &[getattr(2), getattr(3)]), 0);
+        // Writing invalidates the cached attributes
+        assert_eq!(attr_warnings(std::time::Duration::from_secs(60), &[getattr(2), write_request(3, b"data"), getattr(4)]), 0);
+    }
+
+    /// Filesystem that reuses inode number 2 with a new generation for every lookup
+    struct ReusingFS {
+        generation: u64,
+    }
+
+    impl Filesystem for ReusingFS {
+        fn lookup(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: crate::ReplyEntry) {
+            self.generation += 1;
+            let kind = if self.generation > 1 { crate::FileType::Directory } else { crate::FileType::RegularFile };
+            let attr = crate::FileAttr::builder(2, kind).size(self.generation).times(std::time::UNIX_EPOCH).build();
+            reply.entry(&std::time::Duration::from_secs(60), &attr, self.generation);
+        }
+
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: crate::ReplyAttr) {
+            let kind = if self.generation > 1 { crate::FileType::Directory } else { crate::FileType::RegularFile };
+            reply.attr(&std::time::Duration::from_secs(60), &crate::FileAttr::builder(ino, kind).size(self.generation).times(std::time::UNIX_EPOCH).build());
+        }
+    }
+
+    #[test]
+    fn attr_changes_of_reused_inode() {
+        let lookup = |unique| request(fuse_opcode::FUSE_LOOKUP, unique, 1, b"file\0");
+        let forget = request(fuse_opcode::FUSE_FORGET, 4, 2, &1u64.to_ne_bytes());
+        // A forgotten inode number is reused for another file, which isn't a change
+        let mut tracker = None;
+        let requests = [lookup(2), request(fuse_opcode::FUSE_GETATTR, 3, 2, &[]), forget, lookup(5), request(fuse_opcode::FUSE_GETATTR, 6, 2, &[])];
+        let replies = run_with(ReusingFS { generation: 0 }, SessionBuilder::new().track_attr_changes(), &requests, |se| {
+            tracker = se.attr_tracker().cloned();
+        });
+        assert!(replies.iter().all(|reply| reply_header(reply).1 == 0));
+        let tracker = tracker.unwrap();
+        assert_eq!(tracker.incoherent(), 0);
+        let (generation, attr) = tracker.replied(2).unwrap();
+        assert_eq!((generation, attr.kind, attr.size), (2, crate::FileType::Directory, 2));
+        // Even if the kernel driver didn't forget it (e.g. a lookup racing with the forget)
+        let mut tracker = None;
+        let requests = [lookup(2), lookup(3)];
+        run_with(ReusingFS { generation: 0 }, SessionBuilder::new().track_attr_changes(), &requests, |se| {
+            tracker = se.attr_tracker().cloned();
+        });
+        assert_eq!(tracker.unwrap().incoherent(), 0);
+    }
+
+    #[cfg(feature = "abi-7-16")]
+    struct ForgettingFS {
+        batches: Arc<Mutex<(u64, u64)>>,
+        ignore_batches: bool,
+    }
+
+    #[cfg(feature = "abi-7-16")]
+    impl Filesystem for ForgettingFS {
+        fn forget(&mut self, _req: &Request<'_>, _ino: u64, nlookup: u64) {
+            self.batches.lock().unwrap().1 += nlookup;
+        }
+
+        fn batch_forget(&mut self, req: &Request<'_>, nodes: &[crate::ForgetOne]) {
+            self.batches.lock().unwrap().0 += 1;
+            if !self.ignore_batches {
+                for node in nodes {
+                    self.forget(req, node.ino(), node.nlookup());
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "abi-7-16")]
+    #[test]
+    fn batch_forget() {
+        // Large batches of forgets, as sent by the kernel driver when dropping its caches
+        let requests: Vec<_> = (0..4u64).map(|batch| {
+            let mut args = Vec::new();
+            args.extend_from_slice(&1000u32.to_ne_bytes());
+            args.extend_from_slice(&0u32.to_ne_bytes());
+            for ino in 0..1000u64 {
+                args.extend_from_slice(&(2 + batch * 1000 + ino).to_ne_bytes());
+                args.extend_from_slice(&2u64.to_ne_bytes());
+            }
+            request(fuse_opcode::FUSE_BATCH_FORGET, 2 + batch, 0, &args)
+        }).collect();
+        let batches = Arc::new(Mutex::new((0, 0)));
+        let replies = run(ForgettingFS { batches: batches.clone(), ignore_batches: false }, SessionBuilder::new(), &requests);
+        assert!(replies.is_empty());
+        assert_eq!(*batches.lock().unwrap(), (4, 4 * 1000 * 2));
+        // Filesystems that don't keep track of inodes can ignore whole batches
+        let batches = Arc::new(Mutex::new((0, 0)));
+        run(ForgettingFS { batches: batches.clone(), ignore_batches: true }, SessionBuilder::new(), &requests);
+        assert_eq!(*batches.lock().unwrap(), (4, 0));
+    }
+
+    /// Filesystem that notifies about inode 2 when it's forgotten or its attributes are
+    /// requested, and reuses its number with a new generation once all lookups are forgotten
+    #[cfg(feature = "abi-7-12")]
+    struct NotifyingFS {
+        notifier: Option<crate::Notifier>,
+        generation: u64,
+        nlookup: u64,
+    }
+
+    #[cfg(feature = "abi-7-12")]
+    impl NotifyingFS {
+        fn notify(&self, generation: u64) {
+            if let Some(notifier) = self.notifier.as_ref().unwrap().if_live(2, generation) {
+                notifier.inval_inode(2, 0, 0).unwrap();
+            }
+        }
+    }
+
+    #[cfg(feature = "abi-7-12")]
+    impl Filesystem for NotifyingFS {
+        fn lookup(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: crate::ReplyEntry) {
+            self.nlookup += 1;
+            let attr = crate::FileAttr::builder(2, crate::FileType::RegularFile).build();
+            reply.entry(&std::time::Duration::from_secs(60), &attr, self.generation);
+        }
+
+        fn forget(&mut self, _req: &Request<'_>, _ino: u64, nlookup: u64) {
+            self.nlookup -= nlookup;
+            self.notify(self.generation);
+            if self.nlookup == 0 {
+                self.generation += 1;
+            }
+        }
+
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: crate::ReplyAttr) {
+            for generation in 1..=self.generation {
+                self.notify(generation);
+            }
+            reply.attr(&std::time::Duration::from_secs(60), &crate::FileAttr::builder(ino, crate::FileType::RegularFile).build());
+        }
+    }
+
+    #[cfg(feature = "abi-7-12")]
+    #[test]
+    fn notify_after_forget() {
+        let lookup = |unique| request(fuse_opcode::FUSE_LOOKUP, unique, 1, b"file\0");
+        let forget = |unique| request(fuse_opcode::FUSE_FORGET, unique, 2, &1u64.to_ne_bytes());
+        // The first forget leaves a lookup, so the inode is still live. The second one makes
+        // the notification suppressed, as well as the one for the generation before reuse.
+        let requests = [lookup(2), lookup(3), forget(4), forget(5), lookup(6), request(fuse_opcode::FUSE_GETATTR, 7, 2, &[])];
+        let mut tracker = None;
+        let fs = NotifyingFS { notifier: None, generation: 1, nlookup: 0 };
+        let replies = run_with(fs, SessionBuilder::new().track_lookups(), &requests, |se| {
+            se.filesystem.notifier = Some(se.notifier());
+            tracker = se.lookup_tracker().cloned();
+        });
+        let notifications = replies.iter().filter(|reply| reply_header(reply).0 == 0).count();
+        assert_eq!((replies.len(), notifications), (6, 2));
+        assert_eq!(tracker.unwrap().stats(), (1, 2));
+        // Without tracking, every notification is sent
+        let fs = NotifyingFS { notifier: None, generation: 1, nlookup: 0 };
+        let replies = run_with(fs, SessionBuilder::new(), &requests, |se| {
+            se.filesystem.notifier = Some(se.notifier());
+        });
+        assert_eq!(replies.iter().filter(|reply| reply_header(reply).0 == 0).count(), 4);
+    }
+
+    /// Filesystem with a directory "dir" (inode 2) in the root directory, that removes and
+    /// renames anything but "busy", and leaves invalidating entries named "keep" to itself
+    #[cfg(feature = "abi-7-12")]
+    struct RenamingFS;
+
+    #[cfg(feature = "abi-7-12")]
+    impl RenamingFS {
+        fn reply(name: &OsStr, reply: ReplyEmpty) {
+            match name.to_str().unwrap() {
+                "busy" => reply.error(libc::EBUSY),
+                "keep" => reply.without_invalidation().ok(),
+                _ => reply.ok(),
+            }
+        }
+    }
+
+    #[cfg(feature = "abi-7-12")]
+    impl Filesystem for RenamingFS {
+        fn lookup(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: crate::ReplyEntry) {
+            reply.entry(&Duration::from_secs(60), &crate::FileAttr::builder(2, crate::FileType::Directory).build(), 1);
+        }
+
+        fn unlink(&mut self, _req: &Request<'_>, _parent: u64, name: &OsStr, reply: ReplyEmpty) {
+            RenamingFS::reply(name, reply);
+        }
+
+        fn rmdir(&mut self, _req: &Request<'_>, _parent: u64, name: &OsStr, reply: ReplyEmpty) {
+            RenamingFS::reply(name, reply);
+        }
+
+        fn rename(&mut self, _req: &Request<'_>, _parent: u64, name: &OsStr, _newparent: u64, _newname: &OsStr, reply: ReplyEmpty) {
+            RenamingFS::reply(name, reply);
+        }
+    }
+
+    /// Build a raw rename request
+    #[cfg(feature = "abi-7-12")]
+    fn rename_request(unique: u64, parent: u64, name: &str, newparent: u64, newname: &str) -> Vec<u8> {
+        let mut args = newparent.to_ne_bytes().to_vec();
+        args.extend_from_slice(name.as_bytes());
+        args.push(0);
+        args.extend_from_slice(newname.as_bytes());
+        args.push(0);
+        request(fuse_opcode::FUSE_RENAME, unique, parent, &args)
+    }
+
+    /// Returns the unique id or notification code, and the parent and name of invalidated
+    /// entries
+    #[cfg(feature = "abi-7-12")]
+    fn decode_inval_entry(reply: &[u8]) -> (u64, i32, Option<(u64, &str)>) {
+        let (unique, error) = reply_header(reply);
+        if unique != 0 {
+            return (unique, error, None);
+        }
+        let mut parent = [0; 8];
+        parent.copy_from_slice(&reply[16..24]);
+        let mut namelen = [0; 4];
+        namelen.copy_from_slice(&reply[24..28]);
+        let name = &reply[32..32 + u32::from_ne_bytes(namelen) as usize];
+        assert_eq!(reply.len(), 32 + name.len() + 1);
+        (0, error, Some((u64::from_ne_bytes(parent), std::str::from_utf8(name).unwrap())))
+    }
+
+    #[cfg(feature = "abi-7-12")]
+    #[test]
+    fn auto_invalidate_entries() {
+        let inval = fuse_notify_code::FUSE_NOTIFY_INVAL_ENTRY as i32;
+        let requests = [
+            request(fuse_opcode::FUSE_LOOKUP, 2, 1, b"dir\0"),
+            // Rename over another file, which invalidates source and target
+            rename_request(3, 1, "a", 2, "b"),
+            // Entries in directories that the kernel driver doesn't know can't be cached
+            rename_request(4, 7, "c", 7, "d"),
+            // Not invalidated on failure, or if the filesystem does it itself
+            request(fuse_opcode::FUSE_UNLINK, 5, 1, b"busy\0"),
+            request(fuse_opcode::FUSE_UNLINK, 6, 1, b"keep\0"),
+            request(fuse_opcode::FUSE_RMDIR, 7, 2, b"e\0"),
+        ];
+        let replies = run(RenamingFS, SessionBuilder::new().auto_invalidate_entries(), &requests);
+        // Notifications are sent from another thread, so they may come later than the reply
+        let (notifications, replies): (Vec<_>, Vec<_>) = replies.iter().map(|reply| decode_inval_entry(reply)).partition(|reply| reply.0 == 0);
+        assert_eq!(replies, [(2, 0, None), (3, 0, None), (4, 0, None), (5, -libc::EBUSY, None), (6, 0, None), (7, 0, None)]);
+        assert_eq!(notifications, [(0, inval, Some((1, "a"))), (0, inval, Some((2, "b"))), (0, inval, Some((2, "e")))]);
+        // Without auto invalidation, no notifications are sent
+        let replies = run(RenamingFS, SessionBuilder::new().track_lookups(), &requests);
+        assert!(replies.iter().all(|reply| reply_header(reply).0 != 0));
+    }
+
+    #[test]
+    fn latency_histograms() {
+        let requests = [request(fuse_opcode::FUSE_GETATTR, 2, 1, &[]), read_request(3, 4096)];
+        let mut metrics = None;
+        run_with(NullFS, SessionBuilder::new().latency_histograms().ordered_replies(true), &requests, |se| {
+            metrics = Some(se.metrics());
+        });
+        let text = metrics.unwrap().render_openmetrics();
+        let labels = "fsname=\"\",mountpoint=\"/test\"";
+        assert!(text.contains(&format!("fuse_requests_total{{{}}} 3\n", labels)));
+        assert!(text.contains(&format!("fuse_errors_total{{{}}} 2\n", labels)));
+        assert!(text.contains(&format!("fuse_reply_latency_seconds_count{{{},class=\"metadata\"}} 2\n", labels)));
+        assert!(text.contains(&format!("fuse_reply_latency_seconds_count{{{},class=\"data\"}} 1\n", labels)));
+        // Without histograms, latencies aren't recorded
+        let mut metrics = None;
+        run_with(NullFS, SessionBuilder::new(), &requests, |se| {
+            metrics = Some(se.metrics());
+        });
+        assert!(!metrics.unwrap().render_openmetrics().contains("fuse_reply_latency_seconds"));
+    }
 }
@@ -0,0 +1,421 @@
+//! Path resolution for passthrough filesystems
+//!
+//! A passthrough filesystem resolves paths below its backing directory itself. The kernel
+//! driver only looks up one name at a time and follows symlinks of the mounted filesystem on
+//! its own, but the backing directory can change under the filesystem: a symlink that points
+//! out of it (`../..` or an absolute path) or a symlink loop in a path the filesystem resolves
+//! would make it act on files outside of the backing directory, or resolve forever.
+//!
+//! `ResolutionGuard` resolves paths relative to a base directory like `openat2(2)` with
+//! `RESOLVE_BENEATH | RESOLVE_NO_MAGICLINKS` does: symlinks are followed as long as they stay
+//! below the base directory. Escaping it (with `..`, an absolute path or an absolute symlink)
+//! fails with `EXDEV`, symlink loops and too many symlinks fail with `ELOOP`. On Linux 5.6 and
+//! later, `openat2` is used. Elsewhere (older kernels and macOS), paths are resolved one
+//! component at a time in userspace, never following symlinks implicitly.
+
+use std::collections::{HashSet, VecDeque};
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{File, Metadata};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Component, Path};
+#[cfg(target_os = "linux")]
+use std::path::PathBuf;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool, Ordering};
+use libc::{c_int, EINVAL, ELOOP, ENOENT, ENOTDIR, EXDEV};
+
+/// Maximum number of symlinks to follow by default (`MAXSYMLINKS` of Linux, which `openat2`
+/// uses as well)
+const DEFAULT_MAX_SYMLINKS: usize = 40;
+
+/// Flags to open resolved files with. The file is only used to refer to it, not to read or
+/// write it, so no permission to do so is needed.
+#[cfg(target_os = "linux")]
+const PATH_FLAGS: c_int = libc::O_PATH | libc::O_CLOEXEC;
+#[cfg(target_os = "macos")]
+const PATH_FLAGS: c_int = 0x8000 | libc::O_CLOEXEC;                 // O_EVTONLY
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+const PATH_FLAGS: c_int = libc::O_RDONLY | libc::O_CLOEXEC;
+
+/// Flags to open a symlink itself with
+#[cfg(target_os = "macos")]
+const SYMLINK_FLAGS: c_int = PATH_FLAGS | 0x0020_0000;              // O_SYMLINK
+#[cfg(not(target_os = "macos"))]
+const SYMLINK_FLAGS: c_int = PATH_FLAGS | libc::O_NOFOLLOW;
+
+/// System call number of openat2 (the same on all architectures with unified numbers)
+#[cfg(all(target_os = "linux", not(any(target_arch = "mips", target_arch = "mips64"))))]
+const SYS_OPENAT2: libc::c_long = 437;
+
+/// Argument of openat2
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+#[cfg(target_os = "linux")]
+const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+#[cfg(target_os = "linux")]
+const RESOLVE_BENEATH: u64 = 0x08;
+
+/// Whether to follow a symlink at the end of a path
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FollowPolicy {
+    /// Resolve to the file a symlink at the end of the path points to
+    Follow,
+    /// Resolve to a symlink at the end of the path itself
+    NoFollow,
+}
+
+/// File descriptor of a resolved path. It only refers to the file (on Linux it's opened with
+/// `O_PATH`), use it with `*at` system calls or reopen it through `proc_path`.
+#[derive(Debug)]
+pub struct ResolvedFd {
+    file: File,
+}
+
+impl ResolvedFd {
+    /// Returns the metadata of the resolved file (of a symlink itself if it wasn't followed)
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        self.file.metadata()
+    }
+
+    /// Returns a path that refers to exactly the resolved file, as long as this is open. Calls
+    /// with this path don't resolve anything but the file itself, e.g. `fs::read_dir` on it
+    /// opens the resolved directory even if its path was replaced with a symlink meanwhile.
+    #[cfg(target_os = "linux")]
+    pub fn proc_path(&self) -> PathBuf {
+        PathBuf::from(format!("/proc/self/fd/{}", self.file.as_raw_fd()))
+    }
+
+    /// Returns the file descriptor as a file
+    pub fn into_file(self) -> File {
+        self.file
+    }
+}
+
+impl AsRawFd for ResolvedFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// Resolves paths below a base directory. See the module documentation for details.
+#[derive(Debug)]
+pub struct ResolutionGuard {
+    max_symlinks: usize,
+    /// True as long as openat2 may be available
+    #[cfg(target_os = "linux")]
+    openat2: AtomicBool,
+}
+
+impl Default for ResolutionGuard {
+    fn default() -> ResolutionGuard {
+        ResolutionGuard::new()
+    }
+}
+
+impl ResolutionGuard {
+    /// Create a guard that follows up to 40 symlinks per path
+    pub fn new() -> ResolutionGuard {
+        ResolutionGuard {
+            max_symlinks: DEFAULT_MAX_SYMLINKS,
+            #[cfg(target_os = "linux")]
+            openat2: AtomicBool::new(cfg!(not(any(target_arch = "mips", target_arch = "mips64")))),
+        }
+    }
+
+    /// Follow at most the given number of symlinks per path. Since the limit of `openat2` is
+    /// fixed, paths are always resolved in userspace with another limit than the default.
+    pub fn max_symlinks(mut self, max: usize) -> ResolutionGuard {
+        self.max_symlinks = max;
+        self
+    }
+
+    /// Use `openat2` if the kernel supports it (the default). Disabling it makes the guard
+    /// resolve paths in userspace like on older kernels.
+    #[cfg(target_os = "linux")]
+    pub fn openat2(self, enable: bool) -> ResolutionGuard {
+        self.openat2.store(enable, Ordering::Relaxed);
+        self
+    }
+
+    /// Resolve the given path relative to the given base directory. An empty path resolves
+    /// to the base directory itself.
+    pub fn resolve(&self, base: RawFd, path: &Path, follow: FollowPolicy) -> io::Result<ResolvedFd> {
+        #[cfg(all(target_os = "linux", not(any(target_arch = "mips", target_arch = "mips64"))))]
+        {
+            if self.max_symlinks == DEFAULT_MAX_SYMLINKS && self.openat2.load(Ordering::Relaxed) {
+                match openat2(base, path, follow) {
+                    // Unknown to the kernel, or forbidden by a seccomp filter
+                    Err(ref err) if err.raw_os_error() == Some(libc::ENOSYS) || err.raw_os_error() == Some(libc::EPERM) => {
+                        self.openat2.store(false, Ordering::Relaxed);
+                    }
+                    res => return res.map(|file| ResolvedFd { file }),
+                }
+            }
+        }
+        self.resolve_userspace(base, path, follow).map(|file| ResolvedFd { file })
+    }
+
+    /// Resolve the parent directory of the given path relative to the given base directory
+    /// (following a symlink to it). Returns the directory and the last component of the path,
+    /// to operate on the directory entry with `*at` system calls (e.g. to create it). Fails
+    /// with `EINVAL` if the path doesn't end with a name.
+    pub fn resolve_parent(&self, base: RawFd, path: &Path) -> io::Result<(ResolvedFd, OsString)> {
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return Err(io::Error::from_raw_os_error(EINVAL)),
+        };
+        let dir = self.resolve(base, parent, FollowPolicy::Follow)?;
+        if !dir.metadata()?.is_dir() {
+            return Err(io::Error::from_raw_os_error(ENOTDIR));
+        }
+        Ok((dir, name.to_os_string()))
+    }
+
+    /// Resolve a path one component at a time. Directories are opened without following
+    /// symlinks, so that a directory that is replaced with a symlink while resolving fails
+    /// instead of being followed.
+    fn resolve_userspace(&self, base: RawFd, path: &Path, follow: FollowPolicy) -> io::Result<File> {
+        let mut pending = components(path)?;
+        let mut dirs: Vec<File> = Vec::new();
+        let mut symlinks = 0;
+        let mut visited = HashSet::new();
+        loop {
+            let dir = dirs.last().map(AsRawFd::as_raw_fd).unwrap_or(base);
+            let name = match pending.pop_front() {
+                Some(name) => name,
+                None => return openat(dir, OsStr::new("."), PATH_FLAGS | libc::O_DIRECTORY),
+            };
+            if name == ".." {
+                if dirs.pop().is_none() {
+                    return Err(io::Error::from_raw_os_error(EXDEV));
+                }
+                continue;
+            }
+            let last = pending.is_empty();
+            let stat = fstatat(dir, &name)?;
+            let is_symlink = stat.st_mode & libc::S_IFMT == libc::S_IFLNK;
+            if is_symlink && (!last || follow == FollowPolicy::Follow) {
+                symlinks += 1;
+                if symlinks > self.max_symlinks {
+                    return Err(io::Error::from_raw_os_error(ELOOP));
+                }
+                // Resolving is deterministic, so getting to the same symlink with the same
+                // remaining path again is a loop
+                let dir_stat = fstatat(dir, OsStr::new("."))?;
+                if !visited.insert((dir_stat.st_dev, dir_stat.st_ino, name.clone(), pending.clone())) {
+                    return Err(io::Error::from_raw_os_error(ELOOP));
+                }
+                let mut target = components(Path::new(&readlinkat(dir, &name)?))?;
+                target.extend(pending.drain(..));
+                pending = target;
+                continue;
+            }
+            if last {
+                let flags = if is_symlink { SYMLINK_FLAGS } else { PATH_FLAGS | libc::O_NOFOLLOW };
+                return openat(dir, &name, flags);
+            }
+            dirs.push(openat(dir, &name, PATH_FLAGS | libc::O_DIRECTORY | libc::O_NOFOLLOW)?);
+        }
+    }
+}
+
+/// Returns the components of a relative path. Absolute paths fail with `EXDEV`, like they do
+/// with `RESOLVE_BENEATH`.
+fn components(path: &Path) -> io::Result<VecDeque<OsString>> {
+    path.components().filter(|component| *component != Component::CurDir).map(|component| match component {
+        Component::Normal(name) => Ok(name.to_os_string()),
+        Component::ParentDir => Ok(OsString::from("..")),
+        _ => Err(io::Error::from_raw_os_error(EXDEV)),
+    }).collect()
+}
+
+fn cstring(name: &OsStr) -> io::Result<CString> {
+    CString::new(name.as_bytes()).map_err(|_| io::Error::from_raw_os_error(EINVAL))
+}
+
+fn openat(dir: RawFd, name: &OsStr, flags: c_int) -> io::Result<File> {
+    let name = cstring(name)?;
+    let fd = unsafe { libc::openat(dir, name.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn fstatat(dir: RawFd, name: &OsStr) -> io::Result<libc::stat> {
+    let name = cstring(name)?;
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstatat(dir, name.as_ptr(), &mut stat, libc::AT_SYMLINK_NOFOLLOW) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat)
+}
+
+fn readlinkat(dir: RawFd, name: &OsStr) -> io::Result<OsString> {
+    let name = cstring(name)?;
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+    let len = unsafe { libc::readlinkat(dir, name.as_ptr(), buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // An empty symlink doesn't resolve to anything
+    if len == 0 {
+        return Err(io::Error::from_raw_os_error(ENOENT));
+    }
+    buf.truncate(len as usize);
+    Ok(OsString::from_vec(buf))
+}
+
+#[cfg(all(target_os = "linux", not(any(target_arch = "mips", target_arch = "mips64"))))]
+fn openat2(base: RawFd, path: &Path, follow: FollowPolicy) -> io::Result<File> {
+    let path = if path.as_os_str().is_empty() { cstring(OsStr::new("."))? } else { cstring(path.as_os_str())? };
+    let flags = match follow {
+        FollowPolicy::Follow => PATH_FLAGS,
+        FollowPolicy::NoFollow => PATH_FLAGS | libc::O_NOFOLLOW,
+    };
+    let how = OpenHow { flags: flags as u64, mode: 0, resolve: RESOLVE_BENEATH | RESOLVE_NO_MAGICLINKS };
+    let fd = unsafe { libc::syscall(SYS_OPENAT2, base, path.as_ptr(), &how as *const OpenHow, mem::size_of::<OpenHow>()) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::os::unix::io::AsRawFd;
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use libc::{ELOOP, ENOENT, ENOTDIR, EXDEV};
+    use super::{FollowPolicy, ResolutionGuard};
+
+    /// Test directory below the temporary directory, removed when dropped
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> TestDir {
+            let path = std::env::temp_dir().join(format!("fuse-rs-resolve-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(path.join("base/dir/sub")).unwrap();
+            fs::write(path.join("base/dir/file"), b"data").unwrap();
+            fs::write(path.join("outside"), b"secret").unwrap();
+            let link = |target: &str, name: &str| symlink(target, path.join("base").join(name)).unwrap();
+            link("dir/file", "rel");
+            link("dir", "dirlink");
+            link("../outside", "escape");
+            link(path.join("outside").to_str().unwrap(), "absolute");
+            link("loop2", "loop1");
+            link("loop1", "loop2");
+            link("dirlink/sub/../..", "updown");
+            link("missing", "dangling");
+            link("dir/file/x", "notdir");
+            for i in 0..45 {
+                link(&format!("chain{}", i + 1), &format!("chain{}", i));
+            }
+            fs::write(path.join("base/chain45"), b"end").unwrap();
+            TestDir(path)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Error code or file type (directory, symlink) and size (of files) of a resolved path
+    type Resolved = Result<(bool, bool, u64), i32>;
+
+    /// Resolve a path below the base directory
+    fn resolve(guard: &ResolutionGuard, dir: &TestDir, path: &str, follow: FollowPolicy) -> Resolved {
+        let base = fs::File::open(dir.0.join("base")).unwrap();
+        let fd = guard.resolve(base.as_raw_fd(), Path::new(path), follow).map_err(|err| err.raw_os_error().unwrap())?;
+        let meta = fd.metadata().unwrap();
+        Ok((meta.is_dir(), meta.file_type().is_symlink(), if meta.is_dir() { 0 } else { meta.len() }))
+    }
+
+    const CASES: &[(&str, FollowPolicy, Resolved)] = &[
+        ("", FollowPolicy::NoFollow, Ok((true, false, 0))),
+        ("dir/file", FollowPolicy::NoFollow, Ok((false, false, 4))),
+        ("rel", FollowPolicy::Follow, Ok((false, false, 4))),
+        ("rel", FollowPolicy::NoFollow, Ok((false, true, 8))),
+        ("dirlink/file", FollowPolicy::NoFollow, Ok((false, false, 4))),
+        ("dirlink/sub/../file", FollowPolicy::NoFollow, Ok((false, false, 4))),
+        ("updown/dir/./file", FollowPolicy::NoFollow, Ok((false, false, 4))),
+        ("dir/..", FollowPolicy::NoFollow, Ok((true, false, 0))),
+        ("dangling", FollowPolicy::NoFollow, Ok((false, true, 7))),
+        ("dangling", FollowPolicy::Follow, Err(ENOENT)),
+        ("notdir", FollowPolicy::Follow, Err(ENOTDIR)),
+        ("missing/file", FollowPolicy::NoFollow, Err(ENOENT)),
+        // Escape attempts
+        ("..", FollowPolicy::NoFollow, Err(EXDEV)),
+        ("dir/../../outside", FollowPolicy::NoFollow, Err(EXDEV)),
+        ("/etc/passwd", FollowPolicy::NoFollow, Err(EXDEV)),
+        ("escape", FollowPolicy::Follow, Err(EXDEV)),
+        ("absolute", FollowPolicy::Follow, Err(EXDEV)),
+        ("escape", FollowPolicy::NoFollow, Ok((false, true, 10))),
+        // Loops and deep chains
+        ("loop1", FollowPolicy::Follow, Err(ELOOP)),
+        ("loop1/file", FollowPolicy::NoFollow, Err(ELOOP)),
+        ("chain5", FollowPolicy::Follow, Ok((false, false, 3))),
+        ("chain0", FollowPolicy::Follow, Err(ELOOP)),
+    ];
+
+    #[test]
+    fn resolve_in_userspace() {
+        let dir = TestDir::new("userspace");
+        #[cfg(target_os = "linux")]
+        let guard = ResolutionGuard::new().openat2(false);
+        #[cfg(not(target_os = "linux"))]
+        let guard = ResolutionGuard::new();
+        for (path, follow, expected) in CASES {
+            assert_eq!(resolve(&guard, &dir, path, *follow), *expected, "{} ({:?})", path, follow);
+        }
+        // Custom limits of symlinks
+        let guard = ResolutionGuard::new().max_symlinks(50);
+        assert_eq!(resolve(&guard, &dir, "chain0", FollowPolicy::Follow), Ok((false, false, 3)));
+        let guard = ResolutionGuard::new().max_symlinks(1);
+        assert_eq!(resolve(&guard, &dir, "rel", FollowPolicy::Follow), Ok((false, false, 4)));
+        assert_eq!(resolve(&guard, &dir, "chain43", FollowPolicy::Follow), Err(ELOOP));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn openat2_parity() {
+        let dir = TestDir::new("openat2");
+        let guard = ResolutionGuard::new();
+        let fallback = ResolutionGuard::new().openat2(false);
+        for (path, follow, expected) in CASES {
+            assert_eq!(resolve(&fallback, &dir, path, *follow), *expected, "{} ({:?})", path, follow);
+            // Same results with openat2, if the kernel supports it
+            assert_eq!(resolve(&guard, &dir, path, *follow), *expected, "{} ({:?}) with openat2", path, follow);
+        }
+    }
+
+    #[test]
+    fn resolve_parent() {
+        let dir = TestDir::new("parent");
+        let base = fs::File::open(dir.0.join("base")).unwrap();
+        let guard = ResolutionGuard::new();
+        let (parent, name) = guard.resolve_parent(base.as_raw_fd(), Path::new("dirlink/new")).unwrap();
+        assert!(parent.metadata().unwrap().is_dir());
+        assert_eq!(name, "new");
+        let err = |path: &str| guard.resolve_parent(base.as_raw_fd(), Path::new(path)).unwrap_err().raw_os_error();
+        assert_eq!(err(""), Some(libc::EINVAL));
+        assert_eq!(err("dir/.."), Some(libc::EINVAL));
+        assert_eq!(err("escape/new"), Some(EXDEV));
+        assert_eq!(err("dir/file/new"), Some(ENOTDIR));
+    }
+}
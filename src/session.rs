@@ -5,16 +5,49 @@
 //! filesystem is mounted, the session loop receives, dispatches and replies to kernel requests
 //! for filesystem operations under its mount point.
 
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
 use std::io;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
+use std::os::unix::io::RawFd;
 use std::path::{PathBuf, Path};
+use std::sync::{mpsc, Arc, Condvar, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 use thread_scoped::{scoped, JoinGuard};
+use fuse_abi::{FUSE_KERNEL_VERSION, FUSE_KERNEL_MINOR_VERSION};
+use fuse_abi::consts::FUSE_MIN_READ_BUFFER;
 use libc::{EAGAIN, EINTR, ENODEV, ENOENT};
-use log::{error, info};
+use log::{error, info, warn};
 
-use crate::channel::{self, Channel};
-use crate::request::Request;
+use crate::access::AccessCache;
+use crate::attrdiff::AttrTracker;
+use crate::buffer::{BufferPool, RequestBuffer};
+use crate::channel::{self, Channel, ChannelSender};
+#[cfg(target_os = "linux")]
+use crate::connection;
+use crate::fairness::{DispatchStats, FairDispatch, FairQueue, RequestClass};
+use crate::fdbudget;
+use crate::interrupt::{InterruptFlag, InterruptTable};
+use crate::filter::{ReadOnlySwitch, RequestFilter};
+use crate::killpriv::KillPriv;
+use crate::ll;
+use crate::lookups::LookupTracker;
+use crate::metrics::{self, Latencies, Metrics};
+#[cfg(feature = "abi-7-12")]
+use crate::notify::{EntryInvalidation, EntryInvalidator, Notifier};
+#[cfg(target_os = "linux")]
+use crate::pidns::pid_namespace_id;
+use crate::pidns::PidNamespace;
+use crate::reply::{OnSent, ReplySender};
+use crate::request::{Request, INIT_FLAGS};
+#[cfg(target_os = "linux")]
+use crate::supervisor;
+use crate::task::{TaskScope, DEFAULT_TASK_TIMEOUT};
+use crate::xattrcache::XAttrCache;
 use crate::Filesystem;
 
 /// The max size of write requests from the kernel. The absolute minimum is 4k,
@@ -22,81 +55,1683 @@ use crate::Filesystem;
 /// and 128k on other systems.
 pub const MAX_WRITE_SIZE: usize = 16 * 1024 * 1024;
 
-/// Size of the buffer for reading a request from the kernel. Since the kernel may send
-/// up to MAX_WRITE_SIZE bytes in a write request, we use that value plus some extra space.
-const BUFFER_SIZE: usize = MAX_WRITE_SIZE + 4096;
+/// Extra space in the buffer for reading a request from the kernel. Since the kernel may
+/// send up to max write size bytes in a write request, the buffer needs to be larger than
+/// that to also hold the request header.
+const BUFFER_HEADROOM: usize = 4096;
+
+/// Default number of write payloads that filesystem methods can take at the same time
+/// without copying (see `Request::take_write_payload`)
+const DEFAULT_WRITE_PAYLOADS: usize = 4;
+
+/// Default time that the kernel driver caches entries and attributes of replies without an
+/// explicit TTL (see `SessionBuilder::default_ttl`)
+pub(crate) const DEFAULT_TTL: Duration = Duration::from_secs(1);
+
+/// Default max number of inodes whose replied attributes, owners or lookups the session keeps
+/// track of (see `SessionBuilder::max_tracked_inodes`)
+const DEFAULT_MAX_TRACKED_INODES: usize = 64 * 1024;
+
+/// Number of ordered replies that can be queued for the reply writer before senders block
+/// (see `SessionBuilder::ordered_replies`)
+const ORDERED_REPLY_QUEUE: usize = 64;
+
+/// Max number of background requests the kernel driver uses if the init reply doesn't set it
+/// (see `SessionBuilder::max_background`)
+#[cfg(feature = "abi-7-13")]
+const DEFAULT_MAX_BACKGROUND: u16 = 12;
+
+/// Congestion threshold the kernel driver uses if the init reply doesn't set it (see
+/// `SessionBuilder::congestion_threshold`)
+#[cfg(feature = "abi-7-13")]
+const DEFAULT_CONGESTION_THRESHOLD: u16 = 9;
+
+/// Limit of outstanding requests. Every reply holds a guard that counts as one outstanding
+/// request until the reply is sent.
+#[derive(Debug)]
+pub struct InflightLimit {
+    /// Max number of outstanding requests
+    max: usize,
+    /// Current number of outstanding requests
+    count: Mutex<usize>,
+    /// Notified whenever an outstanding request completes
+    completed: Condvar,
+}
+
+impl InflightLimit {
+    fn new(max: usize) -> InflightLimit {
+        InflightLimit { max, count: Mutex::new(0), completed: Condvar::new() }
+    }
+
+    /// Block until the number of outstanding requests is below the limit
+    fn wait(&self) {
+        let mut count = self.count.lock().unwrap();
+        while *count >= self.max {
+            count = self.completed.wait(count).unwrap();
+        }
+    }
+
+    /// Count a new outstanding request until the returned guard is dropped
+    fn track(limit: &Arc<InflightLimit>) -> InflightGuard {
+        *limit.count.lock().unwrap() += 1;
+        InflightGuard { limit: limit.clone() }
+    }
+}
+
+/// Guard that counts as an outstanding request while it exists
+#[derive(Debug)]
+pub struct InflightGuard {
+    limit: Arc<InflightLimit>,
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        *self.limit.count.lock().unwrap() -= 1;
+        self.limit.completed.notify_one();
+    }
+}
+
+/// Statistics of a session run (see `Session::run_with_summary`)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RunSummary {
+    /// Number of dispatched requests (including init)
+    pub requests_processed: u64,
+    /// Number of requests that were replied with an error
+    pub errors: u64,
+    /// Number of bytes replied to read requests
+    pub bytes_read: u64,
+    /// Number of bytes replied as written to write requests
+    pub bytes_written: u64,
+    /// Number of create requests that opened an existing file (see
+    /// `ReplyCreate::opened_existing`)
+    pub opened_existing: u64,
+    /// Number of requests that the kernel driver shouldn't have sent with the negotiated
+    /// capabilities (see `SessionBuilder::check_capabilities`)
+    pub capability_anomalies: u64,
+    /// Time the session loop ran
+    pub uptime: Duration,
+}
+
+/// Sizes of the session's maps that keep track of inodes (see `Session::tracking_stats`)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TrackingStats {
+    /// Number of inodes with replied attributes (see `SessionBuilder::track_attr_changes`)
+    pub attrs: usize,
+    /// Number of times all replied attributes were dropped because the limit was reached
+    pub attr_overflows: u64,
+    /// Number of inodes with known owners (see `SessionBuilder::safe_defaults`)
+    pub owners: usize,
+    /// Number of times all owners were forgotten because the limit was reached
+    pub owner_overflows: u64,
+    /// Number of inodes known to the kernel driver (see `SessionBuilder::track_lookups`)
+    pub lookups: usize,
+    /// Number of notifications that were suppressed because the inode was forgotten or its
+    /// number reused (see `Notifier::if_live`)
+    pub suppressed_notifications: u64,
+}
+
+impl TrackingStats {
+    /// Collect the sizes of the given trackers (zero if disabled)
+    pub(crate) fn collect(attrs: Option<&Arc<AttrTracker>>, privs: Option<&Arc<KillPriv>>, lookups: Option<&Arc<LookupTracker>>) -> TrackingStats {
+        let (attrs, attr_overflows) = attrs.map_or((0, 0), |attrs| attrs.stats());
+        let (owners, owner_overflows) = privs.map_or((0, 0), |privs| privs.stats());
+        let (lookups, suppressed_notifications) = lookups.map_or((0, 0), |lookups| lookups.stats());
+        TrackingStats { attrs, attr_overflows, owners, owner_overflows, lookups, suppressed_notifications }
+    }
+}
+
+/// Data transfer of a request whose replied size is counted
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Transfer {
+    Read,
+    Write,
+}
+
+/// Message to the writer of ordered replies
+enum Ordered {
+    /// Reply with the given sequence number, or none if the request wasn't replied, and the
+    /// function to call once it was sent
+    Reply(u64, Option<(Vec<u8>, Option<OnSent>)>),
+    /// Notify once all earlier messages are handled
+    Flush(mpsc::Sender<()>),
+}
+
+/// Queue of replies to a writer thread that writes them in the order their requests were
+/// dispatched (see `SessionBuilder::ordered_replies`)
+#[derive(Debug)]
+struct ReplyOrder {
+    /// Sequence number of the next dispatched request
+    next: AtomicU64,
+    /// Queue to the writer thread
+    queue: Mutex<mpsc::SyncSender<Ordered>>,
+}
+
+/// Write replies from the given queue in sequence. Replies that arrive early are held back
+/// until all earlier ones are written.
+fn write_ordered(ch: ChannelSender, queue: mpsc::Receiver<Ordered>, outstanding: Weak<OutstandingReplies>) {
+    let mut next = 0;
+    let mut pending = BTreeMap::new();
+    for message in queue {
+        match message {
+            Ordered::Reply(seq, data) => { pending.insert(seq, data); }
+            Ordered::Flush(done) => { let _ = done.send(()); }
+        }
+        while let Some(data) = pending.remove(&next) {
+            if let Some((data, sent)) = data {
+                let res = ch.send(&[&data]);
+                if let (Ok(()), Some(sent)) = (&res, sent) {
+                    sent();
+                }
+                if let Some(outstanding) = outstanding.upgrade() {
+                    outstanding.sent(res);
+                }
+            }
+            next += 1;
+        }
+    }
+}
+
+/// Unique ids of requests that wait for a reply. If the kernel resends a request, it uses
+/// the same unique id again, and the first reply retires it. Another reply for the same id
+/// (e.g. a late reply to the original request) would desync the protocol and is dropped.
+/// Also counts dispatched requests and sent replies for the run summary, and notices if the
+/// filesystem was unmounted while sending a reply.
+#[derive(Debug, Default)]
+pub struct OutstandingReplies {
+    uniques: Mutex<HashSet<u64>>,
+    interrupts: Arc<InterruptTable>,
+    device_gone: AtomicBool,
+    requests: AtomicU64,
+    errors: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    opened_existing: AtomicU64,
+    anomalies: AtomicU64,
+    order: Option<ReplyOrder>,
+    /// Latencies of replies by request class, if recorded
+    latencies: Option<Arc<Latencies>>,
+}
+
+impl OutstandingReplies {
+    /// Create outstanding replies whose replies are written right away, and whose latencies
+    /// are recorded in the given histograms (if any)
+    pub(crate) fn unordered(latencies: Option<Arc<Latencies>>) -> Arc<OutstandingReplies> {
+        Arc::new(OutstandingReplies { latencies, ..OutstandingReplies::default() })
+    }
+
+    /// Create outstanding replies whose replies are written by a writer thread to the given
+    /// channel in the order their requests were dispatched
+    fn ordered(ch: ChannelSender, latencies: Option<Arc<Latencies>>) -> Arc<OutstandingReplies> {
+        let (tx, rx) = mpsc::sync_channel(ORDERED_REPLY_QUEUE);
+        let order = ReplyOrder { next: AtomicU64::new(0), queue: Mutex::new(tx) };
+        let outstanding = Arc::new(OutstandingReplies { order: Some(order), latencies: latencies.clone(), ..OutstandingReplies::default() });
+        let weak = Arc::downgrade(&outstanding);
+        match thread::Builder::new().name("fuse-replies".into()).spawn(move || write_ordered(ch, rx, weak)) {
+            Ok(_) => outstanding,
+            Err(err) => {
+                warn!("Failed to start reply writer, replies are written unordered: {}", err);
+                OutstandingReplies::unordered(latencies)
+            }
+        }
+    }
+
+    /// Returns a queue to the reply writer and a sequence number for a newly dispatched
+    /// request, if replies are ordered
+    fn sequence(&self) -> Option<(u64, mpsc::SyncSender<Ordered>)> {
+        self.order.as_ref().map(|order| (order.next.fetch_add(1, Ordering::SeqCst), order.queue.lock().unwrap().clone()))
+    }
+
+    /// Wait up to the given time until the reply writer wrote all replies that can be
+    /// written in order (if replies are ordered)
+    fn flush(&self, timeout: Duration) {
+        if let Some(ref order) = self.order {
+            let (tx, rx) = mpsc::channel();
+            if order.queue.lock().unwrap().send(Ordered::Flush(tx)).is_ok() && rx.recv_timeout(timeout).is_err() {
+                warn!("Timed out writing ordered replies");
+            }
+        }
+    }
+
+    /// Wait for a reply to the request with the given unique id
+    fn register(&self, unique: u64) {
+        self.uniques.lock().unwrap().insert(unique);
+        self.interrupts.dispatched(unique);
+    }
+
+    /// Returns true if the request with the given unique id waited for a reply (and doesn't
+    /// anymore)
+    fn retire(&self, unique: u64) -> bool {
+        let retired = self.uniques.lock().unwrap().remove(&unique);
+        self.interrupts.replied(unique);
+        retired
+    }
+
+    /// Interrupt the request with the given unique id. Returns true if it waits for a reply,
+    /// otherwise it's interrupted once it's dispatched (if it wasn't replied already).
+    pub(crate) fn interrupt(&self, unique: u64) -> bool {
+        let uniques = self.uniques.lock().unwrap();
+        let outstanding = uniques.contains(&unique);
+        self.interrupts.interrupt(unique, outstanding);
+        outstanding
+    }
+
+    /// Returns the flag that tells whether the request with the given unique id was
+    /// interrupted
+    pub(crate) fn interrupt_flag(&self, unique: u64) -> InterruptFlag {
+        InterruptFlag::new(self.interrupts.clone(), unique)
+    }
+
+    /// Handle the result of sending a reply. Sending fails with ENODEV if the filesystem was
+    /// unmounted; the session loop ends then instead of dispatching requests whose replies
+    /// can't be sent either.
+    pub(crate) fn sent(&self, res: io::Result<()>) {
+        match res {
+            Ok(()) => (),
+            Err(ref err) if err.raw_os_error() == Some(ENODEV) => {
+                if !self.device_gone.swap(true, Ordering::SeqCst) {
+                    info!("Failed to send FUSE reply, the filesystem was unmounted");
+                }
+            }
+            Err(err) => error!("Failed to send FUSE reply: {}", err),
+        }
+    }
+
+    /// Returns the number of requests that wait for a reply
+    pub(crate) fn outstanding(&self) -> usize {
+        self.uniques.lock().unwrap().len()
+    }
+
+    /// Returns the latency histograms of replies, if recorded
+    pub(crate) fn latencies(&self) -> Option<&Arc<Latencies>> {
+        self.latencies.as_ref()
+    }
+
+    /// Returns true if sending a reply failed because the filesystem was unmounted
+    fn device_gone(&self) -> bool {
+        self.device_gone.load(Ordering::SeqCst)
+    }
+
+    /// Count a dispatched request
+    pub(crate) fn count_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a sent reply (header and payload) to a request with the given transfer
+    fn count_reply(&self, transfer: Option<Transfer>, data: &[&[u8]]) {
+        let mut error = [0; 4];
+        error.copy_from_slice(&data[0][4..8]);
+        if i32::from_ne_bytes(error) != 0 {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        match transfer {
+            Some(Transfer::Read) => {
+                let len = data[1..].iter().map(|bytes| bytes.len() as u64).sum();
+                self.bytes_read.fetch_add(len, Ordering::Relaxed);
+            }
+            // The size is the first field of fuse_write_out
+            Some(Transfer::Write) => if let Some(bytes) = data.get(1).filter(|bytes| bytes.len() >= 4) {
+                let mut size = [0; 4];
+                size.copy_from_slice(&bytes[..4]);
+                self.bytes_written.fetch_add(u64::from(u32::from_ne_bytes(size)), Ordering::Relaxed);
+            },
+            None => (),
+        }
+    }
+
+    /// Count a create request that opened an existing file
+    pub(crate) fn count_opened_existing(&self) {
+        self.opened_existing.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a request that shouldn't have been sent with the negotiated capabilities
+    pub(crate) fn count_anomaly(&self) {
+        self.anomalies.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the counts as run summary with the given uptime
+    pub(crate) fn summary(&self, uptime: Duration) -> RunSummary {
+        RunSummary {
+            requests_processed: self.requests.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            opened_existing: self.opened_existing.load(Ordering::Relaxed),
+            capability_anomalies: self.anomalies.load(Ordering::Relaxed),
+            uptime,
+        }
+    }
+}
+
+/// Reply sender that counts as an outstanding request until the reply is sent
+#[derive(Debug)]
+pub struct InflightSender {
+    ch: ChannelSender,
+    unique: u64,
+    outstanding: Arc<OutstandingReplies>,
+    transfer: Option<Transfer>,
+    /// Sequence number and queue to the reply writer, if replies are ordered
+    order: Option<(u64, mpsc::SyncSender<Ordered>)>,
+    /// Whether the reply was passed to the reply writer
+    queued: Cell<bool>,
+    /// Class of the request and time it was dispatched, if latencies are recorded
+    timing: Option<(RequestClass, Instant)>,
+    _guard: Option<InflightGuard>,
+}
+
+impl InflightSender {
+    /// Create a reply sender for the request with the given unique id, that is tracked by
+    /// the given limit
+    pub(crate) fn new(ch: ChannelSender, unique: u64, outstanding: &Arc<OutstandingReplies>, limit: Option<&Arc<InflightLimit>>) -> InflightSender {
+        outstanding.register(unique);
+        let order = outstanding.sequence();
+        InflightSender { ch, unique, outstanding: outstanding.clone(), transfer: None, order, queued: Cell::new(false), timing: None, _guard: limit.map(InflightLimit::track) }
+    }
+
+    /// Count the replied size of the given transfer
+    pub(crate) fn counting(mut self, transfer: Option<Transfer>) -> InflightSender {
+        self.transfer = transfer;
+        self
+    }
+
+    /// Record the latency of the reply to a request of the given class, which is dispatched
+    /// now (if latencies are recorded)
+    pub(crate) fn timed(mut self, class: RequestClass) -> InflightSender {
+        if self.outstanding.latencies.is_some() {
+            self.timing = Some((class, Instant::now()));
+        }
+        self
+    }
+}
+
+impl ReplySender for InflightSender {
+    fn send(&self, data: &[&[u8]]) {
+        self.send_with(data, None);
+    }
+
+    fn send_confirmed(&self, data: &[&[u8]], sent: Box<dyn FnOnce() + Send>) {
+        self.send_with(data, Some(sent));
+    }
+}
+
+impl InflightSender {
+    /// Send the reply unless the request was replied already, and call the given function
+    /// once it was written
+    fn send_with(&self, data: &[&[u8]], sent: Option<OnSent>) {
+        if self.outstanding.retire(self.unique) {
+            self.outstanding.count_reply(self.transfer, data);
+            if let (Some((class, dispatched)), Some(latencies)) = (self.timing, self.outstanding.latencies()) {
+                latencies.record(class, dispatched.elapsed());
+            }
+            match self.order {
+                Some((seq, ref queue)) => {
+                    let _ = queue.send(Ordered::Reply(seq, Some((data.concat(), sent))));
+                    self.queued.set(true);
+                }
+                None => {
+                    let res = self.ch.send(data);
+                    if let (Ok(()), Some(sent)) = (&res, sent) {
+                        sent();
+                    }
+                    self.outstanding.sent(res);
+                }
+            }
+        } else {
+            warn!("Dropping reply to request {} that was replied already", self.unique);
+        }
+    }
+}
+
+impl Drop for InflightSender {
+    fn drop(&mut self) {
+        // Later replies can't be written before the reply writer knows that there's none
+        if let Some((seq, ref queue)) = self.order {
+            if !self.queued.get() {
+                let _ = queue.send(Ordered::Reply(seq, None));
+            }
+        }
+    }
+}
+
+/// Names of init flags by bit (like in `consts`), empty for unknown bits
+#[cfg(not(target_os = "macos"))]
+const INIT_FLAG_NAMES: [&str; 32] = [
+    "FUSE_ASYNC_READ", "FUSE_POSIX_LOCKS", "FUSE_FILE_OPS", "FUSE_ATOMIC_O_TRUNC",
+    "FUSE_EXPORT_SUPPORT", "FUSE_BIG_WRITES", "FUSE_DONT_MASK", "FUSE_SPLICE_WRITE",
+    "FUSE_SPLICE_MOVE", "FUSE_SPLICE_READ", "FUSE_FLOCK_LOCKS", "FUSE_HAS_IOCTL_DIR",
+    "FUSE_AUTO_INVAL_DATA", "FUSE_DO_READDIRPLUS", "FUSE_READDIRPLUS_AUTO", "FUSE_ASYNC_DIO",
+    "FUSE_WRITEBACK_CACHE", "FUSE_NO_OPEN_SUPPORT", "FUSE_PARALLEL_DIROPS", "FUSE_HANDLE_KILLPRIV",
+    "FUSE_POSIX_ACL", "FUSE_ABORT_ERROR", "FUSE_MAX_PAGES", "FUSE_CACHE_SYMLINKS",
+    "FUSE_NO_OPENDIR_SUPPORT", "FUSE_EXPLICIT_INVAL_DATA", "FUSE_MAP_ALIGNMENT", "FUSE_SUBMOUNTS",
+    "FUSE_HANDLE_KILLPRIV_V2", "", "", "",
+];
+#[cfg(target_os = "macos")]
+const INIT_FLAG_NAMES: [&str; 32] = [
+    "FUSE_ASYNC_READ", "FUSE_POSIX_LOCKS", "FUSE_FILE_OPS", "FUSE_ATOMIC_O_TRUNC",
+    "FUSE_EXPORT_SUPPORT", "FUSE_BIG_WRITES", "FUSE_DONT_MASK", "FUSE_SPLICE_WRITE",
+    "FUSE_SPLICE_MOVE", "FUSE_SPLICE_READ", "FUSE_FLOCK_LOCKS", "FUSE_HAS_IOCTL_DIR",
+    "FUSE_AUTO_INVAL_DATA", "FUSE_DO_READDIRPLUS", "FUSE_READDIRPLUS_AUTO", "FUSE_ASYNC_DIO",
+    "FUSE_WRITEBACK_CACHE", "FUSE_NO_OPEN_SUPPORT", "FUSE_PARALLEL_DIROPS", "FUSE_HANDLE_KILLPRIV",
+    "FUSE_POSIX_ACL", "FUSE_ABORT_ERROR", "FUSE_MAX_PAGES", "FUSE_CACHE_SYMLINKS",
+    "FUSE_NO_OPENDIR_SUPPORT", "FUSE_EXPLICIT_INVAL_DATA", "FUSE_MAP_ALIGNMENT", "FUSE_ALLOCATE",
+    "FUSE_EXCHANGE_DATA", "FUSE_CASE_INSENSITIVE", "FUSE_VOL_RENAME", "FUSE_XTIMES",
+];
+
+/// Raw init flags that are formatted symbolically, e.g. `FUSE_ASYNC_READ | FUSE_BIG_WRITES`.
+/// Unknown flags are formatted in hex.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct InitFlags(pub u32);
+
+impl fmt::Display for InitFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 == 0 {
+            return f.write_str("0");
+        }
+        let mut unknown = 0;
+        let mut first = true;
+        for (bit, name) in INIT_FLAG_NAMES.iter().enumerate() {
+            if self.0 & 1 << bit == 0 {
+                continue;
+            }
+            if name.is_empty() {
+                unknown |= 1 << bit;
+                continue;
+            }
+            write!(f, "{}{}", if first { "" } else { " | " }, name)?;
+            first = false;
+        }
+        if unknown != 0 {
+            write!(f, "{}{:#x}", if first { "" } else { " | " }, unknown)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for InitFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Init settings that a filesystem asks for when it's initialized (see `Filesystem::init`).
+/// The defaults keep what the session asks for anyway.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InitConfig {
+    flags: u32,
+    max_readahead: Option<u32>,
+}
+
+impl InitConfig {
+    /// Create default init settings
+    pub fn new() -> InitConfig {
+        InitConfig::default()
+    }
+
+    /// Ask for the given raw init flags (see `consts`, e.g. `FUSE_WRITEBACK_CACHE`) in
+    /// addition to the ones the session asks for. Flags the kernel driver didn't report as
+    /// capable are dropped silently; which ones were negotiated is available with
+    /// `ProtocolCapabilities::negotiated_flags`. Flags that change the protocol need support
+    /// of the session, e.g. `FUSE_MAX_PAGES` must not be set.
+    pub fn flags(mut self, flags: u32) -> InitConfig {
+        self.flags |= flags;
+        self
+    }
+
+    /// Limit the max readahead size to the given number of bytes. Defaults to the size the
+    /// kernel driver asked for, which is also the upper limit.
+    pub fn max_readahead(mut self, bytes: u32) -> InitConfig {
+        self.max_readahead = Some(bytes);
+        self
+    }
+}
+
+/// Protocol version and capabilities of the kernel driver, as reported in its init request,
+/// along with what was negotiated. The debug output shows both, with flags formatted
+/// symbolically.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProtocolCapabilities {
+    major: u32,
+    minor: u32,
+    max_readahead: u32,
+    flags: u32,
+    wanted: u32,
+    #[cfg(feature = "abi-7-13")]
+    max_background: u16,
+    #[cfg(feature = "abi-7-13")]
+    congestion_threshold: u16,
+}
+
+impl ProtocolCapabilities {
+    /// Capabilities before init, with the given init flags the session asks for
+    pub(crate) fn wanting(wanted: u32) -> ProtocolCapabilities {
+        ProtocolCapabilities { wanted, ..ProtocolCapabilities::default() }
+    }
+
+    /// Capabilities reported in an init request with the given version, max readahead size
+    /// and flags
+    pub(crate) fn init(self, major: u32, minor: u32, max_readahead: u32, flags: u32) -> ProtocolCapabilities {
+        ProtocolCapabilities { major, minor, max_readahead, flags, ..self }
+    }
+
+    /// Capabilities with the init settings of the filesystem applied
+    pub(crate) fn configure(self, config: &InitConfig) -> ProtocolCapabilities {
+        let max_readahead = config.max_readahead.map_or(self.max_readahead, |max| max.min(self.max_readahead));
+        ProtocolCapabilities { max_readahead, wanted: self.wanted | config.flags, ..self }
+    }
+
+    /// Returns the ABI version (major, minor) of the kernel driver. The negotiated version
+    /// is the lower one of this and the version the crate was built for.
+    pub fn version(&self) -> (u32, u32) {
+        (self.major, self.minor)
+    }
+
+    /// Returns the negotiated ABI version (major, minor), i.e. the lower one of the kernel
+    /// driver's version and the version the crate was built for (see the `abi-7-*` features)
+    pub fn negotiated_version(&self) -> (u32, u32) {
+        (self.major, self.minor).min((FUSE_KERNEL_VERSION, FUSE_KERNEL_MINOR_VERSION))
+    }
+
+    /// Returns the negotiated max readahead size (in bytes): the size the kernel driver asked
+    /// for, unless the filesystem asked for less (see `InitConfig::max_readahead`). Requests
+    /// to `Filesystem::init` see the size the kernel driver asked for.
+    pub fn max_readahead(&self) -> u32 {
+        self.max_readahead
+    }
+
+    /// Returns the max number of background requests (e.g. readahead and asynchronous reads)
+    /// the kernel driver keeps outstanding, as replied to the init request (see
+    /// `SessionBuilder::max_background`). The kernel driver lowers it to
+    /// /proc/sys/fs/fuse/max_user_bgreq for unprivileged mounts, and it can be changed at
+    /// runtime in the connection's sysfs directory (see `Session::sysfs_dir`).
+    #[cfg(feature = "abi-7-13")]
+    pub fn max_background(&self) -> u16 {
+        match self.max_background {
+            0 => DEFAULT_MAX_BACKGROUND,
+            max => max,
+        }
+    }
+
+    /// Returns the number of background requests from which the kernel driver considers the
+    /// filesystem congested, as replied to the init request (see
+    /// `SessionBuilder::congestion_threshold`). Like the max number of background requests,
+    /// it may be lowered for unprivileged mounts and changed at runtime.
+    #[cfg(feature = "abi-7-13")]
+    pub fn congestion_threshold(&self) -> u16 {
+        match self.congestion_threshold {
+            0 => DEFAULT_CONGESTION_THRESHOLD,
+            threshold => threshold,
+        }
+    }
+
+    /// Returns the raw init flags the kernel driver reported as capable (see `consts`)
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// Returns the raw init flags that were negotiated: the flags the session asked for and
+    /// the kernel driver reported as capable
+    pub fn negotiated_flags(&self) -> u32 {
+        self.flags & self.wanted
+    }
+
+    /// Returns true if `FUSE_DONT_MASK` was negotiated (see `SessionBuilder::dont_mask`). The
+    /// kernel driver doesn't apply the umask to the mode of new files then, and filesystems
+    /// need to apply it themselves (see `CreateMode::masked`).
+    #[cfg(feature = "abi-7-12")]
+    pub fn dont_mask(&self) -> bool {
+        self.negotiated_flags() & fuse_abi::consts::FUSE_DONT_MASK != 0
+    }
+
+    /// Returns true if `FUSE_DO_READDIRPLUS` was negotiated (see `SessionBuilder::readdirplus`),
+    /// i.e. the kernel driver reads directories with readdirplus
+    #[cfg(feature = "abi-7-21")]
+    pub fn readdirplus(&self) -> bool {
+        self.negotiated_flags() & fuse_abi::consts::FUSE_DO_READDIRPLUS != 0
+    }
+
+    /// Returns true if `FUSE_HANDLE_KILLPRIV_V2` was negotiated (see
+    /// `SessionBuilder::handle_killpriv_v2`). The filesystem needs to clear setuid and setgid
+    /// bits of files when the kernel driver flags requests to do so, and remove file
+    /// capabilities on every write and truncate.
+    #[cfg(all(feature = "abi-7-33", not(target_os = "macos")))]
+    pub fn handle_killpriv_v2(&self) -> bool {
+        self.negotiated_flags() & fuse_abi::consts::FUSE_HANDLE_KILLPRIV_V2 != 0
+    }
+
+    /// Returns true if the kernel driver mounts directories flagged as submount roots as
+    /// separate filesystems (see `ReplyEntry::submount_stub`). Needs ABI 7.32 and is
+    /// currently only offered by virtiofs, not by /dev/fuse mounts.
+    #[cfg(all(feature = "abi-7-32", not(target_os = "macos")))]
+    pub fn submounts(&self) -> bool {
+        (self.major, self.minor) >= (7, 32) && self.flags & fuse_abi::consts::FUSE_SUBMOUNTS != 0
+    }
+}
+
+impl fmt::Debug for ProtocolCapabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProtocolCapabilities")
+            .field("version", &self.version())
+            .field("negotiated_version", &self.negotiated_version())
+            .field("max_readahead", &self.max_readahead)
+            .field("flags", &InitFlags(self.flags))
+            .field("wanted_flags", &InitFlags(self.wanted))
+            .field("negotiated_flags", &InitFlags(self.negotiated_flags()))
+            .finish()
+    }
+}
+
+/// Reason for a filesystem to shut down (see `Filesystem::destroy`)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ShutdownReason {
+    /// The kernel driver sent a destroy request (e.g. when unmounting a block device based
+    /// filesystem)
+    KernelDestroy,
+    /// The connection to the kernel driver was closed without a destroy request (e.g. by an
+    /// external or forced unmount)
+    DeviceClosed,
+    /// The session loop failed to receive or parse a request
+    LoopError,
+    /// The filesystem was unmounted by dropping its `BackgroundSession`
+    UserRequested,
+    /// The connection to the kernel driver was aborted (e.g. through its abort file in sysfs,
+    /// see `Session::abort_connection`). If the filesystem is still mounted, accessing it
+    /// fails with ENOTCONN until it's unmounted, which should be done lazily since it may be
+    /// busy (Linux only)
+    Aborted {
+        /// Whether the filesystem is still mounted at its mountpoint
+        still_mounted: bool,
+    },
+}
+
+/// Result of receiving from the kernel driver
+enum Received {
+    /// A request was received
+    Request,
+    /// No request was received, try again
+    Nothing,
+    /// The connection was closed
+    Closed(ShutdownReason),
+}
+
+/// Builder for sessions with non-default settings
+#[derive(Debug, Default)]
+pub struct SessionBuilder {
+    max_inflight: Option<usize>,
+    max_write_size: Option<usize>,
+    max_write_payloads: Option<usize>,
+    default_ttl: Option<Duration>,
+    negative_xattrs: Vec<OsString>,
+    raise_fd_limit: bool,
+    track_attr_changes: bool,
+    track_lookups: bool,
+    #[cfg(feature = "abi-7-12")]
+    auto_invalidate_entries: bool,
+    latency_histograms: bool,
+    fair_dispatch: Option<FairDispatch>,
+    access_ttl: Option<Duration>,
+    xattr_ttl: Option<Duration>,
+    request_filter: Option<Box<dyn RequestFilter>>,
+    read_only: Option<ReadOnlySwitch>,
+    #[cfg(target_os = "linux")]
+    watch_mount_flags: bool,
+    ordered_replies: bool,
+    check_capabilities: bool,
+    max_tracked_inodes: Option<usize>,
+    #[cfg(feature = "abi-7-12")]
+    dont_mask: bool,
+    #[cfg(feature = "abi-7-21")]
+    readdirplus: bool,
+    #[cfg(all(feature = "abi-7-33", not(target_os = "macos")))]
+    handle_killpriv_v2: bool,
+    #[cfg(feature = "abi-7-13")]
+    max_background: u16,
+    #[cfg(feature = "abi-7-13")]
+    congestion_threshold: u16,
+    safe_defaults: bool,
+}
+
+impl SessionBuilder {
+    /// Create a new session builder with default settings
+    pub fn new() -> SessionBuilder {
+        SessionBuilder::default()
+    }
+
+    /// Limit the number of outstanding requests (requests that were not replied yet). If the
+    /// limit is reached, the session loop stops reading new requests from the kernel until
+    /// some of the outstanding requests complete. This bounds memory usage if filesystem
+    /// methods reply asynchronously. By default, there's no limit. The session loop needs
+    /// to read at least one request at a time, so a limit of 0 is raised to 1 with a warning.
+    pub fn max_inflight(mut self, max: usize) -> SessionBuilder {
+        self.max_inflight = Some(max);
+        if max == 0 {
+            warn!("Max number of outstanding requests 0 is too small, using 1");
+            self.max_inflight = Some(1);
+        }
+        self
+    }
+
+    /// Set the max size of write requests (defaults to 16 MiB, which is also the maximum).
+    /// The session's request buffers are sized accordingly, so a smaller size saves memory.
+    /// The kernel driver requires buffers of at least `FUSE_MIN_READ_BUFFER` (8 KiB) to be
+    /// able to send any request, so smaller sizes are raised to that with a warning.
+    pub fn max_write_size(mut self, size: usize) -> SessionBuilder {
+        self.max_write_size = Some(size);
+        if size < FUSE_MIN_READ_BUFFER {
+            warn!("Max write size {} is too small, using {}", size, FUSE_MIN_READ_BUFFER);
+            self.max_write_size = Some(FUSE_MIN_READ_BUFFER);
+        }
+        if size > MAX_WRITE_SIZE {
+            warn!("Max write size {} is too large, using {}", size, MAX_WRITE_SIZE);
+            self.max_write_size = Some(MAX_WRITE_SIZE);
+        }
+        self
+    }
+
+    /// Limit the number of write payloads that filesystem methods can take without copying
+    /// at the same time (see `Request::take_write_payload`). Every taken payload keeps a
+    /// request buffer of about 16 MiB busy until it's dropped, further payloads are copied.
+    /// Defaults to 4.
+    pub fn max_write_payloads(mut self, max: usize) -> SessionBuilder {
+        self.max_write_payloads = Some(max);
+        self
+    }
+
+    /// Set the max number of background requests (e.g. readahead and asynchronous reads) the
+    /// kernel driver keeps outstanding (ABI 7.13). Defaults to the kernel driver's default of
+    /// 12, and 0 means the default as well. The value the kernel driver uses is available
+    /// from `ProtocolCapabilities::max_background`, e.g. to size a pool of worker threads.
+    #[cfg(feature = "abi-7-13")]
+    pub fn max_background(mut self, max: u16) -> SessionBuilder {
+        self.max_background = max;
+        self
+    }
+
+    /// Set the number of background requests from which the kernel driver considers the
+    /// filesystem congested (ABI 7.13). Defaults to the kernel driver's default of 9, and 0
+    /// means the default as well.
+    #[cfg(feature = "abi-7-13")]
+    pub fn congestion_threshold(mut self, threshold: u16) -> SessionBuilder {
+        self.congestion_threshold = threshold;
+        self
+    }
+
+    /// Set the time that the kernel driver caches entries and attributes replied with
+    /// `ReplyEntry::entry_default`, `ReplyAttr::attr_default` and
+    /// `ReplyCreate::created_default`. Replies with an explicit TTL (e.g. `ReplyEntry::entry`)
+    /// always use the given TTL instead. Defaults to 1 second.
+    pub fn default_ttl(mut self, ttl: Duration) -> SessionBuilder {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    /// Reply to getxattr requests for the given extended attribute name with ENODATA right
+    /// away, without calling the filesystem. Useful for attributes the filesystem never has
+    /// but that the kernel asks for often, like `security.capability` (which the kernel
+    /// queries on every exec and write of a file). Can be used multiple times to add more
+    /// names.
+    pub fn negative_xattr<N: AsRef<OsStr>>(mut self, name: N) -> SessionBuilder {
+        self.negative_xattrs.push(name.as_ref().to_os_string());
+        self
+    }
+
+    /// Raise the limit of open files of the process (RLIMIT_NOFILE) to the hard limit before
+    /// mounting. Useful for filesystems that keep a backing file open for every open file
+    /// (see `FdBudget`). Failing to raise the limit is logged, but doesn't fail the mount.
+    pub fn raise_fd_limit(mut self) -> SessionBuilder {
+        self.raise_fd_limit = true;
+        self
+    }
+
+    /// Log a warning whenever the filesystem replies to lookup or getattr with attributes
+    /// that differ from the previous reply for the same inode, while the kernel driver may
+    /// still cache the previous ones (their TTL didn't expire yet and the inode wasn't
+    /// modified through the mount since). Applications may see stale attributes then, e.g.
+    /// an outdated file size. Meant for debugging, since it keeps the replied attributes of
+    /// all inodes.
+    pub fn track_attr_changes(mut self) -> SessionBuilder {
+        self.track_attr_changes = true;
+        self
+    }
+
+    /// Keep track of the lookup count and generation of every inode the kernel driver knows,
+    /// i.e. that was replied in an entry and not forgotten yet. Notifications for inodes that
+    /// were forgotten or whose number was reused with another generation can be suppressed
+    /// then (see `Notifier::if_live`), since they'd affect another file. Suppressed
+    /// notifications are counted in `TrackingStats::suppressed_notifications`. The number of
+    /// tracked inodes is limited by `max_tracked_inodes`.
+    pub fn track_lookups(mut self) -> SessionBuilder {
+        self.track_lookups = true;
+        self
+    }
+
+    /// Invalidate cached entries after replying success to `unlink`, `rmdir`, `rename` and
+    /// `rename2`: the removed entry, or the source and target of a rename (which may have
+    /// replaced another file). Spares filesystems to keep track of which entries to invalidate
+    /// with long entry TTLs. Notifications are only sent for entries in directories that the
+    /// kernel driver knows, which needs `track_lookups` (enabled by this as well). The
+    /// invalidation can be skipped per operation with `ReplyEmpty::without_invalidation`. The
+    /// notifications are sent from a separate thread once the reply was sent, since writing
+    /// them may block until the kernel driver finished other operations in the directory.
+    #[cfg(feature = "abi-7-12")]
+    pub fn auto_invalidate_entries(mut self) -> SessionBuilder {
+        self.auto_invalidate_entries = true;
+        self.track_lookups = true;
+        self
+    }
+
+    /// Record the time from dispatching each request to sending its reply in histograms per
+    /// request class (see `RequestClass`), with fixed buckets from 1µs up to about half a
+    /// second whose bounds double. Recording doesn't lock, but takes the time for every
+    /// request. The histograms are rendered with `Session::render_openmetrics`. Disabled by
+    /// default.
+    pub fn latency_histograms(mut self) -> SessionBuilder {
+        self.latency_histograms = true;
+        self
+    }
+
+    /// Dispatch requests fairly between metadata and data operations (see `FairDispatch`).
+    /// The session loop then reads requests ahead and copies them into queues. Statistics
+    /// are available with `Session::dispatch_stats`.
+    pub fn fair_dispatch(mut self, config: FairDispatch) -> SessionBuilder {
+        self.fair_dispatch = Some(config);
+        self
+    }
+
+    /// Remember the results of access checks (by user, group, inode and mask) for the given
+    /// time and reply to repeated checks without calling the filesystem. Results are
+    /// forgotten if the inode's attributes or extended attributes (ACLs) are changed through
+    /// the mount. Changes the filesystem makes by other means (e.g. on a remote backend) or to
+    /// the supplementary groups of a user aren't noticed until the results expire, so keep
+    /// the time short. Only grants and permission errors are cached. Disabled by default.
+    pub fn cache_access(mut self, ttl: Duration) -> SessionBuilder {
+        self.access_ttl = Some(ttl);
+        self
+    }
+
+    /// Remember extended attribute values that the filesystem replies to listxattr with (see
+    /// `ReplyXattr::list`) for the given time and reply to getxattr requests for the listed
+    /// inodes without calling the filesystem. Values are forgotten if the inode's attributes
+    /// or extended attributes are changed through the mount. Cached values are served to any
+    /// user, so only list values that every user who may list them may also get. Disabled by
+    /// default.
+    pub fn cache_xattrs(mut self, ttl: Duration) -> SessionBuilder {
+        self.xattr_ttl = Some(ttl);
+        self
+    }
+
+    /// Consult the given filter before dispatching requests to the filesystem and reply to
+    /// refused requests with the filter's error code (see `RequestFilter` and `UidGidFilter`).
+    /// Init, destroy, forget and interrupt requests are never filtered.
+    pub fn request_filter<F: RequestFilter + 'static>(mut self, filter: F) -> SessionBuilder {
+        self.request_filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Refuse requests that modify the filesystem with `EROFS` while the given switch is
+    /// enabled, before they're dispatched to the filesystem. The switch can be toggled at any
+    /// time through a clone of it, e.g. from `Filesystem::mount_flags_changed` to follow the
+    /// mount's flags.
+    pub fn read_only(mut self, switch: ReadOnlySwitch) -> SessionBuilder {
+        self.read_only = Some(switch);
+        self
+    }
+
+    /// Watch /proc/self/mountinfo in a background thread for the mount being made read-only
+    /// or read-write (e.g. by `mount -o remount,ro`), and call the filesystem's
+    /// `mount_flags_changed` method when it happens. Only for sessions that mount the
+    /// filesystem themselves. Disabled by default.
+    #[cfg(target_os = "linux")]
+    pub fn watch_mount_flags(mut self) -> SessionBuilder {
+        self.watch_mount_flags = true;
+        self
+    }
+
+    /// Write replies in the order their requests were dispatched, by a single writer thread.
+    /// Replies that are sent early (e.g. by asynchronous filesystem methods) are held back
+    /// until the replies to all earlier requests are written, so a slow request delays all
+    /// later replies, and every reply is copied once more. This costs throughput if requests
+    /// are replied concurrently, but gives a deterministic order of replies (e.g. for backends
+    /// or tests that expect FIFO replies). Disabled by default, i.e. replies are written as
+    /// soon as they're sent.
+    pub fn ordered_replies(mut self, ordered: bool) -> SessionBuilder {
+        self.ordered_replies = ordered;
+        self
+    }
+
+    /// Check every request against the negotiated protocol version and init flags, and warn
+    /// about requests that the kernel driver shouldn't have sent (e.g. readdirplus although
+    /// `FUSE_DO_READDIRPLUS` wasn't negotiated). Such requests are still dispatched, since the
+    /// kernel driver knows best what it supports, but are counted in
+    /// `RunSummary::capability_anomalies`. Meant for debugging, disabled by default.
+    pub fn check_capabilities(mut self) -> SessionBuilder {
+        self.check_capabilities = true;
+        self
+    }
+
+    /// Limit the number of inodes whose replied attributes (see `track_attr_changes`), owners
+    /// (see `safe_defaults`) and lookups (see `track_lookups`) the session keeps track of
+    /// (defaults to 65536 each). This bounds memory usage if clients walk huge (or endless,
+    /// synthetic) directory trees. Once the limit is reached, expired attributes are dropped,
+    /// and if that doesn't help, all of them; owners are all forgotten; new inodes aren't
+    /// tracked for lookups anymore. A warning is logged the first time. Attribute changes of
+    /// dropped inodes go unnoticed then, writes by owners of forgotten inodes remove file
+    /// capabilities like writes by other users, and notifications for unknown inodes aren't
+    /// suppressed anymore. Sizes are available with `Session::tracking_stats`.
+    pub fn max_tracked_inodes(mut self, max: usize) -> SessionBuilder {
+        self.max_tracked_inodes = Some(max);
+        self
+    }
+
+    /// Ask the kernel driver not to apply the umask to the mode of new files (`FUSE_DONT_MASK`).
+    /// Filesystems then get the mode as requested by the application and need to apply the
+    /// umask themselves, unless they want to honor default ACLs of the parent directory
+    /// instead (which replace the umask). Whether the kernel driver agreed is available with
+    /// `ProtocolCapabilities::dont_mask`.
+    #[cfg(feature = "abi-7-12")]
+    pub fn dont_mask(mut self) -> SessionBuilder {
+        self.dont_mask = true;
+        self
+    }
+
+    /// Ask the kernel driver to read directories with readdirplus, which returns the
+    /// attributes of the entries as well (`FUSE_DO_READDIRPLUS`), whenever it expects the
+    /// attributes to be needed (`FUSE_READDIRPLUS_AUTO`). The filesystem must implement
+    /// `Filesystem::readdirplus` then. Whether the kernel driver agreed is available with
+    /// `ProtocolCapabilities::readdirplus`.
+    #[cfg(feature = "abi-7-21")]
+    pub fn readdirplus(mut self) -> SessionBuilder {
+        self.readdirplus = true;
+        self
+    }
+
+    /// Ask the kernel driver to leave clearing setuid and setgid bits and removing file
+    /// capabilities on write, chown and truncate to the filesystem, and to flag the write
+    /// (`FUSE_WRITE_KILL_SUIDGID`), setattr (`FATTR_KILL_SUIDGID`) and open
+    /// (`FUSE_OPEN_KILL_SUIDGID`) requests that must clear setuid and setgid bits
+    /// (`FUSE_HANDLE_KILLPRIV_V2`). File capabilities must be removed on every write and
+    /// truncate. Whether the kernel driver agreed is available with
+    /// `ProtocolCapabilities::handle_killpriv_v2`.
+    #[cfg(all(feature = "abi-7-33", not(target_os = "macos")))]
+    pub fn handle_killpriv_v2(mut self) -> SessionBuilder {
+        self.handle_killpriv_v2 = true;
+        self
+    }
+
+    /// Enable safe defaults for filesystems that access backing files with privileges of
+    /// their own (e.g. passthrough filesystems running as root). Unless
+    /// `FUSE_HANDLE_KILLPRIV_V2` was negotiated, the session removes file capabilities by
+    /// calling the filesystem's `removexattr` for `security.capability` before dispatching a
+    /// write by another user than the file's owner, or a setattr request that changes the
+    /// size, like the kernel driver does for local filesystems. Owners are known from replied
+    /// attributes; writes to files whose owner isn't known are treated like writes by another
+    /// user. Capabilities are only removed again after they were set through the mount.
+    /// Disabled by default.
+    pub fn safe_defaults(mut self) -> SessionBuilder {
+        self.safe_defaults = true;
+        self
+    }
+
+    /// Create a new session by mounting the given filesystem to the given mountpoint
+    pub fn mount<FS: Filesystem>(self, filesystem: FS, mountpoint: &Path, options: &[&OsStr]) -> io::Result<Session<FS>> {
+        if self.raise_fd_limit {
+            match fdbudget::raise_nofile_limit() {
+                Ok((previous, limit)) if previous == limit => info!("Limit of open files is at its maximum of {}", limit),
+                Ok((previous, limit)) => info!("Raised limit of open files from {} to {}", previous, limit),
+                Err(err) => warn!("Failed to raise limit of open files: {}", err),
+            }
+        }
+        let mut se = Session::new(filesystem, mountpoint, options)?;
+        self.configure(&mut se);
+        Ok(se)
+    }
+
+    /// Apply the settings to the given session
+    pub(crate) fn configure<FS: Filesystem>(self, se: &mut Session<FS>) {
+        se.inflight = self.max_inflight.map(|max| Arc::new(InflightLimit::new(max)));
+        se.max_write = self.max_write_size.unwrap_or(MAX_WRITE_SIZE);
+        let payloads = self.max_write_payloads.unwrap_or(DEFAULT_WRITE_PAYLOADS);
+        se.buffers = Arc::new(BufferPool::new(se.max_write + BUFFER_HEADROOM, payloads));
+        se.default_ttl = self.default_ttl.unwrap_or(DEFAULT_TTL);
+        se.negative_xattrs = self.negative_xattrs;
+        let max_tracked = self.max_tracked_inodes.unwrap_or(DEFAULT_MAX_TRACKED_INODES);
+        if self.track_attr_changes {
+            se.attrs = Some(Arc::new(AttrTracker::new(max_tracked)));
+        }
+        if self.track_lookups {
+            let lookups = Arc::new(LookupTracker::new(max_tracked));
+            #[cfg(feature = "abi-7-12")]
+            se.notifier.track_lookups(lookups.clone());
+            se.lookups = Some(lookups);
+        }
+        #[cfg(feature = "abi-7-12")]
+        {
+            if self.auto_invalidate_entries {
+                match EntryInvalidator::start(se.notifier.clone()) {
+                    Ok(invalidator) => se.invalidator = Some(invalidator),
+                    Err(err) => warn!("Failed to start entry invalidation, entries aren't invalidated automatically: {}", err),
+                }
+            }
+        }
+        se.fair_dispatch = self.fair_dispatch.map(|config| (config, DispatchStats::default()));
+        se.access = self.access_ttl.map(|ttl| Arc::new(AccessCache::new(ttl)));
+        se.xattrs = self.xattr_ttl.map(|ttl| Arc::new(XAttrCache::new(ttl)));
+        se.filter = self.request_filter;
+        se.read_only = self.read_only;
+        se.check_capabilities = self.check_capabilities;
+        #[cfg(target_os = "linux")]
+        {
+            if self.watch_mount_flags && se.connection.is_some() {
+                let changed = Arc::new(Mutex::new(None));
+                match connection::watch_mount_flags(se.mountpoint(), Arc::downgrade(&changed)) {
+                    Ok(()) => se.mount_flags = Some(changed),
+                    Err(err) => warn!("Failed to watch mount flags of {}: {}", se.mountpoint().display(), err),
+                }
+            }
+        }
+        let latencies = if self.latency_histograms { Some(Arc::new(Latencies::default())) } else { None };
+        if self.ordered_replies {
+            se.outstanding = OutstandingReplies::ordered(se.ch.sender(), latencies);
+        } else if latencies.is_some() {
+            se.outstanding = OutstandingReplies::unordered(latencies);
+        }
+        if self.safe_defaults {
+            se.privs = Some(Arc::new(KillPriv::new(max_tracked)));
+        }
+        #[cfg(feature = "abi-7-13")]
+        {
+            se.capabilities.max_background = self.max_background;
+            se.capabilities.congestion_threshold = self.congestion_threshold;
+        }
+        #[cfg(feature = "abi-7-12")]
+        {
+            if self.dont_mask {
+                se.capabilities.wanted |= fuse_abi::consts::FUSE_DONT_MASK;
+            }
+        }
+        #[cfg(feature = "abi-7-21")]
+        {
+            if self.readdirplus {
+                se.capabilities.wanted |= fuse_abi::consts::FUSE_DO_READDIRPLUS | fuse_abi::consts::FUSE_READDIRPLUS_AUTO;
+            }
+        }
+        #[cfg(all(feature = "abi-7-33", not(target_os = "macos")))]
+        {
+            if self.handle_killpriv_v2 {
+                se.capabilities.wanted |= fuse_abi::consts::FUSE_HANDLE_KILLPRIV_V2;
+            }
+        }
+    }
+}
 
 /// The session data structure
 #[derive(Debug)]
 pub struct Session<FS: Filesystem> {
+    /// Communication channel to the kernel driver. Dropped (i.e. closed and unmounted) before
+    /// the filesystem, so that the filesystem can clean up in `Drop` after it was unmounted.
+    ch: Channel,
     /// Filesystem operation implementations
     pub filesystem: FS,
-    /// Communication channel to the kernel driver
-    ch: Channel,
     /// FUSE protocol major version
     pub proto_major: u32,
     /// FUSE protocol minor version
     pub proto_minor: u32,
+    /// Capabilities the kernel driver reported in its init request
+    capabilities: ProtocolCapabilities,
     /// True if the filesystem is initialized (init operation done)
     pub initialized: bool,
-    /// True if the filesystem was destroyed (destroy operation done)
+    /// True if the filesystem was destroyed (destroy method called)
     pub destroyed: bool,
+    /// Notified once the filesystem is initialized (used by background sessions)
+    init_notify: Option<mpsc::Sender<()>>,
+    /// Tasks spawned by filesystem methods
+    tasks: TaskScope,
+    /// Time to wait for tasks to finish when the session ends
+    task_timeout: Duration,
+    /// Limit of outstanding requests
+    inflight: Option<Arc<InflightLimit>>,
+    /// Requests that wait for a reply
+    outstanding: Arc<OutstandingReplies>,
+    /// Whether request pids can be used for /proc lookups
+    pid_ns: PidNamespace,
+    /// Value of the fsname mount option (empty if not given), to label metrics
+    fsname: String,
+    /// Set before unmounting on behalf of the user
+    unmount_requested: Arc<AtomicBool>,
+    /// Why the session loop ended
+    shutdown: Arc<Mutex<Option<ShutdownReason>>>,
+    /// Id of the connection to the kernel driver, if mounted by the session
+    #[cfg(target_os = "linux")]
+    connection: Option<u64>,
+    /// Max size of write requests
+    max_write: usize,
+    /// Pool of request buffers that replace buffers taken by filesystem methods
+    buffers: Arc<BufferPool>,
+    /// Time to cache entries and attributes of replies without an explicit TTL
+    default_ttl: Duration,
+    /// Names of extended attributes that are answered with ENODATA by the session
+    negative_xattrs: Vec<OsString>,
+    /// Replied attributes to check for changes while they're cached
+    attrs: Option<Arc<AttrTracker>>,
+    /// Lookup counts and generations of inodes known to the kernel driver, if enabled
+    lookups: Option<Arc<LookupTracker>>,
+    /// Settings and statistics of fair dispatch, if enabled
+    fair_dispatch: Option<(FairDispatch, DispatchStats)>,
+    /// Cached results of access checks, if enabled
+    access: Option<Arc<AccessCache>>,
+    /// Cached values of extended attributes, if enabled
+    xattrs: Option<Arc<XAttrCache>>,
+    /// Filter that may refuse requests before they're dispatched
+    filter: Option<Box<dyn RequestFilter>>,
+    /// Switch that refuses modifying requests while enabled
+    read_only: Option<ReadOnlySwitch>,
+    /// Whether requests are checked against the negotiated capabilities
+    check_capabilities: bool,
+    /// Read-only state of the mount that the filesystem wasn't told yet, if watched
+    #[cfg(target_os = "linux")]
+    mount_flags: Option<Arc<Mutex<Option<bool>>>>,
+    /// Owners of inodes for removing file capabilities, if safe defaults are enabled
+    privs: Option<Arc<KillPriv>>,
+    /// Sends notifications to the kernel driver, closed before the channel
+    #[cfg(feature = "abi-7-12")]
+    notifier: Notifier,
+    /// Queues invalidations of entries that operations removed or renamed, if enabled
+    #[cfg(feature = "abi-7-12")]
+    invalidator: Option<EntryInvalidator>,
 }
 
 impl<FS: Filesystem> Session<FS> {
     /// Create a new session by mounting the given filesystem to the given mountpoint
     pub fn new(filesystem: FS, mountpoint: &Path, options: &[&OsStr]) -> io::Result<Session<FS>> {
         info!("Mounting {}", mountpoint.display());
+        #[cfg(target_os = "linux")]
+        let mount_ns = pid_namespace_id(Path::new("/proc"));
         Channel::new(mountpoint, options).map(|ch| {
-            Session {
-                filesystem: filesystem,
-                ch: ch,
-                proto_major: 0,
-                proto_minor: 0,
-                initialized: false,
-                destroyed: false,
+            let mut se = Session::with_channel(filesystem, ch);
+            se.fsname = metrics::fsname(options).unwrap_or_default();
+            #[cfg(target_os = "linux")]
+            {
+                se.pid_ns = PidNamespace::inspect(Path::new("/proc"), mount_ns);
+                se.connection = connection::connection_id(mountpoint).ok();
+            }
+            if se.pid_ns != PidNamespace::Same {
+                warn!("Request pids can't be used for /proc lookups (pid namespace {:?})", se.pid_ns);
             }
+            se
         })
     }
 
+    /// Create a new session for the given filesystem that uses the given channel
+    pub(crate) fn with_channel(filesystem: FS, ch: Channel) -> Session<FS> {
+        #[cfg(feature = "abi-7-12")]
+        let notifier = Notifier::new(ch.sender());
+        Session {
+            ch,
+            filesystem,
+            proto_major: 0,
+            proto_minor: 0,
+            capabilities: ProtocolCapabilities::wanting(INIT_FLAGS),
+            initialized: false,
+            destroyed: false,
+            init_notify: None,
+            tasks: TaskScope::new(),
+            task_timeout: DEFAULT_TASK_TIMEOUT,
+            inflight: None,
+            outstanding: Arc::new(OutstandingReplies::default()),
+            // There are no pid namespaces on other systems
+            pid_ns: PidNamespace::Same,
+            fsname: String::new(),
+            unmount_requested: Arc::new(AtomicBool::new(false)),
+            shutdown: Arc::default(),
+            #[cfg(target_os = "linux")]
+            connection: None,
+            max_write: MAX_WRITE_SIZE,
+            buffers: Arc::new(BufferPool::new(MAX_WRITE_SIZE + BUFFER_HEADROOM, DEFAULT_WRITE_PAYLOADS)),
+            default_ttl: DEFAULT_TTL,
+            negative_xattrs: Vec::new(),
+            attrs: None,
+            lookups: None,
+            fair_dispatch: None,
+            access: None,
+            xattrs: None,
+            filter: None,
+            read_only: None,
+            check_capabilities: false,
+            #[cfg(target_os = "linux")]
+            mount_flags: None,
+            privs: None,
+            #[cfg(feature = "abi-7-12")]
+            notifier,
+            #[cfg(feature = "abi-7-12")]
+            invalidator: None,
+        }
+    }
+
     /// Return path of the mounted filesystem
     pub fn mountpoint(&self) -> &Path {
         &self.ch.mountpoint()
     }
 
+    /// Returns the id of the connection to the kernel driver, as used in
+    /// /sys/fs/fuse/connections. Read from /proc/self/mountinfo.
+    #[cfg(target_os = "linux")]
+    pub fn connection_id(&self) -> io::Result<u64> {
+        connection::connection_id(self.mountpoint())
+    }
+
+    /// Returns the device number of the mounted filesystem. The kernel driver assigns every
+    /// mount its own device number and reports it as `st_dev` for all files of the
+    /// filesystem, so there's no device field in `FileAttr`. Read from /proc/self/mountinfo
+    /// (calling stat on the mountpoint instead would deadlock in the session loop).
+    ///
+    /// ```no_run
+    /// use std::fs;
+    /// use std::os::unix::fs::MetadataExt;
+    /// # struct NullFS;
+    /// # impl fuse::Filesystem for NullFS {}
+    /// # fn main() -> std::io::Result<()> {
+    /// let session = unsafe { fuse::spawn_mount(NullFS, "/mnt/null", &[])? };
+    /// assert_eq!(fs::metadata("/mnt/null")?.dev(), session.device_id()?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(target_os = "linux")]
+    pub fn device_id(&self) -> io::Result<u64> {
+        connection::device_id(self.mountpoint())
+    }
+
+    /// Returns the sysfs directory of the connection to the kernel driver, which can be used
+    /// to tune it at runtime (e.g. `max_background` and `congestion_threshold`). Returns
+    /// `None` if it doesn't exist (e.g. if the fusectl filesystem isn't mounted).
+    #[cfg(target_os = "linux")]
+    pub fn sysfs_dir(&self) -> Option<PathBuf> {
+        connection::sysfs_dir(self.mountpoint())
+    }
+
+    /// Abort the connection to the kernel driver. This is destructive and meant as a last
+    /// resort to unstick a hanging filesystem: all outstanding and future requests fail with
+    /// ENOTCONN until the filesystem is unmounted, and replies to outstanding requests are
+    /// lost. Needs the permission to write to the connection's abort file in sysfs. The
+    /// session loop ends with `ShutdownReason::Aborted`.
+    #[cfg(target_os = "linux")]
+    pub fn abort_connection(&self) -> io::Result<()> {
+        connection::abort_connection(self.mountpoint())
+    }
+
+    /// Remount the filesystem read-only, so that the kernel driver refuses modifying
+    /// operations itself. Only this mount is changed, its other options are kept. Needs
+    /// `CAP_SYS_ADMIN`, fails with `io::ErrorKind::PermissionDenied` otherwise (e.g. for
+    /// mounts made through fusermount). To refuse modifying requests without privileges, use
+    /// `SessionBuilder::read_only`.
+    #[cfg(target_os = "linux")]
+    pub fn remount_readonly(&self) -> io::Result<()> {
+        connection::remount(self.mountpoint(), true)
+    }
+
+    /// Remount the filesystem read-write (see `remount_readonly`)
+    #[cfg(target_os = "linux")]
+    pub fn remount_readwrite(&self) -> io::Result<()> {
+        connection::remount(self.mountpoint(), false)
+    }
+
+    /// Returns a handle to send notifications to the kernel driver (e.g. to invalidate
+    /// cached data), which can be used from other threads while the session is running
+    #[cfg(feature = "abi-7-12")]
+    pub fn notifier(&self) -> Notifier {
+        self.notifier.clone()
+    }
+
+    /// Use the given task scope for tasks spawned by filesystem methods (e.g. to run tasks
+    /// on a tokio runtime)
+    pub fn set_task_scope(&mut self, tasks: TaskScope) {
+        self.tasks = tasks;
+    }
+
+    /// Set the time to wait for tasks spawned by filesystem methods to finish when the
+    /// session ends
+    pub fn set_task_timeout(&mut self, timeout: Duration) {
+        self.task_timeout = timeout;
+    }
+
+    /// Returns the protocol version and capabilities of the kernel driver (version and flags
+    /// are zero before the filesystem is initialized)
+    pub fn capabilities(&self) -> ProtocolCapabilities {
+        self.capabilities
+    }
+
+    /// Remember the protocol version and capabilities of the kernel driver
+    pub(crate) fn set_capabilities(&mut self, capabilities: ProtocolCapabilities) {
+        self.proto_major = capabilities.major;
+        self.proto_minor = capabilities.minor;
+        self.capabilities = capabilities;
+    }
+
+    /// Returns the max size of write requests
+    pub(crate) fn max_write(&self) -> usize {
+        self.max_write
+    }
+
+    /// Returns true if getxattr requests for the given name should be answered with ENODATA
+    /// without calling the filesystem
+    pub(crate) fn is_negative_xattr(&self, name: &OsStr) -> bool {
+        self.negative_xattrs.iter().any(|n| n == name)
+    }
+
+    /// Returns the dispatch statistics if requests are dispatched fairly (see
+    /// `SessionBuilder::fair_dispatch`). The statistics can be inspected from other threads
+    /// while the session is running.
+    pub fn dispatch_stats(&self) -> Option<DispatchStats> {
+        self.fair_dispatch.as_ref().map(|(_, stats)| stats.clone())
+    }
+
+    /// Returns the sizes of the maps that keep track of inodes for tracking attribute changes
+    /// and safe defaults (zero if disabled). The limit of their sizes can be set with
+    /// `SessionBuilder::max_tracked_inodes`.
+    pub fn tracking_stats(&self) -> TrackingStats {
+        TrackingStats::collect(self.attrs.as_ref(), self.privs.as_ref(), self.lookups.as_ref())
+    }
+
+    /// Returns a handle to render the metrics of the session (request counters, tracking
+    /// stats and latency histograms, see `SessionBuilder::latency_histograms`) in OpenMetrics
+    /// text format, labeled with the fsname mount option and the mountpoint. Like the
+    /// notifier, it can be used from other threads while the session is running.
+    pub fn metrics(&self) -> Metrics {
+        Metrics::new(&self.fsname, self.mountpoint(), self.outstanding.clone(), self.attrs.clone(), self.privs.clone(), self.lookups.clone())
+    }
+
+    /// Render the metrics of the session in OpenMetrics text format (see `Session::metrics`)
+    pub fn render_openmetrics(&self) -> String {
+        self.metrics().render_openmetrics()
+    }
+
+    /// Returns the cache of access checks, if enabled
+    pub(crate) fn access_cache(&self) -> Option<&Arc<AccessCache>> {
+        self.access.as_ref()
+    }
+
+    /// Returns the request filter, if any
+    pub(crate) fn request_filter(&self) -> Option<&dyn RequestFilter> {
+        self.filter.as_ref().map(|filter| filter.as_ref())
+    }
+
+    /// Returns the switch that refuses modifying requests, if any
+    pub(crate) fn read_only(&self) -> Option<&ReadOnlySwitch> {
+        self.read_only.as_ref()
+    }
+
+    /// Returns true if requests are checked against the negotiated capabilities
+    pub(crate) fn check_capabilities(&self) -> bool {
+        self.check_capabilities
+    }
+
+    /// Tell the filesystem if the mount was made read-only or read-write since the last
+    /// request, if the mount is watched and the filesystem is initialized
+    pub(crate) fn notify_mount_flags(&mut self) {
+        #[cfg(target_os = "linux")]
+        {
+            if !self.initialized {
+                return;
+            }
+            let changed = self.mount_flags.as_ref().and_then(|changed| changed.lock().unwrap().take());
+            if let Some(readonly) = changed {
+                info!("{} was made {}", self.mountpoint().display(), if readonly { "read-only" } else { "read-write" });
+                self.filesystem.mount_flags_changed(readonly);
+            }
+        }
+    }
+
+    /// Returns the cache of extended attribute values, if enabled
+    pub(crate) fn xattr_cache(&self) -> Option<&Arc<XAttrCache>> {
+        self.xattrs.as_ref()
+    }
+
+    /// Returns the time to cache entries and attributes of replies without an explicit TTL
+    pub(crate) fn default_ttl(&self) -> Duration {
+        self.default_ttl
+    }
+
+    /// Returns the owners of inodes for removing file capabilities, if safe defaults are enabled
+    pub(crate) fn kill_priv(&self) -> Option<&Arc<KillPriv>> {
+        self.privs.as_ref()
+    }
+
+    /// Returns the tracker of replied attributes, if attribute changes are tracked
+    pub(crate) fn attr_tracker(&self) -> Option<&Arc<AttrTracker>> {
+        self.attrs.as_ref()
+    }
+
+    /// Returns the tracker of lookup counts, if lookups are tracked
+    pub(crate) fn lookup_tracker(&self) -> Option<&Arc<LookupTracker>> {
+        self.lookups.as_ref()
+    }
+
+    /// Returns the invalidation of the given entries to send after replying success to the
+    /// operation that removed or renamed them, if entries are invalidated automatically
+    #[cfg(feature = "abi-7-12")]
+    pub(crate) fn entry_invalidation(&self, entries: &[(u64, &OsStr)]) -> Option<EntryInvalidation> {
+        match (self.lookups.as_ref(), self.invalidator.as_ref()) {
+            (Some(lookups), Some(invalidator)) => EntryInvalidation::new(invalidator, lookups, entries),
+            _ => None,
+        }
+    }
+
+    /// Mark the filesystem as initialized and notify anyone waiting for it
+    pub(crate) fn set_initialized(&mut self) {
+        self.initialized = true;
+        if let Some(tx) = self.init_notify.take() {
+            let _ = tx.send(());
+        }
+    }
+
+    /// Call the filesystem's pre_unmount and destroy methods with the given reason, unless
+    /// they were called already or the filesystem was never initialized
+    pub(crate) fn destroy(&mut self, reason: ShutdownReason) {
+        if self.initialized && !self.destroyed {
+            self.destroyed = true;
+            if let Err(err) = self.filesystem.pre_unmount() {
+                error!("Failed to flush filesystem before unmount: {}", io::Error::from_raw_os_error(err));
+            }
+            self.filesystem.destroy(reason);
+        }
+    }
+
     /// Run the session loop that receives kernel requests and dispatches them to method
     /// calls into the filesystem. This read-dispatch-loop is non-concurrent to prevent
     /// having multiple buffers (which take up much memory), but the filesystem methods
     /// may run concurrent by spawning threads.
+    ///
+    /// If sending a reply fails because the filesystem was unmounted (ENODEV), the loop ends
+    /// before dispatching the next request, like if the kernel driver closed the connection.
+    ///
+    /// Before returning, the filesystem's destroy method is called (if the kernel didn't
+    /// request it already). This happens before the session is dropped, i.e. before the
+    /// filesystem gets unmounted if it's still mounted.
     pub fn run(&mut self) -> io::Result<()> {
+        self.run_loop(None)
+    }
+
+    /// Run the session loop like `run` and return statistics of the requests the session
+    /// handled (since it was created) once the loop ends. Useful for logging on shutdown.
+    pub fn run_with_summary(&mut self) -> io::Result<RunSummary> {
+        let started = Instant::now();
+        self.run_loop(None)?;
+        Ok(self.outstanding.summary(started.elapsed()))
+    }
+
+    /// Run the session loop like `run`, until the given fd (e.g. an eventfd or the read end of
+    /// a pipe) becomes readable. Then the filesystem's destroy method is called and the
+    /// filesystem gets unmounted. Requests that the kernel driver sent in the meantime aren't
+    /// dispatched anymore. The fd is neither read from nor closed.
+    ///
+    /// This allows to shut down a session from another thread without unmounting the
+    /// filesystem from there while the session is still running.
+    pub fn run_until(mut self, shutdown: RawFd) -> io::Result<()> {
+        self.run_loop(Some(shutdown))
+    }
+
+    /// Run the session loop, optionally until the given fd becomes readable
+    fn run_loop(&mut self, shutdown: Option<RawFd>) -> io::Result<()> {
+        let res = match self.fair_dispatch.clone() {
+            Some((config, stats)) => self.fair_receive_loop(shutdown, FairQueue::new(&config, stats), config.queued_bytes_limit()),
+            None => self.receive_loop(shutdown),
+        };
+        let (reason, res) = match res {
+            Ok(reason) => (reason, Ok(())),
+            Err(err) => (ShutdownReason::LoopError, Err(err)),
+        };
+        if let ShutdownReason::Aborted { still_mounted } = reason {
+            warn!("Connection to the kernel driver was aborted, {} is {}", self.mountpoint().display(), if still_mounted { "still mounted" } else { "detached" });
+        }
+        *self.shutdown.lock().unwrap() = Some(reason);
+        self.destroy(reason);
+        res
+    }
+
+    /// Returns why the session loop ended, or `None` if it didn't run (yet). Supervisors can
+    /// tell from this whether the filesystem was unmounted or whether its connection was
+    /// aborted, in which case it should be unmounted lazily if it's still mounted (e.g.
+    /// using `supervisor::unmount_lazy`) before mounting it again.
+    pub fn shutdown_reason(&self) -> Option<ShutdownReason> {
+        *self.shutdown.lock().unwrap()
+    }
+
+    /// Receive and dispatch requests until the connection ends or the given fd becomes
+    /// readable. Returns why it ended.
+    fn receive_loop(&mut self, shutdown: Option<RawFd>) -> io::Result<ShutdownReason> {
         // Buffer for receiving requests from the kernel. Only one is allocated and
-        // it is reused immediately after dispatching to conserve memory and allocations.
-        let mut buffer: Vec<u8> = Vec::with_capacity(BUFFER_SIZE);
+        // it is reused immediately after dispatching to conserve memory and allocations
+        // (unless a filesystem method took a write payload from it).
+        let mut buffer = RequestBuffer::new(self.buffers.clone());
         loop {
-            // Read the next request from the given channel to kernel driver
-            // The kernel driver makes sure that we get exactly one request per read
-            match self.ch.receive(&mut buffer) {
-                Ok(()) => match Request::new(self.ch.sender(), &buffer) {
+            // Wait for outstanding requests to complete if there are too many
+            if let Some(ref limit) = self.inflight {
+                limit.wait();
+            }
+            if self.outstanding.device_gone() {
+                info!("Filesystem was unmounted while replying, ending session loop");
+                return Ok(self.closed_reason());
+            }
+            // Wait for the next request unless a shutdown is requested first
+            if let Some(fd) = shutdown {
+                if self.ch.wait_unless(fd)? {
+                    info!("Shutdown requested, ending session loop");
+                    return Ok(ShutdownReason::UserRequested);
+                }
+            }
+            match self.receive(&mut buffer)? {
+                Received::Request => match Request::new(self.ch.sender(), &self.tasks, &self.outstanding, self.inflight.as_ref(), self.pid_ns, self.capabilities, &buffer) {
                     // Dispatch request
-                    Some(req) => req.dispatch(self),
+                    Ok(req) => req.dispatch(self),
+                    // Unknown operations were replied already
+                    Err(ll::RequestError::UnknownOperation(_)) => (),
                     // Quit loop on illegal request
-                    None => break,
+                    Err(_) => return Ok(ShutdownReason::LoopError),
                 },
-                Err(err) => match err.raw_os_error() {
-                    // Operation interrupted. Accordingly to FUSE, this is safe to retry
-                    Some(ENOENT) => continue,
-                    // Interrupted system call, retry
-                    Some(EINTR) => continue,
-                    // Explicitly try again
-                    Some(EAGAIN) => continue,
-                    // Filesystem was unmounted, quit the loop
-                    Some(ENODEV) => break,
-                    // Unhandled error
-                    _ => return Err(err),
+                Received::Nothing => (),
+                Received::Closed(reason) => return Ok(reason),
+            }
+        }
+    }
+
+    /// Like `receive_loop`, but reads requests ahead into the given queues (up to the given
+    /// number of bytes) and dispatches them fairly
+    fn fair_receive_loop(&mut self, shutdown: Option<RawFd>, mut queue: FairQueue<RequestBuffer>, max_queued_bytes: usize) -> io::Result<ShutdownReason> {
+        let mut buffer = RequestBuffer::new(self.buffers.clone());
+        // Queued requests are still dispatched after the connection was closed
+        let mut closed = None;
+        loop {
+            // Wait for outstanding requests to complete if there are too many
+            if let Some(ref limit) = self.inflight {
+                limit.wait();
+            }
+            if self.outstanding.device_gone() {
+                info!("Filesystem was unmounted while replying, ending session loop");
+                return Ok(self.closed_reason());
+            }
+            if let Some(fd) = shutdown {
+                let requested = if queue.is_empty() && closed.is_none() { self.ch.wait_unless(fd)? } else { channel::is_readable(fd)? };
+                if requested {
+                    info!("Shutdown requested, ending session loop");
+                    return Ok(ShutdownReason::UserRequested);
+                }
+            }
+            // Wait for the next request if there's none queued, then read ahead what's ready
+            let mut block = queue.is_empty();
+            while closed.is_none() && queue.queued_bytes() < max_queued_bytes && (block || self.ch.is_ready()?) {
+                match self.receive(&mut buffer)? {
+                    Received::Request => {
+                        let (class, pid) = match ll::Request::try_from(buffer.data()) {
+                            Ok(req) => (RequestClass::of(&req), req.pid()),
+                            // Fails again when dispatched
+                            Err(_) => (RequestClass::Metadata, 0),
+                        };
+                        let len = buffer.data().len();
+                        queue.push(buffer.copy(), class, pid, len, Instant::now());
+                        block = false;
+                    }
+                    Received::Nothing => (),
+                    Received::Closed(reason) => closed = Some(reason),
                 }
             }
+            let queued = match queue.pop(Instant::now()) {
+                Some(queued) => queued,
+                None => return Ok(closed.unwrap_or(ShutdownReason::DeviceClosed)),
+            };
+            match Request::new(self.ch.sender(), &self.tasks, &self.outstanding, self.inflight.as_ref(), self.pid_ns, self.capabilities, &queued) {
+                Ok(req) => req.dispatch(self),
+                Err(ll::RequestError::UnknownOperation(_)) => (),
+                Err(_) => return Ok(ShutdownReason::LoopError),
+            }
         }
-        Ok(())
+    }
+
+    /// Receive the next request into the given buffer
+    fn receive(&self, buffer: &mut RequestBuffer) -> io::Result<Received> {
+        // Read the next request from the given channel to kernel driver
+        // The kernel driver makes sure that we get exactly one request per read
+        match self.ch.receive(buffer.receive_buffer()) {
+            // End of file, the connection was closed
+            Ok(()) if buffer.data().is_empty() => Ok(Received::Closed(self.closed_reason())),
+            Ok(()) => Ok(Received::Request),
+            Err(err) => match err.raw_os_error() {
+                // Operation interrupted. Accordingly to FUSE, this is safe to retry
+                Some(ENOENT) => Ok(Received::Nothing),
+                // Interrupted system call, retry
+                Some(EINTR) => Ok(Received::Nothing),
+                // Explicitly try again
+                Some(EAGAIN) => Ok(Received::Nothing),
+                // Filesystem was unmounted, quit the loop
+                Some(ENODEV) => Ok(Received::Closed(self.closed_reason())),
+                // Unhandled error
+                _ => Err(err),
+            }
+        }
+    }
+
+    /// Returns the requests that wait for a reply
+    #[cfg(test)]
+    pub(crate) fn outstanding(&self) -> &Arc<OutstandingReplies> {
+        &self.outstanding
+    }
+
+    /// Remember that the filesystem is about to be unmounted on behalf of the user
+    #[cfg(test)]
+    pub(crate) fn request_unmount(&self) {
+        self.unmount_requested.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns the reason for the connection being closed. The kernel driver closes it the
+    /// same way if it's aborted, so the mount table tells whether it was unmounted.
+    fn closed_reason(&self) -> ShutdownReason {
+        if self.unmount_requested.load(Ordering::SeqCst) {
+            return ShutdownReason::UserRequested;
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(still_mounted) = self.connection.and_then(|id| connection::aborted_connection(self.mountpoint(), id)) {
+                return ShutdownReason::Aborted { still_mounted };
+            }
+        }
+        ShutdownReason::DeviceClosed
     }
 }
 
@@ -109,6 +1744,16 @@ impl<'a, FS: Filesystem + Send + 'a> Session<FS> {
 
 impl<FS: Filesystem> Drop for Session<FS> {
     fn drop(&mut self) {
+        // Give tasks a chance to finish (and reply) before the channel gets closed
+        self.tasks.shutdown(self.task_timeout);
+        self.outstanding.flush(self.task_timeout);
+        #[cfg(feature = "abi-7-12")]
+        {
+            if let Some(ref invalidator) = self.invalidator {
+                invalidator.flush(self.task_timeout);
+            }
+            self.notifier.close();
+        }
         info!("Unmounted {}", self.mountpoint().display());
     }
 }
@@ -117,27 +1762,115 @@ impl<FS: Filesystem> Drop for Session<FS> {
 pub struct BackgroundSession<'a> {
     /// Path of the mounted filesystem
     pub mountpoint: PathBuf,
+    /// Set before unmounting, so that the session knows why it ends
+    unmount_requested: Arc<AtomicBool>,
+    /// Why the session loop ended
+    shutdown: Arc<Mutex<Option<ShutdownReason>>>,
+    /// Sends notifications to the kernel driver
+    #[cfg(feature = "abi-7-12")]
+    notifier: Notifier,
     /// Thread guard of the background session
     pub guard: JoinGuard<'a, io::Result<()>>,
 }
 
 impl<'a> BackgroundSession<'a> {
     /// Create a new background session for the given session by running its
-    /// session loop in a background thread. If the returned handle is dropped,
-    /// the filesystem is unmounted and the given session ends.
-    pub unsafe fn new<FS: Filesystem + Send + 'a>(se: Session<FS>) -> io::Result<BackgroundSession<'a>> {
+    /// session loop in a background thread. This waits until the kernel driver
+    /// initialized the filesystem, so the filesystem is ready to be used as soon
+    /// as this returns. If the returned handle is dropped, the filesystem is
+    /// unmounted and the given session ends.
+    pub unsafe fn new<FS: Filesystem + Send + 'a>(mut se: Session<FS>) -> io::Result<BackgroundSession<'a>> {
         let mountpoint = se.mountpoint().to_path_buf();
+        let unmount_requested = se.unmount_requested.clone();
+        let shutdown = se.shutdown.clone();
+        #[cfg(feature = "abi-7-12")]
+        let notifier = se.notifier();
+        let (tx, rx) = mpsc::channel();
+        se.init_notify = Some(tx);
         let guard = scoped(move || {
             let mut se = se;
             se.run()
         });
-        Ok(BackgroundSession { mountpoint: mountpoint, guard: guard })
+        // The sender is dropped without notifying if the session loop ends before init
+        match rx.recv() {
+            Ok(()) => Ok(BackgroundSession {
+                mountpoint,
+                unmount_requested,
+                shutdown,
+                #[cfg(feature = "abi-7-12")]
+                notifier,
+                guard,
+            }),
+            Err(_) => match guard.join() {
+                Err(err) => Err(err),
+                // Filesystem was unmounted before it was initialized
+                Ok(()) => Err(io::Error::from_raw_os_error(ENODEV)),
+            },
+        }
+    }
+
+    /// Returns why the session loop ended, or `None` if it's still running (see
+    /// `Session::shutdown_reason`)
+    pub fn shutdown_reason(&self) -> Option<ShutdownReason> {
+        *self.shutdown.lock().unwrap()
+    }
+
+    /// Returns a handle to send notifications to the kernel driver (see `Session::notifier`)
+    #[cfg(feature = "abi-7-12")]
+    pub fn notifier(&self) -> Notifier {
+        self.notifier.clone()
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl<'a> BackgroundSession<'a> {
+    /// Returns the id of the connection to the kernel driver (see `Session::connection_id`)
+    pub fn connection_id(&self) -> io::Result<u64> {
+        connection::connection_id(&self.mountpoint)
+    }
+
+    /// Returns the device number of the mounted filesystem (see `Session::device_id`)
+    pub fn device_id(&self) -> io::Result<u64> {
+        connection::device_id(&self.mountpoint)
+    }
+
+    /// Returns the sysfs directory of the connection to the kernel driver (see
+    /// `Session::sysfs_dir`)
+    pub fn sysfs_dir(&self) -> Option<PathBuf> {
+        connection::sysfs_dir(&self.mountpoint)
+    }
+
+    /// Abort the connection to the kernel driver (see `Session::abort_connection`, this is
+    /// destructive)
+    pub fn abort_connection(&self) -> io::Result<()> {
+        connection::abort_connection(&self.mountpoint)
+    }
+
+    /// Remount the filesystem read-only (see `Session::remount_readonly`)
+    pub fn remount_readonly(&self) -> io::Result<()> {
+        connection::remount(&self.mountpoint, true)
+    }
+
+    /// Remount the filesystem read-write (see `Session::remount_readwrite`)
+    pub fn remount_readwrite(&self) -> io::Result<()> {
+        connection::remount(&self.mountpoint, false)
     }
 }
 
 impl<'a> Drop for BackgroundSession<'a> {
     fn drop(&mut self) {
         info!("Unmounting {}", self.mountpoint.display());
+        self.unmount_requested.store(true, Ordering::SeqCst);
+        // An aborted filesystem may be busy, but can be detached
+        #[cfg(target_os = "linux")]
+        {
+            if self.shutdown_reason() == Some(ShutdownReason::Aborted { still_mounted: true }) {
+                if let Err(err) = supervisor::unmount_lazy(&self.mountpoint) {
+                    error!("Failed to unmount {}: {}", self.mountpoint.display(), err);
+                }
+                return;
+            }
+        }
         // Unmounting the filesystem will eventually end the session loop,
         // drop the session and hence end the background thread.
         match channel::unmount(&self.mountpoint) {
@@ -154,3 +1887,98 @@ impl<'a> fmt::Debug for BackgroundSession<'a> {
         write!(f, "BackgroundSession {{ mountpoint: {:?}, guard: JoinGuard<()> }}", self.mountpoint)
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+    use fuse_abi::consts::FUSE_MIN_READ_BUFFER;
+    use super::{InflightLimit, OutstandingReplies, SessionBuilder, MAX_WRITE_SIZE};
+
+    #[test]
+    #[cfg(all(feature = "abi-7-32", not(target_os = "macos")))]
+    fn submount_capability() {
+        use fuse_abi::consts::FUSE_SUBMOUNTS;
+        use super::ProtocolCapabilities;
+        let capabilities = ProtocolCapabilities::default();
+        assert!(capabilities.init(7, 32, 0, FUSE_SUBMOUNTS).submounts());
+        assert!(!capabilities.init(7, 32, 0, 0).submounts());
+        // The flag isn't defined before ABI 7.32
+        assert!(!capabilities.init(7, 31, 0, FUSE_SUBMOUNTS).submounts());
+    }
+
+    #[test]
+    fn offered_and_negotiated_capabilities() {
+        use fuse_abi::consts::{FUSE_ASYNC_READ, FUSE_POSIX_LOCKS};
+        use fuse_abi::FUSE_KERNEL_MINOR_VERSION;
+        use super::{InitFlags, ProtocolCapabilities};
+        // Not defined before ABI 7.9
+        const FUSE_BIG_WRITES: u32 = 1 << 5;
+        // A newer kernel offers more than the session wants
+        let capabilities = ProtocolCapabilities::wanting(FUSE_ASYNC_READ | FUSE_BIG_WRITES).init(7, 99, 128 * 1024, FUSE_ASYNC_READ | FUSE_POSIX_LOCKS);
+        assert_eq!(capabilities.version(), (7, 99));
+        assert_eq!(capabilities.negotiated_version(), (7, FUSE_KERNEL_MINOR_VERSION));
+        assert_eq!(capabilities.max_readahead(), 128 * 1024);
+        assert_eq!(capabilities.flags(), FUSE_ASYNC_READ | FUSE_POSIX_LOCKS);
+        assert_eq!(capabilities.negotiated_flags(), FUSE_ASYNC_READ);
+        assert_eq!(format!("{:?}", capabilities), format!(
+            "ProtocolCapabilities {{ version: (7, 99), negotiated_version: (7, {}), max_readahead: 131072, \
+             flags: FUSE_ASYNC_READ | FUSE_POSIX_LOCKS, wanted_flags: FUSE_ASYNC_READ | FUSE_BIG_WRITES, \
+             negotiated_flags: FUSE_ASYNC_READ }}", FUSE_KERNEL_MINOR_VERSION));
+        // An older kernel's version is negotiated
+        assert_eq!(capabilities.init(7, 6, 0, 0).negotiated_version(), (7, 6));
+        assert_eq!(InitFlags(0).to_string(), "0");
+        #[cfg(not(target_os = "macos"))]
+        assert_eq!(InitFlags(FUSE_BIG_WRITES | 3 << 30).to_string(), "FUSE_BIG_WRITES | 0xc0000000");
+    }
+
+    #[test]
+    fn inflight_limit() {
+        let limit = Arc::new(InflightLimit::new(2));
+        let guard1 = InflightLimit::track(&limit);
+        let _guard2 = InflightLimit::track(&limit);
+        let (tx, rx) = channel();
+        let reader_limit = limit.clone();
+        thread::spawn(move || {
+            reader_limit.wait();
+            tx.send(()).unwrap();
+        });
+        // The reader pauses while the limit is reached...
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        // ...and continues as soon as an outstanding request completes
+        drop(guard1);
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn inflight_limit_of_zero() {
+        // A limit of 0 would never let the reader continue
+        assert_eq!(SessionBuilder::new().max_inflight(0).max_inflight, Some(1));
+        let limit = InflightLimit::new(1);
+        limit.wait();
+    }
+
+    #[test]
+    fn unmounted_while_replying() {
+        let outstanding = OutstandingReplies::default();
+        outstanding.sent(Ok(()));
+        outstanding.sent(Err(io::Error::from_raw_os_error(libc::EBADF)));
+        assert!(!outstanding.device_gone());
+        outstanding.sent(Err(io::Error::from_raw_os_error(libc::ENODEV)));
+        assert!(outstanding.device_gone());
+    }
+
+    #[test]
+    fn max_write_size_is_clamped() {
+        let builder = SessionBuilder::new().max_write_size(1024);
+        assert_eq!(builder.max_write_size, Some(FUSE_MIN_READ_BUFFER));
+        let builder = SessionBuilder::new().max_write_size(128 * 1024);
+        assert_eq!(builder.max_write_size, Some(128 * 1024));
+        let builder = SessionBuilder::new().max_write_size(64 * 1024 * 1024);
+        assert_eq!(builder.max_write_size, Some(MAX_WRITE_SIZE));
+    }
+}
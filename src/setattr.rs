@@ -0,0 +1,232 @@
+//! Attribute changes of setattr requests
+//!
+//! A single setattr request can change several attributes at once (e.g. `open` with
+//! `O_TRUNC` on a file with setuid bits, or `utimes` after a copy), and the replied attributes
+//! must reflect all of them. `SetattrChanges` holds the changes of a request and tells in
+//! which order to apply them, `TruncateEffect` what a size change does to the file's data.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use fuse_abi::fuse_setattr_in;
+use fuse_abi::consts::*;
+
+/// Attributes to change by a setattr request (see `Filesystem::setattr_changes`)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SetattrChanges {
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    size: Option<u64>,
+    atime: Option<SystemTime>,
+    mtime: Option<SystemTime>,
+    fh: Option<u64>,
+    crtime: Option<SystemTime>,
+    chgtime: Option<SystemTime>,
+    bkuptime: Option<SystemTime>,
+    flags: Option<u32>,
+}
+
+/// Returns the given value if the given bit is set in the valid bits
+fn valid<T>(valid: u32, bit: u32, value: T) -> Option<T> {
+    match valid & bit {
+        0 => None,
+        _ => Some(value),
+    }
+}
+
+impl SetattrChanges {
+    /// Changes of the given setattr request
+    pub(crate) fn new(arg: &fuse_setattr_in) -> SetattrChanges {
+        let changes = SetattrChanges {
+            mode: valid(arg.valid, FATTR_MODE, arg.mode),
+            uid: valid(arg.valid, FATTR_UID, arg.uid),
+            gid: valid(arg.valid, FATTR_GID, arg.gid),
+            size: valid(arg.valid, FATTR_SIZE, arg.size),
+            atime: valid(arg.valid, FATTR_ATIME, UNIX_EPOCH + Duration::new(arg.atime, arg.atimensec)),
+            mtime: valid(arg.valid, FATTR_MTIME, UNIX_EPOCH + Duration::new(arg.mtime, arg.mtimensec)),
+            fh: valid(arg.valid, FATTR_FH, arg.fh),
+            ..SetattrChanges::default()
+        };
+        #[cfg(target_os = "macos")]
+        let changes = SetattrChanges {
+            crtime: valid(arg.valid, FATTR_CRTIME, UNIX_EPOCH + Duration::new(arg.crtime, arg.crtimensec)),
+            chgtime: valid(arg.valid, FATTR_CHGTIME, UNIX_EPOCH + Duration::new(arg.chgtime, arg.chgtimensec)),
+            bkuptime: valid(arg.valid, FATTR_BKUPTIME, UNIX_EPOCH + Duration::new(arg.bkuptime, arg.bkuptimensec)),
+            flags: valid(arg.valid, FATTR_FLAGS, arg.flags),
+            ..changes
+        };
+        changes
+    }
+
+    /// New file mode (including the file type bits), if it changes
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+    }
+
+    /// New owner, if it changes
+    pub fn uid(&self) -> Option<u32> {
+        self.uid
+    }
+
+    /// New group, if it changes
+    pub fn gid(&self) -> Option<u32> {
+        self.gid
+    }
+
+    /// New size, if it changes (see `truncate`)
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// New access time, if it changes
+    pub fn atime(&self) -> Option<SystemTime> {
+        self.atime
+    }
+
+    /// New modification time, if it changes
+    pub fn mtime(&self) -> Option<SystemTime> {
+        self.mtime
+    }
+
+    /// File handle of the open file the request was made with (e.g. by `ftruncate`), if any
+    pub fn fh(&self) -> Option<u64> {
+        self.fh
+    }
+
+    /// New creation time, if it changes (macOS only)
+    pub fn crtime(&self) -> Option<SystemTime> {
+        self.crtime
+    }
+
+    /// New change time, if it changes (macOS only)
+    pub fn chgtime(&self) -> Option<SystemTime> {
+        self.chgtime
+    }
+
+    /// New backup time, if it changes (macOS only)
+    pub fn bkuptime(&self) -> Option<SystemTime> {
+        self.bkuptime
+    }
+
+    /// New file flags (see chflags(2)), if they change (macOS only)
+    pub fn flags(&self) -> Option<u32> {
+        self.flags
+    }
+
+    /// Returns true if no attribute changes
+    pub fn is_empty(&self) -> bool {
+        self.apply_order().is_empty()
+    }
+
+    /// Returns the steps to apply the changes in. The size is changed first, while the file's
+    /// permissions are still the old ones (a new mode may not allow writing anymore). The
+    /// owner is changed before the mode, since changing the owner clears setuid and setgid
+    /// bits that a new mode may set again. Truncating and changing the owner or mode update
+    /// the file's times, so times are set after that. Flags come last, since they can make
+    /// the file immutable.
+    pub fn apply_order(&self) -> Vec<SetattrStep> {
+        let mut steps = Vec::new();
+        if self.size.is_some() {
+            steps.push(SetattrStep::Size);
+        }
+        if self.uid.is_some() || self.gid.is_some() {
+            steps.push(SetattrStep::Owner);
+        }
+        if self.mode.is_some() {
+            steps.push(SetattrStep::Mode);
+        }
+        if self.atime.is_some() || self.mtime.is_some() || self.crtime.is_some() || self.chgtime.is_some() || self.bkuptime.is_some() {
+            steps.push(SetattrStep::Times);
+        }
+        if self.flags.is_some() {
+            steps.push(SetattrStep::Flags);
+        }
+        steps
+    }
+
+    /// Returns what changing the size does to the data of a file of the given current size
+    pub fn truncate(&self, current_size: u64) -> TruncateEffect {
+        match self.size {
+            Some(size) => TruncateEffect::new(current_size, size),
+            None => TruncateEffect::NoChange,
+        }
+    }
+}
+
+/// Step of applying attribute changes (see `SetattrChanges::apply_order`)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SetattrStep {
+    /// Change the size (see `SetattrChanges::truncate`)
+    Size,
+    /// Change the owner and/or group
+    Owner,
+    /// Change the mode
+    Mode,
+    /// Change the access, modification and (on macOS) other times
+    Times,
+    /// Change the file flags (macOS only)
+    Flags,
+}
+
+/// Effect of changing the size of a file on its data
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TruncateEffect {
+    /// The size stays the same
+    NoChange,
+    /// The file shrinks: data at and beyond the given offset (the new size) is discarded
+    DiscardFrom(u64),
+    /// The file grows to the given size: data between the current and the new size reads as
+    /// zeros (it may be a hole)
+    ZeroExtendTo(u64),
+}
+
+impl TruncateEffect {
+    /// Returns the effect of changing the size of a file from the current to the new size
+    pub fn new(current_size: u64, new_size: u64) -> TruncateEffect {
+        if new_size < current_size {
+            TruncateEffect::DiscardFrom(new_size)
+        } else if new_size > current_size {
+            TruncateEffect::ZeroExtendTo(new_size)
+        } else {
+            TruncateEffect::NoChange
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::UNIX_EPOCH;
+    use super::{SetattrChanges, SetattrStep, TruncateEffect};
+
+    #[test]
+    fn truncate_effect() {
+        assert_eq!(TruncateEffect::new(10, 4), TruncateEffect::DiscardFrom(4));
+        assert_eq!(TruncateEffect::new(10, 0), TruncateEffect::DiscardFrom(0));
+        assert_eq!(TruncateEffect::new(4, 10), TruncateEffect::ZeroExtendTo(10));
+        assert_eq!(TruncateEffect::new(0, 1), TruncateEffect::ZeroExtendTo(1));
+        assert_eq!(TruncateEffect::new(10, 10), TruncateEffect::NoChange);
+        let changes = SetattrChanges { size: Some(3), ..SetattrChanges::default() };
+        assert_eq!(changes.truncate(5), TruncateEffect::DiscardFrom(3));
+        assert_eq!(SetattrChanges::default().truncate(5), TruncateEffect::NoChange);
+    }
+
+    #[test]
+    fn truncate_and_chmod() {
+        let changes = SetattrChanges { mode: Some(0o100444), size: Some(0), ..SetattrChanges::default() };
+        assert_eq!(changes.apply_order(), [SetattrStep::Size, SetattrStep::Mode]);
+    }
+
+    #[test]
+    fn apply_order() {
+        assert!(SetattrChanges::default().is_empty());
+        // The file handle isn't a change
+        assert!(SetattrChanges { fh: Some(1), ..SetattrChanges::default() }.is_empty());
+        let changes = SetattrChanges { gid: Some(100), mtime: Some(UNIX_EPOCH), ..SetattrChanges::default() };
+        assert_eq!(changes.apply_order(), [SetattrStep::Owner, SetattrStep::Times]);
+        let changes = SetattrChanges {
+            mode: Some(0o104755), uid: Some(0), gid: Some(0), size: Some(1), atime: Some(UNIX_EPOCH),
+            flags: Some(0), ..SetattrChanges::default()
+        };
+        assert_eq!(changes.apply_order(), [SetattrStep::Size, SetattrStep::Owner, SetattrStep::Mode, SetattrStep::Times, SetattrStep::Flags]);
+    }
+}
@@ -0,0 +1,280 @@
+//! Filesystem statistics
+//!
+//! Statistics replied to statfs requests (see `ReplyStatfs::stats`). The kernel driver passes
+//! them on to statfs(2) and statvfs(3), except for the flags (`f_flag`, e.g. `ST_RDONLY`) and
+//! the filesystem id, which the protocol can't carry: the flags are taken from the mount
+//! options and the filesystem id is always 0. The maximum length of file names reaches
+//! userspace as `f_namemax`.
+//!
+//! Filesystems that can't tell their size (e.g. network or virtual filesystems) shouldn't
+//! reply with counts of 0, which look like a full filesystem. Counts with all bits set (like
+//! `(fsblkcnt_t)-1`) are the convention for unknown values instead: `df` shows them as `-`.
+//! `StatFs::unknown` replies with such counts.
+
+use std::convert::TryInto;
+use std::{error, fmt};
+
+/// Statistics of a filesystem, built and checked with `StatFs::builder` (or converted from
+/// the statistics of another filesystem with `StatFs::from_statvfs`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StatFs {
+    blocks: u64,
+    bfree: u64,
+    bavail: u64,
+    files: u64,
+    ffree: u64,
+    bsize: u32,
+    namelen: u32,
+    frsize: u32,
+}
+
+impl StatFs {
+    /// Create a builder for filesystem statistics. See `StatFsBuilder` for defaults.
+    pub fn builder() -> StatFsBuilder {
+        StatFsBuilder::default()
+    }
+
+    /// Convert the given statistics of another filesystem (e.g. the one a passthrough
+    /// filesystem is backed by). Fails if a value doesn't fit or the statistics are
+    /// inconsistent. The flags can't be replied (see the module documentation).
+    pub fn from_statvfs(st: &libc::statvfs) -> Result<StatFs, StatFsError> {
+        StatFs::builder()
+            .blocks(convert(st.f_blocks, "blocks")?)
+            .bfree(convert(st.f_bfree, "bfree")?)
+            .bavail(convert(st.f_bavail, "bavail")?)
+            .files(convert(st.f_files, "files")?)
+            .ffree(convert(st.f_ffree, "ffree")?)
+            .bsize(convert(st.f_bsize, "bsize")?)
+            .namelen(convert(st.f_namemax, "namelen")?)
+            .frsize(convert(st.f_frsize, "frsize")?)
+            .build()
+    }
+
+    /// Statistics of a filesystem whose size is unknown: all block and inode counts have all
+    /// bits set (see the module documentation), sizes are the defaults of `StatFsBuilder`.
+    /// Programs that use the legacy 32-bit statfs(2) get `EOVERFLOW` for such block counts,
+    /// but not for inode counts (which the kernel passes on as -1).
+    pub fn unknown() -> StatFs {
+        StatFs { blocks: !0, bfree: !0, bavail: !0, files: !0, ffree: !0, ..StatFs::default() }
+    }
+
+    /// Total number of blocks (in units of `frsize`)
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    /// Number of free blocks
+    pub fn bfree(&self) -> u64 {
+        self.bfree
+    }
+
+    /// Number of free blocks for unprivileged users
+    pub fn bavail(&self) -> u64 {
+        self.bavail
+    }
+
+    /// Total number of inodes
+    pub fn files(&self) -> u64 {
+        self.files
+    }
+
+    /// Number of free inodes
+    pub fn ffree(&self) -> u64 {
+        self.ffree
+    }
+
+    /// Preferred block size for I/O
+    pub fn bsize(&self) -> u32 {
+        self.bsize
+    }
+
+    /// Maximum length of file names
+    pub fn namelen(&self) -> u32 {
+        self.namelen
+    }
+
+    /// Fundamental block size, the unit of the block counts
+    pub fn frsize(&self) -> u32 {
+        self.frsize
+    }
+}
+
+impl Default for StatFs {
+    /// Statistics of an empty filesystem with the defaults of `StatFsBuilder`
+    fn default() -> StatFs {
+        StatFs { blocks: 0, bfree: 0, bavail: 0, files: 0, ffree: 0, bsize: 512, namelen: 255, frsize: 512 }
+    }
+}
+
+/// Builder for filesystem statistics with named values. All counts default to 0, the block
+/// size to 512 bytes, the maximum length of file names to 255 bytes and the fundamental block
+/// size to the block size.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StatFsBuilder {
+    stats: StatFs,
+    frsize: Option<u32>,
+}
+
+impl StatFsBuilder {
+    /// Set the total number of blocks (in units of the fundamental block size)
+    pub fn blocks(mut self, blocks: u64) -> StatFsBuilder {
+        self.stats.blocks = blocks;
+        self
+    }
+
+    /// Set the number of free blocks
+    pub fn bfree(mut self, bfree: u64) -> StatFsBuilder {
+        self.stats.bfree = bfree;
+        self
+    }
+
+    /// Set the number of free blocks for unprivileged users
+    pub fn bavail(mut self, bavail: u64) -> StatFsBuilder {
+        self.stats.bavail = bavail;
+        self
+    }
+
+    /// Set the total number of inodes
+    pub fn files(mut self, files: u64) -> StatFsBuilder {
+        self.stats.files = files;
+        self
+    }
+
+    /// Set the number of free inodes
+    pub fn ffree(mut self, ffree: u64) -> StatFsBuilder {
+        self.stats.ffree = ffree;
+        self
+    }
+
+    /// Set the preferred block size for I/O
+    pub fn bsize(mut self, bsize: u32) -> StatFsBuilder {
+        self.stats.bsize = bsize;
+        self
+    }
+
+    /// Set the maximum length of file names
+    pub fn namelen(mut self, namelen: u32) -> StatFsBuilder {
+        self.stats.namelen = namelen;
+        self
+    }
+
+    /// Set the fundamental block size, the unit of the block counts
+    pub fn frsize(mut self, frsize: u32) -> StatFsBuilder {
+        self.frsize = Some(frsize);
+        self
+    }
+
+    /// Check the statistics and build them. Fails if there are more free blocks or inodes than
+    /// there are in total, more blocks available than free, or a size is 0.
+    pub fn build(self) -> Result<StatFs, StatFsError> {
+        let stats = StatFs { frsize: self.frsize.unwrap_or(self.stats.bsize), ..self.stats };
+        if stats.bfree > stats.blocks {
+            return Err(StatFsError::Inconsistent("bfree", "blocks"));
+        }
+        if stats.bavail > stats.bfree {
+            return Err(StatFsError::Inconsistent("bavail", "bfree"));
+        }
+        if stats.ffree > stats.files {
+            return Err(StatFsError::Inconsistent("ffree", "files"));
+        }
+        for &(size, field) in &[(stats.bsize, "bsize"), (stats.frsize, "frsize"), (stats.namelen, "namelen")] {
+            if size == 0 {
+                return Err(StatFsError::Zero(field));
+            }
+        }
+        Ok(stats)
+    }
+}
+
+/// Error of invalid filesystem statistics
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatFsError {
+    /// The first named value is greater than the second (e.g. more free blocks than blocks)
+    Inconsistent(&'static str, &'static str),
+    /// The named size is 0
+    Zero(&'static str),
+    /// The named value doesn't fit in the reply
+    Overflow(&'static str),
+}
+
+impl fmt::Display for StatFsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatFsError::Inconsistent(value, limit) => write!(f, "Filesystem statistics have {} greater than {}", value, limit),
+            StatFsError::Zero(value) => write!(f, "Filesystem statistics have {} of 0", value),
+            StatFsError::Overflow(value) => write!(f, "Filesystem statistics have {} out of range", value),
+        }
+    }
+}
+
+impl error::Error for StatFsError {}
+
+/// Convert the given value of statvfs, whose type depends on the platform
+fn convert<T: TryInto<U>, U>(value: T, field: &'static str) -> Result<U, StatFsError> {
+    value.try_into().map_err(|_| StatFsError::Overflow(field))
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::mem;
+    use super::{StatFs, StatFsError};
+
+    #[test]
+    fn defaults() {
+        let stats = StatFs::builder().build().unwrap();
+        assert_eq!(stats, StatFs::default());
+        assert_eq!((stats.bsize(), stats.frsize(), stats.namelen()), (512, 512, 255));
+        // The fundamental block size follows the block size unless set
+        assert_eq!(StatFs::builder().bsize(4096).build().unwrap().frsize(), 4096);
+        assert_eq!(StatFs::builder().bsize(4096).frsize(512).build().unwrap().frsize(), 512);
+    }
+
+    #[test]
+    fn validation() {
+        let full = StatFs::builder().blocks(100).bfree(50).bavail(40).files(10).ffree(5);
+        assert!(full.build().is_ok());
+        assert_eq!(full.bfree(101).build(), Err(StatFsError::Inconsistent("bfree", "blocks")));
+        assert_eq!(full.bavail(51).build(), Err(StatFsError::Inconsistent("bavail", "bfree")));
+        assert_eq!(full.ffree(11).build(), Err(StatFsError::Inconsistent("ffree", "files")));
+        assert_eq!(full.bsize(0).build(), Err(StatFsError::Zero("bsize")));
+        assert_eq!(full.frsize(0).build(), Err(StatFsError::Zero("frsize")));
+        assert_eq!(full.namelen(0).build(), Err(StatFsError::Zero("namelen")));
+        assert_eq!(full.bfree(101).build().unwrap_err().to_string(), "Filesystem statistics have bfree greater than blocks");
+    }
+
+    #[test]
+    fn unknown() {
+        let stats = StatFs::unknown();
+        assert_eq!((stats.blocks(), stats.bfree(), stats.bavail()), (!0, !0, !0));
+        assert_eq!((stats.files(), stats.ffree()), (!0, !0));
+        assert_eq!((stats.bsize(), stats.frsize(), stats.namelen()), (512, 512, 255));
+        // Unknown counts are consistent, also if only the inode counts are unknown
+        assert_eq!(StatFs::builder().blocks(!0).bfree(!0).bavail(!0).files(!0).ffree(!0).build(), Ok(stats));
+        assert!(StatFs::builder().blocks(100).bfree(50).bavail(40).files(!0).ffree(!0).build().is_ok());
+    }
+
+    #[test]
+    fn from_statvfs() {
+        let mut st: libc::statvfs = unsafe { mem::zeroed() };
+        st.f_blocks = 100;
+        st.f_bfree = 50;
+        st.f_bavail = 40;
+        st.f_files = 10;
+        st.f_ffree = 5;
+        st.f_bsize = 4096;
+        st.f_frsize = 1024;
+        st.f_namemax = 255;
+        let stats = StatFs::from_statvfs(&st).unwrap();
+        assert_eq!(stats, StatFs::builder().blocks(100).bfree(50).bavail(40).files(10).ffree(5).bsize(4096).frsize(1024).build().unwrap());
+        // Inconsistent statistics are refused as well
+        st.f_bavail = 60;
+        assert_eq!(StatFs::from_statvfs(&st), Err(StatFsError::Inconsistent("bavail", "bfree")));
+        st.f_bavail = 40;
+        // Sizes that don't fit in 32 bits, if they can be represented at all
+        if mem::size_of::<libc::c_ulong>() > 4 {
+            st.f_bsize = !0;
+            assert_eq!(StatFs::from_statvfs(&st), Err(StatFsError::Overflow("bsize")));
+        }
+    }
+}
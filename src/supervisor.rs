@@ -0,0 +1,470 @@
+//! Fail-open supervision
+//!
+//! By default, if a filesystem process dies or hangs, every access to its mountpoint fails or
+//! hangs until the filesystem is unmounted. For a filesystem that is mounted over a directory
+//! to add something to its original contents (e.g. auditing), it's often preferable to "fail
+//! open": get out of the way as soon as the filesystem stops working, so that applications
+//! see the original directory again.
+//!
+//! The kernel driver can't be told to do this, but a supervisor process can. The filesystem
+//! is run in a forked child process while the parent watches it. Once the child exits (for
+//! whatever reason, including SIGKILL), or stops updating its heartbeat file, the parent
+//! lazily unmounts the filesystem, which makes the original directory visible again
+//! immediately, even if some processes are still stuck in the dead filesystem.
+//!
+//! The child reports the connection id of its mount to the parent right after mounting. The
+//! parent only unmounts if that mount is still the topmost one at the backing directory, so
+//! it never detaches a filesystem that was mounted over it later, or the directory's own
+//! mount if the child failed to mount.
+//!
+//! The filesystem can't access the original directory through its path after mounting over
+//! it, so it gets passed an open handle to the directory to serve from (e.g. using `openat`).
+
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use libc::{c_int, pid_t};
+use log::{error, info, warn};
+
+use crate::connection;
+use crate::{Filesystem, Session, SessionBuilder, ShutdownReason};
+
+/// How often the supervisor checks the filesystem process
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Supervisor settings. Without a heartbeat, the supervisor only reacts to the filesystem
+/// process exiting. With a heartbeat, the filesystem process regularly accesses the root of
+/// the mountpoint (which goes through the filesystem) and writes its pid and the current
+/// time to the heartbeat file afterwards. If the file isn't updated within the watchdog
+/// timeout, the filesystem is considered wedged, the process is killed and the filesystem
+/// unmounted. External monitors can watch the heartbeat file as well and run
+/// `fusermount -u -z` themselves.
+#[derive(Clone, Debug)]
+pub struct FailOpen {
+    heartbeat: Option<(PathBuf, Duration)>,
+    watchdog_timeout: Option<Duration>,
+}
+
+impl FailOpen {
+    /// Create supervisor settings without heartbeat and watchdog
+    pub fn new() -> FailOpen {
+        FailOpen { heartbeat: None, watchdog_timeout: None }
+    }
+
+    /// Write a heartbeat to the given file at the given interval. The file contains the pid
+    /// of the filesystem process and the time of the last heartbeat in seconds since the epoch.
+    /// The file must not be inside the mountpoint.
+    pub fn heartbeat<P: AsRef<Path>>(mut self, path: P, interval: Duration) -> FailOpen {
+        self.heartbeat = Some((path.as_ref().to_path_buf(), interval));
+        self
+    }
+
+    /// Kill the filesystem process and unmount if the heartbeat wasn't updated within the
+    /// given timeout. Has no effect without a heartbeat.
+    pub fn watchdog(mut self, timeout: Duration) -> FailOpen {
+        self.watchdog_timeout = Some(timeout);
+        self
+    }
+
+    /// Mount a filesystem over the given backing directory and supervise it. The filesystem
+    /// is created by the given function in a forked child process and gets an open handle
+    /// to the backing directory. Returns once the filesystem process is gone and the
+    /// filesystem is unmounted (unless another filesystem was mounted over it since).
+    /// Returns an error if the filesystem process failed, was killed by a signal or was
+    /// killed by the watchdog, or if the connection to the kernel driver was aborted (see
+    /// `ShutdownReason::Aborted`).
+    ///
+    /// This forks the current process, so it should be called before spawning any threads.
+    pub fn run<FS, F, P>(&self, builder: SessionBuilder, backing_dir: P, options: &[&OsStr], filesystem: F) -> io::Result<()>
+    where
+        FS: Filesystem,
+        F: FnOnce(File) -> FS,
+        P: AsRef<Path>,
+    {
+        self.run_mounted(backing_dir, filesystem, |filesystem, mountpoint| builder.mount(filesystem, mountpoint, options))
+    }
+
+    /// Like `run`, but mounts the filesystem with the given function in the child process
+    fn run_mounted<FS, F, M, P>(&self, backing_dir: P, filesystem: F, mount: M) -> io::Result<()>
+    where
+        FS: Filesystem,
+        F: FnOnce(File) -> FS,
+        M: FnOnce(FS, &Path) -> io::Result<Session<FS>>,
+        P: AsRef<Path>,
+    {
+        let backing_dir = fs::canonicalize(backing_dir)?;
+        let backing = File::open(&backing_dir)?;
+        // The child reports the connection id of its mount. Non-blocking, so that the parent
+        // doesn't wait for processes that the filesystem spawned and that inherited the pipe.
+        let mut pipe: [c_int; 2] = [0; 2];
+        if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut reports = unsafe { File::from_raw_fd(pipe[0]) };
+        let report = unsafe { File::from_raw_fd(pipe[1]) };
+        let start = SystemTime::now();
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => {
+                drop(reports);
+                let res = mount(filesystem(backing), &backing_dir).and_then(|mut se| {
+                    let reported = connection::connection_id(&backing_dir).and_then(|id| (&report).write_all(&id.to_ne_bytes()));
+                    if let Err(err) = reported {
+                        warn!("Failed to report the mount of {}, the supervisor won't unmount it: {}", backing_dir.display(), err);
+                    }
+                    drop(report);
+                    if let Some((ref path, interval)) = self.heartbeat {
+                        spawn_heartbeat(path.clone(), interval, backing_dir.clone());
+                    }
+                    se.run()?;
+                    // Let the supervisor detach an aborted filesystem
+                    match se.shutdown_reason() {
+                        Some(ShutdownReason::Aborted { still_mounted: true }) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, "connection to the kernel driver was aborted")),
+                        _ => Ok(()),
+                    }
+                });
+                let code = match res {
+                    Ok(()) => 0,
+                    Err(err) => {
+                        error!("Filesystem failed: {}", err);
+                        1
+                    }
+                };
+                process::exit(code);
+            }
+            pid => {
+                drop(backing);
+                drop(report);
+                let res = self.supervise(pid, start);
+                // A filesystem that exited normally was unmounted already
+                if res.is_err() {
+                    detach(&backing_dir, &mut reports);
+                }
+                res
+            }
+        }
+    }
+
+    /// Wait for the filesystem process to exit (or kill it if the watchdog trips)
+    fn supervise(&self, pid: pid_t, start: SystemTime) -> io::Result<()> {
+        let watchdog = match (&self.heartbeat, self.watchdog_timeout) {
+            (&Some((ref path, _)), Some(timeout)) => Some((path, timeout)),
+            _ => None,
+        };
+        loop {
+            if let Some(status) = try_wait(pid)? {
+                return exit_result(status);
+            }
+            if let Some((path, timeout)) = watchdog {
+                let last = fs::metadata(path).and_then(|m| m.modified()).unwrap_or(start);
+                if last.max(start).elapsed().unwrap_or_default() > timeout {
+                    error!("Filesystem process {} missed its heartbeat for {:?}, killing it", pid, timeout);
+                    unsafe { libc::kill(pid, libc::SIGKILL); }
+                    wait(pid)?;
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "filesystem process missed its heartbeat"));
+                }
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+impl Default for FailOpen {
+    fn default() -> FailOpen {
+        FailOpen::new()
+    }
+}
+
+/// Mount a filesystem over the given backing directory and lazily unmount it as soon as the
+/// filesystem process exits. See `FailOpen::run` for details.
+pub fn run_with_failopen<FS, F, P>(builder: SessionBuilder, backing_dir: P, options: &[&OsStr], filesystem: F) -> io::Result<()>
+where
+    FS: Filesystem,
+    F: FnOnce(File) -> FS,
+    P: AsRef<Path>,
+{
+    FailOpen::new().run(builder, backing_dir, options, filesystem)
+}
+
+/// Lazily unmount the given mountpoint. The filesystem is detached immediately, even if it's
+/// busy or doesn't respond anymore. Falls back to `fusermount -u -z` if not permitted to
+/// unmount directly.
+pub fn unmount_lazy(mountpoint: &Path) -> io::Result<()> {
+    let mnt = CString::new(mountpoint.as_os_str().as_bytes())?;
+    if unsafe { libc::umount2(mnt.as_ptr(), libc::MNT_DETACH) } == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() != Some(libc::EPERM) {
+        return Err(err);
+    }
+    let status = Command::new("fusermount").arg("-u").arg("-z").arg(mountpoint).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        warn!("fusermount failed: {}", status);
+        Err(err)
+    }
+}
+
+/// Lazily unmount the filesystem that the exited filesystem process reported to have mounted
+/// at the given mountpoint, if it's still mounted there and not covered by another mount
+fn detach(mountpoint: &Path, reports: &mut File) {
+    let mut id = [0; 8];
+    match reports.read(&mut id) {
+        Ok(8) => (),
+        _ => {
+            info!("Filesystem process didn't mount {}, not unmounting it", mountpoint.display());
+            return;
+        }
+    }
+    let id = u64::from_ne_bytes(id);
+    match connection::is_topmost(mountpoint, id) {
+        Ok(true) => {
+            if let Err(err) = unmount_lazy(mountpoint) {
+                warn!("Failed to unmount {}: {}", mountpoint.display(), err);
+            }
+        }
+        Ok(false) => warn!("Filesystem (connection {}) isn't the topmost mount of {} anymore, not unmounting it", id, mountpoint.display()),
+        Err(err) => warn!("Failed to check the mounts of {}, not unmounting it: {}", mountpoint.display(), err),
+    }
+}
+
+/// Spawn a thread that regularly accesses the mountpoint and writes a heartbeat afterwards
+fn spawn_heartbeat(path: PathBuf, interval: Duration, mountpoint: PathBuf) {
+    let pid = process::id();
+    thread::spawn(move || loop {
+        let start = Instant::now();
+        // Blocks if the filesystem is wedged, which stops the heartbeat
+        if let Err(err) = fs::metadata(&mountpoint) {
+            warn!("Heartbeat failed to access {}: {}", mountpoint.display(), err);
+        } else {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            if let Err(err) = fs::write(&path, format!("{} {}\n", pid, now.as_secs())) {
+                warn!("Failed to write heartbeat to {}: {}", path.display(), err);
+            }
+        }
+        if let Some(remaining) = interval.checked_sub(start.elapsed()) {
+            thread::sleep(remaining);
+        }
+    });
+}
+
+/// Returns the wait status of the given child process if it exited
+fn try_wait(pid: pid_t) -> io::Result<Option<c_int>> {
+    let mut status = 0;
+    match unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(None),
+        _ => Ok(Some(status)),
+    }
+}
+
+/// Wait for the given child process to exit
+fn wait(pid: pid_t) -> io::Result<c_int> {
+    let mut status = 0;
+    if unsafe { libc::waitpid(pid, &mut status, 0) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(status)
+}
+
+/// Turn the wait status of the filesystem process into a result. Abnormal exits are reported
+/// as aborted connection, like the kernel driver sees them.
+fn exit_result(status: c_int) -> io::Result<()> {
+    if libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0 {
+        info!("Filesystem process exited");
+        Ok(())
+    } else if libc::WIFSIGNALED(status) {
+        let msg = format!("filesystem process killed by signal {}", libc::WTERMSIG(status));
+        error!("{}", msg);
+        Err(io::Error::new(io::ErrorKind::ConnectionAborted, msg))
+    } else {
+        let msg = format!("filesystem process exited with status {}", libc::WEXITSTATUS(status));
+        error!("{}", msg);
+        Err(io::Error::new(io::ErrorKind::ConnectionAborted, msg))
+    }
+}
+
+
+#[cfg(test)]
+#[cfg(feature = "mount-tests")]
+mod test {
+    use std::ffi::CString;
+    use std::fs::{self, File};
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use std::ptr;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use libc::{pid_t, ENOENT};
+    use crate::channel::Channel;
+    use crate::testing::unprivileged_mount_env;
+    use crate::{FileAttr, FileType, Filesystem, ReplyAttr, Request, Session};
+    use super::{unmount_lazy, FailOpen};
+
+    /// A filesystem that hides the contents of the backing directory
+    struct HidingFS {
+        _backing: File,
+    }
+
+    impl Filesystem for HidingFS {
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+            match ino {
+                1 => reply.attr(&Duration::from_secs(0), &FileAttr::builder(1, FileType::Directory).build()),
+                _ => reply.error(ENOENT),
+            }
+        }
+    }
+
+    fn tempdir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("fuse-rs-{}-{}", name, process::id()));
+        fs::create_dir_all(&path).unwrap();
+        fs::canonicalize(path).unwrap()
+    }
+
+    fn is_mounted(path: &Path) -> bool {
+        fs::read_to_string("/proc/self/mountinfo").unwrap()
+            .lines()
+            .any(|line| line.split(' ').nth(4) == path.to_str())
+    }
+
+    fn mount_tmpfs(path: &Path) {
+        let tmpfs = CString::new("tmpfs").unwrap();
+        let mnt = CString::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mount(tmpfs.as_ptr(), mnt.as_ptr(), tmpfs.as_ptr(), 0, ptr::null()) }, 0, "{}", io::Error::last_os_error());
+    }
+
+    fn unmount(path: &Path) {
+        let mnt = CString::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::umount2(mnt.as_ptr(), 0) }, 0, "{}", io::Error::last_os_error());
+    }
+
+    /// Supervise a hiding filesystem mounted directly over the given directory in a
+    /// background thread. Returns the thread and the pid of the filesystem process once the
+    /// filesystem answers.
+    fn supervise(backing: &Path, name: &str) -> (thread::JoinHandle<io::Result<()>>, pid_t) {
+        let heartbeat = std::env::temp_dir().join(format!("fuse-rs-{}-{}.heartbeat", name, process::id()));
+        let _ = fs::remove_file(&heartbeat);
+        let supervisor = {
+            let (backing, heartbeat) = (backing.to_path_buf(), heartbeat.clone());
+            thread::spawn(move || {
+                FailOpen::new()
+                    .heartbeat(heartbeat, Duration::from_millis(100))
+                    .run_mounted(backing, |backing| HidingFS { _backing: backing }, |filesystem, mountpoint| {
+                        Ok(Session::with_channel(filesystem, Channel::mount_direct(mountpoint)?))
+                    })
+            })
+        };
+        let start = Instant::now();
+        let pid = loop {
+            if let Ok(content) = fs::read_to_string(&heartbeat) {
+                break content.split(' ').next().unwrap().parse().unwrap();
+            }
+            assert!(start.elapsed() < Duration::from_secs(10), "filesystem didn't come up");
+            thread::sleep(Duration::from_millis(10));
+        };
+        fs::remove_file(&heartbeat).unwrap();
+        (supervisor, pid)
+    }
+
+    #[test]
+    fn backing_dir_reachable_after_sigkill() {
+        let env = match unprivileged_mount_env() {
+            Ok(env) => env,
+            Err(err) => {
+                eprintln!("Skipping test: {}", err);
+                return;
+            }
+        };
+        env.run(|_| {
+            let backing = tempdir("failopen-sigkill");
+            fs::write(backing.join("original.txt"), "original\n").unwrap();
+            let (supervisor, pid) = supervise(&backing, "failopen-sigkill");
+            assert!(is_mounted(&backing));
+            assert!(!backing.join("original.txt").exists());
+
+            // Keep accessing the filesystem while killing it
+            let traffic = {
+                let path = backing.join("original.txt");
+                thread::spawn(move || {
+                    let start = Instant::now();
+                    while start.elapsed() < Duration::from_secs(1) {
+                        let _ = fs::metadata(&path);
+                    }
+                })
+            };
+            thread::sleep(Duration::from_millis(100));
+            unsafe { libc::kill(pid, libc::SIGKILL); }
+            let killed = Instant::now();
+
+            // The original directory must become reachable again within a bounded time
+            loop {
+                if let Ok(content) = fs::read_to_string(backing.join("original.txt")) {
+                    assert_eq!(content, "original\n");
+                    break;
+                }
+                assert!(killed.elapsed() < Duration::from_secs(2), "backing directory not reachable after kill");
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert!(supervisor.join().unwrap().is_err());
+            assert!(!is_mounted(&backing));
+            traffic.join().unwrap();
+            fs::remove_dir_all(&backing).unwrap();
+        }).unwrap();
+    }
+
+    #[test]
+    fn covering_mount_kept() {
+        let env = match unprivileged_mount_env() {
+            Ok(env) => env,
+            Err(err) => {
+                eprintln!("Skipping test: {}", err);
+                return;
+            }
+        };
+        env.run(|_| {
+            let backing = tempdir("failopen-covered");
+            let (supervisor, pid) = supervise(&backing, "failopen-covered");
+            // Another filesystem mounted over the supervised one must stay mounted
+            mount_tmpfs(&backing);
+            fs::write(backing.join("covering.txt"), "covering\n").unwrap();
+            unsafe { libc::kill(pid, libc::SIGKILL); }
+            assert!(supervisor.join().unwrap().is_err());
+            assert_eq!(fs::read_to_string(backing.join("covering.txt")).unwrap(), "covering\n");
+            unmount(&backing);
+            unmount_lazy(&backing).unwrap();
+            fs::remove_dir_all(&backing).unwrap();
+        }).unwrap();
+    }
+
+    #[test]
+    fn failed_mount_kept() {
+        let env = match unprivileged_mount_env() {
+            Ok(env) => env,
+            Err(err) => {
+                eprintln!("Skipping test: {}", err);
+                return;
+            }
+        };
+        env.run(|_| {
+            let backing = tempdir("failopen-failed");
+            mount_tmpfs(&backing);
+            // The backing directory's own mount must stay mounted if the filesystem never was
+            let res = FailOpen::new().run_mounted(&backing, |backing| HidingFS { _backing: backing }, |_, _| {
+                Err(io::Error::from_raw_os_error(libc::EPERM))
+            });
+            assert!(res.is_err());
+            assert!(is_mounted(&backing));
+            unmount(&backing);
+            fs::remove_dir_all(&backing).unwrap();
+        }).unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+//! System call profiles
+//!
+//! Lists of (Linux) system calls this library makes in different phases of a session. These can
+//! be used as a starting point for building a seccomp allowlist for a filesystem process. The
+//! lists cover calls made by this library (and libfuse during mount/unmount), including the
+//! file and thread operations of the standard library it uses, but not calls the standard
+//! library makes on its own (e.g. for memory allocation, thread setup or logging), nor calls
+//! made by the filesystem implementation itself. Helpers that filesystems use explicitly
+//! (`JournaledFilesystem`, `ResolutionGuard`, `FailOpen` and `testing`) aren't covered either.
+
+/// A named list of system calls
+#[derive(Clone, Copy, Debug)]
+pub struct SyscallProfile {
+    /// Name of the phase this profile covers
+    pub name: &'static str,
+    /// Names of system calls made in this phase
+    pub syscalls: &'static [&'static str],
+}
+
+/// Mounting through libfuse. As root, libfuse opens `/dev/fuse` and mounts directly. Otherwise
+/// it runs the setuid helper `fusermount` and receives the fd of `/dev/fuse` over a socket.
+/// Before, the device is checked for access, and the fd limit may be raised
+/// (`SessionBuilder::raise_fd_limit`). Remounting (e.g. read-only) mounts again.
+pub const MOUNT: SyscallProfile = SyscallProfile {
+    name: "mount",
+    syscalls: &[
+        "openat", "close", "fstat", "lstat", "stat", "statx", "readlink", "getcwd", "getuid",
+        "access", "mount", "socketpair", "clone", "clone3", "execve", "recvmsg", "wait4", "fcntl",
+        "prlimit64",
+    ],
+};
+
+/// Running the session loop (receiving requests and sending replies). Besides reading and
+/// writing the device, the loop polls it (`Session::run_until`, fair dispatch), spawns threads
+/// (tasks, the reply writer of ordered replies, the entry invalidator and the mount flags
+/// watch), reads `/proc/self/mountinfo` and `/proc/<pid>` files, and writes to the connection's
+/// `abort` file in sysfs. This library never uses splice, vmsplice or pipes to talk to the kernel driver,
+/// and never calls fsync on the device.
+pub const SESSION_LOOP: SyscallProfile = SyscallProfile {
+    name: "session-loop",
+    syscalls: &[
+        "read", "writev", "futex", "poll", "clone", "clone3", "openat", "close", "statx",
+        "readlink", "write",
+    ],
+};
+
+/// Unmounting. Unprivileged users on Linux can't unmount directly, in which case libfuse runs
+/// the setuid helper `fusermount -u`.
+pub const UNMOUNT: SyscallProfile = SyscallProfile {
+    name: "unmount",
+    syscalls: &["close", "umount2", "clone", "execve", "wait4"],
+};
+
+/// All system call profiles
+pub const PROFILES: &[SyscallProfile] = &[MOUNT, SESSION_LOOP, UNMOUNT];
+
+
+#[cfg(test)]
+mod test {
+    use super::{SyscallProfile, MOUNT, PROFILES, SESSION_LOOP, UNMOUNT};
+
+    /// Library functions and the system calls they make on Linux (none for functions that
+    /// don't make system calls on Linux)
+    const CALLS: &[(&str, &[&str])] = &[
+        ("libc::access(", &["access"]),
+        ("libc::close(", &["close"]),
+        ("libc::getrlimit(", &["prlimit64"]),
+        ("libc::makedev(", &[]),
+        ("libc::mount(", &["mount"]),
+        ("libc::open(", &["openat"]),
+        ("libc::poll(", &["poll"]),
+        ("libc::read(", &["read"]),
+        ("libc::setrlimit(", &["prlimit64"]),
+        ("libc::umount(", &["umount2"]),
+        ("libc::unmount(", &[]),
+        ("libc::writev(", &["writev"]),
+        ("File::open(", &["openat", "close"]),
+        ("fs::read_link(", &["readlink"]),
+        ("fs::read_to_string(", &["openat", "statx", "read", "close"]),
+        ("fs::write(", &["openat", "write", "close"]),
+        ("thread::Builder", &["clone", "clone3"]),
+    ];
+
+    /// Modules of the library and the profiles of the phases they run in
+    const MODULES: &[(&str, &str, &[SyscallProfile])] = &[
+        ("channel.rs", include_str!("channel.rs"), &[MOUNT, SESSION_LOOP, UNMOUNT]),
+        ("connection.rs", include_str!("connection.rs"), &[MOUNT, SESSION_LOOP]),
+        ("fdbudget.rs", include_str!("fdbudget.rs"), &[MOUNT]),
+        ("notify.rs", include_str!("notify.rs"), &[SESSION_LOOP]),
+        ("pidns.rs", include_str!("pidns.rs"), &[SESSION_LOOP]),
+        ("reply.rs", include_str!("reply.rs"), &[SESSION_LOOP]),
+        ("request.rs", include_str!("request.rs"), &[SESSION_LOOP]),
+        ("session.rs", include_str!("session.rs"), &[MOUNT, SESSION_LOOP]),
+        ("task.rs", include_str!("task.rs"), &[SESSION_LOOP]),
+    ];
+
+    #[test]
+    fn profiles_cover_modules() {
+        for &(module, source, profiles) in MODULES {
+            let code = source.split("\n#[cfg(test)]\n").next().unwrap();
+            for line in code.lines().filter(|line| !line.trim_start().starts_with("//")) {
+                for rest in line.split("libc::").skip(1) {
+                    let len = rest.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(rest.len());
+                    if rest[len..].starts_with('(') {
+                        let call = format!("libc::{}(", &rest[..len]);
+                        assert!(CALLS.iter().any(|&(name, _)| name == call), "unknown call {} in {}", call, module);
+                    }
+                }
+                for &(name, syscalls) in CALLS.iter().filter(|&&(name, _)| line.contains(name)) {
+                    for syscall in syscalls {
+                        assert!(profiles.iter().any(|p| p.syscalls.contains(syscall)), "{} of {} in {} not in its profiles", syscall, name, module);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn session_loop_is_minimal() {
+        for syscall in &["splice", "vmsplice", "pipe", "pipe2", "fsync", "fdatasync", "ioctl"] {
+            assert!(!SESSION_LOOP.syscalls.contains(syscall), "{} in session loop profile", syscall);
+        }
+    }
+
+    #[test]
+    fn unique_profile_names() {
+        for (i, profile) in PROFILES.iter().enumerate() {
+            assert!(PROFILES[i + 1..].iter().all(|p| p.name != profile.name));
+        }
+    }
+}
@@ -0,0 +1,282 @@
+//! Session-scoped tasks
+//!
+//! Filesystem methods may reply asynchronously by moving the reply to another thread. Tasks
+//! spawned through a `TaskScope` are tied to the lifetime of the session: they get a shutdown
+//! token that is tripped when the session ends, and the session waits (for a limited time) for
+//! them to finish before unmounting.
+
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use log::{error, warn};
+
+/// Default time to wait for tasks to finish when a session ends
+pub const DEFAULT_TASK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// State shared between a task scope and its tasks
+#[derive(Debug, Default)]
+struct State {
+    /// True once the scope was shut down
+    shutdown: bool,
+    /// Number of tasks that didn't finish yet
+    active: usize,
+    /// Id of the next task that runs in its own thread
+    next_id: u64,
+    /// Ids of tasks with threads that finished since their handles were last collected
+    finished: Vec<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<State>,
+    /// Notified whenever the scope is shut down or a task finishes
+    changed: Condvar,
+}
+
+/// Token that tells a task whether the session it belongs to is shutting down. Tasks should
+/// check it regularly and return early once it's tripped.
+#[derive(Clone, Debug)]
+pub struct ShutdownToken {
+    shared: Arc<Shared>,
+}
+
+impl ShutdownToken {
+    /// Returns true if the session is shutting down
+    pub fn is_shutdown(&self) -> bool {
+        self.shared.state.lock().unwrap().shutdown
+    }
+
+    /// Wait for the given duration or until the session is shutting down, whatever comes
+    /// first. Returns true if the session is shutting down. Useful as an interruptible sleep.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.state.lock().unwrap();
+        while !state.shutdown {
+            let now = Instant::now();
+            if now >= deadline { break; }
+            state = self.shared.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        state.shutdown
+    }
+}
+
+/// Decrements the number of active tasks when a task finishes (even if it panics), and
+/// records the id of the finished task if it runs in its own thread
+struct TaskGuard {
+    shared: Arc<Shared>,
+    id: Option<u64>,
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.active -= 1;
+        if let Some(id) = self.id {
+            state.finished.push(id);
+        }
+        self.shared.changed.notify_all();
+    }
+}
+
+/// How tasks of a scope are run
+enum Backend {
+    /// Run every task in a new thread, keeping the join handles of the threads by task id
+    Thread(Mutex<Vec<(u64, JoinHandle<()>)>>),
+    /// Run tasks on a user-provided tokio runtime
+    #[cfg(feature = "tokio")]
+    Tokio(tokio::runtime::Handle),
+}
+
+impl fmt::Debug for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Thread(_) => write!(f, "Thread"),
+            #[cfg(feature = "tokio")]
+            Backend::Tokio(_) => write!(f, "Tokio"),
+        }
+    }
+}
+
+/// A scope for tasks spawned by filesystem methods. Cloning a scope gives another handle to
+/// the same scope.
+#[derive(Clone, Debug)]
+pub struct TaskScope {
+    shared: Arc<Shared>,
+    backend: Arc<Backend>,
+}
+
+impl TaskScope {
+    /// Create a new task scope that runs every task in a separate thread
+    pub fn new() -> TaskScope {
+        TaskScope {
+            shared: Arc::default(),
+            backend: Arc::new(Backend::Thread(Mutex::new(Vec::new()))),
+        }
+    }
+
+    /// Create a new task scope that runs tasks on the given tokio runtime. Tasks are expected
+    /// to block, so they're run using `spawn_blocking`.
+    #[cfg(feature = "tokio")]
+    pub fn with_runtime(handle: tokio::runtime::Handle) -> TaskScope {
+        TaskScope {
+            shared: Arc::default(),
+            backend: Arc::new(Backend::Tokio(handle)),
+        }
+    }
+
+    /// Spawn a task. The task gets a shutdown token that is tripped once the scope is shut down.
+    pub fn spawn<F: FnOnce(ShutdownToken) + Send + 'static>(&self, f: F) -> io::Result<()> {
+        let id = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.active += 1;
+            state.next_id += 1;
+            state.next_id
+        };
+        // Only the ids of tasks in threads are needed to collect their join handles
+        let thread_id = match *self.backend {
+            Backend::Thread(_) => Some(id),
+            #[cfg(feature = "tokio")]
+            Backend::Tokio(_) => None,
+        };
+        let guard = TaskGuard { shared: self.shared.clone(), id: thread_id };
+        let token = ShutdownToken { shared: self.shared.clone() };
+        let task = move || {
+            let _guard = guard;
+            f(token);
+        };
+        match *self.backend {
+            Backend::Thread(ref handles) => {
+                // If spawning fails, the task is dropped and the guard decrements the counter
+                let handle = thread::Builder::new().name("fuse-task".into()).spawn(task)?;
+                let mut handles = handles.lock().unwrap();
+                handles.push((id, handle));
+                // Collect the handles of finished tasks to not accumulate them. Their threads
+                // are done with the task, so joining them never waits for a running task.
+                let finished: Vec<_> = self.shared.state.lock().unwrap().finished.drain(..).collect();
+                if !finished.is_empty() {
+                    let (done, running): (Vec<_>, Vec<_>) = handles.drain(..).partition(|&(id, _)| finished.contains(&id));
+                    *handles = running;
+                    join_all(done.into_iter().map(|(_, handle)| handle));
+                }
+            }
+            #[cfg(feature = "tokio")]
+            Backend::Tokio(ref handle) => {
+                // Dropping the join handle detaches the task, the guard keeps track of it
+                drop(handle.spawn_blocking(task));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the number of tasks that didn't finish yet
+    pub fn active(&self) -> usize {
+        self.shared.state.lock().unwrap().active
+    }
+
+    /// Shut down the scope. This trips the shutdown token of all tasks and waits up to the
+    /// given timeout for them to finish. Returns true if all tasks finished in time. Tasks
+    /// that are still running after the timeout are logged and left running in the background.
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.state.lock().unwrap();
+        state.shutdown = true;
+        self.shared.changed.notify_all();
+        while state.active > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("{} task(s) didn't finish within {:?} after shutdown", state.active, timeout);
+                return false;
+            }
+            state = self.shared.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        drop(state);
+        match *self.backend {
+            Backend::Thread(ref handles) => join_all(handles.lock().unwrap().drain(..).map(|(_, handle)| handle)),
+            #[cfg(feature = "tokio")]
+            Backend::Tokio(_) => (),
+        }
+        true
+    }
+}
+
+impl Default for TaskScope {
+    fn default() -> TaskScope {
+        TaskScope::new()
+    }
+}
+
+/// Join the given (finished) task threads, logging tasks that panicked
+fn join_all<I: Iterator<Item = JoinHandle<()>>>(handles: I) {
+    for handle in handles {
+        if handle.join().is_err() {
+            error!("Task panicked");
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use super::TaskScope;
+
+    #[test]
+    fn shutdown_waits_for_cooperative_task() {
+        let scope = TaskScope::new();
+        let (tx, rx) = channel();
+        scope.spawn(move |token| {
+            tx.send(()).unwrap();
+            while !token.wait_timeout(Duration::from_millis(10)) {}
+            thread::sleep(Duration::from_millis(50));
+        }).unwrap();
+        rx.recv().unwrap();
+        assert_eq!(scope.active(), 1);
+        assert!(scope.shutdown(Duration::from_secs(5)));
+        assert_eq!(scope.active(), 0);
+    }
+
+    #[test]
+    fn shutdown_gives_up_on_uncooperative_task() {
+        let scope = TaskScope::new();
+        scope.spawn(|_token| {
+            thread::sleep(Duration::from_secs(2));
+        }).unwrap();
+        let start = Instant::now();
+        assert!(!scope.shutdown(Duration::from_millis(100)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(scope.active(), 1);
+    }
+
+    #[test]
+    fn spawn_doesnt_wait_for_running_tasks() {
+        let scope = TaskScope::new();
+        scope.spawn(|token| {
+            token.wait_timeout(Duration::from_secs(5));
+        }).unwrap();
+        // Spawning collects finished tasks, but must not join the one that's still running
+        let start = Instant::now();
+        for _ in 0..20 {
+            scope.spawn(|_token| ()).unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(scope.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn task_observes_shutdown() {
+        let scope = TaskScope::new();
+        let (tx, rx) = channel();
+        scope.spawn(move |token| {
+            assert!(!token.is_shutdown());
+            tx.send(token.wait_timeout(Duration::from_secs(5))).unwrap();
+        }).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        scope.shutdown(Duration::from_secs(5));
+        assert!(rx.recv().unwrap());
+    }
+}
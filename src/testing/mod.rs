@@ -0,0 +1,95 @@
+//! Test support
+//!
+//! `CapturingSender` collects replies in memory, so that tests can assert the exact bytes a
+//! filesystem replies. With the `mount-tests` feature on Linux, `MountEnv` mounts filesystems
+//! without privileges for tests that go through the kernel driver.
+
+use std::sync::{Arc, Mutex};
+
+use crate::reply::ReplySender;
+
+#[cfg(all(target_os = "linux", feature = "mount-tests"))]
+mod mount;
+
+#[cfg(all(target_os = "linux", feature = "mount-tests"))]
+pub use mount::{unprivileged_mount_env, MountEnv, Unsupported};
+#[cfg(all(target_os = "linux", feature = "mount-tests"))]
+pub(crate) use mount::mount_options;
+
+/// Reply sender that collects the raw replies sent with it (header and payload), e.g. for
+/// golden tests of reply bytes. Clones share the collected replies, so a clone can be passed
+/// to a reply while the original is kept to inspect what was sent.
+///
+/// ```
+/// use fuse::{Reply, ReplyData};
+/// use fuse::testing::{reply_header, CapturingSender};
+///
+/// let sender = CapturingSender::new();
+/// let reply: ReplyData = Reply::new(42, sender.clone());
+/// reply.data(b"hello");
+/// let replies = sender.replies();
+/// assert_eq!(reply_header(&replies[0]), (42, 0));
+/// assert_eq!(&replies[0][16..], b"hello");
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CapturingSender {
+    replies: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl CapturingSender {
+    /// Create a sender without any replies collected
+    pub fn new() -> CapturingSender {
+        CapturingSender::default()
+    }
+
+    /// Returns the replies collected so far
+    pub fn replies(&self) -> Vec<Vec<u8>> {
+        self.replies.lock().unwrap().clone()
+    }
+
+    /// Returns the replies collected so far and forgets them
+    pub fn take(&self) -> Vec<Vec<u8>> {
+        self.replies.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl ReplySender for CapturingSender {
+    fn send(&self, data: &[&[u8]]) {
+        self.replies.lock().unwrap().push(data.concat());
+    }
+}
+
+/// Returns the unique id and error (negative errno, 0 on success) of a raw reply. The
+/// payload of a reply follows its 16 byte header.
+pub fn reply_header(reply: &[u8]) -> (u64, i32) {
+    let mut error = [0; 4];
+    error.copy_from_slice(&reply[4..8]);
+    let mut unique = [0; 8];
+    unique.copy_from_slice(&reply[8..16]);
+    (u64::from_ne_bytes(unique), i32::from_ne_bytes(error))
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{Reply, ReplyEmpty, ReplyWrite};
+    use super::{reply_header, CapturingSender};
+
+    #[test]
+    fn capture_replies() {
+        let sender = CapturingSender::new();
+        let reply: ReplyWrite = Reply::new(2, sender.clone());
+        reply.written(0x1234);
+        let reply: ReplyEmpty = Reply::new(3, sender.clone());
+        reply.error(libc::EIO);
+        let replies = sender.take();
+        assert_eq!(replies.len(), 2);
+        assert_eq!(reply_header(&replies[0]), (2, 0));
+        assert_eq!(replies[0].len(), 16 + 8);
+        assert_eq!(&replies[0][16..20], &0x1234u32.to_ne_bytes());
+        assert_eq!(reply_header(&replies[1]), (3, -libc::EIO));
+        assert_eq!(replies[1].len(), 16);
+        // Taken replies are forgotten
+        assert!(sender.replies().is_empty());
+    }
+}
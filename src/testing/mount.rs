@@ -0,0 +1,187 @@
+//! Test support for mounting without privileges
+//!
+//! On recent Linux kernels with unprivileged user namespaces enabled, a process can mount
+//! FUSE filesystems without the setuid helper `fusermount`: it creates a user namespace (in
+//! which it's root) and a mount namespace, and calls mount(2) directly. Mounts are private to
+//! the namespace and vanish with it, so tests can't leave stale mounts behind.
+//!
+//! Creating a user namespace requires a single-threaded process, which the test harness isn't.
+//! Tests are therefore run in a forked child process, which should only run the test and
+//! exit (see `MountEnv::run`).
+
+use std::any::Any;
+use std::error;
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::process;
+use libc::c_int;
+
+use crate::channel::Channel;
+use crate::session::{BackgroundSession, Session};
+use crate::Filesystem;
+
+/// Unprivileged mounting isn't possible on this system
+#[derive(Debug)]
+pub struct Unsupported {
+    reason: String,
+}
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unprivileged mounting is unsupported: {}", self.reason)
+    }
+}
+
+impl error::Error for Unsupported {}
+
+/// An environment for tests that mount filesystems in an unprivileged user and mount namespace
+#[derive(Debug)]
+pub struct MountEnv {
+    _private: (),
+}
+
+/// Returns an environment for unprivileged mount tests, or why it's unsupported (e.g. user
+/// namespaces are disabled or FUSE can't be mounted in them). Tests should be skipped then.
+pub fn unprivileged_mount_env() -> Result<MountEnv, Unsupported> {
+    let env = MountEnv { _private: () };
+    env.run(|_| {
+        let mountpoint = std::env::temp_dir().join(format!("fuse-rs-probe-{}", process::id()));
+        fs::create_dir_all(&mountpoint).unwrap();
+        // Dropping the channel unmounts again
+        let res = Channel::mount_direct(&mountpoint).map(drop);
+        let _ = fs::remove_dir(&mountpoint);
+        if let Err(err) = res {
+            panic!("failed to mount: {}", err);
+        }
+    }).map_err(|reason| Unsupported { reason })?;
+    Ok(env)
+}
+
+impl MountEnv {
+    /// Run the given test in a new user namespace (mapping the current user to root) and mount
+    /// namespace. The test runs in a forked child process; it can't change any state of the
+    /// calling process and shouldn't spawn processes that outlive it. Returns the panic message
+    /// if the test panicked, or why the namespaces couldn't be created.
+    pub fn run<F: FnOnce(&MountEnv)>(&self, f: F) -> Result<(), String> {
+        let mut pipe: [c_int; 2] = [0; 2];
+        if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(format!("failed to create pipe: {}", io::Error::last_os_error()));
+        }
+        let mut reader = unsafe { fs::File::from_raw_fd(pipe[0]) };
+        let writer = unsafe { fs::File::from_raw_fd(pipe[1]) };
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        match unsafe { libc::fork() } {
+            -1 => Err(format!("failed to fork: {}", io::Error::last_os_error())),
+            0 => {
+                drop(reader);
+                let res = enter_namespaces(uid, gid).map_err(|err| format!("failed to enter namespaces: {}", err))
+                    .and_then(|()| panic::catch_unwind(AssertUnwindSafe(|| f(self))).map_err(panic_message));
+                let code = match res {
+                    Ok(()) => 0,
+                    Err(message) => {
+                        let _ = (&writer).write_all(message.as_bytes());
+                        1
+                    }
+                };
+                // Never return to the caller (e.g. the test harness) in the child
+                unsafe { libc::_exit(code) }
+            }
+            pid => {
+                drop(writer);
+                let mut message = String::new();
+                let _ = reader.read_to_string(&mut message);
+                let mut status = 0;
+                if unsafe { libc::waitpid(pid, &mut status, 0) } < 0 {
+                    return Err(format!("failed to wait for test process: {}", io::Error::last_os_error()));
+                }
+                match (libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0, message.is_empty()) {
+                    (true, _) => Ok(()),
+                    (false, false) => Err(message),
+                    (false, true) => Err(format!("test process failed with status {:#x}", status)),
+                }
+            }
+        }
+    }
+
+    /// Mount the given filesystem directly with mount(2) and run its session in a background
+    /// thread. Must be called from a test that is run by `run`. The filesystem is unmounted
+    /// when the returned handle is dropped.
+    pub fn mount<'a, FS: Filesystem + Send + 'a>(&self, filesystem: FS, mountpoint: &Path) -> io::Result<BackgroundSession<'a>> {
+        let ch = Channel::mount_direct(mountpoint)?;
+        unsafe { BackgroundSession::new(Session::with_channel(filesystem, ch)) }
+    }
+}
+
+/// Create and enter a user namespace that maps the given user and group to root, and a
+/// mount namespace whose mounts don't propagate to the parent namespace
+fn enter_namespaces(uid: u32, gid: u32) -> io::Result<()> {
+    if unsafe { libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Unprivileged processes must deny setgroups before mapping groups
+    fs::write("/proc/self/setgroups", "deny")?;
+    fs::write("/proc/self/uid_map", format!("0 {} 1", uid))?;
+    fs::write("/proc/self/gid_map", format!("0 {} 1", gid))?;
+    let root = CString::new("/").unwrap();
+    let none = CString::new("none").unwrap();
+    if unsafe { libc::mount(none.as_ptr(), root.as_ptr(), std::ptr::null(), libc::MS_REC | libc::MS_PRIVATE, std::ptr::null()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Returns the message of a panic payload
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "test panicked".to_string(),
+        },
+    }
+}
+
+/// Returns mount options for mounting FUSE directly with the given device fd
+pub(crate) fn mount_options(fd: c_int) -> CString {
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let options = format!("fd={},rootmode=40000,user_id={},group_id={}", fd, uid, gid);
+    CString::new(options).unwrap()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{mount_options, panic_message, unprivileged_mount_env};
+
+    #[test]
+    fn options() {
+        let options = mount_options(7).into_string().unwrap();
+        assert!(options.starts_with("fd=7,rootmode=40000,user_id="), "{}", options);
+    }
+
+    #[test]
+    fn messages() {
+        assert_eq!(panic_message(Box::new("static")), "static");
+        assert_eq!(panic_message(Box::new(String::from("owned"))), "owned");
+        assert_eq!(panic_message(Box::new(42)), "test panicked");
+    }
+
+    #[test]
+    fn run_in_namespace() {
+        let env = match unprivileged_mount_env() {
+            Ok(env) => env,
+            Err(err) => {
+                eprintln!("Skipping test: {}", err);
+                return;
+            }
+        };
+        // The test runs as root of the new user namespace, and panics are reported
+        env.run(|_| assert_eq!(unsafe { libc::geteuid() }, 0)).unwrap();
+        assert_eq!(env.run(|_| panic!("failed")), Err("failed".to_string()));
+    }
+}
@@ -0,0 +1,135 @@
+//! Cache of extended attribute values
+//!
+//! Copying tools (e.g. `cp -a`, `rsync -X` or `tar --xattrs`) list the extended attributes of
+//! every file and then get each of them, which takes a round-trip to the filesystem per
+//! attribute. Filesystems that can fetch all values at once (e.g. from a remote backend) can
+//! reply to listxattr with the values too (see `XAttrList`), and the session replies to the
+//! following getxattr requests without calling the filesystem.
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::ll;
+
+/// Max number of inodes with cached values. Expired values are dropped when the cache is full,
+/// and if that doesn't help, all of them.
+const MAX_ENTRIES: usize = 1024;
+
+/// Listed extended attributes of an inode and when they expire
+#[derive(Debug)]
+struct Listed {
+    values: HashMap<OsString, Vec<u8>>,
+    expires: Instant,
+}
+
+/// Extended attribute values of inodes, as replied to listxattr
+#[derive(Debug)]
+pub struct XAttrCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<u64, Listed>>,
+}
+
+impl XAttrCache {
+    /// Create a new cache that keeps values for the given time
+    pub fn new(ttl: Duration) -> XAttrCache {
+        XAttrCache { ttl, entries: Mutex::default() }
+    }
+
+    /// Returns the cached value of the given extended attribute if the inode's attributes were
+    /// listed recently: `Some(None)` if the attribute wasn't listed (doesn't exist), or `None`
+    /// if nothing is known about the inode.
+    pub fn get(&self, ino: u64, name: &OsStr) -> Option<Option<Vec<u8>>> {
+        self.get_at(ino, name, Instant::now())
+    }
+
+    fn get_at(&self, ino: u64, name: &OsStr, now: Instant) -> Option<Option<Vec<u8>>> {
+        match self.entries.lock().unwrap().get(&ino) {
+            Some(listed) if now < listed.expires => Some(listed.values.get(name).cloned()),
+            _ => None,
+        }
+    }
+
+    /// Remember the complete list of extended attributes of the given inode with their values
+    pub fn insert(&self, ino: u64, values: HashMap<OsString, Vec<u8>>) {
+        self.insert_at(ino, values, Instant::now())
+    }
+
+    fn insert_at(&self, ino: u64, values: HashMap<OsString, Vec<u8>>, now: Instant) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(&ino) {
+            entries.retain(|_, listed| now < listed.expires);
+            if entries.len() >= MAX_ENTRIES {
+                entries.clear();
+            }
+        }
+        entries.insert(ino, Listed { values, expires: now + self.ttl });
+    }
+
+    /// Forget values of inodes whose extended attributes the given request may change.
+    /// Besides setting and removing attributes, changing the mode changes the ACL attributes,
+    /// and a forgotten inode number may be reused for another file.
+    pub fn observe(&self, request: &ll::Request<'_>) {
+        match request.operation() {
+            ll::Operation::SetAttr { .. } | ll::Operation::SetXAttr { .. } | ll::Operation::RemoveXAttr { .. } |
+            ll::Operation::Forget { .. } => self.forget(request.nodeid()),
+            #[cfg(feature = "abi-7-16")]
+            ll::Operation::BatchForget { nodes, .. } => {
+                for node in nodes.iter() {
+                    self.forget(node.nodeid);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Forget the values of the given inode
+    pub fn forget(&self, ino: u64) {
+        self.entries.lock().unwrap().remove(&ino);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::ffi::{OsStr, OsString};
+    use std::time::{Duration, Instant};
+    use super::{XAttrCache, MAX_ENTRIES};
+
+    fn values(value: &[u8]) -> HashMap<OsString, Vec<u8>> {
+        let mut values = HashMap::new();
+        values.insert(OsString::from("user.foo"), value.to_vec());
+        values
+    }
+
+    #[test]
+    fn expires() {
+        let cache = XAttrCache::new(Duration::from_secs(1));
+        let now = Instant::now();
+        cache.insert_at(2, values(b"bar"), now);
+        let name = OsStr::new("user.foo");
+        assert_eq!(cache.get_at(2, name, now + Duration::from_millis(500)), Some(Some(b"bar".to_vec())));
+        // Attributes that weren't listed don't exist
+        assert_eq!(cache.get_at(2, OsStr::new("user.baz"), now), Some(None));
+        assert_eq!(cache.get_at(3, name, now), None);
+        assert_eq!(cache.get_at(2, name, now + Duration::from_secs(1)), None);
+    }
+
+    #[test]
+    fn bounded() {
+        let cache = XAttrCache::new(Duration::from_secs(1));
+        let now = Instant::now();
+        for ino in 0..MAX_ENTRIES as u64 {
+            cache.insert_at(ino, values(b""), now);
+        }
+        // Listing a cached inode again replaces its values
+        cache.insert_at(0, values(b"new"), now);
+        assert_eq!(cache.entries.lock().unwrap().len(), MAX_ENTRIES);
+        // Expired values make room
+        let later = now + Duration::from_secs(2);
+        cache.insert_at(1 << 32, values(b""), later);
+        assert_eq!(cache.entries.lock().unwrap().len(), 1);
+    }
+}
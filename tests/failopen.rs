@@ -0,0 +1,112 @@
+//! Fail-open supervision tests
+//!
+//! These tests need a working FUSE setup (the kernel driver and permission to mount, e.g. via
+//! fusermount) and are therefore ignored by default. Run them with `cargo test -- --ignored`.
+//! With the `mount-tests` feature, the supervisor's own tests cover the same without
+//! privileges by mounting in a user namespace.
+
+#![cfg(target_os = "linux")]
+
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+use libc::ENOENT;
+use fuse::{FileAttr, FileType, Filesystem, InitConfig, ReplyAttr, Request, SessionBuilder};
+use fuse::supervisor::FailOpen;
+
+/// A filesystem that hides the backing directory. Only the root can be accessed, which the
+/// heartbeat needs.
+struct HidingFS {
+    _backing: File,
+}
+
+impl Filesystem for HidingFS {
+    fn init(&mut self, _req: &Request<'_>) -> Result<InitConfig, libc::c_int> {
+        Ok(InitConfig::default())
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match ino {
+            1 => reply.attr(&Duration::from_secs(0), &FileAttr::builder(1, FileType::Directory).build()),
+            _ => reply.error(ENOENT),
+        }
+    }
+}
+
+fn tempdir(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("fuse-rs-{}-{}", name, process::id()));
+    fs::create_dir_all(&path).unwrap();
+    path
+}
+
+fn is_mounted(path: &PathBuf) -> bool {
+    let path = fs::canonicalize(path).unwrap();
+    fs::read_to_string("/proc/self/mountinfo").unwrap()
+        .lines()
+        .any(|line| line.split(' ').nth(4) == Some(path.to_str().unwrap()))
+}
+
+#[test]
+#[ignore]
+fn backing_dir_reachable_after_sigkill() {
+    let backing = tempdir("failopen");
+    fs::write(backing.join("original.txt"), "original\n").unwrap();
+    let heartbeat = std::env::temp_dir().join(format!("fuse-rs-failopen-{}.heartbeat", process::id()));
+    let _ = fs::remove_file(&heartbeat);
+
+    let supervisor = {
+        let backing = backing.clone();
+        let heartbeat = heartbeat.clone();
+        thread::spawn(move || {
+            let options: [&OsStr; 2] = ["-o".as_ref(), "nonempty".as_ref()];
+            FailOpen::new()
+                .heartbeat(heartbeat, Duration::from_millis(100))
+                .run(SessionBuilder::new(), backing, &options, |backing| HidingFS { _backing: backing })
+        })
+    };
+
+    // Wait for the filesystem to come up and hide the original file
+    let start = Instant::now();
+    let pid = loop {
+        if let Ok(content) = fs::read_to_string(&heartbeat) {
+            break content.split(' ').next().unwrap().parse::<libc::pid_t>().unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_secs(10), "filesystem didn't come up");
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert!(is_mounted(&backing));
+    assert!(!backing.join("original.txt").exists());
+
+    // Keep accessing the filesystem while killing it
+    let traffic = {
+        let backing = backing.clone();
+        thread::spawn(move || {
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_secs(2) {
+                let _ = fs::metadata(backing.join("original.txt"));
+            }
+        })
+    };
+    thread::sleep(Duration::from_millis(100));
+    unsafe { libc::kill(pid, libc::SIGKILL); }
+    let killed = Instant::now();
+
+    // The original directory must become reachable again within a bounded time
+    loop {
+        if let Ok(content) = fs::read_to_string(backing.join("original.txt")) {
+            assert_eq!(content, "original\n");
+            break;
+        }
+        assert!(killed.elapsed() < Duration::from_secs(2), "backing directory not reachable after kill");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(supervisor.join().unwrap().is_err());
+    assert!(!is_mounted(&backing));
+    traffic.join().unwrap();
+
+    fs::remove_file(&heartbeat).unwrap();
+    fs::remove_dir_all(&backing).unwrap();
+}
@@ -0,0 +1,216 @@
+//! Mount tests
+//!
+//! These tests need a working FUSE setup (the kernel driver and permission to mount, e.g. via
+//! fusermount) and are therefore ignored by default. Run them with `cargo test -- --ignored`.
+//! With the `mount-tests` feature, tests mount without privileges in a user namespace if the
+//! kernel allows it, and fall back to fusermount otherwise.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use libc::ENOENT;
+use fuse::{BackgroundSession, FileType, FileAttr, Filesystem, Request, ReplyAttr, ReplyEntry, ShutdownReason};
+#[cfg(all(target_os = "linux", feature = "mount-tests"))]
+use fuse::testing::MountEnv;
+
+/// Stand-in for the unprivileged mount environment if it's not available
+#[cfg(not(all(target_os = "linux", feature = "mount-tests")))]
+struct MountEnv;
+
+const TTL: Duration = Duration::from_secs(1);
+
+const SINGLE_ATTR: FileAttr = FileAttr {
+    ino: 2,
+    size: 0,
+    blocks: 0,
+    atime: UNIX_EPOCH,
+    mtime: UNIX_EPOCH,
+    ctime: UNIX_EPOCH,
+    crtime: UNIX_EPOCH,
+    kind: FileType::RegularFile,
+    perm: 0o644,
+    nlink: 1,
+    uid: 0,
+    gid: 0,
+    rdev: 0,
+    flags: 0,
+};
+
+const ROOT_ATTR: FileAttr = FileAttr {
+    ino: 1,
+    kind: FileType::Directory,
+    perm: 0o755,
+    nlink: 2,
+    ..SINGLE_ATTR
+};
+
+struct SingleFS;
+
+impl Filesystem for SingleFS {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if parent == 1 && name == "single" {
+            reply.entry(&TTL, &SINGLE_ATTR, 0);
+        } else {
+            reply.error(ENOENT);
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match ino {
+            1 => reply.attr(&TTL, &ROOT_ATTR),
+            2 => reply.attr(&TTL, &SINGLE_ATTR),
+            _ => reply.error(ENOENT),
+        }
+    }
+}
+
+fn mountpoint(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("fuse-rs-{}-{}", name, process::id()));
+    fs::create_dir_all(&path).unwrap();
+    path
+}
+
+/// Run the given test in an unprivileged mount environment if possible, otherwise directly
+fn run_mount_test<F: FnOnce(Option<&MountEnv>)>(test: F) {
+    #[cfg(all(target_os = "linux", feature = "mount-tests"))]
+    match fuse::testing::unprivileged_mount_env() {
+        Ok(env) => return env.run(|env| test(Some(env))).unwrap(),
+        Err(err) => eprintln!("{}, falling back to fusermount", err),
+    }
+    test(None)
+}
+
+/// Mount the given filesystem in the given unprivileged mount environment, or using fusermount
+fn spawn_mount<'a, FS: Filesystem + Send + 'a>(env: Option<&MountEnv>, filesystem: FS, mountpoint: &Path) -> BackgroundSession<'a> {
+    match env {
+        #[cfg(all(target_os = "linux", feature = "mount-tests"))]
+        Some(env) => env.mount(filesystem, mountpoint).unwrap(),
+        _ => unsafe { fuse::spawn_mount(filesystem, mountpoint, &[]) }.unwrap(),
+    }
+}
+
+#[test]
+#[ignore]
+fn spawn_mount_is_usable_immediately() {
+    run_mount_test(|env| {
+        let mountpoint = mountpoint("spawn_mount");
+        let session = spawn_mount(env, SingleFS, &mountpoint);
+        assert!(mountpoint.join("single").exists());
+        drop(session);
+        fs::remove_dir(&mountpoint).unwrap();
+    });
+}
+
+#[test]
+#[cfg(all(target_os = "linux", feature = "mount-tests"))]
+fn unprivileged_mount() {
+    let env = match fuse::testing::unprivileged_mount_env() {
+        Ok(env) => env,
+        Err(err) => {
+            eprintln!("Skipping test: {}", err);
+            return;
+        }
+    };
+    env.run(|env| {
+        let mountpoint = mountpoint("unprivileged");
+        let session = env.mount(SingleFS, &mountpoint).unwrap();
+        assert_eq!(fs::metadata(mountpoint.join("single")).unwrap().len(), 0);
+        assert!(!mountpoint.join("missing").exists());
+        drop(session);
+        fs::remove_dir(&mountpoint).unwrap();
+    }).unwrap();
+}
+
+#[test]
+#[ignore]
+#[cfg(target_os = "linux")]
+fn abort_connection() {
+    let mountpoint = mountpoint("abort_connection");
+    let session = unsafe { fuse::spawn_mount(SingleFS, &mountpoint, &[]) }.unwrap();
+    let id = session.connection_id().unwrap();
+    let dir = session.sysfs_dir().unwrap();
+    assert!(dir.ends_with(id.to_string()));
+    session.abort_connection().unwrap();
+    // The filesystem doesn't answer anymore, but is still mounted
+    let err = fs::metadata(mountpoint.join("single")).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOTCONN));
+    let start = Instant::now();
+    while session.shutdown_reason().is_none() && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(session.shutdown_reason(), Some(ShutdownReason::Aborted { still_mounted: true }));
+    // Dropping the session detaches the filesystem
+    drop(session);
+    fs::remove_dir(&mountpoint).unwrap();
+}
+
+/// Filesystem with an empty root directory that supports a single unnamed file, which can be
+/// linked into the root directory as "linked"
+#[cfg(all(target_os = "linux", feature = "abi-7-37"))]
+struct TmpFileFS {
+    linked: bool,
+}
+
+#[cfg(all(target_os = "linux", feature = "abi-7-37"))]
+impl Filesystem for TmpFileFS {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if parent == 1 && self.linked && name == "linked" {
+            reply.entry(&TTL, &SINGLE_ATTR, 0);
+        } else {
+            reply.error(ENOENT);
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match ino {
+            1 => reply.attr(&TTL, &ROOT_ATTR),
+            2 if self.linked => reply.attr(&TTL, &SINGLE_ATTR),
+            2 => reply.attr(&TTL, &FileAttr { nlink: 0, ..SINGLE_ATTR }),
+            _ => reply.error(ENOENT),
+        }
+    }
+
+    fn tmpfile(&mut self, _req: &Request<'_>, parent: u64, _mode: fuse::CreateMode, _flags: u32, reply: fuse::ReplyCreate) {
+        assert_eq!(parent, 1);
+        reply.created(&TTL, &FileAttr { nlink: 0, ..SINGLE_ATTR }, 0, 0, 0);
+    }
+
+    fn link(&mut self, _req: &Request<'_>, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        assert_eq!((ino, newparent, newname), (2, 1, OsStr::new("linked")));
+        self.linked = true;
+        reply.entry(&TTL, &SINGLE_ATTR, 0);
+    }
+}
+
+#[test]
+#[ignore]
+#[cfg(all(target_os = "linux", feature = "abi-7-37"))]
+fn tmpfile_and_linkat() {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    run_mount_test(|env| {
+        let mountpoint = mountpoint("tmpfile");
+        let session = spawn_mount(env, TmpFileFS { linked: false }, &mountpoint);
+        let dir = CString::new(mountpoint.as_os_str().as_bytes()).unwrap();
+        let fd = unsafe { libc::open(dir.as_ptr(), libc::O_TMPFILE | libc::O_RDWR, 0o600) };
+        if fd < 0 {
+            // Kernels before Linux 6.1 don't support O_TMPFILE on FUSE filesystems
+            let err = std::io::Error::last_os_error();
+            assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP), "{}", err);
+            eprintln!("Skipping test, O_TMPFILE isn't supported by the kernel");
+        } else {
+            let proc_path = CString::new(format!("/proc/self/fd/{}", fd)).unwrap();
+            let target = CString::new(mountpoint.join("linked").as_os_str().as_bytes()).unwrap();
+            let res = unsafe { libc::linkat(libc::AT_FDCWD, proc_path.as_ptr(), libc::AT_FDCWD, target.as_ptr(), libc::AT_SYMLINK_FOLLOW) };
+            assert_eq!(res, 0, "{}", std::io::Error::last_os_error());
+            unsafe { libc::close(fd) };
+            assert!(mountpoint.join("linked").exists());
+        }
+        drop(session);
+        fs::remove_dir(&mountpoint).unwrap();
+    });
+}